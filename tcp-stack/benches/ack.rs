@@ -0,0 +1,67 @@
+//! Cumulative ACK processing with many segments in flight
+//!
+//! Holds 10k one-MSS segments, then acknowledges them one ACK per segment,
+//! as a receiver without delayed ACKs would. Each ACK should cost the same
+//! however much is still pending.
+//!
+//! ```sh
+//! cargo bench --bench ack
+//! ```
+
+use bytes::Bytes;
+use std::hint::black_box;
+use std::time::{Duration, Instant as Clock};
+use tcp_stack::reliability::retransmit::{PendingSegment, SegmentKind};
+use tcp_stack::reliability::RetransmissionManager;
+use tcp_stack::utils::{Instant, SeqNumber};
+
+const MSS: u32 = 1460;
+const ROUNDS: u32 = 20;
+
+/// A manager holding `count` segments from `start`
+fn in_flight(start: SeqNumber, count: u32) -> RetransmissionManager {
+  let mut retransmit = RetransmissionManager::new();
+  for i in 0..count {
+    let segment = PendingSegment {
+      seq: start + i * MSS,
+      len: MSS,
+      data: Bytes::new(),
+      retransmit_count: 0,
+      first_sent: Instant::ZERO,
+      kind: SegmentKind::Data,
+    };
+    retransmit.add_segment(segment, 1.0, Instant::ZERO);
+  }
+  retransmit
+}
+
+/// Mean time per ACK acknowledging `count` segments one at a time
+fn ack_each(start: SeqNumber, count: u32) -> Duration {
+  let mut total = Duration::ZERO;
+  for _ in 0..ROUNDS {
+    let mut retransmit = in_flight(start, count);
+    let clock = Clock::now();
+    for i in 1..=count {
+      black_box(retransmit.acknowledge(start + i * MSS, Instant::ZERO));
+    }
+    total += clock.elapsed();
+    assert_eq!(retransmit.pending_count(), 0);
+  }
+  total / (ROUNDS * count)
+}
+
+fn main() {
+  for count in [100, 1_000, 10_000] {
+    println!(
+      "{count:>6} in flight: {:?} per ACK",
+      ack_each(SeqNumber(0), count)
+    );
+  }
+  // Sequence numbers wrapping part way through the window
+  let wrapping = SeqNumber(u32::MAX - 5_000 * MSS);
+  println!(
+    "{:>6} in flight across a wrap: {:?} per ACK",
+    10_000,
+    ack_each(wrapping, 10_000)
+  );
+}
@@ -0,0 +1,97 @@
+//! Pure ACKs through the engine's fast path
+//!
+//! Holds 1k one-MSS segments in flight on an established connection, then
+//! acknowledges them one bare ACK at a time, first through `on_segment`,
+//! which takes the acknowledgment and stops, and then through `on_ack`
+//! followed by `receive_text`, the two steps of the full path a segment
+//! with data takes. The full path also pays for a second engine step, so
+//! the difference overstates the saving somewhat.
+//!
+//! ```sh
+//! cargo bench --bench pure_ack
+//! ```
+
+use bytes::Bytes;
+use std::hint::black_box;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant as Clock};
+use tcp_stack::connection::{ControlBlock, Engine};
+use tcp_stack::packet::{TcpFlags, TcpHeader};
+use tcp_stack::reliability::retransmit::{PendingSegment, SegmentKind};
+use tcp_stack::utils::{Instant, SeqNumber};
+
+const LOCAL: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 40000);
+const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80);
+const ISS: u32 = 1000;
+const IRS: u32 = 5000;
+const MSS: u32 = 1460;
+const COUNT: u32 = 1_000;
+const ROUNDS: u32 = 50;
+
+/// An established connection with `COUNT` segments in flight
+fn in_flight() -> ControlBlock {
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+  Engine::new(LOCAL, REMOTE, &mut control).open(Instant::ZERO);
+  let mut syn_ack = TcpHeader::syn(REMOTE.port(), LOCAL.port(), IRS, MSS as u16);
+  syn_ack.flags = syn_ack.flags.with_ack();
+  syn_ack.ack_num = ISS + 1;
+  Engine::new(LOCAL, REMOTE, &mut control).on_segment(&syn_ack, &[], Instant::ZERO);
+
+  let start = SeqNumber(ISS + 1);
+  for i in 0..COUNT {
+    let seq = start + i * MSS;
+    control.on_send(seq, MSS);
+    control.retransmit.add_segment(
+      PendingSegment {
+        seq,
+        len: MSS,
+        data: Bytes::new(),
+        retransmit_count: 0,
+        first_sent: Instant::ZERO,
+        kind: SegmentKind::Data,
+      },
+      1.0,
+      Instant::ZERO,
+    );
+  }
+  control
+}
+
+/// The peer's bare ACK of the first `i` segments
+fn ack(i: u32) -> TcpHeader {
+  let mut header = TcpHeader::new(REMOTE.port(), LOCAL.port());
+  header.flags = TcpFlags::new().with_ack();
+  header.seq_num = IRS + 1;
+  header.ack_num = ISS + 1 + i * MSS;
+  header.window_size = u16::MAX;
+  header
+}
+
+/// Mean time per ACK, handing each to `take`
+fn per_ack(mut take: impl FnMut(&mut Engine<'_>, &TcpHeader, Instant)) -> Duration {
+  let mut total = Duration::ZERO;
+  for _ in 0..ROUNDS {
+    let mut control = in_flight();
+    let acks: Vec<TcpHeader> = (1..=COUNT).map(ack).collect();
+    let now = Instant::from_millis(10);
+    let clock = Clock::now();
+    for header in &acks {
+      take(&mut Engine::new(LOCAL, REMOTE, &mut control), header, now);
+    }
+    total += clock.elapsed();
+    assert_eq!(control.snd_una(), control.snd_nxt());
+  }
+  total / (ROUNDS * COUNT)
+}
+
+fn main() {
+  let fast = per_ack(|engine, header, now| {
+    black_box(engine.on_segment(header, &[], now));
+  });
+  let full = per_ack(|engine, header, now| {
+    black_box(engine.on_ack(header, 0, now));
+    black_box(engine.receive_text(header, &[], now));
+  });
+  println!("fast path: {fast:?} per ACK");
+  println!("full path: {full:?} per ACK");
+}
@@ -3,7 +3,8 @@
 //! This example demonstrates a simple TCP echo server using our userspace TCP stack.
 //! Note: Requires root privileges to create raw sockets.
 
-use tcp_stack::{RawSocket, TcpConnection};
+use tcp_stack::socket::privilege;
+use tcp_stack::TcpConnection;
 use tracing::{info, error};
 use std::net::{Ipv4Addr, SocketAddrV4};
 
@@ -18,10 +19,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!("TCP Echo Server starting...");
 
-    // Try to create a raw socket (requires root)
-    match RawSocket::new() {
+    // Open the raw socket while privileged, then continue as `nobody`
+    let (uid, gid) = privilege::lookup_user("nobody").unwrap_or((65534, 65534));
+    match privilege::open_then_drop(uid, gid) {
         Ok(socket) => {
-            info!("Raw socket created successfully");
+            info!("Raw socket created, running as uid {}", uid);
             
             let local_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080);
             let remote_addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 12345);
@@ -0,0 +1,14 @@
+//! Event Log Timeline Example
+//!
+//! Converts an event log written with `EventLog::to_json_lines` into an HTML
+//! timeline: `cargo run --example qlog_timeline -- conn.qlog > conn.html`
+
+use std::{env, fs};
+use tcp_stack::connection::qlog::timeline_html;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  let path = env::args().nth(1).ok_or("usage: qlog_timeline <file>")?;
+  let json_lines = fs::read_to_string(path)?;
+  print!("{}", timeline_html(&json_lines));
+  Ok(())
+}
@@ -0,0 +1,336 @@
+//! Stateful fuzzing of an established connection
+//!
+//! The input is read as a sequence of operations: segments from the peer,
+//! built around the current sequence numbers so most land in or near the
+//! windows; raw packets; clock advances; and application reads and writes.
+//! The control block runs over a `Loopback` device and a `ManualClock`, as
+//! `tests/conformance.rs` drives it, and after every operation must still
+//! hold its invariants:
+//!
+//! - SND.UNA and RCV.NXT never move backwards, and SND.UNA never passes
+//!   SND.NXT
+//! - every byte we send or deliver is the byte written at that offset
+//! - the send buffer stays within its limit
+//! - out-of-order segments stay within their count cap
+//! - the state only makes legal transitions
+//!
+//! Run with `cargo fuzz run connection` from the crate root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tcp_stack::connection::{ControlBlock, TcpState};
+use tcp_stack::device::Loopback;
+use tcp_stack::packet::{Ipv4Header, TcpFlags, TcpHeader};
+use tcp_stack::utils::{Clock, Instant, ManualClock, SeqNumber};
+use tcp_stack::NetworkDevice;
+
+const LOCAL: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+const REMOTE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const LOCAL_PORT: u16 = 40000;
+const REMOTE_PORT: u16 = 8080;
+const ISS: u32 = 0xffff_f000;
+const IRS: u32 = 0x7fff_f000;
+
+fuzz_target!(|data: &[u8]| {
+  Session::new().run(Input(data));
+});
+
+/// Byte at stream offset `offset` of what we write
+fn our_byte(offset: u32) -> u8 {
+  (offset % 253) as u8
+}
+
+/// Byte at stream offset `offset` of what the peer sends
+fn peer_byte(offset: u32) -> u8 {
+  (offset % 251) as u8
+}
+
+/// Fuzzer input, consumed from the front
+struct Input<'a>(&'a [u8]);
+
+impl Input<'_> {
+  fn byte(&mut self) -> Option<u8> {
+    let (&first, rest) = self.0.split_first()?;
+    self.0 = rest;
+    Some(first)
+  }
+
+  fn u16(&mut self) -> Option<u16> {
+    Some(u16::from_be_bytes([self.byte()?, self.byte()?]))
+  }
+
+  fn bytes(&mut self, len: usize) -> &[u8] {
+    let (taken, rest) = self.0.split_at(len.min(self.0.len()));
+    self.0 = rest;
+    taken
+  }
+}
+
+struct Session {
+  clock: ManualClock,
+  device: Loopback,
+  control: ControlBlock,
+  /// Bytes written by the application so far
+  written: u32,
+  /// Bytes read by the application so far
+  delivered: u32,
+  snd_una: SeqNumber,
+  rcv_nxt: SeqNumber,
+  state: TcpState,
+}
+
+impl Session {
+  /// A connection just past its handshake
+  fn new() -> Self {
+    let start = Instant::from_secs(1);
+    let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), start);
+    control.on_send(SeqNumber(ISS), 1);
+    control.set_irs(SeqNumber(IRS));
+    control.window_scale = 0;
+    control.update_send_window(SeqNumber(IRS), SeqNumber(ISS + 1), 65535);
+    control.on_ack(SeqNumber(ISS + 1));
+    control.state = TcpState::Established;
+    Self {
+      clock: ManualClock::new(start),
+      device: Loopback::new(),
+      snd_una: control.snd_una(),
+      rcv_nxt: control.rcv_nxt(),
+      state: control.state,
+      control,
+      written: 0,
+      delivered: 0,
+    }
+  }
+
+  fn run(&mut self, mut input: Input) {
+    while let Some(op) = input.byte() {
+      match op % 5 {
+        0 => {
+          let Some(segment) = self.peer_segment(&mut input) else {
+            return;
+          };
+          self.device.inject(segment);
+        }
+        1 => {
+          let len = input.byte().unwrap_or(0) as usize;
+          self.device.inject(input.bytes(len).to_vec());
+        }
+        2 => {
+          let ms = input.u16().unwrap_or(0);
+          self.clock.advance(Duration::from_millis(ms.into()));
+        }
+        3 => {
+          let len = input.u16().unwrap_or(0) as u32;
+          let data: Vec<u8> = (self.written..self.written + len).map(our_byte).collect();
+          self.written += self.control.write(&data) as u32;
+        }
+        _ => {
+          let mut buf = vec![0u8; input.u16().unwrap_or(0) as usize];
+          let len = self.control.recv_stream.read(&mut buf);
+          for (i, &byte) in buf[..len].iter().enumerate() {
+            assert_eq!(
+              byte,
+              peer_byte(self.delivered + i as u32),
+              "delivered out of order"
+            );
+          }
+          self.delivered += len as u32;
+        }
+      }
+      self.poll();
+      self.check();
+    }
+  }
+
+  /// A plausible segment from the peer: offsets from RCV.NXT and SND.UNA
+  /// in steps of 64 bytes, mostly with ACK set
+  fn peer_segment(&self, input: &mut Input) -> Option<Vec<u8>> {
+    let bits = input.byte()?;
+    let seq_offset = input.byte()? as i8 as i32 * 64;
+    let ack_offset = input.byte()? as i8 as i32 * 64;
+    let window = input.u16()?;
+    let len = (input.byte()? as usize * 8).min(self.control.mss as usize);
+
+    let mut flags = TcpFlags::new();
+    if bits & 0x01 != 0 {
+      flags = flags.with_fin();
+    }
+    if bits & 0x02 != 0 {
+      flags = flags.with_syn();
+    }
+    if bits & 0x04 != 0 {
+      flags = flags.with_rst();
+    }
+    if bits & 0x08 != 0 {
+      flags = flags.with_psh();
+    }
+    if bits & 0x10 == 0 {
+      flags = flags.with_ack();
+    }
+    let seq = SeqNumber(self.control.rcv_nxt().0.wrapping_add_signed(seq_offset));
+    let ack = SeqNumber(self.control.snd_una().0.wrapping_add_signed(ack_offset));
+    let offset = seq.0.wrapping_sub(IRS + 1);
+    let payload: Vec<u8> = (0..len as u32)
+      .map(|i| peer_byte(offset.wrapping_add(i)))
+      .collect();
+
+    let mut header = TcpHeader::new(REMOTE_PORT, LOCAL_PORT);
+    header.flags = flags;
+    header.seq_num = seq.0;
+    header.ack_num = ack.0;
+    header.window_size = window;
+    Some(packet(REMOTE, LOCAL, &header, &payload))
+  }
+
+  /// Process one packet from the peer, as the connection's receive path
+  /// would
+  fn receive(&mut self, packet: &[u8]) {
+    let Some((_, segment)) = Ipv4Header::parse(packet) else {
+      return;
+    };
+    let Some((tcp, payload)) = TcpHeader::parse(segment) else {
+      return;
+    };
+    let now = self.clock.now();
+    let seq = SeqNumber(tcp.seq_num);
+    if self.control.state == TcpState::Closed {
+      return;
+    }
+    if tcp.flags.is_rst() {
+      if seq == self.control.rcv_nxt() {
+        self.control.retransmit.clear();
+        self.control.send_queue.clear();
+        self.control.state = TcpState::Closed;
+      }
+      return;
+    }
+    if tcp.flags.is_ack() {
+      let window = self.control.scaled_peer_window(tcp.window_size);
+      self
+        .control
+        .process_ack(seq, SeqNumber(tcp.ack_num), window, payload.len(), now);
+    }
+
+    let mut ack = false;
+    if !payload.is_empty() && self.control.state == TcpState::Established {
+      self.control.recv_stream.push(seq, payload.to_vec());
+      ack = true;
+    }
+    let rcv_nxt = self.control.rcv_nxt();
+    if tcp.flags.is_fin()
+      && seq + payload.len() as u32 == rcv_nxt
+      && self.control.state == TcpState::Established
+    {
+      self.control.recv_stream.set_rcv_nxt(rcv_nxt + 1);
+      self.control.state = TcpState::CloseWait;
+      ack = true;
+    }
+    if ack {
+      let header = self.header(TcpFlags::new().with_ack(), self.control.snd_nxt());
+      self.send(header, &[]);
+    }
+  }
+
+  /// Take in what the device received, then send what is due
+  fn poll(&mut self) {
+    let mut buf = vec![0u8; 65535];
+    while let Ok(len) = self.device.recv(&mut buf) {
+      self.receive(&buf[..len]);
+    }
+
+    let now = self.clock.now();
+    if matches!(
+      self.control.state,
+      TcpState::Established | TcpState::CloseWait
+    ) {
+      let data = TcpFlags::new().with_ack().with_psh();
+      for (seq, payload) in self.control.next_segments(now) {
+        self.send(self.header(data, seq), &payload);
+      }
+      let fast = self.control.take_fast_retransmit(now);
+      for segment in fast.into_iter().chain(self.control.poll_retransmit(now)) {
+        self.send(self.header(data, segment.seq), &segment.data);
+      }
+      if self.control.poll_window_probe(now) {
+        let header = self.header(TcpFlags::new().with_ack(), self.control.snd_una() - 1);
+        self.send(header, &[]);
+      }
+    }
+
+    while let Some(packet) = self.device.take_sent() {
+      self.check_sent(&packet);
+    }
+  }
+
+  fn header(&self, flags: TcpFlags, seq: SeqNumber) -> TcpHeader {
+    let mut header = TcpHeader::new(LOCAL_PORT, REMOTE_PORT);
+    header.flags = flags;
+    header.seq_num = seq.0;
+    header.ack_num = self.control.rcv_nxt().0;
+    header.window_size = self.control.advertised_window();
+    header
+  }
+
+  fn send(&mut self, header: TcpHeader, payload: &[u8]) {
+    let packet = packet(LOCAL, REMOTE, &header, payload);
+    self.device.send(&packet).unwrap();
+  }
+
+  /// A segment we sent carries the bytes written at its offset and stays
+  /// below SND.NXT
+  fn check_sent(&self, packet: &[u8]) {
+    let (_, segment) = Ipv4Header::parse(packet).expect("sent an unparsable packet");
+    let (tcp, payload) = TcpHeader::parse(segment).expect("sent an unparsable segment");
+    let seq = SeqNumber(tcp.seq_num);
+    assert!(
+      !(seq + payload.len() as u32).after(self.control.snd_nxt()),
+      "sent past SND.NXT"
+    );
+    let offset = seq.0.wrapping_sub(ISS + 1);
+    for (i, &byte) in payload.iter().enumerate() {
+      assert_eq!(byte, our_byte(offset + i as u32), "sent the wrong byte");
+    }
+  }
+
+  fn check(&mut self) {
+    let control = &self.control;
+    assert!(
+      !control.snd_una().after(control.snd_nxt()),
+      "SND.UNA passed SND.NXT"
+    );
+    assert!(
+      !self.snd_una.after(control.snd_una()),
+      "SND.UNA moved backwards"
+    );
+    assert!(
+      !self.rcv_nxt.after(control.rcv_nxt()),
+      "RCV.NXT moved backwards"
+    );
+    assert!(control.send_buffered() <= control.send_buffer_limit);
+    let reorder = control.recv_stream.reorder();
+    assert!(reorder.held_segments() <= reorder.max_segments());
+    let legal = matches!(
+      (self.state, control.state),
+      (TcpState::Established, TcpState::CloseWait) | (_, TcpState::Closed)
+    ) || self.state == control.state;
+    assert!(legal, "{:?} -> {:?}", self.state, control.state);
+
+    self.snd_una = control.snd_una();
+    self.rcv_nxt = control.rcv_nxt();
+    self.state = control.state;
+  }
+}
+
+/// IPv4 packet carrying a TCP segment with a valid checksum
+fn packet(src: Ipv4Addr, dst: Ipv4Addr, header: &TcpHeader, payload: &[u8]) -> Vec<u8> {
+  let checksum = header.calculate_checksum(u32::from(src), u32::from(dst), payload);
+  let mut segment = header.serialize();
+  segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+  segment.extend_from_slice(payload);
+  let mut packet = Ipv4Header::new(src, dst, segment.len()).serialize();
+  packet.extend_from_slice(&segment);
+  packet
+}
@@ -0,0 +1,51 @@
+/* C bindings for the tcp-stack userspace TCP implementation.
+ *
+ * Build the library with
+ *   cargo rustc --release --lib --features ffi --crate-type cdylib
+ *
+ * Functions return 0 (or a byte count) on success and a negated errno value
+ * on failure. Raw sockets need root or CAP_NET_RAW.
+ */
+
+#ifndef TCP_STACK_H
+#define TCP_STACK_H
+
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct TcpStackHandle tcp_stack;
+typedef struct TcpListener tcp_listener;
+typedef struct TcpConnection tcp_conn;
+
+tcp_stack *tcp_stack_new(void);
+void tcp_stack_free(tcp_stack *stack);
+/* 0 waits until the SYN retries run out */
+int tcp_stack_set_connect_timeout(tcp_stack *stack, uint32_t timeout_ms);
+/* 0 restores the default */
+int tcp_stack_set_backlog(tcp_stack *stack, uint32_t backlog);
+
+/* remote is "host:port" */
+int tcp_stack_connect(tcp_stack *stack, const char *remote, tcp_conn **out);
+/* local is "ip:port" */
+int tcp_stack_listen(tcp_stack *stack, const char *local, tcp_listener **out);
+int tcp_stack_accept(tcp_listener *listener, tcp_conn **out);
+void tcp_stack_listener_free(tcp_listener *listener);
+
+ssize_t tcp_stack_send(tcp_conn *conn, const void *buf, size_t len);
+/* 0 once the peer has closed */
+ssize_t tcp_stack_recv(tcp_conn *conn, void *buf, size_t len);
+/* negative returns from close at once, 0 resets the connection */
+int tcp_stack_set_linger(tcp_conn *conn, int32_t linger_ms);
+/* Closes and frees conn, even on failure */
+int tcp_stack_close(tcp_conn *conn);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TCP_STACK_H */
@@ -0,0 +1,415 @@
+//! Soak test: tens of thousands of concurrent connections between two
+//! `TcpStack`s, each sending and receiving through a `Loopback` device
+//!
+//! Every connection follows a random script: how much each side sends, in
+//! what chunks, and whether the client aborts part way. Packets can be
+//! dropped at random to keep the retransmission timers busy. Time is
+//! virtual and jumps to the next deadline when the wire is idle, so
+//! TIME-WAIT passes at once.
+//!
+//! At the end every connection must have closed and been removed. Both
+//! stacks must hold no connections, flows, queued packets or timers, and
+//! the bytes of graceful connections must all have arrived. Memory is
+//! measured with a counting allocator once the handshakes are done.
+//!
+//! ```text
+//! cargo run --release --features soak --bin soak -- \
+//!     --connections 50000 --seed 7 --loss 1
+//! ```
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tcp_stack::connection::{CloseReason, TcpState};
+use tcp_stack::device::{Loopback, NetworkDevice};
+use tcp_stack::stack::StackUsage;
+use tcp_stack::utils::Instant;
+use tcp_stack::{ConnectionHandle, TcpStack};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const FIRST_PORT: u16 = 8000;
+/// Connections per server port, within the client's 16384 ephemeral ports
+const PER_PORT: usize = 10_000;
+/// Most bytes one side of a connection sends
+const MAX_TRANSFER: usize = 16 * 1024;
+const MAX_CHUNK: usize = 4096;
+/// Share of connections the client aborts, in percent
+const ABORT_PERCENT: u32 = 5;
+/// Clock step while packets are moving
+const STEP: Duration = Duration::from_millis(1);
+
+/// Bytes currently allocated by the process
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+struct Options {
+    connections: usize,
+    seed: u64,
+    /// Packets dropped on the wire, in percent
+    loss: f64,
+}
+
+impl Options {
+    fn parse() -> Self {
+        let mut options = Options {
+            connections: 20_000,
+            seed: 1,
+            loss: 0.0,
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(flag) = args.next() {
+            let value = args.next().unwrap_or_else(|| usage());
+            match flag.as_str() {
+                "--connections" => options.connections = value.parse().unwrap_or_else(|_| usage()),
+                "--seed" => options.seed = value.parse().unwrap_or_else(|_| usage()),
+                "--loss" => options.loss = value.parse().unwrap_or_else(|_| usage()),
+                _ => usage(),
+            }
+        }
+        options
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: soak [--connections N] [--seed S] [--loss PERCENT]");
+    std::process::exit(2);
+}
+
+/// One end of a connection and what it has left to do
+struct End {
+    handle: ConnectionHandle,
+    to_send: usize,
+    sent: usize,
+    received: usize,
+    /// Abort instead of closing once this much is sent
+    abort_at: Option<usize>,
+    closed: bool,
+    done: bool,
+}
+
+impl End {
+    fn new(handle: ConnectionHandle, rng: &mut StdRng, may_abort: bool) -> Self {
+        let to_send = rng.gen_range(0..=MAX_TRANSFER);
+        let abort_at =
+            (may_abort && rng.gen_ratio(ABORT_PERCENT, 100)).then(|| rng.gen_range(0..=to_send));
+        Self {
+            handle,
+            to_send,
+            sent: 0,
+            received: 0,
+            abort_at,
+            closed: false,
+            done: false,
+        }
+    }
+}
+
+/// What the connections that finished added up to
+#[derive(Default)]
+struct Tally {
+    /// Bytes sent and received over connections that closed gracefully
+    sent: usize,
+    received: usize,
+    aborted: usize,
+    reset: usize,
+    /// Left in FIN-WAIT-2 after the peer's reset was lost
+    stranded: usize,
+    failed: usize,
+}
+
+impl Tally {
+    fn record(&mut self, end: &End, reason: Option<CloseReason>) {
+        match reason {
+            Some(CloseReason::PeerFin) => {
+                self.sent += end.sent;
+                self.received += end.received;
+            }
+            Some(CloseReason::PeerRst) => self.reset += 1,
+            _ => self.failed += 1,
+        }
+    }
+}
+
+/// Run one end's script for this step
+fn drive(stack: &mut TcpStack, end: &mut End, tally: &mut Tally, rng: &mut StdRng, now: Instant) {
+    let mut buf = [0u8; MAX_CHUNK];
+    loop {
+        let len = stack.recv(end.handle, &mut buf, now);
+        if len == 0 {
+            break;
+        }
+        end.received += len;
+    }
+
+    let state = stack.state(end.handle);
+    if state == Some(TcpState::Closed) {
+        tally.record(end, stack.close_reason(end.handle));
+        stack.remove(end.handle);
+        end.done = true;
+        return;
+    }
+    if !matches!(state, Some(TcpState::Established | TcpState::CloseWait)) || end.closed {
+        return;
+    }
+
+    if end.sent < end.to_send {
+        let len = rng.gen_range(1..=MAX_CHUNK).min(end.to_send - end.sent);
+        end.sent += stack.send(end.handle, &buf[..len]);
+    }
+    if end.abort_at.is_some_and(|at| end.sent >= at) {
+        stack.abort(end.handle);
+        tally.aborted += 1;
+        end.done = true;
+    } else if end.sent == end.to_send {
+        stack.close(end.handle);
+        end.closed = true;
+    }
+}
+
+/// Abort ends waiting in FIN-WAIT-2, which has no timer: the peer already
+/// acknowledged our FIN, and if its reset was dropped nothing more arrives.
+/// Returns how many there were
+fn abandon_fin_wait2(stack: &mut TcpStack, ends: &mut [End], tally: &mut Tally) -> usize {
+    let mut abandoned = 0;
+    for end in ends.iter_mut().filter(|end| !end.done) {
+        if stack.state(end.handle) == Some(TcpState::FinWait2) {
+            stack.abort(end.handle);
+            tally.stranded += 1;
+            end.done = true;
+            abandoned += 1;
+        }
+    }
+    abandoned
+}
+
+/// One stack and the loopback device it sends and receives through
+struct Host {
+    stack: TcpStack,
+    device: Loopback,
+}
+
+impl Host {
+    fn new(addr: Ipv4Addr, rng: &mut StdRng) -> Self {
+        Self {
+            stack: TcpStack::new(addr, rng.gen()),
+            device: Loopback::new(),
+        }
+    }
+}
+
+/// Send everything `from` has out through its device and carry it to
+/// `to`, dropping some. Returns the number sent and the number delivered
+fn carry(
+    from: &mut Host,
+    to: &mut Host,
+    now: Instant,
+    rng: &mut StdRng,
+    loss: f64,
+) -> (usize, usize) {
+    let mut sent = 0;
+    while let Some(packet) = from.stack.poll_transmit(now) {
+        from.device
+            .send(&packet)
+            .expect("the loopback device takes every packet");
+        sent += 1;
+    }
+    while let Some(packet) = from.device.take_sent() {
+        if !rng.gen_bool(loss) {
+            to.device.inject(packet);
+        }
+    }
+    let mut buf = [0u8; 65536];
+    let mut delivered = 0;
+    while let Ok(len) = to.device.recv(&mut buf) {
+        to.stack.handle_packet(&buf[..len], now);
+        delivered += 1;
+    }
+    (sent, delivered)
+}
+
+/// Carry packets between the hosts until neither has any to send. Returns
+/// the number delivered
+fn exchange(
+    client: &mut Host,
+    server: &mut Host,
+    now: Instant,
+    rng: &mut StdRng,
+    loss: f64,
+) -> usize {
+    let mut delivered = 0;
+    loop {
+        let (up, arrived) = carry(client, server, now, rng, loss);
+        delivered += arrived;
+        let (down, arrived) = carry(server, client, now, rng, loss);
+        delivered += arrived;
+        if up == 0 && down == 0 {
+            return delivered;
+        }
+    }
+}
+
+fn main() {
+    let options = Options::parse();
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let loss = (options.loss / 100.0).clamp(0.0, 1.0);
+    let started = std::time::Instant::now();
+
+    let baseline = ALLOCATED.load(Ordering::Relaxed);
+    let mut client = Host::new(CLIENT, &mut rng);
+    let mut server = Host::new(SERVER, &mut rng);
+    server.stack.set_backlog(options.connections);
+    let ports = options.connections.div_ceil(PER_PORT);
+    for port in 0..ports {
+        server.stack.listen(FIRST_PORT + port as u16);
+    }
+
+    let mut now = Instant::ZERO;
+    let mut clients: Vec<End> = (0..options.connections)
+        .map(|i| {
+            let remote = SocketAddrV4::new(SERVER, FIRST_PORT + (i / PER_PORT) as u16);
+            let handle = client
+                .stack
+                .connect(remote, now)
+                .expect("an ephemeral port is free");
+            End::new(handle, &mut rng, true)
+        })
+        .collect();
+    let mut servers: Vec<End> = Vec::with_capacity(options.connections);
+    let mut client_tally = Tally::default();
+    let mut server_tally = Tally::default();
+    let mut delivered = 0;
+    let mut per_connection = None;
+
+    loop {
+        let moved = exchange(&mut client, &mut server, now, &mut rng, loss);
+        delivered += moved;
+        while let Some(handle) = server.stack.accept() {
+            servers.push(End::new(handle, &mut rng, false));
+        }
+
+        if per_connection.is_none()
+            && clients
+                .iter()
+                .all(|end| end.done || client.stack.state(end.handle) != Some(TcpState::SynSent))
+        {
+            let bytes = ALLOCATED.load(Ordering::Relaxed).saturating_sub(baseline);
+            per_connection = Some(bytes / servers.len().max(1));
+        }
+
+        for end in clients.iter_mut().filter(|end| !end.done) {
+            drive(&mut client.stack, end, &mut client_tally, &mut rng, now);
+        }
+        for end in servers.iter_mut().filter(|end| !end.done) {
+            drive(&mut server.stack, end, &mut server_tally, &mut rng, now);
+        }
+        clients.retain(|end| !end.done);
+        servers.retain(|end| !end.done);
+        if clients.is_empty() && servers.is_empty() {
+            break;
+        }
+
+        now = if moved > 0 {
+            now + STEP
+        } else {
+            match [client.stack.poll_timeout(), server.stack.poll_timeout()]
+                .into_iter()
+                .flatten()
+                .min()
+            {
+                Some(deadline) => deadline.max(now + STEP),
+                None if abandon_fin_wait2(&mut client.stack, &mut clients, &mut client_tally)
+                    + abandon_fin_wait2(&mut server.stack, &mut servers, &mut server_tally)
+                    > 0 =>
+                {
+                    now + STEP
+                }
+                None => {
+                    eprintln!(
+                        "stuck with {} client and {} server connections open",
+                        clients.len(),
+                        servers.len()
+                    );
+                    std::process::exit(1);
+                }
+            }
+        };
+    }
+
+    println!("connections:        {}", options.connections);
+    println!("packets delivered:  {}", delivered);
+    println!(
+        "virtual time:       {:.1}s",
+        now.total_millis() as f64 / 1000.0
+    );
+    println!(
+        "wall time:          {:.1}s",
+        started.elapsed().as_secs_f64()
+    );
+    println!(
+        "aborted / reset:    {} / {}",
+        client_tally.aborted, server_tally.reset
+    );
+    println!(
+        "stranded fin-wait2: {}",
+        client_tally.stranded + server_tally.stranded
+    );
+    println!(
+        "failed handshakes:  {}",
+        client_tally.failed + server_tally.failed
+    );
+    if let Some(bytes) = per_connection {
+        println!("memory per conn:    {} bytes (both ends)", bytes);
+    }
+
+    let mut ok = true;
+    for (name, stack) in [("client", &client.stack), ("server", &server.stack)] {
+        let usage = stack.usage();
+        if usage != StackUsage::default() {
+            eprintln!("{} leaked: {:?}", name, usage);
+            ok = false;
+        }
+    }
+    if client_tally.sent != server_tally.received || server_tally.sent != client_tally.received {
+        eprintln!(
+            "bytes lost: client sent {} / server got {}, server sent {} / client got {}",
+            client_tally.sent, server_tally.received, server_tally.sent, client_tally.received
+        );
+        ok = false;
+    }
+    if !ok {
+        std::process::exit(1);
+    }
+    println!("no leaks");
+}
@@ -0,0 +1,85 @@
+//! Single-threaded blocking runtime
+//!
+//! `TcpStack::run_blocking` drives a stack over a `RawSocket` on the calling
+//! thread, for tools that want no async runtime at all. Each turn it waits
+//! in `poll(2)` until a packet arrives or the stack's next timer is due,
+//! feeds the packets to `handle_packet`, gives the application its turn
+//! and sends everything `poll_transmit` returns, which fires due timers on
+//! the way. It needs nothing beyond libc, so it builds with
+//! `default-features = false, features = ["raw-socket"]`.
+
+use crate::device::NetworkDevice;
+use crate::socket::RawSocket;
+use crate::utils::Instant;
+use crate::TcpStack;
+use std::io;
+use std::ops::ControlFlow;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+/// Longest `run_blocking` waits, so the application gets a turn at least
+/// this often even with nothing arriving and no timer armed
+pub const MAX_WAIT: Duration = Duration::from_secs(1);
+
+impl TcpStack {
+  /// Run the stack over `socket` until `app` breaks, calling it once per
+  /// turn with the stack and the current time. `app` sends, receives,
+  /// connects and accepts through the stack; what it queues goes out
+  /// before the next wait. Stops at the first socket error other than an
+  /// interrupted wait
+  pub fn run_blocking<F>(&mut self, socket: &mut RawSocket, mut app: F) -> io::Result<()>
+  where
+    F: FnMut(&mut TcpStack, Instant) -> ControlFlow<()>,
+  {
+    socket.set_nonblocking(true)?;
+    let mut buf = vec![0u8; 65535];
+    loop {
+      let now = Instant::now();
+      if app(self, now).is_break() {
+        return Ok(());
+      }
+      while let Some(packet) = self.poll_transmit(now) {
+        socket.send(&packet)?;
+      }
+
+      let timeout = match self.poll_timeout() {
+        Some(deadline) => deadline
+          .saturating_duration_since(Instant::now())
+          .min(MAX_WAIT),
+        None => MAX_WAIT,
+      };
+      if wait_readable(socket, timeout)? {
+        loop {
+          match socket.recv(&mut buf) {
+            Ok(len) => self.handle_packet(&buf[..len], Instant::now()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Wait up to `timeout` for a packet on `socket`, returning whether one is
+/// there. A signal ends the wait early with nothing to read
+fn wait_readable(socket: &RawSocket, timeout: Duration) -> io::Result<bool> {
+  let [_, recv] = socket.as_fds();
+  let mut fd = libc::pollfd {
+    fd: recv.as_raw_fd(),
+    events: libc::POLLIN,
+    revents: 0,
+  };
+  // Round up, so a timer due in under a millisecond is not polled for in a
+  // busy loop
+  let millis = timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as libc::c_int;
+  let ret = unsafe { libc::poll(&mut fd, 1, millis) };
+  if ret < 0 {
+    let e = io::Error::last_os_error();
+    if e.kind() == io::ErrorKind::Interrupted {
+      return Ok(false);
+    }
+    return Err(e);
+  }
+  Ok(ret > 0 && fd.revents & libc::POLLIN != 0)
+}
@@ -0,0 +1,208 @@
+//! Stack-level settings
+//!
+//! `StackConfig` holds what a `TcpStack` gives each connection it opens:
+//! congestion control, keep-alive, buffer sizes, the FIN-WAIT-2 timeout,
+//! plus the listen backlog, the OS fingerprint profile, whether packets
+//! are captured and whether some are corrupted on purpose. Changes apply
+//! to connections opened afterwards and leave existing ones alone; capture
+//! and corruption cover every packet from then on.
+//!
+//! Two providers can be plugged in as trait objects. An `IsnGenerator`
+//! picks each connection's initial sequence number in place of the stack's
+//! keyed hash, and a `TimestampClock` gives the TSval of the SYNs and
+//! SYN-ACKs sent, which is otherwise zero. Fixed ones make a run
+//! deterministic or reproduce a captured session bit for bit; a clock with
+//! a per-flow offset keeps the host's uptime from showing (RFC 7323 7.1).
+//! Settings are equal only if they share the same providers.
+//!
+//! With `std`, a `StackConfigHandle` lets another thread, such as a
+//! config-file watcher or an admin endpoint, change the settings of a
+//! running stack. The stack picks up a change the next time it handles a
+//! packet, polls or connects, at the cost of one atomic load when nothing
+//! changed.
+
+use crate::congestion::CongestionAlgorithm;
+use crate::connection::control::{DEFAULT_FIN_WAIT2_TIMEOUT, DEFAULT_SEND_BUFFER};
+use crate::connection::{KeepalivePolicy, OsProfile};
+use crate::packet::Corruption;
+use crate::reliability::RetryLimits;
+use crate::reliability::stream::DEFAULT_RECV_CAPACITY;
+use crate::stack::DEFAULT_BACKLOG;
+use crate::utils::{Instant, SeqNumber};
+use alloc::sync::Arc;
+use core::fmt;
+use core::net::SocketAddrV4;
+use core::time::Duration;
+
+/// Picks the initial sequence number of each connection
+pub trait IsnGenerator: fmt::Debug + Send + Sync {
+  fn initial_seq(&self, local: SocketAddrV4, remote: SocketAddrV4, now: Instant) -> SeqNumber;
+}
+
+/// Gives the TSval of the segments a connection sends (RFC 7323)
+pub trait TimestampClock: fmt::Debug + Send + Sync {
+  fn ts_val(&self, local: SocketAddrV4, remote: SocketAddrV4, now: Instant) -> u32;
+}
+
+/// Milliseconds since `Instant::ZERO`, the same for every flow
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MillisClock;
+
+impl TimestampClock for MillisClock {
+  fn ts_val(&self, _local: SocketAddrV4, _remote: SocketAddrV4, now: Instant) -> u32 {
+    now.total_millis() as u32
+  }
+}
+
+/// Settings a `TcpStack` applies to the connections it opens
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StackConfig {
+  pub congestion: CongestionAlgorithm,
+  /// Keep-alive of new connections; `None` leaves it off
+  pub keepalive: Option<KeepalivePolicy>,
+  /// Receive buffer of new connections, which sets the window scale they
+  /// offer
+  pub recv_buffer: usize,
+  /// Cap on a new connection's unsent and unacknowledged bytes
+  pub send_buffer: usize,
+  /// How long a new connection waits in FIN-WAIT-2 for the peer's FIN;
+  /// `None` waits for ever
+  pub fin_wait2_timeout: Option<Duration>,
+  /// Half-open connections kept before new SYNs are dropped
+  pub backlog: usize,
+  /// Retransmissions new connections make of each kind of segment before
+  /// giving up; `syn_ack` also bounds the SYN-ACKs of half-open ones
+  pub retry_limits: RetryLimits,
+  /// Record every packet received and sent in a pcap capture
+  pub capture: bool,
+  /// System whose handshake signature new connections mimic
+  pub profile: Option<OsProfile>,
+  /// Debugging aid: corrupt some of the packets sent, to check the
+  /// receive path's validation end to end
+  pub corruption: Option<Corruption>,
+  /// Initial sequence numbers of new connections; `None` keeps the
+  /// stack's keyed hash (RFC 6528)
+  #[cfg_attr(feature = "serde", serde(skip))]
+  pub isn: Option<Arc<dyn IsnGenerator>>,
+  /// TSval clock of new connections; `None` sends zero
+  #[cfg_attr(feature = "serde", serde(skip))]
+  pub timestamp_clock: Option<Arc<dyn TimestampClock>>,
+}
+
+impl StackConfig {
+  pub fn new() -> Self {
+    Self {
+      congestion: CongestionAlgorithm::default(),
+      keepalive: None,
+      recv_buffer: DEFAULT_RECV_CAPACITY,
+      send_buffer: DEFAULT_SEND_BUFFER,
+      fin_wait2_timeout: Some(DEFAULT_FIN_WAIT2_TIMEOUT),
+      backlog: DEFAULT_BACKLOG,
+      retry_limits: RetryLimits::default(),
+      capture: false,
+      profile: None,
+      corruption: None,
+      isn: None,
+      timestamp_clock: None,
+    }
+  }
+}
+
+impl PartialEq for StackConfig {
+  fn eq(&self, other: &Self) -> bool {
+    fn same<T: ?Sized>(a: &Option<Arc<T>>, b: &Option<Arc<T>>) -> bool {
+      match (a, b) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        (a, b) => a.is_none() && b.is_none(),
+      }
+    }
+    self.congestion == other.congestion
+      && self.keepalive == other.keepalive
+      && self.recv_buffer == other.recv_buffer
+      && self.send_buffer == other.send_buffer
+      && self.fin_wait2_timeout == other.fin_wait2_timeout
+      && self.backlog == other.backlog
+      && self.retry_limits == other.retry_limits
+      && self.capture == other.capture
+      && self.profile == other.profile
+      && self.corruption == other.corruption
+      && same(&self.isn, &other.isn)
+      && same(&self.timestamp_clock, &other.timestamp_clock)
+  }
+}
+
+impl Eq for StackConfig {}
+
+impl StackConfig {
+  /// TSval a new connection from `local` to `remote` sends at `now`
+  pub fn ts_val(&self, local: SocketAddrV4, remote: SocketAddrV4, now: Instant) -> u32 {
+    self
+      .timestamp_clock
+      .as_ref()
+      .map_or(0, |clock| clock.ts_val(local, remote, now))
+  }
+}
+
+impl Default for StackConfig {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct Shared {
+  config: std::sync::Mutex<StackConfig>,
+  /// Bumped on every change
+  version: core::sync::atomic::AtomicU64,
+}
+
+/// Changes the settings of a running `TcpStack` from anywhere
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct StackConfigHandle(std::sync::Arc<Shared>);
+
+#[cfg(feature = "std")]
+impl StackConfigHandle {
+  pub fn new(config: StackConfig) -> Self {
+    Self(std::sync::Arc::new(Shared {
+      config: std::sync::Mutex::new(config),
+      version: core::sync::atomic::AtomicU64::new(0),
+    }))
+  }
+
+  pub fn get(&self) -> StackConfig {
+    self.lock().clone()
+  }
+
+  pub fn set(&self, config: StackConfig) {
+    self.update(|current| *current = config);
+  }
+
+  /// Change some settings in place
+  pub fn update(&self, change: impl FnOnce(&mut StackConfig)) {
+    let mut config = self.lock();
+    change(&mut config);
+    self
+      .0
+      .version
+      .fetch_add(1, core::sync::atomic::Ordering::Release);
+  }
+
+  /// The settings and their version, if changed since `version`
+  pub(crate) fn changed_since(&self, version: u64) -> Option<(StackConfig, u64)> {
+    let current = self.0.version.load(core::sync::atomic::Ordering::Acquire);
+    if current == version {
+      return None;
+    }
+    let config = self.lock();
+    // Read the version again under the lock, so it matches the settings
+    let current = self.0.version.load(core::sync::atomic::Ordering::Acquire);
+    Some((config.clone(), current))
+  }
+
+  fn lock(&self) -> std::sync::MutexGuard<'_, StackConfig> {
+    self.0.config.lock().unwrap_or_else(|e| e.into_inner())
+  }
+}
@@ -3,3 +3,11 @@
 pub mod newreno;
 
 pub use newreno::NewReno;
+
+/// Congestion control algorithms the stack implements
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CongestionAlgorithm {
+  #[default]
+  NewReno,
+}
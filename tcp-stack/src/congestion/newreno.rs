@@ -2,6 +2,10 @@
 
 use crate::utils::SeqNumber;
 
+/// Appropriate Byte Counting limit L (RFC 3465): slow start grows cwnd by
+/// at most this many SMSS per ACK, however much a stretch ACK covers
+pub const ABC_LIMIT: u32 = 2;
+
 /// NewReno congestion control state
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CongestionState {
@@ -18,6 +22,9 @@ pub struct NewReno {
   dup_acks: u32,
   last_cwnd_reduction: SeqNumber,
   initial_mss: u32,
+  /// Bytes acknowledged since cwnd last grew, short of the SMSS (slow
+  /// start) or cwnd (congestion avoidance) that grows it
+  bytes_acked: u32,
 }
 
 impl NewReno {
@@ -30,20 +37,33 @@ impl NewReno {
       dup_acks: 0,
       last_cwnd_reduction: SeqNumber(0),
       initial_mss,
+      bytes_acked: 0,
     }
   }
 
   pub fn on_ack(&mut self, ack: SeqNumber, bytes_acked: u32) {
     match self.state {
       CongestionState::SlowStart => {
-        self.cwnd += bytes_acked;
+        // Whole SMSS only: a segment's ACK split into many small ones
+        // (ACK division) grows cwnd no more than the one ACK would
+        self.bytes_acked = self.bytes_acked.saturating_add(bytes_acked);
+        let segments = self.bytes_acked / self.initial_mss;
+        self.bytes_acked %= self.initial_mss;
+        self.cwnd += segments.min(ABC_LIMIT) * self.initial_mss;
         if self.cwnd >= self.ssthresh {
           self.state = CongestionState::CongestionAvoidance;
           self.cwnd = self.ssthresh + 2 * self.initial_mss;
+          self.bytes_acked = 0;
         }
       }
       CongestionState::CongestionAvoidance => {
-        self.cwnd += self.initial_mss * bytes_acked / self.cwnd;
+        // Byte counting: one SMSS per cwnd of data acknowledged, whether
+        // that took one ACK per segment or a single stretch ACK
+        self.bytes_acked = self.bytes_acked.saturating_add(bytes_acked);
+        if self.bytes_acked >= self.cwnd {
+          self.bytes_acked -= self.cwnd;
+          self.cwnd += self.initial_mss;
+        }
       }
       CongestionState::FastRecovery => {
         if ack.after(self.last_cwnd_reduction) {
@@ -56,30 +76,64 @@ impl NewReno {
     self.dup_acks = 0;
   }
 
-  pub fn on_duplicate_ack(&mut self) {
+  /// Count a duplicate ACK; `flight_size` is the data outstanding when it
+  /// arrived (RFC 5681 FlightSize)
+  pub fn on_duplicate_ack(&mut self, flight_size: u32) {
     self.dup_acks += 1;
 
     if self.dup_acks == 3 {
-      self.enter_fast_retransmit();
+      self.enter_fast_retransmit(flight_size);
     } else if self.dup_acks > 3 && self.state == CongestionState::FastRecovery {
       self.cwnd += self.initial_mss;
     }
   }
 
-  fn enter_fast_retransmit(&mut self) {
-    self.ssthresh = (self.cwnd / 2).max(2 * self.initial_mss);
+  fn enter_fast_retransmit(&mut self, flight_size: u32) {
+    self.ssthresh = self.loss_ssthresh(flight_size);
     self.cwnd = self.ssthresh + 3 * self.initial_mss;
     self.state = CongestionState::FastRecovery;
     self.dup_acks = 3;
   }
 
-  pub fn on_timeout(&mut self) {
-    self.ssthresh = (self.cwnd / 2).max(2 * self.initial_mss);
+  /// Collapse cwnd after a retransmission timeout with `flight_size` bytes
+  /// outstanding
+  pub fn on_timeout(&mut self, flight_size: u32) {
+    self.ssthresh = self.loss_ssthresh(flight_size);
     self.cwnd = self.initial_mss;
     self.state = CongestionState::SlowStart;
     self.dup_acks = 0;
   }
 
+  /// Restart after an idle period (RFC 5681 4.1): cwnd drops to no more
+  /// than the restart window, the initial window, so a stale cwnd is not
+  /// sent as one burst. ssthresh is kept
+  pub fn on_idle_restart(&mut self) {
+    self.cwnd = self.cwnd.min(self.initial_mss);
+    if self.cwnd < self.ssthresh {
+      self.state = CongestionState::SlowStart;
+    }
+  }
+
+  /// ssthresh after a loss: max(FlightSize / 2, 2 * SMSS) (RFC 5681 eq. 4).
+  /// Halving FlightSize rather than cwnd keeps an application-limited
+  /// sender from keeping a threshold it never used
+  fn loss_ssthresh(&self, flight_size: u32) -> u32 {
+    (flight_size / 2).max(2 * self.initial_mss)
+  }
+
+  /// Resume with `cwnd` and `ssthresh` carried over from another instance
+  pub fn restore(&mut self, cwnd: u32, ssthresh: u32) {
+    self.cwnd = cwnd.max(self.initial_mss);
+    self.ssthresh = ssthresh;
+    self.state = if self.cwnd < ssthresh {
+      CongestionState::SlowStart
+    } else {
+      CongestionState::CongestionAvoidance
+    };
+    self.dup_acks = 0;
+    self.bytes_acked = 0;
+  }
+
   pub fn cwnd(&self) -> u32 {
     self.cwnd
   }
@@ -91,6 +145,11 @@ impl NewReno {
   pub fn state(&self) -> CongestionState {
     self.state
   }
+
+  /// Duplicate ACKs since the last new acknowledgment
+  pub fn dup_acks(&self) -> u32 {
+    self.dup_acks
+  }
 }
 
 impl Default for NewReno {
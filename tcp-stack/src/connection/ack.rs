@@ -0,0 +1,222 @@
+//! ACK generation policy
+//!
+//! Every decision about when to acknowledge is made here (RFC 1122 4.2.3.2,
+//! RFC 5681 4.2, RFC 3168): out-of-order, gap-filling and CE-marked segments
+//! are acknowledged at once, in-order data waits for a second full-sized
+//! segment or the delayed-ACK timer, and a receive window that reopens
+//! triggers a window update. An update that reopens a closed window is
+//! re-sent, backing off, until the peer sends something: the receiver's
+//! counterpart to the peer's persist timer, for stacks that probe a zero
+//! window slowly.
+
+use super::Timer;
+use crate::packet::TcpOption;
+use crate::reliability::ReorderBuffer;
+use crate::utils::Instant;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+/// Delayed-ACK timeout (Linux's minimum; RFC 1122 allows up to 500ms)
+pub const DEFAULT_ACK_DELAY: Duration = Duration::from_millis(40);
+
+/// SACK blocks that fit beside a timestamp option (RFC 2018)
+pub const MAX_SACK_BLOCKS: usize = 3;
+
+/// First re-send of an unanswered window update (Linux's minimum RTO)
+pub const DEFAULT_WINDOW_UPDATE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Re-sends of an unanswered window update before leaving the stall to the
+/// peer's persist timer
+pub const MAX_WINDOW_UPDATE_RETRIES: u32 = 4;
+
+/// Configurable knobs of the ACK policy
+#[derive(Debug, Clone)]
+pub struct AckPolicy {
+  /// Delay ACKs for in-order data; when false every segment is ACKed at once
+  pub delayed_ack: bool,
+  pub ack_delay: Duration,
+  /// ACK at least every this many full-sized segments
+  pub segments_per_ack: u32,
+  /// Include SACK blocks when the peer permitted SACK
+  pub sack: bool,
+  /// Wait before re-sending a window update that reopened a closed window,
+  /// doubled for each re-send
+  pub window_update_interval: Duration,
+}
+
+impl Default for AckPolicy {
+  fn default() -> Self {
+    Self {
+      delayed_ack: true,
+      ack_delay: DEFAULT_ACK_DELAY,
+      segments_per_ack: 2,
+      sack: true,
+      window_update_interval: DEFAULT_WINDOW_UPDATE_INTERVAL,
+    }
+  }
+}
+
+/// What the receiver should do about acknowledging
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckDecision {
+  /// Send an ACK now
+  Immediate,
+  /// Send an ACK once this deadline passes unless one goes out earlier
+  Delayed(Instant),
+  /// Nothing to acknowledge
+  None,
+}
+
+/// Properties of a received segment that matter to the ACK policy
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReceivedSegment {
+  /// Sequence space consumed: payload plus SYN/FIN
+  pub len: u32,
+  pub full_sized: bool,
+  /// Starts at RCV.NXT
+  pub in_order: bool,
+  /// Fills all or part of a hole in the reorder buffer
+  pub fills_gap: bool,
+  /// Arrived marked Congestion Experienced
+  pub ce: bool,
+}
+
+/// Tracks unacknowledged receipts and the delayed-ACK timer
+pub struct AckGenerator {
+  policy: AckPolicy,
+  unacked_segments: u32,
+  timer: Timer,
+  advertised_window: u32,
+  /// Re-sends the update that reopened the window
+  update_timer: Timer,
+  update_retries: u32,
+}
+
+impl AckGenerator {
+  pub fn new() -> Self {
+    Self::with_policy(AckPolicy::default())
+  }
+
+  pub fn with_policy(policy: AckPolicy) -> Self {
+    Self {
+      policy,
+      unacked_segments: 0,
+      timer: Timer::new(),
+      advertised_window: 0,
+      update_timer: Timer::new(),
+      update_retries: 0,
+    }
+  }
+
+  pub fn policy(&self) -> &AckPolicy {
+    &self.policy
+  }
+
+  pub fn set_policy(&mut self, policy: AckPolicy) {
+    self.policy = policy;
+  }
+
+  /// Decide how to acknowledge a segment that just arrived
+  pub fn on_segment(&mut self, segment: ReceivedSegment, now: Instant) -> AckDecision {
+    if segment.len == 0 {
+      // Pure ACKs are never acknowledged
+      return AckDecision::None;
+    }
+    if !segment.in_order || segment.fills_gap || segment.ce || !self.policy.delayed_ack {
+      return AckDecision::Immediate;
+    }
+
+    if segment.full_sized {
+      self.unacked_segments += 1;
+      if self.unacked_segments >= self.policy.segments_per_ack {
+        return AckDecision::Immediate;
+      }
+    }
+    if self.timer.deadline().is_none() {
+      self.timer.start(now, self.policy.ack_delay);
+    }
+    AckDecision::Delayed(self.timer.deadline().unwrap_or(now))
+  }
+
+  /// Decide whether the receive window growing to `window` warrants a
+  /// window update: it reopened to at least one MSS, or grew by two. One
+  /// that reopened the window is re-sent until the peer answers
+  pub fn on_window_update(&mut self, window: u32, mss: u32, now: Instant) -> AckDecision {
+    let reopened = self.advertised_window < mss && window >= mss;
+    let grew = window >= self.advertised_window.saturating_add(2 * mss);
+    if reopened {
+      self.update_retries = 0;
+      self
+        .update_timer
+        .start(now, self.policy.window_update_interval);
+    }
+    if reopened || grew {
+      AckDecision::Immediate
+    } else {
+      AckDecision::None
+    }
+  }
+
+  /// Whether an unanswered window update is due to be re-sent
+  pub fn poll_window_update(&mut self, now: Instant) -> bool {
+    if !self.update_timer.is_expired(now) {
+      return false;
+    }
+    if self.update_retries >= MAX_WINDOW_UPDATE_RETRIES {
+      self.update_timer.cancel();
+      return false;
+    }
+    self.update_retries += 1;
+    let interval = self.policy.window_update_interval * (1 << self.update_retries);
+    self.update_timer.start(now, interval);
+    true
+  }
+
+  pub fn window_update_deadline(&self) -> Option<Instant> {
+    self.update_timer.deadline()
+  }
+
+  /// A segment arrived from the peer, so it is not stuck waiting on our
+  /// window
+  pub fn on_peer_segment(&mut self) {
+    self.update_timer.cancel();
+  }
+
+  /// Whether the delayed-ACK timer has fired
+  pub fn poll(&self, now: Instant) -> bool {
+    self.timer.is_expired(now)
+  }
+
+  pub fn deadline(&self) -> Option<Instant> {
+    self.timer.deadline()
+  }
+
+  /// Record that an ACK advertising `window` was sent
+  pub fn on_ack_sent(&mut self, window: u32) {
+    self.unacked_segments = 0;
+    self.timer.cancel();
+    self.advertised_window = window;
+  }
+
+  /// SACK options describing what `reorder` holds beyond RCV.NXT
+  pub fn sack_options(&self, reorder: &ReorderBuffer) -> Vec<TcpOption> {
+    if !self.policy.sack {
+      return Vec::new();
+    }
+    reorder
+      .sack_blocks()
+      .into_iter()
+      .take(MAX_SACK_BLOCKS)
+      .map(|(left, right)| TcpOption::Sack {
+        left: left.0,
+        right: right.0,
+      })
+      .collect()
+  }
+}
+
+impl Default for AckGenerator {
+  fn default() -> Self {
+    Self::new()
+  }
+}
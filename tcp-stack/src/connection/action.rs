@@ -0,0 +1,107 @@
+//! Side effects of connection processing
+//!
+//! Processing never does I/O itself. `Engine` returns what should happen as
+//! a list of `Action`s and the runtime that owns the I/O carries them out:
+//! `TcpConnection` against its raw socket, `TcpStack` by queueing packets,
+//! and tests by looking at them.
+
+use crate::packet::TcpHeader;
+use crate::utils::Instant;
+use alloc::string::String;
+use alloc::vec::Vec;
+use bytes::Bytes;
+use core::net::Ipv4Addr;
+
+/// One thing for the runtime to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+  /// Send a segment to the peer. The checksum is left to the runtime, which
+  /// knows the addresses it sends from
+  SendSegment { header: TcpHeader, payload: Bytes },
+  /// A timer was armed or re-armed; the runtime must call back by `deadline`
+  StartTimer { timer: TimerKind, deadline: Instant },
+  /// `len` more bytes are readable from `ControlBlock::recv_stream`
+  DeliverData { len: usize },
+  /// The connection reached CLOSED
+  Close { reason: CloseReason },
+}
+
+/// Timers of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TimerKind {
+  Retransmit,
+  /// Zero-window probes
+  Persist,
+  /// Re-sent window updates
+  WindowUpdate,
+  Keepalive,
+  /// Gives up on a peer that never sends its FIN after ours
+  FinWait2,
+  /// End of TIME-WAIT, kept by the runtime rather than the control block
+  TimeWait,
+}
+
+impl TimerKind {
+  /// The timers `ControlBlock` keeps
+  pub const HELD: [TimerKind; 5] = [
+    TimerKind::Retransmit,
+    TimerKind::Persist,
+    TimerKind::WindowUpdate,
+    TimerKind::Keepalive,
+    TimerKind::FinWait2,
+  ];
+}
+
+/// Why a connection closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CloseReason {
+  /// Both sides' FINs were exchanged and acknowledged
+  PeerFin,
+  /// The peer reset the connection, or refused our SYN
+  PeerRst,
+  /// The peer left every keep-alive probe unanswered, the handshake ran
+  /// out of time, or the peer never sent its FIN in FIN-WAIT-2
+  Timeout,
+  /// The application aborted the connection
+  UserAbort,
+  /// A segment went unacknowledged through every retransmission its
+  /// `RetryLimits` allow
+  RetransmitLimit,
+  /// An ICMP error reported the peer unreachable
+  IcmpError,
+  /// The local address was removed or its interface went down
+  NetworkDown,
+}
+
+impl CloseReason {
+  /// Whether the connection ended with both sides' data delivered
+  pub fn is_graceful(self) -> bool {
+    self == CloseReason::PeerFin
+  }
+}
+
+/// A change to the host's network that can strand connections, as a
+/// `socket::NetworkWatcher` reports it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkEvent {
+  /// Interface `index` went down or was removed; `addrs` were its IPv4
+  /// addresses
+  LinkDown {
+    index: u32,
+    name: String,
+    addrs: Vec<Ipv4Addr>,
+  },
+  /// `addr` was removed from interface `index`
+  AddressRemoved { index: u32, addr: Ipv4Addr },
+}
+
+impl NetworkEvent {
+  /// Whether connections from `local` can no longer send
+  pub fn affects(&self, local: Ipv4Addr) -> bool {
+    match self {
+      NetworkEvent::LinkDown { addrs, .. } => addrs.contains(&local),
+      NetworkEvent::AddressRemoved { addr, .. } => *addr == local,
+    }
+  }
+}
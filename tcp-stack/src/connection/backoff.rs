@@ -0,0 +1,42 @@
+//! Retransmission backoff progress
+//!
+//! Each retransmission timeout doubles the wait before the next, so a
+//! connection to a vanished peer goes quiet for minutes before
+//! `RetryLimits` give up on it. An observer set with
+//! `ControlBlock::set_backoff_observer` hears of every timeout as it
+//! happens, with the attempt number and the wait until the next one, and
+//! decides whether to keep trying. Interactive tools use it to show
+//! "retrying..." and to let the user give up sooner.
+
+use crate::reliability::retransmit::SegmentKind;
+use alloc::boxed::Box;
+use core::time::Duration;
+
+/// One retransmission timeout, as reported to a backoff observer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackoffProgress {
+  /// What the oldest unacknowledged segment carries
+  pub kind: SegmentKind,
+  /// Retransmissions of that segment so far, this one included
+  pub attempt: u32,
+  /// Retransmissions its `RetryLimits` allow before the connection closes
+  pub limit: u32,
+  /// Wait until the next retransmission if this one goes unanswered
+  pub next_retry: Duration,
+  /// Time since the segment was first sent
+  pub elapsed: Duration,
+}
+
+/// What a backoff observer decides after a retransmission timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackoffDecision {
+  /// Retransmit and keep backing off
+  #[default]
+  Continue,
+  /// Give up: the connection is aborted as by `Engine::abort`, closing
+  /// with `CloseReason::UserAbort`
+  Abort,
+}
+
+/// Called with each retransmission timeout of a connection
+pub type BackoffObserver = Box<dyn FnMut(&BackoffProgress) -> BackoffDecision + Send>;
@@ -0,0 +1,71 @@
+//! ACK-less transmission for one-way measurement streams (experimental)
+//!
+//! A connection in blast mode sends whatever is queued at a fixed rate set
+//! by a token bucket, whatever the congestion window, the peer's receive
+//! window or the ACKs coming back say. Nothing sent is held for
+//! retransmission, so what the network drops stays dropped. It ignores
+//! congestion entirely: use it to measure paths you are allowed to load,
+//! never on shared networks. It takes both the `blast` feature and a call
+//! to `ControlBlock::enable_blast`.
+//!
+//! The receiving end pairs it with `LossAccounting`. Instead of waiting for
+//! a retransmission that will never come, each hole in the sequence space
+//! is counted as lost and skipped, and the data after it is readable at
+//! once. Bytes that turn up after their hole was skipped, reordered or
+//! duplicated, count as late and are not delivered.
+
+use crate::utils::SeqNumber;
+
+/// One-way loss as seen by the receiver of a blast stream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LossAccounting {
+  /// Segments that brought new data
+  pub received_segments: u64,
+  /// New bytes received
+  pub received_bytes: u64,
+  /// Holes skipped
+  pub gaps: u64,
+  /// Bytes in the holes skipped
+  pub lost_bytes: u64,
+  /// Bytes arriving below RCV.NXT
+  pub late_bytes: u64,
+}
+
+impl LossAccounting {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Account for a segment of `len` bytes at `seq`, returning the RCV.NXT
+  /// to take it at: `seq` itself when it leaves a hole behind, else
+  /// `rcv_nxt`
+  pub fn on_segment(&mut self, seq: SeqNumber, len: u32, rcv_nxt: SeqNumber) -> SeqNumber {
+    if seq.after(rcv_nxt) {
+      self.gaps += 1;
+      self.lost_bytes += u64::from(seq.diff(rcv_nxt));
+      self.count_new(len);
+      return seq;
+    }
+    let late = rcv_nxt.diff(seq).min(len);
+    self.late_bytes += u64::from(late);
+    self.count_new(len - late);
+    rcv_nxt
+  }
+
+  fn count_new(&mut self, len: u32) {
+    if len > 0 {
+      self.received_segments += 1;
+      self.received_bytes += u64::from(len);
+    }
+  }
+
+  /// Share of the bytes sent that never arrived in time, from 0 to 1
+  pub fn loss_ratio(&self) -> f64 {
+    let total = self.lost_bytes + self.received_bytes;
+    if total == 0 {
+      return 0.0;
+    }
+    self.lost_bytes as f64 / total as f64
+  }
+}
@@ -0,0 +1,100 @@
+//! Active close and `SO_LINGER`
+//!
+//! `close` queues a FIN behind any data still waiting to be sent. What it
+//! does next depends on `ControlBlock::linger`:
+//!
+//! - `None`: send what the windows allow now, plus the FIN if the queue
+//!   drained, and return. Nothing keeps delivering once the connection is
+//!   dropped, so there is no telling whether the peer got everything.
+//! - `Some(Duration::ZERO)`: abort. Queued and unacknowledged data is
+//!   discarded and the peer is sent a reset.
+//! - `Some(timeout)`: keep sending and retransmitting until every byte and
+//!   the FIN are acknowledged. If `timeout` passes first the peer is sent a
+//!   reset and `close` fails with `TcpError::Timeout`.
+
+use super::connect::POLL_INTERVAL;
+use super::{TcpConnection, TcpState};
+use crate::error::{Result, TcpError};
+use crate::utils::Instant;
+use std::io;
+use std::time::Duration;
+
+impl TcpConnection {
+  /// Close the sending side, lingering as `ControlBlock::linger` says. `Ok`
+  /// under a linger timeout means the peer acknowledged all data and the FIN
+  pub fn close(&mut self) -> Result<()> {
+    match self.subflow.control.linger {
+      None => {
+        let now = Instant::now();
+        self.transmit(now)?;
+        self.send_fin(now)?;
+        Ok(())
+      }
+      Some(timeout) if timeout.is_zero() => {
+        self.abort()?;
+        Ok(())
+      }
+      Some(timeout) => self.linger(timeout),
+    }
+  }
+
+  /// `close` without blocking the async runtime; the connection is dropped
+  /// once it finishes
+  #[cfg(feature = "async")]
+  pub async fn close_async(mut self) -> Result<()> {
+    let result = tokio::task::spawn_blocking(move || self.close()).await;
+    result.map_err(|e| TcpError::Io(io::Error::other(e)))?
+  }
+
+  /// Discard everything unsent or unacknowledged and reset the connection
+  pub fn abort(&mut self) -> io::Result<()> {
+    if matches!(self.state(), TcpState::Closed | TcpState::Listen) {
+      return Ok(());
+    }
+    let actions = self.engine().abort(Instant::now());
+    self.execute(actions)
+  }
+
+  /// Whether our FIN is out and the peer acknowledged it and all data
+  /// before it
+  pub fn is_flushed(&self) -> bool {
+    matches!(
+      self.state(),
+      TcpState::FinWait2 | TcpState::TimeWait | TcpState::Closed
+    )
+  }
+
+  /// Drive the close until the FIN is acknowledged or `timeout` passes
+  fn linger(&mut self, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0u8; 65535];
+    self.subflow.socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    loop {
+      let now = Instant::now();
+      self.transmit(now)?;
+      self.send_fin(now)?;
+      if self.is_flushed() {
+        return Ok(());
+      }
+      if now >= deadline {
+        debug!(
+          "[conn {}] Linger to {} timed out with {} bytes unacknowledged",
+          self.trace_id(),
+          self.subflow.remote,
+          self.subflow.control.snd_nxt().diff(self.subflow.control.snd_una())
+        );
+        self.abort()?;
+        return Err(TcpError::Timeout);
+      }
+
+      self.poll_peer(&mut buf)?;
+    }
+  }
+
+  /// Send the FIN once the send queue has drained
+  fn send_fin(&mut self, now: Instant) -> io::Result<()> {
+    let actions = self.engine().fin(now);
+    self.execute(actions)
+  }
+}
@@ -0,0 +1,466 @@
+//! Active open: SYN-SENT processing with timeouts and cancellation
+//!
+//! A connect sends SYN and retransmits it with exponential backoff until the
+//! handshake completes, the retry limit or overall timeout is reached, or the
+//! attempt is cancelled. Cancellation is cooperative: the handshake loop
+//! polls its `CancelHandle` at least every `POLL_INTERVAL`.
+//!
+//! With several candidate addresses, attempts can be raced as in Happy
+//! Eyeballs (RFC 8305): each starts `attempt_delay` after the previous one,
+//! or as soon as it fails, and the first to complete cancels the rest.
+
+use super::{CloseReason, TcpConnection, TcpState};
+use crate::error::{Result, TcpError};
+use crate::packet::TcpHeader;
+use crate::reliability::SegmentKind;
+use crate::socket::route::{self, Route};
+use crate::socket::{DestinationPolicy, RawSocket};
+use crate::utils::Instant;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+/// SYN retransmissions before giving up (Linux `tcp_syn_retries` default)
+pub const DEFAULT_SYN_RETRIES: u32 = 6;
+
+/// Initial SYN retransmission timeout (RFC 6298)
+const INITIAL_SYN_RTO: Duration = Duration::from_secs(1);
+
+/// Ephemeral ports tried before `connect_with` gives up with `AddrInUse`
+const EPHEMERAL_PORT_ATTEMPTS: usize = 8;
+
+/// RFC 8305 recommended Connection Attempt Delay
+pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Longest the handshake loop blocks before re-checking timers and
+/// cancellation
+pub(super) const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Shared flag for cancelling an in-progress connect from another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn cancel(&self) {
+    self.0.store(true, Ordering::Release);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.0.load(Ordering::Acquire)
+  }
+}
+
+/// Limits applied to an active open
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+  /// Overall deadline for the handshake
+  pub timeout: Option<Duration>,
+  /// SYN retransmissions before failing with `Timeout`
+  pub syn_retries: u32,
+  pub cancel: Option<CancelHandle>,
+  /// TTL for outgoing packets, if not the default
+  pub ttl: Option<u8>,
+  /// Enforce GTSM from the SYN-ACK on, for a peer this many hops away
+  pub gtsm_hops: Option<u8>,
+  /// When connecting to several addresses, start the next attempt this long
+  /// after the previous one instead of waiting for it to fail
+  pub attempt_delay: Option<Duration>,
+  /// Destinations besides unicast the connection may send to; `None`
+  /// keeps the socket's own policy, which refuses them by default
+  pub destinations: Option<DestinationPolicy>,
+}
+
+impl Default for ConnectOptions {
+  fn default() -> Self {
+    Self {
+      timeout: None,
+      syn_retries: DEFAULT_SYN_RETRIES,
+      cancel: None,
+      ttl: None,
+      gtsm_hops: None,
+      attempt_delay: None,
+      destinations: None,
+    }
+  }
+}
+
+impl TcpConnection {
+  /// Connect to `remote`, giving up after the default SYN retry limit
+  pub fn connect(remote: SocketAddrV4) -> Result<Self> {
+    Self::connect_with(remote, ConnectOptions::default())
+  }
+
+  /// Connect to `remote`, failing with `Timeout` if the handshake has not
+  /// completed within `timeout`
+  pub fn connect_timeout(remote: SocketAddrV4, timeout: Duration) -> Result<Self> {
+    Self::connect_with(
+      remote,
+      ConnectOptions {
+        timeout: Some(timeout),
+        ..ConnectOptions::default()
+      },
+    )
+  }
+
+  /// Connect to `remote` on a new raw socket, using the source address the
+  /// routing table picks and a random ephemeral port. Fails at once with
+  /// `HostUnreachable` if there is no route; `route` says which was used
+  pub fn connect_with(remote: SocketAddrV4, options: ConnectOptions) -> Result<Self> {
+    // Refuse before the route lookup, which fails less clearly. The source
+    // the kernel picks for a loopback destination is always loopback
+    let policy = options.destinations.unwrap_or_default();
+    policy
+      .check(Ipv4Addr::LOCALHOST, *remote.ip())
+      .map_err(io::Error::from)?;
+    let route = Self::route_to(*remote.ip())?;
+    let source = route.source;
+    debug!(
+      "Routing to {} from {} via {}",
+      remote,
+      source,
+      route.interface.as_deref().unwrap_or("unknown interface")
+    );
+    for _ in 0..EPHEMERAL_PORT_ATTEMPTS {
+      let local = SocketAddrV4::new(source, ephemeral_port());
+      match Self::connect_socket(RawSocket::new()?, local, remote, options.clone()) {
+        Err(TcpError::AddrInUse(_)) => continue,
+        Ok(mut conn) => {
+          conn.route = Some(route);
+          return Ok(conn);
+        }
+        result => return result,
+      }
+    }
+    Err(TcpError::AddrInUse(SocketAddrV4::new(source, 0)))
+  }
+
+  /// The route a connection to `remote` would take, or `HostUnreachable`
+  /// if the routing table has none
+  pub fn route_to(remote: Ipv4Addr) -> Result<Route> {
+    route::lookup(remote).map_err(|e| match e.kind() {
+      io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => {
+        TcpError::HostUnreachable(remote)
+      }
+      _ => e.into(),
+    })
+  }
+
+  /// Resolve `host` (`"example.com:443"`) and connect to its IPv4
+  /// addresses in order, each attempt bounded by the default SYN retry limit
+  pub fn connect_host(host: &str) -> Result<Self> {
+    Self::connect_host_with(host, ConnectOptions::default())
+  }
+
+  /// Like `connect_host`, with `options` applied to every attempt; the
+  /// error from the last address tried is returned if none succeed
+  pub fn connect_host_with(host: &str, options: ConnectOptions) -> Result<Self> {
+    let addrs = ipv4_addrs(host.to_socket_addrs()?, host)?;
+    Self::connect_any(&addrs, options)
+  }
+
+  /// Connect to the first of `addrs` that answers, trying them in order or,
+  /// with `attempt_delay` set, racing them
+  pub fn connect_any(addrs: &[SocketAddrV4], options: ConnectOptions) -> Result<Self> {
+    if options.attempt_delay.is_some() {
+      return Self::race(addrs, options);
+    }
+
+    let mut last_err = None;
+    for &remote in addrs {
+      match Self::connect_with(remote, options.clone()) {
+        Ok(conn) => return Ok(conn),
+        Err(TcpError::Cancelled) => return Err(TcpError::Cancelled),
+        Err(e) => {
+          debug!("Connect to {} failed: {}", remote, e);
+          last_err = Some(e);
+        }
+      }
+    }
+    Err(last_err.unwrap_or_else(no_addresses))
+  }
+
+  /// Perform the handshake from an explicit local address over `socket`,
+  /// failing with `AddrInUse` if another connection has the same 4-tuple,
+  /// or at once with a `PermissionDenied` error if the destination policy
+  /// refuses `remote`
+  pub fn connect_socket(
+    mut socket: RawSocket,
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    options: ConnectOptions,
+  ) -> Result<Self> {
+    if let Some(policy) = options.destinations {
+      socket.set_destination_policy(policy)?;
+    }
+    socket
+      .destination_policy()
+      .check(*local.ip(), *remote.ip())
+      .map_err(io::Error::from)?;
+    let mut conn = Self::new(socket, local, remote);
+    conn.register()?;
+    conn.set_gtsm(options.gtsm_hops);
+    if let Some(ttl) = options.ttl {
+      conn.set_ttl(ttl);
+    }
+    conn.handshake(&options)?;
+    Ok(conn)
+  }
+
+  /// Connect without blocking the async runtime; dropping the returned
+  /// future cancels the attempt
+  #[cfg(feature = "async")]
+  pub async fn connect_async(
+    remote: SocketAddrV4,
+    options: ConnectOptions,
+  ) -> Result<Self> {
+    Self::blocking(options, move |options| Self::connect_with(remote, options)).await
+  }
+
+  /// `connect_host_with` for async callers; resolution runs on tokio's
+  /// blocking pool
+  #[cfg(feature = "async")]
+  pub async fn connect_host_async(host: &str, options: ConnectOptions) -> Result<Self> {
+    let addrs = ipv4_addrs(tokio::net::lookup_host(host).await?, host)?;
+    Self::blocking(options, move |options| Self::connect_any(&addrs, options)).await
+  }
+
+  /// Run a blocking connect on tokio's blocking pool, cancelling it if the
+  /// future is dropped
+  #[cfg(feature = "async")]
+  async fn blocking<F>(options: ConnectOptions, connect: F) -> Result<Self>
+  where
+    F: FnOnce(ConnectOptions) -> Result<Self> + Send + 'static,
+  {
+    let cancel = options.cancel.clone().unwrap_or_default();
+    let mut guard = CancelOnDrop(Some(cancel.clone()));
+    let options = ConnectOptions {
+      cancel: Some(cancel),
+      ..options
+    };
+
+    let result = tokio::task::spawn_blocking(move || connect(options)).await;
+    guard.0 = None;
+    result.map_err(|e| TcpError::Io(io::Error::other(e)))?
+  }
+
+  /// RFC 8305 connection racing over `addrs`, each attempt on its own thread
+  fn race(addrs: &[SocketAddrV4], options: ConnectOptions) -> Result<Self> {
+    if addrs.is_empty() {
+      return Err(no_addresses());
+    }
+    let delay = options.attempt_delay.unwrap_or(DEFAULT_ATTEMPT_DELAY);
+    let parent = options.cancel.clone();
+    let (tx, rx) = mpsc::channel();
+    let mut attempts: Vec<CancelHandle> = Vec::new();
+    let mut running = 0;
+    let mut next_start = Instant::now();
+    let mut last_err = None;
+
+    let cancel_all =
+      |attempts: &[CancelHandle]| attempts.iter().for_each(CancelHandle::cancel);
+
+    loop {
+      if parent.as_ref().is_some_and(CancelHandle::is_cancelled) {
+        cancel_all(&attempts);
+        return Err(TcpError::Cancelled);
+      }
+
+      let now = Instant::now();
+      if attempts.len() < addrs.len() && (now >= next_start || running == 0) {
+        let remote = addrs[attempts.len()];
+        let cancel = CancelHandle::new();
+        let options = ConnectOptions {
+          cancel: Some(cancel.clone()),
+          attempt_delay: None,
+          ..options.clone()
+        };
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+          let _ = tx.send((remote, Self::connect_with(remote, options)));
+        });
+        debug!("Racing connect to {}", remote);
+        attempts.push(cancel);
+        running += 1;
+        next_start = now + delay;
+      }
+
+      let wait = if attempts.len() < addrs.len() {
+        next_start.saturating_duration_since(now).min(POLL_INTERVAL)
+      } else {
+        POLL_INTERVAL
+      };
+      match rx.recv_timeout(wait) {
+        Ok((remote, Ok(conn))) => {
+          debug!("Connect to {} won the race", remote);
+          cancel_all(&attempts);
+          return Ok(conn);
+        }
+        Ok((remote, Err(e))) => {
+          debug!("Connect to {} failed: {}", remote, e);
+          running -= 1;
+          last_err = Some(e);
+          if running == 0 && attempts.len() == addrs.len() {
+            return Err(last_err.unwrap_or_else(no_addresses));
+          }
+          // A failure starts the next attempt right away
+          next_start = Instant::now();
+        }
+        Err(mpsc::RecvTimeoutError::Timeout) => {}
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+          return Err(last_err.unwrap_or_else(no_addresses));
+        }
+      }
+    }
+  }
+
+  fn handshake(&mut self, options: &ConnectOptions) -> Result<()> {
+    let start = Instant::now();
+    let deadline = options.timeout.map(|t| start + t);
+    let mut rto = INITIAL_SYN_RTO;
+    let mut retries = 0;
+    let mut buf = vec![0u8; 65535];
+
+    self.subflow.socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    self.send_syn()?;
+    self.set_state(TcpState::SynSent);
+    let mut next_retry = start + rto;
+
+    loop {
+      if options
+        .cancel
+        .as_ref()
+        .is_some_and(CancelHandle::is_cancelled)
+      {
+        self.close_with(CloseReason::UserAbort);
+        return Err(TcpError::Cancelled);
+      }
+
+      let now = Instant::now();
+      if deadline.is_some_and(|d| now >= d) {
+        self.close_with(CloseReason::Timeout);
+        return Err(TcpError::Timeout);
+      }
+
+      if now >= next_retry {
+        if retries >= options.syn_retries {
+          self.close_with(CloseReason::Timeout);
+          return Err(TcpError::Timeout);
+        }
+        retries += 1;
+        rto *= 2;
+        next_retry = now + rto;
+        debug!(
+          "[conn {}] Retransmitting SYN to {} (attempt {})",
+          self.trace_id(),
+          self.subflow.remote,
+          retries + 1
+        );
+        self.send_syn()?;
+        self.subflow.control.count_retransmission(SegmentKind::Syn);
+      }
+
+      let Some(tcp) = self.recv_segment(&mut buf)? else {
+        continue;
+      };
+      match self.state() {
+        TcpState::SynSent => self.process_syn_sent(&tcp)?,
+        TcpState::SynReceived => self.process_syn_received(&tcp)?,
+        _ => {}
+      }
+
+      match self.state() {
+        TcpState::Established => {
+          if retries == 0 {
+            // Karn: only sample RTT from a SYN that was never retransmitted
+            let rtt = Instant::now() - start;
+            self.subflow.control.rtt_estimator.update(rtt.as_secs_f64());
+          }
+          return Ok(());
+        }
+        TcpState::SynReceived => {
+          // Simultaneous open: our SYN-ACK is outstanding, keep waiting
+        }
+        _ => {}
+      }
+    }
+  }
+
+  /// Send our SYN, or SYN-ACK when in SYN-RECEIVED
+  pub(super) fn send_syn(&mut self) -> io::Result<()> {
+    let syn = self.engine().syn(Instant::now());
+    self.execute(vec![syn])
+  }
+
+  /// RFC 793 "SEGMENT ARRIVES" processing for the SYN-SENT state
+  fn process_syn_sent(&mut self, tcp: &TcpHeader) -> Result<()> {
+    let actions = self.engine().on_syn_sent(tcp, Instant::now());
+    self.execute(actions)?;
+    if self.state() == TcpState::Closed {
+      return Err(TcpError::ConnectionRefused);
+    }
+    Ok(())
+  }
+}
+
+impl TcpConnection {
+  /// Completion of a passive or simultaneous open: wait for the ACK of our
+  /// SYN-ACK
+  pub(super) fn process_syn_received(&mut self, tcp: &TcpHeader) -> Result<()> {
+    let actions = self.engine().on_syn_received(tcp, Instant::now());
+    self.execute(actions)?;
+    if self.state() == TcpState::Closed {
+      return Err(TcpError::ConnectionRefused);
+    }
+    Ok(())
+  }
+}
+
+/// Cancels the wrapped handle when dropped, unless disarmed by clearing it
+#[cfg(feature = "async")]
+struct CancelOnDrop(Option<CancelHandle>);
+
+#[cfg(feature = "async")]
+impl Drop for CancelOnDrop {
+  fn drop(&mut self) {
+    if let Some(cancel) = self.0.take() {
+      cancel.cancel();
+    }
+  }
+}
+
+/// Source address the kernel's routing table would use to reach `remote`
+fn no_addresses() -> TcpError {
+  io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to").into()
+}
+
+/// IPv4 addresses among resolver results, failing if there are none
+fn ipv4_addrs(
+  addrs: impl Iterator<Item = SocketAddr>,
+  host: &str,
+) -> io::Result<Vec<SocketAddrV4>> {
+  let v4: Vec<_> = addrs
+    .filter_map(|addr| match addr {
+      SocketAddr::V4(addr) => Some(addr),
+      SocketAddr::V6(_) => None,
+    })
+    .collect();
+  if v4.is_empty() {
+    return Err(io::Error::new(
+      io::ErrorKind::AddrNotAvailable,
+      format!("{host} has no IPv4 addresses"),
+    ));
+  }
+  Ok(v4)
+}
+
+/// Random port from the IANA dynamic range
+fn ephemeral_port() -> u16 {
+  use rand::Rng;
+  rand::thread_rng().gen_range(49152..=65535)
+}
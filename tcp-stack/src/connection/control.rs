@@ -1,70 +1,1224 @@
 //! TCP Control Block (PCB)
+//!
+//! Each sequence variable has exactly one home: SND.UNA and SND.WND are the
+//! edges of `send_window`, RCV.NXT and RCV.WND belong to `recv_stream`, and
+//! SND.NXT is kept here. Read them through `snd_una()`, `snd_nxt()`,
+//! `snd_wnd()`, `rcv_nxt()` and `rcv_wnd()`.
 
-use super::TcpState;
+use super::backoff::{BackoffDecision, BackoffObserver, BackoffProgress};
+#[cfg(feature = "blast")]
+use super::blast::LossAccounting;
+use super::feedback::{AckFeedback, BoxedAckObserver};
+use super::fingerprint::OsProfile;
+use super::qlog::{EventLog, QlogEvent, RetransmitTrigger};
+#[cfg(feature = "compression")]
+use super::transform::Transforms;
+use super::{
+  stats, AckGenerator, CloseReason, ConnectionStats, Keepalive, TcpState,
+  ThroughputSample, ThroughputSampler, TimeSeqKind, TimeSequence, Timer, TimerKind,
+  WindowScaling,
+};
+use crate::config::TimestampClock;
+use crate::congestion::newreno::CongestionState;
 use crate::congestion::NewReno;
-use crate::flow_control::SlidingWindow;
-use crate::reliability::{ReorderBuffer, RetransmissionManager};
-use crate::utils::SeqNumber;
-use std::time::Instant;
+#[cfg(feature = "std")]
+use crate::flow_control::SharedShaper;
+use crate::flow_control::{Pacer, Segmenter, SlidingWindow, TokenBucket};
+use crate::memory;
+use crate::packet::{Dss, IpOptionsPolicy, Ipv4Header, MpCapable, MptcpOption, TcpHeader, TcpOption};
+use crate::reliability::retransmit::{PendingSegment, SegmentKind, SegmentRef};
+use crate::reliability::stream::DEFAULT_RECV_CAPACITY;
+use crate::reliability::{ReceiveStream, RetransmissionManager};
+use crate::utils::{Instant, SeqNumber};
+use alloc::sync::Arc;
+#[cfg(feature = "compression")]
+use alloc::vec;
+use alloc::vec::Vec;
+use bytes::Bytes;
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+/// Header bytes of NOP, NOP, timestamp
+const TIMESTAMP_OPTION_LEN: usize = 12;
+
+/// Default cap on unsent plus unacknowledged bytes held per connection
+pub const DEFAULT_SEND_BUFFER: usize = 256 * 1024;
+
+/// Default `send_low_watermark`, as for `SO_SNDLOWAT`
+pub const DEFAULT_SEND_LOW_WATERMARK: usize = 1;
+
+/// Default `fin_wait2_timeout`, as Linux's `tcp_fin_timeout`
+pub const DEFAULT_FIN_WAIT2_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Upper bound on the backed-off RTO, in seconds (RFC 6298 2.5)
+pub const MAX_RTO: f64 = 60.0;
+
+/// Largest window scale shift allowed (RFC 7323 2.3)
+pub const MAX_WINDOW_SCALE: u8 = 14;
+
+/// Smallest MSS used (RFC 9293 3.7.1: IPv4 minimum of 576 less headers)
+pub const MIN_MSS: u16 = 536;
+
+/// MSS we offer and send with until the peer's is known
+pub const DEFAULT_MSS: u16 = 1460;
+
+/// Idle time after which TS.Recent is too old for PAWS to trust, as the
+/// peer's timestamp clock may have wrapped since (RFC 7323 5.5)
+pub const PAWS_IDLE: Duration = Duration::from_secs(24 * 24 * 60 * 60);
+
+/// Room for options in an IPv4 header
+pub const MAX_IP_OPTIONS_LEN: usize = Ipv4Header::MAX_OPTIONS_LEN;
+
+/// Smallest window scale that lets a 16-bit window field cover `buffer`
+/// bytes, at most `MAX_WINDOW_SCALE`
+pub fn window_scale_for(buffer: usize) -> u8 {
+  let mut shift = 0;
+  while shift < MAX_WINDOW_SCALE && buffer >> shift > u16::MAX as usize {
+    shift += 1;
+  }
+  shift
+}
+
+/// Our MSS `ours` once the peer advertised `peer`. Zero is nonsense and
+/// leaves it alone; other values are held to at least `MIN_MSS`
+pub fn clamp_mss(ours: u16, peer: u16) -> u16 {
+  if peer == 0 {
+    return ours;
+  }
+  ours.min(peer.max(MIN_MSS))
+}
+
+/// TSval of the timestamp option `tcp` carries, if any
+fn peer_ts_val(tcp: &TcpHeader) -> Option<u32> {
+  tcp.options.iter().find_map(|option| match *option {
+    TcpOption::Timestamp { ts_val, .. } => Some(ts_val),
+    _ => None,
+  })
+}
+
+/// Source of `ControlBlock::trace_id`
+static NEXT_TRACE_ID: AtomicU32 = AtomicU32::new(1);
 
 /// Protocol Control Block
 pub struct ControlBlock {
+  /// Short process-unique id tagging the connection's log events as
+  /// `[conn N]`
+  pub trace_id: u32,
   pub state: TcpState,
 
+  /// ISS
   pub send_seq: SeqNumber,
-  pub send_una: SeqNumber,
-  pub send_nxt: SeqNumber,
-  pub send_wnd: u32,
-
+  send_nxt: SeqNumber,
+  /// IRS
   pub recv_seq: SeqNumber,
-  pub recv_ack: SeqNumber,
-  pub recv_wnd: u32,
 
   pub congestion: NewReno,
-  pub send_window: SlidingWindow,
-  pub recv_buffer: ReorderBuffer,
+  /// Application bytes waiting to be sent
+  pub send_queue: Segmenter,
+  /// Cap on `send_queue` plus the retransmission queue, in bytes
+  pub send_buffer_limit: usize,
+  /// Fewest writable bytes that make the connection ready for writing
+  pub send_low_watermark: usize,
+  send_window: SlidingWindow,
+  /// Per-connection pacing cap, applied on top of cwnd
+  pub rate_limit: Option<TokenBucket>,
+  /// Spreads each window over the RTT, when enabled
+  pub pacer: Option<Pacer>,
+  /// Aggregate cap shared with other connections
+  #[cfg(feature = "std")]
+  pub shaper: Option<SharedShaper>,
+  /// The rate of blast mode, which sends without regard to ACKs
+  #[cfg(feature = "blast")]
+  blast: Option<TokenBucket>,
+  /// Counts loss in a blast stream and skips its holes, when enabled
+  #[cfg(feature = "blast")]
+  pub loss: Option<LossAccounting>,
+  pub recv_stream: ReceiveStream,
+  /// Experimental payload transforms, such as compression
+  #[cfg(feature = "compression")]
+  pub transforms: Transforms,
   pub retransmit: RetransmissionManager,
+  /// Persist timer, probing a zero window
+  persist: Timer,
+  persist_backoff: u32,
+  /// A third duplicate ACK asked for the first unacknowledged segment
+  fast_retransmit: bool,
 
   pub rtt_estimator: RttEstimator,
+  /// Told of every retransmission timeout, and may abort instead
+  pub backoff_observer: Option<BackoffObserver>,
+  /// Told of every ACK taken
+  pub ack_observer: Option<BoxedAckObserver>,
+  /// Gives the TSval of the segments sent; they carry zero without one
+  pub timestamp_clock: Option<Arc<dyn TimestampClock>>,
+  /// System whose handshake signature ours mimics; set with `set_profile`
+  pub profile: Option<OsProfile>,
+  /// RTT sample of the ACK last processed, for `ack_observer`
+  ack_rtt: Option<Duration>,
+  pub ack: AckGenerator,
+  /// The peer's SYN carried SACK-permitted
+  pub sack_permitted: bool,
+  /// Both SYNs carried the timestamp option (RFC 7323), so every segment
+  /// carries one
+  pub timestamps: bool,
+  /// TSval of the peer's latest segment at or before RCV.NXT, which ours
+  /// echo as TSecr (RFC 7323 4.3)
+  pub ts_recent: u32,
+  /// When TS.Recent was last taken from a synchronized segment; PAWS is
+  /// off until then, and again once it is `PAWS_IDLE` old
+  pub ts_recent_at: Option<Instant>,
   pub mss: u16,
+  /// Shift applied to the windows we advertise, and offered in our SYN.
+  /// Derived from the receive buffer unless set; zero once the peer's SYN
+  /// turns out to lack the option
   pub window_scale: u8,
+  /// Shift applied to the peer's window field; zero unless its SYN carried
+  /// a window scale option (RFC 7323)
+  pub peer_window_scale: u8,
+  /// Both SYNs carried the window scale option
+  pub window_scaling: bool,
+  /// MP_CAPABLE of the peer's SYN. We never answer it, so the connection
+  /// is plain TCP, but the peer's key is kept
+  pub peer_mp_capable: Option<MpCapable>,
+  /// The DSS option of the last segment from the peer carrying one
+  pub peer_dss: Option<Dss>,
+
+  /// DSCP and ECN codepoints set on outgoing packets
+  pub dscp: u8,
+  pub ecn: u8,
+  /// TTL set on outgoing packets
+  pub ttl: u8,
+  /// IP options carried by outgoing packets, padded to whole words
+  ip_options: Vec<u8>,
+  /// Lowest TTL accepted from the peer when GTSM (RFC 5082) is enabled
+  pub min_ttl: Option<u8>,
+  /// What becomes of received packets carrying IP options
+  pub ip_options_policy: IpOptionsPolicy,
+
+  /// Keep-alive probing (`SO_KEEPALIVE`)
+  pub keepalive: Keepalive,
+  /// How long closing waits for unsent data (`SO_LINGER`)
+  pub linger: Option<Duration>,
+  /// Longest data may stay unacknowledged before the connection is dropped
+  /// (RFC 5482)
+  pub user_timeout: Option<Duration>,
+  /// Longest FIN-WAIT-2 waits for the peer's FIN before the connection is
+  /// reset (`TCP_LINGER2`); `None` waits for ever
+  pub fin_wait2_timeout: Option<Duration>,
+  fin_wait2: Timer,
 
   pub last_activity: Instant,
+  /// Why the connection closed; set on entering TIME-WAIT too, since only
+  /// a graceful close gets there
+  pub close_reason: Option<CloseReason>,
+  pub stats: ConnectionStats,
+  /// Time-sequence recording, when enabled
+  pub timeseq: Option<TimeSequence>,
+  /// Structured event log, when enabled
+  pub qlog: Option<EventLog>,
+  /// Per-interval throughput sampling, when enabled
+  pub sampler: Option<ThroughputSampler>,
 }
 
 impl ControlBlock {
+  /// Create a control block with a random initial sequence number
+  #[cfg(feature = "std")]
   pub fn new() -> Self {
-    let initial_seq = SeqNumber::random();
+    Self::with_initial_seq(SeqNumber::random(), Instant::now())
+  }
 
+  /// Create a control block with a caller-chosen initial sequence number
+  pub fn with_initial_seq(initial_seq: SeqNumber, now: Instant) -> Self {
     Self {
+      trace_id: NEXT_TRACE_ID.fetch_add(1, Ordering::Relaxed),
       state: TcpState::Closed,
       send_seq: initial_seq,
-      send_una: initial_seq,
       send_nxt: initial_seq,
-      send_wnd: 65535,
-
       recv_seq: SeqNumber(0),
-      recv_ack: SeqNumber(0),
-      recv_wnd: 65535,
 
       congestion: NewReno::new(),
-      send_window: SlidingWindow::new(65535),
-      recv_buffer: ReorderBuffer::new(),
+      send_queue: Segmenter::new(),
+      send_buffer_limit: DEFAULT_SEND_BUFFER,
+      send_low_watermark: DEFAULT_SEND_LOW_WATERMARK,
+      send_window: SlidingWindow::starting_at(initial_seq, 65535),
+      rate_limit: None,
+      pacer: None,
+      #[cfg(feature = "std")]
+      shaper: None,
+      #[cfg(feature = "blast")]
+      blast: None,
+      #[cfg(feature = "blast")]
+      loss: None,
+      recv_stream: ReceiveStream::new(),
+      #[cfg(feature = "compression")]
+      transforms: Transforms::new(),
       retransmit: RetransmissionManager::new(),
+      persist: Timer::new(),
+      persist_backoff: 0,
+      fast_retransmit: false,
 
       rtt_estimator: RttEstimator::new(),
-      mss: 1460,
-      window_scale: 7,
+      backoff_observer: None,
+      ack_observer: None,
+      timestamp_clock: None,
+      profile: None,
+      ack_rtt: None,
+      ack: AckGenerator::new(),
+      sack_permitted: false,
+      timestamps: false,
+      ts_recent: 0,
+      ts_recent_at: None,
+      mss: DEFAULT_MSS,
+      window_scale: window_scale_for(DEFAULT_RECV_CAPACITY),
+      peer_window_scale: 0,
+      window_scaling: false,
+      peer_mp_capable: None,
+      peer_dss: None,
+
+      dscp: 0,
+      ecn: 0,
+      ttl: 64,
+      ip_options: Vec::new(),
+      min_ttl: None,
+      ip_options_policy: IpOptionsPolicy::Accept,
+
+      keepalive: Keepalive::new(now),
+      linger: None,
+      user_timeout: None,
+      fin_wait2_timeout: Some(DEFAULT_FIN_WAIT2_TIMEOUT),
+      fin_wait2: Timer::new(),
+
+      last_activity: now,
+      close_reason: None,
+      stats: ConnectionStats::new(),
+      timeseq: None,
+      qlog: None,
+      sampler: None,
+    }
+  }
+
+  /// Oldest unacknowledged sequence number
+  pub fn snd_una(&self) -> SeqNumber {
+    self.send_window.left_edge()
+  }
 
-      last_activity: Instant::now(),
+  /// Next sequence number to send
+  pub fn snd_nxt(&self) -> SeqNumber {
+    self.send_nxt
+  }
+
+  /// Peer's window in bytes, from SND.UNA
+  pub fn snd_wnd(&self) -> u32 {
+    self.send_window.size()
+  }
+
+  /// Next sequence number expected from the peer
+  pub fn rcv_nxt(&self) -> SeqNumber {
+    self.recv_stream.rcv_nxt()
+  }
+
+  /// Receive window: the space left in the receive buffer
+  pub fn rcv_wnd(&self) -> u32 {
+    self.recv_stream.window()
+  }
+
+  pub fn send_window(&self) -> &SlidingWindow {
+    &self.send_window
+  }
+
+  /// Put SND.UNA at `una` and SND.NXT at `nxt` under a peer window of
+  /// `window` bytes, as a restored snapshot left them
+  pub fn resume_send(&mut self, una: SeqNumber, nxt: SeqNumber, window: u32) {
+    self.send_window = SlidingWindow::starting_at(una, window);
+    self.send_nxt = nxt;
+  }
+
+  /// Record the peer's SYN: IRS, and RCV.NXT just past it
+  pub fn set_irs(&mut self, irs: SeqNumber) {
+    self.recv_seq = irs;
+    self.recv_stream.set_rcv_nxt(irs + 1);
+  }
+
+  /// Take the peer's ISN, window, MSS and negotiated options from its SYN.
+  /// The window of a SYN is never scaled
+  pub fn on_peer_syn(&mut self, tcp: &TcpHeader) {
+    let irs = SeqNumber(tcp.seq_num);
+    self.set_irs(irs);
+    let window = tcp.window_size as u32;
+    if tcp.flags.is_ack() {
+      self.update_send_window(irs, SeqNumber(tcp.ack_num), window);
+    } else {
+      self.set_initial_send_window(window);
+    }
+    let mut requested = None;
+    for option in &tcp.options {
+      match option {
+        TcpOption::MaximumSegmentSize(mss) => self.on_peer_mss(*mss),
+        TcpOption::SackPermitted => self.sack_permitted = true,
+        TcpOption::Timestamp { ts_val, .. } => {
+          self.timestamps = self.profile.is_none_or(OsProfile::timestamps);
+          self.ts_recent = *ts_val;
+        }
+        TcpOption::WindowScale(shift) => requested = Some(*shift),
+        TcpOption::Mptcp(MptcpOption::MpCapable(capable)) => {
+          self.peer_mp_capable = Some(*capable);
+        }
+        _ => {}
+      }
+    }
+    // Our SYN always offers a scale, so the peer's SYN decides. Scaling
+    // applies only when both carry the option
+    let Some(requested) = requested else {
+      self.window_scale = 0;
+      self.peer_window_scale = 0;
+      self.window_scaling = false;
+      self.stats.window_scaling = None;
+      return;
+    };
+    if requested > MAX_WINDOW_SCALE {
+      warn!(
+        "Peer window scale {} capped at {}",
+        requested, MAX_WINDOW_SCALE
+      );
+    }
+    self.peer_window_scale = requested.min(MAX_WINDOW_SCALE);
+    self.window_scaling = true;
+    self.stats.window_scaling = Some(WindowScaling {
+      local: self.window_scale,
+      peer: self.peer_window_scale,
+      requested,
+    });
+  }
+
+  /// Take the MSS the peer advertised. Zero is nonsense and ignored; other
+  /// values are held to at least `MIN_MSS`, which every IPv4 host must
+  /// accept, so a peer cannot make us send pathologically small segments
+  pub fn on_peer_mss(&mut self, mss: u16) {
+    if mss == 0 {
+      warn!("Ignoring peer MSS of zero");
+      return;
+    }
+    if mss < MIN_MSS {
+      debug!("Peer MSS {} raised to {}", mss, MIN_MSS);
+    }
+    self.mss = clamp_mss(self.mss, mss);
+  }
+
+  /// Size the receive buffer. Before the handshake this also picks the
+  /// window scale we offer, the smallest that covers it
+  pub fn set_recv_buffer(&mut self, size: usize) {
+    self.recv_stream.set_capacity(size);
+    if self.state == TcpState::Closed {
+      self.window_scale = window_scale_for(size);
     }
   }
 
-  pub fn update_activity(&mut self) {
-    self.last_activity = Instant::now();
+  /// Keep the DSS option among `options`, if any, as `peer_dss`
+  pub fn on_peer_options(&mut self, options: &[TcpOption]) {
+    for option in options {
+      if let TcpOption::Mptcp(MptcpOption::Dss(dss)) = option {
+        self.peer_dss = Some(*dss);
+      }
+    }
+  }
+
+  /// Whether `tcp` fails PAWS (RFC 7323 5.3 R1): it carries a TSval older
+  /// than a still valid TS.Recent, so is an old duplicate. Resets are
+  /// exempt
+  pub fn fails_paws(&self, tcp: &TcpHeader, now: Instant) -> bool {
+    !tcp.flags.is_rst() && peer_ts_val(tcp).is_some_and(|ts_val| self.ts_older(ts_val, now))
+  }
+
+  /// Take the TSval of `tcp`, an acceptable segment, as TS.Recent (RFC
+  /// 7323 5.3 R3) unless it is older, or the segment starts past RCV.NXT
+  /// so arrived after a hole and must not be echoed ahead of the segment
+  /// that fills it
+  pub fn on_peer_timestamp(&mut self, tcp: &TcpHeader, now: Instant) {
+    if !self.timestamps || SeqNumber(tcp.seq_num).after(self.rcv_nxt()) {
+      return;
+    }
+    if let Some(ts_val) = peer_ts_val(tcp).filter(|ts_val| !self.ts_older(*ts_val, now)) {
+      self.ts_recent = ts_val;
+      self.ts_recent_at = Some(now);
+    }
+  }
+
+  /// Whether `ts_val` is before TS.Recent, as long as that is valid
+  fn ts_older(&self, ts_val: u32, now: Instant) -> bool {
+    self.timestamps
+      && self.ts_recent_at.is_some_and(|at| now - at < PAWS_IDLE)
+      && (ts_val.wrapping_sub(self.ts_recent) as i32) < 0
+  }
+
+  /// Send the handshake `profile` sends: its SYN options, window, window
+  /// scale and TTL. Takes effect on a connection not yet opened
+  pub fn set_profile(&mut self, profile: OsProfile) {
+    self.profile = Some(profile);
+    self.ttl = profile.ttl();
+    if self.state == TcpState::Closed {
+      self.window_scale = profile.window_scale();
+    }
+  }
+
+  /// Account for `len` sequence numbers sent from `seq`; SND.NXT only moves
+  /// forward, so retransmissions leave it alone
+  pub fn on_send(&mut self, seq: SeqNumber, len: u32) {
+    let end = seq + len;
+    if end.after(self.send_nxt) {
+      self.send_nxt = end;
+    }
+  }
+
+  /// Advance SND.UNA to `ack` if it acknowledges new data
+  /// (SND.UNA < SEG.ACK =< SND.NXT). Returns whether it did
+  pub fn on_ack(&mut self, ack: SeqNumber) -> bool {
+    if !ack.after(self.snd_una()) || ack.after(self.send_nxt) {
+      return false;
+    }
+    self.send_window.advance(ack);
+    true
+  }
+
+  /// Count the bytes of a D-SACK (RFC 2883) in `options`, a segment's from
+  /// the peer acknowledging `seg_ack`, as spurious retransmissions. The
+  /// first SACK block is a D-SACK when it lies below SEG.ACK or within the
+  /// second block; it only means a needless retransmission once we have
+  /// retransmitted at all
+  pub fn on_dsack(&mut self, seg_ack: SeqNumber, options: &[TcpOption]) {
+    let mut blocks = options.iter().filter_map(|option| match option {
+      TcpOption::Sack { left, right } => Some((SeqNumber(*left), SeqNumber(*right))),
+      _ => None,
+    });
+    let Some((left, right)) = blocks.next() else {
+      return;
+    };
+    let below_ack = !right.after(seg_ack);
+    let within_next = blocks
+      .next()
+      .is_some_and(|(outer_left, outer_right)| {
+        !left.before(outer_left) && !right.after(outer_right)
+      });
+    if !(below_ack || within_next) || !right.after(left) || self.stats.retransmissions == 0 {
+      return;
+    }
+    stats::count(
+      &mut self.stats.spurious_retransmit_bytes,
+      u64::from(right.diff(left)),
+    );
+  }
+
+  /// Process the acknowledgment in a segment from the peer, returning the
+  /// bytes it newly acknowledged. A stretch ACK covering many segments
+  /// credits all of them at once; only an ACK that acknowledges nothing new,
+  /// carries no data and leaves the window alone while data is outstanding
+  /// counts as a duplicate (RFC 5681 2).
+  ///
+  /// A misbehaving receiver gains nothing from ACKs for data never sent,
+  /// which are ignored, or from more duplicates than segments in flight,
+  /// which do not inflate cwnd; both count in `stats` along with ACKs that
+  /// split a segment
+  pub fn process_ack(
+    &mut self,
+    seg_seq: SeqNumber,
+    seg_ack: SeqNumber,
+    window: u32,
+    payload_len: usize,
+    now: Instant,
+  ) -> u32 {
+    let una = self.snd_una();
+    self.ack_rtt = None;
+    if seg_ack.after(self.send_nxt) {
+      stats::count(&mut self.stats.optimistic_acks, 1);
+      return 0;
+    }
+    if !self.on_ack(seg_ack) {
+      let duplicate = seg_ack == una
+        && payload_len == 0
+        && window == self.snd_wnd()
+        && self.in_flight() > 0;
+      self.update_send_window(seg_seq, seg_ack, window);
+      if duplicate {
+        stats::count(&mut self.stats.duplicate_acks, 1);
+        // Each duplicate stands for a segment that left the network
+        if self.congestion.dup_acks() as usize >= self.retransmit.pending_count() {
+          stats::count(&mut self.stats.excess_duplicate_acks, 1);
+          return 0;
+        }
+        let recovering = self.congestion.state() == CongestionState::FastRecovery;
+        self.on_duplicate_ack();
+        if !recovering && self.congestion.state() == CongestionState::FastRecovery {
+          self.fast_retransmit = true;
+        }
+      }
+      return 0;
+    }
+
+    let acked = seg_ack.diff(una);
+    let segments = self.retransmit.acknowledge(seg_ack, now);
+    if segments.is_empty() {
+      // SND.UNA moved into the middle of a segment
+      stats::count(&mut self.stats.split_acks, 1);
+    }
+    // Karn: only segments never retransmitted give unambiguous samples
+    let rtt = segments
+      .iter()
+      .filter(|segment| segment.retransmit_count == 0)
+      .map(|segment| now - segment.first_sent)
+      .min();
+    if let Some(rtt) = rtt {
+      self.on_rtt_sample(rtt);
+    }
+    self.ack_rtt = rtt;
+    self.rtt_estimator.reset_backoff();
+    self.congestion.on_ack(seg_ack, acked);
+    stats::count(&mut self.stats.bytes_acked, acked as u64);
+    self.update_send_window(seg_seq, seg_ack, window);
+    self.record_timeseq(now, TimeSeqKind::Ack, seg_ack, 0);
+    acked
+  }
+
+  /// Tell `ack_observer` of the ACK `process_ack` just took, which newly
+  /// acknowledged `acked` bytes and carried `options`. ACKs for data never
+  /// sent are not reported
+  pub fn report_ack(
+    &mut self,
+    seg_ack: SeqNumber,
+    acked: u32,
+    options: &[TcpOption],
+    now: Instant,
+  ) {
+    if self.ack_observer.is_none() || seg_ack.after(self.send_nxt) {
+      return;
+    }
+    let sacked: Vec<(SeqNumber, SeqNumber)> = options
+      .iter()
+      .filter_map(|option| match option {
+        TcpOption::Sack { left, right } => Some((SeqNumber(*left), SeqNumber(*right))),
+        _ => None,
+      })
+      .collect();
+    let feedback = AckFeedback {
+      ack: seg_ack,
+      acked_bytes: acked,
+      rtt: self.ack_rtt,
+      sacked: &sacked,
+      in_flight: self.in_flight(),
+      cwnd: self.congestion.cwnd(),
+      now,
+    };
+    if let Some(observer) = &mut self.ack_observer {
+      observer.on_ack(&feedback);
+    }
+  }
+
+  /// Take the window of the peer's SYN, which carries no usable ACK
+  pub fn set_initial_send_window(&mut self, window: u32) {
+    self.send_window.set_size(window);
+  }
+
+  pub fn update_activity(&mut self, now: Instant) {
+    self.last_activity = now;
+  }
+
+  /// Enable GTSM for a peer at most `hops` hops away: send with TTL 255 and
+  /// accept only packets arriving with TTL of at least `255 - hops`.
+  /// `None` disables the check and restores the default TTL
+  pub fn set_gtsm(&mut self, hops: Option<u8>) {
+    match hops {
+      Some(hops) => {
+        self.ttl = 255;
+        self.min_ttl = Some(255 - hops);
+      }
+      None => {
+        self.ttl = 64;
+        self.min_ttl = None;
+      }
+    }
+  }
+
+  /// Whether a packet arriving with `ttl` passes the GTSM check
+  pub fn accepts_ttl(&self, ttl: u8) -> bool {
+    match self.min_ttl {
+      Some(min) => ttl >= min,
+      None => true,
+    }
+  }
+
+  /// Cap the send rate at `bytes_per_sec`, or remove the cap with `None`
+  pub fn set_max_send_rate(&mut self, bytes_per_sec: Option<u64>, now: Instant) {
+    self.rate_limit = match (bytes_per_sec, self.rate_limit.take()) {
+      (Some(rate), Some(mut bucket)) => {
+        bucket.set_rate(rate, now);
+        Some(bucket)
+      }
+      (Some(rate), None) => Some(TokenBucket::new(rate, now)),
+      (None, _) => None,
+    };
+  }
+
+  /// Bytes in flight: sent but not yet acknowledged
+  pub fn in_flight(&self) -> u32 {
+    self.send_nxt.diff(self.snd_una())
+  }
+
+  /// Apply the window advertised by a segment from the peer (RFC 793 3.9).
+  /// Taken only if SND.UNA =< SEG.ACK =< SND.NXT and the segment is newer
+  /// than the one that last set the window (SND.WL1/SND.WL2), so reordered
+  /// ACKs can neither shrink nor inflate it. Returns whether it was taken
+  pub fn update_send_window(
+    &mut self,
+    seg_seq: SeqNumber,
+    seg_ack: SeqNumber,
+    window: u32,
+  ) -> bool {
+    if seg_ack.before(self.snd_una()) || seg_ack.after(self.send_nxt) {
+      return false;
+    }
+    self.send_window.update(seg_seq, seg_ack, window)
+  }
+
+  /// SEG.SEQ of the segment that last set the send window
+  pub fn send_wl1(&self) -> Option<SeqNumber> {
+    self.send_window.wl1()
+  }
+
+  /// SEG.ACK of the segment that last set the send window
+  pub fn send_wl2(&self) -> Option<SeqNumber> {
+    self.send_window.wl2()
+  }
+
+  /// Window field for an outgoing non-SYN segment, scaled down by our
+  /// window scale
+  pub fn advertised_window(&self) -> u16 {
+    (self.rcv_wnd() >> self.window_scale).min(u16::MAX as u32) as u16
+  }
+
+  /// Bytes of the window field of a non-SYN segment from the peer
+  pub fn scaled_peer_window(&self, window: u16) -> u32 {
+    (window as u32) << self.peer_window_scale
+  }
+
+  /// RFC 5681 FlightSize: bytes in flight less those the peer has SACKed
+  pub fn flight_size(&self) -> u32 {
+    self
+      .in_flight()
+      .saturating_sub(self.retransmit.sacked_bytes())
+  }
+
+  /// A duplicate ACK arrived: let congestion control count it against the
+  /// current FlightSize
+  pub fn on_duplicate_ack(&mut self) {
+    let flight_size = self.flight_size();
+    self.congestion.on_duplicate_ack(flight_size);
+  }
+
+  /// The retransmission timer fired
+  pub fn on_retransmit_timeout(&mut self) {
+    let flight_size = self.flight_size();
+    self.congestion.on_timeout(flight_size);
+  }
+
+  /// The send window: the smaller of the peer's window and cwnd, both
+  /// measured from SND.UNA
+  pub fn effective_window(&self) -> u32 {
+    self.snd_wnd().min(self.congestion.cwnd())
+  }
+
+  /// New bytes flow and congestion control allow past SND.NXT. The single
+  /// answer to "may we send", for the send path and the persist timer alike
+  pub fn can_send_bytes(&self) -> u32 {
+    self.effective_window().saturating_sub(self.in_flight())
+  }
+
+  /// Whether the persist timer should probe a zero window: data is queued,
+  /// nothing in flight will draw a window update, and no byte may be sent
+  pub fn needs_window_probe(&self, queued: usize) -> bool {
+    #[cfg(feature = "blast")]
+    if self.blast.is_some() {
+      return false;
+    }
+    queued > 0 && self.in_flight() == 0 && self.can_send_bytes() == 0
+  }
+
+  /// New bytes that may be sent at `now`: what `can_send_bytes` allows,
+  /// further capped by the rate limits and pacing
+  pub fn send_budget(&mut self, now: Instant) -> u32 {
+    #[cfg(feature = "blast")]
+    if let Some(bucket) = &mut self.blast {
+      return bucket.available(now).min(u64::from(u32::MAX)) as u32;
+    }
+    let mut budget = self.can_send_bytes() as u64;
+    if let Some(bucket) = &mut self.rate_limit {
+      budget = budget.min(bucket.available(now));
+    }
+    if let Some(pacer) = &mut self.pacer {
+      let cwnd = self.congestion.cwnd();
+      let slow_start = cwnd < self.congestion.ssthresh();
+      pacer.update(cwnd, self.rtt_estimator.srtt(), slow_start, now);
+      budget = budget.min(pacer.available(now));
+    }
+    #[cfg(feature = "std")]
+    if let Some(shaper) = &self.shaper {
+      budget = budget.min(shaper.available(now));
+    }
+    budget as u32
+  }
+
+  /// Option bytes every segment carries: the timestamp option, padded with
+  /// two NOPs, once negotiated
+  pub fn fixed_option_len(&self) -> usize {
+    if self.timestamps {
+      TIMESTAMP_OPTION_LEN
+    } else {
+      0
+    }
+  }
+
+  /// Payload room of a segment carrying `extra_option_len` option bytes on
+  /// top of the fixed ones (e.g. SACK blocks). The MSS counts payload only
+  /// (RFC 6691), so IP and TCP options come out of it to keep within the
+  /// path MTU
+  pub fn payload_room(&self, extra_option_len: usize) -> usize {
+    let options = self.ip_options.len() + self.fixed_option_len() + extra_option_len;
+    (self.mss as usize).saturating_sub(options)
+  }
+
+  pub fn ip_options(&self) -> &[u8] {
+    &self.ip_options
+  }
+
+  /// Carry `options` in every packet sent, padded with end-of-list bytes to
+  /// whole words. Returns false, leaving the options alone, if they do not
+  /// fit an IPv4 header
+  pub fn set_ip_options(&mut self, options: &[u8]) -> bool {
+    let padded = options.len().next_multiple_of(4);
+    if padded > MAX_IP_OPTIONS_LEN {
+      return false;
+    }
+    self.ip_options = options.to_vec();
+    self.ip_options.resize(padded, 0);
+    true
+  }
+
+  /// IPv4 header for a packet to the peer carrying `segment_len` bytes of
+  /// TCP, with this connection's codepoints, TTL and options
+  pub fn ip_header(
+    &self,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    segment_len: usize,
+  ) -> Ipv4Header {
+    let mut ip = Ipv4Header::new(src, dst, segment_len);
+    ip.dscp = self.dscp;
+    ip.ecn = self.ecn;
+    ip.ttl = self.ttl;
+    ip.set_option_bytes(&self.ip_options);
+    ip
+  }
+
+  /// Bytes the sender holds in memory: queued for sending or kept for
+  /// retransmission
+  pub fn send_buffered(&self) -> usize {
+    self.send_queue.queued() + self.retransmit.buffered_bytes()
+  }
+
+  /// Room left under `send_buffer_limit`
+  pub fn send_buffer_free(&self) -> usize {
+    self.send_buffer_limit.saturating_sub(self.send_buffered())
+  }
+
+  /// Queue as much of `data` as the send buffer and the stack's memory
+  /// headroom have room for, returning the count taken. Zero means the
+  /// writer must wait for ACKs to free space
+  pub fn write(&mut self, data: &[u8]) -> usize {
+    let len = data
+      .len()
+      .min(self.send_buffer_free())
+      .min(memory::headroom());
+    #[cfg(feature = "compression")]
+    if self.transforms.has_send() {
+      // The limits count what the application wrote; what the transform
+      // makes of it is queued whole
+      let encoded = self.transforms.encode(&data[..len]);
+      self.send_queue.write(&encoded);
+      return len;
+    }
+    self.send_queue.write(&data[..len]);
+    len
+  }
+
+  /// `write` for data already in a `Bytes`, which is queued and sent from
+  /// without being copied. What does not fit is left for the caller to
+  /// offer again, as `data.slice(taken..)`
+  pub fn write_bytes(&mut self, data: Bytes) -> usize {
+    #[cfg(feature = "compression")]
+    if self.transforms.has_send() {
+      return self.write(&data);
+    }
+    let len = data
+      .len()
+      .min(self.send_buffer_free())
+      .min(memory::headroom());
+    self.send_queue.write_bytes(data.slice(..len));
+    len
+  }
+
+  /// Copy received data into `buf`, undoing the peer's transform if one is
+  /// set. Zero when nothing is readable yet
+  pub fn read(&mut self, buf: &mut [u8]) -> usize {
+    #[cfg(feature = "compression")]
+    if self.transforms.has_recv() || self.transforms.decoded() > 0 {
+      let mut received = vec![0u8; self.recv_stream.readable()];
+      let len = self.recv_stream.read(&mut received);
+      return self.transforms.decode(&received[..len], buf);
+    }
+    self.recv_stream.read(buf)
+  }
+
+  /// Take every readable byte as `Bytes` chunks, the buffers segments
+  /// arrived in, without copying. A transform's output is copied out into
+  /// fresh chunks
+  pub fn read_bytes(&mut self) -> Vec<Bytes> {
+    #[cfg(feature = "compression")]
+    if self.transforms.has_recv() || self.transforms.decoded() > 0 {
+      let mut decoded = vec![0u8; 65536];
+      let mut chunks = Vec::new();
+      loop {
+        let len = self.read(&mut decoded);
+        if len == 0 {
+          return chunks;
+        }
+        chunks.push(Bytes::copy_from_slice(&decoded[..len]));
+      }
+    }
+    self.recv_stream.read_bytes()
+  }
+
+  /// Bytes a write could add that would both fit the send buffer and go
+  /// out at once under the effective window, behind what is already queued
+  pub fn writable_bytes(&self) -> usize {
+    let window =
+      (self.can_send_bytes() as usize).saturating_sub(self.send_queue.queued());
+    window.min(self.send_buffer_free()).min(memory::headroom())
+  }
+
+  /// Whether at least `send_low_watermark` bytes are writable
+  pub fn write_ready(&self) -> bool {
+    self.writable_bytes() >= self.send_low_watermark.max(1)
+  }
+
+  /// Shrink cwnd to the restart window if the connection has been idle for
+  /// longer than the RTO. Returns whether it did
+  pub fn restart_if_idle(&mut self, now: Instant) -> bool {
+    let idle = (now - self.last_activity).as_secs_f64();
+    if idle <= self.rtt_estimator.rto() {
+      return false;
+    }
+    self.congestion.on_idle_restart();
+    true
+  }
+
+  /// Segments to send now out of `send_queue`, advancing SND.NXT past them
+  /// and keeping them for retransmission
+  pub fn next_segments(&mut self, now: Instant) -> Vec<(SeqNumber, Bytes)> {
+    if self.send_queue.queued() > 0 && self.in_flight() == 0 {
+      self.restart_if_idle(now);
+    }
+    let budget = self.send_budget(now);
+    let in_flight = self.in_flight();
+    // Nagle would hold the tail for an ACK blast mode does not wait for
+    #[cfg(feature = "blast")]
+    let in_flight = if self.blast.is_some() && budget as usize >= self.send_queue.queued() {
+      0
+    } else {
+      in_flight
+    };
+    let segments = self.send_queue.segments(
+      self.send_nxt,
+      self.payload_room(0),
+      budget,
+      in_flight,
+    );
+    let rto = self.rtt_estimator.rto();
+    if !segments.is_empty() {
+      self.update_activity(now);
+    }
+    for (seq, payload) in &segments {
+      self.on_send(*seq, payload.len() as u32);
+      self.record_timeseq(now, TimeSeqKind::Sent, *seq, payload.len() as u32);
+      #[cfg(feature = "blast")]
+      if self.blast.is_some() {
+        continue;
+      }
+      self.retransmit.add_segment(
+        PendingSegment {
+          seq: *seq,
+          len: payload.len() as u32,
+          data: payload.clone(),
+          retransmit_count: 0,
+          first_sent: now,
+          kind: SegmentKind::Data,
+        },
+        rto,
+        now,
+      );
+    }
+    segments
+  }
+
+  /// Segments due for retransmission at `now`, after letting congestion
+  /// control react to the timeout. Each expiry doubles the RTO
+  /// (RFC 6298 5.5)
+  pub fn poll_retransmit(&mut self, now: Instant) -> Vec<SegmentRef> {
+    if !self.retransmit.should_retransmit(now) {
+      return Vec::new();
+    }
+    self.on_retransmit_timeout();
+    // A lost SYN or FIN backs off on its own count and leaves the RTO that
+    // data will use alone
+    if !self.retransmit.only_control() {
+      self.rtt_estimator.backoff();
+    }
+    let rto = self.rtt_estimator.rto();
+    let segments = self.retransmit.get_retransmit_segments(rto, now);
+    for segment in &segments {
+      self.count_retransmission(segment.kind);
+      self.record_timeseq(now, TimeSeqKind::Retransmit, segment.seq, segment.len);
+      self.log_event(
+        now,
+        QlogEvent::Retransmit {
+          seq: segment.seq.0,
+          len: segment.len,
+          trigger: RetransmitTrigger::Timeout,
+        },
+      );
+    }
+    segments
+  }
+
+  /// Report the retransmission timeout just handled to the backoff
+  /// observer and return its decision; `Continue` without one
+  pub fn report_backoff(&mut self, now: Instant) -> BackoffDecision {
+    let Some(observer) = &mut self.backoff_observer else {
+      return BackoffDecision::Continue;
+    };
+    let Some(head) = self.retransmit.pending().next() else {
+      return BackoffDecision::Continue;
+    };
+    let progress = BackoffProgress {
+      kind: head.kind,
+      attempt: head.retransmit_count,
+      limit: self.retransmit.limits().limit(head.kind),
+      next_retry: self
+        .retransmit
+        .deadline()
+        .map_or(Duration::ZERO, |deadline| deadline - now),
+      elapsed: now - head.first_sent,
+    };
+    observer(&progress)
+  }
+
+  /// The first unacknowledged segment, once after the third duplicate ACK
+  /// (RFC 5681 3.2)
+  pub fn take_fast_retransmit(&mut self, now: Instant) -> Option<SegmentRef> {
+    if !core::mem::take(&mut self.fast_retransmit) {
+      return None;
+    }
+    let segment = SegmentRef::from(self.retransmit.segment_at(self.snd_una())?);
+    self.count_retransmission(segment.kind);
+    self.record_timeseq(now, TimeSeqKind::Retransmit, segment.seq, segment.len);
+    self.log_event(
+      now,
+      QlogEvent::Retransmit {
+        seq: segment.seq.0,
+        len: segment.len,
+        trigger: RetransmitTrigger::DuplicateAcks,
+      },
+    );
+    Some(segment)
+  }
+
+  /// Count a retransmission, and a SYN or FIN one against its own counter
+  pub fn count_retransmission(&mut self, kind: SegmentKind) {
+    stats::count(&mut self.stats.retransmissions, 1);
+    match kind {
+      SegmentKind::Data => {}
+      SegmentKind::Syn | SegmentKind::SynAck => {
+        stats::count(&mut self.stats.syn_retransmissions, 1);
+      }
+      SegmentKind::Fin => stats::count(&mut self.stats.fin_retransmissions, 1),
+    }
+  }
+
+  /// Whether to send a zero-window probe at `now`. The persist timer runs
+  /// while `needs_window_probe` holds, starting from the RTO and doubling
+  /// after each probe up to `MAX_RTO`
+  pub fn poll_window_probe(&mut self, now: Instant) -> bool {
+    if !self.needs_window_probe(self.send_queue.queued()) {
+      self.persist.cancel();
+      self.persist_backoff = 0;
+      return false;
+    }
+    if self.persist.deadline().is_none() {
+      self.persist.start(now, self.persist_interval());
+      return false;
+    }
+    if !self.persist.is_expired(now) {
+      return false;
+    }
+    self.persist_backoff = (self.persist_backoff + 1).min(16);
+    self.persist.start(now, self.persist_interval());
+    true
+  }
+
+  /// When the persist timer fires, if it is running
+  pub fn window_probe_deadline(&self) -> Option<Instant> {
+    self.persist.deadline()
+  }
+
+  /// Start waiting for the peer's FIN, on entering FIN-WAIT-2
+  pub fn start_fin_wait2(&mut self, now: Instant) {
+    match self.fin_wait2_timeout {
+      Some(timeout) => self.fin_wait2.start(now, timeout),
+      None => self.fin_wait2.cancel(),
+    }
+  }
+
+  /// Whether the peer's FIN is overdue in FIN-WAIT-2
+  pub fn poll_fin_wait2(&self, now: Instant) -> bool {
+    self.state == TcpState::FinWait2 && self.fin_wait2.is_expired(now)
+  }
+
+  /// When `timer` fires, if it is running. Keep-alive only runs while
+  /// the connection can send, the FIN-WAIT-2 timer only in FIN-WAIT-2;
+  /// TIME-WAIT is left to the runtime
+  pub fn timer_deadline(&self, timer: TimerKind) -> Option<Instant> {
+    match timer {
+      TimerKind::Retransmit => self.retransmit.deadline(),
+      TimerKind::Persist => self.persist.deadline(),
+      TimerKind::WindowUpdate => self.ack.window_update_deadline(),
+      TimerKind::Keepalive => match self.state {
+        TcpState::Established | TcpState::CloseWait => self.keepalive.deadline(),
+        _ => None,
+      },
+      TimerKind::FinWait2 => match self.state {
+        TcpState::FinWait2 => self.fin_wait2.deadline(),
+        _ => None,
+      },
+      TimerKind::TimeWait => None,
+    }
+  }
+
+  /// When the first running timer fires
+  pub fn next_deadline(&self) -> Option<Instant> {
+    TimerKind::HELD
+      .into_iter()
+      .filter_map(|timer| self.timer_deadline(timer))
+      .min()
+  }
+
+  fn persist_interval(&self) -> Duration {
+    let secs = self.rtt_estimator.rto() * f64::from(1u32 << self.persist_backoff);
+    Duration::from_secs_f64(secs.min(MAX_RTO))
+  }
+
+  /// Feed an RTT measurement to the estimator and the sampler
+  pub fn on_rtt_sample(&mut self, rtt: Duration) {
+    self.rtt_estimator.update(rtt.as_secs_f64());
+    if let Some(sampler) = &mut self.sampler {
+      sampler.on_rtt(rtt);
+    }
+  }
+
+  /// Send at `rate` bytes per second from `now` on, ignoring cwnd, the
+  /// peer's window and its ACKs, and holding nothing for retransmission.
+  /// Experimental: see `connection::blast`
+  #[cfg(feature = "blast")]
+  pub fn enable_blast(&mut self, rate: u64, now: Instant) {
+    self.blast = Some(TokenBucket::new(rate, now));
+  }
+
+  /// Back to ACK-clocked sending. What blast mode sent is not recovered
+  #[cfg(feature = "blast")]
+  pub fn disable_blast(&mut self) {
+    self.blast = None;
+  }
+
+  /// The blast rate in bytes per second, or `None` outside blast mode
+  #[cfg(feature = "blast")]
+  pub fn blast_rate(&self) -> Option<u64> {
+    self.blast.as_ref().map(TokenBucket::rate)
+  }
+
+  /// Count loss and skip holes in what the peer sends, for receiving a
+  /// blast stream
+  #[cfg(feature = "blast")]
+  pub fn enable_loss_accounting(&mut self) {
+    self.loss = Some(LossAccounting::new());
+  }
+
+  /// Sample throughput every `interval`, starting at `now`
+  pub fn enable_sampling(&mut self, interval: Duration, now: Instant) {
+    self.sampler = Some(ThroughputSampler::new(interval, now, &self.stats));
+  }
+
+  /// The throughput sample for an interval that has ended by `now`
+  pub fn poll_sample(&mut self, now: Instant) -> Option<ThroughputSample> {
+    self.sampler.as_mut()?.poll(now, &self.stats)
+  }
+
+  /// Start recording time-sequence samples, keeping the last `capacity`
+  pub fn enable_timeseq(&mut self, capacity: usize) {
+    self.timeseq = Some(TimeSequence::with_capacity(capacity));
+  }
+
+  fn record_timeseq(
+    &mut self,
+    now: Instant,
+    kind: TimeSeqKind,
+    seq: SeqNumber,
+    len: u32,
+  ) {
+    let cwnd = self.congestion.cwnd();
+    let window = self.snd_wnd();
+    if let Some(timeseq) = &mut self.timeseq {
+      timeseq.record(now, kind, seq, len, cwnd, window);
+    }
+  }
+
+  /// Start the structured event log, keeping the last `capacity` events
+  pub fn enable_qlog(&mut self, capacity: usize) {
+    self.qlog = Some(EventLog::with_capacity(capacity));
+  }
+
+  /// Add `event` to the event log, if enabled
+  pub fn log_event(&mut self, now: Instant, event: QlogEvent) {
+    if let Some(qlog) = &mut self.qlog {
+      qlog.record(now.total_micros(), event);
+    }
+  }
+
+  /// Charge transmitted payload bytes against the rate limits and pacing
+  pub fn on_transmit(&mut self, bytes: usize) {
+    #[cfg(feature = "blast")]
+    if let Some(bucket) = &mut self.blast {
+      bucket.consume(bytes as u64);
+    }
+    if let Some(bucket) = &mut self.rate_limit {
+      bucket.consume(bytes as u64);
+    }
+    if let Some(pacer) = &mut self.pacer {
+      pacer.consume(bytes as u64);
+    }
+    #[cfg(feature = "std")]
+    if let Some(shaper) = &self.shaper {
+      shaper.consume(bytes as u64);
+    }
   }
 }
 
+#[cfg(feature = "std")]
 impl Default for ControlBlock {
   fn default() -> Self {
     Self::new()
@@ -76,6 +1230,8 @@ pub struct RttEstimator {
   srtt: f64,
   rttvar: f64,
   rto: f64,
+  /// Timeouts since the last new acknowledgment, each doubling the RTO
+  backoff: u32,
 }
 
 impl RttEstimator {
@@ -84,6 +1240,7 @@ impl RttEstimator {
       srtt: 0.0,
       rttvar: 0.0,
       rto: 1.0,
+      backoff: 0,
     }
   }
 
@@ -101,15 +1258,42 @@ impl RttEstimator {
     }
 
     self.rto = (self.srtt + 4.0 * self.rttvar).max(1.0);
+    self.backoff = 0;
   }
 
+  /// The RTO, backed off for each timeout since the last new ACK
   pub fn rto(&self) -> f64 {
-    self.rto
+    (self.rto * f64::from(1u32 << self.backoff)).min(MAX_RTO)
+  }
+
+  /// Double the RTO after a retransmission timeout
+  pub fn backoff(&mut self) {
+    self.backoff = (self.backoff + 1).min(16);
+  }
+
+  /// New data was acknowledged: return to the computed RTO
+  pub fn reset_backoff(&mut self) {
+    self.backoff = 0;
   }
 
   pub fn srtt(&self) -> f64 {
     self.srtt
   }
+
+  pub fn rttvar(&self) -> f64 {
+    self.rttvar
+  }
+
+  /// Resume from `srtt` and `rttvar` measured elsewhere; zero means no
+  /// sample yet
+  pub fn restore(&mut self, srtt: f64, rttvar: f64) {
+    *self = Self::new();
+    if srtt > 0.0 {
+      self.srtt = srtt;
+      self.rttvar = rttvar;
+      self.rto = (srtt + 4.0 * rttvar).max(1.0);
+    }
+  }
 }
 
 impl Default for RttEstimator {
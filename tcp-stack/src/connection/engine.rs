@@ -0,0 +1,1020 @@
+//! Sans-IO segment processing
+//!
+//! `Engine` is the TCP state machine of one connection, working on a
+//! borrowed `ControlBlock`. Each entry point takes the current time and
+//! returns the `Action`s that follow from it, so the same processing runs
+//! under the raw socket `TcpConnection`, the packet-queue `TcpStack`, or a
+//! test with no I/O at all. An `Action::StartTimer` is returned whenever one
+//! of the control block's timers is armed or moves; `ControlBlock::
+//! next_deadline` says when the earliest fires. With `ControlBlock::qlog`
+//! enabled, every step also logs what it decided.
+
+use super::action::{Action, CloseReason, TimerKind};
+use super::backoff::BackoffDecision;
+use super::qlog::QlogEvent;
+use super::request::RequestSock;
+use super::{stats, AckDecision, ControlBlock, DropReason, KeepaliveAction, TcpState};
+use crate::flow_control::PacketLimiter;
+use crate::packet::{Ipv4Header, TcpFlags, TcpHeader, TcpOption};
+use crate::reliability::retransmit::{PendingSegment, SegmentKind, SegmentRef};
+use crate::utils::{Instant, SeqNumber};
+use alloc::vec;
+use alloc::vec::Vec;
+use bytes::Bytes;
+use core::net::SocketAddrV4;
+use core::time::Duration;
+
+/// How long a connection stays in TIME-WAIT, as Linux's `TCP_TIMEWAIT_LEN`
+pub const TIME_WAIT_DURATION: Duration = Duration::from_secs(60);
+
+/// ICMP destination unreachable codes that mean the peer will not answer:
+/// protocol and port unreachable, and communication administratively
+/// prohibited
+pub(crate) const ICMP_HARD_ERRORS: [u8; 5] = [2, 3, 9, 10, 13];
+
+/// Segment processing for the connection between `local` and `remote`
+pub struct Engine<'a> {
+  local: SocketAddrV4,
+  remote: SocketAddrV4,
+  control: &'a mut ControlBlock,
+  /// Caps the resets and challenge ACKs sent in answer to the peer
+  replies: Option<&'a mut PacketLimiter>,
+}
+
+impl<'a> Engine<'a> {
+  pub fn new(
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    control: &'a mut ControlBlock,
+  ) -> Self {
+    Self {
+      local,
+      remote,
+      control,
+      replies: None,
+    }
+  }
+
+  /// Send resets and challenge ACKs only as `limiter` allows
+  pub fn with_reply_limiter(mut self, limiter: &'a mut PacketLimiter) -> Self {
+    self.replies = Some(limiter);
+    self
+  }
+
+  pub fn state(&self) -> TcpState {
+    self.control.state
+  }
+
+  /// Header addressed to the peer carrying our current SND.NXT and RCV.NXT,
+  /// and once negotiated a timestamp option stamped at `now` that echoes
+  /// TS.Recent (RFC 7323 3.2). Resets carry none
+  pub fn header(&self, flags: TcpFlags, now: Instant) -> TcpHeader {
+    let mut header = TcpHeader::new(self.local.port(), self.remote.port());
+    header.seq_num = self.control.snd_nxt().0;
+    header.ack_num = self.control.rcv_nxt().0;
+    header.flags = flags;
+    header.window_size = self.control.advertised_window();
+    if self.control.timestamps && !flags.is_rst() {
+      header.set_options(vec![TcpOption::Timestamp {
+        ts_val: self.ts_val(now),
+        ts_ecr: self.control.ts_recent,
+      }]);
+    }
+    header
+  }
+
+  /// Account for a segment received from the peer in `ip`, returning false
+  /// if it must be discarded
+  pub fn on_receive(&mut self, ip: &Ipv4Header) -> bool {
+    if !self.control.accepts_ttl(ip.ttl) {
+      self.record_drop(DropReason::TtlTooLow);
+      return false;
+    }
+    let policy = self.control.ip_options_policy;
+    if !policy.admits(&ip.options) {
+      self.record_drop(DropReason::IpOptions);
+      return false;
+    }
+    let stats = &mut self.control.stats;
+    if policy.strips(&ip.options) {
+      stats::count(&mut stats.ip_options_stripped, 1);
+    }
+    stats::count(&mut stats.segments_received, 1);
+    stats.peer_dscp = ip.dscp;
+    if ip.ecn == Ipv4Header::ECN_CE {
+      stats::count(&mut stats.ecn_ce_received, 1);
+    }
+    true
+  }
+
+  /// Active open: send our SYN and keep it for retransmission
+  pub fn open(&mut self, now: Instant) -> Vec<Action> {
+    self.run(now, |engine, actions| {
+      engine.set_state(TcpState::SynSent, now, actions);
+      actions.push(engine.syn(now));
+      engine.hold_syn(now);
+    })
+  }
+
+  /// Passive open: answer the peer's SYN with a SYN-ACK kept for
+  /// retransmission
+  pub fn accept_syn(&mut self, tcp: &TcpHeader, now: Instant) -> Vec<Action> {
+    self.run(now, |engine, actions| {
+      engine.log_received(tcp, 0, now);
+      engine.control.on_peer_syn(tcp);
+      engine.set_state(TcpState::SynReceived, now, actions);
+      actions.push(engine.syn(now));
+      engine.hold_syn(now);
+    })
+  }
+
+  /// Take over a half-open connection whose SYN-ACK was sent elsewhere:
+  /// SYN-RECEIVED as if `accept_syn` had run when its SYN arrived, with the
+  /// SYN-ACK and its retransmissions accounted for. Nothing is sent
+  pub fn accept_request(&mut self, request: &RequestSock) {
+    self.control.send_seq = request.iss;
+    self.control.resume_send(request.iss, request.iss, 0);
+    self.control.set_recv_buffer(request.recv_buffer);
+    self.control.mss = request.mss;
+    if let Some(profile) = request.profile {
+      self.control.set_profile(profile);
+    }
+    self.control.window_scale = request.offered_scale;
+    let stats = &mut self.control.stats;
+    stats::count(&mut stats.segments_received, 1);
+    stats::count(&mut stats.segments_sent, 1 + u64::from(request.retransmits));
+    if request.ip_options_stripped {
+      stats::count(&mut stats.ip_options_stripped, 1);
+    }
+    // Its SYN-ACK went out long ago
+    self.accept_syn(&request.peer_syn(), request.opened);
+    for _ in 0..request.retransmits {
+      self.control.count_retransmission(SegmentKind::SynAck);
+    }
+    self
+      .control
+      .retransmit
+      .set_retransmit_count(request.iss, request.retransmits);
+  }
+
+  /// RFC 793 "SEGMENT ARRIVES" processing for every state past LISTEN
+  pub fn on_segment(
+    &mut self,
+    tcp: &TcpHeader,
+    payload: &[u8],
+    now: Instant,
+  ) -> Vec<Action> {
+    self.run(now, |engine, actions| {
+      engine.log_received(tcp, payload.len(), now);
+      engine.control.ack.on_peer_segment();
+      engine.control.keepalive.on_peer_segment(now);
+      engine.control.on_peer_options(&tcp.options);
+      match engine.state() {
+        TcpState::Closed | TcpState::Listen => {}
+        TcpState::Established | TcpState::CloseWait if engine.is_pure_ack(tcp, payload) => {
+          if !engine.paws_rejects(tcp, now, actions) {
+            engine.control.on_peer_timestamp(tcp, now);
+            engine.ack_segment(tcp, 0, now, actions);
+          }
+        }
+        TcpState::SynSent => engine.syn_sent(tcp, now, actions),
+        TcpState::SynReceived => {
+          engine.syn_received(tcp, now, actions);
+          if engine.state() == TcpState::Established {
+            engine.synchronized(tcp, payload, now, actions);
+          }
+        }
+        _ => engine.synchronized(tcp, payload, now, actions),
+      }
+    })
+  }
+
+  /// SYN-SENT processing: complete an active or simultaneous open
+  pub fn on_syn_sent(&mut self, tcp: &TcpHeader, now: Instant) -> Vec<Action> {
+    self.run(now, |engine, actions| engine.syn_sent(tcp, now, actions))
+  }
+
+  /// SYN-RECEIVED processing: wait for the ACK of our SYN-ACK
+  pub fn on_syn_received(&mut self, tcp: &TcpHeader, now: Instant) -> Vec<Action> {
+    self.run(now, |engine, actions| {
+      engine.syn_received(tcp, now, actions)
+    })
+  }
+
+  /// Take the acknowledgment and window of a segment carrying
+  /// `payload_len` bytes, moving on once our FIN is acknowledged
+  pub fn on_ack(
+    &mut self,
+    tcp: &TcpHeader,
+    payload_len: usize,
+    now: Instant,
+  ) -> Vec<Action> {
+    self.run(now, |engine, actions| {
+      engine.ack_segment(tcp, payload_len, now, actions)
+    })
+  }
+
+  /// Deliver a segment's payload and act on a FIN that follows it in
+  /// sequence, acknowledging either. Keep-alive probes, which carry an old
+  /// sequence number, draw an ACK too
+  pub fn receive_text(
+    &mut self,
+    tcp: &TcpHeader,
+    payload: &[u8],
+    now: Instant,
+  ) -> Vec<Action> {
+    self.run(now, |engine, actions| {
+      engine.log_received(tcp, payload.len(), now);
+      if engine.paws_rejects(tcp, now, actions) {
+        return;
+      }
+      engine.control.on_peer_timestamp(tcp, now);
+      engine.text(tcp, payload, now, actions);
+    })
+  }
+
+  /// Send what the windows allow and whatever the timers say is due
+  pub fn poll(&mut self, now: Instant) -> Vec<Action> {
+    self.run(now, |engine, actions| engine.poll_timers(now, actions))
+  }
+
+  /// Fire every timer due by `now` in one pass and return the segments
+  /// they send together. Returns nothing, without touching any timer, when
+  /// none is due, so a runtime can tick thousands of connections cheaply
+  pub fn on_tick(&mut self, now: Instant) -> Vec<Action> {
+    if self
+      .control
+      .next_deadline()
+      .is_none_or(|deadline| now < deadline)
+    {
+      return Vec::new();
+    }
+    self.run(now, |engine, actions| engine.expire_timers(now, actions))
+  }
+
+  /// A window update if a read opened the receive window enough. None
+  /// once the peer's FIN is in, as nothing more will come to use it
+  pub fn update_window(&mut self, now: Instant) -> Vec<Action> {
+    self.run(now, |engine, actions| {
+      if !matches!(
+        engine.state(),
+        TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+      ) {
+        return;
+      }
+      let window = engine.control.rcv_wnd();
+      // A full segment from the peer carries the same options ours do, so
+      // only its payload room has to fit
+      let mss = engine.control.payload_room(0) as u32;
+      if engine.control.ack.on_window_update(window, mss, now) == AckDecision::Immediate {
+        actions.push(engine.ack(now));
+      }
+    })
+  }
+
+  /// Our FIN, once the send queue has drained, kept for retransmission
+  pub fn fin(&mut self, now: Instant) -> Vec<Action> {
+    self.run(now, |engine, actions| {
+      let next = match engine.state() {
+        TcpState::Established => TcpState::FinWait1,
+        TcpState::CloseWait => TcpState::LastAck,
+        _ => return,
+      };
+      if engine.control.send_queue.queued() > 0 {
+        return;
+      }
+      let fin = engine.header(TcpFlags::new().with_fin().with_ack(), now);
+      actions.push(Action::SendSegment {
+        header: fin,
+        payload: Bytes::new(),
+      });
+      let seq = engine.control.snd_nxt();
+      engine.control.on_send(seq, 1);
+      engine.hold(seq, 1, Bytes::new(), SegmentKind::Fin, now);
+      engine.set_state(next, now, actions);
+    })
+  }
+
+  /// Abort at the application's request: discard everything unsent or
+  /// unacknowledged and reset the peer if it knows of the connection
+  pub fn abort(&mut self, now: Instant) -> Vec<Action> {
+    self.run(now, |engine, actions| {
+      match engine.state() {
+        TcpState::Closed | TcpState::Listen => return,
+        // Already finished gracefully; the peer has nothing to reset
+        TcpState::TimeWait => {
+          engine.set_state(TcpState::Closed, now, actions);
+          return;
+        }
+        TcpState::SynSent => {}
+        _ => actions.push(engine.reset()),
+      }
+      engine.control.send_queue.clear();
+      engine.close(CloseReason::UserAbort, now, actions);
+    })
+  }
+
+  /// The local address went away: discard everything unsent or
+  /// unacknowledged and close without a reset, which could not be sent
+  pub fn on_network_down(&mut self, now: Instant) -> Vec<Action> {
+    self.run(now, |engine, actions| {
+      match engine.state() {
+        TcpState::Closed | TcpState::Listen => return,
+        // Already finished gracefully
+        TcpState::TimeWait => {
+          engine.set_state(TcpState::Closed, now, actions);
+          return;
+        }
+        _ => {}
+      }
+      engine.control.send_queue.clear();
+      engine.close(CloseReason::NetworkDown, now, actions);
+    })
+  }
+
+  /// An ICMP destination unreachable with `code`, quoting our segment that
+  /// started at `seq`. Hard errors (RFC 1122 4.2.3.9) abort a connection
+  /// still opening; an established one rides them out, as routes recover.
+  /// Quotes outside what we have in flight are forged or stale (RFC 5927)
+  pub fn on_icmp_unreachable(
+    &mut self,
+    code: u8,
+    seq: SeqNumber,
+    now: Instant,
+  ) -> Vec<Action> {
+    self.run(now, |engine, actions| {
+      let in_flight =
+        !seq.before(engine.control.snd_una()) && seq.before(engine.control.snd_nxt());
+      if !in_flight {
+        engine.record_drop(DropReason::OutOfWindow);
+        return;
+      }
+      if !ICMP_HARD_ERRORS.contains(&code) {
+        return;
+      }
+      if matches!(engine.state(), TcpState::SynSent | TcpState::SynReceived) {
+        debug!(
+          "[conn {}] ICMP unreachable code {} from {} during the handshake",
+          engine.control.trace_id, code, engine.remote
+        );
+        engine.close(CloseReason::IcmpError, now, actions);
+      }
+    })
+  }
+
+  /// Our SYN, or SYN-ACK in SYN-RECEIVED, stamped by the timestamp clock
+  /// at `now`. The MSS it offers counts payload only (RFC 6691), so the
+  /// options every later segment carries are not taken off it
+  pub fn syn(&mut self, now: Instant) -> Action {
+    let iss = self.control.send_seq;
+    let ack = (self.state() == TcpState::SynReceived).then(|| self.control.rcv_nxt());
+    // A SYN-ACK carries our scale only if the SYN it answers had one
+    let scale =
+      (ack.is_none() || self.control.window_scaling).then_some(self.control.window_scale);
+    let mut header = syn_header(
+      self.local,
+      self.remote,
+      iss,
+      ack,
+      self.control.mss,
+      self.control.rcv_wnd(),
+      scale,
+    );
+    stamp_ts(&mut header, self.ts_val(now), self.control.ts_recent);
+    if let Some(profile) = self.control.profile {
+      profile.shape_syn(&mut header, ack.is_none() || self.control.timestamps);
+    }
+    self.control.on_send(iss, 1);
+    Action::SendSegment {
+      header,
+      payload: Bytes::new(),
+    }
+  }
+
+  /// TSval of a segment sent at `now`; zero without a timestamp clock
+  fn ts_val(&self, now: Instant) -> u32 {
+    self
+      .control
+      .timestamp_clock
+      .as_ref()
+      .map_or(0, |clock| clock.ts_val(self.local, self.remote, now))
+  }
+
+  /// Acknowledge RCV.NXT, with SACK blocks for anything held out of order
+  pub fn ack(&mut self, now: Instant) -> Action {
+    let mut header = self.header(TcpFlags::new().with_ack(), now);
+    if self.control.sack_permitted {
+      let sack = self
+        .control
+        .ack
+        .sack_options(self.control.recv_stream.reorder());
+      if !sack.is_empty() {
+        let mut options = core::mem::take(&mut header.options);
+        options.extend(sack);
+        header.set_options(options);
+      }
+    }
+    let window = self.control.rcv_wnd();
+    self.control.ack.on_ack_sent(window);
+    Action::SendSegment {
+      header,
+      payload: Bytes::new(),
+    }
+  }
+
+  /// A keep-alive or zero-window probe: an empty ACK carrying SND.NXT - 1
+  pub fn probe(&self, now: Instant) -> Action {
+    let mut header = self.header(TcpFlags::new().with_ack(), now);
+    header.seq_num = (self.control.snd_nxt() - 1).0;
+    Action::SendSegment {
+      header,
+      payload: Bytes::new(),
+    }
+  }
+
+  /// A reset aborting the connection
+  pub fn reset(&self) -> Action {
+    let mut header = self.header(TcpFlags::new().with_rst().with_ack(), Instant::ZERO);
+    header.window_size = 0;
+    Action::SendSegment {
+      header,
+      payload: Bytes::new(),
+    }
+  }
+
+  /// Run one processing step, then report the timers it armed or moved
+  /// and log what it decided
+  fn run(
+    &mut self,
+    now: Instant,
+    step: impl FnOnce(&mut Self, &mut Vec<Action>),
+  ) -> Vec<Action> {
+    let before = TimerKind::HELD.map(|timer| self.control.timer_deadline(timer));
+    let metrics = self.metrics();
+    let mut actions = Vec::new();
+    step(self, &mut actions);
+    for (timer, before) in TimerKind::HELD.into_iter().zip(before) {
+      match self.control.timer_deadline(timer) {
+        Some(deadline) if before != Some(deadline) => {
+          actions.push(Action::StartTimer { timer, deadline });
+        }
+        _ => {}
+      }
+    }
+    if self.control.qlog.is_some() {
+      self.log_actions(&actions, metrics, now);
+    }
+    actions
+  }
+
+  /// cwnd, ssthresh and congestion state, as logged
+  fn metrics(&self) -> QlogEvent {
+    QlogEvent::MetricsUpdated {
+      cwnd: self.control.congestion.cwnd(),
+      ssthresh: self.control.congestion.ssthresh(),
+      state: self.control.congestion.state(),
+    }
+  }
+
+  /// Log the segments sent and timers set by a step, and the congestion
+  /// metrics if it moved them from `metrics`
+  fn log_actions(&mut self, actions: &[Action], metrics: QlogEvent, now: Instant) {
+    for action in actions {
+      let event = match action {
+        Action::SendSegment { header, payload } => QlogEvent::PacketSent {
+          seq: header.seq_num,
+          len: payload.len() as u32,
+          flags: header.flags,
+        },
+        Action::StartTimer { timer, deadline } => QlogEvent::TimerSet {
+          timer: *timer,
+          deadline_micros: deadline.total_micros(),
+        },
+        Action::DeliverData { .. } | Action::Close { .. } => continue,
+      };
+      self.control.log_event(now, event);
+    }
+    let updated = self.metrics();
+    if updated != metrics {
+      self.control.log_event(now, updated);
+    }
+  }
+
+  /// Log a segment from the peer carrying `len` bytes
+  fn log_received(&mut self, tcp: &TcpHeader, len: usize, now: Instant) {
+    self.control.log_event(
+      now,
+      QlogEvent::PacketReceived {
+        seq: tcp.seq_num,
+        ack: tcp.ack_num,
+        len: len as u32,
+        window: tcp.window_size,
+        flags: tcp.flags,
+      },
+    );
+  }
+
+  fn set_state(&mut self, state: TcpState, now: Instant, actions: &mut Vec<Action>) {
+    debug!(
+      "[conn {}] State transition: {:?} -> {:?}",
+      self.control.trace_id, self.control.state, state
+    );
+    let old = self.control.state;
+    self
+      .control
+      .log_event(now, QlogEvent::StateUpdated { old, new: state });
+    self.control.state = state;
+    if state == TcpState::FinWait2 {
+      self.control.start_fin_wait2(now);
+    }
+    if state == TcpState::TimeWait {
+      // Both FINs are acknowledged; only the wait for stray segments is left
+      self.control.close_reason = Some(CloseReason::PeerFin);
+      actions.push(Action::StartTimer {
+        timer: TimerKind::TimeWait,
+        deadline: now + TIME_WAIT_DURATION,
+      });
+    }
+  }
+
+  fn close(&mut self, reason: CloseReason, now: Instant, actions: &mut Vec<Action>) {
+    self.control.retransmit.clear();
+    self.control.close_reason = Some(reason);
+    self.set_state(TcpState::Closed, now, actions);
+    self.control.log_event(now, QlogEvent::Closed { reason });
+    actions.push(Action::Close { reason });
+  }
+
+  /// Whether the reply limiter lets a reset or challenge ACK go out
+  fn may_reply(&mut self, now: Instant) -> bool {
+    match &mut self.replies {
+      Some(limiter) => limiter.allow(*self.remote.ip(), now),
+      None => true,
+    }
+  }
+
+  /// Drop `tcp` if it fails PAWS, acknowledging it as far as the reply
+  /// limiter allows so a peer whose clock did step back resynchronizes
+  /// (RFC 7323 5.3 R1)
+  fn paws_rejects(
+    &mut self,
+    tcp: &TcpHeader,
+    now: Instant,
+    actions: &mut Vec<Action>,
+  ) -> bool {
+    if !self.control.fails_paws(tcp, now) {
+      return false;
+    }
+    self.record_drop(DropReason::PawsFail);
+    if self.may_reply(now) {
+      actions.push(self.ack(now));
+    }
+    true
+  }
+
+  /// Count a discarded segment against this connection and the stack
+  fn record_drop(&mut self, reason: DropReason) {
+    self.control.stats.drops.record(reason);
+    #[cfg(feature = "std")]
+    stats::record_stack_drop(reason);
+  }
+
+  fn syn_sent(&mut self, tcp: &TcpHeader, now: Instant, actions: &mut Vec<Action>) {
+    let iss = self.control.send_seq;
+    let ack = SeqNumber(tcp.ack_num);
+    let acceptable = ack.after(iss) && !ack.after(self.control.snd_nxt());
+    if tcp.flags.is_ack() && !acceptable {
+      if !tcp.flags.is_rst() && self.may_reply(now) {
+        let mut reset = TcpHeader::new(self.local.port(), self.remote.port());
+        reset.seq_num = tcp.ack_num;
+        reset.flags = TcpFlags::new().with_rst();
+        reset.window_size = 0;
+        actions.push(Action::SendSegment {
+          header: reset,
+          payload: Bytes::new(),
+        });
+      }
+      self.record_drop(DropReason::OutOfWindow);
+      return;
+    }
+    if tcp.flags.is_rst() {
+      if tcp.flags.is_ack() {
+        self.close(CloseReason::PeerRst, now, actions);
+      }
+      return;
+    }
+    if !tcp.flags.is_syn() {
+      return;
+    }
+
+    // Takes SND.UNA past our SYN when the segment acknowledges it
+    self.control.on_peer_syn(tcp);
+    if tcp.flags.is_ack() {
+      self.on_syn_acked(now);
+      self.set_state(TcpState::Established, now, actions);
+      actions.push(self.ack(now));
+    } else {
+      // Simultaneous open: our SYN is now retried as a SYN-ACK
+      self.set_state(TcpState::SynReceived, now, actions);
+      let iss = self.control.send_seq;
+      self.control.retransmit.set_kind(iss, SegmentKind::SynAck);
+      actions.push(self.syn(now));
+    }
+  }
+
+  fn syn_received(&mut self, tcp: &TcpHeader, now: Instant, actions: &mut Vec<Action>) {
+    if tcp.flags.is_rst() {
+      self.close(CloseReason::PeerRst, now, actions);
+      return;
+    }
+    if tcp.flags.is_syn() && !tcp.flags.is_ack() {
+      // The peer retransmitted its SYN: our SYN-ACK was lost
+      actions.push(self.syn(now));
+      self.control.count_retransmission(SegmentKind::SynAck);
+      return;
+    }
+    let ack = SeqNumber(tcp.ack_num);
+    if !tcp.flags.is_ack() || !self.control.on_ack(ack) {
+      self.record_drop(DropReason::OutOfWindow);
+      return;
+    }
+    let window = self.control.scaled_peer_window(tcp.window_size);
+    self
+      .control
+      .update_send_window(SeqNumber(tcp.seq_num), ack, window);
+    self.on_syn_acked(now);
+    self.set_state(TcpState::Established, now, actions);
+  }
+
+  /// Stop retransmitting our SYN, timing the handshake if it was sent once
+  fn on_syn_acked(&mut self, now: Instant) {
+    let ack = self.control.send_seq + 1;
+    for syn in self.control.retransmit.acknowledge(ack, now) {
+      if syn.retransmit_count == 0 {
+        self.control.on_rtt_sample(now - syn.first_sent);
+      }
+    }
+    self.control.rtt_estimator.reset_backoff();
+  }
+
+  /// Whether a segment is a bare ACK at RCV.NXT, which is most of what a
+  /// bulk sender hears. It has nothing for the receive stream or window
+  /// and asks for no reply, so only its acknowledgment needs taking. An
+  /// empty segment below RCV.NXT is a keep-alive probe and is not one
+  fn is_pure_ack(&self, tcp: &TcpHeader, payload: &[u8]) -> bool {
+    let flags = tcp.flags;
+    payload.is_empty()
+      && flags.is_ack()
+      && !(flags.is_syn() || flags.is_fin() || flags.is_rst())
+      && SeqNumber(tcp.seq_num) == self.control.rcv_nxt()
+  }
+
+  /// Processing once both sides are synchronized
+  fn synchronized(
+    &mut self,
+    tcp: &TcpHeader,
+    payload: &[u8],
+    now: Instant,
+    actions: &mut Vec<Action>,
+  ) {
+    let seq = SeqNumber(tcp.seq_num);
+    if tcp.flags.is_rst() {
+      if self.state() == TcpState::TimeWait {
+        // RFC 1337: a reset cutting TIME-WAIT short would free the 4-tuple
+        // while old duplicates may still be in the network
+        debug!(
+          "[conn {}] Ignoring RST in TIME-WAIT",
+          self.control.trace_id
+        );
+        return;
+      }
+      let rcv_nxt = self.control.rcv_nxt();
+      if seq.before(rcv_nxt) || !seq.before(rcv_nxt + self.control.rcv_wnd().max(1)) {
+        self.record_drop(DropReason::OutOfWindow);
+        return;
+      }
+      if seq != rcv_nxt {
+        // RFC 5961 3.2: only a reset at RCV.NXT is taken. Anywhere else in
+        // the window it may be blind, so challenge it with an ACK that a
+        // real peer answers with a reset at RCV.NXT
+        if self.may_reply(now) {
+          actions.push(self.ack(now));
+        }
+        return;
+      }
+      self.close(CloseReason::PeerRst, now, actions);
+      return;
+    }
+    if self.paws_rejects(tcp, now, actions) {
+      return;
+    }
+    if tcp.flags.is_syn() {
+      // A SYN-ACK retransmitted because our ACK was lost, or a stale SYN
+      // (RFC 5961 4.2): either way the answer is an ACK
+      if self.may_reply(now) {
+        actions.push(self.ack(now));
+      }
+      return;
+    }
+    if !tcp.flags.is_ack() {
+      return;
+    }
+    self.control.on_peer_timestamp(tcp, now);
+    self.ack_segment(tcp, payload.len(), now, actions);
+    if self.state() != TcpState::Closed {
+      self.text(tcp, payload, now, actions);
+    }
+  }
+
+  fn ack_segment(
+    &mut self,
+    tcp: &TcpHeader,
+    payload_len: usize,
+    now: Instant,
+    actions: &mut Vec<Action>,
+  ) {
+    let window = self.control.scaled_peer_window(tcp.window_size);
+    let seq = SeqNumber(tcp.seq_num);
+    let ack = SeqNumber(tcp.ack_num);
+    self.control.on_dsack(ack, &tcp.options);
+    let acked = self.control.process_ack(seq, ack, window, payload_len, now);
+    self.control.report_ack(ack, acked, &tcp.options, now);
+    if acked == 0 {
+      return;
+    }
+    if self.control.snd_una() != self.control.snd_nxt() {
+      return;
+    }
+    match self.state() {
+      TcpState::FinWait1 => self.set_state(TcpState::FinWait2, now, actions),
+      TcpState::Closing => self.set_state(TcpState::TimeWait, now, actions),
+      TcpState::LastAck => self.close(CloseReason::PeerFin, now, actions),
+      _ => {}
+    }
+  }
+
+  fn text(
+    &mut self,
+    tcp: &TcpHeader,
+    payload: &[u8],
+    now: Instant,
+    actions: &mut Vec<Action>,
+  ) {
+    match self.state() {
+      TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 => {}
+      TcpState::TimeWait if tcp.flags.is_fin() => {
+        // A retransmission of the FIN already taken means the peer did not
+        // get our ACK of it: restart the wait. Any other FIN is an old
+        // duplicate and leaves the timer alone
+        let fin = SeqNumber(tcp.seq_num) + payload.len() as u32;
+        if fin + 1 == self.control.rcv_nxt() {
+          self.set_state(TcpState::TimeWait, now, actions);
+          actions.push(self.ack(now));
+        }
+        return;
+      }
+      _ => return,
+    }
+    let seq = SeqNumber(tcp.seq_num);
+    #[cfg(feature = "blast")]
+    if let Some(loss) = &mut self.control.loss {
+      let rcv_nxt = self.control.recv_stream.rcv_nxt();
+      let rcv_nxt = loss.on_segment(seq, payload.len() as u32, rcv_nxt);
+      self.control.recv_stream.set_rcv_nxt(rcv_nxt);
+    }
+    if !payload.is_empty() {
+      let duplicate = self
+        .control
+        .recv_stream
+        .duplicate_bytes(seq, payload.len() as u32);
+      stats::count(
+        &mut self.control.stats.duplicate_bytes_received,
+        u64::from(duplicate),
+      );
+      // Bytes past the window's right edge are dropped; the ACK below
+      // still goes out, so the peer's ACK clock keeps running
+      let right_edge = self.control.rcv_nxt() + self.control.rcv_wnd();
+      let room = if right_edge.after(seq) {
+        right_edge.diff(seq) as usize
+      } else {
+        0
+      };
+      if room < payload.len() {
+        self.record_drop(DropReason::BufferFull);
+      }
+      let accepted = &payload[..room.min(payload.len())];
+      let len = self
+        .control
+        .recv_stream
+        .push(seq, Bytes::copy_from_slice(accepted));
+      if len > 0 {
+        actions.push(Action::DeliverData { len });
+      }
+    }
+    let rcv_nxt = self.control.rcv_nxt();
+    if tcp.flags.is_fin() && seq + payload.len() as u32 == rcv_nxt {
+      self.control.recv_stream.set_rcv_nxt(rcv_nxt + 1);
+      let next = match self.state() {
+        TcpState::Established => TcpState::CloseWait,
+        TcpState::FinWait1 => TcpState::Closing,
+        _ => TcpState::TimeWait,
+      };
+      self.set_state(next, now, actions);
+    } else if payload.is_empty() && !seq.before(rcv_nxt) {
+      return;
+    }
+    actions.push(self.ack(now));
+  }
+
+  fn poll_timers(&mut self, now: Instant, actions: &mut Vec<Action>) {
+    match self.state() {
+      TcpState::Closed | TcpState::Listen | TcpState::TimeWait => return,
+      TcpState::Established | TcpState::CloseWait => {
+        for (seq, payload) in self.control.next_segments(now) {
+          let mut header = self.header(TcpFlags::new().with_ack().with_psh(), now);
+          header.seq_num = seq.0;
+          actions.push(Action::SendSegment { header, payload });
+        }
+      }
+      _ => {}
+    }
+    if let Some(segment) = self.control.take_fast_retransmit(now) {
+      actions.push(self.resend(segment, now));
+    }
+    self.expire_timers(now, actions);
+  }
+
+  /// Retransmissions, probes, window updates and keep-alives due by `now`
+  fn expire_timers(&mut self, now: Instant, actions: &mut Vec<Action>) {
+    if matches!(
+      self.state(),
+      TcpState::Closed | TcpState::Listen | TcpState::TimeWait
+    ) {
+      return;
+    }
+    if self.control.qlog.is_some() {
+      for timer in TimerKind::HELD {
+        if self
+          .control
+          .timer_deadline(timer)
+          .is_some_and(|at| at <= now)
+        {
+          self
+            .control
+            .log_event(now, QlogEvent::TimerExpired { timer });
+        }
+      }
+    }
+    let segments = self.control.poll_retransmit(now);
+    let exhausted = self.control.retransmit.take_exhausted();
+    if !segments.is_empty()
+      && exhausted.is_none()
+      && self.control.report_backoff(now) == BackoffDecision::Abort
+    {
+      debug!(
+        "[conn {}] Backoff observer gave up",
+        self.control.trace_id
+      );
+      if self.state() != TcpState::SynSent {
+        actions.push(self.reset());
+      }
+      self.control.send_queue.clear();
+      self.close(CloseReason::UserAbort, now, actions);
+      return;
+    }
+    for segment in segments {
+      actions.push(self.resend(segment, now));
+    }
+    if let Some(kind) = exhausted {
+      debug!(
+        "[conn {}] Giving up after {} retransmissions of {:?}",
+        self.control.trace_id,
+        self.control.retransmit.limits().limit(kind),
+        kind
+      );
+      self.close(CloseReason::RetransmitLimit, now, actions);
+      return;
+    }
+    if matches!(self.state(), TcpState::SynSent | TcpState::SynReceived) {
+      return;
+    }
+    if self.control.poll_window_probe(now) {
+      actions.push(self.probe(now));
+    }
+    if self.control.ack.poll_window_update(now) {
+      actions.push(self.ack(now));
+    }
+    if self.control.poll_fin_wait2(now) {
+      debug!(
+        "[conn {}] No FIN from the peer in FIN-WAIT-2",
+        self.control.trace_id
+      );
+      actions.push(self.reset());
+      self.close(CloseReason::Timeout, now, actions);
+      return;
+    }
+    if matches!(self.state(), TcpState::Established | TcpState::CloseWait) {
+      match self.control.keepalive.poll(now) {
+        KeepaliveAction::None => {}
+        KeepaliveAction::Probe => actions.push(self.probe(now)),
+        KeepaliveAction::PeerUnreachable => {
+          self.close(CloseReason::Timeout, now, actions);
+        }
+      }
+    }
+  }
+
+  /// Keep a segment for retransmission
+  fn hold(
+    &mut self,
+    seq: SeqNumber,
+    len: u32,
+    data: Bytes,
+    kind: SegmentKind,
+    now: Instant,
+  ) {
+    let rto = self.control.rtt_estimator.rto();
+    self.control.retransmit.add_segment(
+      PendingSegment {
+        seq,
+        len,
+        data,
+        retransmit_count: 0,
+        first_sent: now,
+        kind,
+      },
+      rto,
+      now,
+    );
+  }
+
+  fn hold_syn(&mut self, now: Instant) {
+    let iss = self.control.send_seq;
+    let kind = match self.state() {
+      TcpState::SynReceived => SegmentKind::SynAck,
+      _ => SegmentKind::Syn,
+    };
+    if self.control.retransmit.segment_at(iss).is_none() {
+      self.hold(iss, 1, Bytes::new(), kind, now);
+    }
+  }
+
+  /// Retransmit a segment, restoring the SYN or FIN it carried
+  fn resend(&mut self, segment: SegmentRef, now: Instant) -> Action {
+    if matches!(segment.kind, SegmentKind::Syn | SegmentKind::SynAck) {
+      return self.syn(now);
+    }
+    let mut flags = TcpFlags::new().with_ack();
+    if segment.len as usize > segment.data.len() {
+      flags = flags.with_fin();
+    }
+    let mut header = self.header(flags, now);
+    header.seq_num = segment.seq.0;
+    Action::SendSegment {
+      header,
+      payload: segment.data,
+    }
+  }
+}
+
+/// A SYN from `local` to `remote`, or a SYN-ACK when it acknowledges `ack`,
+/// offering `mss` and the window scale `scale` if any. The window of a SYN
+/// is never scaled
+pub(crate) fn syn_header(
+  local: SocketAddrV4,
+  remote: SocketAddrV4,
+  iss: SeqNumber,
+  ack: Option<SeqNumber>,
+  mss: u16,
+  window: u32,
+  scale: Option<u8>,
+) -> TcpHeader {
+  let mut header = TcpHeader::syn(local.port(), remote.port(), iss.0, mss);
+  header.window_size = window.min(u16::MAX as u32) as u16;
+  header
+    .options
+    .retain(|option| !matches!(option, TcpOption::WindowScale(_)));
+  if let Some(ack) = ack {
+    header.flags = header.flags.with_ack();
+    header.ack_num = ack.0;
+  }
+  if let Some(scale) = scale {
+    header.options.push(TcpOption::WindowScale(scale));
+  }
+  header
+}
+
+/// Set the TSval and TSecr of the timestamp option `header` carries, if
+/// any. A SYN echoes zero, a SYN-ACK the TSval of the SYN it answers
+pub(crate) fn stamp_ts(header: &mut TcpHeader, ts_val: u32, ts_ecr: u32) {
+  for option in &mut header.options {
+    if let TcpOption::Timestamp {
+      ts_val: val,
+      ts_ecr: ecr,
+    } = option
+    {
+      *val = ts_val;
+      *ecr = ts_ecr;
+    }
+  }
+}
@@ -0,0 +1,341 @@
+//! Expectations on the segments an engine sends
+//!
+//! `SegmentTrace` collects the `Action`s an `Engine` returns, stamped with
+//! the time of the call, and `expect` checks the segments among them in the
+//! order they were sent:
+//!
+//! ```ignore
+//! let mut trace = SegmentTrace::new(Instant::ZERO);
+//! trace.record(now, engine.open(now));
+//! trace.expect(Expect::syn().mss().window_scale());
+//! trace.record(later, engine.on_segment(&syn_ack, &[], later));
+//! trace.expect(Expect::ack().acking(IRS + 1).within(Duration::from_millis(200)));
+//! trace.done();
+//! ```
+//!
+//! An `Expect` names the flags a segment carries, in packetdrill's
+//! notation where it prints one, and optionally its options, sequence
+//! number, acknowledgment, window and payload length. `within` bounds the
+//! time since the segment matched before it, or since the trace began. A
+//! segment that does not match panics at the caller with the expectation
+//! and what was sent instead.
+
+use super::Action;
+use crate::packet::{TcpFlags, TcpHeader, TcpOption};
+use crate::utils::Instant;
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+
+/// Flags that decide what kind of segment one is; PSH, URG and the ECN
+/// bits are checked only when asked for
+const KIND_FLAGS: u8 = TcpFlags::SYN | TcpFlags::ACK | TcpFlags::FIN | TcpFlags::RST;
+
+/// A segment the engine sent, when it sent it
+#[derive(Debug, Clone)]
+pub struct TracedSegment {
+  pub at: Instant,
+  pub header: TcpHeader,
+  pub len: usize,
+}
+
+impl fmt::Display for TracedSegment {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let header = &self.header;
+    write!(
+      f,
+      "{} seq {} ack {} win {} len {}",
+      flag_string(header.flags),
+      header.seq_num,
+      header.ack_num,
+      header.window_size,
+      self.len
+    )?;
+    for option in &header.options {
+      match option {
+        TcpOption::MaximumSegmentSize(mss) => write!(f, " mss {mss}")?,
+        TcpOption::WindowScale(scale) => write!(f, " ws {scale}")?,
+        TcpOption::SackPermitted => f.write_str(" sackOK")?,
+        TcpOption::Sack { left, right } => write!(f, " sack {left}:{right}")?,
+        TcpOption::Timestamp { ts_val, ts_ecr } => write!(f, " ts {ts_val} {ts_ecr}")?,
+        TcpOption::Mptcp(option) => write!(f, " mptcp {}", option.subtype())?,
+        TcpOption::EndOfList | TcpOption::NoOperation => {}
+      }
+    }
+    write!(f, " at {:?}", Duration::from_micros(self.at.total_micros()))
+  }
+}
+
+/// What the next segment sent should look like
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Expect {
+  /// Bits that must be set. Of the `KIND_FLAGS`, those not named here
+  /// must be clear
+  flags: u8,
+  mss: Option<Option<u16>>,
+  window_scale: Option<Option<u8>>,
+  sack_permitted: bool,
+  timestamps: bool,
+  seq: Option<u32>,
+  ack: Option<u32>,
+  window: Option<u16>,
+  len: Option<usize>,
+  within: Option<Duration>,
+}
+
+impl Expect {
+  fn with_flags(flags: u8) -> Self {
+    Self {
+      flags,
+      ..Self::default()
+    }
+  }
+
+  /// A SYN of an active open
+  pub fn syn() -> Self {
+    Self::with_flags(TcpFlags::SYN)
+  }
+
+  pub fn syn_ack() -> Self {
+    Self::with_flags(TcpFlags::SYN | TcpFlags::ACK)
+  }
+
+  /// An ACK carrying neither SYN, FIN nor RST, with or without data
+  pub fn ack() -> Self {
+    Self::with_flags(TcpFlags::ACK)
+  }
+
+  /// A FIN, which always acknowledges once synchronized
+  pub fn fin() -> Self {
+    Self::with_flags(TcpFlags::FIN | TcpFlags::ACK)
+  }
+
+  /// A reset, with or without an acknowledgment
+  pub fn rst() -> Self {
+    Self::with_flags(TcpFlags::RST)
+  }
+
+  /// Carrying an MSS option of any value
+  pub fn mss(mut self) -> Self {
+    self.mss = Some(None);
+    self
+  }
+
+  pub fn mss_of(mut self, mss: u16) -> Self {
+    self.mss = Some(Some(mss));
+    self
+  }
+
+  /// Carrying a window scale option of any value
+  pub fn window_scale(mut self) -> Self {
+    self.window_scale = Some(None);
+    self
+  }
+
+  pub fn window_scale_of(mut self, scale: u8) -> Self {
+    self.window_scale = Some(Some(scale));
+    self
+  }
+
+  pub fn sack_permitted(mut self) -> Self {
+    self.sack_permitted = true;
+    self
+  }
+
+  pub fn timestamps(mut self) -> Self {
+    self.timestamps = true;
+    self
+  }
+
+  /// With PSH set
+  pub fn push(mut self) -> Self {
+    self.flags |= TcpFlags::PSH;
+    self
+  }
+
+  pub fn seq(mut self, seq: u32) -> Self {
+    self.seq = Some(seq);
+    self
+  }
+
+  /// Acknowledging everything before `ack`
+  pub fn acking(mut self, ack: u32) -> Self {
+    self.ack = Some(ack);
+    self
+  }
+
+  pub fn window(mut self, window: u16) -> Self {
+    self.window = Some(window);
+    self
+  }
+
+  /// Carrying `len` bytes of payload
+  pub fn len(mut self, len: usize) -> Self {
+    self.len = Some(len);
+    self
+  }
+
+  /// Sent no later than `limit` after the segment matched before it
+  pub fn within(mut self, limit: Duration) -> Self {
+    self.within = Some(limit);
+    self
+  }
+
+  /// Whether `segment`, sent `elapsed` after the previous match, is one
+  pub fn matches(&self, segment: &TracedSegment, elapsed: Duration) -> bool {
+    let header = &segment.header;
+    let kind = header.flags.0 & KIND_FLAGS;
+    let want = self.flags & KIND_FLAGS;
+    // A reset may or may not acknowledge
+    let kind_match =
+      kind == want || want == TcpFlags::RST && kind == TcpFlags::RST | TcpFlags::ACK;
+    let flags_match = kind_match && header.flags.0 & self.flags == self.flags;
+    let options = &header.options;
+    let mss_match = self.mss.is_none_or(|want| {
+      options.iter().any(|option| {
+        matches!(option, TcpOption::MaximumSegmentSize(mss) if want.is_none_or(|want| want == *mss))
+      })
+    });
+    let scale_match = self.window_scale.is_none_or(|want| {
+      options.iter().any(|option| {
+        matches!(option, TcpOption::WindowScale(scale) if want.is_none_or(|want| want == *scale))
+      })
+    });
+    let sack_match = !self.sack_permitted || options.contains(&TcpOption::SackPermitted);
+    let ts_match = !self.timestamps
+      || options
+        .iter()
+        .any(|option| matches!(option, TcpOption::Timestamp { .. }));
+    flags_match
+      && mss_match
+      && scale_match
+      && sack_match
+      && ts_match
+      && self.seq.is_none_or(|seq| seq == header.seq_num)
+      && self.ack.is_none_or(|ack| ack == header.ack_num)
+      && self.window.is_none_or(|window| window == header.window_size)
+      && self.len.is_none_or(|len| len == segment.len)
+      && self.within.is_none_or(|limit| elapsed <= limit)
+  }
+}
+
+impl fmt::Display for Expect {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&flag_string(TcpFlags(self.flags)))?;
+    if let Some(mss) = self.mss {
+      match mss {
+        Some(mss) => write!(f, " with mss {mss}")?,
+        None => f.write_str(" with mss")?,
+      }
+    }
+    if let Some(scale) = self.window_scale {
+      match scale {
+        Some(scale) => write!(f, " with ws {scale}")?,
+        None => f.write_str(" with ws")?,
+      }
+    }
+    if self.sack_permitted {
+      f.write_str(" with sackOK")?;
+    }
+    if self.timestamps {
+      f.write_str(" with ts")?;
+    }
+    if let Some(seq) = self.seq {
+      write!(f, " seq {seq}")?;
+    }
+    if let Some(ack) = self.ack {
+      write!(f, " acking {ack}")?;
+    }
+    if let Some(window) = self.window {
+      write!(f, " win {window}")?;
+    }
+    if let Some(len) = self.len {
+      write!(f, " len {len}")?;
+    }
+    if let Some(limit) = self.within {
+      write!(f, " within {limit:?}")?;
+    }
+    Ok(())
+  }
+}
+
+/// The segments an engine sent, checked in order against `Expect`s
+#[derive(Debug, Clone)]
+pub struct SegmentTrace {
+  unmatched: VecDeque<TracedSegment>,
+  /// When the last segment matched, or the trace began
+  last_match: Instant,
+}
+
+impl SegmentTrace {
+  /// An empty trace, timing `within` from `start` until the first match
+  pub fn new(start: Instant) -> Self {
+    Self {
+      unmatched: VecDeque::new(),
+      last_match: start,
+    }
+  }
+
+  /// Take the segments among `actions`, returned by an engine call at
+  /// `now`. The actions are handed back for anything else to look at
+  pub fn record(&mut self, now: Instant, actions: Vec<Action>) -> Vec<Action> {
+    for action in &actions {
+      if let Action::SendSegment { header, payload } = action {
+        self.unmatched.push_back(TracedSegment {
+          at: now,
+          header: header.clone(),
+          len: payload.len(),
+        });
+      }
+    }
+    actions
+  }
+
+  /// Segments recorded and not yet matched, oldest first
+  pub fn unmatched(&self) -> impl Iterator<Item = &TracedSegment> {
+    self.unmatched.iter()
+  }
+
+  /// Take the next segment sent, panicking unless it is `expect`
+  #[track_caller]
+  pub fn expect(&mut self, expect: Expect) -> TracedSegment {
+    let Some(segment) = self.unmatched.pop_front() else {
+      panic!("expected {expect}, but nothing more was sent");
+    };
+    let elapsed = segment.at - self.last_match;
+    if !expect.matches(&segment, elapsed) {
+      panic!("expected {expect}, got {segment}");
+    }
+    self.last_match = segment.at;
+    segment
+  }
+
+  /// Panic if anything sent is left unmatched
+  #[track_caller]
+  pub fn done(&self) {
+    if self.unmatched.is_empty() {
+      return;
+    }
+    let rest: Vec<String> = self.unmatched.iter().map(|segment| segment.to_string()).collect();
+    panic!("sent more than expected: {}", rest.join("; "));
+  }
+}
+
+/// Flags as packetdrill prints them: `S`, `F`, `R`, `P` and `.` for ACK
+fn flag_string(flags: TcpFlags) -> String {
+  let mut out = String::new();
+  for (bit, letter) in [
+    (TcpFlags::SYN, 'S'),
+    (TcpFlags::FIN, 'F'),
+    (TcpFlags::RST, 'R'),
+    (TcpFlags::PSH, 'P'),
+    (TcpFlags::ACK, '.'),
+  ] {
+    if flags.0 & bit != 0 {
+      out.push(letter);
+    }
+  }
+  out
+}
@@ -0,0 +1,47 @@
+//! Transport feedback for external components
+//!
+//! An `AckObserver` set on a connection hears of every acceptable ACK from
+//! the peer once the connection has taken it: the bytes it newly
+//! acknowledged, the RTT sample it gave if any, and the SACK blocks it
+//! carried. An application-level scheduler can pace what it hands over by
+//! it, and research instrumentation can record it, without patching the
+//! crate. Duplicate ACKs are reported with nothing acknowledged; ACKs for
+//! data never sent are not reported at all.
+//!
+//! Any `FnMut(&AckFeedback)` closure is an observer.
+
+use crate::utils::{Instant, SeqNumber};
+use alloc::boxed::Box;
+use core::time::Duration;
+
+/// What one ACK told the sender
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AckFeedback<'a> {
+  /// The cumulative acknowledgment
+  pub ack: SeqNumber,
+  /// Sequence space newly acknowledged, zero for a duplicate
+  pub acked_bytes: u32,
+  /// The RTT sample taken, from the newest segment acknowledged that was
+  /// never retransmitted (Karn)
+  pub rtt: Option<Duration>,
+  /// The SACK blocks carried, as `(left, right)` edges
+  pub sacked: &'a [(SeqNumber, SeqNumber)],
+  /// Sequence space still unacknowledged after this ACK
+  pub in_flight: u32,
+  pub cwnd: u32,
+  pub now: Instant,
+}
+
+/// Told of every ACK a connection takes
+pub trait AckObserver: Send {
+  fn on_ack(&mut self, feedback: &AckFeedback<'_>);
+}
+
+impl<F: FnMut(&AckFeedback<'_>) + Send> AckObserver for F {
+  fn on_ack(&mut self, feedback: &AckFeedback<'_>) {
+    self(feedback)
+  }
+}
+
+/// An observer as a connection holds it
+pub type BoxedAckObserver = Box<dyn AckObserver>;
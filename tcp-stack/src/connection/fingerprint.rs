@@ -0,0 +1,107 @@
+//! Operating system fingerprint profiles
+//!
+//! Passive fingerprinting tools such as p0f tell stacks apart by their SYNs:
+//! the order and padding of the options, the window, the window scale,
+//! whether timestamps are offered and the IP TTL. An `OsProfile` makes the
+//! SYNs and SYN-ACKs a connection sends look like those of a common
+//! desktop or server system, for measurements and security research where
+//! the wire signature matters.
+//!
+//! The profile shapes the handshake only. Window scaling and timestamps
+//! are negotiated as the profile offers them, so the rest of the
+//! connection stays consistent with its SYN. The TSval is still that of
+//! the connection's timestamp clock.
+
+use crate::packet::{OptionPadding, TcpHeader, TcpOption};
+use alloc::vec::Vec;
+
+/// A system whose handshake signature a connection takes on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OsProfile {
+  /// Linux 5.x and later: MSS, SACK permitted, timestamps, NOP, window
+  /// scale 7; window 64240, TTL 64
+  Linux,
+  /// Windows 10 and 11: MSS, NOP, window scale 8, NOP, NOP, SACK
+  /// permitted and no timestamps; window 64240, TTL 128
+  Windows,
+  /// macOS: MSS, NOP, window scale 6, NOP, NOP, timestamps, SACK
+  /// permitted, EOL; window 65535, TTL 64
+  MacOs,
+}
+
+impl OsProfile {
+  /// IP TTL of every packet sent
+  pub fn ttl(self) -> u8 {
+    match self {
+      OsProfile::Linux | OsProfile::MacOs => 64,
+      OsProfile::Windows => 128,
+    }
+  }
+
+  /// Window of the SYN and SYN-ACK
+  pub fn syn_window(self) -> u16 {
+    match self {
+      OsProfile::Linux | OsProfile::Windows => 64240,
+      OsProfile::MacOs => 65535,
+    }
+  }
+
+  /// Window scale offered
+  pub fn window_scale(self) -> u8 {
+    match self {
+      OsProfile::Linux => 7,
+      OsProfile::Windows => 8,
+      OsProfile::MacOs => 6,
+    }
+  }
+
+  /// Whether the SYN offers timestamps
+  pub fn timestamps(self) -> bool {
+    !matches!(self, OsProfile::Windows)
+  }
+
+  /// Option kinds of a SYN in the order sent, NOPs included
+  pub fn syn_layout(self) -> &'static [u8] {
+    const MSS: u8 = TcpOption::KIND_MSS;
+    const NOP: u8 = TcpOption::KIND_NOP;
+    const WS: u8 = TcpOption::KIND_WINDOW_SCALE;
+    const SACK_OK: u8 = TcpOption::KIND_SACK_PERMITTED;
+    const TS: u8 = TcpOption::KIND_TIMESTAMP;
+    match self {
+      OsProfile::Linux => &[MSS, SACK_OK, TS, NOP, WS],
+      OsProfile::Windows => &[MSS, NOP, WS, NOP, NOP, SACK_OK],
+      OsProfile::MacOs => &[MSS, NOP, WS, NOP, NOP, TS, SACK_OK],
+    }
+  }
+
+  /// Lay out the options of `syn`, a SYN or SYN-ACK, as the profile does
+  /// and give it the profile's window. Options the header lacks are left
+  /// out with the NOPs before them. Timestamps are dropped unless the
+  /// profile offers them and `timestamps` allows them, which for a SYN-ACK
+  /// it should only if the SYN carried them (RFC 7323 3.2)
+  pub fn shape_syn(self, syn: &mut TcpHeader, timestamps: bool) {
+    let timestamps = timestamps && self.timestamps();
+    let mut options = Vec::new();
+    let mut nops = 0;
+    for &kind in self.syn_layout() {
+      if kind == TcpOption::KIND_NOP {
+        nops += 1;
+        continue;
+      }
+      let found = syn
+        .options
+        .iter()
+        .find(|option| option.kind() == kind)
+        .filter(|_| kind != TcpOption::KIND_TIMESTAMP || timestamps);
+      if let Some(option) = found {
+        options.extend(core::iter::repeat_n(TcpOption::NoOperation, nops));
+        options.push(option.clone());
+      }
+      nops = 0;
+    }
+    syn.options = options;
+    syn.padding = OptionPadding::EndOfList;
+    syn.window_size = self.syn_window();
+  }
+}
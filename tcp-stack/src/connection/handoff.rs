@@ -0,0 +1,205 @@
+//! Handing connections to another process
+//!
+//! For a zero-downtime restart the old process sends its raw socket's two
+//! descriptors (`SCM_RIGHTS`) and a snapshot of each connection over a Unix
+//! socket, then drops the connections; dropping sends nothing to the peers.
+//! The new process takes the socket and snapshots with `recv_handoff` and
+//! resumes the connections from there.
+//!
+//! On the wire: one message carrying the descriptors with a header of
+//! `MAGIC` and the snapshot count, then each `ConnectionSnapshot::encode`d
+//! snapshot behind its length.
+
+use super::{ConnectionSnapshot, TcpConnection};
+use crate::error::Result;
+use crate::socket::RawSocket;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+/// First bytes of a handoff
+const MAGIC: [u8; 4] = *b"TCPH";
+
+/// Descriptors in a handoff: the raw socket's send and receive sockets
+const FD_COUNT: usize = 2;
+
+/// `recvmsg` flags for the descriptor message
+#[cfg(not(target_vendor = "apple"))]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(target_vendor = "apple")]
+const RECV_FLAGS: libc::c_int = 0;
+
+/// Largest encoded snapshot accepted, bounding what a bad peer can make us
+/// allocate
+const MAX_SNAPSHOT_LEN: usize = 64 << 20;
+
+/// What `recv_handoff` takes over
+pub struct Handoff {
+  pub socket: RawSocket,
+  pub snapshots: Vec<ConnectionSnapshot>,
+}
+
+impl Handoff {
+  /// Restore every connection, each over its own handle on the socket
+  pub fn resume(self) -> Result<Vec<TcpConnection>> {
+    self
+      .snapshots
+      .iter()
+      .map(|snapshot| TcpConnection::restore(self.socket.try_clone()?, snapshot))
+      .collect()
+  }
+}
+
+/// Send `socket` and `connections` to the process at the other end of
+/// `channel`. Connections not yet established, or already closed, are left
+/// out
+pub fn send_handoff(
+  channel: &mut UnixStream,
+  socket: &RawSocket,
+  connections: &[&TcpConnection],
+) -> io::Result<()> {
+  let snapshots: Vec<_> = connections
+    .iter()
+    .filter_map(|conn| conn.snapshot())
+    .collect();
+  let mut header = MAGIC.to_vec();
+  header.extend_from_slice(&(snapshots.len() as u32).to_be_bytes());
+  let fds = socket.as_fds().map(|fd| fd.as_raw_fd());
+  send_fds(channel, &fds, &header)?;
+
+  for snapshot in &snapshots {
+    let encoded = snapshot.encode();
+    channel.write_all(&(encoded.len() as u32).to_be_bytes())?;
+    channel.write_all(&encoded)?;
+  }
+  channel.flush()
+}
+
+/// Take over the socket and connections sent with `send_handoff`
+pub fn recv_handoff(channel: &mut UnixStream) -> io::Result<Handoff> {
+  let mut header = [0u8; 8];
+  let fds = recv_fds(channel, &mut header)?;
+  let Ok([send_fd, recv_fd]) = <[OwnedFd; FD_COUNT]>::try_from(fds) else {
+    return Err(invalid("handoff without the socket's descriptors"));
+  };
+  if header[..4] != MAGIC {
+    return Err(invalid("not a connection handoff"));
+  }
+  let count = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+
+  let mut snapshots = Vec::new();
+  for _ in 0..count {
+    let mut len = [0u8; 4];
+    channel.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_SNAPSHOT_LEN {
+      return Err(invalid("snapshot too large"));
+    }
+    let mut encoded = vec![0u8; len];
+    channel.read_exact(&mut encoded)?;
+    let snapshot =
+      ConnectionSnapshot::decode(&encoded).ok_or_else(|| invalid("bad snapshot"))?;
+    snapshots.push(snapshot);
+  }
+  debug!("Took over {} connections", snapshots.len());
+  Ok(Handoff {
+    socket: RawSocket::from_fds(send_fd, recv_fd),
+    snapshots,
+  })
+}
+
+fn invalid(message: &'static str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Send `payload` with `fds` attached
+fn send_fds(channel: &UnixStream, fds: &[RawFd], payload: &[u8]) -> io::Result<()> {
+  let fds_len = mem::size_of_val(fds) as u32;
+  let space = unsafe { libc::CMSG_SPACE(fds_len) } as usize;
+  // u64s keep the control buffer aligned for `cmsghdr`
+  let mut control = vec![0u64; space.div_ceil(8)];
+  let mut iov = libc::iovec {
+    iov_base: payload.as_ptr() as *mut libc::c_void,
+    iov_len: payload.len(),
+  };
+  let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+  msg.msg_iov = &mut iov;
+  msg.msg_iovlen = 1;
+  msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+  msg.msg_controllen = space as _;
+
+  let ret = unsafe {
+    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+    (*cmsg).cmsg_level = libc::SOL_SOCKET;
+    (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+    (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+    ptr::copy_nonoverlapping(
+      fds.as_ptr(),
+      libc::CMSG_DATA(cmsg) as *mut RawFd,
+      fds.len(),
+    );
+    libc::sendmsg(channel.as_raw_fd(), &msg, 0)
+  };
+  if ret < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  if ret as usize != payload.len() {
+    return Err(io::ErrorKind::WriteZero.into());
+  }
+  Ok(())
+}
+
+/// Fill `payload`, taking the descriptors that came with it
+fn recv_fds(channel: &mut UnixStream, payload: &mut [u8]) -> io::Result<Vec<OwnedFd>> {
+  let fds_len = (FD_COUNT * mem::size_of::<RawFd>()) as u32;
+  let space = unsafe { libc::CMSG_SPACE(fds_len) } as usize;
+  let mut control = vec![0u64; space.div_ceil(8)];
+  let mut iov = libc::iovec {
+    iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+    iov_len: payload.len(),
+  };
+  let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+  msg.msg_iov = &mut iov;
+  msg.msg_iovlen = 1;
+  msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+  msg.msg_controllen = space as _;
+
+  // Marked close-on-exec as they arrive, so they never leak into a later
+  // `exec`. macOS lacks the flag; its descriptors are marked just after
+  let ret = unsafe { libc::recvmsg(channel.as_raw_fd(), &mut msg, RECV_FLAGS) };
+  if ret < 0 {
+    return Err(io::Error::last_os_error());
+  }
+
+  let mut fds = Vec::new();
+  unsafe {
+    let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+    while !cmsg.is_null() {
+      if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+        let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+        let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+        for i in 0..len / mem::size_of::<RawFd>() {
+          let fd = ptr::read_unaligned(data.add(i));
+          #[cfg(target_vendor = "apple")]
+          libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+          fds.push(OwnedFd::from_raw_fd(fd));
+        }
+      }
+      cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+    }
+  }
+  if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+    // Close what did arrive now rather than leave it to the unwinding of
+    // `fds`; the descriptors cut off were never installed
+    drop(fds);
+    return Err(invalid("handoff carried more descriptors than expected"));
+  }
+  if ret == 0 {
+    return Err(io::ErrorKind::UnexpectedEof.into());
+  }
+  // A stream may split the header; the descriptors came with its start
+  channel.read_exact(&mut payload[ret as usize..])?;
+  Ok(fds)
+}
@@ -0,0 +1,236 @@
+//! TCP keep-alive probes (RFC 1122 4.2.3.6)
+//!
+//! A probe is an empty ACK carrying SND.NXT - 1. The byte it names was
+//! already acknowledged, so a live peer answers with a duplicate ACK and a
+//! peer that lost the connection answers with a reset.
+//!
+//! `Keepalive` decides when to probe: once the peer has been silent for the
+//! policy's idle time, then every interval until it answers. A peer that
+//! leaves the policy's count of probes unanswered is unreachable, which can
+//! be detected long before retransmissions give up.
+
+#[cfg(feature = "raw-socket")]
+use super::connect::POLL_INTERVAL;
+#[cfg(feature = "raw-socket")]
+use super::{CloseReason, ConnectionEvent, TcpConnection, TcpState};
+#[cfg(feature = "raw-socket")]
+use crate::error::Result;
+use crate::utils::Instant;
+use core::time::Duration;
+#[cfg(feature = "raw-socket")]
+use std::io;
+
+/// When to probe a silent peer and when to give up on it, as Linux's
+/// `TCP_KEEPIDLE`, `TCP_KEEPINTVL` and `TCP_KEEPCNT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KeepalivePolicy {
+  /// Silence before the first probe
+  pub idle: Duration,
+  /// Wait between unanswered probes
+  pub interval: Duration,
+  /// Unanswered probes before the peer is unreachable
+  pub probes: u32,
+}
+
+impl KeepalivePolicy {
+  /// Probe after `idle`, with Linux's interval and count
+  pub fn new(idle: Duration) -> Self {
+    Self {
+      idle,
+      ..Self::default()
+    }
+  }
+}
+
+impl Default for KeepalivePolicy {
+  fn default() -> Self {
+    Self {
+      idle: Duration::from_secs(7200),
+      interval: Duration::from_secs(75),
+      probes: 9,
+    }
+  }
+}
+
+/// What the keep-alive timer calls for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepaliveAction {
+  None,
+  /// Send a probe
+  Probe,
+  /// Every probe went unanswered
+  PeerUnreachable,
+}
+
+/// Keep-alive timer of one connection
+#[derive(Debug, Clone)]
+pub struct Keepalive {
+  policy: KeepalivePolicy,
+  enabled: bool,
+  last_heard: Instant,
+  last_probe: Instant,
+  probes_sent: u32,
+}
+
+impl Keepalive {
+  /// A disabled timer that counts silence from `now`
+  pub fn new(now: Instant) -> Self {
+    Self {
+      policy: KeepalivePolicy::default(),
+      enabled: false,
+      last_heard: now,
+      last_probe: now,
+      probes_sent: 0,
+    }
+  }
+
+  pub fn policy(&self) -> KeepalivePolicy {
+    self.policy
+  }
+
+  pub fn set_policy(&mut self, policy: KeepalivePolicy) {
+    self.policy = policy;
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.enabled
+  }
+
+  pub fn set_enabled(&mut self, enabled: bool) {
+    self.enabled = enabled;
+  }
+
+  /// Probes sent since the peer was last heard from
+  pub fn probes_sent(&self) -> u32 {
+    self.probes_sent
+  }
+
+  /// How long the peer has been silent
+  pub fn silence(&self, now: Instant) -> Duration {
+    now - self.last_heard
+  }
+
+  /// A segment arrived from the peer
+  pub fn on_peer_segment(&mut self, now: Instant) {
+    self.last_heard = now;
+    self.probes_sent = 0;
+  }
+
+  /// A probe went out, on the timer or on demand. The peer then has one
+  /// interval per probe left in the policy to answer, even with keep-alive
+  /// disabled
+  pub fn on_probe_sent(&mut self, now: Instant) {
+    self.last_probe = now;
+    self.probes_sent += 1;
+  }
+
+  /// When the timer next calls for something, if it is running
+  pub fn deadline(&self) -> Option<Instant> {
+    if self.probes_sent > 0 {
+      Some(self.last_probe + self.policy.interval)
+    } else if self.enabled {
+      Some(self.last_heard + self.policy.idle)
+    } else {
+      None
+    }
+  }
+
+  /// What is due by `now`. A `Probe` is counted as sent
+  pub fn poll(&mut self, now: Instant) -> KeepaliveAction {
+    match self.deadline() {
+      Some(deadline) if now >= deadline => {}
+      _ => return KeepaliveAction::None,
+    }
+    if self.probes_sent >= self.policy.probes {
+      return KeepaliveAction::PeerUnreachable;
+    }
+    self.on_probe_sent(now);
+    KeepaliveAction::Probe
+  }
+}
+
+#[cfg(feature = "raw-socket")]
+impl TcpConnection {
+  /// Send a single keep-alive probe
+  pub fn send_keepalive(&mut self) -> io::Result<()> {
+    let probe = self.engine().probe(Instant::now());
+    self.execute(vec![probe])
+  }
+
+  /// Probe the peer now rather than after the idle time. If it stays silent
+  /// through the policy's remaining probes, the connection closes with a
+  /// `ConnectionEvent::PeerUnreachable`
+  pub fn probe_now(&mut self) -> io::Result<()> {
+    self.send_keepalive()?;
+    self.subflow.control.keepalive.on_probe_sent(Instant::now());
+    Ok(())
+  }
+
+  /// Enable keep-alive with `policy`, or disable it
+  pub fn set_keepalive(&mut self, policy: Option<KeepalivePolicy>) {
+    let keepalive = &mut self.subflow.control.keepalive;
+    keepalive.set_enabled(policy.is_some());
+    if let Some(policy) = policy {
+      keepalive.set_policy(policy);
+    }
+  }
+
+  /// Report a peer that left every keep-alive probe unanswered; the engine
+  /// has already closed the connection
+  pub(super) fn on_peer_unreachable(&mut self) {
+    let keepalive = &self.subflow.control.keepalive;
+    let event = ConnectionEvent::PeerUnreachable {
+      trace_id: self.trace_id(),
+      probes: keepalive.probes_sent(),
+      silent_for: keepalive.silence(Instant::now()),
+    };
+    debug!(
+      "[conn {}] {} unreachable after {} keep-alive probes",
+      self.trace_id(),
+      self.subflow.remote,
+      keepalive.probes_sent()
+    );
+    self.post(event);
+  }
+
+  /// Probe the peer and wait up to `timeout` for its answer. Returns false,
+  /// and closes the connection, if the peer reset it or stayed silent
+  pub fn probe_alive(&mut self, timeout: Duration) -> Result<bool> {
+    if self.state() != TcpState::Established {
+      return Ok(false);
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0u8; 65535];
+    self.subflow.socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    self.send_keepalive()?;
+
+    while Instant::now() < deadline {
+      let Some(tcp) = self.recv_segment(&mut buf)? else {
+        continue;
+      };
+      if tcp.flags.is_rst() {
+        debug!(
+          "[conn {}] Keep-alive to {} answered with reset",
+          self.trace_id(),
+          self.subflow.remote
+        );
+        self.close_with(CloseReason::PeerRst);
+        return Ok(false);
+      }
+      if tcp.flags.is_ack() {
+        self.subflow.control.update_activity(Instant::now());
+        return Ok(true);
+      }
+    }
+
+    debug!(
+      "[conn {}] Keep-alive to {} timed out",
+      self.trace_id(),
+      self.subflow.remote
+    );
+    self.close_with(CloseReason::Timeout);
+    Ok(false)
+  }
+}
@@ -0,0 +1,439 @@
+//! Passive open and sharded listeners
+//!
+//! Every raw socket sees every inbound TCP segment, so several listeners can
+//! share a port the way `SO_REUSEPORT` sockets do: each shard reads its own
+//! socket and only handles the flows whose 4-tuple hashes to it. Worker
+//! threads accept in parallel without a shared SYN queue.
+//!
+//! The kernel answers SYNs to ports it has no socket for with a reset, so
+//! the host must be told to drop those (e.g. an `iptables` rule on outgoing
+//! RSTs from the listening port) for handshakes to complete.
+//!
+//! A SYN filter sees each new SYN before the listener allocates anything
+//! for it, and may accept it, drop it silently or refuse it with a reset.
+//! Before the filter, a `PacketLimiter` drops SYNs over its rate.
+//!
+//! An accepted SYN is answered from the listener's own socket and kept as
+//! a `RequestSock`. The connection, with its socket and buffers, is built
+//! when the handshake's final ACK arrives.
+//!
+//! Options set on the listener are inherited by every connection it
+//! accepts, as with socket options on a listening socket. `RcvBuf`,
+//! `MaxSeg` and `WindowScale` already shape the SYN-ACK. An accept hook
+//! then sees each new connection before `accept` returns it, to override
+//! options per peer.
+
+use super::connect::POLL_INTERVAL;
+use super::stats::{self, DropReason};
+use super::{ConnOption, ConnOptionKind, RequestSock, TcpConnection, TcpState};
+use crate::demux::{ConnectionKey, Demultiplexer, Shard};
+use crate::error::Result;
+use crate::flow_control::PacketLimiter;
+use crate::memory::{self, MemoryPool};
+use crate::packet::{IpOptionsPolicy, Ipv4Header, TcpFlags, TcpHeader, TcpOption};
+use crate::reliability::stream::DEFAULT_RECV_CAPACITY;
+use crate::socket::RawSocket;
+use crate::utils::{Instant, SeqNumber};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddrV4;
+
+/// Half-open connections kept per listener before new SYNs are ignored
+pub const DEFAULT_BACKLOG: usize = 128;
+
+/// Bytes charged to `MemoryPool::AcceptQueue` per half-open connection
+const HALF_OPEN_COST: usize = std::mem::size_of::<RequestSock>();
+
+/// What a SYN filter decides for a new SYN
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SynVerdict {
+  /// Answer with a SYN-ACK
+  Accept,
+  /// Ignore it, as if it never arrived
+  Drop,
+  /// Refuse it with a reset, as a closed port would
+  Reset,
+}
+
+/// Called with the source address and options of each new SYN
+type SynFilter = Box<dyn FnMut(SocketAddrV4, &[TcpOption]) -> SynVerdict + Send>;
+
+/// Called with each connection the handshake completed, before `accept`
+/// returns it
+type AcceptHook = Box<dyn FnMut(&mut TcpConnection) + Send>;
+
+/// Listening endpoint accepting connections on one shard of a port
+pub struct TcpListener {
+  socket: RawSocket,
+  local: SocketAddrV4,
+  shard: Shard,
+  backlog: usize,
+  gtsm_hops: Option<u8>,
+  ip_options_policy: IpOptionsPolicy,
+  syn_filter: Option<SynFilter>,
+  syn_limiter: PacketLimiter,
+  /// Inherited by accepted connections, one per kind
+  options: Vec<ConnOption>,
+  on_accept: Option<AcceptHook>,
+  pending: HashMap<ConnectionKey, RequestSock>,
+}
+
+impl TcpListener {
+  /// Listen on `local`; an unspecified IP accepts on every local address
+  pub fn bind(local: SocketAddrV4) -> Result<Self> {
+    Self::bind_shard(local, Shard::single())
+  }
+
+  /// Listen on one shard of `local`, for listeners spread over processes.
+  /// Fails with `AddrInUse` if this process already listens there
+  pub fn bind_shard(local: SocketAddrV4, shard: Shard) -> Result<Self> {
+    let socket = RawSocket::new()?;
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    Demultiplexer::global().listen(local, shard)?;
+    Ok(Self {
+      socket,
+      local,
+      shard,
+      backlog: DEFAULT_BACKLOG,
+      gtsm_hops: None,
+      ip_options_policy: IpOptionsPolicy::Accept,
+      syn_filter: None,
+      syn_limiter: PacketLimiter::new(),
+      options: Vec::new(),
+      on_accept: None,
+      pending: HashMap::new(),
+    })
+  }
+
+  /// One listener per shard of `local`, to hand to `count` worker threads
+  pub fn bind_sharded(local: SocketAddrV4, count: usize) -> Result<Vec<Self>> {
+    (0..count)
+      .map(|index| Self::bind_shard(local, Shard::new(index, count)))
+      .collect()
+  }
+
+  pub fn local_addr(&self) -> SocketAddrV4 {
+    self.local
+  }
+
+  pub fn shard(&self) -> Shard {
+    self.shard
+  }
+
+  pub fn set_backlog(&mut self, backlog: usize) {
+    self.backlog = backlog;
+  }
+
+  /// Enforce GTSM (RFC 5082) on incoming SYNs and accepted connections
+  pub fn set_gtsm(&mut self, hops: Option<u8>) {
+    self.gtsm_hops = hops;
+  }
+
+  /// Apply `policy` to IP options on incoming SYNs and accepted connections
+  pub fn set_ip_options_policy(&mut self, policy: IpOptionsPolicy) {
+    self.ip_options_policy = policy;
+  }
+
+  /// Decide on each new SYN with `filter` before any state is allocated for
+  /// it, for allowlists, rate limits or tarpits. Retransmitted SYNs of a
+  /// half-open connection already accepted do not reach it
+  pub fn set_syn_filter<F>(&mut self, filter: F)
+  where
+    F: FnMut(SocketAddrV4, &[TcpOption]) -> SynVerdict + Send + 'static,
+  {
+    self.syn_filter = Some(Box::new(filter));
+  }
+
+  pub fn clear_syn_filter(&mut self) {
+    self.syn_filter = None;
+  }
+
+  /// Set an option for every connection accepted from now on, replacing
+  /// one of the same kind. Fails with `InvalidOption` as
+  /// `TcpConnection::set_option` would
+  pub fn set_option(&mut self, option: ConnOption) -> Result<()> {
+    option.check()?;
+    self.options.retain(|set| set.kind() != option.kind());
+    self.options.push(option);
+    Ok(())
+  }
+
+  /// The option of `kind` accepted connections inherit, if one was set
+  pub fn option(&self, kind: ConnOptionKind) -> Option<ConnOption> {
+    self.options.iter().copied().find(|option| option.kind() == kind)
+  }
+
+  /// Run `hook` on each accepted connection after it inherits the
+  /// listener's options, for per-connection overrides. Options that shape
+  /// the handshake (`MaxSeg`, `WindowScale`) are settled by then
+  pub fn set_on_accept<F>(&mut self, hook: F)
+  where
+    F: FnMut(&mut TcpConnection) + Send + 'static,
+  {
+    self.on_accept = Some(Box::new(hook));
+  }
+
+  pub fn clear_on_accept(&mut self) {
+    self.on_accept = None;
+  }
+
+  /// Limits on new SYNs, globally and per source prefix; those over it are
+  /// dropped
+  pub fn syn_limiter_mut(&mut self) -> &mut PacketLimiter {
+    &mut self.syn_limiter
+  }
+
+  /// Block until a connection owned by this shard completes its handshake
+  pub fn accept(&mut self) -> Result<TcpConnection> {
+    let mut buf = vec![0u8; 65535];
+    loop {
+      let len = match self.socket.recv_from(&mut buf) {
+        Ok((len, _)) => len,
+        Err(e)
+          if matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+          ) =>
+        {
+          continue;
+        }
+        Err(e) => return Err(e.into()),
+      };
+
+      let Some((ip, segment)) = Ipv4Header::parse(&buf[..len]) else {
+        continue;
+      };
+      if ip.protocol != Ipv4Header::PROTOCOL_TCP {
+        continue;
+      }
+      if segment.len() < 4
+        || u16::from_be_bytes([segment[2], segment[3]]) != self.local.port()
+        || !(self.local.ip().is_unspecified() || ip.dst_addr == *self.local.ip())
+      {
+        continue;
+      }
+      if !super::checksum_ok(&ip, segment) {
+        stats::record_stack_drop(DropReason::BadChecksum);
+        continue;
+      }
+      let Some((tcp, _payload)) = TcpHeader::parse(segment) else {
+        stats::record_stack_drop(DropReason::MalformedOptions);
+        continue;
+      };
+      let Some(key) = ConnectionKey::from_headers(&ip, &tcp) else {
+        continue;
+      };
+      if !self.shard.owns(&key) {
+        continue;
+      }
+
+      if let Some(conn) = self.handle_segment(key, &ip, &tcp)? {
+        return Ok(conn);
+      }
+    }
+  }
+
+  /// LISTEN / SYN-RECEIVED processing for one inbound segment, returning the
+  /// connection once it is established
+  fn handle_segment(
+    &mut self,
+    key: ConnectionKey,
+    ip: &Ipv4Header,
+    tcp: &TcpHeader,
+  ) -> Result<Option<TcpConnection>> {
+    if let Some(request) = self.pending.get(&key) {
+      if !self.admits(ip) {
+        return Ok(None);
+      }
+      if tcp.flags.is_rst() {
+        debug!("Half-open connection from {} reset", key.remote);
+        self.take_pending(&key);
+        return Ok(None);
+      }
+      if tcp.flags.is_syn() && !tcp.flags.is_ack() {
+        // The peer retransmitted its SYN: our SYN-ACK was lost
+        self.send_syn_ack(request)?;
+        return Ok(None);
+      }
+      if !request.acknowledged_by(tcp) {
+        stats::record_stack_drop(DropReason::OutOfWindow);
+        return Ok(None);
+      }
+      let request = self.take_pending(&key).expect("request is pending");
+      return self.establish(&request, ip, tcp);
+    }
+
+    if Demultiplexer::global().find(&key).is_some() {
+      // Traffic of an accepted connection, which reads its own socket
+      return Ok(None);
+    }
+    if !tcp.flags.is_syn() || tcp.flags.is_ack() || tcp.flags.is_rst() {
+      stats::record_stack_drop(DropReason::NoConnection);
+      return Ok(None);
+    }
+    if !self.syn_limiter.allow(*key.remote.ip(), Instant::now()) {
+      stats::record_stack_drop(DropReason::RateLimited);
+      return Ok(None);
+    }
+    let verdict = match &mut self.syn_filter {
+      Some(filter) => filter(key.remote, &tcp.options),
+      None => SynVerdict::Accept,
+    };
+    if verdict != SynVerdict::Accept {
+      debug!("SYN filter: {:?} SYN from {}", verdict, key.remote);
+      stats::record_stack_drop(DropReason::Filtered);
+      if verdict == SynVerdict::Reset {
+        self.refuse(&key, tcp)?;
+      }
+      return Ok(None);
+    }
+    if self.pending.len() >= self.backlog {
+      warn!("Backlog full, dropping SYN from {}", key.remote);
+      stats::record_stack_drop(DropReason::BufferFull);
+      return Ok(None);
+    }
+    if !memory::try_charge(MemoryPool::AcceptQueue, HALF_OPEN_COST) {
+      warn!("Memory under pressure, dropping SYN from {}", key.remote);
+      stats::record_stack_drop(DropReason::BufferFull);
+      return Ok(None);
+    }
+    match self.open_half(&key, ip, tcp) {
+      Ok(Some(request)) => {
+        self.pending.insert(key, request);
+      }
+      result => {
+        memory::release(MemoryPool::AcceptQueue, HALF_OPEN_COST);
+        result?;
+      }
+    }
+    Ok(None)
+  }
+
+  /// Answer a new SYN, returning the half-open connection to queue
+  fn open_half(
+    &mut self,
+    key: &ConnectionKey,
+    ip: &Ipv4Header,
+    tcp: &TcpHeader,
+  ) -> Result<Option<RequestSock>> {
+    if !self.admits(ip) {
+      return Ok(None);
+    }
+    let recv_buffer = match self.option(ConnOptionKind::RcvBuf) {
+      Some(ConnOption::RcvBuf(size)) => size,
+      _ => DEFAULT_RECV_CAPACITY,
+    };
+    let mut request = RequestSock::new(
+      key.local,
+      key.remote,
+      SeqNumber::random(),
+      tcp,
+      recv_buffer,
+      Instant::now(),
+    );
+    if let Some(ConnOption::MaxSeg(mss)) = self.option(ConnOptionKind::MaxSeg) {
+      request.mss = mss;
+    }
+    if let Some(ConnOption::WindowScale(shift)) = self.option(ConnOptionKind::WindowScale) {
+      request.offered_scale = shift;
+    }
+    request.ip_options_stripped = self.ip_options_policy.strips(&ip.options);
+    self.send_syn_ack(&request)?;
+    Ok(Some(request))
+  }
+
+  /// Build the connection `request` becomes now that `tcp` acknowledged
+  /// its SYN-ACK
+  fn establish(
+    &mut self,
+    request: &RequestSock,
+    ip: &Ipv4Header,
+    tcp: &TcpHeader,
+  ) -> Result<Option<TcpConnection>> {
+    let mut conn = TcpConnection::new(RawSocket::new()?, request.local, request.remote);
+    if conn.register().is_err() {
+      debug!(
+        "Ignoring handshake from {} for a connection already in use",
+        request.remote
+      );
+      return Ok(None);
+    }
+    conn.set_gtsm(self.gtsm_hops);
+    conn.set_ip_options_policy(self.ip_options_policy);
+    for &option in &self.options {
+      conn.set_option(option)?;
+    }
+    conn.engine().accept_request(request);
+    if !conn.on_receive(ip) || conn.process_syn_received(tcp).is_err() {
+      return Ok(None);
+    }
+    if conn.state() != TcpState::Established {
+      return Ok(None);
+    }
+    if let Some(hook) = &mut self.on_accept {
+      hook(&mut conn);
+    }
+    Ok(Some(conn))
+  }
+
+  /// Whether a segment in `ip` passes GTSM and the IP options policy
+  fn admits(&self, ip: &Ipv4Header) -> bool {
+    if self.gtsm_hops.is_some_and(|hops| ip.ttl < 255 - hops) {
+      stats::record_stack_drop(DropReason::TtlTooLow);
+      return false;
+    }
+    if !self.ip_options_policy.admits(&ip.options) {
+      stats::record_stack_drop(DropReason::IpOptions);
+      return false;
+    }
+    true
+  }
+
+  fn send_syn_ack(&self, request: &RequestSock) -> io::Result<()> {
+    let ttl = if self.gtsm_hops.is_some() { 255 } else { 64 };
+    self.send(request.local, request.remote, &request.syn_ack(), ttl)
+  }
+
+  /// Reset a SYN the filter refused (RFC 793 3.4)
+  fn refuse(&self, key: &ConnectionKey, tcp: &TcpHeader) -> io::Result<()> {
+    let mut reset = TcpHeader::new(key.local.port(), key.remote.port());
+    reset.ack_num = tcp.seq_num.wrapping_add(1);
+    reset.flags = TcpFlags::new().with_rst().with_ack();
+    reset.window_size = 0;
+    self.send(key.local, key.remote, &reset, 64)
+  }
+
+  /// Send a segment with no payload from `local` to `remote`
+  fn send(
+    &self,
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    header: &TcpHeader,
+    ttl: u8,
+  ) -> io::Result<()> {
+    let checksum =
+      header.calculate_checksum(u32::from(*local.ip()), u32::from(*remote.ip()), &[]);
+    let mut segment = header.serialize();
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    let mut ip = Ipv4Header::new(*local.ip(), *remote.ip(), segment.len());
+    ip.ttl = ttl;
+    let mut packet = ip.serialize();
+    packet.extend_from_slice(&segment);
+    self.socket.send_to(&packet, *remote.ip())?;
+    Ok(())
+  }
+
+  /// Remove a half-open connection from the queue
+  fn take_pending(&mut self, key: &ConnectionKey) -> Option<RequestSock> {
+    let request = self.pending.remove(key)?;
+    memory::release(MemoryPool::AcceptQueue, HALF_OPEN_COST);
+    Some(request)
+  }
+}
+
+impl Drop for TcpListener {
+  fn drop(&mut self) {
+    memory::release(MemoryPool::AcceptQueue, self.pending.len() * HALF_OPEN_COST);
+    Demultiplexer::global().unlisten(self.local, self.shard);
+  }
+}
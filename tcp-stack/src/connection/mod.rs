@@ -1,41 +1,550 @@
 //! TCP connection state machine
 
+pub mod ack;
+pub mod action;
+pub mod backoff;
+#[cfg(feature = "blast")]
+pub mod blast;
+#[cfg(feature = "raw-socket")]
+pub mod close;
+#[cfg(feature = "raw-socket")]
+pub mod connect;
 pub mod control;
+pub mod engine;
+pub mod expect;
+pub mod feedback;
+pub mod fingerprint;
+#[cfg(all(feature = "raw-socket", unix))]
+pub mod handoff;
+pub mod keepalive;
+#[cfg(feature = "raw-socket")]
+pub mod listen;
+#[cfg(feature = "raw-socket")]
+pub mod pool;
+pub mod qlog;
+pub mod request;
+pub mod snapshot;
+#[cfg(feature = "raw-socket")]
+pub mod sockopt;
 pub mod states;
+pub mod stats;
+#[cfg(all(feature = "raw-socket", feature = "async", unix))]
+pub mod stream;
+#[cfg(feature = "raw-socket")]
+pub mod subflow;
+pub mod telemetry;
 pub mod timer;
+pub mod timeseq;
+#[cfg(feature = "raw-socket")]
+pub mod transfer;
+#[cfg(feature = "compression")]
+pub mod transform;
 
+pub use ack::{AckDecision, AckGenerator, AckPolicy};
+pub use action::{Action, CloseReason, NetworkEvent, TimerKind};
+pub use backoff::{BackoffDecision, BackoffObserver, BackoffProgress};
+#[cfg(feature = "blast")]
+pub use blast::LossAccounting;
+#[cfg(feature = "raw-socket")]
+pub use connect::{CancelHandle, ConnectOptions};
 pub use control::ControlBlock;
+pub use engine::Engine;
+pub use expect::{Expect, SegmentTrace};
+pub use feedback::{AckFeedback, AckObserver, BoxedAckObserver};
+pub use fingerprint::OsProfile;
+#[cfg(all(feature = "raw-socket", unix))]
+pub use handoff::{recv_handoff, send_handoff, Handoff};
+pub use keepalive::{Keepalive, KeepaliveAction, KeepalivePolicy};
+#[cfg(feature = "raw-socket")]
+pub use listen::{SynVerdict, TcpListener};
+#[cfg(feature = "raw-socket")]
+pub use pool::{ConnectionPool, PoolOptions, PooledConnection};
+pub use qlog::{EventLog, QlogEvent, QlogRecord, RetransmitTrigger};
+pub use request::RequestSock;
+pub use snapshot::ConnectionSnapshot;
+#[cfg(feature = "raw-socket")]
+pub use sockopt::{ConnOption, ConnOptionKind};
 pub use states::TcpState;
+pub use stats::{ConnectionStats, DropCounters, DropReason, WindowScaling};
+#[cfg(all(feature = "raw-socket", feature = "async", unix))]
+pub use stream::TcpStream;
+#[cfg(feature = "raw-socket")]
+pub use subflow::Subflow;
+pub use telemetry::{ConnectionEvent, ThroughputSample, ThroughputSampler};
 pub use timer::Timer;
+pub use timeseq::{TimeSeqKind, TimeSeqSample, TimeSequence};
+#[cfg(feature = "compression")]
+pub use transform::{StreamTransform, Transforms};
 
+#[cfg(feature = "raw-socket")]
+use crate::demux::{ConnectionId, Demultiplexer};
+#[cfg(feature = "raw-socket")]
+use subflow::Arrival;
+#[cfg(feature = "raw-socket")]
+use crate::error::{Result, TcpError};
+#[cfg(feature = "raw-socket")]
+use crate::flow_control::{Pacer, Priority, SharedShaper};
+#[cfg(feature = "raw-socket")]
+use crate::packet::{IpOptionsPolicy, Ipv4Header, PacketFilter, TcpHeader};
+#[cfg(feature = "raw-socket")]
+use crate::socket::route::Route;
+#[cfg(feature = "raw-socket")]
 use crate::socket::RawSocket;
+#[cfg(feature = "raw-socket")]
+use crate::utils::{Instant, SeqNumber};
+#[cfg(feature = "raw-socket")]
+use std::io;
+#[cfg(feature = "raw-socket")]
 use std::net::SocketAddrV4;
-use tracing::debug;
+#[cfg(feature = "raw-socket")]
+use std::sync::mpsc;
+#[cfg(feature = "raw-socket")]
+use std::time::Duration;
 
-/// TCP Connection
+/// TCP Connection driven over a raw socket
+#[cfg(feature = "raw-socket")]
 pub struct TcpConnection {
-  pub control: ControlBlock,
-  pub socket: RawSocket,
-  pub remote: SocketAddrV4,
-  pub local: SocketAddrV4,
+  /// The one path the connection runs over
+  subflow: Subflow,
+  /// Id under which the 4-tuple is registered with the global demultiplexer
+  id: Option<ConnectionId>,
+  /// Log a digest of every segment sent and received
+  correlate: bool,
+  /// Only segments passing this are traced, if set
+  trace_filter: Option<PacketFilter>,
+  /// Where throughput samples and other events are posted
+  events: Option<mpsc::Sender<ConnectionEvent>>,
+  /// Route looked up before connecting, if `connect_with` did
+  route: Option<Route>,
 }
 
+#[cfg(feature = "raw-socket")]
 impl TcpConnection {
   pub fn new(socket: RawSocket, local: SocketAddrV4, remote: SocketAddrV4) -> Self {
     Self {
-      control: ControlBlock::new(),
-      socket,
-      remote,
-      local,
+      subflow: Subflow::new(socket, local, remote),
+      id: None,
+      correlate: false,
+      trace_filter: None,
+      events: None,
+      route: None,
+    }
+  }
+
+  /// Claim the 4-tuple in the global demultiplexer until this connection is
+  /// dropped
+  fn register(&mut self) -> Result<()> {
+    self.id = Some(Demultiplexer::global().register(self.subflow.key())?);
+    Ok(())
+  }
+
+  pub fn subflow(&self) -> &Subflow {
+    &self.subflow
+  }
+
+  pub fn subflow_mut(&mut self) -> &mut Subflow {
+    &mut self.subflow
+  }
+
+  pub fn control(&self) -> &ControlBlock {
+    &self.subflow.control
+  }
+
+  pub fn control_mut(&mut self) -> &mut ControlBlock {
+    &mut self.subflow.control
+  }
+
+  pub fn local_addr(&self) -> SocketAddrV4 {
+    self.subflow.local
+  }
+
+  pub fn remote_addr(&self) -> SocketAddrV4 {
+    self.subflow.remote
+  }
+
+  /// Id in the global demultiplexer, once connecting or accepted
+  pub fn id(&self) -> Option<ConnectionId> {
+    self.id
+  }
+
+  /// Source address, interface and next hop chosen when connecting by
+  /// destination alone
+  pub fn route(&self) -> Option<&Route> {
+    self.route.as_ref()
+  }
+
+  /// Id tagging this connection's log events as `[conn N]`; unlike `id` it
+  /// is assigned at creation and never reused
+  pub fn trace_id(&self) -> u32 {
+    self.subflow.control.trace_id
+  }
+
+  /// Log a digest (`TcpHeader::digest`) of each segment sent and received,
+  /// so events can be matched against a packet capture taken elsewhere
+  pub fn set_packet_correlation(&mut self, enabled: bool) {
+    self.correlate = enabled;
+  }
+
+  /// Trace only the segments `filter` passes, or all of them with `None`
+  pub fn set_trace_filter(&mut self, filter: Option<PacketFilter>) {
+    self.trace_filter = filter;
+  }
+
+  /// Post this connection's events to `events`; several connections may
+  /// share one channel. Posting stops once the receiver is dropped
+  pub fn set_event_sender(&mut self, events: Option<mpsc::Sender<ConnectionEvent>>) {
+    self.events = events;
+  }
+
+  /// Post a `ConnectionEvent::Throughput` every `interval` while the
+  /// connection is driven, or stop with `None`
+  pub fn set_sampling_interval(&mut self, interval: Option<Duration>) {
+    match interval {
+      Some(interval) => self.subflow.control.enable_sampling(interval, Instant::now()),
+      None => self.subflow.control.sampler = None,
+    }
+  }
+
+  fn post(&mut self, event: ConnectionEvent) {
+    if let Some(events) = &self.events {
+      if events.send(event).is_err() {
+        self.events = None;
+      }
     }
   }
 
   pub fn state(&self) -> TcpState {
-    self.control.state
+    self.subflow.control.state
+  }
+
+  /// Save the connection for `restore` here or in another process sharing
+  /// the raw socket, or `None` unless both sides are synchronized
+  pub fn snapshot(&self) -> Option<ConnectionSnapshot> {
+    ConnectionSnapshot::capture(self.subflow.local, self.subflow.remote, &self.subflow.control)
+  }
+
+  /// Resume a connection saved by `snapshot` over `socket`, claiming its
+  /// 4-tuple. In the same process the saved connection must be dropped
+  /// first, or this fails with `AddrInUse`
+  pub fn restore(socket: RawSocket, snapshot: &ConnectionSnapshot) -> Result<Self> {
+    let mut conn = Self::new(socket, snapshot.local, snapshot.remote);
+    conn.subflow.control = snapshot.restore(Instant::now());
+    conn.register()?;
+    Ok(conn)
+  }
+
+  /// Close without a reset if `event` took the local address away, so
+  /// calls fail with `CloseReason::NetworkDown` at once instead of
+  /// retransmitting until a limit. Returns whether it closed
+  pub fn on_network_event(&mut self, event: &NetworkEvent) -> bool {
+    if !event.affects(*self.subflow.local.ip())
+      || matches!(
+        self.state(),
+        TcpState::Closed | TcpState::Listen | TcpState::TimeWait
+      )
+    {
+      return false;
+    }
+    let actions = self.engine().on_network_down(Instant::now());
+    // Nothing is sent, so nothing can fail
+    let _ = self.execute(actions);
+    true
+  }
+
+  /// Why the connection closed, once it has
+  pub fn close_reason(&self) -> Option<CloseReason> {
+    self.subflow.control.close_reason
+  }
+
+  /// Close the connection for `reason` without the engine, as the blocking
+  /// loops do when they give up, and report it
+  pub(crate) fn close_with(&mut self, reason: CloseReason) {
+    self.subflow.control.retransmit.clear();
+    self.subflow.control.close_reason = Some(reason);
+    self.set_state(TcpState::Closed);
+    self.on_closed(reason);
+  }
+
+  /// Log and post a close the connection has already made
+  fn on_closed(&mut self, reason: CloseReason) {
+    debug!("[conn {}] Closed: {:?}", self.trace_id(), reason);
+    self.post(ConnectionEvent::Closed {
+      trace_id: self.trace_id(),
+      reason,
+    });
+  }
+
+  /// The error for an operation the connection can no longer carry out
+  fn closed_error(&self) -> TcpError {
+    match self.subflow.control.close_reason {
+      Some(reason) => TcpError::closed(reason),
+      None => TcpError::NotConnected,
+    }
+  }
+
+  pub fn stats(&self) -> &ConnectionStats {
+    &self.subflow.control.stats
+  }
+
+  /// Zero the statistics and start a new epoch
+  pub fn stats_reset(&mut self) {
+    self.subflow.control.stats.reset();
   }
 
   pub fn set_state(&mut self, state: TcpState) {
-    debug!("State transition: {:?} -> {:?}", self.control.state, state);
-    self.control.state = state;
+    debug!(
+      "[conn {}] State transition: {:?} -> {:?}",
+      self.trace_id(), self.subflow.control.state, state
+    );
+    self.subflow.control.state = state;
+  }
+
+  /// Pace this connection at no more than `bytes_per_sec`, whatever cwnd
+  /// allows
+  pub fn set_max_send_rate(&mut self, bytes_per_sec: u64) {
+    self
+      .subflow
+      .control
+      .set_max_send_rate(Some(bytes_per_sec), Instant::now());
+  }
+
+  pub fn clear_max_send_rate(&mut self) {
+    self.subflow.control.set_max_send_rate(None, Instant::now());
   }
+
+  /// Spread sends over the RTT with `pacer`, or send what cwnd allows at
+  /// once with `None`
+  pub fn set_pacer(&mut self, pacer: Option<Pacer>) {
+    self.subflow.control.pacer = pacer;
+  }
+
+  /// Also draw from `shaper`, capping the total rate of every connection
+  /// attached to it
+  pub fn set_shaper(&mut self, shaper: SharedShaper) {
+    self.subflow.control.shaper = Some(shaper);
+  }
+
+  /// Mark outgoing packets with `dscp` (6 bits)
+  pub fn set_dscp(&mut self, dscp: u8) {
+    debug_assert!(dscp < 64, "DSCP is a 6-bit field");
+    self.subflow.control.dscp = dscp & 0x3F;
+  }
+
+  /// Mark outgoing packets with the DSCP of `priority`'s class. Each
+  /// connection sends on its own, so there is no scheduler to order them;
+  /// `TcpStack::set_priority` does both
+  pub fn set_priority(&mut self, priority: Priority) {
+    self.subflow.control.dscp = priority.dscp();
+  }
+
+  /// Set the ECN codepoint of outgoing packets (2 bits)
+  pub fn set_ecn(&mut self, ecn: u8) {
+    debug_assert!(ecn < 4, "ECN is a 2-bit field");
+    self.subflow.control.ecn = ecn & 0x03;
+  }
+
+  /// Set the TTL of outgoing packets
+  pub fn set_ttl(&mut self, ttl: u8) {
+    self.subflow.control.ttl = ttl;
+  }
+
+  /// Enforce GTSM (RFC 5082) for a peer at most `hops` hops away, or turn
+  /// it off with `None`
+  pub fn set_gtsm(&mut self, hops: Option<u8>) {
+    self.subflow.control.set_gtsm(hops);
+  }
+
+  /// Decide what becomes of packets from the peer carrying IP options
+  pub fn set_ip_options_policy(&mut self, policy: IpOptionsPolicy) {
+    self.subflow.control.ip_options_policy = policy;
+  }
+
+  /// Call `observer` on every retransmission timeout, which may abort the
+  /// connection instead of backing off further, or stop with `None`
+  pub fn set_backoff_observer(&mut self, observer: Option<BackoffObserver>) {
+    self.subflow.control.backoff_observer = observer;
+  }
+
+  /// Call `observer` with the feedback of every ACK the connection takes,
+  /// or stop with `None`
+  pub fn set_ack_observer(&mut self, observer: Option<BoxedAckObserver>) {
+    self.subflow.control.ack_observer = observer;
+  }
+
+  /// Disable Nagle's algorithm so small writes go out without waiting for
+  /// outstanding data to be acknowledged
+  pub fn set_nodelay(&mut self, nodelay: bool) {
+    self.subflow.control.send_queue.set_nagle(!nodelay);
+  }
+
+  /// Count a discarded segment against this connection and the stack
+  fn record_drop(&mut self, reason: DropReason) {
+    trace!(
+      "[conn {}] Dropping segment from {}: {:?}",
+      self.trace_id(),
+      self.subflow.remote,
+      reason
+    );
+    self.subflow.control.stats.drops.record(reason);
+    stats::record_stack_drop(reason);
+  }
+
+  /// Segment processing for this connection
+  fn engine(&mut self) -> Engine<'_> {
+    self.subflow.engine()
+  }
+
+  /// Carry out what processing decided. The blocking and async loops check
+  /// every timer on each pass and readers take data from `recv_stream`
+  /// themselves, so only segments and closes need doing
+  fn execute(&mut self, actions: Vec<Action>) -> io::Result<()> {
+    for action in actions {
+      match action {
+        Action::SendSegment { header, payload } => {
+          self.send_segment(&header, &payload)?
+        }
+        Action::Close { reason } => {
+          // Only keep-alive gives up with a plain timeout
+          if reason == CloseReason::Timeout {
+            self.on_peer_unreachable();
+          }
+          self.on_closed(reason);
+        }
+        Action::StartTimer { .. } | Action::DeliverData { .. } => {}
+      }
+    }
+    Ok(())
+  }
+
+  /// Account for a segment received from the peer in `ip`, returning false
+  /// if it must be discarded
+  fn on_receive(&mut self, ip: &Ipv4Header) -> bool {
+    self.engine().on_receive(ip)
+  }
+
+  /// Wait up to the socket's read timeout for a segment from the peer,
+  /// returning `None` on timeout or if what arrived was for someone else
+  fn recv_segment(&mut self, buf: &mut [u8]) -> io::Result<Option<TcpHeader>> {
+    match self.read_segment(buf) {
+      Err(e)
+        if matches!(
+          e.kind(),
+          io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ) =>
+      {
+        Ok(None)
+      }
+      result => result,
+    }
+  }
+
+  /// `recv_segment` that reports an empty socket as `WouldBlock` or
+  /// `TimedOut`, so a non-blocking caller knows when it has drained it
+  fn read_segment(&mut self, buf: &mut [u8]) -> io::Result<Option<TcpHeader>> {
+    let (ip, tcp, payload, segment) = match self.subflow.recv(buf)? {
+      Arrival::Segment {
+        ip,
+        tcp,
+        payload,
+        segment,
+      } => (ip, tcp, payload, segment),
+      Arrival::Dropped(reason) => {
+        self.record_drop(reason);
+        return Ok(None);
+      }
+      Arrival::Foreign => return Ok(None),
+    };
+    if !self.on_receive(&ip) {
+      return Ok(None);
+    }
+    self.trace_segment("recv", &tcp, payload.len(), segment);
+    let now = Instant::now();
+    self.subflow.control.ack.on_peer_segment();
+    self.subflow.control.keepalive.on_peer_segment(now);
+    let actions = self.engine().receive_text(&tcp, payload, now);
+    self.execute(actions)?;
+    Ok(Some(tcp))
+  }
+
+  /// Take the acknowledgment and window of a segment from the peer
+  fn on_ack_segment(&mut self, tcp: &TcpHeader) -> io::Result<()> {
+    let actions = self.engine().on_ack(tcp, 0, Instant::now());
+    self.execute(actions)
+  }
+
+  /// Send what the windows allow out of the send queue, and whatever the
+  /// timers say is due
+  fn transmit(&mut self, now: Instant) -> io::Result<()> {
+    let actions = self.engine().poll(now);
+    self.execute(actions)?;
+    if let Some(sample) = self.subflow.control.poll_sample(now) {
+      let trace_id = self.trace_id();
+      self.post(ConnectionEvent::Throughput { trace_id, sample });
+    }
+    Ok(())
+  }
+
+  /// Send a window update if a read opened the receive window enough
+  fn update_window(&mut self, now: Instant) -> io::Result<()> {
+    let actions = self.engine().update_window(now);
+    self.execute(actions)
+  }
+
+  /// Log a segment's flags, sequence range and ACK, plus its digest in
+  /// correlation mode, unless the trace filter turns it away
+  fn trace_segment(&self, direction: &str, tcp: &TcpHeader, len: usize, segment: &[u8]) {
+    if let Some(filter) = &self.trace_filter {
+      let (src, dst) = match direction {
+        "send" => (self.subflow.local, self.subflow.remote),
+        _ => (self.subflow.remote, self.subflow.local),
+      };
+      if !filter.matches_segment(src, dst, tcp.flags) {
+        return;
+      }
+    }
+    let seq = SeqNumber(tcp.seq_num);
+    trace!(
+      "[conn {}] {} {:?} seq={}..{} ack={} win={}",
+      self.trace_id(),
+      direction,
+      tcp.flags,
+      seq.0,
+      (seq + len as u32).0,
+      tcp.ack_num,
+      tcp.window_size
+    );
+    if self.correlate {
+      debug!(
+        "[conn {}] {} digest={:016x}",
+        self.trace_id(),
+        direction,
+        TcpHeader::digest(segment)
+      );
+    }
+  }
+
+  /// Checksum, wrap in IPv4 and transmit a segment to the peer
+  fn send_segment(&mut self, header: &TcpHeader, payload: &[u8]) -> io::Result<()> {
+    let segment = self.subflow.send(header, payload)?;
+    self.trace_segment("send", header, payload.len(), &segment);
+    Ok(())
+  }
+}
+
+#[cfg(feature = "raw-socket")]
+impl Drop for TcpConnection {
+  fn drop(&mut self) {
+    if let Some(id) = self.id {
+      Demultiplexer::global().unregister(id);
+    }
+  }
+}
+
+/// Verify a received segment's checksum. Segments the host sent itself cross
+/// the loopback device with the checksum left to offload, so are trusted
+#[cfg(feature = "raw-socket")]
+pub(crate) fn checksum_ok(ip: &Ipv4Header, segment: &[u8]) -> bool {
+  ip.src_addr.is_loopback()
+    || ip.src_addr == ip.dst_addr
+    || TcpHeader::verify_checksum(ip.src_addr.into(), ip.dst_addr.into(), segment)
 }
@@ -0,0 +1,172 @@
+//! Reuse of established connections
+//!
+//! `ConnectionPool` keeps idle connections per destination. A checked-out
+//! connection goes back to the pool when its `PooledConnection` is dropped,
+//! provided it is still established; before an idle connection is handed out
+//! again it must answer a keep-alive probe.
+
+use super::{ConnectOptions, TcpConnection, TcpState};
+use crate::error::Result;
+use crate::utils::Instant;
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Limits applied by a `ConnectionPool`
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+  /// Idle connections kept per destination; extras are closed on return
+  pub max_idle_per_host: usize,
+  /// Idle connections older than this are discarded instead of reused
+  pub idle_timeout: Duration,
+  /// How long a reused connection has to answer its keep-alive probe
+  pub health_check_timeout: Duration,
+  /// Options for new connections
+  pub connect: ConnectOptions,
+}
+
+impl Default for PoolOptions {
+  fn default() -> Self {
+    Self {
+      max_idle_per_host: 8,
+      idle_timeout: Duration::from_secs(90),
+      health_check_timeout: Duration::from_secs(1),
+      connect: ConnectOptions::default(),
+    }
+  }
+}
+
+struct IdleConnection {
+  conn: TcpConnection,
+  since: Instant,
+}
+
+type IdleMap = HashMap<SocketAddrV4, Vec<IdleConnection>>;
+
+/// Cache of established connections keyed by destination
+#[derive(Clone)]
+pub struct ConnectionPool {
+  idle: Arc<Mutex<IdleMap>>,
+  options: PoolOptions,
+}
+
+impl ConnectionPool {
+  pub fn new() -> Self {
+    Self::with_options(PoolOptions::default())
+  }
+
+  pub fn with_options(options: PoolOptions) -> Self {
+    Self {
+      idle: Arc::new(Mutex::new(HashMap::new())),
+      options,
+    }
+  }
+
+  /// A healthy idle connection to `remote`, or a new one
+  pub fn get(&self, remote: SocketAddrV4) -> Result<PooledConnection> {
+    while let Some(idle) = self.take_idle(remote) {
+      let mut conn = idle.conn;
+      if Instant::now() - idle.since >= self.options.idle_timeout {
+        continue;
+      }
+      if conn.probe_alive(self.options.health_check_timeout)? {
+        return Ok(self.wrap(conn));
+      }
+      debug!(
+        "[conn {}] Discarding dead pooled connection to {}",
+        conn.trace_id(),
+        remote
+      );
+    }
+
+    let conn = TcpConnection::connect_with(remote, self.options.connect.clone())?;
+    Ok(self.wrap(conn))
+  }
+
+  /// Idle connections currently cached for `remote`
+  pub fn idle_count(&self, remote: SocketAddrV4) -> usize {
+    self.lock().get(&remote).map_or(0, Vec::len)
+  }
+
+  /// Drop idle connections that have outlived the idle timeout
+  pub fn evict_expired(&self) {
+    let now = Instant::now();
+    let timeout = self.options.idle_timeout;
+    self.lock().retain(|_, conns| {
+      conns.retain(|idle| now - idle.since < timeout);
+      !conns.is_empty()
+    });
+  }
+
+  fn take_idle(&self, remote: SocketAddrV4) -> Option<IdleConnection> {
+    // Most recently returned first: it is the least likely to have died
+    self.lock().get_mut(&remote)?.pop()
+  }
+
+  fn put_idle(&self, conn: TcpConnection) {
+    let mut idle = self.lock();
+    let conns = idle.entry(conn.remote_addr()).or_default();
+    if conns.len() < self.options.max_idle_per_host {
+      conns.push(IdleConnection {
+        conn,
+        since: Instant::now(),
+      });
+    }
+  }
+
+  fn wrap(&self, conn: TcpConnection) -> PooledConnection {
+    PooledConnection {
+      conn: Some(conn),
+      pool: self.clone(),
+    }
+  }
+
+  fn lock(&self) -> MutexGuard<'_, IdleMap> {
+    self.idle.lock().unwrap_or_else(|e| e.into_inner())
+  }
+}
+
+impl Default for ConnectionPool {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Connection checked out of a `ConnectionPool`, returned to it on drop
+pub struct PooledConnection {
+  conn: Option<TcpConnection>,
+  pool: ConnectionPool,
+}
+
+impl PooledConnection {
+  /// Take the connection out of the pool's care for good
+  pub fn detach(mut self) -> TcpConnection {
+    self.conn.take().expect("connection present until drop")
+  }
+}
+
+impl Deref for PooledConnection {
+  type Target = TcpConnection;
+
+  fn deref(&self) -> &TcpConnection {
+    self.conn.as_ref().expect("connection present until drop")
+  }
+}
+
+impl DerefMut for PooledConnection {
+  fn deref_mut(&mut self) -> &mut TcpConnection {
+    self.conn.as_mut().expect("connection present until drop")
+  }
+}
+
+impl Drop for PooledConnection {
+  fn drop(&mut self) {
+    if let Some(conn) = self.conn.take() {
+      if conn.state() == TcpState::Established {
+        self.pool.put_idle(conn);
+      }
+    }
+  }
+}
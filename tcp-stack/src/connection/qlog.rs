@@ -0,0 +1,347 @@
+//! Structured event log, after QUIC's qlog
+//!
+//! `EventLog` records the decisions a connection makes as it makes them:
+//! segments sent and received, retransmissions and why, cwnd and ssthresh
+//! changes, timers armed and expired, and state transitions, each with its
+//! timestamp. Events are kept in a bounded ring and exported as JSON lines,
+//! one `{"time_us", "name", "data"}` object per line with qlog's
+//! `category:event` names, or as a self-contained HTML timeline for
+//! looking into a throughput anomaly offline.
+
+use super::action::{CloseReason, TimerKind};
+use super::TcpState;
+use crate::congestion::newreno::CongestionState;
+use crate::packet::TcpFlags;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// Events kept by default
+pub const DEFAULT_EVENT_LOG_CAPACITY: usize = 16384;
+
+/// What set off a retransmission
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetransmitTrigger {
+  /// The retransmission timer expired
+  Timeout,
+  /// Three duplicate ACKs
+  DuplicateAcks,
+}
+
+/// One decision of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QlogEvent {
+  PacketSent {
+    seq: u32,
+    len: u32,
+    flags: TcpFlags,
+  },
+  PacketReceived {
+    seq: u32,
+    ack: u32,
+    len: u32,
+    window: u16,
+    flags: TcpFlags,
+  },
+  /// The segment at `seq` was given up as lost and sent again
+  Retransmit {
+    seq: u32,
+    len: u32,
+    trigger: RetransmitTrigger,
+  },
+  /// Congestion control moved cwnd, ssthresh or its state
+  MetricsUpdated {
+    cwnd: u32,
+    ssthresh: u32,
+    state: CongestionState,
+  },
+  /// A timer was armed or moved to `deadline_micros`
+  TimerSet {
+    timer: TimerKind,
+    deadline_micros: u64,
+  },
+  TimerExpired {
+    timer: TimerKind,
+  },
+  StateUpdated {
+    old: TcpState,
+    new: TcpState,
+  },
+  Closed {
+    reason: CloseReason,
+  },
+}
+
+impl QlogEvent {
+  /// qlog-style `category:event` name
+  pub fn name(&self) -> &'static str {
+    match self {
+      QlogEvent::PacketSent { .. } => "transport:packet_sent",
+      QlogEvent::PacketReceived { .. } => "transport:packet_received",
+      QlogEvent::Retransmit { .. } => "recovery:packet_retransmitted",
+      QlogEvent::MetricsUpdated { .. } => "recovery:metrics_updated",
+      QlogEvent::TimerSet { .. } | QlogEvent::TimerExpired { .. } => {
+        "recovery:timer_updated"
+      }
+      QlogEvent::StateUpdated { .. } => "connectivity:connection_state_updated",
+      QlogEvent::Closed { .. } => "connectivity:connection_closed",
+    }
+  }
+
+  /// The event's fields as the body of a JSON object
+  fn write_data(&self, out: &mut String) {
+    let _ = match self {
+      QlogEvent::PacketSent { seq, len, flags } => write!(
+        out,
+        "\"seq\":{},\"len\":{},\"flags\":\"{}\"",
+        seq,
+        len,
+        flag_letters(*flags)
+      ),
+      QlogEvent::PacketReceived {
+        seq,
+        ack,
+        len,
+        window,
+        flags,
+      } => write!(
+        out,
+        "\"seq\":{},\"ack\":{},\"len\":{},\"window\":{},\"flags\":\"{}\"",
+        seq,
+        ack,
+        len,
+        window,
+        flag_letters(*flags)
+      ),
+      QlogEvent::Retransmit { seq, len, trigger } => write!(
+        out,
+        "\"seq\":{},\"len\":{},\"trigger\":\"{:?}\"",
+        seq, len, trigger
+      ),
+      QlogEvent::MetricsUpdated {
+        cwnd,
+        ssthresh,
+        state,
+      } => write!(
+        out,
+        "\"cwnd\":{},\"ssthresh\":{},\"congestion_state\":\"{:?}\"",
+        cwnd, ssthresh, state
+      ),
+      QlogEvent::TimerSet {
+        timer,
+        deadline_micros,
+      } => write!(
+        out,
+        "\"timer\":\"{:?}\",\"event_type\":\"set\",\"deadline_us\":{}",
+        timer, deadline_micros
+      ),
+      QlogEvent::TimerExpired { timer } => {
+        write!(out, "\"timer\":\"{:?}\",\"event_type\":\"expired\"", timer)
+      }
+      QlogEvent::StateUpdated { old, new } => {
+        write!(out, "\"old\":\"{:?}\",\"new\":\"{:?}\"", old, new)
+      }
+      QlogEvent::Closed { reason } => write!(out, "\"reason\":\"{:?}\"", reason),
+    };
+  }
+}
+
+/// An event and when it happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QlogRecord {
+  pub time_micros: u64,
+  pub event: QlogEvent,
+}
+
+/// Bounded ring of events; the oldest are dropped first
+pub struct EventLog {
+  records: VecDeque<QlogRecord>,
+  capacity: usize,
+}
+
+impl EventLog {
+  pub fn new() -> Self {
+    Self::with_capacity(DEFAULT_EVENT_LOG_CAPACITY)
+  }
+
+  pub fn with_capacity(capacity: usize) -> Self {
+    Self {
+      records: VecDeque::with_capacity(capacity.min(DEFAULT_EVENT_LOG_CAPACITY)),
+      capacity,
+    }
+  }
+
+  pub fn record(&mut self, time_micros: u64, event: QlogEvent) {
+    if self.capacity == 0 {
+      return;
+    }
+    if self.records.len() == self.capacity {
+      self.records.pop_front();
+    }
+    self.records.push_back(QlogRecord { time_micros, event });
+  }
+
+  pub fn records(&self) -> impl Iterator<Item = &QlogRecord> {
+    self.records.iter()
+  }
+
+  pub fn len(&self) -> usize {
+    self.records.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.records.is_empty()
+  }
+
+  pub fn clear(&mut self) {
+    self.records.clear();
+  }
+
+  /// One JSON object per line
+  pub fn to_json_lines(&self) -> String {
+    let mut out = String::new();
+    for record in &self.records {
+      let _ = write!(
+        out,
+        "{{\"time_us\":{},\"name\":\"{}\",\"data\":{{",
+        record.time_micros,
+        record.event.name()
+      );
+      record.event.write_data(&mut out);
+      out.push_str("}}\n");
+    }
+    out
+  }
+
+  /// The events as a standalone HTML timeline, as `timeline_html`
+  pub fn to_html(&self) -> String {
+    timeline_html(&self.to_json_lines())
+  }
+}
+
+impl Default for EventLog {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Convert JSON lines written by `EventLog::to_json_lines` into a
+/// standalone HTML page: cwnd over time above a table of every event, with
+/// times in milliseconds from the first. Lines in any other form are
+/// skipped
+pub fn timeline_html(json_lines: &str) -> String {
+  let records: Vec<(u64, &str, &str)> =
+    json_lines.lines().filter_map(parse_line).collect();
+  let start = records.first().map_or(0, |(time, _, _)| *time);
+  let mut out = String::from(
+    "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Connection timeline</title>\n\
+     <style>body{font-family:monospace}td{padding:0 8px}\
+     .transport{color:#246}.recovery{color:#a40}.connectivity{color:#080}</style>\n\
+     </head><body>\n",
+  );
+  write_cwnd_plot(&records, start, &mut out);
+  out.push_str("<table>\n<tr><th>ms</th><th>event</th><th>data</th></tr>\n");
+  for (time, name, data) in &records {
+    let category = name.split(':').next().unwrap_or_default();
+    let micros = time.saturating_sub(start);
+    let _ = write!(
+      out,
+      "<tr class=\"{}\"><td>{}.{:03}</td><td>",
+      escape(category),
+      micros / 1000,
+      micros % 1000
+    );
+    let _ = writeln!(out, "{}</td><td>{}</td></tr>", escape(name), escape(data));
+  }
+  out.push_str("</table>\n</body></html>\n");
+  out
+}
+
+/// Time, name and data of one line `to_json_lines` wrote
+fn parse_line(line: &str) -> Option<(u64, &str, &str)> {
+  let rest = line.trim().strip_prefix("{\"time_us\":")?;
+  let (time, rest) = rest.split_once(",\"name\":\"")?;
+  let (name, rest) = rest.split_once("\",\"data\":{")?;
+  let data = rest.strip_suffix("}}")?;
+  Some((time.parse().ok()?, name, data))
+}
+
+/// cwnd from `recovery:metrics_updated` events as a step plot, if there
+/// are any
+fn write_cwnd_plot(records: &[(u64, &str, &str)], start: u64, out: &mut String) {
+  const WIDTH: u64 = 800;
+  const HEIGHT: u64 = 200;
+  let points: Vec<(u64, u64)> = records
+    .iter()
+    .filter(|(_, name, _)| *name == "recovery:metrics_updated")
+    .filter_map(|(time, _, data)| {
+      let cwnd = data.strip_prefix("\"cwnd\":")?.split(',').next()?;
+      Some((time.saturating_sub(start), cwnd.parse().ok()?))
+    })
+    .collect();
+  let Some(peak) = points
+    .iter()
+    .map(|(_, cwnd)| *cwnd)
+    .max()
+    .filter(|&peak| peak > 0)
+  else {
+    return;
+  };
+  let span = records
+    .last()
+    .map_or(0, |(time, _, _)| time.saturating_sub(start))
+    .max(1);
+  let _ = write!(
+    out,
+    "<p>cwnd, peak {} bytes</p>\n<svg width=\"{}\" height=\"{}\">\
+     <polyline fill=\"none\" stroke=\"#a40\" points=\"",
+    peak, WIDTH, HEIGHT
+  );
+  let mut last = None;
+  for (at, cwnd) in points {
+    let x = at * WIDTH / span;
+    let y = HEIGHT - cwnd * HEIGHT / peak;
+    if let Some(last_y) = last {
+      let _ = write!(out, "{},{} ", x, last_y);
+    }
+    let _ = write!(out, "{},{} ", x, y);
+    last = Some(y);
+  }
+  out.push_str("\"/></svg>\n");
+}
+
+/// `text` with the characters HTML gives meaning to escaped
+fn escape(text: &str) -> String {
+  let mut out = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '<' => out.push_str("&lt;"),
+      '>' => out.push_str("&gt;"),
+      '&' => out.push_str("&amp;"),
+      '"' => out.push_str("&quot;"),
+      c => out.push(c),
+    }
+  }
+  out
+}
+
+/// Flags as tcpdump letters, e.g. `S.` for a SYN-ACK
+fn flag_letters(flags: TcpFlags) -> String {
+  let mut letters = String::new();
+  for (bit, letter) in [
+    (TcpFlags::FIN, 'F'),
+    (TcpFlags::SYN, 'S'),
+    (TcpFlags::RST, 'R'),
+    (TcpFlags::PSH, 'P'),
+    (TcpFlags::URG, 'U'),
+    (TcpFlags::ECE, 'E'),
+    (TcpFlags::CWR, 'W'),
+    (TcpFlags::ACK, '.'),
+  ] {
+    if flags.0 & bit != 0 {
+      letters.push(letter);
+    }
+  }
+  letters
+}
@@ -0,0 +1,205 @@
+//! Half-open connections
+//!
+//! A SYN to a listening port is cheap to send and may never be followed by
+//! the handshake's final ACK, so it gets no `ControlBlock`. A `RequestSock`,
+//! like the kernel's `request_sock`, keeps only what the handshake needs:
+//! both initial sequence numbers, the options the SYN negotiated and the
+//! SYN-ACK retransmission timer. The receive stream, retransmission queue,
+//! congestion state and stats are built once the final ACK arrives, by
+//! `Engine::accept_request`, from the same inputs, so the connection comes
+//! out as if it had sat in SYN-RECEIVED all along.
+
+use super::control::{clamp_mss, window_scale_for, DEFAULT_MSS, MAX_RTO};
+use super::engine::{stamp_ts, syn_header};
+use super::fingerprint::OsProfile;
+use crate::memory;
+use crate::packet::{TcpFlags, TcpHeader, TcpOption};
+use crate::reliability::RetryLimits;
+use crate::utils::{Instant, SeqNumber};
+use alloc::vec::Vec;
+use core::net::SocketAddrV4;
+use core::time::Duration;
+
+/// Wait before the first SYN-ACK retransmission (RFC 6298 2.1)
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// A connection in SYN-RECEIVED that has not been acknowledged yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestSock {
+  pub local: SocketAddrV4,
+  pub remote: SocketAddrV4,
+  /// Our initial sequence number
+  pub iss: SeqNumber,
+  /// The peer's initial sequence number
+  pub irs: SeqNumber,
+  /// Window of the peer's SYN, which is never scaled
+  pub peer_window: u16,
+  /// MSS the peer advertised, if it did
+  pub peer_mss: Option<u16>,
+  /// Window scale the peer asked for, if it offered scaling
+  pub peer_window_scale: Option<u8>,
+  pub sack_permitted: bool,
+  pub timestamps: bool,
+  /// Receive buffer of the connection, which sets the window the SYN-ACK
+  /// offers
+  pub recv_buffer: usize,
+  /// MSS the SYN-ACK offers, before the peer's clamps it
+  pub mss: u16,
+  /// Window scale the SYN-ACK offers if the SYN offered scaling; the
+  /// smallest covering `recv_buffer` unless set
+  pub offered_scale: u8,
+  /// IP options were stripped from the SYN
+  pub ip_options_stripped: bool,
+  /// TSval of the next SYN-ACK sent
+  pub ts_val: u32,
+  /// TSval of the SYN, which the SYN-ACK echoes
+  pub ts_recent: u32,
+  /// System whose SYN-ACK ours mimics; set with `set_profile`
+  pub profile: Option<OsProfile>,
+  /// When the SYN arrived and the first SYN-ACK went out
+  pub opened: Instant,
+  /// SYN-ACKs resent on timeout so far
+  pub retransmits: u32,
+  /// When the SYN-ACK is next resent
+  pub deadline: Instant,
+}
+
+impl RequestSock {
+  /// The half-open connection a `syn` from `remote` opens
+  pub fn new(
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    iss: SeqNumber,
+    syn: &TcpHeader,
+    recv_buffer: usize,
+    now: Instant,
+  ) -> Self {
+    let mut request = Self {
+      local,
+      remote,
+      iss,
+      irs: SeqNumber(syn.seq_num),
+      peer_window: syn.window_size,
+      peer_mss: None,
+      peer_window_scale: None,
+      sack_permitted: false,
+      timestamps: false,
+      recv_buffer,
+      mss: DEFAULT_MSS,
+      offered_scale: window_scale_for(recv_buffer),
+      ip_options_stripped: false,
+      ts_val: 0,
+      ts_recent: 0,
+      profile: None,
+      opened: now,
+      retransmits: 0,
+      deadline: now + INITIAL_RTO,
+    };
+    for option in &syn.options {
+      match option {
+        TcpOption::MaximumSegmentSize(mss) => request.peer_mss = Some(*mss),
+        TcpOption::SackPermitted => request.sack_permitted = true,
+        TcpOption::Timestamp { ts_val, .. } => {
+          request.timestamps = true;
+          request.ts_recent = *ts_val;
+        }
+        TcpOption::WindowScale(shift) => request.peer_window_scale = Some(*shift),
+        _ => {}
+      }
+    }
+    request
+  }
+
+  /// The SYN that opened the connection, as far as it matters to the
+  /// control block that takes over
+  pub fn peer_syn(&self) -> TcpHeader {
+    let mut syn = TcpHeader::new(self.remote.port(), self.local.port());
+    syn.seq_num = self.irs.0;
+    syn.window_size = self.peer_window;
+    syn.flags = TcpFlags::new().with_syn();
+    let mut options = Vec::new();
+    if let Some(mss) = self.peer_mss {
+      options.push(TcpOption::MaximumSegmentSize(mss));
+    }
+    if self.sack_permitted {
+      options.push(TcpOption::SackPermitted);
+    }
+    if self.timestamps {
+      options.push(TcpOption::Timestamp {
+        ts_val: self.ts_recent,
+        ts_ecr: 0,
+      });
+    }
+    if let Some(shift) = self.peer_window_scale {
+      options.push(TcpOption::WindowScale(shift));
+    }
+    syn.options = options;
+    syn
+  }
+
+  /// Window scale the SYN-ACK offers; none unless the SYN offered one
+  pub fn window_scale(&self) -> Option<u8> {
+    self.peer_window_scale.map(|_| self.offered_scale)
+  }
+
+  /// The SYN-ACK, the same one `Engine::syn` sends in SYN-RECEIVED
+  pub fn syn_ack(&self) -> TcpHeader {
+    let mss = self
+      .peer_mss
+      .map_or(self.mss, |peer| clamp_mss(self.mss, peer));
+    let window = self.recv_buffer.min(memory::window_cap()) as u32;
+    let mut header = syn_header(
+      self.local,
+      self.remote,
+      self.iss,
+      Some(self.irs + 1),
+      mss,
+      window,
+      self.window_scale(),
+    );
+    stamp_ts(&mut header, self.ts_val, self.ts_recent);
+    if let Some(profile) = self.profile {
+      profile.shape_syn(&mut header, self.timestamps);
+    }
+    header
+  }
+
+  /// Answer as `profile` does, offering its window scale
+  pub fn set_profile(&mut self, profile: OsProfile) {
+    self.profile = Some(profile);
+    self.offered_scale = profile.window_scale();
+  }
+
+  /// IP TTL of the SYN-ACK
+  pub fn ttl(&self) -> u8 {
+    self.profile.map_or(64, OsProfile::ttl)
+  }
+
+  /// Whether a RST with `tcp`'s sequence number falls in the window the
+  /// SYN-ACK offered, as `Engine` checks a synchronized connection's
+  /// (RFC 5961 3.2). Any other RST is blind and ignored
+  pub fn reset_in_window(&self, tcp: &TcpHeader) -> bool {
+    let rcv_nxt = self.irs + 1;
+    let window = u32::from(self.syn_ack().window_size).max(1);
+    let seq = SeqNumber(tcp.seq_num);
+    !seq.before(rcv_nxt) && seq.before(rcv_nxt + window)
+  }
+
+  /// Whether `tcp` acknowledges the SYN-ACK and so completes the handshake
+  pub fn acknowledged_by(&self, tcp: &TcpHeader) -> bool {
+    tcp.flags.is_ack() && SeqNumber(tcp.ack_num) == self.iss + 1
+  }
+
+  /// Back off after the SYN-ACK timed out at `now`, returning false once
+  /// `limits` allow no more retransmissions and the request should go
+  pub fn on_timeout(&mut self, now: Instant, limits: &RetryLimits) -> bool {
+    if self.retransmits >= limits.syn_ack {
+      return false;
+    }
+    self.retransmits += 1;
+    let interval = (INITIAL_RTO * (1 << self.retransmits.min(16)))
+      .min(Duration::from_secs_f64(MAX_RTO));
+    self.deadline = now + interval;
+    true
+  }
+}
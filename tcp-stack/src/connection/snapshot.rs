@@ -0,0 +1,316 @@
+//! Connection checkpoints
+//!
+//! A `ConnectionSnapshot` holds what a synchronized connection needs to go
+//! on in a fresh `ControlBlock`: its 4-tuple, sequence numbers, windows,
+//! negotiated options, and the bytes not yet acknowledged, sent or read.
+//! Restored on the same 4-tuple, in another process sharing the raw socket
+//! or after a restart, the connection carries on without the peer noticing.
+//!
+//! Timers, statistics and out-of-order data are not kept: the peer resends
+//! what it sent out of order, and data awaiting acknowledgment is resent
+//! from the snapshot when the retransmission timer fires. `encode` gives a
+//! compact byte form; with the `serde` feature the snapshot also derives
+//! `Serialize` and `Deserialize`.
+
+use super::{ControlBlock, TcpState};
+use crate::reliability::retransmit::{PendingSegment, SegmentKind};
+use crate::utils::{Instant, SeqNumber};
+use alloc::vec::Vec;
+use bytes::Bytes;
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+/// First byte of an encoded snapshot, bumped when the layout changes
+const VERSION: u8 = 1;
+
+/// `TcpState`s by their encoded value
+const STATES: [TcpState; 11] = [
+  TcpState::Closed,
+  TcpState::Listen,
+  TcpState::SynSent,
+  TcpState::SynReceived,
+  TcpState::Established,
+  TcpState::FinWait1,
+  TcpState::FinWait2,
+  TcpState::CloseWait,
+  TcpState::Closing,
+  TcpState::LastAck,
+  TcpState::TimeWait,
+];
+
+/// Saved state of one connection
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionSnapshot {
+  pub local: SocketAddrV4,
+  pub remote: SocketAddrV4,
+  pub state: TcpState,
+
+  /// ISS, SND.UNA and SND.NXT
+  pub iss: u32,
+  pub snd_una: u32,
+  pub snd_nxt: u32,
+  /// Peer's window in bytes, already scaled
+  pub snd_wnd: u32,
+  /// IRS and RCV.NXT
+  pub irs: u32,
+  pub rcv_nxt: u32,
+  pub recv_buffer: u32,
+
+  pub mss: u16,
+  pub window_scale: u8,
+  pub peer_window_scale: u8,
+  pub window_scaling: bool,
+  pub sack_permitted: bool,
+  pub timestamps: bool,
+
+  pub cwnd: u32,
+  pub ssthresh: u32,
+  /// Smoothed RTT and its variation in seconds; zero before any sample
+  pub srtt: f64,
+  pub rttvar: f64,
+
+  /// Data sent from SND.UNA and not yet acknowledged. A FIN awaiting its
+  /// ACK follows it when SND.NXT is one past the data
+  pub unacked: Vec<u8>,
+  /// Data queued but not yet sent
+  pub unsent: Vec<u8>,
+  /// Data received and not yet read, ending at RCV.NXT
+  pub unread: Vec<u8>,
+}
+
+impl ConnectionSnapshot {
+  /// Save the connection between `local` and `remote`, or `None` before
+  /// both sides are synchronized or once it has closed
+  pub fn capture(
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    control: &ControlBlock,
+  ) -> Option<Self> {
+    if matches!(
+      control.state,
+      TcpState::Closed | TcpState::Listen | TcpState::SynSent | TcpState::SynReceived
+    ) {
+      return None;
+    }
+    let snd_una = control.snd_una();
+    let mut unacked = Vec::new();
+    for segment in control.retransmit.pending() {
+      let offset = segment.seq.diff(snd_una) as usize;
+      let end = offset + segment.data.len();
+      if unacked.len() < end {
+        unacked.resize(end, 0);
+      }
+      unacked[offset..end].copy_from_slice(&segment.data);
+    }
+    Some(Self {
+      local,
+      remote,
+      state: control.state,
+      iss: control.send_seq.0,
+      snd_una: snd_una.0,
+      snd_nxt: control.snd_nxt().0,
+      snd_wnd: control.snd_wnd(),
+      irs: control.recv_seq.0,
+      rcv_nxt: control.rcv_nxt().0,
+      recv_buffer: control.recv_stream.capacity() as u32,
+      mss: control.mss,
+      window_scale: control.window_scale,
+      peer_window_scale: control.peer_window_scale,
+      window_scaling: control.window_scaling,
+      sack_permitted: control.sack_permitted,
+      timestamps: control.timestamps,
+      cwnd: control.congestion.cwnd(),
+      ssthresh: control.congestion.ssthresh(),
+      srtt: control.rtt_estimator.srtt(),
+      rttvar: control.rtt_estimator.rttvar(),
+      unacked,
+      unsent: control.send_queue.unsent(),
+      unread: control.recv_stream.unread(),
+    })
+  }
+
+  /// A control block that carries on where the snapshot left off. The data
+  /// awaiting acknowledgment is held for retransmission at `now`
+  pub fn restore(&self, now: Instant) -> ControlBlock {
+    let mut control = ControlBlock::with_initial_seq(SeqNumber(self.iss), now);
+    control.set_recv_buffer(self.recv_buffer as usize);
+    control.state = self.state;
+    control.mss = self.mss;
+    control.window_scale = self.window_scale;
+    control.peer_window_scale = self.peer_window_scale;
+    control.window_scaling = self.window_scaling;
+    control.sack_permitted = self.sack_permitted;
+    control.timestamps = self.timestamps;
+
+    control.recv_seq = SeqNumber(self.irs);
+    let rcv_nxt = SeqNumber(self.rcv_nxt);
+    let unread_start = rcv_nxt - self.unread.len() as u32;
+    control.recv_stream.set_rcv_nxt(unread_start);
+    control.recv_stream.push(unread_start, self.unread.clone());
+
+    let snd_una = SeqNumber(self.snd_una);
+    let snd_nxt = SeqNumber(self.snd_nxt);
+    control.resume_send(snd_una, snd_nxt, self.snd_wnd);
+    control.congestion.restore(self.cwnd, self.ssthresh);
+    control.rtt_estimator.restore(self.srtt, self.rttvar);
+    control.send_queue.write(&self.unsent);
+
+    let rto = control.rtt_estimator.rto();
+    let mss = usize::from(self.mss.max(1));
+    let mut seq = snd_una;
+    for chunk in self.unacked.chunks(mss) {
+      let len = chunk.len() as u32;
+      control.retransmit.add_segment(
+        held(seq, len, Bytes::copy_from_slice(chunk), SegmentKind::Data, now),
+        rto,
+        now,
+      );
+      seq = seq + len;
+    }
+    if snd_nxt.diff(snd_una) as usize > self.unacked.len() {
+      control.retransmit.add_segment(
+        held(seq, 1, Bytes::new(), SegmentKind::Fin, now),
+        rto,
+        now,
+      );
+    }
+    control
+  }
+
+  /// The snapshot as bytes, for `decode` in this or another process
+  pub fn encode(&self) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(64 + self.unacked.len() + self.unsent.len());
+    buf.push(VERSION);
+    for addr in [self.local, self.remote] {
+      buf.extend_from_slice(&addr.ip().octets());
+      buf.extend_from_slice(&addr.port().to_be_bytes());
+    }
+    let state = STATES.iter().position(|state| *state == self.state);
+    buf.push(state.unwrap_or(0) as u8);
+    for value in [
+      self.iss,
+      self.snd_una,
+      self.snd_nxt,
+      self.snd_wnd,
+      self.irs,
+      self.rcv_nxt,
+      self.recv_buffer,
+      self.cwnd,
+      self.ssthresh,
+    ] {
+      buf.extend_from_slice(&value.to_be_bytes());
+    }
+    buf.extend_from_slice(&self.mss.to_be_bytes());
+    buf.push(self.window_scale);
+    buf.push(self.peer_window_scale);
+    let flags = u8::from(self.window_scaling)
+      | u8::from(self.sack_permitted) << 1
+      | u8::from(self.timestamps) << 2;
+    buf.push(flags);
+    buf.extend_from_slice(&self.srtt.to_bits().to_be_bytes());
+    buf.extend_from_slice(&self.rttvar.to_bits().to_be_bytes());
+    for data in [&self.unacked, &self.unsent, &self.unread] {
+      buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+      buf.extend_from_slice(data);
+    }
+    buf
+  }
+
+  /// Read a snapshot written by `encode`, or `None` if `data` is not one
+  pub fn decode(data: &[u8]) -> Option<Self> {
+    let mut reader = Reader(data);
+    if reader.u8()? != VERSION {
+      return None;
+    }
+    let local = reader.addr()?;
+    let remote = reader.addr()?;
+    let state = *STATES.get(usize::from(reader.u8()?))?;
+    let [iss, snd_una, snd_nxt, snd_wnd, irs, rcv_nxt, recv_buffer, cwnd, ssthresh] =
+      [(); 9].map(|_| reader.u32());
+    let mss = u16::from_be_bytes(reader.take()?);
+    let window_scale = reader.u8()?;
+    let peer_window_scale = reader.u8()?;
+    let flags = reader.u8()?;
+    let srtt = f64::from_bits(u64::from_be_bytes(reader.take()?));
+    let rttvar = f64::from_bits(u64::from_be_bytes(reader.take()?));
+    let snapshot = Self {
+      local,
+      remote,
+      state,
+      iss: iss?,
+      snd_una: snd_una?,
+      snd_nxt: snd_nxt?,
+      snd_wnd: snd_wnd?,
+      irs: irs?,
+      rcv_nxt: rcv_nxt?,
+      recv_buffer: recv_buffer?,
+      mss,
+      window_scale,
+      peer_window_scale,
+      window_scaling: flags & 1 != 0,
+      sack_permitted: flags & 2 != 0,
+      timestamps: flags & 4 != 0,
+      cwnd: cwnd?,
+      ssthresh: ssthresh?,
+      srtt,
+      rttvar,
+      unacked: reader.bytes()?,
+      unsent: reader.bytes()?,
+      unread: reader.bytes()?,
+    };
+    reader.0.is_empty().then_some(snapshot)
+  }
+}
+
+/// A restored segment awaiting acknowledgment. Counted as retransmitted so
+/// its ACK yields no RTT sample (Karn): it was first sent before the restore
+fn held(
+  seq: SeqNumber,
+  len: u32,
+  data: Bytes,
+  kind: SegmentKind,
+  now: Instant,
+) -> PendingSegment {
+  PendingSegment {
+    seq,
+    len,
+    data,
+    retransmit_count: 1,
+    first_sent: now,
+    kind,
+  }
+}
+
+/// Cursor over an encoded snapshot
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+  fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+    let (head, rest) = self.0.split_first_chunk::<N>()?;
+    self.0 = rest;
+    Some(*head)
+  }
+
+  fn u8(&mut self) -> Option<u8> {
+    Some(self.take::<1>()?[0])
+  }
+
+  fn u32(&mut self) -> Option<u32> {
+    Some(u32::from_be_bytes(self.take()?))
+  }
+
+  fn addr(&mut self) -> Option<SocketAddrV4> {
+    let ip = Ipv4Addr::from(self.take::<4>()?);
+    Some(SocketAddrV4::new(ip, u16::from_be_bytes(self.take()?)))
+  }
+
+  fn bytes(&mut self) -> Option<Vec<u8>> {
+    let len = self.u32()? as usize;
+    if self.0.len() < len {
+      return None;
+    }
+    let (data, rest) = self.0.split_at(len);
+    self.0 = rest;
+    Some(data.to_vec())
+  }
+}
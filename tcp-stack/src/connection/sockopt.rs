@@ -0,0 +1,188 @@
+//! setsockopt-style option access
+//!
+//! `TcpConnection::set_option` and `get_option` expose the connection's
+//! tunables under the names socket programmers know, each mapped onto the
+//! control block field that implements it.
+
+use super::control::{MAX_WINDOW_SCALE, MIN_MSS};
+use super::{KeepalivePolicy, TcpConnection};
+use crate::congestion::CongestionAlgorithm;
+use crate::error::{Result, TcpError};
+use std::time::Duration;
+
+/// A connection option together with its value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnOption {
+  /// `TCP_NODELAY`: disable Nagle's algorithm
+  NoDelay(bool),
+  /// `SO_KEEPALIVE` with the idle time before probing (`TCP_KEEPIDLE`);
+  /// `None` disables
+  KeepAlive(Option<Duration>),
+  /// `TCP_KEEPINTVL`: wait between unanswered keep-alive probes
+  KeepInterval(Duration),
+  /// `TCP_KEEPCNT`: unanswered probes before the peer is unreachable
+  KeepCount(u32),
+  /// `SO_LINGER`: how long closing waits for unsent data; `None` disables
+  Linger(Option<Duration>),
+  /// `TCP_MAXSEG`: MSS to advertise and send with; set before connecting
+  MaxSeg(u16),
+  /// `SO_RCVBUF`: receive buffer, and so the largest window advertised.
+  /// Set before connecting, it also picks the window scale we offer
+  RcvBuf(usize),
+  /// Window scale shift to offer in our SYN in place of the one derived
+  /// from `RcvBuf`; set before connecting
+  WindowScale(u8),
+  /// `SO_SNDBUF`: cap on unsent plus unacknowledged bytes
+  SndBuf(usize),
+  /// `SO_SNDLOWAT`: writable bytes needed before a stream reports it is
+  /// ready for writing
+  SndLowat(usize),
+  /// `TCP_USER_TIMEOUT` (RFC 5482): how long data may stay unacknowledged
+  /// before the connection is dropped; `None` disables
+  UserTimeout(Option<Duration>),
+  /// `TCP_LINGER2`: how long FIN-WAIT-2 waits for the peer's FIN before
+  /// the connection is reset; `None` waits for ever
+  Linger2(Option<Duration>),
+  /// `TCP_CONGESTION`
+  CongestionAlgorithm(CongestionAlgorithm),
+  /// `IP_TTL`
+  Ttl(u8),
+  /// `IP_TOS`: DSCP in the upper six bits, ECN in the lower two
+  Tos(u8),
+}
+
+/// Names of the options, for `get_option`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnOptionKind {
+  NoDelay,
+  KeepAlive,
+  KeepInterval,
+  KeepCount,
+  Linger,
+  MaxSeg,
+  RcvBuf,
+  WindowScale,
+  SndBuf,
+  SndLowat,
+  UserTimeout,
+  Linger2,
+  CongestionAlgorithm,
+  Ttl,
+  Tos,
+}
+
+impl ConnOption {
+  /// Fail with `InvalidOption` for values the stack cannot honour
+  pub fn check(&self) -> Result<()> {
+    let problem = match *self {
+      ConnOption::KeepInterval(interval) if interval.is_zero() => "KeepInterval of zero",
+      ConnOption::KeepCount(0) => "KeepCount of zero",
+      ConnOption::MaxSeg(mss) if mss < MIN_MSS => "MaxSeg below 536",
+      ConnOption::WindowScale(shift) if shift > MAX_WINDOW_SCALE => "WindowScale above 14",
+      ConnOption::Ttl(0) => "Ttl of zero",
+      _ => return Ok(()),
+    };
+    Err(TcpError::InvalidOption(problem))
+  }
+
+  pub fn kind(&self) -> ConnOptionKind {
+    match self {
+      ConnOption::NoDelay(_) => ConnOptionKind::NoDelay,
+      ConnOption::KeepAlive(_) => ConnOptionKind::KeepAlive,
+      ConnOption::KeepInterval(_) => ConnOptionKind::KeepInterval,
+      ConnOption::KeepCount(_) => ConnOptionKind::KeepCount,
+      ConnOption::Linger(_) => ConnOptionKind::Linger,
+      ConnOption::MaxSeg(_) => ConnOptionKind::MaxSeg,
+      ConnOption::RcvBuf(_) => ConnOptionKind::RcvBuf,
+      ConnOption::WindowScale(_) => ConnOptionKind::WindowScale,
+      ConnOption::SndBuf(_) => ConnOptionKind::SndBuf,
+      ConnOption::SndLowat(_) => ConnOptionKind::SndLowat,
+      ConnOption::UserTimeout(_) => ConnOptionKind::UserTimeout,
+      ConnOption::Linger2(_) => ConnOptionKind::Linger2,
+      ConnOption::CongestionAlgorithm(_) => ConnOptionKind::CongestionAlgorithm,
+      ConnOption::Ttl(_) => ConnOptionKind::Ttl,
+      ConnOption::Tos(_) => ConnOptionKind::Tos,
+    }
+  }
+}
+
+impl TcpConnection {
+  /// Set an option. Fails with `InvalidOption` for values the stack cannot
+  /// honour
+  pub fn set_option(&mut self, option: ConnOption) -> Result<()> {
+    option.check()?;
+    let control = &mut self.subflow.control;
+    match option {
+      ConnOption::NoDelay(nodelay) => control.send_queue.set_nagle(!nodelay),
+      ConnOption::KeepAlive(idle) => {
+        control.keepalive.set_enabled(idle.is_some());
+        if let Some(idle) = idle {
+          let policy = control.keepalive.policy();
+          control
+            .keepalive
+            .set_policy(KeepalivePolicy { idle, ..policy });
+        }
+      }
+      ConnOption::KeepInterval(interval) => {
+        let policy = control.keepalive.policy();
+        control
+          .keepalive
+          .set_policy(KeepalivePolicy { interval, ..policy });
+      }
+      ConnOption::KeepCount(probes) => {
+        let policy = control.keepalive.policy();
+        control
+          .keepalive
+          .set_policy(KeepalivePolicy { probes, ..policy });
+      }
+      ConnOption::Linger(timeout) => control.linger = timeout,
+      ConnOption::MaxSeg(mss) => control.mss = mss,
+      ConnOption::RcvBuf(size) => control.set_recv_buffer(size),
+      ConnOption::WindowScale(shift) => control.window_scale = shift,
+      ConnOption::SndBuf(size) => control.send_buffer_limit = size,
+      ConnOption::SndLowat(bytes) => control.send_low_watermark = bytes,
+      ConnOption::UserTimeout(timeout) => control.user_timeout = timeout,
+      ConnOption::Linger2(timeout) => control.fin_wait2_timeout = timeout,
+      ConnOption::CongestionAlgorithm(CongestionAlgorithm::NewReno) => {}
+      ConnOption::Ttl(ttl) => control.ttl = ttl,
+      ConnOption::Tos(tos) => {
+        control.dscp = tos >> 2;
+        control.ecn = tos & 0x03;
+      }
+    }
+    Ok(())
+  }
+
+  /// Current value of an option
+  pub fn get_option(&self, kind: ConnOptionKind) -> ConnOption {
+    let control = &self.subflow.control;
+    match kind {
+      ConnOptionKind::NoDelay => ConnOption::NoDelay(!control.send_queue.nagle()),
+      ConnOptionKind::KeepAlive => ConnOption::KeepAlive(
+        control
+          .keepalive
+          .is_enabled()
+          .then(|| control.keepalive.policy().idle),
+      ),
+      ConnOptionKind::KeepInterval => {
+        ConnOption::KeepInterval(control.keepalive.policy().interval)
+      }
+      ConnOptionKind::KeepCount => {
+        ConnOption::KeepCount(control.keepalive.policy().probes)
+      }
+      ConnOptionKind::Linger => ConnOption::Linger(control.linger),
+      ConnOptionKind::MaxSeg => ConnOption::MaxSeg(control.mss),
+      ConnOptionKind::RcvBuf => ConnOption::RcvBuf(control.recv_stream.capacity()),
+      ConnOptionKind::WindowScale => ConnOption::WindowScale(control.window_scale),
+      ConnOptionKind::SndBuf => ConnOption::SndBuf(control.send_buffer_limit),
+      ConnOptionKind::SndLowat => ConnOption::SndLowat(control.send_low_watermark),
+      ConnOptionKind::UserTimeout => ConnOption::UserTimeout(control.user_timeout),
+      ConnOptionKind::Linger2 => ConnOption::Linger2(control.fin_wait2_timeout),
+      ConnOptionKind::CongestionAlgorithm => {
+        ConnOption::CongestionAlgorithm(CongestionAlgorithm::NewReno)
+      }
+      ConnOptionKind::Ttl => ConnOption::Ttl(control.ttl),
+      ConnOptionKind::Tos => ConnOption::Tos(control.dscp << 2 | control.ecn),
+    }
+  }
+}
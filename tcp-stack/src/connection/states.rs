@@ -2,6 +2,7 @@
 
 /// TCP connection states (RFC 793)
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TcpState {
   #[default]
   Closed,
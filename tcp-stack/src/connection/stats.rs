@@ -0,0 +1,269 @@
+//! Per-connection statistics and receive-path drop accounting
+//!
+//! Counters are `u64` and saturate rather than wrap. `ConnectionStats::reset`
+//! zeroes them and starts a new epoch, so a monitor holding an older
+//! snapshot can tell a reset from a wrap and take deltas with `since`.
+
+/// Counters describing the activity of a single connection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionStats {
+  pub segments_sent: u64,
+  pub segments_received: u64,
+  pub bytes_sent: u64,
+  pub bytes_received: u64,
+  /// Retransmissions of every kind, the two below included
+  pub retransmissions: u64,
+  /// Retransmitted SYNs and SYN-ACKs
+  pub syn_retransmissions: u64,
+  pub fin_retransmissions: u64,
+  /// Bytes cumulatively acknowledged by the peer: the delivered count for
+  /// delivery-rate samples, stretch ACKs included in full
+  pub bytes_acked: u64,
+  pub duplicate_acks: u64,
+  /// ACKs that moved SND.UNA into the middle of a segment, as an
+  /// ACK-division attack sends
+  pub split_acks: u64,
+  /// Duplicate ACKs beyond the segments in flight, kept from inflating cwnd
+  pub excess_duplicate_acks: u64,
+  /// ACKs for data never sent (optimistic ACKs), ignored
+  pub optimistic_acks: u64,
+  /// Payload bytes received that had already arrived: retransmissions the
+  /// peer did not need to send, or copies made by the network
+  pub duplicate_bytes_received: u64,
+  /// Bytes the peer reported receiving twice in a D-SACK block (RFC 2883)
+  /// once we had retransmitted: retransmissions that were not needed
+  pub spurious_retransmit_bytes: u64,
+  /// DSCP of the most recent segment from the peer
+  pub peer_dscp: u8,
+  /// Received segments marked Congestion Experienced
+  pub ecn_ce_received: u64,
+  /// Received segments processed without the IP options they carried
+  pub ip_options_stripped: u64,
+  /// Received segments discarded, by reason
+  pub drops: DropCounters,
+  /// Window scaling as negotiated; `None` unless both SYNs carried it
+  pub window_scaling: Option<WindowScaling>,
+  /// Times the counters have been reset
+  pub epoch: u64,
+}
+
+impl ConnectionStats {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Zero the counters and start a new epoch
+  pub fn reset(&mut self) {
+    *self = Self {
+      peer_dscp: self.peer_dscp,
+      window_scaling: self.window_scaling,
+      epoch: self.epoch.saturating_add(1),
+      ..Self::default()
+    };
+  }
+
+  /// ACKs that fit the pattern of a receiver gaming congestion control; a
+  /// sender exposed to untrusted clients can watch this grow
+  pub fn suspicious_acks(&self) -> u64 {
+    self
+      .split_acks
+      .saturating_add(self.excess_duplicate_acks)
+      .saturating_add(self.optimistic_acks)
+  }
+
+  /// Counts since `earlier`, a snapshot of these stats; everything counted
+  /// in this epoch if they were reset since
+  pub fn since(&self, earlier: &ConnectionStats) -> ConnectionStats {
+    if earlier.epoch != self.epoch {
+      return self.clone();
+    }
+    ConnectionStats {
+      segments_sent: self.segments_sent.saturating_sub(earlier.segments_sent),
+      segments_received: self
+        .segments_received
+        .saturating_sub(earlier.segments_received),
+      bytes_sent: self.bytes_sent.saturating_sub(earlier.bytes_sent),
+      bytes_received: self.bytes_received.saturating_sub(earlier.bytes_received),
+      retransmissions: self.retransmissions.saturating_sub(earlier.retransmissions),
+      syn_retransmissions: self
+        .syn_retransmissions
+        .saturating_sub(earlier.syn_retransmissions),
+      fin_retransmissions: self
+        .fin_retransmissions
+        .saturating_sub(earlier.fin_retransmissions),
+      bytes_acked: self.bytes_acked.saturating_sub(earlier.bytes_acked),
+      duplicate_acks: self.duplicate_acks.saturating_sub(earlier.duplicate_acks),
+      split_acks: self.split_acks.saturating_sub(earlier.split_acks),
+      excess_duplicate_acks: self
+        .excess_duplicate_acks
+        .saturating_sub(earlier.excess_duplicate_acks),
+      optimistic_acks: self.optimistic_acks.saturating_sub(earlier.optimistic_acks),
+      duplicate_bytes_received: self
+        .duplicate_bytes_received
+        .saturating_sub(earlier.duplicate_bytes_received),
+      spurious_retransmit_bytes: self
+        .spurious_retransmit_bytes
+        .saturating_sub(earlier.spurious_retransmit_bytes),
+      peer_dscp: self.peer_dscp,
+      ecn_ce_received: self.ecn_ce_received.saturating_sub(earlier.ecn_ce_received),
+      ip_options_stripped: self
+        .ip_options_stripped
+        .saturating_sub(earlier.ip_options_stripped),
+      drops: self.drops.since(&earlier.drops),
+      window_scaling: self.window_scaling,
+      epoch: self.epoch,
+    }
+  }
+}
+
+/// Window scale shifts agreed in the handshake (RFC 7323)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WindowScaling {
+  /// Applied to the windows we advertise
+  pub local: u8,
+  /// Applied to the peer's windows
+  pub peer: u8,
+  /// What the peer's SYN asked for, above `peer` when it was capped
+  pub requested: u8,
+}
+
+/// Add `n` to a counter, saturating rather than wrapping
+pub fn count(counter: &mut u64, n: u64) {
+  *counter = counter.saturating_add(n);
+}
+
+/// Why a received packet was discarded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DropReason {
+  /// TCP checksum did not verify
+  BadChecksum,
+  /// Sequence or acknowledgment number outside the window
+  OutOfWindow,
+  /// No connection or listener for the segment
+  NoConnection,
+  /// Timestamp older than the last one seen (RFC 7323 PAWS)
+  PawsFail,
+  /// Backlog or receive buffer full
+  BufferFull,
+  /// Truncated header or unparsable options
+  MalformedOptions,
+  /// TTL below the GTSM threshold (RFC 5082)
+  TtlTooLow,
+  /// SYN turned away by a listener's SYN filter
+  Filtered,
+  /// SYN over a rate limit
+  RateLimited,
+  /// IP options refused by the `IpOptionsPolicy`
+  IpOptions,
+}
+
+impl DropReason {
+  pub const ALL: [DropReason; 10] = [
+    DropReason::BadChecksum,
+    DropReason::OutOfWindow,
+    DropReason::NoConnection,
+    DropReason::PawsFail,
+    DropReason::BufferFull,
+    DropReason::MalformedOptions,
+    DropReason::TtlTooLow,
+    DropReason::Filtered,
+    DropReason::RateLimited,
+    DropReason::IpOptions,
+  ];
+}
+
+/// Drop counts per `DropReason`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DropCounters {
+  pub bad_checksum: u64,
+  pub out_of_window: u64,
+  pub no_connection: u64,
+  pub paws_fail: u64,
+  pub buffer_full: u64,
+  pub malformed_options: u64,
+  pub ttl_too_low: u64,
+  pub filtered: u64,
+  pub rate_limited: u64,
+  pub ip_options: u64,
+}
+
+impl DropCounters {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn record(&mut self, reason: DropReason) {
+    count(self.counter_mut(reason), 1);
+  }
+
+  /// Drops since `earlier`
+  pub fn since(&self, earlier: &DropCounters) -> DropCounters {
+    let mut delta = DropCounters::new();
+    for reason in DropReason::ALL {
+      *delta.counter_mut(reason) = self.get(reason).saturating_sub(earlier.get(reason));
+    }
+    delta
+  }
+
+  pub fn get(&self, reason: DropReason) -> u64 {
+    match reason {
+      DropReason::BadChecksum => self.bad_checksum,
+      DropReason::OutOfWindow => self.out_of_window,
+      DropReason::NoConnection => self.no_connection,
+      DropReason::PawsFail => self.paws_fail,
+      DropReason::BufferFull => self.buffer_full,
+      DropReason::MalformedOptions => self.malformed_options,
+      DropReason::TtlTooLow => self.ttl_too_low,
+      DropReason::Filtered => self.filtered,
+      DropReason::RateLimited => self.rate_limited,
+      DropReason::IpOptions => self.ip_options,
+    }
+  }
+
+  pub fn total(&self) -> u64 {
+    DropReason::ALL
+      .iter()
+      .fold(0, |total, &reason| total.saturating_add(self.get(reason)))
+  }
+
+  fn counter_mut(&mut self, reason: DropReason) -> &mut u64 {
+    match reason {
+      DropReason::BadChecksum => &mut self.bad_checksum,
+      DropReason::OutOfWindow => &mut self.out_of_window,
+      DropReason::NoConnection => &mut self.no_connection,
+      DropReason::PawsFail => &mut self.paws_fail,
+      DropReason::BufferFull => &mut self.buffer_full,
+      DropReason::MalformedOptions => &mut self.malformed_options,
+      DropReason::TtlTooLow => &mut self.ttl_too_low,
+      DropReason::Filtered => &mut self.filtered,
+      DropReason::RateLimited => &mut self.rate_limited,
+      DropReason::IpOptions => &mut self.ip_options,
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+static STACK_DROPS: [core::sync::atomic::AtomicU64; DropReason::ALL.len()] =
+  [const { core::sync::atomic::AtomicU64::new(0) }; DropReason::ALL.len()];
+
+/// Count a drop against the whole stack
+#[cfg(feature = "std")]
+pub fn record_stack_drop(reason: DropReason) {
+  STACK_DROPS[reason as usize].fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+}
+
+/// Drops across every connection and listener in the process, including
+/// packets that matched no connection
+#[cfg(feature = "std")]
+pub fn stack_drops() -> DropCounters {
+  let mut counters = DropCounters::new();
+  for reason in DropReason::ALL {
+    *counters.counter_mut(reason) =
+      STACK_DROPS[reason as usize].load(core::sync::atomic::Ordering::Relaxed);
+  }
+  counters
+}
@@ -0,0 +1,152 @@
+//! Async stream adapter
+//!
+//! `TcpStream` drives a `TcpConnection` from a tokio task. Its raw socket is
+//! registered with the reactor, so arriving ACKs wake whoever waits on the
+//! stream, and a timer wakes it when the retransmission or persist timer
+//! fires.
+//!
+//! Writers get backpressure from `writable().await` or `poll_write_ready`,
+//! which resolve once the effective window and the send buffer both have
+//! room for `send_low_watermark` bytes.
+
+use super::{CloseReason, TcpConnection, TcpState};
+use crate::utils::Instant;
+use std::future::{poll_fn, Future};
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::time::Sleep;
+
+/// Async adapter over an established connection
+pub struct TcpStream {
+  /// Declared before `conn` so it deregisters before the socket closes
+  readiness: AsyncFd<RawFd>,
+  conn: TcpConnection,
+  retransmit_timer: Pin<Box<Sleep>>,
+  buf: Vec<u8>,
+}
+
+impl TcpStream {
+  /// Wrap a connection, switching its socket to non-blocking. Must be
+  /// called from within a tokio runtime
+  pub fn new(conn: TcpConnection) -> io::Result<Self> {
+    conn.subflow.socket.set_nonblocking(true)?;
+    let readiness = AsyncFd::new(conn.subflow.socket.as_raw_fd())?;
+    Ok(Self {
+      readiness,
+      conn,
+      retransmit_timer: Box::pin(tokio::time::sleep(Duration::ZERO)),
+      buf: vec![0u8; 65535],
+    })
+  }
+
+  pub fn connection(&self) -> &TcpConnection {
+    &self.conn
+  }
+
+  pub fn connection_mut(&mut self) -> &mut TcpConnection {
+    &mut self.conn
+  }
+
+  /// Hand the connection back with its socket blocking again
+  pub fn into_inner(self) -> io::Result<TcpConnection> {
+    let Self {
+      readiness, conn, ..
+    } = self;
+    drop(readiness);
+    conn.subflow.socket.set_nonblocking(false)?;
+    Ok(conn)
+  }
+
+  /// Writable bytes needed before the stream reports it is ready
+  /// (`SO_SNDLOWAT`)
+  pub fn set_send_low_watermark(&mut self, bytes: usize) {
+    self.conn.subflow.control.send_low_watermark = bytes;
+  }
+
+  /// Queue as much of `data` as the send buffer takes and send what the
+  /// windows allow, without waiting. Returns the count queued
+  pub fn try_write(&mut self, data: &[u8]) -> io::Result<usize> {
+    let len = self.conn.subflow.control.write(data);
+    self.conn.transmit(Instant::now())?;
+    Ok(len)
+  }
+
+  /// Wait until the stream is writable, then `try_write`
+  pub async fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+    self.writable().await?;
+    self.try_write(data)
+  }
+
+  /// Wait until at least `send_low_watermark` bytes are writable
+  pub async fn writable(&mut self) -> io::Result<()> {
+    poll_fn(|cx| self.poll_write_ready(cx)).await
+  }
+
+  /// Ready once at least `send_low_watermark` bytes are writable. Until
+  /// then, processes the peer's ACKs and retransmits as they fall due.
+  /// Fails once the connection can no longer send: with the error of its
+  /// `CloseReason` if it closed, `NotConnected` otherwise
+  pub fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    loop {
+      self.conn.transmit(Instant::now())?;
+      if !matches!(
+        self.conn.state(),
+        TcpState::Established | TcpState::CloseWait
+      ) {
+        let error = match self.conn.close_reason() {
+          Some(reason) => reason.into(),
+          None => io::ErrorKind::NotConnected.into(),
+        };
+        return Poll::Ready(Err(error));
+      }
+      if self.conn.subflow.control.write_ready() {
+        return Poll::Ready(Ok(()));
+      }
+      if self.poll_retransmit_timer(cx).is_ready() {
+        continue;
+      }
+
+      let mut guard = ready!(self.readiness.poll_read_ready(cx))?;
+      let (conn, buf) = (&mut self.conn, &mut self.buf);
+      if let Ok(result) = guard.try_io(|_| drain(conn, buf)) {
+        result?;
+      }
+    }
+  }
+
+  /// Ready when the retransmission, persist, window update or keep-alive
+  /// timer fires; pending while all are idle
+  fn poll_retransmit_timer(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+    let Some(deadline) = self.conn.subflow.control.next_deadline() else {
+      return Poll::Pending;
+    };
+    let wait = deadline.saturating_duration_since(Instant::now());
+    self
+      .retransmit_timer
+      .as_mut()
+      .reset(tokio::time::Instant::now() + wait);
+    self.retransmit_timer.as_mut().poll(cx)
+  }
+}
+
+/// Process every segment waiting on the socket. Only returns on error,
+/// `WouldBlock` once the socket is empty
+fn drain(conn: &mut TcpConnection, buf: &mut [u8]) -> io::Result<()> {
+  loop {
+    let Some(tcp) = conn.read_segment(buf)? else {
+      continue;
+    };
+    if tcp.flags.is_rst() {
+      debug!("[conn {}] Reset by {}", conn.trace_id(), conn.subflow.remote);
+      conn.close_with(CloseReason::PeerRst);
+      return Err(CloseReason::PeerRst.into());
+    }
+    if tcp.flags.is_ack() {
+      conn.on_ack_segment(&tcp)?;
+    }
+  }
+}
@@ -0,0 +1,127 @@
+//! One path of a connection
+//!
+//! A `Subflow` carries a connection's segments over one 4-tuple: it holds
+//! the raw socket, both addresses and the `ControlBlock` with that path's
+//! sequence space, congestion window and RTT estimate, and it does the
+//! segment I/O. `TcpConnection` owns one and adds what belongs to the
+//! connection as a whole: its id, tracing and events. A multipath or
+//! connection-migration layer can then hold several subflows under one
+//! connection.
+
+use super::{stats, ControlBlock, DropReason, Engine};
+use crate::demux::ConnectionKey;
+use crate::packet::{Ipv4Header, TcpHeader};
+use crate::socket::RawSocket;
+use std::io;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+
+/// What a packet read from the socket was, to the subflow
+pub(crate) enum Arrival<'a> {
+  /// A segment of this subflow, checksum verified
+  Segment {
+    ip: Ipv4Header,
+    tcp: TcpHeader,
+    payload: &'a [u8],
+    /// The whole TCP segment, for tracing
+    segment: &'a [u8],
+  },
+  /// A segment of this subflow that had to be discarded
+  Dropped(DropReason),
+  /// Someone else's packet
+  Foreign,
+}
+
+/// The segments of a connection over one 4-tuple
+pub struct Subflow {
+  pub local: SocketAddrV4,
+  pub remote: SocketAddrV4,
+  pub socket: RawSocket,
+  pub control: ControlBlock,
+}
+
+impl Subflow {
+  pub fn new(socket: RawSocket, local: SocketAddrV4, remote: SocketAddrV4) -> Self {
+    Self {
+      local,
+      remote,
+      socket,
+      control: ControlBlock::new(),
+    }
+  }
+
+  pub fn key(&self) -> ConnectionKey {
+    ConnectionKey::new(self.local, self.remote)
+  }
+
+  /// Congestion window in bytes
+  pub fn cwnd(&self) -> u32 {
+    self.control.congestion.cwnd()
+  }
+
+  /// Smoothed RTT, or `None` before the first sample
+  pub fn srtt(&self) -> Option<Duration> {
+    let srtt = self.control.rtt_estimator.srtt();
+    (srtt > 0.0).then(|| Duration::from_secs_f64(srtt))
+  }
+
+  /// Segment processing for this subflow
+  pub fn engine(&mut self) -> Engine<'_> {
+    Engine::new(self.local, self.remote, &mut self.control)
+  }
+
+  /// Read one packet from the socket and say whether it is ours
+  pub(crate) fn recv<'a>(&mut self, buf: &'a mut [u8]) -> io::Result<Arrival<'a>> {
+    let (len, _) = self.socket.recv_from(buf)?;
+    let buf = &buf[..len];
+    let Some((ip, segment)) = Ipv4Header::parse(buf) else {
+      return Ok(Arrival::Foreign);
+    };
+    if ip.protocol != Ipv4Header::PROTOCOL_TCP || ip.src_addr != *self.remote.ip() {
+      return Ok(Arrival::Foreign);
+    }
+    if segment.len() < 4
+      || u16::from_be_bytes([segment[0], segment[1]]) != self.remote.port()
+      || u16::from_be_bytes([segment[2], segment[3]]) != self.local.port()
+    {
+      return Ok(Arrival::Foreign);
+    }
+    if !super::checksum_ok(&ip, segment) {
+      return Ok(Arrival::Dropped(DropReason::BadChecksum));
+    }
+    let Some((tcp, payload)) = TcpHeader::parse(segment) else {
+      return Ok(Arrival::Dropped(DropReason::MalformedOptions));
+    };
+    Ok(Arrival::Segment {
+      ip,
+      tcp,
+      payload,
+      segment,
+    })
+  }
+
+  /// Checksum, wrap in IPv4 and transmit a segment to the peer, returning
+  /// the TCP segment as sent
+  pub(crate) fn send(&mut self, header: &TcpHeader, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let checksum = header.calculate_checksum(
+      u32::from(*self.local.ip()),
+      u32::from(*self.remote.ip()),
+      payload,
+    );
+    let mut segment = header.serialize();
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    segment.extend_from_slice(payload);
+
+    let ip = self
+      .control
+      .ip_header(*self.local.ip(), *self.remote.ip(), segment.len());
+    let mut packet = ip.serialize();
+    packet.extend_from_slice(&segment);
+
+    self.socket.send_to(&packet, *self.remote.ip())?;
+    stats::count(&mut self.control.stats.segments_sent, 1);
+    stats::count(&mut self.control.stats.bytes_sent, payload.len() as u64);
+    self.control.on_transmit(payload.len());
+    Ok(segment)
+  }
+}
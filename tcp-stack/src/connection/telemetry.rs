@@ -0,0 +1,123 @@
+//! Per-interval throughput sampling
+//!
+//! `ThroughputSampler` turns a connection's cumulative counters into rates
+//! over fixed intervals, so dashboards can plot live per-flow goodput,
+//! retransmission ratio and RTT without diffing counters themselves.
+//! `TcpConnection` posts each sample as a `ConnectionEvent` to the sender
+//! set with `set_event_sender`, along with other events worth monitoring.
+
+use super::{CloseReason, ConnectionStats};
+use crate::utils::Instant;
+use core::time::Duration;
+
+/// Rates over one sampling interval
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ThroughputSample {
+  /// Start of the interval
+  pub start_micros: u64,
+  /// Length of the interval; longer than configured if the connection was
+  /// not polled when it ended
+  pub duration: Duration,
+  /// Bytes newly acknowledged by the peer
+  pub bytes_acked: u64,
+  /// Acknowledged bytes per second
+  pub goodput: f64,
+  pub segments_sent: u64,
+  pub retransmissions: u64,
+  /// Share of the segments sent that were retransmissions
+  pub retransmit_ratio: f64,
+  /// Mean of the RTT samples taken, if any
+  pub avg_rtt: Option<Duration>,
+}
+
+/// Something that happened on a connection, for monitoring
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum ConnectionEvent {
+  /// A sampling interval ended on the connection with `trace_id`
+  Throughput {
+    trace_id: u32,
+    sample: ThroughputSample,
+  },
+  /// The peer answered none of `probes` keep-alive probes and has been
+  /// silent for `silent_for`; the connection is closed
+  PeerUnreachable {
+    trace_id: u32,
+    probes: u32,
+    silent_for: Duration,
+  },
+  /// The connection with `trace_id` closed
+  Closed { trace_id: u32, reason: CloseReason },
+}
+
+/// Accumulates one interval at a time
+#[derive(Debug, Clone)]
+pub struct ThroughputSampler {
+  interval: Duration,
+  start: Instant,
+  /// Counters at the start of the interval
+  baseline: ConnectionStats,
+  rtt_sum: Duration,
+  rtt_count: u32,
+}
+
+impl ThroughputSampler {
+  /// Start sampling every `interval` from `now`, against `stats` as they
+  /// stand
+  pub fn new(interval: Duration, now: Instant, stats: &ConnectionStats) -> Self {
+    Self {
+      interval,
+      start: now,
+      baseline: stats.clone(),
+      rtt_sum: Duration::ZERO,
+      rtt_count: 0,
+    }
+  }
+
+  pub fn interval(&self) -> Duration {
+    self.interval
+  }
+
+  /// Count an RTT sample towards the current interval
+  pub fn on_rtt(&mut self, rtt: Duration) {
+    self.rtt_sum += rtt;
+    self.rtt_count += 1;
+  }
+
+  /// The sample for the interval just ended, if one has by `now`, starting
+  /// the next
+  pub fn poll(
+    &mut self,
+    now: Instant,
+    stats: &ConnectionStats,
+  ) -> Option<ThroughputSample> {
+    let duration = now.saturating_duration_since(self.start);
+    if duration < self.interval || duration.is_zero() {
+      return None;
+    }
+    let delta = stats.since(&self.baseline);
+    let (bytes_acked, segments_sent, retransmissions) = (
+      delta.bytes_acked,
+      delta.segments_sent,
+      delta.retransmissions,
+    );
+    let sample = ThroughputSample {
+      start_micros: self.start.total_micros(),
+      duration,
+      bytes_acked,
+      goodput: bytes_acked as f64 / duration.as_secs_f64(),
+      segments_sent,
+      retransmissions,
+      retransmit_ratio: if segments_sent == 0 {
+        0.0
+      } else {
+        retransmissions as f64 / segments_sent as f64
+      },
+      avg_rtt: (self.rtt_count > 0).then(|| self.rtt_sum / self.rtt_count),
+    };
+    *self = Self::new(self.interval, now, stats);
+    Some(sample)
+  }
+}
@@ -1,6 +1,7 @@
 //! TCP timers
 
-use std::time::{Duration, Instant};
+use crate::utils::Instant;
+use core::time::Duration;
 
 /// TCP Timer
 pub struct Timer {
@@ -16,31 +17,32 @@ impl Timer {
     }
   }
 
-  pub fn start(&mut self, duration: Duration) {
+  pub fn start(&mut self, now: Instant, duration: Duration) {
     self.duration = duration;
-    self.deadline = Some(Instant::now() + duration);
+    self.deadline = Some(now + duration);
   }
 
   pub fn cancel(&mut self) {
     self.deadline = None;
   }
 
-  pub fn is_expired(&self) -> bool {
-    self.deadline.is_some_and(|dl| Instant::now() >= dl)
+  pub fn is_expired(&self, now: Instant) -> bool {
+    self.deadline.is_some_and(|dl| now >= dl)
   }
 
-  pub fn time_until_expiry(&self) -> Option<Duration> {
-    self.deadline.map(|dl| {
-      let now = Instant::now();
-      if dl > now { dl - now } else { Duration::ZERO }
-    })
+  pub fn time_until_expiry(&self, now: Instant) -> Option<Duration> {
+    self.deadline.map(|dl| dl.saturating_duration_since(now))
   }
 
-  pub fn reset(&mut self) {
+  pub fn reset(&mut self, now: Instant) {
     if self.deadline.is_some() {
-      self.deadline = Some(Instant::now() + self.duration);
+      self.deadline = Some(now + self.duration);
     }
   }
+
+  pub fn deadline(&self) -> Option<Instant> {
+    self.deadline
+  }
 }
 
 impl Default for Timer {
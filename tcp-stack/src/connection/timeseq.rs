@@ -0,0 +1,156 @@
+//! Time-sequence diagnostics
+//!
+//! `TimeSequence` records what a connection sent and had acknowledged over
+//! time, with cwnd and the peer's window alongside, in a bounded ring. The
+//! CSV and JSON exports carry one row per event, ready to plot as the
+//! classic tcptrace/xplot time-sequence graph.
+
+use crate::utils::{Instant, SeqNumber};
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use core::fmt::Write;
+
+/// Events kept by default
+pub const DEFAULT_TIMESEQ_CAPACITY: usize = 4096;
+
+/// What a time-sequence sample records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TimeSeqKind {
+  /// A new segment covering `seq..end`
+  Sent,
+  /// A segment covering `seq..end` sent again
+  Retransmit,
+  /// Cumulative acknowledgment up to `seq` (`end` equals `seq`)
+  Ack,
+}
+
+impl TimeSeqKind {
+  fn as_str(&self) -> &'static str {
+    match self {
+      TimeSeqKind::Sent => "sent",
+      TimeSeqKind::Retransmit => "retransmit",
+      TimeSeqKind::Ack => "ack",
+    }
+  }
+}
+
+/// One time-sequence event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TimeSeqSample {
+  pub time_micros: u64,
+  pub kind: TimeSeqKind,
+  pub seq: u32,
+  pub end: u32,
+  pub cwnd: u32,
+  /// Peer's window (SND.WND) at the time
+  pub window: u32,
+}
+
+/// Bounded ring of time-sequence samples; the oldest are dropped first
+pub struct TimeSequence {
+  samples: VecDeque<TimeSeqSample>,
+  capacity: usize,
+}
+
+impl TimeSequence {
+  pub fn new() -> Self {
+    Self::with_capacity(DEFAULT_TIMESEQ_CAPACITY)
+  }
+
+  pub fn with_capacity(capacity: usize) -> Self {
+    Self {
+      samples: VecDeque::with_capacity(capacity.min(DEFAULT_TIMESEQ_CAPACITY)),
+      capacity,
+    }
+  }
+
+  /// Record an event covering `seq..seq + len`
+  pub fn record(
+    &mut self,
+    now: Instant,
+    kind: TimeSeqKind,
+    seq: SeqNumber,
+    len: u32,
+    cwnd: u32,
+    window: u32,
+  ) {
+    if self.capacity == 0 {
+      return;
+    }
+    if self.samples.len() == self.capacity {
+      self.samples.pop_front();
+    }
+    self.samples.push_back(TimeSeqSample {
+      time_micros: now.total_micros(),
+      kind,
+      seq: seq.0,
+      end: (seq + len).0,
+      cwnd,
+      window,
+    });
+  }
+
+  pub fn samples(&self) -> impl Iterator<Item = &TimeSeqSample> {
+    self.samples.iter()
+  }
+
+  pub fn len(&self) -> usize {
+    self.samples.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.samples.is_empty()
+  }
+
+  pub fn clear(&mut self) {
+    self.samples.clear();
+  }
+
+  /// Samples as CSV with a header row
+  pub fn to_csv(&self) -> String {
+    let mut out = String::from("time_us,kind,seq,end,cwnd,window\n");
+    for s in &self.samples {
+      let _ = writeln!(
+        out,
+        "{},{},{},{},{},{}",
+        s.time_micros,
+        s.kind.as_str(),
+        s.seq,
+        s.end,
+        s.cwnd,
+        s.window
+      );
+    }
+    out
+  }
+
+  /// Samples as a JSON array of objects with the CSV's columns
+  pub fn to_json(&self) -> String {
+    let mut out = String::from("[");
+    for (i, s) in self.samples.iter().enumerate() {
+      if i > 0 {
+        out.push(',');
+      }
+      let _ = write!(
+        out,
+        "{{\"time_us\":{},\"kind\":\"{}\",\"seq\":{},\"end\":{},\"cwnd\":{},\"window\":{}}}",
+        s.time_micros,
+        s.kind.as_str(),
+        s.seq,
+        s.end,
+        s.cwnd,
+        s.window
+      );
+    }
+    out.push(']');
+    out
+  }
+}
+
+impl Default for TimeSequence {
+  fn default() -> Self {
+    Self::new()
+  }
+}
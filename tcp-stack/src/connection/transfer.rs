@@ -0,0 +1,126 @@
+//! Blocking send and receive
+//!
+//! `send` and `recv` drive the connection while they wait: each pass sends
+//! what the windows allow, retransmits what is due, and takes one segment
+//! from the peer.
+
+use super::connect::POLL_INTERVAL;
+use super::{CloseReason, ControlBlock, TcpConnection, TcpState};
+use crate::error::{Result, TcpError};
+use crate::utils::Instant;
+use bytes::Bytes;
+
+impl TcpConnection {
+  /// Queue all of `data`, blocking while the send buffer is full. Returns
+  /// once the last byte is queued, not once it is acknowledged
+  pub fn send(&mut self, data: &[u8]) -> Result<usize> {
+    if !matches!(self.state(), TcpState::Established | TcpState::CloseWait) {
+      return Err(self.closed_error());
+    }
+    let mut buf = vec![0u8; 65535];
+    self.subflow.socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    let mut written = 0;
+    loop {
+      written += self.subflow.control.write(&data[written..]);
+      self.transmit(Instant::now())?;
+      if written == data.len() {
+        return Ok(written);
+      }
+      self.poll_peer(&mut buf)?;
+    }
+  }
+
+  /// `send` for data already in a `Bytes`, which is segmented and held for
+  /// retransmission without being copied
+  pub fn send_bytes(&mut self, data: Bytes) -> Result<usize> {
+    if !matches!(self.state(), TcpState::Established | TcpState::CloseWait) {
+      return Err(self.closed_error());
+    }
+    let mut buf = vec![0u8; 65535];
+    self.subflow.socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    let mut written = 0;
+    loop {
+      written += self.subflow.control.write_bytes(data.slice(written..));
+      self.transmit(Instant::now())?;
+      if written == data.len() {
+        return Ok(written);
+      }
+      self.poll_peer(&mut buf)?;
+    }
+  }
+
+  /// Block until data is readable and copy it into `buf`. Returns 0 once
+  /// the peer has closed and everything it sent has been read, and fails
+  /// with the error of its `CloseReason` after an abnormal close
+  pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    let len = self.recv_with(|control| {
+      let len = control.read(buf);
+      (len > 0).then_some(len)
+    })?;
+    Ok(len.unwrap_or(0))
+  }
+
+  /// Block until data is readable and take all of it as `Bytes` chunks,
+  /// the buffers segments arrived in, without copying. Returns no chunks
+  /// once the peer has closed and everything it sent has been read, and
+  /// fails as `recv` does
+  pub fn recv_bytes(&mut self) -> Result<Vec<Bytes>> {
+    let chunks = self.recv_with(|control| {
+      let chunks = control.read_bytes();
+      (!chunks.is_empty()).then_some(chunks)
+    })?;
+    Ok(chunks.unwrap_or_default())
+  }
+
+  /// Drive the connection until `take` gets something out of the control
+  /// block, or `None` once the peer has closed and nothing more will come
+  fn recv_with<T>(
+    &mut self,
+    mut take: impl FnMut(&mut ControlBlock) -> Option<T>,
+  ) -> Result<Option<T>> {
+    let mut segment = vec![0u8; 65535];
+    self.subflow.socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    loop {
+      if let Some(taken) = take(&mut self.subflow.control) {
+        self.update_window(Instant::now())?;
+        return Ok(Some(taken));
+      }
+      match self.state() {
+        TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 => {}
+        TcpState::CloseWait
+        | TcpState::Closing
+        | TcpState::LastAck
+        | TcpState::TimeWait => return Ok(None),
+        TcpState::Closed if self.close_reason().is_some_and(CloseReason::is_graceful) => {
+          return Ok(None)
+        }
+        _ => return Err(self.closed_error()),
+      }
+      self.transmit(Instant::now())?;
+      self.poll_peer(&mut segment)?;
+    }
+  }
+
+  /// Wait up to the socket's read timeout for a segment and take its ACK.
+  /// A reset closes the connection and fails with `ConnectionReset`
+  pub(super) fn poll_peer(&mut self, buf: &mut [u8]) -> Result<()> {
+    let Some(tcp) = self.recv_segment(buf)? else {
+      return Ok(());
+    };
+    if tcp.flags.is_rst() {
+      debug!("[conn {}] Reset by {}", self.trace_id(), self.subflow.remote);
+      self.close_with(CloseReason::PeerRst);
+      return Err(TcpError::ConnectionReset);
+    }
+    if tcp.flags.is_ack() {
+      self.on_ack_segment(&tcp)?;
+    }
+    Ok(())
+  }
+}
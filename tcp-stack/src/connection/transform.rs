@@ -0,0 +1,102 @@
+//! Payload transforms (experimental)
+//!
+//! A `StreamTransform` rewrites the byte stream between the application and
+//! the connection: on the way into the send queue, before segmentation, and
+//! on the way out of the receive stream. It is meant for trying out
+//! transport-level compression with zstd, lz4 or the like.
+//!
+//! Nothing on the wire says a transform is in use, so the applications at
+//! both ends agree on it out of band and set matching transforms before any
+//! data flows. Sequence numbers, windows and buffer limits all count
+//! transformed bytes.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// One direction of a stream transform
+pub trait StreamTransform: Send {
+  /// Transform `input`, appending to `output` all the peer needs to undo
+  /// it so far: a compressor flushes at the end of each call. A decoder
+  /// may hold back input that ends mid-frame until more arrives
+  fn process(&mut self, input: &[u8], output: &mut Vec<u8>);
+}
+
+/// Transforms of a connection, one per direction
+#[derive(Default)]
+pub struct Transforms {
+  send: Option<Box<dyn StreamTransform>>,
+  recv: Option<Box<dyn StreamTransform>>,
+  /// Output of the receive transform not yet read
+  decoded: VecDeque<u8>,
+}
+
+impl Transforms {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Transform data written from now on with `transform`, or stop with
+  /// `None`
+  pub fn set_send(&mut self, transform: Option<Box<dyn StreamTransform>>) {
+    self.send = transform;
+  }
+
+  /// Undo the peer's transform on data read from now on, or stop with
+  /// `None`
+  pub fn set_recv(&mut self, transform: Option<Box<dyn StreamTransform>>) {
+    self.recv = transform;
+  }
+
+  pub fn has_send(&self) -> bool {
+    self.send.is_some()
+  }
+
+  pub fn has_recv(&self) -> bool {
+    self.recv.is_some()
+  }
+
+  /// `data` as it goes into the send queue
+  pub fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    match &mut self.send {
+      Some(transform) => transform.process(data, &mut output),
+      None => output.extend_from_slice(data),
+    }
+    output
+  }
+
+  /// Take `received` from the receive stream and hand back up to `buf`'s
+  /// length of its decoded form
+  pub fn decode(&mut self, received: &[u8], buf: &mut [u8]) -> usize {
+    if !received.is_empty() {
+      let mut output = Vec::new();
+      match &mut self.recv {
+        Some(transform) => transform.process(received, &mut output),
+        None => output.extend_from_slice(received),
+      }
+      self.decoded.extend(output);
+    }
+    let len = buf.len().min(self.decoded.len());
+    for (dst, src) in buf.iter_mut().zip(self.decoded.drain(..len)) {
+      *dst = src;
+    }
+    len
+  }
+
+  /// Decoded bytes waiting to be read
+  pub fn decoded(&self) -> usize {
+    self.decoded.len()
+  }
+}
+
+impl fmt::Debug for Transforms {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Transforms")
+      .field("send", &self.send.is_some())
+      .field("recv", &self.recv.is_some())
+      .field("decoded", &self.decoded.len())
+      .finish()
+  }
+}
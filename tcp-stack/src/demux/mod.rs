@@ -1,12 +1,26 @@
 //! Packet demultiplexing
+//!
+//! Besides routing, the demultiplexer owns the 4-tuples and listening ports
+//! in use, so a second connection or listener on the same endpoint fails
+//! with `AddrInUse` rather than stealing the first one's packets.
 
+use crate::error::{Result, TcpError};
 use crate::packet::{Ipv4Header, TcpHeader};
+use crate::utils::{Slab, SlabKey};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::net::SocketAddrV4;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+/// Generation-checked id of a registered connection; ids of removed
+/// connections never match a later one
+pub type ConnectionId = SlabKey;
 
 /// Demultiplexer for routing packets to connections
 pub struct Demultiplexer {
-  connections: HashMap<ConnectionKey, u64>,
+  connections: HashMap<ConnectionKey, ConnectionId>,
+  ids: Slab<ConnectionKey>,
+  listeners: Vec<(SocketAddrV4, Shard)>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -20,6 +34,38 @@ impl ConnectionKey {
     Self { local, remote }
   }
 
+  /// Stable hash of the 4-tuple, identical across threads and
+  /// processes so every shard agrees on which one owns a flow
+  pub fn flow_hash(&self) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    let fields = [
+      self.local.ip().octets(),
+      self.remote.ip().octets(),
+      [
+        (self.local.port() >> 8) as u8,
+        self.local.port() as u8,
+        (self.remote.port() >> 8) as u8,
+        self.remote.port() as u8,
+      ],
+    ];
+    for byte in fields.iter().flatten() {
+      hash ^= *byte as u32;
+      hash = hash.wrapping_mul(0x0100_0193);
+    }
+    // murmur3 finalizer: FNV alone leaves the high bits, which `shard`
+    // uses, nearly unchanged across neighbouring ports
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^ (hash >> 16)
+  }
+
+  /// Index of the shard, out of `count`, that owns this flow
+  pub fn shard(&self, count: usize) -> usize {
+    ((self.flow_hash() as u64 * count as u64) >> 32) as usize
+  }
+
   pub fn from_headers(ip: &Ipv4Header, tcp: &TcpHeader) -> Option<Self> {
     Some(Self {
       local: SocketAddrV4::new(ip.dst_addr, tcp.dst_port),
@@ -28,23 +74,113 @@ impl ConnectionKey {
   }
 }
 
+/// One of `count` shards that together partition the 4-tuple space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+  pub index: usize,
+  pub count: usize,
+}
+
+impl Shard {
+  pub fn new(index: usize, count: usize) -> Self {
+    assert!(
+      index < count,
+      "shard index {index} out of range for {count} shards"
+    );
+    Self { index, count }
+  }
+
+  /// The only shard of an unsharded listener
+  pub fn single() -> Self {
+    Self::new(0, 1)
+  }
+
+  pub fn owns(&self, key: &ConnectionKey) -> bool {
+    key.shard(self.count) == self.index
+  }
+}
+
+impl Default for Shard {
+  fn default() -> Self {
+    Self::single()
+  }
+}
+
 impl Demultiplexer {
   pub fn new() -> Self {
     Self {
       connections: HashMap::new(),
+      ids: Slab::new(),
+      listeners: Vec::new(),
+    }
+  }
+
+  /// Table shared by every connection and listener in the process
+  pub fn global() -> MutexGuard<'static, Demultiplexer> {
+    static GLOBAL: OnceLock<Mutex<Demultiplexer>> = OnceLock::new();
+    GLOBAL
+      .get_or_init(|| Mutex::new(Demultiplexer::new()))
+      .lock()
+      .unwrap_or_else(|e| e.into_inner())
+  }
+
+  /// Allocate an id routing `key` to a new connection, failing if another
+  /// connection owns the 4-tuple
+  pub fn register(&mut self, key: ConnectionKey) -> Result<ConnectionId> {
+    match self.connections.entry(key) {
+      Entry::Occupied(entry) => Err(TcpError::AddrInUse(entry.key().local)),
+      Entry::Vacant(entry) => {
+        let id = self.ids.insert(entry.key().clone());
+        entry.insert(id);
+        Ok(id)
+      }
     }
   }
 
-  pub fn register(&mut self, key: ConnectionKey, id: u64) {
-    self.connections.insert(key, id);
+  /// Release `id` and its 4-tuple; a stale id is ignored
+  pub fn unregister(&mut self, id: ConnectionId) -> Option<ConnectionKey> {
+    let key = self.ids.remove(id)?;
+    self.connections.remove(&key);
+    Some(key)
+  }
+
+  pub fn find(&self, key: &ConnectionKey) -> Option<ConnectionId> {
+    self.connections.get(key).copied()
+  }
+
+  /// 4-tuple of connection `id`, if the id is still current
+  pub fn key(&self, id: ConnectionId) -> Option<&ConnectionKey> {
+    self.ids.get(id)
+  }
+
+  pub fn len(&self) -> usize {
+    self.ids.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.ids.is_empty()
   }
 
-  pub fn unregister(&mut self, key: &ConnectionKey) {
-    self.connections.remove(key);
+  /// Claim `shard` of `local` for a listener. Listeners on the same port
+  /// conflict when their addresses overlap (either is unspecified or both
+  /// are equal) unless they are distinct shards of one sharded group
+  pub fn listen(&mut self, local: SocketAddrV4, shard: Shard) -> Result<()> {
+    let conflict = self.listeners.iter().any(|(addr, other)| {
+      let overlaps = addr.port() == local.port()
+        && (addr.ip() == local.ip()
+          || addr.ip().is_unspecified()
+          || local.ip().is_unspecified());
+      overlaps && (other.count != shard.count || other.index == shard.index)
+    });
+    if conflict {
+      return Err(TcpError::AddrInUse(local));
+    }
+    self.listeners.push((local, shard));
+    Ok(())
   }
 
-  pub fn find(&self, key: &ConnectionKey) -> Option<&u64> {
-    self.connections.get(key)
+  pub fn unlisten(&mut self, local: SocketAddrV4, shard: Shard) {
+    self.listeners.retain(|entry| *entry != (local, shard));
   }
 }
 
@@ -0,0 +1,47 @@
+//! In-memory device for tests and fuzzing
+//!
+//! Packets the stack sends queue up for the caller to take, and packets the
+//! caller injects queue up for the stack to receive. Nothing is delivered
+//! anywhere else.
+
+use super::NetworkDevice;
+use std::collections::VecDeque;
+use std::io;
+
+/// Device backed by two packet queues
+#[derive(Debug, Default)]
+pub struct Loopback {
+  inbound: VecDeque<Vec<u8>>,
+  outbound: VecDeque<Vec<u8>>,
+}
+
+impl Loopback {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Queue a packet for the stack to receive
+  pub fn inject(&mut self, packet: Vec<u8>) {
+    self.inbound.push_back(packet);
+  }
+
+  /// Take the oldest packet the stack sent
+  pub fn take_sent(&mut self) -> Option<Vec<u8>> {
+    self.outbound.pop_front()
+  }
+}
+
+impl NetworkDevice for Loopback {
+  fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+    self.outbound.push_back(packet.to_vec());
+    Ok(())
+  }
+
+  /// Like a datagram socket, a packet longer than `buf` is truncated
+  fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let packet = self.inbound.pop_front().ok_or(io::ErrorKind::WouldBlock)?;
+    let len = packet.len().min(buf.len());
+    buf[..len].copy_from_slice(&packet[..len]);
+    Ok(len)
+  }
+}
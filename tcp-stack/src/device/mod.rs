@@ -0,0 +1,54 @@
+//! Network device abstraction
+//!
+//! A `NetworkDevice` moves whole IPv4 packets in and out of the stack. The
+//! raw socket (`raw-socket` feature) is one implementation; the optional `smoltcp` adapters let
+//! smoltcp's `phy` drivers be used in its place and vice versa.
+
+pub mod loopback;
+pub mod peer;
+#[cfg(feature = "smoltcp")]
+pub mod smoltcp;
+
+pub use loopback::Loopback;
+pub use peer::{Behavior, ScriptedPeer};
+
+#[cfg(feature = "raw-socket")]
+use crate::socket::RawSocket;
+use std::io;
+#[cfg(feature = "raw-socket")]
+use std::net::Ipv4Addr;
+
+/// Device carrying raw IPv4 packets (including the IP header)
+pub trait NetworkDevice {
+  /// Transmit a single IPv4 packet
+  fn send(&mut self, packet: &[u8]) -> io::Result<()>;
+
+  /// Receive a single IPv4 packet into `buf`, returning its length
+  ///
+  /// Returns `ErrorKind::WouldBlock` when no packet is available on a
+  /// non-blocking device.
+  fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+  /// Largest IP packet the device can carry
+  fn mtu(&self) -> usize {
+    1500
+  }
+}
+
+#[cfg(feature = "raw-socket")]
+impl NetworkDevice for RawSocket {
+  fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+    if packet.len() < 20 {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "packet shorter than an IPv4 header",
+      ));
+    }
+    let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+    self.send_to(packet, dst).map(|_| ())
+  }
+
+  fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.recv_from(buf).map(|(len, _)| len)
+  }
+}
@@ -0,0 +1,343 @@
+//! Scripted remote end for scenario tests
+//!
+//! `ScriptedPeer` plays the other side of a connection over a `Loopback`:
+//! each `poll` it takes the packets the stack sent, answers them as a
+//! simple TCP would and injects the answers. `Behavior`s bend it into the
+//! peer a scenario needs, such as one that acknowledges every other
+//! segment or resets the connection at a given time. It never retransmits,
+//! so the link between the two is lossless unless the test drops packets
+//! itself.
+
+use super::Loopback;
+use crate::packet::{Ipv4Header, TcpFlags, TcpHeader};
+use crate::utils::{Instant, SeqNumber};
+use std::collections::BTreeMap;
+use std::net::SocketAddrV4;
+
+/// Largest segment the peer sends
+const PEER_MSS: usize = 1460;
+
+/// Something the peer does differently from a well-behaved TCP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Behavior {
+  /// Acknowledge only every `n`th data segment. Out-of-order segments and
+  /// FINs are still acknowledged at once
+  AckEvery(usize),
+  /// Advertise `window` once `at` bytes have arrived
+  ShrinkWindow { at: usize, window: u16 },
+  /// Reset the connection at the first `poll` at or after this time
+  ResetAt(Instant),
+  /// Hold back every `n`th data segment and take it after the next one
+  Reorder(usize),
+}
+
+/// Remote end of one connection, driven through a `Loopback`
+#[derive(Debug)]
+pub struct ScriptedPeer {
+  addr: SocketAddrV4,
+  /// The stack's end, once known
+  remote: Option<SocketAddrV4>,
+  behaviors: Vec<Behavior>,
+  iss: SeqNumber,
+  snd_nxt: SeqNumber,
+  snd_una: SeqNumber,
+  rcv_nxt: Option<SeqNumber>,
+  window: u16,
+  /// Bytes queued by `send` and not yet sent
+  unsent: Vec<u8>,
+  /// Our FIN goes out once `unsent` drains
+  closing: bool,
+  fin_sent: bool,
+  received: Vec<u8>,
+  /// Segments that arrived ahead of `rcv_nxt`, by sequence number
+  out_of_order: BTreeMap<u32, Vec<u8>>,
+  /// Data segments taken in, for `AckEvery` and `Reorder`
+  data_segments: usize,
+  held: Option<(TcpHeader, Vec<u8>)>,
+  fin_received: bool,
+  reset_sent: bool,
+  reset_received: bool,
+}
+
+impl ScriptedPeer {
+  /// A peer at `addr` starting its sequence space at `iss`
+  pub fn new(addr: SocketAddrV4, iss: u32) -> Self {
+    Self {
+      addr,
+      remote: None,
+      behaviors: Vec::new(),
+      iss: SeqNumber(iss),
+      snd_nxt: SeqNumber(iss),
+      snd_una: SeqNumber(iss),
+      rcv_nxt: None,
+      window: u16::MAX,
+      unsent: Vec::new(),
+      closing: false,
+      fin_sent: false,
+      received: Vec::new(),
+      out_of_order: BTreeMap::new(),
+      data_segments: 0,
+      held: None,
+      fin_received: false,
+      reset_sent: false,
+      reset_received: false,
+    }
+  }
+
+  /// Add `behavior` to the script
+  pub fn with(mut self, behavior: Behavior) -> Self {
+    self.behaviors.push(behavior);
+    self
+  }
+
+  pub fn addr(&self) -> SocketAddrV4 {
+    self.addr
+  }
+
+  /// Open a connection to `remote`, which must be listening
+  pub fn connect(&mut self, remote: SocketAddrV4, device: &mut Loopback) {
+    self.remote = Some(remote);
+    let header = self.header(TcpFlags::new().with_syn(), self.iss);
+    self.snd_nxt = self.iss + 1;
+    self.emit(device, &header, &[]);
+  }
+
+  /// Queue `data` to send to the stack once the connection is open
+  pub fn send(&mut self, data: &[u8]) {
+    self.unsent.extend_from_slice(data);
+  }
+
+  /// Send our FIN once everything queued has gone
+  pub fn close(&mut self) {
+    self.closing = true;
+  }
+
+  /// Everything received in order from the stack
+  pub fn received(&self) -> &[u8] {
+    &self.received
+  }
+
+  pub fn fin_received(&self) -> bool {
+    self.fin_received
+  }
+
+  pub fn reset_received(&self) -> bool {
+    self.reset_received
+  }
+
+  /// Whether the stack has acknowledged everything we sent
+  pub fn all_acked(&self) -> bool {
+    self.snd_una == self.snd_nxt
+  }
+
+  /// Take in every packet the stack sent to us and answer it, then send
+  /// what is queued or due at `now`. Packets to other addresses are dropped,
+  /// as on a wire. Returns the number of packets taken
+  pub fn poll(&mut self, device: &mut Loopback, now: Instant) -> usize {
+    let mut taken = 0;
+    while let Some(packet) = device.take_sent() {
+      if self.accept(device, &packet) {
+        taken += 1;
+      }
+    }
+    // Nothing came after a held segment this time
+    if let Some((tcp, payload)) = self.held.take() {
+      self.segment(device, &tcp, &payload);
+    }
+    if self.reset_sent || self.reset_received {
+      return taken;
+    }
+
+    let due = self.behaviors.iter().any(|behavior| {
+      matches!(behavior, Behavior::ResetAt(at) if *at <= now)
+    });
+    if due && self.rcv_nxt.is_some() {
+      let header = self.header(TcpFlags::new().with_rst().with_ack(), self.snd_nxt);
+      self.emit(device, &header, &[]);
+      self.reset_sent = true;
+      return taken;
+    }
+    self.transmit(device);
+    taken
+  }
+
+  /// Handle one packet the stack sent, returning false if it is not ours
+  fn accept(&mut self, device: &mut Loopback, packet: &[u8]) -> bool {
+    let Some((ip, segment)) = Ipv4Header::parse(packet) else {
+      return false;
+    };
+    let Some((tcp, payload)) = TcpHeader::parse(segment) else {
+      return false;
+    };
+    if ip.dst_addr != *self.addr.ip() || tcp.dst_port != self.addr.port() {
+      return false;
+    }
+    let remote = SocketAddrV4::new(ip.src_addr, tcp.src_port);
+    if self.remote.is_some_and(|known| known != remote) {
+      return false;
+    }
+    self.remote = Some(remote);
+    if self.reset_sent || self.reset_received {
+      return true;
+    }
+
+    if !payload.is_empty() {
+      self.data_segments += 1;
+      if self.hold_back() && self.held.is_none() {
+        self.held = Some((tcp, payload.to_vec()));
+        return true;
+      }
+    }
+    self.segment(device, &tcp, payload);
+    if let Some((held, payload)) = self.held.take() {
+      self.segment(device, &held, &payload);
+    }
+    true
+  }
+
+  /// Process a segment from the stack
+  fn segment(&mut self, device: &mut Loopback, tcp: &TcpHeader, payload: &[u8]) {
+    let flags = tcp.flags;
+    if flags.is_rst() {
+      self.reset_received = true;
+      return;
+    }
+    if flags.is_ack() {
+      let ack = SeqNumber(tcp.ack_num);
+      if ack.after(self.snd_una) && !ack.after(self.snd_nxt) {
+        self.snd_una = ack;
+      }
+    }
+    if flags.is_syn() {
+      // Our SYN-ACK may have been lost, so answer a repeated SYN alike
+      let rcv_nxt = SeqNumber(tcp.seq_num) + 1;
+      self.rcv_nxt = Some(rcv_nxt);
+      if flags.is_ack() {
+        self.ack(device);
+      } else {
+        let syn_ack = self.header(TcpFlags::new().with_syn().with_ack(), self.iss);
+        self.snd_nxt = self.iss + 1;
+        self.emit(device, &syn_ack, &[]);
+      }
+      return;
+    }
+    let Some(rcv_nxt) = self.rcv_nxt else {
+      return;
+    };
+
+    let seq = SeqNumber(tcp.seq_num);
+    let in_order = seq == rcv_nxt;
+    if !payload.is_empty() {
+      if in_order {
+        self.deliver(payload);
+      } else if seq.after(rcv_nxt) {
+        self.out_of_order.insert(seq.0, payload.to_vec());
+      }
+    }
+    let end = seq + payload.len() as u32;
+    if flags.is_fin() && self.rcv_nxt == Some(end) {
+      self.rcv_nxt = Some(end + 1);
+      self.fin_received = true;
+    }
+
+    let ack_now = !in_order || flags.is_fin() || self.acks_segment();
+    if (!payload.is_empty() || flags.is_fin()) && ack_now {
+      self.ack(device);
+    }
+  }
+
+  /// Take in-order `payload`, then whatever it made contiguous
+  fn deliver(&mut self, payload: &[u8]) {
+    let mut rcv_nxt = self.rcv_nxt.expect("synchronized") + payload.len() as u32;
+    self.received.extend_from_slice(payload);
+    while let Some(data) = self.out_of_order.remove(&rcv_nxt.0) {
+      rcv_nxt = rcv_nxt + data.len() as u32;
+      self.received.extend_from_slice(&data);
+    }
+    // Anything now wholly behind RCV.NXT is a duplicate
+    self
+      .out_of_order
+      .retain(|&seq, data| (SeqNumber(seq) + data.len() as u32).after(rcv_nxt));
+    self.rcv_nxt = Some(rcv_nxt);
+  }
+
+  /// Whether the data segment just taken in is acknowledged under
+  /// `AckEvery`
+  fn acks_segment(&self) -> bool {
+    self.behaviors.iter().all(|behavior| match behavior {
+      Behavior::AckEvery(n) => self.data_segments % (*n).max(1) == 0,
+      _ => true,
+    })
+  }
+
+  /// Whether the data segment just taken in is held back under `Reorder`
+  fn hold_back(&self) -> bool {
+    self.behaviors.iter().any(|behavior| match behavior {
+      Behavior::Reorder(n) => self.data_segments % (*n).max(1) == 0,
+      _ => false,
+    })
+  }
+
+  /// The window to advertise, after any `ShrinkWindow` that applies
+  fn advertised_window(&self) -> u16 {
+    self
+      .behaviors
+      .iter()
+      .filter_map(|behavior| match behavior {
+        Behavior::ShrinkWindow { at, window } if self.received.len() >= *at => {
+          Some(*window)
+        }
+        _ => None,
+      })
+      .min()
+      .unwrap_or(self.window)
+  }
+
+  /// Send queued data and, once it is gone, our FIN
+  fn transmit(&mut self, device: &mut Loopback) {
+    if self.rcv_nxt.is_none() || self.fin_sent {
+      return;
+    }
+    let flags = TcpFlags::new().with_ack().with_psh();
+    for chunk in std::mem::take(&mut self.unsent).chunks(PEER_MSS) {
+      let header = self.header(flags, self.snd_nxt);
+      self.snd_nxt = self.snd_nxt + chunk.len() as u32;
+      self.emit(device, &header, chunk);
+    }
+    if self.closing {
+      let header = self.header(TcpFlags::new().with_fin().with_ack(), self.snd_nxt);
+      self.snd_nxt = self.snd_nxt + 1;
+      self.fin_sent = true;
+      self.emit(device, &header, &[]);
+    }
+  }
+
+  fn ack(&mut self, device: &mut Loopback) {
+    let header = self.header(TcpFlags::new().with_ack(), self.snd_nxt);
+    self.emit(device, &header, &[]);
+  }
+
+  /// Our header for a segment at `seq`, acknowledging RCV.NXT if known
+  fn header(&self, flags: TcpFlags, seq: SeqNumber) -> TcpHeader {
+    let remote = self.remote.expect("peer address known");
+    let mut header = TcpHeader::new(self.addr.port(), remote.port());
+    header.flags = flags;
+    header.seq_num = seq.0;
+    header.ack_num = self.rcv_nxt.map_or(0, |rcv_nxt| rcv_nxt.0);
+    header.window_size = self.advertised_window();
+    header
+  }
+
+  /// Checksum a segment and inject it as an IPv4 packet
+  fn emit(&self, device: &mut Loopback, header: &TcpHeader, payload: &[u8]) {
+    let src = *self.addr.ip();
+    let dst = *self.remote.expect("peer address known").ip();
+    let checksum = header.calculate_checksum(src.into(), dst.into(), payload);
+    let mut segment = header.serialize();
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    segment.extend_from_slice(payload);
+    let mut packet = Ipv4Header::new(src, dst, segment.len()).serialize();
+    packet.extend_from_slice(&segment);
+    device.inject(packet);
+  }
+}
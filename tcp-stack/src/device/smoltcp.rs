@@ -0,0 +1,173 @@
+//! Adapters between `NetworkDevice` and smoltcp's `phy::Device`
+//!
+//! Only IP-medium devices are supported: the stack exchanges bare IPv4
+//! packets, so Ethernet framing is left to the smoltcp device.
+
+use super::NetworkDevice;
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use std::io;
+
+/// Exposes a `NetworkDevice` as a smoltcp `phy::Device`
+pub struct SmoltcpDevice<D: NetworkDevice> {
+  inner: D,
+  rx_buffer: Vec<u8>,
+}
+
+impl<D: NetworkDevice> SmoltcpDevice<D> {
+  pub fn new(inner: D) -> Self {
+    let mtu = inner.mtu();
+    Self {
+      inner,
+      rx_buffer: vec![0; mtu],
+    }
+  }
+
+  pub fn get_ref(&self) -> &D {
+    &self.inner
+  }
+
+  pub fn get_mut(&mut self) -> &mut D {
+    &mut self.inner
+  }
+
+  pub fn into_inner(self) -> D {
+    self.inner
+  }
+}
+
+pub struct RxToken {
+  buffer: Vec<u8>,
+}
+
+impl phy::RxToken for RxToken {
+  fn consume<R, F>(self, f: F) -> R
+  where
+    F: FnOnce(&[u8]) -> R,
+  {
+    f(&self.buffer)
+  }
+}
+
+pub struct TxToken<'a, D: NetworkDevice> {
+  device: &'a mut D,
+}
+
+impl<D: NetworkDevice> phy::TxToken for TxToken<'_, D> {
+  fn consume<R, F>(self, len: usize, f: F) -> R
+  where
+    F: FnOnce(&mut [u8]) -> R,
+  {
+    let mut buffer = vec![0; len];
+    let result = f(&mut buffer);
+    if let Err(e) = self.device.send(&buffer) {
+      debug!("smoltcp adapter transmit failed: {}", e);
+    }
+    result
+  }
+}
+
+impl<D: NetworkDevice> phy::Device for SmoltcpDevice<D> {
+  type RxToken<'a>
+    = RxToken
+  where
+    Self: 'a;
+  type TxToken<'a>
+    = TxToken<'a, D>
+  where
+    Self: 'a;
+
+  fn receive(
+    &mut self,
+    _timestamp: Instant,
+  ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+    let len = self.inner.recv(&mut self.rx_buffer).ok()?;
+    let rx = RxToken {
+      buffer: self.rx_buffer[..len].to_vec(),
+    };
+    let tx = TxToken {
+      device: &mut self.inner,
+    };
+    Some((rx, tx))
+  }
+
+  fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+    Some(TxToken {
+      device: &mut self.inner,
+    })
+  }
+
+  fn capabilities(&self) -> DeviceCapabilities {
+    let mut caps = DeviceCapabilities::default();
+    caps.medium = Medium::Ip;
+    caps.max_transmission_unit = self.inner.mtu();
+    caps
+  }
+}
+
+/// Exposes a smoltcp `phy::Device` (tun, raw socket, loopback...) as a
+/// `NetworkDevice`
+pub struct FromSmoltcp<D: phy::Device> {
+  inner: D,
+}
+
+impl<D: phy::Device> FromSmoltcp<D> {
+  /// Wrap a smoltcp device, which must use `Medium::Ip`
+  pub fn new(inner: D) -> io::Result<Self> {
+    if inner.capabilities().medium != Medium::Ip {
+      return Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "only IP-medium smoltcp devices carry bare IPv4 packets",
+      ));
+    }
+    Ok(Self { inner })
+  }
+
+  pub fn get_ref(&self) -> &D {
+    &self.inner
+  }
+
+  pub fn get_mut(&mut self) -> &mut D {
+    &mut self.inner
+  }
+
+  pub fn into_inner(self) -> D {
+    self.inner
+  }
+}
+
+impl<D: phy::Device> NetworkDevice for FromSmoltcp<D> {
+  fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+    use smoltcp::phy::TxToken as _;
+
+    let token = self
+      .inner
+      .transmit(Instant::now())
+      .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+    token.consume(packet.len(), |buf| buf.copy_from_slice(packet));
+    Ok(())
+  }
+
+  fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    use smoltcp::phy::RxToken as _;
+
+    let (rx, _tx) = self
+      .inner
+      .receive(Instant::now())
+      .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+    rx.consume(|data| {
+      if data.len() > buf.len() {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          "packet larger than receive buffer",
+        ));
+      }
+      buf[..data.len()].copy_from_slice(data);
+      Ok(data.len())
+    })
+  }
+
+  fn mtu(&self) -> usize {
+    self.inner.capabilities().max_transmission_unit
+  }
+}
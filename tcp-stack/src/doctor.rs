@@ -0,0 +1,296 @@
+//! Startup self-test of the host environment
+//!
+//! Most failures to get a connection up with a userspace stack come from
+//! the host rather than the code: no permission to open raw sockets, the
+//! kernel resetting every handshake because it has no socket for the port,
+//! reverse path filtering dropping replies, or an interface MTU smaller
+//! than the segments sent. `doctor` checks each of these without sending a
+//! packet and says what to change for every one that fails.
+
+use crate::connection::control::DEFAULT_MSS;
+use crate::packet::{Ipv4Header, TcpHeader};
+use crate::socket::privilege::check_raw_socket_privilege;
+use crate::socket::{route, RawSocket};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::process::Command;
+
+/// Where `doctor` looks up the default route to. Nothing is sent to it
+pub const PROBE_DESTINATION: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+/// How a check came out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Status {
+  Pass,
+  /// Likely to cause trouble, but connections may still work
+  Warn,
+  /// Connections will not work until this is fixed
+  Fail,
+  /// Could not be checked on this host
+  Skip,
+}
+
+/// The outcome of one check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+  pub name: &'static str,
+  pub status: Status,
+  /// What was found
+  pub detail: String,
+  /// What to do about it, unless it passed
+  pub remedy: Option<String>,
+}
+
+impl Check {
+  fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+    Self {
+      name,
+      status: Status::Pass,
+      detail: detail.into(),
+      remedy: None,
+    }
+  }
+
+  fn skip(name: &'static str, detail: impl Into<String>) -> Self {
+    Self {
+      name,
+      status: Status::Skip,
+      detail: detail.into(),
+      remedy: None,
+    }
+  }
+
+  fn problem(
+    name: &'static str,
+    status: Status,
+    detail: impl Into<String>,
+    remedy: impl Into<String>,
+  ) -> Self {
+    Self {
+      name,
+      status,
+      detail: detail.into(),
+      remedy: Some(remedy.into()),
+    }
+  }
+}
+
+/// Everything `doctor` checked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+  pub checks: Vec<Check>,
+}
+
+impl Report {
+  /// No check failed; warnings are allowed
+  pub fn passed(&self) -> bool {
+    self.checks.iter().all(|check| check.status != Status::Fail)
+  }
+
+  /// Checks that warned or failed
+  pub fn problems(&self) -> impl Iterator<Item = &Check> {
+    self
+      .checks
+      .iter()
+      .filter(|check| matches!(check.status, Status::Warn | Status::Fail))
+  }
+}
+
+impl fmt::Display for Report {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for check in &self.checks {
+      let tag = match check.status {
+        Status::Pass => " ok ",
+        Status::Warn => "warn",
+        Status::Fail => "FAIL",
+        Status::Skip => "skip",
+      };
+      writeln!(f, "[{tag}] {}: {}", check.name, check.detail)?;
+      if let Some(remedy) = &check.remedy {
+        writeln!(f, "       fix: {remedy}")?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Check the host along the default route
+pub fn doctor() -> Report {
+  doctor_for(PROBE_DESTINATION)
+}
+
+/// Check the host along the route to `dst`
+pub fn doctor_for(dst: Ipv4Addr) -> Report {
+  let route = route::lookup(dst);
+  let interface = route
+    .as_ref()
+    .ok()
+    .and_then(|route| route.interface.clone());
+  let mut checks = vec![raw_socket()];
+  checks.push(match &route {
+    Ok(route) => Check::pass(
+      "route",
+      format!(
+        "{dst} leaves from {} via {}",
+        route.source,
+        interface.as_deref().unwrap_or("an unknown interface")
+      ),
+    ),
+    Err(e) => Check::problem(
+      "route",
+      Status::Fail,
+      format!("no route to {dst}: {e}"),
+      "bring an interface up with an IPv4 address and a default route",
+    ),
+  });
+  checks.push(kernel_resets());
+  checks.push(rp_filter(interface.as_deref()));
+  checks.push(mtu(interface.as_deref()));
+  Report { checks }
+}
+
+fn raw_socket() -> Check {
+  const NAME: &str = "raw socket";
+  if let Err(e) = check_raw_socket_privilege() {
+    return Check::problem(NAME, Status::Fail, "not permitted", e.to_string());
+  }
+  match RawSocket::new() {
+    Ok(_) => Check::pass(NAME, "opened"),
+    Err(e) => Check::problem(
+      NAME,
+      Status::Fail,
+      format!("could not be opened: {e}"),
+      "check that no seccomp profile or sandbox blocks SOCK_RAW",
+    ),
+  }
+}
+
+/// The kernel answers segments for ports it has no socket for with a
+/// reset, which kills every handshake unless a firewall drops them
+fn kernel_resets() -> Check {
+  const NAME: &str = "kernel resets";
+  let remedy = "drop the kernel's RSTs from the ports the stack uses, e.g. \
+                `iptables -A OUTPUT -p tcp --sport 8080 --tcp-flags RST RST -j DROP`";
+  if cfg!(not(target_os = "linux")) {
+    return Check::skip(NAME, "firewall rules are only inspected on Linux");
+  }
+  let mut read_any = false;
+  for (tool, args) in [
+    ("iptables", &["-S", "OUTPUT"][..]),
+    ("nft", &["list", "ruleset"][..]),
+  ] {
+    let Ok(output) = Command::new(tool).args(args).output() else {
+      continue;
+    };
+    if !output.status.success() {
+      continue;
+    }
+    read_any = true;
+    if drops_resets(&String::from_utf8_lossy(&output.stdout)) {
+      return Check::pass(NAME, format!("an {tool} rule drops outgoing RSTs"));
+    }
+  }
+  if read_any {
+    Check::problem(NAME, Status::Warn, "no rule drops outgoing RSTs", remedy)
+  } else {
+    Check::problem(
+      NAME,
+      Status::Warn,
+      "could not read the firewall rules (iptables or nft missing, or not root)",
+      remedy,
+    )
+  }
+}
+
+/// Whether iptables `-S` or nft ruleset output has a rule dropping RSTs
+fn drops_resets(rules: &str) -> bool {
+  rules.lines().any(|rule| {
+    let rule = rule.to_ascii_lowercase();
+    let drops = rule.contains("-j drop") || rule.trim_end().ends_with("drop");
+    let matches_rst = match rule.find("--tcp-flags") {
+      Some(at) => rule[at..].contains("rst"),
+      None => rule.contains("tcp flags") && rule.contains("rst"),
+    };
+    drops && matches_rst
+  })
+}
+
+/// Strict reverse path filtering drops packets arriving on an interface
+/// the reply would not leave by, before a raw socket sees them
+fn rp_filter(interface: Option<&str>) -> Check {
+  const NAME: &str = "rp_filter";
+  let read = |conf: &str| {
+    std::fs::read_to_string(format!("/proc/sys/net/ipv4/conf/{conf}/rp_filter"))
+      .ok()
+      .and_then(|value| value.trim().parse::<u8>().ok())
+  };
+  let Some(all) = read("all") else {
+    return Check::skip(NAME, "not available on this host");
+  };
+  // The kernel applies the higher of the two values
+  let mode = interface.and_then(read).map_or(all, |own| own.max(all));
+  let conf = interface.unwrap_or("all");
+  match mode {
+    1 => Check::problem(
+      NAME,
+      Status::Warn,
+      format!("strict on {conf}, which drops replies on asymmetric routes"),
+      format!(
+        "use loose mode: `sysctl -w net.ipv4.conf.all.rp_filter=2 \
+         net.ipv4.conf.{conf}.rp_filter=2`"
+      ),
+    ),
+    0 => Check::pass(NAME, format!("off on {conf}")),
+    _ => Check::pass(NAME, format!("loose on {conf}")),
+  }
+}
+
+/// An MTU below what a full-sized segment needs makes the host drop or
+/// fragment them
+fn mtu(interface: Option<&str>) -> Check {
+  const NAME: &str = "MTU";
+  let Some(interface) = interface else {
+    return Check::skip(NAME, "outgoing interface unknown");
+  };
+  let Some(mtu) = std::fs::read_to_string(format!("/sys/class/net/{interface}/mtu"))
+    .ok()
+    .and_then(|value| value.trim().parse::<usize>().ok())
+  else {
+    return Check::skip(NAME, format!("MTU of {interface} unknown"));
+  };
+  let needed = usize::from(DEFAULT_MSS) + Ipv4Header::MIN_SIZE + TcpHeader::MIN_SIZE;
+  if mtu >= needed {
+    return Check::pass(NAME, format!("{mtu} on {interface}"));
+  }
+  let mss = mtu.saturating_sub(Ipv4Header::MIN_SIZE + TcpHeader::MIN_SIZE);
+  Check::problem(
+    NAME,
+    Status::Warn,
+    format!("{mtu} on {interface}, below the {needed} a {DEFAULT_MSS}-byte segment needs"),
+    format!("set `ConnOption::MaxSeg({mss})` on connections over {interface}"),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::drops_resets;
+
+  #[test]
+  fn test_reset_drop_rules_are_recognized() {
+    assert!(drops_resets(
+      "-P OUTPUT ACCEPT\n-A OUTPUT -p tcp -m tcp --sport 8080 --tcp-flags RST RST -j DROP\n"
+    ));
+    assert!(drops_resets(
+      "table inet filter {\n  chain output {\n    tcp sport 8080 tcp flags rst drop\n  }\n}"
+    ));
+    assert!(!drops_resets(
+      "-A OUTPUT -p tcp --tcp-flags SYN SYN -j DROP\n"
+    ));
+    assert!(!drops_resets(
+      "-A OUTPUT -p tcp --tcp-flags RST RST -j ACCEPT\n"
+    ));
+    assert!(!drops_resets(
+      "-A OUTPUT -p tcp -j DROP -m comment --comment RST\n"
+    ));
+  }
+}
@@ -0,0 +1,86 @@
+//! Error types
+
+use crate::connection::CloseReason;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// Errors reported by the stack
+#[derive(Debug, thiserror::Error)]
+pub enum TcpError {
+  /// The process lacks the privilege needed to open raw sockets
+  #[error("missing privilege: {0}")]
+  MissingPrivilege(String),
+
+  /// Dropping privileges after opening the socket failed
+  #[error("failed to drop privileges: {0}")]
+  PrivilegeDrop(String),
+
+  /// The handshake did not complete within the timeout or retry limit
+  #[error("connection timed out")]
+  Timeout,
+
+  /// The operation was cancelled through its `CancelHandle`
+  #[error("operation cancelled")]
+  Cancelled,
+
+  /// The peer answered our SYN with a reset
+  #[error("connection refused")]
+  ConnectionRefused,
+
+  /// The connection is not in a state that allows the operation
+  #[error("not connected")]
+  NotConnected,
+
+  /// The peer reset an established connection
+  #[error("connection reset by peer")]
+  ConnectionReset,
+
+  /// The connection closed for the reason given before or while the
+  /// operation ran
+  #[error("connection closed: {0:?}")]
+  Closed(CloseReason),
+
+  /// The routing table has no route to the destination
+  #[error("no route to host {0}")]
+  HostUnreachable(Ipv4Addr),
+
+  /// Another connection or listener already uses the local endpoint
+  #[error("address already in use: {0}")]
+  AddrInUse(SocketAddrV4),
+
+  /// A connection option was given a value the stack cannot honour
+  #[error("invalid option: {0}")]
+  InvalidOption(&'static str),
+
+  #[error("I/O error: {0}")]
+  Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, TcpError>;
+
+impl TcpError {
+  /// The error for an operation on a connection that closed for `reason`.
+  /// A reset stays `ConnectionReset`
+  pub fn closed(reason: CloseReason) -> Self {
+    match reason {
+      CloseReason::PeerRst => TcpError::ConnectionReset,
+      reason => TcpError::Closed(reason),
+    }
+  }
+}
+
+/// The error async I/O reports on a connection that closed for `reason`;
+/// the `TcpError::Closed` inside names the reason
+impl From<CloseReason> for io::Error {
+  fn from(reason: CloseReason) -> Self {
+    let kind = match reason {
+      CloseReason::PeerFin => io::ErrorKind::BrokenPipe,
+      CloseReason::PeerRst => io::ErrorKind::ConnectionReset,
+      CloseReason::Timeout | CloseReason::RetransmitLimit => io::ErrorKind::TimedOut,
+      CloseReason::UserAbort => io::ErrorKind::ConnectionAborted,
+      CloseReason::IcmpError => io::ErrorKind::HostUnreachable,
+      CloseReason::NetworkDown => io::ErrorKind::NetworkDown,
+    };
+    io::Error::new(kind, TcpError::Closed(reason))
+  }
+}
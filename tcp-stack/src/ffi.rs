@@ -0,0 +1,298 @@
+//! C bindings
+//!
+//! Opaque handles over the stack, its listeners and connections, declared
+//! for C in `include/tcp_stack.h`. Functions return 0 (or a byte count) on
+//! success and a negated `errno` value on failure, so callers can pass
+//! `-ret` to `strerror`. Every handle is owned by the caller and released
+//! with its `_free` or `_close` function.
+//!
+//! Build the shared library with
+//! `cargo rustc --release --lib --features ffi --crate-type cdylib`.
+
+use crate::connection::{CloseReason, ConnectOptions};
+use crate::error::TcpError;
+use crate::{TcpConnection, TcpListener};
+use std::ffi::{c_char, c_int, CStr};
+use std::io;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+
+/// Settings applied to the connections and listeners a stack creates
+pub struct TcpStackHandle {
+  options: ConnectOptions,
+  backlog: Option<usize>,
+}
+
+/// `errno` value for an error
+fn errno(error: &TcpError) -> c_int {
+  match error {
+    TcpError::MissingPrivilege(_) | TcpError::PrivilegeDrop(_) => libc::EPERM,
+    TcpError::Timeout => libc::ETIMEDOUT,
+    TcpError::Cancelled => libc::ECANCELED,
+    TcpError::ConnectionRefused => libc::ECONNREFUSED,
+    TcpError::HostUnreachable(_) => libc::EHOSTUNREACH,
+    TcpError::AddrInUse(_) => libc::EADDRINUSE,
+    TcpError::InvalidOption(_) => libc::EINVAL,
+    TcpError::NotConnected => libc::ENOTCONN,
+    TcpError::ConnectionReset => libc::ECONNRESET,
+    TcpError::Closed(reason) => match reason {
+      CloseReason::PeerFin => libc::EPIPE,
+      CloseReason::PeerRst => libc::ECONNRESET,
+      CloseReason::Timeout | CloseReason::RetransmitLimit => libc::ETIMEDOUT,
+      CloseReason::UserAbort => libc::ECONNABORTED,
+      CloseReason::IcmpError => libc::EHOSTUNREACH,
+      CloseReason::NetworkDown => libc::ENETDOWN,
+    },
+    TcpError::Io(e) => e.raw_os_error().unwrap_or(match e.kind() {
+      io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => libc::EINVAL,
+      io::ErrorKind::TimedOut => libc::ETIMEDOUT,
+      io::ErrorKind::WouldBlock => libc::EAGAIN,
+      _ => libc::EIO,
+    }),
+  }
+}
+
+/// Borrow a NUL-terminated UTF-8 string, or fail with `EINVAL`
+///
+/// # Safety
+/// `s` must be null or point to a NUL-terminated string
+unsafe fn str_arg<'a>(s: *const c_char) -> Result<&'a str, c_int> {
+  if s.is_null() {
+    return Err(libc::EINVAL);
+  }
+  CStr::from_ptr(s).to_str().map_err(|_| libc::EINVAL)
+}
+
+/// Create a stack with default settings
+#[no_mangle]
+pub extern "C" fn tcp_stack_new() -> *mut TcpStackHandle {
+  Box::into_raw(Box::new(TcpStackHandle {
+    options: ConnectOptions::default(),
+    backlog: None,
+  }))
+}
+
+/// Free a stack. Connections and listeners it created stay valid
+///
+/// # Safety
+/// `stack` must be null or come from `tcp_stack_new`, and not be used again
+#[no_mangle]
+pub unsafe extern "C" fn tcp_stack_free(stack: *mut TcpStackHandle) {
+  if !stack.is_null() {
+    drop(Box::from_raw(stack));
+  }
+}
+
+/// Limit how long `tcp_stack_connect` waits for the handshake; 0 waits
+/// until the SYN retries run out
+///
+/// # Safety
+/// `stack` must be null or a live stack handle
+#[no_mangle]
+pub unsafe extern "C" fn tcp_stack_set_connect_timeout(
+  stack: *mut TcpStackHandle,
+  timeout_ms: u32,
+) -> c_int {
+  let Some(stack) = stack.as_mut() else {
+    return -libc::EINVAL;
+  };
+  stack.options.timeout =
+    (timeout_ms > 0).then(|| Duration::from_millis(timeout_ms.into()));
+  0
+}
+
+/// Half-open connections each new listener keeps; 0 restores the default
+///
+/// # Safety
+/// `stack` must be null or a live stack handle
+#[no_mangle]
+pub unsafe extern "C" fn tcp_stack_set_backlog(
+  stack: *mut TcpStackHandle,
+  backlog: u32,
+) -> c_int {
+  let Some(stack) = stack.as_mut() else {
+    return -libc::EINVAL;
+  };
+  stack.backlog = (backlog > 0).then_some(backlog as usize);
+  0
+}
+
+/// Connect to `remote`, a `host:port` string, storing the connection in
+/// `*out`
+///
+/// # Safety
+/// `stack` must be null or a live stack handle, `remote` null or a
+/// NUL-terminated string, and `out` null or valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn tcp_stack_connect(
+  stack: *mut TcpStackHandle,
+  remote: *const c_char,
+  out: *mut *mut TcpConnection,
+) -> c_int {
+  let (Some(stack), false) = (stack.as_ref(), out.is_null()) else {
+    return -libc::EINVAL;
+  };
+  let remote = match str_arg(remote) {
+    Ok(remote) => remote,
+    Err(e) => return -e,
+  };
+  match TcpConnection::connect_host_with(remote, stack.options.clone()) {
+    Ok(conn) => {
+      *out = Box::into_raw(Box::new(conn));
+      0
+    }
+    Err(e) => -errno(&e),
+  }
+}
+
+/// Listen on `local`, an `ip:port` string, storing the listener in `*out`
+///
+/// # Safety
+/// `stack` must be null or a live stack handle, `local` null or a
+/// NUL-terminated string, and `out` null or valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn tcp_stack_listen(
+  stack: *mut TcpStackHandle,
+  local: *const c_char,
+  out: *mut *mut TcpListener,
+) -> c_int {
+  let (Some(stack), false) = (stack.as_ref(), out.is_null()) else {
+    return -libc::EINVAL;
+  };
+  let local: SocketAddrV4 =
+    match str_arg(local).and_then(|s| s.parse().map_err(|_| libc::EINVAL)) {
+      Ok(local) => local,
+      Err(e) => return -e,
+    };
+  match TcpListener::bind(local) {
+    Ok(mut listener) => {
+      if let Some(backlog) = stack.backlog {
+        listener.set_backlog(backlog);
+      }
+      *out = Box::into_raw(Box::new(listener));
+      0
+    }
+    Err(e) => -errno(&e),
+  }
+}
+
+/// Block until a connection is established, storing it in `*out`
+///
+/// # Safety
+/// `listener` must be null or a live listener handle, and `out` null or
+/// valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn tcp_stack_accept(
+  listener: *mut TcpListener,
+  out: *mut *mut TcpConnection,
+) -> c_int {
+  let (Some(listener), false) = (listener.as_mut(), out.is_null()) else {
+    return -libc::EINVAL;
+  };
+  match listener.accept() {
+    Ok(conn) => {
+      *out = Box::into_raw(Box::new(conn));
+      0
+    }
+    Err(e) => -errno(&e),
+  }
+}
+
+/// Stop listening and free the listener
+///
+/// # Safety
+/// `listener` must be null or come from `tcp_stack_listen`, and not be used
+/// again
+#[no_mangle]
+pub unsafe extern "C" fn tcp_stack_listener_free(listener: *mut TcpListener) {
+  if !listener.is_null() {
+    drop(Box::from_raw(listener));
+  }
+}
+
+/// Send `len` bytes from `buf`, blocking while the send buffer is full.
+/// Returns the count sent
+///
+/// # Safety
+/// `conn` must be null or a live connection handle, and `buf` valid for
+/// `len` bytes of reads
+#[no_mangle]
+pub unsafe extern "C" fn tcp_stack_send(
+  conn: *mut TcpConnection,
+  buf: *const u8,
+  len: usize,
+) -> isize {
+  let (Some(conn), false) = (conn.as_mut(), buf.is_null() && len > 0) else {
+    return -libc::EINVAL as isize;
+  };
+  let data = if len == 0 {
+    &[]
+  } else {
+    std::slice::from_raw_parts(buf, len)
+  };
+  match conn.send(data) {
+    Ok(sent) => sent as isize,
+    Err(e) => -errno(&e) as isize,
+  }
+}
+
+/// Receive up to `len` bytes into `buf`, blocking until some arrive.
+/// Returns the count received, 0 once the peer has closed
+///
+/// # Safety
+/// `conn` must be null or a live connection handle, and `buf` valid for
+/// `len` bytes of writes
+#[no_mangle]
+pub unsafe extern "C" fn tcp_stack_recv(
+  conn: *mut TcpConnection,
+  buf: *mut u8,
+  len: usize,
+) -> isize {
+  let (Some(conn), false) = (conn.as_mut(), buf.is_null() && len > 0) else {
+    return -libc::EINVAL as isize;
+  };
+  let data = if len == 0 {
+    &mut []
+  } else {
+    std::slice::from_raw_parts_mut(buf, len)
+  };
+  match conn.recv(data) {
+    Ok(received) => received as isize,
+    Err(e) => -errno(&e) as isize,
+  }
+}
+
+/// How long `tcp_stack_close` waits for unacknowledged data (`SO_LINGER`);
+/// negative returns at once, 0 resets the connection
+///
+/// # Safety
+/// `conn` must be null or a live connection handle
+#[no_mangle]
+pub unsafe extern "C" fn tcp_stack_set_linger(
+  conn: *mut TcpConnection,
+  linger_ms: i32,
+) -> c_int {
+  let Some(conn) = conn.as_mut() else {
+    return -libc::EINVAL;
+  };
+  conn.control_mut().linger = u64::try_from(linger_ms).ok().map(Duration::from_millis);
+  0
+}
+
+/// Close the connection as its linger setting says, then free it. The
+/// connection is freed even when closing fails
+///
+/// # Safety
+/// `conn` must be null or come from `tcp_stack_connect` or
+/// `tcp_stack_accept`, and not be used again
+#[no_mangle]
+pub unsafe extern "C" fn tcp_stack_close(conn: *mut TcpConnection) -> c_int {
+  if conn.is_null() {
+    return -libc::EINVAL;
+  }
+  let mut conn = Box::from_raw(conn);
+  match conn.close() {
+    Ok(()) => 0,
+    Err(e) => -errno(&e),
+  }
+}
@@ -0,0 +1,136 @@
+//! Packet rate limiting
+//!
+//! A `PacketLimiter` caps how many packets of one kind (inbound SYNs,
+//! outbound resets and challenge ACKs) the stack handles per second, over
+//! all sources and per source prefix, so a flood cannot monopolize the
+//! packet loop. Limits are token buckets counted in packets.
+
+use super::TokenBucket;
+use crate::utils::Instant;
+use alloc::collections::BTreeMap;
+use core::net::Ipv4Addr;
+
+/// Tokens a packet costs. `TokenBucket` counts bytes; charging a packet
+/// this many lets rates and bursts be given in packets
+const PACKET_COST: u64 = 1000;
+
+/// Source prefixes tracked before idle buckets are evicted
+pub const DEFAULT_MAX_PREFIXES: usize = 4096;
+
+/// Packets per second, with bursts of up to `burst` packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+  pub rate: u64,
+  pub burst: u64,
+}
+
+impl RateLimit {
+  pub fn new(rate: u64, burst: u64) -> Self {
+    Self { rate, burst }
+  }
+
+  fn bucket(&self, now: Instant) -> TokenBucket {
+    let mut bucket = TokenBucket::new(self.rate.saturating_mul(PACKET_COST), now);
+    bucket.set_burst(self.burst.saturating_mul(PACKET_COST));
+    bucket
+  }
+}
+
+/// Global and per-source-prefix limits on one kind of packet. Unlimited
+/// until a limit is set
+#[derive(Debug, Clone)]
+pub struct PacketLimiter {
+  global: Option<TokenBucket>,
+  /// Prefix length and limit applied to each source prefix
+  per_prefix: Option<(u8, RateLimit)>,
+  prefixes: BTreeMap<u32, TokenBucket>,
+  max_prefixes: usize,
+  limited: u64,
+}
+
+impl PacketLimiter {
+  pub fn new() -> Self {
+    Self {
+      global: None,
+      per_prefix: None,
+      prefixes: BTreeMap::new(),
+      max_prefixes: DEFAULT_MAX_PREFIXES,
+      limited: 0,
+    }
+  }
+
+  /// Cap packets from all sources together; `None` removes the cap
+  pub fn set_global(&mut self, limit: Option<RateLimit>, now: Instant) {
+    self.global = limit.map(|limit| limit.bucket(now));
+  }
+
+  /// Cap packets from each source prefix of `prefix_len` bits (32 for each
+  /// address); `None` removes the cap
+  pub fn set_per_prefix(&mut self, prefix_len: u8, limit: Option<RateLimit>) {
+    self.per_prefix = limit.map(|limit| (prefix_len.min(32), limit));
+    self.prefixes.clear();
+  }
+
+  /// Bound the prefixes tracked at once
+  pub fn set_max_prefixes(&mut self, max: usize) {
+    self.max_prefixes = max;
+  }
+
+  /// Whether a packet from `src` may be handled at `now`, charging the
+  /// limits if so
+  pub fn allow(&mut self, src: Ipv4Addr, now: Instant) -> bool {
+    let global_ok = match &mut self.global {
+      Some(bucket) => bucket.available(now) >= PACKET_COST,
+      None => true,
+    };
+    let allowed = global_ok && self.allow_prefix(src, now);
+    if !allowed {
+      self.limited = self.limited.saturating_add(1);
+      return false;
+    }
+    if let Some(bucket) = &mut self.global {
+      bucket.consume(PACKET_COST);
+    }
+    true
+  }
+
+  /// Packets refused so far
+  pub fn limited(&self) -> u64 {
+    self.limited
+  }
+
+  /// Check and charge the bucket of `src`'s prefix
+  fn allow_prefix(&mut self, src: Ipv4Addr, now: Instant) -> bool {
+    let Some((prefix_len, limit)) = self.per_prefix else {
+      return true;
+    };
+    let mask = u32::MAX
+      .checked_shl(32 - u32::from(prefix_len))
+      .unwrap_or(0);
+    let prefix = u32::from(src) & mask;
+    if !self.prefixes.contains_key(&prefix) && self.prefixes.len() >= self.max_prefixes {
+      // Buckets back to full remember nothing a fresh one would not
+      self
+        .prefixes
+        .retain(|_, bucket| bucket.available(now) < bucket.burst());
+      if self.prefixes.len() >= self.max_prefixes {
+        return false;
+      }
+    }
+    let bucket = self
+      .prefixes
+      .entry(prefix)
+      .or_insert_with(|| limit.bucket(now));
+    if bucket.available(now) < PACKET_COST {
+      return false;
+    }
+    bucket.consume(PACKET_COST);
+    true
+  }
+}
+
+impl Default for PacketLimiter {
+  fn default() -> Self {
+    Self::new()
+  }
+}
@@ -1,5 +1,17 @@
 //! Flow control with sliding windows
 
+pub mod limiter;
+pub mod pacer;
+pub mod scheduler;
+pub mod segmenter;
+pub mod shaper;
 pub mod window;
 
+pub use limiter::{PacketLimiter, RateLimit};
+pub use pacer::Pacer;
+pub use scheduler::{FairScheduler, Priority};
+pub use segmenter::Segmenter;
+#[cfg(feature = "std")]
+pub use shaper::SharedShaper;
+pub use shaper::TokenBucket;
 pub use window::SlidingWindow;
@@ -0,0 +1,132 @@
+//! Pacing from cwnd and RTT
+//!
+//! Instead of sending what cwnd allows back to back, a paced sender spreads
+//! it over the round trip at a multiple of cwnd / SRTT: by default 200% in
+//! slow start, so the window can still double each RTT, and 120% after, as
+//! Linux's `tcp_pacing_ss_ratio` and `tcp_pacing_ca_ratio`. The rate can be
+//! smoothed with an exponential moving average so it does not jump with
+//! every ACK.
+//!
+//! The burst allowed at once is 1/2^shift of a second's worth at the
+//! current rate, as Linux's `sk_pacing_shift`: the default 10 is about 1ms.
+//! A smaller shift allows larger bursts and fewer wakeups; a larger one
+//! paces more finely at the cost of waking more often.
+
+use super::TokenBucket;
+use crate::utils::Instant;
+
+/// Percent of cwnd / SRTT paced at in slow start
+pub const DEFAULT_SS_RATIO: u32 = 200;
+/// Percent of cwnd / SRTT paced at in congestion avoidance and recovery
+pub const DEFAULT_CA_RATIO: u32 = 120;
+/// Burst of 1/1024 second's worth, about 1ms
+pub const DEFAULT_PACING_SHIFT: u8 = 10;
+
+/// Paces a connection's sends at a ratio of cwnd / SRTT
+#[derive(Debug, Clone)]
+pub struct Pacer {
+  ss_ratio: u32,
+  ca_ratio: u32,
+  shift: u8,
+  /// Gain of the moving average as a shift: each update moves the rate
+  /// 1/2^smoothing of the way to the target. Zero follows it at once
+  smoothing: u8,
+  /// Current rate in bytes per second; zero until there is an RTT sample
+  rate: u64,
+  bucket: TokenBucket,
+}
+
+impl Pacer {
+  pub fn new(now: Instant) -> Self {
+    Self {
+      ss_ratio: DEFAULT_SS_RATIO,
+      ca_ratio: DEFAULT_CA_RATIO,
+      shift: DEFAULT_PACING_SHIFT,
+      smoothing: 0,
+      rate: 0,
+      bucket: TokenBucket::new(0, now),
+    }
+  }
+
+  pub fn ss_ratio(&self) -> u32 {
+    self.ss_ratio
+  }
+
+  /// Pace at `percent` of cwnd / SRTT in slow start
+  pub fn set_ss_ratio(&mut self, percent: u32) {
+    self.ss_ratio = percent.max(1);
+  }
+
+  pub fn ca_ratio(&self) -> u32 {
+    self.ca_ratio
+  }
+
+  /// Pace at `percent` of cwnd / SRTT once out of slow start
+  pub fn set_ca_ratio(&mut self, percent: u32) {
+    self.ca_ratio = percent.max(1);
+  }
+
+  pub fn shift(&self) -> u8 {
+    self.shift
+  }
+
+  /// Allow bursts of 1/2^`shift` second's worth, at most 2^-31
+  pub fn set_shift(&mut self, shift: u8) {
+    self.shift = shift.min(31);
+  }
+
+  pub fn smoothing(&self) -> u8 {
+    self.smoothing
+  }
+
+  /// Move the rate 1/2^`smoothing` of the way to each new target, at most
+  /// 1/2^16. Zero turns smoothing off
+  pub fn set_smoothing(&mut self, smoothing: u8) {
+    self.smoothing = smoothing.min(16);
+  }
+
+  /// Bytes per second paced at; zero until there is an RTT to pace over
+  pub fn rate(&self) -> u64 {
+    self.rate
+  }
+
+  /// Bytes allowed in one burst
+  pub fn burst(&self) -> u64 {
+    self.bucket.burst()
+  }
+
+  /// Follow `cwnd` over an SRTT of `srtt` seconds
+  pub fn update(&mut self, cwnd: u32, srtt: f64, slow_start: bool, now: Instant) {
+    if srtt <= 0.0 {
+      return;
+    }
+    let ratio = if slow_start {
+      self.ss_ratio
+    } else {
+      self.ca_ratio
+    };
+    let target = (f64::from(cwnd) * f64::from(ratio) / 100.0 / srtt) as u64;
+    self.rate = if self.rate == 0 || self.smoothing == 0 {
+      target
+    } else if target > self.rate {
+      self.rate + ((target - self.rate) >> self.smoothing)
+    } else {
+      self.rate - ((self.rate - target) >> self.smoothing)
+    };
+    self.bucket.set_rate(self.rate, now);
+    self.bucket.set_burst(self.rate >> self.shift);
+  }
+
+  /// Bytes that may be sent at `now`; unlimited until there is a rate
+  pub fn available(&mut self, now: Instant) -> u64 {
+    if self.rate == 0 {
+      return u64::MAX;
+    }
+    self.bucket.available(now)
+  }
+
+  /// Charge `bytes` sent
+  pub fn consume(&mut self, bytes: u64) {
+    self.bucket.consume(bytes);
+  }
+}
@@ -0,0 +1,226 @@
+//! Fair scheduling of packets across flows
+//!
+//! Deficit round robin (Shreedhar and Varghese): each flow with packets
+//! queued earns `quantum` bytes times its weight per round and sends while
+//! its deficit covers the next packet, so a bulk flow cannot starve others
+//! sharing one link, and weights split it in proportion. Packets of one
+//! flow keep their order.
+//!
+//! Flows also carry a `Priority`. Classes are served strictly in order, so
+//! a control channel's packets go ahead of any bulk transfer's; the round
+//! robin shares the link among flows of one class.
+
+use crate::packet::Ipv4Header;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+/// Bytes a flow of weight 1 earns per round: one full-sized packet
+pub const DEFAULT_QUANTUM: usize = 1500;
+
+/// Service class of a flow, highest first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Priority {
+  /// Control and signaling channels
+  Control,
+  /// Latency-sensitive requests and responses
+  Interactive,
+  #[default]
+  Normal,
+  /// Transfers that may wait for everything else
+  Bulk,
+}
+
+impl Priority {
+  /// Classes in the order they are served
+  pub const ALL: [Priority; 4] = [
+    Priority::Control,
+    Priority::Interactive,
+    Priority::Normal,
+    Priority::Bulk,
+  ];
+
+  /// DSCP to mark the class's packets with (RFC 4594): CS5 for signaling,
+  /// AF21 for low-latency data, default forwarding, and CS1 for
+  /// low-priority data
+  pub fn dscp(self) -> u8 {
+    match self {
+      Priority::Control => Ipv4Header::DSCP_CS5,
+      Priority::Interactive => Ipv4Header::DSCP_AF21,
+      Priority::Normal => Ipv4Header::DSCP_DEFAULT,
+      Priority::Bulk => Ipv4Header::DSCP_CS1,
+    }
+  }
+}
+
+/// Queue and deficit of a flow with packets waiting
+#[derive(Debug, Default)]
+struct Flow {
+  queue: VecDeque<Vec<u8>>,
+  deficit: usize,
+  /// Earned its quantum in the current round
+  credited: bool,
+  /// Class whose round the flow is in
+  priority: Priority,
+}
+
+/// Deficit round robin over flows named by `K`, within strict priority
+/// classes
+#[derive(Debug)]
+pub struct FairScheduler<K> {
+  quantum: usize,
+  weights: BTreeMap<K, u32>,
+  priorities: BTreeMap<K, Priority>,
+  flows: BTreeMap<K, Flow>,
+  /// Flows of each class in round order; the front one is being served
+  active: [VecDeque<K>; Priority::ALL.len()],
+}
+
+impl<K: Ord + Copy> FairScheduler<K> {
+  pub fn new() -> Self {
+    Self {
+      quantum: DEFAULT_QUANTUM,
+      weights: BTreeMap::new(),
+      priorities: BTreeMap::new(),
+      flows: BTreeMap::new(),
+      active: Default::default(),
+    }
+  }
+
+  pub fn quantum(&self) -> usize {
+    self.quantum
+  }
+
+  /// Bytes a flow of weight 1 earns per round, at least 1
+  pub fn set_quantum(&mut self, quantum: usize) {
+    self.quantum = quantum.max(1);
+  }
+
+  pub fn weight(&self, key: K) -> u32 {
+    self.weights.get(&key).copied().unwrap_or(1)
+  }
+
+  /// Give `key` `weight` times the share of a flow of weight 1. Zero is
+  /// taken as 1
+  pub fn set_weight(&mut self, key: K, weight: u32) {
+    if weight <= 1 {
+      self.weights.remove(&key);
+    } else {
+      self.weights.insert(key, weight);
+    }
+  }
+
+  pub fn priority(&self, key: K) -> Priority {
+    self.priorities.get(&key).copied().unwrap_or_default()
+  }
+
+  /// Serve `key` in `priority`'s class, packets already queued included
+  pub fn set_priority(&mut self, key: K, priority: Priority) {
+    if priority == Priority::Normal {
+      self.priorities.remove(&key);
+    } else {
+      self.priorities.insert(key, priority);
+    }
+    let Some(flow) = self.flows.get_mut(&key) else {
+      return;
+    };
+    if flow.priority != priority {
+      self.active[flow.priority as usize].retain(|active| *active != key);
+      self.active[priority as usize].push_back(key);
+      flow.priority = priority;
+      flow.credited = false;
+    }
+  }
+
+  /// Forget the weight and priority of a flow that is gone. Packets it
+  /// queued are still sent
+  pub fn remove(&mut self, key: K) {
+    self.weights.remove(&key);
+    self.priorities.remove(&key);
+  }
+
+  /// Queue `packet` behind those already queued for `key`
+  pub fn push(&mut self, key: K, packet: Vec<u8>) {
+    self.queue(key).push_back(packet);
+  }
+
+  /// The queue of `key`, for pushing packets into. The flow joins the
+  /// round; if nothing is pushed it leaves again at its turn
+  pub fn queue(&mut self, key: K) -> &mut VecDeque<Vec<u8>> {
+    let priority = self.priority(key);
+    let active = &mut self.active;
+    &mut self
+      .flows
+      .entry(key)
+      .or_insert_with(|| {
+        active[priority as usize].push_back(key);
+        Flow {
+          priority,
+          ..Flow::default()
+        }
+      })
+      .queue
+  }
+
+  /// Packets queued across all flows
+  pub fn len(&self) -> usize {
+    self.flows.values().map(|flow| flow.queue.len()).sum()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.flows.values().all(|flow| flow.queue.is_empty())
+  }
+
+  /// Flows in the round, including emptied ones that leave at their turn
+  pub fn flow_count(&self) -> usize {
+    self.flows.len()
+  }
+
+  /// The next packet to send and the flow it belongs to, from the highest
+  /// class with any
+  pub fn pop(&mut self) -> Option<(K, Vec<u8>)> {
+    Priority::ALL
+      .into_iter()
+      .find_map(|priority| self.pop_class(priority))
+  }
+
+  /// One round robin step among the flows of `priority`
+  fn pop_class(&mut self, priority: Priority) -> Option<(K, Vec<u8>)> {
+    let active = &mut self.active[priority as usize];
+    loop {
+      let key = *active.front()?;
+      let weight = self.weights.get(&key).copied().unwrap_or(1);
+      let quantum = self.quantum.saturating_mul(weight as usize);
+      let flow = self.flows.get_mut(&key).expect("active flows are kept");
+      let Some(len) = flow.queue.front().map(Vec::len) else {
+        self.flows.remove(&key);
+        active.pop_front();
+        continue;
+      };
+      if !flow.credited {
+        flow.deficit = flow.deficit.saturating_add(quantum);
+        flow.credited = true;
+      }
+      if flow.deficit < len {
+        // Out of credit this round: the rest waits for the next
+        flow.credited = false;
+        active.rotate_left(1);
+        continue;
+      }
+      flow.deficit -= len;
+      let packet = flow.queue.pop_front().expect("queue has a front");
+      if flow.queue.is_empty() {
+        // A flow that runs dry keeps no credit
+        self.flows.remove(&key);
+        active.pop_front();
+      }
+      return Some((key, packet));
+    }
+  }
+}
+
+impl<K: Ord + Copy> Default for FairScheduler<K> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
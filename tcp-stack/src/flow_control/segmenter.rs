@@ -0,0 +1,139 @@
+//! Send-side segmentation
+//!
+//! `Segmenter` queues the application's outgoing bytes and cuts them into
+//! segments no larger than the effective MSS and the send budget. Small
+//! segments are held back by Nagle's algorithm (RFC 896) and sender-side
+//! silly window avoidance (RFC 1122 4.2.3.4) while earlier data is
+//! unacknowledged.
+//!
+//! Writes are queued as the `Bytes` they came in, and a segment lying
+//! within one write is a slice of it rather than a copy, so a payload the
+//! application hands over as `Bytes` reaches packet assembly, and the
+//! retransmission queue, without being copied. Only a segment spanning two
+//! writes is gathered into a buffer of its own.
+
+use crate::utils::SeqNumber;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use bytes::{Bytes, BytesMut};
+
+/// Queue of unsent bytes and the rules for cutting them into segments
+pub struct Segmenter {
+  queue: VecDeque<Bytes>,
+  /// Bytes across `queue`
+  queued: usize,
+  nagle: bool,
+}
+
+impl Segmenter {
+  pub fn new() -> Self {
+    Self {
+      queue: VecDeque::new(),
+      queued: 0,
+      nagle: true,
+    }
+  }
+
+  /// Queue a copy of `data` for sending
+  pub fn write(&mut self, data: &[u8]) {
+    self.write_bytes(Bytes::copy_from_slice(data));
+  }
+
+  /// Queue `data` for sending as it is, to be sliced into segments
+  pub fn write_bytes(&mut self, data: Bytes) {
+    if data.is_empty() {
+      return;
+    }
+    self.queued += data.len();
+    self.queue.push_back(data);
+  }
+
+  /// Discard everything queued
+  pub fn clear(&mut self) {
+    self.queue.clear();
+    self.queued = 0;
+  }
+
+  /// Bytes queued but not yet cut into segments
+  pub fn queued(&self) -> usize {
+    self.queued
+  }
+
+  /// Copy of the queued bytes, oldest first
+  pub fn unsent(&self) -> Vec<u8> {
+    self.queue.iter().flatten().copied().collect()
+  }
+
+  pub fn nagle(&self) -> bool {
+    self.nagle
+  }
+
+  /// Enable or disable Nagle's algorithm (`TCP_NODELAY` disables it)
+  pub fn set_nagle(&mut self, nagle: bool) {
+    self.nagle = nagle;
+  }
+
+  /// Cut the next segments, starting at `snd_nxt`, out of the queue.
+  /// `mss` is the payload room of one segment, `budget` the bytes flow and
+  /// congestion control allow, and `in_flight` the bytes sent but not yet
+  /// acknowledged
+  pub fn segments(
+    &mut self,
+    snd_nxt: SeqNumber,
+    mss: usize,
+    budget: u32,
+    in_flight: u32,
+  ) -> Vec<(SeqNumber, Bytes)> {
+    let mut segments = Vec::new();
+    let mut seq = snd_nxt;
+    let mut budget = budget as usize;
+    let mut in_flight = in_flight;
+
+    while self.queued > 0 && budget > 0 && mss > 0 {
+      let len = self.queued.min(mss).min(budget);
+      if len < mss && in_flight > 0 {
+        // Cut short by the window: wait for it to open instead of sending a
+        // sliver. The tail of the queue: Nagle waits for the ACK to coalesce
+        let window_limited = len < self.queued;
+        if window_limited || self.nagle {
+          break;
+        }
+      }
+
+      segments.push((seq, self.take(len)));
+      seq = seq + len as u32;
+      budget -= len;
+      in_flight = in_flight.saturating_add(len as u32);
+    }
+    segments
+  }
+
+  /// The next `len` queued bytes: a slice of the write at the front when
+  /// it holds them all, else gathered from as many writes as they span
+  fn take(&mut self, len: usize) -> Bytes {
+    self.queued -= len;
+    let front = self.queue.front_mut().expect("len bytes queued");
+    if front.len() > len {
+      return front.split_to(len);
+    }
+    if front.len() == len {
+      return self.queue.pop_front().expect("front exists");
+    }
+    let mut payload = BytesMut::with_capacity(len);
+    while payload.len() < len {
+      let front = self.queue.front_mut().expect("len bytes queued");
+      let part = front.len().min(len - payload.len());
+      payload.extend_from_slice(&front.split_to(part));
+      if front.is_empty() {
+        self.queue.pop_front();
+      }
+    }
+    payload.freeze()
+  }
+}
+
+impl Default for Segmenter {
+  fn default() -> Self {
+    Self::new()
+  }
+}
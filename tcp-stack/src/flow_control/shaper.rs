@@ -0,0 +1,128 @@
+//! Send rate limiting
+//!
+//! A token bucket caps how fast a sender may put bytes on the wire,
+//! independently of what the congestion and receive windows allow. Each
+//! connection can carry its own bucket, and with `std` any number of
+//! connections can also draw from one `SharedShaper` for an aggregate cap.
+//! Under `--cfg loom` its lock is loom's, which tests/loom.rs model-checks.
+
+use crate::utils::Instant;
+use core::time::Duration;
+#[cfg(all(feature = "std", loom))]
+use loom::sync::{Arc, Mutex, MutexGuard};
+#[cfg(all(feature = "std", not(loom)))]
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Smallest burst a bucket allows, so a full-sized segment can always pass
+pub const MIN_BURST: u64 = 2 * 1500;
+
+/// Token bucket refilled at `rate` bytes per second up to `burst` bytes
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+  rate: u64,
+  burst: u64,
+  tokens: u64,
+  last_refill: Instant,
+}
+
+impl TokenBucket {
+  /// Bucket for `rate` bytes per second, starting full, with a burst of
+  /// 10ms worth of data
+  pub fn new(rate: u64, now: Instant) -> Self {
+    let burst = (rate / 100).max(MIN_BURST);
+    Self {
+      rate,
+      burst,
+      tokens: burst,
+      last_refill: now,
+    }
+  }
+
+  pub fn rate(&self) -> u64 {
+    self.rate
+  }
+
+  pub fn burst(&self) -> u64 {
+    self.burst
+  }
+
+  pub fn set_rate(&mut self, rate: u64, now: Instant) {
+    self.refill(now);
+    self.rate = rate;
+  }
+
+  pub fn set_burst(&mut self, burst: u64) {
+    self.burst = burst.max(MIN_BURST);
+    self.tokens = self.tokens.min(self.burst);
+  }
+
+  /// Bytes that may be sent at `now`
+  pub fn available(&mut self, now: Instant) -> u64 {
+    self.refill(now);
+    self.tokens
+  }
+
+  /// Charge `bytes` against the bucket
+  pub fn consume(&mut self, bytes: u64) {
+    self.tokens = self.tokens.saturating_sub(bytes);
+  }
+
+  /// Time until `bytes` (capped at the burst) can be sent
+  pub fn delay_until(&mut self, bytes: u64, now: Instant) -> Duration {
+    let wanted = bytes.min(self.burst);
+    let available = self.available(now);
+    if available >= wanted || self.rate == 0 {
+      return Duration::ZERO;
+    }
+    let missing = (wanted - available) as u128;
+    let micros = (missing * 1_000_000).div_ceil(self.rate as u128);
+    Duration::from_micros(micros.min(u64::MAX as u128) as u64)
+  }
+
+  fn refill(&mut self, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.last_refill).as_micros();
+    let added = elapsed * self.rate as u128 / 1_000_000;
+    // Leave `last_refill` alone until a whole byte has accrued so slow
+    // buckets polled often still fill
+    if added > 0 {
+      self.tokens = (self.tokens as u128 + added).min(self.burst as u128) as u64;
+      self.last_refill = now;
+    }
+  }
+}
+
+/// Token bucket shared by several connections, capping their total rate
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct SharedShaper(Arc<Mutex<TokenBucket>>);
+
+#[cfg(feature = "std")]
+impl SharedShaper {
+  pub fn new(rate: u64, now: Instant) -> Self {
+    Self(Arc::new(Mutex::new(TokenBucket::new(rate, now))))
+  }
+
+  pub fn rate(&self) -> u64 {
+    self.lock().rate()
+  }
+
+  pub fn set_rate(&self, rate: u64, now: Instant) {
+    self.lock().set_rate(rate, now);
+  }
+
+  pub fn available(&self, now: Instant) -> u64 {
+    self.lock().available(now)
+  }
+
+  pub fn consume(&self, bytes: u64) {
+    self.lock().consume(bytes);
+  }
+
+  pub fn delay_until(&self, bytes: u64, now: Instant) -> Duration {
+    self.lock().delay_until(bytes, now)
+  }
+
+  fn lock(&self) -> MutexGuard<'_, TokenBucket> {
+    self.0.lock().unwrap_or_else(|e| e.into_inner())
+  }
+}
@@ -7,6 +7,9 @@ pub struct SlidingWindow {
   size: u32,
   left_edge: SeqNumber,
   right_edge: SeqNumber,
+  /// SND.WL1 and SND.WL2: SEG.SEQ and SEG.ACK of the segment that last set
+  /// the window
+  last_update: Option<(SeqNumber, SeqNumber)>,
 }
 
 impl SlidingWindow {
@@ -15,6 +18,17 @@ impl SlidingWindow {
       size,
       left_edge: SeqNumber(0),
       right_edge: SeqNumber(size),
+      last_update: None,
+    }
+  }
+
+  /// Window of `size` bytes from `left_edge`
+  pub fn starting_at(left_edge: SeqNumber, size: u32) -> Self {
+    Self {
+      size,
+      left_edge,
+      right_edge: left_edge + size,
+      last_update: None,
     }
   }
 
@@ -25,16 +39,42 @@ impl SlidingWindow {
     }
   }
 
+  /// Take the window advertised by an acceptable segment (RFC 793 3.9),
+  /// unless an earlier segment set it: a segment counts as newer by SEG.SEQ,
+  /// then by SEG.ACK, so a reordered stale advertisement is ignored. The
+  /// right edge may move left when the peer shrinks its window. Returns
+  /// whether the window was taken
+  pub fn update(&mut self, seg_seq: SeqNumber, seg_ack: SeqNumber, window: u32) -> bool {
+    if let Some((wl1, wl2)) = self.last_update {
+      let newer = wl1.before(seg_seq) || (wl1 == seg_seq && !seg_ack.before(wl2));
+      if !newer {
+        return false;
+      }
+      if seg_ack.after(self.left_edge) {
+        self.left_edge = seg_ack;
+      }
+    } else {
+      self.left_edge = seg_ack;
+    }
+
+    self.size = window;
+    self.right_edge = seg_ack + window;
+    self.last_update = Some((seg_seq, seg_ack));
+    true
+  }
+
   pub fn can_send(&self, seq: SeqNumber, len: u32) -> bool {
     let seg_end = seq + len;
     !seg_end.after(self.right_edge)
   }
 
+  /// Bytes that may still be sent from `next_seq`; zero once it reaches or,
+  /// after the window shrank, passes the right edge
   pub fn available(&self, next_seq: SeqNumber) -> u32 {
-    if next_seq.0 == self.right_edge.0 || next_seq.after(self.right_edge) {
-      0
-    } else {
+    if next_seq.before(self.right_edge) {
       self.right_edge.diff(next_seq)
+    } else {
+      0
     }
   }
 
@@ -54,4 +94,14 @@ impl SlidingWindow {
   pub fn right_edge(&self) -> SeqNumber {
     self.right_edge
   }
+
+  /// SND.WL1, once a segment has set the window
+  pub fn wl1(&self) -> Option<SeqNumber> {
+    self.last_update.map(|(wl1, _)| wl1)
+  }
+
+  /// SND.WL2, once a segment has set the window
+  pub fn wl2(&self) -> Option<SeqNumber> {
+    self.last_update.map(|(_, wl2)| wl2)
+  }
 }
@@ -9,15 +9,72 @@
 //! - Retransmission with dynamic RTO calculation
 //! - Selective Acknowledgments (SACK)
 //! - TCP options (MSS, Window Scaling, Timestamps)
+//!
+//! The protocol core (packets, sequence arithmetic, checksums, congestion and
+//! flow control, reliability and the state machine) builds without `std` when
+//! the default `std` feature is disabled; it only needs `alloc` and takes the
+//! current time from the caller (see `utils::time`). `TcpStack` drives it
+//! without any I/O, for applications that own their packet loop.
+//!
+//! # Cargo features
+//!
+//! - `std` (default): std-only helpers, the demultiplexer and `NetworkDevice`
+//! - `raw-socket` (default): raw socket backend, `TcpConnection` and `TcpListener`
+//! - `async` (default): tokio, for the async runtime and examples
+//! - `tracing` (default): emit `tracing` events; without it logging compiles away
+//! - `cli` (default): dependencies of the `tcp-stack` binary and examples
+//! - `serde`: `Serialize`/`Deserialize` for headers, state and stats
+//! - `smoltcp`: adapters to smoltcp's `phy::Device`
+//! - `ffi`: C bindings (`ffi`, declared in `include/tcp_stack.h`)
+//! - `compression`: experimental payload transforms (`connection::transform`)
+//! - `blast`: experimental ACK-less sending and loss accounting (`connection::blast`)
+//! - `soak`: the `soak` binary, a leak and memory check over many connections
+//!
+//! Users who only need packet parsing can depend on the crate with
+//! `default-features = false` and pull in neither tokio, libc nor tracing.
 
-pub mod packet;
-pub mod socket;
-pub mod connection;
-pub mod reliability;
-pub mod flow_control;
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[macro_use]
+mod macros;
+
+#[cfg(all(feature = "raw-socket", unix))]
+pub mod blocking;
+pub mod config;
 pub mod congestion;
+pub mod connection;
+#[cfg(all(feature = "raw-socket", unix))]
+pub mod doctor;
+#[cfg(feature = "std")]
 pub mod demux;
+#[cfg(feature = "std")]
+pub mod device;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod flow_control;
+pub mod memory;
+pub mod packet;
+pub mod reliability;
+pub mod replay;
+#[cfg(feature = "raw-socket")]
+pub mod socket;
+pub mod stack;
+#[cfg(all(feature = "raw-socket", unix))]
+pub mod threaded;
 pub mod utils;
 
-pub use connection::TcpConnection;
+#[cfg(feature = "raw-socket")]
+pub use connection::{TcpConnection, TcpListener};
+#[cfg(all(feature = "raw-socket", unix))]
+pub use doctor::doctor;
+#[cfg(feature = "std")]
+pub use device::NetworkDevice;
+#[cfg(feature = "std")]
+pub use error::TcpError;
+#[cfg(feature = "raw-socket")]
 pub use socket::RawSocket;
+pub use stack::{ConnectionHandle, TcpStack};
@@ -1,21 +1,603 @@
-use tcp_stack::utils::calculate_checksum;
-use tracing::info;
+//! `tcp-stack`: the userspace stack as a standalone diagnostic tool
+//!
+//! Every subcommand but `capture` and `doctor` runs a `TcpStack` over a raw
+//! socket from the address the route to the peer leaves by. Only packets
+//! for the stack's own connections reach it, so the host's connections are
+//! never answered with resets. Without a subcommand it runs the startup
+//! self-test and exits.
+//!
+//! ```text
+//! sudo tcp-stack connect example.com:80
+//! sudo tcp-stack listen 8080
+//! sudo tcp-stack pingtcp example.com:443 --count 10
+//! sudo tcp-stack stats example.com:80 --send 65536
+//! sudo tcp-stack capture --filter "port 443" --output https.pcap
+//! ```
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+use clap::{Parser, Subcommand};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4, ToSocketAddrs};
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+use tcp_stack::connection::{CloseReason, DropReason, TcpState};
+use tcp_stack::device::NetworkDevice;
+use tcp_stack::doctor::PROBE_DESTINATION;
+use tcp_stack::packet::PacketFilter;
+use tcp_stack::replay::PcapWriter;
+use tcp_stack::socket::route;
+use tcp_stack::utils::{calculate_checksum, Instant};
+use tcp_stack::{ConnectionHandle, RawSocket, TcpStack};
+use tracing::{info, warn};
+
+/// Longest a turn of `run` waits for a packet
+const TURN: Duration = Duration::from_millis(50);
+
+/// How long `stats` waits for the handshake and for the close
+const STATS_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Parser)]
+#[command(version, about = "A userspace TCP stack over raw sockets")]
+struct Cli {
+    /// Skip the environment self-test run before the command
+    #[arg(long, global = true)]
+    no_self_test: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Open a connection and relay it to stdin and stdout
+    Connect {
+        /// host:port to connect to
+        #[arg(value_parser = parse_remote)]
+        remote: SocketAddrV4,
+    },
+    /// Accept connections, print what they send and send them stdin
+    Listen {
+        port: u16,
+        /// Local address to accept on, by default the one the default
+        /// route leaves from
+        #[arg(long)]
+        addr: Option<Ipv4Addr>,
+    },
+    /// Time handshakes to host:port: from SYN to SYN-ACK, or to RST if the
+    /// port is closed
+    Pingtcp {
+        #[arg(value_parser = parse_remote)]
+        remote: SocketAddrV4,
+        /// Probes to send
+        #[arg(short, long, default_value_t = 4)]
+        count: u32,
+        /// Seconds between probes
+        #[arg(short, long, default_value_t = 1.0)]
+        interval: f64,
+        /// Seconds to wait for each answer
+        #[arg(short = 'W', long, default_value_t = 3.0)]
+        timeout: f64,
+    },
+    /// Connect, send, close, and print the connection's statistics
+    Stats {
+        #[arg(value_parser = parse_remote)]
+        remote: SocketAddrV4,
+        /// Bytes of zeros to send before closing
+        #[arg(long, default_value_t = 0)]
+        send: usize,
+    },
+    /// Write the TCP packets the host receives to a pcap file
+    Capture {
+        #[arg(short, long, default_value = "capture.pcap")]
+        output: PathBuf,
+        /// tcpdump-style filter, e.g. "port 443 and tcp-syn"
+        #[arg(short, long)]
+        filter: Option<String>,
+        /// Stop after this many packets
+        #[arg(short = 'n', long)]
+        count: Option<u64>,
+        /// Stop after this many seconds
+        #[arg(short, long)]
+        duration: Option<f64>,
+    },
+    /// Check the host environment and print a fix for every problem
+    Doctor,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if let Some(Command::Doctor) = cli.command {
+        let report = tcp_stack::doctor();
+        print!("{report}");
+        return if report.passed() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        };
+    }
+
+    // Logs go to stderr, leaving stdout to the data
     tracing_subscriber::fmt()
+        .with_writer(io::stderr)
         .with_env_filter(
             tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive("tcp_stack=info".parse()?),
+                .add_directive("tcp_stack=info".parse().expect("valid directive")),
         )
         .init();
 
-    info!("TCP Stack starting...");
-    
+    if !cli.no_self_test {
+        self_test();
+    }
+    let result = match cli.command {
+        None => {
+            info!("TCP Stack initialized successfully");
+            Ok(true)
+        }
+        Some(Command::Connect { remote }) => connect(remote),
+        Some(Command::Listen { port, addr }) => listen(port, addr),
+        Some(Command::Pingtcp {
+            remote,
+            count,
+            interval,
+            timeout,
+        }) => pingtcp(
+            remote,
+            count,
+            Duration::from_secs_f64(interval),
+            Duration::from_secs_f64(timeout),
+        ),
+        Some(Command::Stats { remote, send }) => stats(remote, send),
+        Some(Command::Capture {
+            output,
+            filter,
+            count,
+            duration,
+        }) => capture(output, filter, count, duration.map(Duration::from_secs_f64)),
+        Some(Command::Doctor) => unreachable!("handled above"),
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("tcp-stack: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Log what `doctor` finds wrong, and check the checksum code
+fn self_test() {
+    let report = tcp_stack::doctor();
+    for check in report.problems() {
+        warn!(
+            "{}: {} ({})",
+            check.name,
+            check.detail,
+            check.remedy.as_deref().unwrap_or("no known fix")
+        );
+    }
+    if !report.passed() {
+        warn!("Environment self-test failed; run `tcp-stack doctor` for details");
+    }
+
     let data = vec![0x45u8, 0x00, 0x00, 0x28];
     let sum = calculate_checksum(&data);
     info!("Checksum test: {:04x}", sum);
-    
-    info!("TCP Stack initialized successfully");
-    Ok(())
+}
+
+/// `host:port`, resolved to its first IPv4 address
+fn parse_remote(remote: &str) -> Result<SocketAddrV4, String> {
+    let addrs = remote
+        .to_socket_addrs()
+        .map_err(|e| format!("cannot resolve {remote:?}: {e}"))?;
+    addrs
+        .filter_map(|addr| match addr {
+            std::net::SocketAddr::V4(addr) => Some(addr),
+            std::net::SocketAddr::V6(_) => None,
+        })
+        .next()
+        .ok_or_else(|| format!("{remote:?} has no IPv4 address"))
+}
+
+/// A stack on the address the route to `dst` leaves from
+fn stack_towards(dst: Ipv4Addr) -> io::Result<TcpStack> {
+    let source = route::lookup(dst)?.source;
+    info!("Using local address {}", source);
+    Ok(TcpStack::new(source, rand::random()))
+}
+
+/// Only the packets `remote` sends
+fn from_peer(remote: SocketAddrV4) -> PacketFilter {
+    PacketFilter::parse(&format!(
+        "src host {} and src port {}",
+        remote.ip(),
+        remote.port()
+    ))
+    .expect("valid filter")
+}
+
+/// `TcpStack::run_blocking`, but only packets passing `filter` reach the
+/// stack. One packet is read per turn, and a turn lasts at most `TURN`
+fn run<F>(
+    stack: &mut TcpStack,
+    socket: &mut RawSocket,
+    filter: &PacketFilter,
+    mut app: F,
+) -> io::Result<()>
+where
+    F: FnMut(&mut TcpStack, Instant) -> ControlFlow<()>,
+{
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let now = Instant::now();
+        if app(stack, now).is_break() {
+            return Ok(());
+        }
+        while let Some(packet) = stack.poll_transmit(now) {
+            socket.send(&packet)?;
+        }
+
+        let timeout = match stack.poll_timeout() {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()).min(TURN),
+            None => TURN,
+        };
+        // A zero timeout would mean none at all
+        socket.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        match socket.recv(&mut buf) {
+            Ok(len) if filter.matches(&buf[..len]) => {
+                stack.handle_packet(&buf[..len], Instant::now())
+            }
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Read stdin on a thread of its own. The channel closes at end of input
+fn read_stdin() -> Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        let mut buf = vec![0u8; 16 * 1024];
+        while let Ok(len) = stdin.read(&mut buf) {
+            if len == 0 || tx.send(buf[..len].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Write everything the connection has received to stdout
+fn drain_to_stdout(stack: &mut TcpStack, handle: ConnectionHandle, now: Instant) {
+    let mut stdout = io::stdout().lock();
+    for chunk in stack.recv_bytes(handle, now) {
+        let _ = stdout.write_all(&chunk);
+    }
+    let _ = stdout.flush();
+}
+
+/// Why a connection ended, for the log
+fn describe(reason: Option<CloseReason>) -> String {
+    reason.map_or_else(|| "still open".to_string(), |reason| format!("{reason:?}"))
+}
+
+fn connect(remote: SocketAddrV4) -> io::Result<bool> {
+    let mut socket = RawSocket::new()?;
+    let mut stack = stack_towards(*remote.ip())?;
+    let handle = stack
+        .connect(remote, Instant::now())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, "no free local port"))?;
+    info!("Connecting to {}", remote);
+
+    let input = read_stdin();
+    let mut pending: Vec<u8> = Vec::new();
+    let mut input_done = false;
+    let mut established = false;
+    run(&mut stack, &mut socket, &from_peer(remote), |stack, now| {
+        let state = stack.state(handle).unwrap_or(TcpState::Closed);
+        if !established && state == TcpState::Established {
+            established = true;
+            info!("Connected to {}", remote);
+        }
+        drain_to_stdout(stack, handle, now);
+        if matches!(state, TcpState::Closed | TcpState::TimeWait) {
+            return ControlFlow::Break(());
+        }
+        if !established {
+            return ControlFlow::Continue(());
+        }
+        // What the send buffer did not take waits for the next turn
+        loop {
+            if !pending.is_empty() {
+                let taken = stack.send(handle, &pending);
+                pending.drain(..taken);
+                if !pending.is_empty() {
+                    break;
+                }
+            }
+            match input.try_recv() {
+                Ok(data) => pending = data,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if !input_done {
+                        input_done = true;
+                        stack.close(handle);
+                    }
+                    break;
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    })?;
+
+    let reason = stack.close_reason(handle);
+    info!("Connection closed: {}", describe(reason));
+    Ok(stack.state(handle) == Some(TcpState::TimeWait) || reason.is_some_and(CloseReason::is_graceful))
+}
+
+fn listen(port: u16, addr: Option<Ipv4Addr>) -> io::Result<bool> {
+    let mut socket = RawSocket::new()?;
+    let mut stack = match addr {
+        Some(addr) => TcpStack::new(addr, rand::random()),
+        None => stack_towards(PROBE_DESTINATION)?,
+    };
+    stack.listen(port);
+    info!("Listening on {}:{}", stack.local_addr(), port);
+    let filter = PacketFilter::parse(&format!("dst port {port}")).expect("valid filter");
+
+    let input = read_stdin();
+    let mut connections: Vec<ConnectionHandle> = Vec::new();
+    run(&mut stack, &mut socket, &filter, |stack, now| {
+        while let Some(handle) = stack.accept() {
+            if let Some(remote) = stack.remote_addr(handle) {
+                info!("Accepted connection from {}", remote);
+            }
+            connections.push(handle);
+        }
+        // Each line of stdin goes to every connection open at the time
+        while let Ok(data) = input.try_recv() {
+            for &handle in &connections {
+                stack.send(handle, &data);
+            }
+        }
+        connections.retain(|&handle| {
+            drain_to_stdout(stack, handle, now);
+            match stack.state(handle) {
+                Some(TcpState::CloseWait) => {
+                    stack.close(handle);
+                    true
+                }
+                Some(TcpState::Closed) | None => {
+                    info!("Connection closed: {}", describe(stack.close_reason(handle)));
+                    stack.remove(handle);
+                    false
+                }
+                _ => true,
+            }
+        });
+        ControlFlow::Continue(())
+    })?;
+    Ok(true)
+}
+
+fn pingtcp(
+    remote: SocketAddrV4,
+    count: u32,
+    interval: Duration,
+    timeout: Duration,
+) -> io::Result<bool> {
+    let mut socket = RawSocket::new()?;
+    let mut stack = stack_towards(*remote.ip())?;
+    println!("PINGTCP {} from {}", remote, stack.local_addr());
+
+    let mut probe: Option<(ConnectionHandle, Instant)> = None;
+    let mut sent = 0;
+    let mut next_at = Instant::now();
+    let mut rtts: Vec<Duration> = Vec::new();
+    run(&mut stack, &mut socket, &from_peer(remote), |stack, now| {
+        if let Some((handle, started)) = probe {
+            let rtt = now.saturating_duration_since(started);
+            let answer = match stack.state(handle) {
+                Some(TcpState::Established) => Some("open"),
+                Some(TcpState::Closed) if stack.close_reason(handle) == Some(CloseReason::PeerRst) => {
+                    Some("closed")
+                }
+                Some(TcpState::Closed) => {
+                    println!("{remote}: seq={sent} unreachable");
+                    stack.abort(handle);
+                    probe = None;
+                    None
+                }
+                _ if rtt >= timeout => {
+                    println!("{remote}: seq={sent} timeout");
+                    stack.abort(handle);
+                    probe = None;
+                    None
+                }
+                _ => None,
+            };
+            if let Some(answer) = answer {
+                println!(
+                    "{remote}: seq={sent} {answer} time={:.3} ms",
+                    rtt.as_secs_f64() * 1000.0
+                );
+                rtts.push(rtt);
+                // Resets an open connection, so the peer does not keep it
+                stack.abort(handle);
+                probe = None;
+            }
+        }
+        if probe.is_none() {
+            if sent == count {
+                return ControlFlow::Break(());
+            }
+            if now >= next_at {
+                let Some(handle) = stack.connect(remote, now) else {
+                    return ControlFlow::Break(());
+                };
+                probe = Some((handle, now));
+                sent += 1;
+                next_at = now + interval;
+            }
+        }
+        ControlFlow::Continue(())
+    })?;
+
+    println!("--- {remote} pingtcp statistics ---");
+    let lost = sent.saturating_sub(rtts.len() as u32);
+    println!(
+        "{sent} probes sent, {} answered, {:.0}% lost",
+        rtts.len(),
+        f64::from(lost) * 100.0 / f64::from(sent.max(1))
+    );
+    if let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) {
+        let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+        let millis = |rtt: &Duration| rtt.as_secs_f64() * 1000.0;
+        println!(
+            "rtt min/avg/max = {:.3}/{:.3}/{:.3} ms",
+            millis(min),
+            millis(&avg),
+            millis(max)
+        );
+    }
+    Ok(!rtts.is_empty())
+}
+
+fn stats(remote: SocketAddrV4, send: usize) -> io::Result<bool> {
+    let mut socket = RawSocket::new()?;
+    let mut stack = stack_towards(*remote.ip())?;
+    let started = Instant::now();
+    let handle = stack
+        .connect(remote, started)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, "no free local port"))?;
+
+    let zeros = vec![0u8; 16 * 1024];
+    let mut remaining = send;
+    let mut closing = false;
+    let mut closed_at: Option<Instant> = None;
+    let mut buf = vec![0u8; 16 * 1024];
+    run(&mut stack, &mut socket, &from_peer(remote), |stack, now| {
+        // What the peer sends is counted and thrown away
+        while stack.recv(handle, &mut buf, now) > 0 {}
+        let state = stack.state(handle).unwrap_or(TcpState::Closed);
+        match state {
+            TcpState::Closed | TcpState::TimeWait => return ControlFlow::Break(()),
+            TcpState::SynSent if now.saturating_duration_since(started) >= STATS_TIMEOUT => {
+                return ControlFlow::Break(())
+            }
+            TcpState::SynSent => return ControlFlow::Continue(()),
+            _ => {}
+        }
+        while remaining > 0 {
+            let taken = stack.send(handle, &zeros[..remaining.min(zeros.len())]);
+            if taken == 0 {
+                break;
+            }
+            remaining -= taken;
+        }
+        if remaining == 0 && !closing {
+            closing = true;
+            closed_at = Some(now);
+            stack.close(handle);
+        }
+        match closed_at {
+            Some(at) if now.saturating_duration_since(at) >= STATS_TIMEOUT => ControlFlow::Break(()),
+            _ => ControlFlow::Continue(()),
+        }
+    })?;
+
+    let state = stack.state(handle).unwrap_or(TcpState::Closed);
+    let Some(control) = stack.control(handle) else {
+        return Ok(false);
+    };
+    let stats = &control.stats;
+    println!("connection   {} -> {}", stack.local_addr(), remote);
+    println!("state        {:?} ({})", state, describe(control.close_reason));
+    println!(
+        "rtt          srtt {:.3} ms, rttvar {:.3} ms, rto {:.3} ms",
+        control.rtt_estimator.srtt() * 1000.0,
+        control.rtt_estimator.rttvar() * 1000.0,
+        control.rtt_estimator.rto() * 1000.0
+    );
+    println!("cwnd         {} bytes, mss {}", control.congestion.cwnd(), control.mss);
+    match &stats.window_scaling {
+        Some(scaling) => println!("window scale {:?}", scaling),
+        None => println!("window scale not negotiated"),
+    }
+    println!(
+        "sent         {} bytes in {} segments, {} retransmitted",
+        stats.bytes_sent, stats.segments_sent, stats.retransmissions
+    );
+    println!(
+        "received     {} bytes in {} segments, {} duplicate bytes",
+        stats.bytes_received, stats.segments_received, stats.duplicate_bytes_received
+    );
+    println!(
+        "acks         {} bytes acked, {} duplicate acks",
+        stats.bytes_acked, stats.duplicate_acks
+    );
+    for reason in DropReason::ALL {
+        let drops = stats.drops.get(reason) + stack.drops().get(reason);
+        if drops > 0 {
+            println!("dropped      {} {:?}", drops, reason);
+        }
+    }
+    Ok(state == TcpState::TimeWait || control.close_reason.is_some_and(CloseReason::is_graceful))
+}
+
+fn capture(
+    output: PathBuf,
+    filter: Option<String>,
+    count: Option<u64>,
+    duration: Option<Duration>,
+) -> io::Result<bool> {
+    let filter = PacketFilter::parse(filter.as_deref().unwrap_or(""))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let mut socket = RawSocket::new()?;
+    socket.set_read_timeout(Some(TURN))?;
+    let mut file = File::create(&output)?;
+    info!("Capturing to {}", output.display());
+
+    // Each `take` starts a new file; after the first only the packets are
+    // appended
+    let header = PcapWriter::new().as_bytes().len();
+    let mut writer = PcapWriter::new();
+    file.write_all(&writer.take())?;
+
+    let started = Instant::now();
+    let mut captured = 0u64;
+    let mut buf = vec![0u8; 65535];
+    while count.is_none_or(|count| captured < count)
+        && duration.is_none_or(|duration| Instant::now().saturating_duration_since(started) < duration)
+    {
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        if !filter.matches(&buf[..len]) {
+            continue;
+        }
+        writer.write(Instant::now(), &buf[..len]);
+        file.write_all(&writer.take()[header..])?;
+        captured += 1;
+    }
+    info!("Captured {} packets", captured);
+    Ok(true)
 }
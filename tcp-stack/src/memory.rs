@@ -0,0 +1,196 @@
+//! Stack-wide memory accounting
+//!
+//! Buffers that hold segment data charge their bytes to one `MemoryPool`:
+//! out-of-order segments, received bytes awaiting a read, sent bytes kept
+//! for retransmission, and half-open connections in accept queues. With
+//! `MemoryLimits` set, the stack sheds load as usage grows:
+//!
+//! - above `pressure`, out-of-order data and new half-open connections are
+//!   refused, and advertised windows shrink to the headroom left;
+//! - at `hard`, in-order data is refused too and writes stop taking bytes.
+//!
+//! Accounting is process-wide, like `stats::stack_drops`.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// What accounted memory is held for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MemoryPool {
+  /// Out-of-order segments awaiting the gap before them
+  Reorder,
+  /// In-order bytes the application has not read
+  Receive,
+  /// Sent bytes kept until acknowledged
+  Retransmit,
+  /// Half-open connections waiting in listeners' accept queues
+  AcceptQueue,
+}
+
+impl MemoryPool {
+  pub const ALL: [MemoryPool; 4] = [
+    MemoryPool::Reorder,
+    MemoryPool::Receive,
+    MemoryPool::Retransmit,
+    MemoryPool::AcceptQueue,
+  ];
+
+  /// Whether the pool gives way first, above the pressure threshold: its
+  /// data can be dropped without stalling a connection
+  fn is_sheddable(self) -> bool {
+    matches!(self, MemoryPool::Reorder | MemoryPool::AcceptQueue)
+  }
+}
+
+/// Thresholds on the total accounted bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MemoryLimits {
+  /// Usage above which sheddable data is refused and windows shrink
+  pub pressure: usize,
+  /// Usage no charge may take the stack past
+  pub hard: usize,
+}
+
+impl MemoryLimits {
+  /// A hard limit, with pressure starting at three quarters of it
+  pub fn new(hard: usize) -> Self {
+    Self {
+      pressure: hard / 4 * 3,
+      hard,
+    }
+  }
+}
+
+/// How close usage is to the limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MemoryPressure {
+  Normal,
+  Pressure,
+  Exhausted,
+}
+
+/// Snapshot of the accountant
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MemoryStats {
+  pub reorder: usize,
+  pub receive: usize,
+  pub retransmit: usize,
+  pub accept_queue: usize,
+  pub limits: Option<MemoryLimits>,
+  /// Charges refused under pressure or at the hard limit
+  pub refused: usize,
+}
+
+impl MemoryStats {
+  pub fn get(&self, pool: MemoryPool) -> usize {
+    match pool {
+      MemoryPool::Reorder => self.reorder,
+      MemoryPool::Receive => self.receive,
+      MemoryPool::Retransmit => self.retransmit,
+      MemoryPool::AcceptQueue => self.accept_queue,
+    }
+  }
+
+  pub fn total(&self) -> usize {
+    MemoryPool::ALL.iter().map(|&pool| self.get(pool)).sum()
+  }
+}
+
+static POOLS: [AtomicUsize; MemoryPool::ALL.len()] =
+  [const { AtomicUsize::new(0) }; MemoryPool::ALL.len()];
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+static PRESSURE: AtomicUsize = AtomicUsize::new(usize::MAX);
+static HARD: AtomicUsize = AtomicUsize::new(usize::MAX);
+static REFUSED: AtomicUsize = AtomicUsize::new(0);
+
+/// Set or clear the limits. Bytes already held stay charged
+pub fn set_limits(limits: Option<MemoryLimits>) {
+  let (pressure, hard) = match limits {
+    Some(limits) => (limits.pressure.min(limits.hard), limits.hard),
+    None => (usize::MAX, usize::MAX),
+  };
+  PRESSURE.store(pressure, Ordering::Relaxed);
+  HARD.store(hard, Ordering::Relaxed);
+}
+
+pub fn limits() -> Option<MemoryLimits> {
+  let hard = HARD.load(Ordering::Relaxed);
+  (hard != usize::MAX).then(|| MemoryLimits {
+    pressure: PRESSURE.load(Ordering::Relaxed),
+    hard,
+  })
+}
+
+pub fn stats() -> MemoryStats {
+  let held = |pool: MemoryPool| POOLS[pool as usize].load(Ordering::Relaxed);
+  MemoryStats {
+    reorder: held(MemoryPool::Reorder),
+    receive: held(MemoryPool::Receive),
+    retransmit: held(MemoryPool::Retransmit),
+    accept_queue: held(MemoryPool::AcceptQueue),
+    limits: limits(),
+    refused: REFUSED.load(Ordering::Relaxed),
+  }
+}
+
+pub fn pressure() -> MemoryPressure {
+  let total = TOTAL.load(Ordering::Relaxed);
+  if total >= HARD.load(Ordering::Relaxed) {
+    MemoryPressure::Exhausted
+  } else if total > PRESSURE.load(Ordering::Relaxed) {
+    MemoryPressure::Pressure
+  } else {
+    MemoryPressure::Normal
+  }
+}
+
+/// Bytes that may still be charged before the hard limit
+pub fn headroom() -> usize {
+  HARD
+    .load(Ordering::Relaxed)
+    .saturating_sub(TOTAL.load(Ordering::Relaxed))
+}
+
+/// Cap on a receive window: the headroom once under pressure, otherwise
+/// no cap
+pub fn window_cap() -> usize {
+  match pressure() {
+    MemoryPressure::Normal => usize::MAX,
+    _ => headroom(),
+  }
+}
+
+/// Charge `bytes` to `pool` unconditionally, for memory already committed
+/// such as sent data
+pub fn charge(pool: MemoryPool, bytes: usize) {
+  POOLS[pool as usize].fetch_add(bytes, Ordering::Relaxed);
+  TOTAL.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Charge `bytes` to `pool` if the limits allow: sheddable pools only up
+/// to the pressure threshold, the others up to the hard limit. Returns
+/// whether the bytes were charged
+pub fn try_charge(pool: MemoryPool, bytes: usize) -> bool {
+  let limit = if pool.is_sheddable() {
+    PRESSURE.load(Ordering::Relaxed)
+  } else {
+    HARD.load(Ordering::Relaxed)
+  };
+  let before = TOTAL.fetch_add(bytes, Ordering::Relaxed);
+  if before.saturating_add(bytes) > limit {
+    TOTAL.fetch_sub(bytes, Ordering::Relaxed);
+    REFUSED.fetch_add(1, Ordering::Relaxed);
+    return false;
+  }
+  POOLS[pool as usize].fetch_add(bytes, Ordering::Relaxed);
+  true
+}
+
+/// Return `bytes` previously charged to `pool`
+pub fn release(pool: MemoryPool, bytes: usize) {
+  POOLS[pool as usize].fetch_sub(bytes, Ordering::Relaxed);
+  TOTAL.fetch_sub(bytes, Ordering::Relaxed);
+}
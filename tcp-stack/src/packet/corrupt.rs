@@ -0,0 +1,88 @@
+//! Deliberate corruption of outgoing packets, for self-checks
+//!
+//! With `StackConfig::corruption` set, a `TcpStack` flips one random bit
+//! in the TCP segment of a fraction of the packets it sends, picked by a
+//! `SimRng` keyed by the stack's seed so a run replays exactly. Corrupting
+//! after the checksum is calculated checks that the peer's checksum
+//! validation rejects the packet. Corrupting before, with the checksum then
+//! made to match, lets the damage through to the receive path behind it:
+//! header parsing, option decoding and sequence checks. Never turn it on
+//! outside tests.
+
+use super::TcpHeader;
+use crate::utils::SimRng;
+
+/// When a corrupted packet's checksum is calculated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CorruptStage {
+  /// After corrupting, so the checksum holds and the damage goes deeper
+  BeforeChecksum,
+  /// Before corrupting, so the checksum no longer holds
+  AfterChecksum,
+}
+
+/// Which outgoing packets to corrupt, and how
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Corruption {
+  /// Packets corrupted per million sent
+  pub per_million: u32,
+  pub stage: CorruptStage,
+}
+
+impl Corruption {
+  pub const MILLION: u32 = 1_000_000;
+
+  /// Corrupt `fraction` of the packets sent, from 0 to 1
+  pub fn new(fraction: f64, stage: CorruptStage) -> Self {
+    Self {
+      per_million: (fraction.clamp(0.0, 1.0) * f64::from(Self::MILLION)) as u32,
+      stage,
+    }
+  }
+
+  pub fn fraction(&self) -> f64 {
+    f64::from(self.per_million) / f64::from(Self::MILLION)
+  }
+
+  /// Flip a bit of the TCP segment of `packet`, an IPv4 packet, if `rng`
+  /// picks it. Returns whether it did
+  pub fn apply(&self, packet: &mut [u8], rng: &mut SimRng) -> bool {
+    if !rng.ratio(self.per_million, Self::MILLION) {
+      return false;
+    }
+    let Some(&version_ihl) = packet.first() else {
+      return false;
+    };
+    let header_len = usize::from(version_ihl & 0x0f) * 4;
+    if header_len < 20 || packet.len() < header_len + 20 {
+      return false;
+    }
+    let src = u32::from_be_bytes([packet[12], packet[13], packet[14], packet[15]]);
+    let dst = u32::from_be_bytes([packet[16], packet[17], packet[18], packet[19]]);
+    let segment = &mut packet[header_len..];
+    let bit = 1 << rng.below(8);
+    match self.stage {
+      CorruptStage::AfterChecksum => {
+        let at = rng.below(segment.len() as u64) as usize;
+        segment[at] ^= bit;
+      }
+      CorruptStage::BeforeChecksum => {
+        // Any byte but the checksum's own
+        let mut at = rng.below(segment.len() as u64 - 2) as usize;
+        if at >= CHECKSUM_AT {
+          at += 2;
+        }
+        segment[at] ^= bit;
+        segment[CHECKSUM_AT..CHECKSUM_AT + 2].fill(0);
+        let checksum = TcpHeader::segment_checksum(src, dst, segment);
+        segment[CHECKSUM_AT..CHECKSUM_AT + 2].copy_from_slice(&checksum.to_be_bytes());
+      }
+    }
+    true
+  }
+}
+
+/// Offset of the checksum in a TCP header
+const CHECKSUM_AT: usize = 16;
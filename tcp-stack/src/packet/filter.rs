@@ -0,0 +1,265 @@
+//! tcpdump-style display filters
+//!
+//! A `PacketFilter` is a small subset of pcap-filter(7): `host`, `port`
+//! and the TCP flag tests `tcp-syn`, `tcp-ack`, `tcp-fin`, `tcp-rst`,
+//! `tcp-push` and `tcp-urg`, each optionally qualified by `src` or `dst`
+//! where that makes sense, combined with `and`, `or`, `not` (or `&&`, `||`,
+//! `!`) and parentheses. `not` binds tightest, then `and`, then `or`. An
+//! empty filter matches every packet.
+//!
+//! ```text
+//! host 10.0.0.1 and port 8080
+//! src port 443 and (tcp-syn or tcp-rst)
+//! not dst host 192.0.2.1
+//! ```
+
+use super::{Ipv4Header, TcpFlags};
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+/// Why a filter expression could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterError {
+  /// The expression stopped where more was expected
+  UnexpectedEnd,
+  /// A word that does not fit where it stands
+  Unexpected(String),
+  /// `host` followed by something other than an IPv4 address
+  BadAddress(String),
+  /// `port` followed by something other than a port number
+  BadPort(String),
+}
+
+impl fmt::Display for FilterError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      FilterError::UnexpectedEnd => write!(f, "filter ends unexpectedly"),
+      FilterError::Unexpected(word) => write!(f, "unexpected {word:?} in filter"),
+      FilterError::BadAddress(word) => write!(f, "{word:?} is not an IPv4 address"),
+      FilterError::BadPort(word) => write!(f, "{word:?} is not a port"),
+    }
+  }
+}
+
+/// Which end of a packet a primitive looks at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+  Either,
+  Src,
+  Dst,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+  Any,
+  Host(Direction, Ipv4Addr),
+  Port(Direction, u16),
+  /// Any of these `TcpFlags` bits set
+  Flags(u8),
+  Not(Box<Expr>),
+  And(Box<Expr>, Box<Expr>),
+  Or(Box<Expr>, Box<Expr>),
+}
+
+/// The fields of a packet a filter can test
+struct Fields {
+  src: Ipv4Addr,
+  dst: Ipv4Addr,
+  /// Source and destination port and flags, for TCP
+  tcp: Option<(u16, u16, TcpFlags)>,
+}
+
+impl Expr {
+  fn matches(&self, fields: &Fields) -> bool {
+    match self {
+      Expr::Any => true,
+      Expr::Host(direction, addr) => match direction {
+        Direction::Either => fields.src == *addr || fields.dst == *addr,
+        Direction::Src => fields.src == *addr,
+        Direction::Dst => fields.dst == *addr,
+      },
+      Expr::Port(direction, port) => fields.tcp.is_some_and(|(src, dst, _)| match direction {
+        Direction::Either => src == *port || dst == *port,
+        Direction::Src => src == *port,
+        Direction::Dst => dst == *port,
+      }),
+      Expr::Flags(bits) => fields.tcp.is_some_and(|(_, _, flags)| flags.0 & bits != 0),
+      Expr::Not(inner) => !inner.matches(fields),
+      Expr::And(left, right) => left.matches(fields) && right.matches(fields),
+      Expr::Or(left, right) => left.matches(fields) || right.matches(fields),
+    }
+  }
+}
+
+/// A parsed display filter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketFilter {
+  expr: Expr,
+  source: String,
+}
+
+impl PacketFilter {
+  /// Parse `expression`
+  pub fn parse(expression: &str) -> Result<Self, FilterError> {
+    let spaced = expression.replace('(', " ( ").replace(')', " ) ");
+    let words: Vec<&str> = spaced.split_whitespace().collect();
+    let mut parser = Parser { words, pos: 0 };
+    let expr = if parser.words.is_empty() {
+      Expr::Any
+    } else {
+      parser.or()?
+    };
+    if let Some(word) = parser.peek() {
+      return Err(FilterError::Unexpected(word.to_string()));
+    }
+    Ok(Self {
+      expr,
+      source: expression.trim().to_string(),
+    })
+  }
+
+  /// Whether the IPv4 packet `packet` passes. Port and flag tests fail
+  /// for anything but TCP, and every test fails for what is not IPv4
+  pub fn matches(&self, packet: &[u8]) -> bool {
+    let Some((ip, segment)) = Ipv4Header::parse(packet) else {
+      return false;
+    };
+    let tcp = (ip.protocol == Ipv4Header::PROTOCOL_TCP && segment.len() >= 14).then(|| {
+      (
+        u16::from_be_bytes([segment[0], segment[1]]),
+        u16::from_be_bytes([segment[2], segment[3]]),
+        TcpFlags(segment[13]),
+      )
+    });
+    self.expr.matches(&Fields {
+      src: ip.src_addr,
+      dst: ip.dst_addr,
+      tcp,
+    })
+  }
+
+  /// Whether a TCP segment from `src` to `dst` carrying `flags` passes
+  pub fn matches_segment(&self, src: SocketAddrV4, dst: SocketAddrV4, flags: TcpFlags) -> bool {
+    self.expr.matches(&Fields {
+      src: *src.ip(),
+      dst: *dst.ip(),
+      tcp: Some((src.port(), dst.port(), flags)),
+    })
+  }
+}
+
+/// The expression as written
+impl fmt::Display for PacketFilter {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.source)
+  }
+}
+
+impl core::str::FromStr for PacketFilter {
+  type Err = FilterError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Self::parse(s)
+  }
+}
+
+/// Recursive descent over the words of an expression
+struct Parser<'a> {
+  words: Vec<&'a str>,
+  pos: usize,
+}
+
+impl<'a> Parser<'a> {
+  fn peek(&self) -> Option<&'a str> {
+    self.words.get(self.pos).copied()
+  }
+
+  fn next(&mut self) -> Result<&'a str, FilterError> {
+    let word = self.peek().ok_or(FilterError::UnexpectedEnd)?;
+    self.pos += 1;
+    Ok(word)
+  }
+
+  /// Take the next word if it is one of `options`
+  fn eat(&mut self, options: &[&str]) -> bool {
+    let found = self.peek().is_some_and(|word| options.contains(&word));
+    if found {
+      self.pos += 1;
+    }
+    found
+  }
+
+  fn or(&mut self) -> Result<Expr, FilterError> {
+    let mut expr = self.and()?;
+    while self.eat(&["or", "||"]) {
+      expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+    }
+    Ok(expr)
+  }
+
+  fn and(&mut self) -> Result<Expr, FilterError> {
+    let mut expr = self.unary()?;
+    while self.eat(&["and", "&&"]) {
+      expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+    }
+    Ok(expr)
+  }
+
+  fn unary(&mut self) -> Result<Expr, FilterError> {
+    if self.eat(&["not", "!"]) {
+      return Ok(Expr::Not(Box::new(self.unary()?)));
+    }
+    if self.eat(&["("]) {
+      let expr = self.or()?;
+      let close = self.next()?;
+      if close != ")" {
+        return Err(FilterError::Unexpected(close.to_string()));
+      }
+      return Ok(expr);
+    }
+    self.primitive()
+  }
+
+  fn primitive(&mut self) -> Result<Expr, FilterError> {
+    let direction = if self.eat(&["src"]) {
+      Direction::Src
+    } else if self.eat(&["dst"]) {
+      Direction::Dst
+    } else {
+      Direction::Either
+    };
+    let word = self.next()?;
+    match word {
+      "host" => {
+        let value = self.next()?;
+        let addr = value
+          .parse()
+          .map_err(|_| FilterError::BadAddress(value.to_string()))?;
+        Ok(Expr::Host(direction, addr))
+      }
+      "port" => {
+        let value = self.next()?;
+        let port = value
+          .parse()
+          .map_err(|_| FilterError::BadPort(value.to_string()))?;
+        Ok(Expr::Port(direction, port))
+      }
+      _ if direction == Direction::Either => {
+        let bits = match word {
+          "tcp-fin" => TcpFlags::FIN,
+          "tcp-syn" => TcpFlags::SYN,
+          "tcp-rst" => TcpFlags::RST,
+          "tcp-push" => TcpFlags::PSH,
+          "tcp-ack" => TcpFlags::ACK,
+          "tcp-urg" => TcpFlags::URG,
+          _ => return Err(FilterError::Unexpected(word.to_string())),
+        };
+        Ok(Expr::Flags(bits))
+      }
+      _ => Err(FilterError::Unexpected(word.to_string())),
+    }
+  }
+}
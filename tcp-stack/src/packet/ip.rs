@@ -1,11 +1,13 @@
 //! IPv4 header structure
 
+use super::IpOption;
 use crate::utils::calculate_checksum;
-use byteorder::{BigEndian, WriteBytesExt};
-use std::net::Ipv4Addr;
+use alloc::vec::Vec;
+use core::net::Ipv4Addr;
 
 /// IPv4 header (20 bytes minimum)
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Ipv4Header {
   pub version: u8,
   pub ihl: u8,
@@ -25,9 +27,29 @@ pub struct Ipv4Header {
 
 impl Ipv4Header {
   pub const MIN_SIZE: usize = 20;
+  /// Room for options after the fixed header
+  pub const MAX_OPTIONS_LEN: usize = 40;
   pub const VERSION: u8 = 4;
+  pub const PROTOCOL_ICMP: u8 = 1;
   pub const PROTOCOL_TCP: u8 = 6;
 
+  /// Common DSCP codepoints (RFC 4594)
+  pub const DSCP_DEFAULT: u8 = 0;
+  pub const DSCP_CS1: u8 = 8;
+  pub const DSCP_AF11: u8 = 10;
+  pub const DSCP_AF21: u8 = 18;
+  pub const DSCP_AF31: u8 = 26;
+  pub const DSCP_AF41: u8 = 34;
+  pub const DSCP_CS5: u8 = 40;
+  pub const DSCP_EF: u8 = 46;
+  pub const DSCP_CS6: u8 = 48;
+
+  /// ECN codepoints (RFC 3168)
+  pub const ECN_NOT_ECT: u8 = 0b00;
+  pub const ECN_ECT1: u8 = 0b01;
+  pub const ECN_ECT0: u8 = 0b10;
+  pub const ECN_CE: u8 = 0b11;
+
   pub fn new(src_addr: Ipv4Addr, dst_addr: Ipv4Addr, payload_len: usize) -> Self {
     Self {
       version: Self::VERSION,
@@ -51,23 +73,52 @@ impl Ipv4Header {
     (self.ihl as usize) * 4
   }
 
+  /// Carry `options`, padded to whole words, with IHL and the total length
+  /// adjusted and the payload length kept. Returns false, leaving the header
+  /// alone, if they do not fit
+  pub fn set_options(&mut self, options: &[IpOption]) -> bool {
+    match IpOption::serialize_list(options) {
+      Some(bytes) => self.set_option_bytes(&bytes),
+      None => false,
+    }
+  }
+
+  /// `set_options` for already encoded options
+  pub fn set_option_bytes(&mut self, options: &[u8]) -> bool {
+    let padded = options.len().next_multiple_of(4);
+    if padded > Self::MAX_OPTIONS_LEN {
+      return false;
+    }
+    let payload_len = (self.total_length as usize).saturating_sub(self.header_len());
+    self.options = options.to_vec();
+    self.options.resize(padded, IpOption::KIND_END);
+    self.ihl = ((Self::MIN_SIZE + padded) / 4) as u8;
+    self.total_length = (self.header_len() + payload_len) as u16;
+    true
+  }
+
+  /// The options decoded, or `None` if they are malformed
+  pub fn parsed_options(&self) -> Option<Vec<IpOption>> {
+    IpOption::parse_list(&self.options)
+  }
+
   pub fn serialize(&self) -> Vec<u8> {
     let mut buf = Vec::with_capacity(self.header_len());
 
     let version_ihl = (self.version << 4) | self.ihl;
-    let dscp_ecn = (self.dscp << 2) | (self.ecn & 0x03);
+    let dscp_ecn = ((self.dscp & 0x3F) << 2) | (self.ecn & 0x03);
 
-    buf.write_u8(version_ihl).unwrap();
-    buf.write_u8(dscp_ecn).unwrap();
-    buf.write_u16::<BigEndian>(self.total_length).unwrap();
-    buf.write_u16::<BigEndian>(self.identification).unwrap();
+    buf.push(version_ihl);
+    buf.push(dscp_ecn);
+    buf.extend_from_slice(&self.total_length.to_be_bytes());
+    buf.extend_from_slice(&self.identification.to_be_bytes());
 
     let flags_frag = ((self.flags as u16) << 13) | (self.fragment_offset & 0x1FFF);
-    buf.write_u16::<BigEndian>(flags_frag).unwrap();
+    buf.extend_from_slice(&flags_frag.to_be_bytes());
 
-    buf.write_u8(self.ttl).unwrap();
-    buf.write_u8(self.protocol).unwrap();
-    buf.write_u16::<BigEndian>(0).unwrap();
+    buf.push(self.ttl);
+    buf.push(self.protocol);
+    buf.extend_from_slice(&0u16.to_be_bytes());
 
     buf.extend_from_slice(&self.src_addr.octets());
     buf.extend_from_slice(&self.dst_addr.octets());
@@ -97,7 +148,7 @@ impl Ipv4Header {
     let ihl = data[0] & 0x0F;
     let header_len = (ihl as usize) * 4;
 
-    if data.len() < header_len {
+    if header_len < Self::MIN_SIZE || data.len() < header_len {
       return None;
     }
 
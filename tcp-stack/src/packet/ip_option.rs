@@ -0,0 +1,303 @@
+//! IPv4 options (RFC 791)
+//!
+//! Typed forms of the options worth sending for path diagnostics, Record
+//! Route and Timestamp, plus whatever else turns up in received headers.
+//! `Ipv4Header::set_options` encodes a list, pads it to whole words and
+//! fixes up IHL and the total length. `IpOptionsPolicy` decides what
+//! happens to received packets that carry options.
+
+use super::Ipv4Header;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::net::Ipv4Addr;
+
+/// What each entry of a Timestamp option holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TimestampFlag {
+  /// Timestamps only
+  TimestampsOnly,
+  /// Each router's address ahead of its timestamp
+  WithAddresses,
+  /// Timestamps from the listed routers only
+  Prespecified,
+}
+
+impl TimestampFlag {
+  fn bits(self) -> u8 {
+    match self {
+      TimestampFlag::TimestampsOnly => 0,
+      TimestampFlag::WithAddresses => 1,
+      TimestampFlag::Prespecified => 3,
+    }
+  }
+
+  fn from_bits(bits: u8) -> Option<Self> {
+    match bits {
+      0 => Some(TimestampFlag::TimestampsOnly),
+      1 => Some(TimestampFlag::WithAddresses),
+      3 => Some(TimestampFlag::Prespecified),
+      _ => None,
+    }
+  }
+
+  fn has_addresses(self) -> bool {
+    self != TimestampFlag::TimestampsOnly
+  }
+}
+
+/// IPv4 option
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IpOption {
+  EndOfList,
+  NoOperation,
+  /// Routers append their address at `pointer`, the 1-based offset of the
+  /// next free slot in the option
+  RecordRoute {
+    pointer: u8,
+    route: Vec<Ipv4Addr>,
+  },
+  /// Routers fill the entry at `pointer`; `overflow` counts those that
+  /// found no room. Entries without addresses carry `None`
+  Timestamp {
+    pointer: u8,
+    overflow: u8,
+    flag: TimestampFlag,
+    entries: Vec<(Option<Ipv4Addr>, u32)>,
+  },
+  /// Any other option, by its type byte
+  Unknown {
+    kind: u8,
+    data: Vec<u8>,
+  },
+}
+
+impl IpOption {
+  pub const KIND_END: u8 = 0;
+  pub const KIND_NOP: u8 = 1;
+  pub const KIND_RECORD_ROUTE: u8 = 7;
+  pub const KIND_TIMESTAMP: u8 = 68;
+  /// Loose and strict source routing
+  pub const KIND_LSRR: u8 = 131;
+  pub const KIND_SSRR: u8 = 137;
+
+  /// Record Route with room for `slots` addresses, at most 9
+  pub fn record_route(slots: usize) -> Self {
+    IpOption::RecordRoute {
+      pointer: 4,
+      route: vec![Ipv4Addr::UNSPECIFIED; slots.min(9)],
+    }
+  }
+
+  /// Timestamp option with room for `slots` entries: up to 9 timestamps,
+  /// or 4 with addresses
+  pub fn timestamps(flag: TimestampFlag, slots: usize) -> Self {
+    let max = if flag.has_addresses() { 4 } else { 9 };
+    let address = flag.has_addresses().then_some(Ipv4Addr::UNSPECIFIED);
+    IpOption::Timestamp {
+      pointer: 5,
+      overflow: 0,
+      flag,
+      entries: vec![(address, 0); slots.min(max)],
+    }
+  }
+
+  pub fn kind(&self) -> u8 {
+    match self {
+      IpOption::EndOfList => Self::KIND_END,
+      IpOption::NoOperation => Self::KIND_NOP,
+      IpOption::RecordRoute { .. } => Self::KIND_RECORD_ROUTE,
+      IpOption::Timestamp { .. } => Self::KIND_TIMESTAMP,
+      IpOption::Unknown { kind, .. } => *kind,
+    }
+  }
+
+  /// Whether the option asks routers to send the packet along a route of
+  /// the sender's choosing
+  pub fn is_source_route(&self) -> bool {
+    matches!(self.kind(), Self::KIND_LSRR | Self::KIND_SSRR)
+  }
+
+  /// Addresses recorded so far by Record Route
+  pub fn recorded_route(&self) -> &[Ipv4Addr] {
+    match self {
+      IpOption::RecordRoute { pointer, route } => {
+        let filled = usize::from(pointer.saturating_sub(4)) / 4;
+        &route[..filled.min(route.len())]
+      }
+      _ => &[],
+    }
+  }
+
+  pub fn serialize(&self) -> Vec<u8> {
+    match self {
+      IpOption::EndOfList => vec![Self::KIND_END],
+      IpOption::NoOperation => vec![Self::KIND_NOP],
+      IpOption::RecordRoute { pointer, route } => {
+        let mut buf = vec![
+          Self::KIND_RECORD_ROUTE,
+          (3 + 4 * route.len()) as u8,
+          *pointer,
+        ];
+        for addr in route {
+          buf.extend_from_slice(&addr.octets());
+        }
+        buf
+      }
+      IpOption::Timestamp {
+        pointer,
+        overflow,
+        flag,
+        entries,
+      } => {
+        let entry_len = if flag.has_addresses() { 8 } else { 4 };
+        let len = 4 + entry_len * entries.len();
+        let mut buf = vec![
+          Self::KIND_TIMESTAMP,
+          len as u8,
+          *pointer,
+          (overflow << 4) | flag.bits(),
+        ];
+        for (addr, timestamp) in entries {
+          if flag.has_addresses() {
+            buf.extend_from_slice(&addr.unwrap_or(Ipv4Addr::UNSPECIFIED).octets());
+          }
+          buf.extend_from_slice(&timestamp.to_be_bytes());
+        }
+        buf
+      }
+      IpOption::Unknown { kind, data } => {
+        let mut buf = vec![*kind, (2 + data.len()) as u8];
+        buf.extend_from_slice(data);
+        buf
+      }
+    }
+  }
+
+  /// Encode `options` padded with end-of-list bytes to whole words, or
+  /// `None` if they do not fit an IPv4 header
+  pub fn serialize_list(options: &[IpOption]) -> Option<Vec<u8>> {
+    let mut buf: Vec<u8> = options.iter().flat_map(IpOption::serialize).collect();
+    buf.resize(buf.len().next_multiple_of(4), Self::KIND_END);
+    (buf.len() <= Ipv4Header::MAX_OPTIONS_LEN).then_some(buf)
+  }
+
+  /// Parse the options area of a header, or `None` if an option runs past
+  /// its end or has an impossible length
+  pub fn parse_list(mut data: &[u8]) -> Option<Vec<IpOption>> {
+    let mut options = Vec::new();
+    while let Some(&kind) = data.first() {
+      match kind {
+        Self::KIND_END => {
+          options.push(IpOption::EndOfList);
+          break;
+        }
+        Self::KIND_NOP => {
+          options.push(IpOption::NoOperation);
+          data = &data[1..];
+          continue;
+        }
+        _ => {}
+      }
+      let len = usize::from(*data.get(1)?);
+      if len < 2 || len > data.len() {
+        return None;
+      }
+      options.push(Self::parse_one(kind, &data[2..len]));
+      data = &data[len..];
+    }
+    Some(options)
+  }
+
+  /// An option of `kind` with `body` following its length byte. Forms the
+  /// typed variants cannot hold are kept as `Unknown`
+  fn parse_one(kind: u8, body: &[u8]) -> IpOption {
+    let unknown = || IpOption::Unknown {
+      kind,
+      data: body.to_vec(),
+    };
+    match kind {
+      Self::KIND_RECORD_ROUTE if !body.is_empty() && (body.len() - 1) % 4 == 0 => {
+        IpOption::RecordRoute {
+          pointer: body[0],
+          route: body[1..].chunks_exact(4).map(ipv4).collect(),
+        }
+      }
+      Self::KIND_TIMESTAMP if body.len() >= 2 => {
+        let Some(flag) = TimestampFlag::from_bits(body[1] & 0x0F) else {
+          return unknown();
+        };
+        let entry_len = if flag.has_addresses() { 8 } else { 4 };
+        let entries = &body[2..];
+        if entries.len() % entry_len != 0 {
+          return unknown();
+        }
+        let entries = entries
+          .chunks_exact(entry_len)
+          .map(|entry| {
+            let (addr, timestamp) = entry.split_at(entry_len - 4);
+            let addr = flag.has_addresses().then(|| ipv4(addr));
+            (
+              addr,
+              u32::from_be_bytes([
+                timestamp[0],
+                timestamp[1],
+                timestamp[2],
+                timestamp[3],
+              ]),
+            )
+          })
+          .collect();
+        IpOption::Timestamp {
+          pointer: body[0],
+          overflow: body[1] >> 4,
+          flag,
+          entries,
+        }
+      }
+      _ => unknown(),
+    }
+  }
+}
+
+/// What to do with received packets carrying IP options. The stack never
+/// acts on them, so stripping amounts to processing the segment as if they
+/// were absent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IpOptionsPolicy {
+  /// Process packets whatever their options
+  #[default]
+  Accept,
+  /// Process packets without their options, counting those that had some
+  Strip,
+  /// Drop packets with source routing options, or options that do not parse
+  DropSourceRoute,
+  /// Drop every packet carrying options
+  DropAll,
+}
+
+impl IpOptionsPolicy {
+  /// Whether a packet carrying `options` may be processed
+  pub fn admits(self, options: &[u8]) -> bool {
+    if options.is_empty() {
+      return true;
+    }
+    match self {
+      IpOptionsPolicy::Accept | IpOptionsPolicy::Strip => true,
+      IpOptionsPolicy::DropSourceRoute => IpOption::parse_list(options)
+        .is_some_and(|options| !options.iter().any(IpOption::is_source_route)),
+      IpOptionsPolicy::DropAll => false,
+    }
+  }
+
+  /// Whether an admitted packet carrying `options` has them stripped
+  pub fn strips(self, options: &[u8]) -> bool {
+    self == IpOptionsPolicy::Strip && !options.is_empty()
+  }
+}
+
+fn ipv4(octets: &[u8]) -> Ipv4Addr {
+  Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])
+}
@@ -1,7 +1,15 @@
 //! TCP and IP packet structures
 
+pub mod corrupt;
+pub mod filter;
 pub mod ip;
+pub mod ip_option;
+pub mod mptcp;
 pub mod tcp;
 
+pub use corrupt::{CorruptStage, Corruption};
+pub use filter::{FilterError, PacketFilter};
 pub use ip::Ipv4Header;
-pub use tcp::{TcpFlags, TcpHeader, TcpOption};
+pub use ip_option::{IpOption, IpOptionsPolicy, TimestampFlag};
+pub use mptcp::{Dss, DssMapping, MpCapable, MptcpOption};
+pub use tcp::{OptionPadding, TcpFlags, TcpHeader, TcpOption};
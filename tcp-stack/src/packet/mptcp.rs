@@ -0,0 +1,251 @@
+//! Multipath TCP options (RFC 8684)
+//!
+//! MPTCP signals everything in TCP option kind 30, with a subtype in the
+//! high nibble of its third byte. MP_CAPABLE and DSS are decoded; other
+//! subtypes keep their bytes so they re-encode as received. The stack does
+//! not speak MPTCP: it never echoes MP_CAPABLE, so a peer falls back to
+//! plain TCP, but what the peer sent is there to look at.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// An MPTCP option
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MptcpOption {
+  MpCapable(MpCapable),
+  Dss(Dss),
+  /// A subtype not decoded, with the low nibble of the subtype byte and
+  /// the bytes after it
+  Other { subtype: u8, low: u8, data: Vec<u8> },
+}
+
+/// MP_CAPABLE: the handshake's offer of MPTCP and its keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MpCapable {
+  pub version: u8,
+  /// Flags A to H, with `CHECKSUM` and `HMAC_SHA256` the ones defined
+  pub flags: u8,
+  /// Key of the option's sender; absent on a version 1 SYN
+  pub sender_key: Option<u64>,
+  /// Key of the receiver, echoed on the third ACK
+  pub receiver_key: Option<u64>,
+  /// Data-level length of the payload of the first data segment
+  pub data_len: Option<u16>,
+  pub checksum: Option<u16>,
+}
+
+impl MpCapable {
+  pub const SUBTYPE: u8 = 0;
+  /// A: DSS checksums are required
+  pub const CHECKSUM: u8 = 0x80;
+  /// H: keys are used with HMAC-SHA256
+  pub const HMAC_SHA256: u8 = 0x01;
+}
+
+/// DSS: the data sequence mapping and data-level ACK of a segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Dss {
+  /// The F, m, M, a and A flags. M and A follow `mapping` and `data_ack`
+  /// when encoding; m and a pick 8-octet sequence numbers
+  pub flags: u8,
+  pub data_ack: Option<u64>,
+  pub mapping: Option<DssMapping>,
+}
+
+/// Where a segment's payload sits in the data sequence space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DssMapping {
+  /// Data sequence number of the first byte mapped
+  pub dsn: u64,
+  /// Its subflow sequence number, relative to the subflow's ISN
+  pub subflow_seq: u32,
+  pub data_len: u16,
+  pub checksum: Option<u16>,
+}
+
+impl Dss {
+  pub const SUBTYPE: u8 = 2;
+  /// F: the mapping ends with the DATA_FIN
+  pub const DATA_FIN: u8 = 0x10;
+  /// m: the data sequence number is 8 octets
+  pub const DSN_64: u8 = 0x08;
+  /// M: a mapping is present
+  pub const MAPPING: u8 = 0x04;
+  /// a: the data ACK is 8 octets
+  pub const ACK_64: u8 = 0x02;
+  /// A: a data ACK is present
+  pub const DATA_ACK: u8 = 0x01;
+
+  pub fn data_fin(&self) -> bool {
+    self.flags & Self::DATA_FIN != 0
+  }
+}
+
+impl MptcpOption {
+  pub const KIND: u8 = 30;
+
+  pub fn subtype(&self) -> u8 {
+    match self {
+      MptcpOption::MpCapable(_) => MpCapable::SUBTYPE,
+      MptcpOption::Dss(_) => Dss::SUBTYPE,
+      MptcpOption::Other { subtype, .. } => *subtype,
+    }
+  }
+
+  /// Decode one option, `data` holding exactly its bytes from the kind on
+  pub fn parse(data: &[u8]) -> Option<Self> {
+    if data.len() < 3 || data[0] != Self::KIND || data[1] as usize != data.len() {
+      return None;
+    }
+    let subtype = data[2] >> 4;
+    let low = data[2] & 0x0f;
+    match subtype {
+      MpCapable::SUBTYPE => parse_mp_capable(low, &data[3..]).map(MptcpOption::MpCapable),
+      Dss::SUBTYPE => parse_dss(&data[3..]).map(MptcpOption::Dss),
+      _ => Some(MptcpOption::Other {
+        subtype,
+        low,
+        data: data[3..].to_vec(),
+      }),
+    }
+  }
+
+  pub fn serialize(&self) -> Vec<u8> {
+    let mut buf = vec![Self::KIND, 0];
+    match self {
+      MptcpOption::MpCapable(capable) => {
+        buf.push(MpCapable::SUBTYPE << 4 | capable.version & 0x0f);
+        buf.push(capable.flags);
+        let fields = [capable.sender_key, capable.receiver_key];
+        for key in fields.into_iter().flatten() {
+          buf.extend_from_slice(&key.to_be_bytes());
+        }
+        if let Some(len) = capable.data_len {
+          buf.extend_from_slice(&len.to_be_bytes());
+          if let Some(checksum) = capable.checksum {
+            buf.extend_from_slice(&checksum.to_be_bytes());
+          }
+        }
+      }
+      MptcpOption::Dss(dss) => {
+        let mut flags = dss.flags & !(Dss::MAPPING | Dss::DATA_ACK);
+        if dss.data_ack.is_some() {
+          flags |= Dss::DATA_ACK;
+        }
+        if dss.mapping.is_some() {
+          flags |= Dss::MAPPING;
+        }
+        buf.extend([Dss::SUBTYPE << 4, flags]);
+        if let Some(ack) = dss.data_ack {
+          put_seq(&mut buf, ack, flags & Dss::ACK_64 != 0);
+        }
+        if let Some(mapping) = &dss.mapping {
+          put_seq(&mut buf, mapping.dsn, flags & Dss::DSN_64 != 0);
+          buf.extend_from_slice(&mapping.subflow_seq.to_be_bytes());
+          buf.extend_from_slice(&mapping.data_len.to_be_bytes());
+          if let Some(checksum) = mapping.checksum {
+            buf.extend_from_slice(&checksum.to_be_bytes());
+          }
+        }
+      }
+      MptcpOption::Other { subtype, low, data } => {
+        buf.push(subtype << 4 | low & 0x0f);
+        buf.extend_from_slice(data);
+      }
+    }
+    buf[1] = buf.len() as u8;
+    buf
+  }
+}
+
+/// MP_CAPABLE after the subtype byte, whose low nibble is the version. Its
+/// length says which fields follow the flags
+fn parse_mp_capable(version: u8, data: &[u8]) -> Option<MpCapable> {
+  let (&flags, rest) = data.split_first()?;
+  let mut capable = MpCapable {
+    version,
+    flags,
+    sender_key: None,
+    receiver_key: None,
+    data_len: None,
+    checksum: None,
+  };
+  match rest.len() {
+    0 => {}
+    8 => capable.sender_key = Some(u64::from_be_bytes(rest.try_into().ok()?)),
+    16 | 18 | 20 => {
+      capable.sender_key = Some(u64::from_be_bytes(rest[..8].try_into().ok()?));
+      capable.receiver_key = Some(u64::from_be_bytes(rest[8..16].try_into().ok()?));
+      if rest.len() >= 18 {
+        capable.data_len = Some(u16::from_be_bytes([rest[16], rest[17]]));
+      }
+      if rest.len() == 20 {
+        capable.checksum = Some(u16::from_be_bytes([rest[18], rest[19]]));
+      }
+    }
+    _ => return None,
+  }
+  Some(capable)
+}
+
+/// DSS after the subtype byte: the flags, then the fields they name
+fn parse_dss(data: &[u8]) -> Option<Dss> {
+  let (&flags, mut rest) = data.split_first()?;
+  let mut dss = Dss {
+    flags,
+    data_ack: None,
+    mapping: None,
+  };
+  if flags & Dss::DATA_ACK != 0 {
+    dss.data_ack = Some(take_seq(&mut rest, flags & Dss::ACK_64 != 0)?);
+  }
+  if flags & Dss::MAPPING != 0 {
+    let dsn = take_seq(&mut rest, flags & Dss::DSN_64 != 0)?;
+    if rest.len() < 6 {
+      return None;
+    }
+    let subflow_seq = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]);
+    let data_len = u16::from_be_bytes([rest[4], rest[5]]);
+    rest = &rest[6..];
+    let checksum = match rest.len() {
+      0 => None,
+      2 => Some(u16::from_be_bytes([rest[0], rest[1]])),
+      _ => return None,
+    };
+    rest = &[];
+    dss.mapping = Some(DssMapping {
+      dsn,
+      subflow_seq,
+      data_len,
+      checksum,
+    });
+  }
+  rest.is_empty().then_some(dss)
+}
+
+/// A 4- or 8-octet sequence number off the front of `data`
+fn take_seq(data: &mut &[u8], wide: bool) -> Option<u64> {
+  let len = if wide { 8 } else { 4 };
+  if data.len() < len {
+    return None;
+  }
+  let (seq, rest) = data.split_at(len);
+  *data = rest;
+  Some(if wide {
+    u64::from_be_bytes(seq.try_into().ok()?)
+  } else {
+    u64::from(u32::from_be_bytes(seq.try_into().ok()?))
+  })
+}
+
+fn put_seq(buf: &mut Vec<u8>, seq: u64, wide: bool) {
+  if wide {
+    buf.extend_from_slice(&seq.to_be_bytes());
+  } else {
+    buf.extend_from_slice(&(seq as u32).to_be_bytes());
+  }
+}
@@ -1,11 +1,13 @@
 //! TCP header structure and options
 
-use crate::utils::calculate_checksum;
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::io::Cursor;
+use super::mptcp::MptcpOption;
+use crate::utils::{calculate_checksum, fnv1a, FNV_OFFSET};
+use alloc::vec;
+use alloc::vec::Vec;
 
 /// TCP flags
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TcpFlags(pub u8);
 
 impl TcpFlags {
@@ -76,6 +78,7 @@ impl Default for TcpFlags {
 
 /// TCP Options
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TcpOption {
   EndOfList,
   NoOperation,
@@ -84,6 +87,8 @@ pub enum TcpOption {
   SackPermitted,
   Sack { left: u32, right: u32 },
   Timestamp { ts_val: u32, ts_ecr: u32 },
+  /// Multipath TCP, kind 30
+  Mptcp(MptcpOption),
 }
 
 impl TcpOption {
@@ -94,6 +99,25 @@ impl TcpOption {
   pub const KIND_SACK_PERMITTED: u8 = 4;
   pub const KIND_SACK: u8 = 5;
   pub const KIND_TIMESTAMP: u8 = 8;
+  pub const KIND_MPTCP: u8 = MptcpOption::KIND;
+
+  pub fn kind(&self) -> u8 {
+    match self {
+      TcpOption::EndOfList => Self::KIND_END,
+      TcpOption::NoOperation => Self::KIND_NOP,
+      TcpOption::MaximumSegmentSize(_) => Self::KIND_MSS,
+      TcpOption::WindowScale(_) => Self::KIND_WINDOW_SCALE,
+      TcpOption::SackPermitted => Self::KIND_SACK_PERMITTED,
+      TcpOption::Sack { .. } => Self::KIND_SACK,
+      TcpOption::Timestamp { .. } => Self::KIND_TIMESTAMP,
+      TcpOption::Mptcp(_) => Self::KIND_MPTCP,
+    }
+  }
+
+  /// Whether the option only pads the option list
+  pub fn is_padding(&self) -> bool {
+    matches!(self, TcpOption::EndOfList | TcpOption::NoOperation)
+  }
 
   pub fn serialize(&self) -> Vec<u8> {
     match self {
@@ -120,9 +144,31 @@ impl TcpOption {
         buf.extend_from_slice(&ts_ecr.to_be_bytes());
         buf
       }
+      TcpOption::Mptcp(option) => option.serialize(),
     }
   }
 
+  /// Parse a SACK option carrying one or more blocks into one `Sack` per block
+  pub fn parse_sack_blocks(data: &[u8]) -> Option<(Vec<Self>, usize)> {
+    if data.len() < 2 || data[0] != Self::KIND_SACK {
+      return None;
+    }
+    let len = data[1] as usize;
+    let count = len.saturating_sub(2) / 8;
+    if count == 0 || 2 + count * 8 != len || data.len() < len {
+      return None;
+    }
+
+    let blocks = data[2..len]
+      .chunks_exact(8)
+      .map(|block| TcpOption::Sack {
+        left: u32::from_be_bytes([block[0], block[1], block[2], block[3]]),
+        right: u32::from_be_bytes([block[4], block[5], block[6], block[7]]),
+      })
+      .collect();
+    Some((blocks, len))
+  }
+
   pub fn parse(data: &[u8]) -> Option<(Self, usize)> {
     if data.is_empty() {
       return None;
@@ -155,6 +201,10 @@ impl TcpOption {
         }
         Some((TcpOption::SackPermitted, 2))
       }
+      Self::KIND_SACK => {
+        let (mut blocks, len) = Self::parse_sack_blocks(data)?;
+        Some((blocks.remove(0), len))
+      }
       Self::KIND_TIMESTAMP => {
         if data.len() < 10 {
           return None;
@@ -163,6 +213,14 @@ impl TcpOption {
         let ts_ecr = u32::from_be_bytes([data[6], data[7], data[8], data[9]]);
         Some((TcpOption::Timestamp { ts_val, ts_ecr }, 10))
       }
+      // A malformed MPTCP option is skipped like an unknown one
+      Self::KIND_MPTCP if data.len() >= 2 && (2..=data.len()).contains(&(data[1] as usize)) => {
+        let len = data[1] as usize;
+        match MptcpOption::parse(&data[..len]) {
+          Some(option) => Some((TcpOption::Mptcp(option), len)),
+          None => Some((TcpOption::NoOperation, len)),
+        }
+      }
       _ => {
         if data.len() < 2 {
           return None;
@@ -177,23 +235,41 @@ impl TcpOption {
   }
 }
 
+/// How `TcpHeader::serialize` lays out and pads options
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OptionPadding {
+  /// Align with NOPs as Linux does: 32-bit option fields on 32-bit
+  /// boundaries, and no trailing padding. NOPs in the option list are
+  /// dropped and placed anew. Some middleboxes reject other layouts
+  #[default]
+  Nop,
+  /// Options exactly as listed, NOPs included, terminated with EOL bytes
+  /// up to the 32-bit boundary. Parsed headers use this so they re-encode
+  /// as received
+  EndOfList,
+}
+
 /// TCP Header
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TcpHeader {
   pub src_port: u16,
   pub dst_port: u16,
   pub seq_num: u32,
   pub ack_num: u32,
-  pub data_offset: u8,
   pub flags: TcpFlags,
   pub window_size: u16,
   pub checksum: u16,
   pub urgent_pointer: u16,
   pub options: Vec<TcpOption>,
+  pub padding: OptionPadding,
 }
 
 impl TcpHeader {
   pub const MIN_SIZE: usize = 20;
+  /// Most option bytes a header can carry, as `data_offset` has four bits
+  pub const MAX_OPTIONS_LEN: usize = 40;
 
   pub fn new(src_port: u16, dst_port: u16) -> Self {
     Self {
@@ -201,12 +277,12 @@ impl TcpHeader {
       dst_port,
       seq_num: 0,
       ack_num: 0,
-      data_offset: 5,
       flags: TcpFlags::new(),
       window_size: 65535,
       checksum: 0,
       urgent_pointer: 0,
       options: Vec::new(),
+      padding: OptionPadding::default(),
     }
   }
 
@@ -223,7 +299,6 @@ impl TcpHeader {
       },
       TcpOption::WindowScale(7),
     ];
-    header.data_offset = ((TcpHeader::MIN_SIZE + 12) / 4) as u8;
     header
   }
 
@@ -240,8 +315,179 @@ impl TcpHeader {
     header
   }
 
+  pub fn set_options(&mut self, options: Vec<TcpOption>) {
+    self.options = options;
+  }
+
+  /// Header bytes `options` take once laid out by `padding`
+  pub fn padded_options_len(options: &[TcpOption], padding: OptionPadding) -> usize {
+    Self::serialize_options(options, padding).len()
+  }
+
+  /// Encode options laid out by `padding`, padded to 32 bits. Options
+  /// past `MAX_OPTIONS_LEN` are left out, trimming SACK blocks first
+  fn serialize_options(options: &[TcpOption], padding: OptionPadding) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let encoded = Self::encode_options(options);
+    match padding {
+      OptionPadding::EndOfList => {
+        for option in &encoded {
+          let Some(fitted) = Self::fit_option(option, Self::MAX_OPTIONS_LEN - buf.len())
+          else {
+            break;
+          };
+          let trimmed = fitted.len() < option.len();
+          buf.extend(fitted);
+          if trimmed {
+            break;
+          }
+        }
+        buf.resize(buf.len().div_ceil(4) * 4, TcpOption::KIND_END);
+      }
+      OptionPadding::Nop => {
+        // Options with 32-bit fields start two bytes past a boundary, after
+        // their kind and length; the rest end on one, unless the option
+        // after them starts two bytes past it
+        let word_aligned = |option: &[u8]| {
+          matches!(option[0], TcpOption::KIND_SACK | TcpOption::KIND_TIMESTAMP)
+        };
+        let encoded: Vec<_> = encoded
+          .iter()
+          .filter(|option| {
+            !matches!(option[0], TcpOption::KIND_END | TcpOption::KIND_NOP)
+          })
+          .collect();
+        for (i, option) in encoded.iter().enumerate() {
+          let next_word_aligned =
+            encoded.get(i + 1).is_some_and(|next| word_aligned(next));
+          let aligned = |start: usize| {
+            if word_aligned(option) {
+              start % 4 == 2
+            } else {
+              let end = (start + option.len()) % 4;
+              end == 0 || (end == 2 && next_word_aligned)
+            }
+          };
+          let mut start = buf.len();
+          while !aligned(start) {
+            start += 1;
+          }
+          let Some(fitted) =
+            Self::fit_option(option, Self::MAX_OPTIONS_LEN.saturating_sub(start))
+          else {
+            break;
+          };
+          let trimmed = fitted.len() < option.len();
+          buf.resize(start, TcpOption::KIND_NOP);
+          buf.extend(fitted);
+          if trimmed {
+            break;
+          }
+        }
+        buf.resize(buf.len().div_ceil(4) * 4, TcpOption::KIND_NOP);
+      }
+    }
+    buf
+  }
+
+  /// An encoded option if it fits in `room` bytes, or as many of its SACK
+  /// blocks as do
+  fn fit_option(option: &[u8], room: usize) -> Option<Vec<u8>> {
+    if option.len() <= room {
+      return Some(option.to_vec());
+    }
+    if option[0] != TcpOption::KIND_SACK || room < 10 {
+      return None;
+    }
+    let len = 2 + (room - 2) / 8 * 8;
+    let mut trimmed = option[..len].to_vec();
+    trimmed[1] = len as u8;
+    Some(trimmed)
+  }
+
+  /// Encode each option, merging consecutive `Sack` blocks into one option
+  fn encode_options(options: &[TcpOption]) -> Vec<Vec<u8>> {
+    let mut encoded = Vec::new();
+    let mut i = 0;
+    while i < options.len() {
+      let blocks: Vec<_> = options[i..]
+        .iter()
+        .map_while(|option| match option {
+          TcpOption::Sack { left, right } => Some((*left, *right)),
+          _ => None,
+        })
+        .collect();
+      if blocks.is_empty() {
+        encoded.push(options[i].serialize());
+        i += 1;
+        continue;
+      }
+
+      let mut buf = vec![TcpOption::KIND_SACK];
+      buf.push((2 + blocks.len() * 8) as u8);
+      for (left, right) in &blocks {
+        buf.extend_from_slice(&left.to_be_bytes());
+        buf.extend_from_slice(&right.to_be_bytes());
+      }
+      encoded.push(buf);
+      i += blocks.len();
+    }
+    encoded
+  }
+
+  /// The options without padding, ordered by kind. SACK blocks keep their
+  /// order, which says which is most recent. Unknown options parse as
+  /// padding, so they are dropped too
+  pub fn canonical_options(&self) -> Vec<TcpOption> {
+    let mut options: Vec<_> = self
+      .options
+      .iter()
+      .filter(|option| !option.is_padding())
+      .cloned()
+      .collect();
+    options.sort_by_key(TcpOption::kind);
+    options
+  }
+
+  /// Put the header in canonical form: canonical options in the NOP
+  /// layout and a zero checksum
+  pub fn normalize(&mut self) {
+    self.options = self.canonical_options();
+    self.padding = OptionPadding::Nop;
+    self.checksum = 0;
+  }
+
+  pub fn normalized(&self) -> Self {
+    let mut header = self.clone();
+    header.normalize();
+    header
+  }
+
+  /// Whether two headers mean the same on the wire: equal but for the
+  /// checksum and option order and padding
+  pub fn wire_eq(&self, other: &Self) -> bool {
+    self.normalized() == other.normalized()
+  }
+
+  /// Whether two encoded segments are semantically identical: headers
+  /// `wire_eq` and payloads equal. Byte-exact comparison fails on
+  /// legitimate differences in NOP padding
+  pub fn segments_equivalent(a: &[u8], b: &[u8]) -> bool {
+    match (Self::parse(a), Self::parse(b)) {
+      (Some((a, a_payload)), Some((b, b_payload))) => {
+        a_payload == b_payload && a.wire_eq(&b)
+      }
+      _ => false,
+    }
+  }
+
+  /// Header length in 32-bit words, from the options and their padding
+  pub fn data_offset(&self) -> u8 {
+    (self.header_len() / 4) as u8
+  }
+
   pub fn header_len(&self) -> usize {
-    (self.data_offset as usize) * 4
+    Self::MIN_SIZE + Self::padded_options_len(&self.options, self.padding)
   }
 
   pub fn options_len(&self) -> usize {
@@ -249,28 +495,23 @@ impl TcpHeader {
   }
 
   pub fn serialize(&self) -> Vec<u8> {
-    let mut buf = Vec::with_capacity(self.header_len());
+    let options = Self::serialize_options(&self.options, self.padding);
+    let data_offset = ((Self::MIN_SIZE + options.len()) / 4) as u16;
+    let mut buf = Vec::with_capacity(Self::MIN_SIZE + options.len());
 
-    buf.write_u16::<BigEndian>(self.src_port).unwrap();
-    buf.write_u16::<BigEndian>(self.dst_port).unwrap();
-    buf.write_u32::<BigEndian>(self.seq_num).unwrap();
-    buf.write_u32::<BigEndian>(self.ack_num).unwrap();
+    buf.extend_from_slice(&self.src_port.to_be_bytes());
+    buf.extend_from_slice(&self.dst_port.to_be_bytes());
+    buf.extend_from_slice(&self.seq_num.to_be_bytes());
+    buf.extend_from_slice(&self.ack_num.to_be_bytes());
 
-    let data_offset_flags = ((self.data_offset as u16) << 4) | (self.flags.0 as u16);
-    buf.write_u16::<BigEndian>(data_offset_flags).unwrap();
+    let data_offset_flags = (data_offset << 12) | (self.flags.0 as u16);
+    buf.extend_from_slice(&data_offset_flags.to_be_bytes());
 
-    buf.write_u16::<BigEndian>(self.window_size).unwrap();
-    buf.write_u16::<BigEndian>(0).unwrap();
-    buf.write_u16::<BigEndian>(self.urgent_pointer).unwrap();
-
-    for option in &self.options {
-      buf.extend(option.serialize());
-    }
-
-    while buf.len() < self.header_len() {
-      buf.push(0);
-    }
+    buf.extend_from_slice(&self.window_size.to_be_bytes());
+    buf.extend_from_slice(&0u16.to_be_bytes());
+    buf.extend_from_slice(&self.urgent_pointer.to_be_bytes());
 
+    buf.extend(options);
     buf
   }
 
@@ -279,22 +520,21 @@ impl TcpHeader {
       return None;
     }
 
-    let mut cursor = Cursor::new(data);
-    let src_port = cursor.read_u16::<BigEndian>().ok()?;
-    let dst_port = cursor.read_u16::<BigEndian>().ok()?;
-    let seq_num = cursor.read_u32::<BigEndian>().ok()?;
-    let ack_num = cursor.read_u32::<BigEndian>().ok()?;
-    let data_offset_flags = cursor.read_u16::<BigEndian>().ok()?;
+    let src_port = u16::from_be_bytes([data[0], data[1]]);
+    let dst_port = u16::from_be_bytes([data[2], data[3]]);
+    let seq_num = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    let ack_num = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+    let data_offset_flags = u16::from_be_bytes([data[12], data[13]]);
 
-    let data_offset = ((data_offset_flags >> 4) & 0x0F) as u8;
-    let flags = (data_offset_flags & 0x3F) as u8;
+    let data_offset = ((data_offset_flags >> 12) & 0x0F) as u8;
+    let flags = (data_offset_flags & 0xFF) as u8;
 
-    let window_size = cursor.read_u16::<BigEndian>().ok()?;
-    let checksum = cursor.read_u16::<BigEndian>().ok()?;
-    let urgent_pointer = cursor.read_u16::<BigEndian>().ok()?;
+    let window_size = u16::from_be_bytes([data[14], data[15]]);
+    let checksum = u16::from_be_bytes([data[16], data[17]]);
+    let urgent_pointer = u16::from_be_bytes([data[18], data[19]]);
 
     let header_len = (data_offset as usize) * 4;
-    if data.len() < header_len {
+    if header_len < Self::MIN_SIZE || data.len() < header_len {
       return None;
     }
 
@@ -303,6 +543,17 @@ impl TcpHeader {
     let mut offset = 0;
 
     while offset < options_data.len() {
+      if options_data[offset] == TcpOption::KIND_SACK {
+        match TcpOption::parse_sack_blocks(&options_data[offset..]) {
+          Some((blocks, len)) => {
+            options.extend(blocks);
+            offset += len;
+            continue;
+          }
+          None => break,
+        }
+      }
+
       if let Some((option, len)) = TcpOption::parse(&options_data[offset..]) {
         if let TcpOption::EndOfList = option {
           break;
@@ -321,23 +572,42 @@ impl TcpHeader {
       dst_port,
       seq_num,
       ack_num,
-      data_offset,
       flags: TcpFlags(flags),
       window_size,
       checksum,
       urgent_pointer,
       options,
+      padding: OptionPadding::EndOfList,
     };
 
     Some((header, payload))
   }
 
-  pub fn calculate_checksum(
-    &self,
-    src_addr: u32,
-    dst_addr: u32,
-    payload: &[u8],
-  ) -> u16 {
+  /// Check the checksum of a received `segment` (header and payload)
+  pub fn verify_checksum(src_addr: u32, dst_addr: u32, segment: &[u8]) -> bool {
+    Self::segment_checksum(src_addr, dst_addr, segment) == 0
+  }
+
+  /// Checksum over the pseudo-header and `segment` as it stands: zero if
+  /// its checksum field is right, the value for the field if it is zeroed
+  pub fn segment_checksum(src_addr: u32, dst_addr: u32, segment: &[u8]) -> u16 {
+    let mut total = Vec::with_capacity(12 + segment.len());
+    total.extend_from_slice(&src_addr.to_be_bytes());
+    total.extend_from_slice(&dst_addr.to_be_bytes());
+    total.push(0);
+    total.push(6);
+    total.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    total.extend_from_slice(segment);
+    calculate_checksum(&total)
+  }
+
+  /// FNV-1a (64-bit) hash of a segment as sent on the wire, header with
+  /// checksum plus payload, for matching logged segments against a capture
+  pub fn digest(segment: &[u8]) -> u64 {
+    fnv1a(FNV_OFFSET, segment.iter().copied())
+  }
+
+  pub fn calculate_checksum(&self, src_addr: u32, dst_addr: u32, payload: &[u8]) -> u16 {
     let header_bytes = self.serialize();
 
     let mut pseudo_header = Vec::with_capacity(12);
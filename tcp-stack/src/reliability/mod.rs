@@ -1,7 +1,9 @@
 //! Reliability mechanisms: retransmission, reordering
 
-pub mod retransmit;
 pub mod reorder;
+pub mod retransmit;
+pub mod stream;
 
-pub use retransmit::RetransmissionManager;
 pub use reorder::ReorderBuffer;
+pub use retransmit::{RetransmissionManager, RetryLimits, SegmentKind};
+pub use stream::ReceiveStream;
@@ -1,13 +1,34 @@
 //! Out-of-order packet reassembly
-
+//!
+//! Held segments are capped in bytes and in number. The count matters on
+//! its own: thousands of one-byte segments, each behind its own hole, cost
+//! little against the byte cap but bloat the map and every SACK scan. At
+//! the count cap the segment furthest past the next expected byte is
+//! evicted, so the data filling the oldest hole, which is what lets
+//! delivery resume, is the last to go.
+
+use crate::memory::{self, MemoryPool};
 use crate::utils::SeqNumber;
-use std::collections::BTreeMap;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use bytes::Bytes;
+
+/// Default cap on bytes held out of order
+pub const DEFAULT_REORDER_BYTES: usize = 1024 * 1024;
+
+/// Default cap on distinct segments held out of order
+pub const DEFAULT_REORDER_SEGMENTS: usize = 256;
 
 /// Buffer for reassembling out-of-order segments
 pub struct ReorderBuffer {
-  segments: BTreeMap<u32, Vec<u8>>,
+  segments: BTreeMap<u32, Bytes>,
   next_expected: SeqNumber,
   max_buffer_size: usize,
+  max_segments: usize,
+  /// Bytes of out-of-order segments, charged to `MemoryPool::Reorder`
+  held: usize,
+  /// Segments evicted to make room at the count cap
+  evicted: u64,
 }
 
 impl ReorderBuffer {
@@ -15,11 +36,18 @@ impl ReorderBuffer {
     Self {
       segments: BTreeMap::new(),
       next_expected: SeqNumber(0),
-      max_buffer_size: 1024 * 1024,
+      max_buffer_size: DEFAULT_REORDER_BYTES,
+      max_segments: DEFAULT_REORDER_SEGMENTS,
+      held: 0,
+      evicted: 0,
     }
   }
 
-  pub fn add(&mut self, seq: SeqNumber, data: Vec<u8>) -> Vec<(SeqNumber, Vec<u8>)> {
+  /// Take a segment, returning those that became contiguous. Out-of-order
+  /// data is refused once memory is under pressure, past the byte cap, or
+  /// at the count cap when nothing held lies further out
+  pub fn add(&mut self, seq: SeqNumber, data: impl Into<Bytes>) -> Vec<(SeqNumber, Bytes)> {
+    let data = data.into();
     let mut ready = Vec::new();
 
     let seq_val = seq.0;
@@ -27,15 +55,25 @@ impl ReorderBuffer {
       return ready;
     }
 
-    if self.segments.len() * 1460 >= self.max_buffer_size {
-      return ready;
+    let in_order = seq == self.next_expected;
+    if !in_order {
+      if self.held + data.len() > self.max_buffer_size || !self.make_room(seq) {
+        return ready;
+      }
+      if !memory::try_charge(MemoryPool::Reorder, data.len()) {
+        return ready;
+      }
+      self.held += data.len();
     }
-
     self.segments.insert(seq_val, data);
 
     while let Some(data) = self.segments.remove(&self.next_expected.0) {
       let data_len = data.len() as u32;
       let seg_seq = self.next_expected;
+      if !(in_order && seg_seq == seq) {
+        memory::release(MemoryPool::Reorder, data.len());
+        self.held -= data.len();
+      }
       ready.push((seg_seq, data));
       self.next_expected = self.next_expected + data_len;
     }
@@ -43,6 +81,32 @@ impl ReorderBuffer {
     ready
   }
 
+  /// Get below the count cap for a segment at `seq`, evicting the held
+  /// segment furthest past the next expected byte. Returns false if that
+  /// would be the new segment itself
+  fn make_room(&mut self, seq: SeqNumber) -> bool {
+    while self.segments.len() >= self.max_segments {
+      let next = self.next_expected;
+      let furthest = self
+        .segments
+        .keys()
+        .copied()
+        .max_by_key(|&start| SeqNumber(start).diff(next));
+      let Some(furthest) = furthest else {
+        // A cap of zero holds nothing out of order
+        return false;
+      };
+      if seq.diff(next) >= SeqNumber(furthest).diff(next) {
+        return false;
+      }
+      let data = self.segments.remove(&furthest).expect("key just found");
+      memory::release(MemoryPool::Reorder, data.len());
+      self.held -= data.len();
+      self.evicted += 1;
+    }
+    true
+  }
+
   fn is_duplicate(&self, seq: SeqNumber, _data: &[u8]) -> bool {
     if seq.before(self.next_expected) {
       return true;
@@ -67,8 +131,56 @@ impl ReorderBuffer {
     self.next_expected
   }
 
+  /// Contiguous ranges held beyond the next expected byte, as
+  /// `(left, right)` edges for SACK blocks
+  pub fn sack_blocks(&self) -> Vec<(SeqNumber, SeqNumber)> {
+    let mut blocks: Vec<(SeqNumber, SeqNumber)> = Vec::new();
+    for (&start, data) in &self.segments {
+      let left = SeqNumber(start);
+      let right = left + data.len() as u32;
+      match blocks.last_mut() {
+        Some(last) if last.1 == left => last.1 = right,
+        _ => blocks.push((left, right)),
+      }
+    }
+    blocks
+  }
+
+  /// Bytes held out of order
+  pub fn held_bytes(&self) -> usize {
+    self.held
+  }
+
+  /// Distinct segments held out of order
+  pub fn held_segments(&self) -> usize {
+    self.segments.len()
+  }
+
+  pub fn max_segments(&self) -> usize {
+    self.max_segments
+  }
+
+  /// Cap the segments held out of order; a lower cap than is held takes
+  /// effect as the next segments arrive
+  pub fn set_max_segments(&mut self, max: usize) {
+    self.max_segments = max;
+  }
+
+  /// Segments evicted to make room at the count cap
+  pub fn evicted(&self) -> u64 {
+    self.evicted
+  }
+
   pub fn clear(&mut self) {
     self.segments.clear();
+    memory::release(MemoryPool::Reorder, self.held);
+    self.held = 0;
+  }
+}
+
+impl Drop for ReorderBuffer {
+  fn drop(&mut self) {
+    memory::release(MemoryPool::Reorder, self.held);
   }
 }
 
@@ -1,111 +1,360 @@
 //! Retransmission management
+//!
+//! SYN, SYN-ACK and FIN are held like data, each tagged with its
+//! `SegmentKind`. Every kind has its own retry limit in `RetryLimits`, and
+//! while only control segments are outstanding the timer backs off on their
+//! retransmission count rather than the RTT estimator's.
+//!
+//! Pending segments are kept in sequence order, which is the order they are
+//! sent in, so a cumulative ACK pops the prefix it covers and never looks
+//! at the rest; lookups by sequence number binary search from SND.UNA.
+//! What is due for resending is handed out as `SegmentRef`s, which share
+//! the held payloads, so a timeout that resends the whole window allocates
+//! one list rather than a copy of every segment.
 
 use crate::connection::timer::Timer;
-use crate::utils::SeqNumber;
-use std::collections::HashMap;
-use std::time::Duration;
+use crate::memory::{self, MemoryPool};
+use crate::utils::{Instant, SeqNumber};
+use alloc::collections::{BTreeSet, VecDeque};
+use alloc::vec::Vec;
+use bytes::Bytes;
+use core::time::Duration;
 
-/// Segment awaiting acknowledgment
+/// Longest interval the timer backs off to, in seconds
+const MAX_INTERVAL: f64 = 60.0;
+
+/// What a pending segment carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SegmentKind {
+  #[default]
+  Data,
+  /// Our SYN of an active open
+  Syn,
+  /// Our SYN-ACK of a passive or simultaneous open
+  SynAck,
+  Fin,
+}
+
+impl SegmentKind {
+  /// A SYN, SYN-ACK or FIN rather than payload
+  pub fn is_control(self) -> bool {
+    self != SegmentKind::Data
+  }
+}
+
+/// Retransmissions of each kind of segment before giving up, as Linux's
+/// `tcp_syn_retries`, `tcp_synack_retries`, `tcp_orphan_retries` and
+/// `tcp_retries2`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RetryLimits {
+  pub syn: u32,
+  pub syn_ack: u32,
+  pub fin: u32,
+  pub data: u32,
+}
+
+impl RetryLimits {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn limit(&self, kind: SegmentKind) -> u32 {
+    match kind {
+      SegmentKind::Data => self.data,
+      SegmentKind::Syn => self.syn,
+      SegmentKind::SynAck => self.syn_ack,
+      SegmentKind::Fin => self.fin,
+    }
+  }
+}
+
+impl Default for RetryLimits {
+  fn default() -> Self {
+    Self {
+      syn: 6,
+      syn_ack: 5,
+      fin: 8,
+      data: 15,
+    }
+  }
+}
+
+/// Segment awaiting acknowledgment. `data` shares the buffer the segment
+/// was first sent from, so holding and resending it copies no payload
 #[derive(Debug, Clone)]
 pub struct PendingSegment {
   pub seq: SeqNumber,
   pub len: u32,
-  pub data: Vec<u8>,
+  pub data: Bytes,
   pub retransmit_count: u32,
-  pub first_sent: std::time::Instant,
+  pub first_sent: Instant,
+  pub kind: SegmentKind,
+}
+
+/// A pending segment due to be resent: where it lies, what it carries and
+/// a handle on its payload. Making one copies nothing, and it leaves the
+/// segment's timing and retransmission count behind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentRef {
+  pub seq: SeqNumber,
+  pub len: u32,
+  pub kind: SegmentKind,
+  pub data: Bytes,
+}
+
+impl From<&PendingSegment> for SegmentRef {
+  fn from(segment: &PendingSegment) -> Self {
+    Self {
+      seq: segment.seq,
+      len: segment.len,
+      kind: segment.kind,
+      data: segment.data.clone(),
+    }
+  }
 }
 
 /// Retransmission manager
 pub struct RetransmissionManager {
-  pending: HashMap<u32, PendingSegment>,
+  /// Segments awaiting acknowledgment, in sequence order from SND.UNA
+  pending: VecDeque<PendingSegment>,
   timer: Timer,
-  max_retries: u32,
+  limits: RetryLimits,
+  /// Kind of a segment that used up its retransmissions, until taken
+  exhausted: Option<SegmentKind>,
+  /// Payload bytes held for retransmission, charged to
+  /// `MemoryPool::Retransmit`
+  buffered: usize,
+  /// Sequence space of the pending segments
+  in_flight: u32,
+  /// Pending segments the peer reported holding through SACK
+  sacked: BTreeSet<u32>,
+  sacked_bytes: u32,
 }
 
 impl RetransmissionManager {
   pub fn new() -> Self {
     Self {
-      pending: HashMap::new(),
+      pending: VecDeque::new(),
       timer: Timer::new(),
-      max_retries: 15,
+      limits: RetryLimits::default(),
+      exhausted: None,
+      buffered: 0,
+      in_flight: 0,
+      sacked: BTreeSet::new(),
+      sacked_bytes: 0,
     }
   }
 
-  pub fn add_segment(&mut self, segment: PendingSegment, rto: f64) {
-    let key = segment.seq.0;
-    self.pending.insert(key, segment);
+  /// Hold `segment` until it is acknowledged. New data goes behind what is
+  /// pending at once; a segment at a pending sequence number replaces it
+  pub fn add_segment(&mut self, segment: PendingSegment, rto: f64, now: Instant) {
+    self.buffered += segment.data.len();
+    memory::charge(MemoryPool::Retransmit, segment.data.len());
+    self.in_flight += segment.len;
+    let at = self.position(segment.seq);
+    if at == self.pending.len() {
+      self.pending.push_back(segment);
+    } else if self.pending[at].seq == segment.seq {
+      let key = segment.seq.0;
+      let old = core::mem::replace(&mut self.pending[at], segment);
+      self.buffered -= old.data.len();
+      memory::release(MemoryPool::Retransmit, old.data.len());
+      self.in_flight -= old.len;
+      if self.sacked.remove(&key) {
+        self.sacked_bytes -= old.len;
+      }
+    } else {
+      self.pending.insert(at, segment);
+    }
 
     if self.pending.len() == 1 {
-      self.timer.start(Duration::from_secs_f64(rto));
+      self.timer.start(now, Duration::from_secs_f64(rto));
     }
   }
 
-  pub fn acknowledge(&mut self, ack: SeqNumber) -> Vec<PendingSegment> {
+  /// Drop the segments `ack` covers and return them. Costs the number of
+  /// segments acknowledged, however many are pending
+  pub fn acknowledge(&mut self, ack: SeqNumber, now: Instant) -> Vec<PendingSegment> {
     let mut acknowledged = Vec::new();
 
-    let ack_val = ack.0;
-    let keys_to_remove: Vec<u32> = self
-      .pending
-      .iter()
-      .filter(|(_, seg)| {
-        let seg_end = seg.seq.0.wrapping_add(seg.len);
-        ack_val >= seg_end
-      })
-      .map(|(k, _)| *k)
-      .collect();
-
-    for key in keys_to_remove {
-      if let Some(seg) = self.pending.remove(&key) {
-        acknowledged.push(seg);
+    // Every segment the ACK covers goes, however many: stretch ACKs and
+    // ranges that wrap the sequence space alike
+    while let Some(seg) = self.pending.front() {
+      if (seg.seq + seg.len).after(ack) {
+        break;
       }
+      let seg = self.pending.pop_front().expect("front exists");
+      self.buffered -= seg.data.len();
+      memory::release(MemoryPool::Retransmit, seg.data.len());
+      self.in_flight -= seg.len;
+      if self.sacked.remove(&seg.seq.0) {
+        self.sacked_bytes -= seg.len;
+      }
+      acknowledged.push(seg);
     }
 
-    if !self.pending.is_empty() {
-      let min_rto = self
-        .pending
-        .values()
-        .map(|s| {
-          let elapsed = s.first_sent.elapsed().as_secs_f64();
-          (1.0 + elapsed * 2.0).min(60.0)
-        })
-        .fold(f64::MAX, f64::min);
-      self.timer.start(Duration::from_secs_f64(min_rto));
-    } else {
-      self.timer.cancel();
+    // The last segment sent is the most recent, so it waited least
+    match self.pending.back() {
+      Some(last) => {
+        let elapsed = (now - last.first_sent).as_secs_f64();
+        let interval = (1.0 + elapsed * 2.0).min(MAX_INTERVAL);
+        self.timer.start(now, Duration::from_secs_f64(interval));
+      }
+      None => self.timer.cancel(),
     }
 
     acknowledged
   }
 
-  pub fn should_retransmit(&self) -> bool {
-    self.timer.is_expired() && !self.pending.is_empty()
+  /// Segments awaiting acknowledgment, in sequence order
+  pub fn pending(&self) -> impl Iterator<Item = &PendingSegment> {
+    self.pending.iter()
+  }
+
+  /// Index of the first pending segment that does not start before `seq`.
+  /// Pending data spans less than half the sequence space, so comparisons
+  /// hold across a wrap
+  fn position(&self, seq: SeqNumber) -> usize {
+    self.pending.partition_point(|seg| seg.seq.before(seq))
   }
 
-  pub fn get_retransmit_segments(&mut self, rto: f64) -> Vec<PendingSegment> {
-    if !self.should_retransmit() {
+  /// When the retransmission timer fires, if it is running
+  pub fn deadline(&self) -> Option<Instant> {
+    self.timer.deadline()
+  }
+
+  pub fn should_retransmit(&self, now: Instant) -> bool {
+    self.timer.is_expired(now) && !self.pending.is_empty()
+  }
+
+  /// The segments to resend now that the timer has expired, counting a
+  /// retransmission against each, and the timer restarted
+  pub fn get_retransmit_segments(&mut self, rto: f64, now: Instant) -> Vec<SegmentRef> {
+    if !self.should_retransmit(now) {
       return Vec::new();
     }
 
     let mut segments = Vec::new();
-    for (_, seg) in self.pending.iter_mut() {
-      seg.retransmit_count += 1;
-      if seg.retransmit_count <= self.max_retries {
-        segments.push(seg.clone());
+    for seg in self.pending.iter_mut() {
+      if seg.retransmit_count >= self.limits.limit(seg.kind) {
+        self.exhausted.get_or_insert(seg.kind);
+        continue;
       }
+      seg.retransmit_count += 1;
+      segments.push(SegmentRef::from(&*seg));
     }
 
-    self.timer.start(Duration::from_secs_f64(rto * 2.0));
+    let interval = if self.only_control() {
+      let count = self
+        .pending
+        .iter()
+        .map(|seg| seg.retransmit_count)
+        .min()
+        .unwrap_or(0);
+      (rto * f64::from(1u32 << count.min(16))).min(MAX_INTERVAL)
+    } else {
+      rto
+    };
+    self.timer.start(now, Duration::from_secs_f64(interval));
     segments
   }
 
+  /// Whether everything pending is a SYN, SYN-ACK or FIN, which back off
+  /// on their own retransmission count
+  pub fn only_control(&self) -> bool {
+    !self.pending.is_empty() && self.pending.iter().all(|seg| seg.kind.is_control())
+  }
+
+  pub fn limits(&self) -> RetryLimits {
+    self.limits
+  }
+
+  pub fn set_limits(&mut self, limits: RetryLimits) {
+    self.limits = limits;
+  }
+
+  /// The kind of a segment that timed out with no retransmissions left,
+  /// once
+  pub fn take_exhausted(&mut self) -> Option<SegmentKind> {
+    self.exhausted.take()
+  }
+
+  /// Retag the pending segment at `seq`, as when a simultaneous open turns
+  /// our SYN into a SYN-ACK
+  pub fn set_kind(&mut self, seq: SeqNumber, kind: SegmentKind) {
+    let at = self.position(seq);
+    if let Some(seg) = self.pending.get_mut(at).filter(|seg| seg.seq == seq) {
+      seg.kind = kind;
+    }
+  }
+
+  /// Record `count` retransmissions of the pending segment at `seq` made
+  /// before it was held here, so it is not timed for an RTT sample
+  pub fn set_retransmit_count(&mut self, seq: SeqNumber, count: u32) {
+    let at = self.position(seq);
+    if let Some(seg) = self.pending.get_mut(at).filter(|seg| seg.seq == seq) {
+      seg.retransmit_count = count;
+    }
+  }
+
+  /// The pending segment starting at `seq`
+  pub fn segment_at(&self, seq: SeqNumber) -> Option<&PendingSegment> {
+    let seg = self.pending.get(self.position(seq))?;
+    (seg.seq == seq).then_some(seg)
+  }
+
   pub fn clear(&mut self) {
     self.pending.clear();
     self.timer.cancel();
+    self.exhausted = None;
+    memory::release(MemoryPool::Retransmit, self.buffered);
+    self.buffered = 0;
+    self.in_flight = 0;
+    self.sacked.clear();
+    self.sacked_bytes = 0;
+  }
+
+  /// Mark pending segments lying wholly within a SACK block as delivered,
+  /// returning the bytes newly marked
+  pub fn on_sack(&mut self, left: SeqNumber, right: SeqNumber) -> u32 {
+    let mut newly = 0;
+    for seg in self.pending.range(self.position(left)..) {
+      if (seg.seq + seg.len).after(right) {
+        break;
+      }
+      if self.sacked.insert(seg.seq.0) {
+        newly += seg.len;
+      }
+    }
+    self.sacked_bytes += newly;
+    newly
+  }
+
+  /// Sequence space the peer has SACKed but not cumulatively acknowledged
+  pub fn sacked_bytes(&self) -> u32 {
+    self.sacked_bytes
   }
 
   pub fn pending_count(&self) -> usize {
     self.pending.len()
   }
+
+  /// Payload bytes held in memory for retransmission
+  pub fn buffered_bytes(&self) -> usize {
+    self.buffered
+  }
+
+  /// Sequence space sent but not yet acknowledged, SYN and FIN included
+  pub fn in_flight(&self) -> u32 {
+    self.in_flight
+  }
+}
+
+impl Drop for RetransmissionManager {
+  fn drop(&mut self) {
+    memory::release(MemoryPool::Retransmit, self.buffered);
+  }
 }
 
 impl Default for RetransmissionManager {
@@ -0,0 +1,194 @@
+//! In-order byte stream on top of segment reassembly
+//!
+//! `ReceiveStream` accepts segments in any order, hands them to a
+//! `ReorderBuffer`, and appends whatever becomes contiguous to a read buffer.
+//! RCV.NXT advances as bytes arrive in order, and the receive window is the
+//! buffer space the application has not yet filled.
+//!
+//! Payloads are kept as the `Bytes` they arrived in, from reassembly to the
+//! read buffer, so `read_bytes` hands the application the very buffers the
+//! segments were copied into once on arrival.
+
+use super::ReorderBuffer;
+use crate::memory::{self, MemoryPool};
+use crate::utils::SeqNumber;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use bytes::{Buf, Bytes};
+
+/// Default receive buffer size, the largest unscaled window
+pub const DEFAULT_RECV_CAPACITY: usize = 65535;
+
+/// Receive-side byte stream with a read cursor
+pub struct ReceiveStream {
+  reorder: ReorderBuffer,
+  /// Chunks ready for the application, none of them empty
+  readable: VecDeque<Bytes>,
+  /// Bytes in `readable`
+  readable_len: usize,
+  capacity: usize,
+}
+
+impl ReceiveStream {
+  pub fn new() -> Self {
+    Self::with_capacity(DEFAULT_RECV_CAPACITY)
+  }
+
+  pub fn with_capacity(capacity: usize) -> Self {
+    Self {
+      reorder: ReorderBuffer::new(),
+      readable: VecDeque::new(),
+      readable_len: 0,
+      capacity,
+    }
+  }
+
+  /// Start the stream at `seq`, normally IRS + 1
+  pub fn set_rcv_nxt(&mut self, seq: SeqNumber) {
+    self.reorder.set_next_expected(seq);
+  }
+
+  /// Next sequence number expected from the peer
+  pub fn rcv_nxt(&self) -> SeqNumber {
+    self.reorder.next_expected()
+  }
+
+  /// Accept a segment's payload, returning how many bytes became readable.
+  /// Bytes already received are trimmed, so retransmissions that overlap
+  /// RCV.NXT still deliver their new tail. In-order data is refused at the
+  /// memory hard limit
+  pub fn push(&mut self, seq: SeqNumber, data: impl Into<Bytes>) -> usize {
+    let mut data = data.into();
+    let rcv_nxt = self.rcv_nxt();
+    if seq.before(rcv_nxt) {
+      let seen = rcv_nxt.diff(seq) as usize;
+      if seen >= data.len() {
+        return 0;
+      }
+      data.advance(seen);
+      return self.push(rcv_nxt, data);
+    }
+
+    // Charge in-order data up front, so it is refused rather than dropped
+    // after delivery; segments it releases from reordering move pools
+    let len = data.len();
+    let mut charged = seq == rcv_nxt && len > 0;
+    if charged && !memory::try_charge(MemoryPool::Receive, len) {
+      return 0;
+    }
+    let mut delivered = 0;
+    for (seg_seq, bytes) in self.reorder.add(seq, data) {
+      if charged && seg_seq == seq {
+        charged = false;
+      } else {
+        memory::charge(MemoryPool::Receive, bytes.len());
+      }
+      delivered += bytes.len();
+      if !bytes.is_empty() {
+        self.readable.push_back(bytes);
+      }
+    }
+    self.readable_len += delivered;
+    if charged {
+      memory::release(MemoryPool::Receive, len);
+    }
+    delivered
+  }
+
+  /// Bytes of a segment at `seq` carrying `len` bytes that already
+  /// arrived, whether in order or held out of order
+  pub fn duplicate_bytes(&self, seq: SeqNumber, len: u32) -> u32 {
+    let end = seq + len;
+    let rcv_nxt = self.rcv_nxt();
+    let mut duplicate = if seq.before(rcv_nxt) {
+      rcv_nxt.diff(seq).min(len)
+    } else {
+      0
+    };
+    for (left, right) in self.reorder.sack_blocks() {
+      let start = if left.after(seq) { left } else { seq };
+      let stop = if right.before(end) { right } else { end };
+      if stop.after(start) {
+        duplicate += stop.diff(start);
+      }
+    }
+    duplicate
+  }
+
+  /// Copy readable bytes into `buf`, returning the count copied
+  pub fn read(&mut self, buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    while len < buf.len() {
+      let Some(chunk) = self.readable.front_mut() else {
+        break;
+      };
+      let n = chunk.len().min(buf.len() - len);
+      buf[len..len + n].copy_from_slice(&chunk[..n]);
+      chunk.advance(n);
+      if chunk.is_empty() {
+        self.readable.pop_front();
+      }
+      len += n;
+    }
+    self.readable_len -= len;
+    memory::release(MemoryPool::Receive, len);
+    len
+  }
+
+  /// Take every readable byte, as the chunks it arrived in, without
+  /// copying
+  pub fn read_bytes(&mut self) -> Vec<Bytes> {
+    memory::release(MemoryPool::Receive, self.readable_len);
+    self.readable_len = 0;
+    self.readable.drain(..).collect()
+  }
+
+  /// Bytes ready for the application
+  pub fn readable(&self) -> usize {
+    self.readable_len
+  }
+
+  /// Copy of the bytes ready for the application, without reading them
+  pub fn unread(&self) -> Vec<u8> {
+    self.readable.iter().flatten().copied().collect()
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  /// Resize the receive buffer; bytes already readable are kept even if
+  /// they exceed the new capacity, closing the window until read
+  pub fn set_capacity(&mut self, capacity: usize) {
+    self.capacity = capacity;
+  }
+
+  /// Receive window to advertise: buffer space not taken by unread bytes,
+  /// shrunk to the memory headroom under pressure
+  pub fn window(&self) -> u32 {
+    let free = self.capacity.saturating_sub(self.readable_len);
+    free.min(memory::window_cap()) as u32
+  }
+
+  /// Out-of-order segments held beyond RCV.NXT
+  pub fn reorder(&self) -> &ReorderBuffer {
+    &self.reorder
+  }
+
+  /// Cap the distinct segments held out of order
+  pub fn set_max_reorder_segments(&mut self, max: usize) {
+    self.reorder.set_max_segments(max);
+  }
+}
+
+impl Drop for ReceiveStream {
+  fn drop(&mut self) {
+    memory::release(MemoryPool::Receive, self.readable_len);
+  }
+}
+
+impl Default for ReceiveStream {
+  fn default() -> Self {
+    Self::new()
+  }
+}
@@ -0,0 +1,557 @@
+//! Offline replay of captured flows
+//!
+//! `Replay` takes the packets of a recorded TCP flow and re-drives a
+//! `ControlBlock` with them, no sockets involved. Segments from the peer are
+//! processed as our stack would process them; segments from the side we
+//! play are checked against what our stack would have sent at that moment.
+//! Each mismatch is reported as a `Divergence` with its frame number, which
+//! makes interop problems against middleboxes and other stacks easy to find
+//! in the capture.
+//!
+//! The application is modelled as writing exactly what the capture shows
+//! being sent and reading everything as soon as it arrives.
+
+pub mod pcap;
+
+pub use pcap::{CapturedPacket, PcapError, PcapReader, PcapWriter};
+
+use crate::connection::{ControlBlock, TcpState};
+use crate::packet::{Ipv4Header, TcpFlags, TcpHeader, TcpOption};
+use crate::reliability::retransmit::{PendingSegment, SegmentKind};
+use crate::utils::{Instant, SeqNumber};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::net::SocketAddrV4;
+use core::time::Duration;
+
+/// Duplicate ACKs that justify a fast retransmit (RFC 5681 3.2)
+const DUP_ACK_THRESHOLD: u32 = 3;
+
+/// A frame identical to the one just before it and this close behind was
+/// captured twice, e.g. on two interfaces; no stack retransmits this fast
+const DUPLICATE_WINDOW: Duration = Duration::from_micros(100);
+
+/// Which endpoint of the capture our stack plays
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+  /// The side that sent the first SYN
+  Client,
+  /// The side that answered it
+  Server,
+}
+
+/// How a captured segment differs from what our stack would have sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DivergenceKind {
+  /// The capture acknowledges `captured` where we would acknowledge `ours`
+  AckNumber { captured: u32, ours: u32 },
+  /// A segment starts past SND.NXT, leaving a hole that was never sent
+  SequenceGap { captured: u32, snd_nxt: u32 },
+  /// New data beyond what flow and congestion control would allow us
+  WindowExceeded { sent: u32, allowed: u32 },
+  /// A retransmission before our RTO expired and without enough duplicate
+  /// ACKs for a fast retransmit
+  EarlyRetransmit { seq: u32 },
+  /// Flags we would not send in `state`, such as data before the handshake
+  /// completes
+  UnexpectedFlags { state: TcpState, flags: TcpFlags },
+}
+
+impl fmt::Display for DivergenceKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DivergenceKind::AckNumber { captured, ours } => {
+        write!(f, "acknowledges {captured}, we would acknowledge {ours}")
+      }
+      DivergenceKind::SequenceGap { captured, snd_nxt } => {
+        write!(f, "starts at {captured}, past SND.NXT {snd_nxt}")
+      }
+      DivergenceKind::WindowExceeded { sent, allowed } => {
+        write!(f, "sends {sent} new bytes, we would send at most {allowed}")
+      }
+      DivergenceKind::EarlyRetransmit { seq } => {
+        write!(f, "retransmits {seq} before our RTO or fast retransmit")
+      }
+      DivergenceKind::UnexpectedFlags { state, flags } => {
+        write!(f, "sends {flags:?} in {state:?}")
+      }
+    }
+  }
+}
+
+/// A divergence at one frame of the capture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+  /// 1-based position among the packets fed to the replay, as capture
+  /// tools number frames
+  pub frame: usize,
+  pub time: Instant,
+  pub kind: DivergenceKind,
+}
+
+impl fmt::Display for Divergence {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let micros = self.time.total_micros();
+    write!(
+      f,
+      "frame {} at {}.{:06}s: {}",
+      self.frame,
+      micros / 1_000_000,
+      micros % 1_000_000,
+      self.kind
+    )
+  }
+}
+
+/// Outcome of a replay
+#[derive(Debug, Clone)]
+pub struct ReplayReport {
+  /// Packets that belonged to the replayed flow
+  pub segments: usize,
+  /// Frames of the flow skipped as capture duplicates
+  pub duplicates: usize,
+  pub divergences: Vec<Divergence>,
+  /// Our state after the last segment
+  pub final_state: TcpState,
+}
+
+impl ReplayReport {
+  pub fn is_conformant(&self) -> bool {
+    self.divergences.is_empty()
+  }
+}
+
+impl fmt::Display for ReplayReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(
+      f,
+      "{} segments, {} divergences, ended in {:?}",
+      self.segments,
+      self.divergences.len(),
+      self.final_state
+    )?;
+    if self.duplicates > 0 {
+      writeln!(f, "  {} duplicate frames skipped", self.duplicates)?;
+    }
+    for divergence in &self.divergences {
+      writeln!(f, "  {divergence}")?;
+    }
+    Ok(())
+  }
+}
+
+/// Re-drives our state machine with one recorded flow
+pub struct Replay {
+  role: Role,
+  /// Our endpoint and the peer's, fixed by the first SYN
+  endpoints: Option<(SocketAddrV4, SocketAddrV4)>,
+  control: Option<ControlBlock>,
+  /// The peer's SYN, held until our SYN-ACK fixes our ISS
+  peer_syn: Option<TcpHeader>,
+  /// Window scale option of our SYN
+  local_window_scale: Option<u8>,
+  /// Our SYN, timed for an RTT sample: its end and when it was sent. Data
+  /// is timed by `ControlBlock::process_ack`
+  rtt_probe: Option<(SeqNumber, Instant)>,
+  dup_acks: u32,
+  /// The flow's last frame: when it was captured, its sender and segment
+  last: Option<(Instant, SocketAddrV4, Vec<u8>)>,
+  frame: usize,
+  segments: usize,
+  duplicates: usize,
+  divergences: Vec<Divergence>,
+}
+
+impl Replay {
+  pub fn new(role: Role) -> Self {
+    Self {
+      role,
+      endpoints: None,
+      control: None,
+      peer_syn: None,
+      local_window_scale: None,
+      rtt_probe: None,
+      dup_acks: 0,
+      last: None,
+      frame: 0,
+      segments: 0,
+      duplicates: 0,
+      divergences: Vec::new(),
+    }
+  }
+
+  /// Replay the first TCP flow of a pcap capture
+  pub fn run(capture: &[u8], role: Role) -> Result<ReplayReport, PcapError> {
+    let mut replay = Self::new(role);
+    for packet in PcapReader::new(capture)? {
+      let packet = packet?;
+      replay.feed(packet.time, packet.data);
+    }
+    Ok(replay.finish())
+  }
+
+  /// Feed the next captured IPv4 packet. Packets of other flows, and any
+  /// before the flow's first SYN, only advance the frame count
+  pub fn feed(&mut self, time: Instant, packet: &[u8]) {
+    self.frame += 1;
+    let Some((ip, segment)) = Ipv4Header::parse(packet) else {
+      return;
+    };
+    if ip.protocol != Ipv4Header::PROTOCOL_TCP {
+      return;
+    }
+    let Some((tcp, payload)) = TcpHeader::parse(segment) else {
+      return;
+    };
+    let src = SocketAddrV4::new(ip.src_addr, tcp.src_port);
+    let dst = SocketAddrV4::new(ip.dst_addr, tcp.dst_port);
+
+    let (local, remote) = match self.endpoints {
+      Some(endpoints) => endpoints,
+      None if tcp.flags.is_syn() && !tcp.flags.is_ack() => {
+        let endpoints = match self.role {
+          Role::Client => (src, dst),
+          Role::Server => (dst, src),
+        };
+        self.endpoints = Some(endpoints);
+        endpoints
+      }
+      None => return,
+    };
+    if (src, dst) != (local, remote) && (src, dst) != (remote, local) {
+      return;
+    }
+    if let Some((at, sender, last)) = &self.last {
+      if *sender == src
+        && time.saturating_duration_since(*at) < DUPLICATE_WINDOW
+        && TcpHeader::segments_equivalent(last, segment)
+      {
+        self.duplicates += 1;
+        return;
+      }
+    }
+    self.last = Some((time, src, segment.to_vec()));
+
+    self.segments += 1;
+    if (src, dst) == (local, remote) {
+      self.on_local(time, &tcp, payload.len() as u32);
+    } else {
+      self.on_remote(time, &tcp, payload);
+    }
+  }
+
+  /// Stop replaying and report
+  pub fn finish(self) -> ReplayReport {
+    ReplayReport {
+      segments: self.segments,
+      duplicates: self.duplicates,
+      divergences: self.divergences,
+      final_state: self
+        .control
+        .as_ref()
+        .map_or(TcpState::Closed, |control| control.state),
+    }
+  }
+
+  fn diverge(&mut self, time: Instant, kind: DivergenceKind) {
+    self.divergences.push(Divergence {
+      frame: self.frame,
+      time,
+      kind,
+    });
+  }
+
+  /// A segment the capture shows our side sending
+  fn on_local(&mut self, time: Instant, tcp: &TcpHeader, payload_len: u32) {
+    let seq = SeqNumber(tcp.seq_num);
+    if tcp.flags.is_syn() {
+      self.on_local_syn(time, tcp);
+      return;
+    }
+    let Some(control) = self.control.as_ref() else {
+      return;
+    };
+    let state = control.state;
+    let rcv_nxt = control.rcv_nxt();
+    let snd_nxt = control.snd_nxt();
+    let allowed = control.can_send_bytes();
+
+    if tcp.flags.is_rst() {
+      if let Some(control) = self.control.as_mut() {
+        control.state = TcpState::Closed;
+      }
+      return;
+    }
+    if tcp.flags.is_ack() && tcp.ack_num != rcv_nxt.0 {
+      self.diverge(
+        time,
+        DivergenceKind::AckNumber {
+          captured: tcp.ack_num,
+          ours: rcv_nxt.0,
+        },
+      );
+    }
+    let sends_data = payload_len > 0 || tcp.flags.is_fin();
+    if sends_data && !matches!(state, TcpState::Established | TcpState::CloseWait) {
+      let retransmitted_fin = tcp.flags.is_fin() && seq.before(snd_nxt);
+      if !retransmitted_fin {
+        self.diverge(
+          time,
+          DivergenceKind::UnexpectedFlags {
+            state,
+            flags: tcp.flags,
+          },
+        );
+      }
+    }
+
+    let len = payload_len + tcp.flags.is_fin() as u32;
+    if len == 0 {
+      return;
+    }
+    if seq.before(snd_nxt) {
+      self.on_local_retransmit(time, seq);
+    } else {
+      if seq.after(snd_nxt) {
+        self.diverge(
+          time,
+          DivergenceKind::SequenceGap {
+            captured: seq.0,
+            snd_nxt: snd_nxt.0,
+          },
+        );
+      }
+      if payload_len > allowed {
+        self.diverge(
+          time,
+          DivergenceKind::WindowExceeded {
+            sent: payload_len,
+            allowed,
+          },
+        );
+      }
+      self.on_local_new_data(time, seq, len, tcp.flags.is_fin());
+    }
+  }
+
+  /// Our SYN fixes the ISS; the peer's SYN may already be waiting on it
+  fn on_local_syn(&mut self, time: Instant, tcp: &TcpHeader) {
+    let iss = SeqNumber(tcp.seq_num);
+    if self.control.is_none() {
+      let mut control = ControlBlock::with_initial_seq(iss, time);
+      self.local_window_scale = window_scale(tcp);
+      control.window_scale = self.local_window_scale.unwrap_or(0);
+      control.on_send(iss, 1);
+      control.state = match self.role {
+        Role::Client => TcpState::SynSent,
+        Role::Server => TcpState::SynReceived,
+      };
+      self.control = Some(control);
+      if let Some(syn) = self.peer_syn.take() {
+        self.on_peer_syn(&syn);
+      }
+      self.rtt_probe = Some((iss + 1, time));
+      return;
+    }
+    // A retransmitted SYN or SYN-ACK
+    self.rtt_probe = None;
+    if let Some(control) = self.control.as_ref() {
+      if tcp.flags.is_ack() && tcp.ack_num != control.rcv_nxt().0 {
+        let ours = control.rcv_nxt().0;
+        self.diverge(
+          time,
+          DivergenceKind::AckNumber {
+            captured: tcp.ack_num,
+            ours,
+          },
+        );
+      }
+    }
+  }
+
+  fn on_local_new_data(&mut self, time: Instant, seq: SeqNumber, len: u32, fin: bool) {
+    let Some(control) = self.control.as_mut() else {
+      return;
+    };
+    control.on_send(seq, len);
+    let rto = control.rtt_estimator.rto();
+    control.retransmit.add_segment(
+      PendingSegment {
+        seq,
+        len,
+        data: vec![0; (len - fin as u32) as usize].into(),
+        retransmit_count: 0,
+        first_sent: time,
+        kind: if fin && len == 1 {
+          SegmentKind::Fin
+        } else {
+          SegmentKind::Data
+        },
+      },
+      rto,
+      time,
+    );
+    control.update_activity(time);
+    if fin {
+      control.state = match control.state {
+        TcpState::CloseWait => TcpState::LastAck,
+        _ => TcpState::FinWait1,
+      };
+    }
+  }
+
+  fn on_local_retransmit(&mut self, time: Instant, seq: SeqNumber) {
+    // Karn: no RTT sample across a retransmission
+    self.rtt_probe = None;
+    let Some(control) = self.control.as_mut() else {
+      return;
+    };
+    if control.retransmit.should_retransmit(time) {
+      control.poll_retransmit(time);
+    } else if self.dup_acks < DUP_ACK_THRESHOLD {
+      self.diverge(time, DivergenceKind::EarlyRetransmit { seq: seq.0 });
+    }
+  }
+
+  /// A segment the capture shows the peer sending, processed as our stack
+  /// would
+  fn on_remote(&mut self, time: Instant, tcp: &TcpHeader, payload: &[u8]) {
+    if tcp.flags.is_syn() && self.control.is_none() {
+      self.peer_syn = Some(tcp.clone());
+      return;
+    }
+    let Some(control) = self.control.as_mut() else {
+      return;
+    };
+    if tcp.flags.is_rst() {
+      control.state = TcpState::Closed;
+      return;
+    }
+    if tcp.flags.is_syn() {
+      if control.state == TcpState::SynSent {
+        self.on_peer_syn(tcp);
+      }
+      let Some(control) = self.control.as_mut() else {
+        return;
+      };
+      let ack = SeqNumber(tcp.ack_num);
+      if tcp.flags.is_ack() && control.on_ack(ack) {
+        control.retransmit.acknowledge(ack, time);
+        control.update_send_window(SeqNumber(tcp.seq_num), ack, tcp.window_size as u32);
+        control.state = TcpState::Established;
+        self.sample_rtt(time, ack);
+      }
+      return;
+    }
+
+    if tcp.flags.is_ack() {
+      self.on_remote_ack(time, tcp, payload.len());
+    }
+    let Some(control) = self.control.as_mut() else {
+      return;
+    };
+    if !matches!(
+      control.state,
+      TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+    ) {
+      return;
+    }
+    let seq = SeqNumber(tcp.seq_num);
+    if !payload.is_empty() {
+      control.recv_stream.push(seq, payload.to_vec());
+      let mut sink = vec![0; control.recv_stream.readable()];
+      control.recv_stream.read(&mut sink);
+    }
+    let rcv_nxt = control.rcv_nxt();
+    if tcp.flags.is_fin() && seq + payload.len() as u32 == rcv_nxt {
+      control.recv_stream.set_rcv_nxt(rcv_nxt + 1);
+      control.state = match control.state {
+        TcpState::Established => TcpState::CloseWait,
+        TcpState::FinWait1 => TcpState::Closing,
+        _ => TcpState::TimeWait,
+      };
+    }
+  }
+
+  fn on_remote_ack(&mut self, time: Instant, tcp: &TcpHeader, payload_len: usize) {
+    let Some(control) = self.control.as_mut() else {
+      return;
+    };
+    let ack = SeqNumber(tcp.ack_num);
+    if control.state == TcpState::SynReceived {
+      if !control.on_ack(ack) {
+        return;
+      }
+      control.retransmit.acknowledge(ack, time);
+      control.state = TcpState::Established;
+      let window = control.scaled_peer_window(tcp.window_size);
+      control.update_send_window(SeqNumber(tcp.seq_num), ack, window);
+      self.sample_rtt(time, ack);
+      return;
+    }
+
+    let duplicates = control.stats.duplicate_acks;
+    let window = control.scaled_peer_window(tcp.window_size);
+    let acked =
+      control.process_ack(SeqNumber(tcp.seq_num), ack, window, payload_len, time);
+    if acked > 0 {
+      self.dup_acks = 0;
+    } else if control.stats.duplicate_acks > duplicates {
+      self.dup_acks += 1;
+    }
+    if control.snd_una() == control.snd_nxt() {
+      control.state = match control.state {
+        TcpState::FinWait1 => TcpState::FinWait2,
+        TcpState::Closing => TcpState::TimeWait,
+        TcpState::LastAck => TcpState::Closed,
+        state => state,
+      };
+    }
+  }
+
+  /// Take the peer's SYN as `record_peer_syn` does
+  fn on_peer_syn(&mut self, tcp: &TcpHeader) {
+    let Some(control) = self.control.as_mut() else {
+      return;
+    };
+    control.set_irs(SeqNumber(tcp.seq_num));
+    control.set_initial_send_window(tcp.window_size as u32);
+    for option in &tcp.options {
+      match option {
+        TcpOption::MaximumSegmentSize(mss) => control.on_peer_mss(*mss),
+        TcpOption::SackPermitted => control.sack_permitted = true,
+        TcpOption::Timestamp { .. } => control.timestamps = true,
+        _ => {}
+      }
+    }
+    // Scaling applies only when both SYNs carry the option
+    match (window_scale(tcp), self.local_window_scale) {
+      (Some(shift), Some(_)) => control.peer_window_scale = shift,
+      _ => control.window_scale = 0,
+    }
+  }
+
+  /// Feed the RTT estimator once the timed segment is acknowledged
+  fn sample_rtt(&mut self, time: Instant, ack: SeqNumber) {
+    let Some((end, sent)) = self.rtt_probe else {
+      return;
+    };
+    if end.after(ack) {
+      return;
+    }
+    self.rtt_probe = None;
+    if let Some(control) = self.control.as_mut() {
+      let rtt = time.saturating_duration_since(sent);
+      control.on_rtt_sample(rtt);
+    }
+  }
+}
+
+/// Shift carried by a SYN's window scale option
+fn window_scale(tcp: &TcpHeader) -> Option<u8> {
+  tcp.options.iter().find_map(|option| match option {
+    TcpOption::WindowScale(shift) => Some((*shift).min(14)),
+    _ => None,
+  })
+}
@@ -0,0 +1,230 @@
+//! Reader and writer for classic libpcap capture files
+//!
+//! Works on the file's bytes, so it needs no `std`. Link-layer headers are
+//! stripped and packets other than IPv4 skipped, leaving the IP packets.
+//! Captures are written as raw IP, microsecond timestamps, little-endian.
+
+use crate::utils::Instant;
+use alloc::vec::Vec;
+use core::fmt;
+
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const FILE_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const SNAPLEN: u32 = 65535;
+const AF_INET: u32 = 2;
+
+/// Why a capture could not be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcapError {
+  /// Not a classic pcap file (pcapng is not supported)
+  BadMagic,
+  /// The file ends inside a header or packet
+  Truncated,
+  /// A link type without IPv4 framing we can strip
+  UnsupportedLinkType(u32),
+}
+
+impl fmt::Display for PcapError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PcapError::BadMagic => write!(f, "not a pcap file"),
+      PcapError::Truncated => write!(f, "capture is truncated"),
+      PcapError::UnsupportedLinkType(link) => write!(f, "unsupported link type {link}"),
+    }
+  }
+}
+
+/// An IPv4 packet from a capture, with its timestamp
+#[derive(Debug, Clone, Copy)]
+pub struct CapturedPacket<'a> {
+  pub time: Instant,
+  pub data: &'a [u8],
+}
+
+/// Iterator over the IPv4 packets of a capture
+pub struct PcapReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+  big_endian: bool,
+  nanos: bool,
+  link_type: u32,
+}
+
+impl<'a> PcapReader<'a> {
+  /// Read the file header of a capture held in `data`
+  pub fn new(data: &'a [u8]) -> Result<Self, PcapError> {
+    if data.len() < FILE_HEADER_LEN {
+      return Err(PcapError::Truncated);
+    }
+    let magic = [data[0], data[1], data[2], data[3]];
+    let (big_endian, nanos) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic))
+    {
+      (MAGIC_MICROS, _) => (false, false),
+      (MAGIC_NANOS, _) => (false, true),
+      (_, MAGIC_MICROS) => (true, false),
+      (_, MAGIC_NANOS) => (true, true),
+      _ => return Err(PcapError::BadMagic),
+    };
+    let mut reader = Self {
+      data,
+      pos: FILE_HEADER_LEN,
+      big_endian,
+      nanos,
+      link_type: 0,
+    };
+    reader.link_type = reader.u32_at(20);
+    match reader.link_type {
+      LINKTYPE_NULL | LINKTYPE_ETHERNET | LINKTYPE_RAW | LINKTYPE_LINUX_SLL
+      | LINKTYPE_IPV4 => Ok(reader),
+      link => Err(PcapError::UnsupportedLinkType(link)),
+    }
+  }
+
+  pub fn link_type(&self) -> u32 {
+    self.link_type
+  }
+
+  fn u32_at(&self, pos: usize) -> u32 {
+    let bytes = [
+      self.data[pos],
+      self.data[pos + 1],
+      self.data[pos + 2],
+      self.data[pos + 3],
+    ];
+    if self.big_endian {
+      u32::from_be_bytes(bytes)
+    } else {
+      u32::from_le_bytes(bytes)
+    }
+  }
+
+  /// The IPv4 packet inside a frame, if it carries one
+  fn strip_link(&self, frame: &'a [u8]) -> Option<&'a [u8]> {
+    match self.link_type {
+      LINKTYPE_RAW | LINKTYPE_IPV4 => Some(frame),
+      LINKTYPE_ETHERNET => {
+        let ethertype = u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]);
+        (ethertype == ETHERTYPE_IPV4).then(|| &frame[14..])
+      }
+      LINKTYPE_LINUX_SLL => {
+        let protocol = u16::from_be_bytes([*frame.get(14)?, *frame.get(15)?]);
+        (protocol == ETHERTYPE_IPV4).then(|| &frame[16..])
+      }
+      LINKTYPE_NULL => {
+        // Host byte order of the capturing machine: accept either
+        let family = frame.get(..4)?;
+        let family = [family[0], family[1], family[2], family[3]];
+        let inet =
+          u32::from_le_bytes(family) == AF_INET || u32::from_be_bytes(family) == AF_INET;
+        inet.then(|| &frame[4..])
+      }
+      _ => None,
+    }
+  }
+}
+
+impl<'a> Iterator for PcapReader<'a> {
+  type Item = Result<CapturedPacket<'a>, PcapError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      if self.pos == self.data.len() {
+        return None;
+      }
+      if self.data.len() - self.pos < RECORD_HEADER_LEN {
+        self.pos = self.data.len();
+        return Some(Err(PcapError::Truncated));
+      }
+      let secs = self.u32_at(self.pos) as u64;
+      let fraction = self.u32_at(self.pos + 4) as u64;
+      let captured = self.u32_at(self.pos + 8) as usize;
+      let start = self.pos + RECORD_HEADER_LEN;
+      if self.data.len() - start < captured {
+        self.pos = self.data.len();
+        return Some(Err(PcapError::Truncated));
+      }
+      self.pos = start + captured;
+
+      let micros = if self.nanos {
+        fraction / 1000
+      } else {
+        fraction
+      };
+      let time = Instant::from_micros(secs * 1_000_000 + micros);
+      if let Some(data) = self.strip_link(&self.data[start..start + captured]) {
+        return Some(Ok(CapturedPacket { time, data }));
+      }
+    }
+  }
+}
+
+/// Builds a capture of IPv4 packets in memory
+#[derive(Debug, Clone)]
+pub struct PcapWriter {
+  data: Vec<u8>,
+}
+
+impl PcapWriter {
+  /// A capture holding only the file header
+  pub fn new() -> Self {
+    let mut data = Vec::with_capacity(FILE_HEADER_LEN);
+    data.extend_from_slice(&MAGIC_MICROS.to_le_bytes());
+    data.extend_from_slice(&2u16.to_le_bytes());
+    data.extend_from_slice(&4u16.to_le_bytes());
+    // Timezone offset and timestamp accuracy, both always zero
+    data.extend_from_slice(&[0; 8]);
+    data.extend_from_slice(&SNAPLEN.to_le_bytes());
+    data.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    Self { data }
+  }
+
+  /// Append `packet`, seen at `time`
+  pub fn write(&mut self, time: Instant, packet: &[u8]) {
+    let micros = time.total_micros();
+    let captured = packet.len().min(SNAPLEN as usize);
+    self
+      .data
+      .extend_from_slice(&((micros / 1_000_000) as u32).to_le_bytes());
+    self
+      .data
+      .extend_from_slice(&((micros % 1_000_000) as u32).to_le_bytes());
+    self
+      .data
+      .extend_from_slice(&(captured as u32).to_le_bytes());
+    self
+      .data
+      .extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    self.data.extend_from_slice(&packet[..captured]);
+  }
+
+  /// Whether no packet has been written
+  pub fn is_empty(&self) -> bool {
+    self.data.len() == FILE_HEADER_LEN
+  }
+
+  /// The capture as a pcap file
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.data
+  }
+
+  /// The capture so far, leaving a new empty one in its place
+  pub fn take(&mut self) -> Vec<u8> {
+    core::mem::take(self).data
+  }
+}
+
+impl Default for PcapWriter {
+  fn default() -> Self {
+    Self::new()
+  }
+}
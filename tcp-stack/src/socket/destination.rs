@@ -0,0 +1,139 @@
+//! Guardrails on where packets may be sent
+//!
+//! A raw socket with `IP_HDRINCL` sends whatever header it is given. Left
+//! alone it will put segments on the wire for the limited broadcast
+//! address, a multicast group or an address no router forwards. A
+//! `DestinationPolicy` refuses those sends with a clear error unless they
+//! are explicitly allowed. Everything is refused by default.
+//!
+//! Loopback destinations are fine from a loopback source, which is the host
+//! talking to itself. From any other source the receiving kernel drops the
+//! packet as a martian, so such a send is refused unless loopback is
+//! allowed.
+
+use std::fmt;
+use std::io;
+use std::net::Ipv4Addr;
+
+/// What a destination address is, as far as the policy cares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DestinationClass {
+  Unicast,
+  /// The limited broadcast address, 255.255.255.255
+  Broadcast,
+  /// 224.0.0.0/4
+  Multicast,
+  /// 127.0.0.0/8
+  Loopback,
+  /// Addresses no host may use (RFC 1812 5.3.7): "this network"
+  /// 0.0.0.0/8 and the reserved 240.0.0.0/4
+  Martian,
+}
+
+impl DestinationClass {
+  pub fn of(addr: Ipv4Addr) -> Self {
+    let first = addr.octets()[0];
+    if addr.is_broadcast() {
+      DestinationClass::Broadcast
+    } else if addr.is_multicast() {
+      DestinationClass::Multicast
+    } else if addr.is_loopback() {
+      DestinationClass::Loopback
+    } else if first == 0 || first >= 240 {
+      DestinationClass::Martian
+    } else {
+      DestinationClass::Unicast
+    }
+  }
+}
+
+impl fmt::Display for DestinationClass {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      DestinationClass::Unicast => "unicast",
+      DestinationClass::Broadcast => "broadcast",
+      DestinationClass::Multicast => "multicast",
+      DestinationClass::Loopback => "loopback",
+      DestinationClass::Martian => "martian",
+    })
+  }
+}
+
+/// Which destinations besides unicast a socket may send to. All are
+/// refused by default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DestinationPolicy {
+  pub broadcast: bool,
+  pub multicast: bool,
+  /// Loopback destinations from a non-loopback source
+  pub loopback: bool,
+  pub martian: bool,
+}
+
+impl DestinationPolicy {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Send anywhere, as the socket did before there was a policy
+  pub fn allow_all() -> Self {
+    Self {
+      broadcast: true,
+      multicast: true,
+      loopback: true,
+      martian: true,
+    }
+  }
+
+  /// Whether a packet from `src` may be sent to `dst`
+  pub fn check(&self, src: Ipv4Addr, dst: Ipv4Addr) -> Result<(), DestinationDenied> {
+    let class = DestinationClass::of(dst);
+    let allowed = match class {
+      DestinationClass::Unicast => true,
+      DestinationClass::Broadcast => self.broadcast,
+      DestinationClass::Multicast => self.multicast,
+      DestinationClass::Loopback => self.loopback || src.is_loopback(),
+      DestinationClass::Martian => self.martian,
+    };
+    if allowed {
+      Ok(())
+    } else {
+      Err(DestinationDenied { addr: dst, class })
+    }
+  }
+}
+
+/// A send the `DestinationPolicy` refused. Converts to an `io::Error` of
+/// kind `PermissionDenied` that carries it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DestinationDenied {
+  pub addr: Ipv4Addr,
+  pub class: DestinationClass,
+}
+
+impl fmt::Display for DestinationDenied {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "refusing to send to {} address {}; allow it in the socket's DestinationPolicy",
+      self.class, self.addr
+    )
+  }
+}
+
+impl std::error::Error for DestinationDenied {}
+
+impl From<DestinationDenied> for io::Error {
+  fn from(denied: DestinationDenied) -> Self {
+    io::Error::new(io::ErrorKind::PermissionDenied, denied)
+  }
+}
+
+/// Source address of an IPv4 packet, unspecified if it is too short to
+/// have one
+pub(crate) fn source_of(packet: &[u8]) -> Ipv4Addr {
+  match packet.get(12..16) {
+    Some(&[a, b, c, d]) => Ipv4Addr::new(a, b, c, d),
+    _ => Ipv4Addr::UNSPECIFIED,
+  }
+}
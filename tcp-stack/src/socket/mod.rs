@@ -1,5 +1,26 @@
 //! Raw socket handling
+//!
+//! `RawSocket` exposes the same constructor and send/receive interface on
+//! every platform: raw IP sockets on Linux, macOS and the BSDs, and Npcap
+//! link-layer injection on Windows.
 
+pub mod destination;
+#[cfg(target_os = "linux")]
+mod netlink;
+#[cfg(windows)]
+pub mod npcap;
+#[cfg(unix)]
+pub mod privilege;
+#[cfg(unix)]
 pub mod raw;
+pub mod route;
+#[cfg(target_os = "linux")]
+pub mod watch;
 
+pub use destination::{DestinationClass, DestinationDenied, DestinationPolicy};
+#[cfg(windows)]
+pub use npcap::RawSocket;
+#[cfg(unix)]
 pub use raw::RawSocket;
+#[cfg(target_os = "linux")]
+pub use watch::NetworkWatcher;
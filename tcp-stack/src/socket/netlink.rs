@@ -0,0 +1,160 @@
+//! Minimal rtnetlink plumbing for the route lookup and network watcher
+
+use std::io;
+use std::os::unix::prelude::*;
+
+pub const HEADER_LEN: usize = 16;
+pub const NLMSG_ERROR: u16 = 2;
+pub const NLMSG_DONE: u16 = 3;
+pub const NLM_F_REQUEST: u16 = 1;
+pub const NLM_F_DUMP: u16 = 0x300;
+
+/// A `NETLINK_ROUTE` socket subscribed to the multicast `groups`
+pub fn open(groups: u32) -> io::Result<OwnedFd> {
+  let fd = unsafe {
+    libc::socket(
+      libc::AF_NETLINK,
+      libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+      libc::NETLINK_ROUTE,
+    )
+  };
+  if fd < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+  if groups != 0 {
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = groups;
+    let ret = unsafe {
+      libc::bind(
+        fd.as_raw_fd(),
+        &addr as *const _ as *const libc::sockaddr,
+        std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+      )
+    };
+    if ret < 0 {
+      return Err(io::Error::last_os_error());
+    }
+  }
+  Ok(fd)
+}
+
+/// A request of `kind` carrying `body`
+pub fn message(kind: u16, flags: u16, body: &[u8]) -> Vec<u8> {
+  let len = HEADER_LEN + body.len();
+  let mut msg = Vec::with_capacity(len);
+  msg.extend_from_slice(&(len as u32).to_ne_bytes());
+  msg.extend_from_slice(&kind.to_ne_bytes());
+  msg.extend_from_slice(&(flags | NLM_F_REQUEST).to_ne_bytes());
+  msg.extend_from_slice(&1u32.to_ne_bytes());
+  msg.extend_from_slice(&0u32.to_ne_bytes());
+  msg.extend_from_slice(body);
+  msg
+}
+
+/// Send a request to the kernel
+pub fn send(fd: &OwnedFd, msg: &[u8]) -> io::Result<()> {
+  let ret = unsafe {
+    libc::send(
+      fd.as_raw_fd(),
+      msg.as_ptr() as *const libc::c_void,
+      msg.len(),
+      0,
+    )
+  };
+  if ret < 0 {
+    Err(io::Error::last_os_error())
+  } else {
+    Ok(())
+  }
+}
+
+/// Receive one datagram, which may hold several messages
+pub fn recv<'a>(fd: &OwnedFd, buf: &'a mut [u8]) -> io::Result<&'a [u8]> {
+  let ret = unsafe {
+    libc::recv(
+      fd.as_raw_fd(),
+      buf.as_mut_ptr() as *mut libc::c_void,
+      buf.len(),
+      0,
+    )
+  };
+  if ret < 0 {
+    Err(io::Error::last_os_error())
+  } else {
+    Ok(&buf[..ret as usize])
+  }
+}
+
+/// Type and body of each message in a datagram
+pub fn messages(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+  std::iter::from_fn(move || {
+    if data.len() < HEADER_LEN {
+      return None;
+    }
+    let len = u32::from_ne_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    if len < HEADER_LEN || len > data.len() {
+      return None;
+    }
+    let kind = u16::from_ne_bytes([data[4], data[5]]);
+    let body = &data[HEADER_LEN..len];
+    data = &data[align(len).min(data.len())..];
+    Some((kind, body))
+  })
+}
+
+/// Type and value of each attribute after a message's fixed header
+pub fn attributes(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+  std::iter::from_fn(move || {
+    if data.len() < 4 {
+      return None;
+    }
+    let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+    if len < 4 || len > data.len() {
+      return None;
+    }
+    let kind = u16::from_ne_bytes([data[2], data[3]]);
+    let value = &data[4..len];
+    data = &data[align(len).min(data.len())..];
+    Some((kind, value))
+  })
+}
+
+/// The error an `NLMSG_ERROR` body carries, or `None` for an
+/// acknowledgement
+pub fn error(body: &[u8]) -> Option<io::Error> {
+  let errno = match body.get(..4) {
+    Some(&[a, b, c, d]) => i32::from_ne_bytes([a, b, c, d]),
+    _ => return Some(malformed()),
+  };
+  (errno != 0).then(|| io::Error::from_raw_os_error(-errno))
+}
+
+pub fn malformed() -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, "malformed netlink reply")
+}
+
+/// Four bytes from an attribute as an IPv4 address
+pub fn ipv4(value: &[u8]) -> Option<std::net::Ipv4Addr> {
+  match value {
+    &[a, b, c, d] => Some(std::net::Ipv4Addr::new(a, b, c, d)),
+    _ => None,
+  }
+}
+
+pub fn interface_name(index: u32) -> Option<String> {
+  let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+  let ret = unsafe { libc::if_indextoname(index, name.as_mut_ptr()) };
+  if ret.is_null() {
+    return None;
+  }
+  let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) };
+  Some(name.to_string_lossy().into_owned())
+}
+
+/// Messages and attributes are padded to four bytes
+fn align(len: usize) -> usize {
+  (len + 3) & !3
+}
@@ -0,0 +1,170 @@
+//! Npcap-based packet backend for Windows
+//!
+//! Windows raw sockets refuse to send TCP segments, so packets are injected
+//! and captured at the link layer through Npcap instead. Outgoing IPv4
+//! packets are wrapped in Ethernet frames using the addresses configured with
+//! `set_link_addresses`; inbound frames are filtered to TCP over IPv4 and
+//! handed out without their Ethernet header.
+
+use super::destination::DestinationPolicy;
+use pcap::{Active, Capture, Device};
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+
+/// Read timeout used to emulate non-blocking reads
+const READ_TIMEOUT_MS: i32 = 1;
+
+/// Ethernet addresses used to frame outgoing packets
+#[derive(Debug, Clone, Copy)]
+struct LinkAddresses {
+  local: [u8; 6],
+  next_hop: [u8; 6],
+}
+
+/// Raw socket for sending/receiving IP packets
+pub struct RawSocket {
+  capture: Mutex<Capture<Active>>,
+  link: Mutex<Option<LinkAddresses>>,
+  nonblocking: AtomicBool,
+  read_timeout: Mutex<Option<Duration>>,
+  policy: DestinationPolicy,
+}
+
+impl RawSocket {
+  /// Create a new raw socket on the default Npcap adapter
+  pub fn new() -> io::Result<Self> {
+    let device = Device::lookup()
+      .map_err(pcap_error)?
+      .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no Npcap adapter found"))?;
+    Self::open(device)
+  }
+
+  /// Create a raw socket on a named Npcap adapter (`\Device\NPF_{...}`)
+  pub fn with_device(name: &str) -> io::Result<Self> {
+    Self::open(Device::from(name))
+  }
+
+  fn open(device: Device) -> io::Result<Self> {
+    let mut capture = Capture::from_device(device)
+      .map_err(pcap_error)?
+      .immediate_mode(true)
+      .snaplen(65535)
+      .timeout(READ_TIMEOUT_MS)
+      .open()
+      .map_err(pcap_error)?;
+    capture.filter("ip proto tcp", true).map_err(pcap_error)?;
+
+    Ok(Self {
+      capture: Mutex::new(capture),
+      link: Mutex::new(None),
+      nonblocking: AtomicBool::new(false),
+      read_timeout: Mutex::new(None),
+      policy: DestinationPolicy::default(),
+    })
+  }
+
+  /// Set the Ethernet source address and the next-hop (gateway or peer)
+  /// address used for outgoing frames; required before sending
+  pub fn set_link_addresses(&self, local: [u8; 6], next_hop: [u8; 6]) {
+    *self.link.lock().unwrap() = Some(LinkAddresses { local, next_hop });
+  }
+
+  pub fn destination_policy(&self) -> DestinationPolicy {
+    self.policy
+  }
+
+  /// Choose which destinations besides unicast `send_to` accepts
+  pub fn set_destination_policy(&mut self, policy: DestinationPolicy) -> io::Result<()> {
+    self.policy = policy;
+    Ok(())
+  }
+
+  /// Send a packet to the given destination, failing with
+  /// `PermissionDenied` if the destination policy refuses it
+  pub fn send_to(&self, packet: &[u8], dst: Ipv4Addr) -> io::Result<usize> {
+    self
+      .policy
+      .check(super::destination::source_of(packet), dst)?;
+    let link = self.link.lock().unwrap().ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::NotConnected,
+        "link addresses not configured; call set_link_addresses first",
+      )
+    })?;
+
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER_LEN + packet.len());
+    frame.extend_from_slice(&link.next_hop);
+    frame.extend_from_slice(&link.local);
+    frame.extend_from_slice(&ETHERTYPE_IPV4);
+    frame.extend_from_slice(packet);
+
+    self
+      .capture
+      .lock()
+      .unwrap()
+      .sendpacket(frame)
+      .map_err(pcap_error)?;
+    trace!("Sent {} bytes to {}", packet.len(), dst);
+    Ok(packet.len())
+  }
+
+  /// Receive a packet
+  pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Ipv4Addr)> {
+    let deadline = self
+      .read_timeout
+      .lock()
+      .unwrap()
+      .map(|t| Instant::now() + t);
+    let mut capture = self.capture.lock().unwrap();
+
+    loop {
+      let frame = match capture.next_packet() {
+        Ok(packet) => packet.data,
+        Err(pcap::Error::TimeoutExpired) | Err(pcap::Error::NoMorePackets) => {
+          if self.nonblocking.load(Ordering::Relaxed)
+            || deadline.is_some_and(|d| Instant::now() >= d)
+          {
+            return Err(io::ErrorKind::WouldBlock.into());
+          }
+          continue;
+        }
+        Err(e) => return Err(pcap_error(e)),
+      };
+
+      if frame.len() < ETHERNET_HEADER_LEN + 20 || frame[12..14] != ETHERTYPE_IPV4 {
+        continue;
+      }
+
+      let packet = &frame[ETHERNET_HEADER_LEN..];
+      let len = packet.len().min(buf.len());
+      buf[..len].copy_from_slice(&packet[..len]);
+
+      let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+      trace!("Received {} bytes from {}", len, src);
+      return Ok((len, src));
+    }
+  }
+
+  /// Set non-blocking mode
+  pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+    self.nonblocking.store(nonblocking, Ordering::Relaxed);
+    Ok(())
+  }
+
+  /// Bound how long a blocking `recv_from` waits; on expiry it fails with
+  /// `WouldBlock`. `None` blocks indefinitely.
+  pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    *self.read_timeout.lock().unwrap() = timeout;
+    Ok(())
+  }
+}
+
+fn pcap_error(e: pcap::Error) -> io::Error {
+  io::Error::other(e)
+}
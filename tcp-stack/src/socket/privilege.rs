@@ -0,0 +1,215 @@
+//! Raw socket privilege checks and privilege dropping
+//!
+//! Opening a raw socket needs root or, on Linux, `CAP_NET_RAW`. Long-running
+//! servers should open their socket early and then give those privileges up;
+//! `open_then_drop` does both in the right order.
+
+use super::RawSocket;
+use crate::error::{Result, TcpError};
+use std::io;
+
+/// Bit index of `CAP_NET_RAW` in the Linux capability sets
+#[cfg(target_os = "linux")]
+const CAP_NET_RAW: u32 = 13;
+
+/// Check that the process can open raw sockets, with a precise explanation
+/// when it cannot
+pub fn check_raw_socket_privilege() -> Result<()> {
+  let euid = unsafe { libc::geteuid() };
+
+  #[cfg(target_os = "linux")]
+  {
+    match effective_capabilities() {
+      Some(caps) if caps & (1 << CAP_NET_RAW) != 0 => Ok(()),
+      Some(_) if euid == 0 => Err(TcpError::MissingPrivilege(
+        "running as root but CAP_NET_RAW is not in the effective capability set \
+         (container or systemd capability bounding?); grant it with \
+         `--cap-add NET_RAW` or `AmbientCapabilities=CAP_NET_RAW`"
+          .to_string(),
+      )),
+      Some(_) => Err(TcpError::MissingPrivilege(format!(
+        "CAP_NET_RAW is not in the effective capability set (euid {euid}); run as \
+         root or grant it with `setcap cap_net_raw+ep <binary>`"
+      ))),
+      None if euid == 0 => Ok(()),
+      None => Err(TcpError::MissingPrivilege(format!(
+        "raw sockets require root or CAP_NET_RAW (euid {euid})"
+      ))),
+    }
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  {
+    if euid == 0 {
+      Ok(())
+    } else {
+      Err(TcpError::MissingPrivilege(format!(
+        "raw sockets require root (euid {euid})"
+      )))
+    }
+  }
+}
+
+/// Replace a permission error from socket creation with the precise reason
+pub(crate) fn explain(err: io::Error) -> io::Error {
+  if err.kind() != io::ErrorKind::PermissionDenied {
+    return err;
+  }
+  match check_raw_socket_privilege() {
+    Err(TcpError::MissingPrivilege(reason)) => {
+      io::Error::new(io::ErrorKind::PermissionDenied, reason)
+    }
+    _ => err,
+  }
+}
+
+/// Look up the uid and gid of a user by name (e.g. `"nobody"`)
+pub fn lookup_user(name: &str) -> Option<(u32, u32)> {
+  let name = std::ffi::CString::new(name).ok()?;
+  let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+  let mut buf = vec![0 as libc::c_char; 4096];
+  let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+  let ret = unsafe {
+    libc::getpwnam_r(
+      name.as_ptr(),
+      &mut pwd,
+      buf.as_mut_ptr(),
+      buf.len(),
+      &mut result,
+    )
+  };
+
+  if ret != 0 || result.is_null() {
+    None
+  } else {
+    Some((pwd.pw_uid, pwd.pw_gid))
+  }
+}
+
+/// Permanently give up elevated privileges
+///
+/// When running as root, switches to `uid`/`gid` (clearing supplementary
+/// groups) and verifies root cannot be regained. On Linux, a non-root
+/// process holding capabilities has all of them cleared instead.
+pub fn drop_privileges(uid: u32, gid: u32) -> Result<()> {
+  if unsafe { libc::geteuid() } != 0 {
+    #[cfg(target_os = "linux")]
+    return clear_capabilities();
+    #[cfg(not(target_os = "linux"))]
+    return Ok(());
+  }
+
+  if uid == 0 {
+    return Err(TcpError::PrivilegeDrop(
+      "refusing to drop privileges to uid 0".to_string(),
+    ));
+  }
+
+  let os_err = |what: &str| {
+    TcpError::PrivilegeDrop(format!("{what}: {}", io::Error::last_os_error()))
+  };
+
+  if unsafe { libc::setgroups(0, std::ptr::null()) } != 0 {
+    return Err(os_err("setgroups"));
+  }
+  if unsafe { libc::setgid(gid as libc::gid_t) } != 0 {
+    return Err(os_err("setgid"));
+  }
+  if unsafe { libc::setuid(uid as libc::uid_t) } != 0 {
+    return Err(os_err("setuid"));
+  }
+
+  if unsafe { libc::setuid(0) } == 0 {
+    return Err(TcpError::PrivilegeDrop(
+      "root privileges could be regained after setuid".to_string(),
+    ));
+  }
+
+  debug!("Dropped privileges to uid {} gid {}", uid, gid);
+  Ok(())
+}
+
+/// Open a raw socket, then drop privileges to `uid`/`gid`
+///
+/// Checks privileges first so a missing capability is reported precisely
+/// rather than as a bare `EPERM`.
+pub fn open_then_drop(uid: u32, gid: u32) -> Result<RawSocket> {
+  check_raw_socket_privilege()?;
+  let socket = RawSocket::new()?;
+  drop_privileges(uid, gid)?;
+  Ok(socket)
+}
+
+/// Effective capability mask from `/proc/self/status`
+#[cfg(target_os = "linux")]
+fn effective_capabilities() -> Option<u64> {
+  let status = std::fs::read_to_string("/proc/self/status").ok()?;
+  parse_cap_eff(&status)
+}
+
+#[cfg(target_os = "linux")]
+fn parse_cap_eff(status: &str) -> Option<u64> {
+  status
+    .lines()
+    .find_map(|line| line.strip_prefix("CapEff:"))
+    .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+}
+
+/// Clear the effective, permitted and inheritable capability sets
+#[cfg(target_os = "linux")]
+fn clear_capabilities() -> Result<()> {
+  #[repr(C)]
+  struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+  }
+
+  #[repr(C)]
+  #[derive(Clone, Copy)]
+  struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+  }
+
+  const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+  let header = CapHeader {
+    version: LINUX_CAPABILITY_VERSION_3,
+    pid: 0,
+  };
+  let data = [CapData {
+    effective: 0,
+    permitted: 0,
+    inheritable: 0,
+  }; 2];
+
+  let ret = unsafe {
+    libc::syscall(libc::SYS_capset, &header as *const CapHeader, data.as_ptr())
+  };
+
+  if ret != 0 {
+    return Err(TcpError::PrivilegeDrop(format!(
+      "capset: {}",
+      io::Error::last_os_error()
+    )));
+  }
+
+  debug!("Cleared all capabilities");
+  Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_cap_eff() {
+    let status = "Name:\ttcp-stack\nCapInh:\t0000000000000000\n\
+                  CapPrm:\t0000000000002000\nCapEff:\t0000000000002000\n";
+    let caps = parse_cap_eff(status).unwrap();
+    assert_ne!(caps & (1 << CAP_NET_RAW), 0);
+    assert_eq!(parse_cap_eff("Name:\tx\n"), None);
+  }
+}
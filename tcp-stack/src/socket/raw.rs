@@ -1,89 +1,98 @@
-//! Raw socket wrapper for Linux
+//! Raw socket wrapper for Linux, macOS and the BSDs
+//!
+//! Sending uses an `IPPROTO_RAW` socket with `IP_HDRINCL`, which is
+//! send-only on every Unix, so a second `IPPROTO_TCP` raw socket receives
+//! inbound segments (IP header included).
+//!
+//! The BSD-derived stacks differ from Linux in two ways handled here:
+//! `sockaddr_in` carries a `sin_len` field, and with `IP_HDRINCL` the
+//! `ip_len`/`ip_off` header fields are exchanged in host byte order (on
+//! receive `ip_len` also excludes the IP header).
 
+use super::destination::DestinationPolicy;
 use std::io;
 use std::net::Ipv4Addr;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::prelude::*;
-use tracing::trace;
+use std::time::Duration;
 
 /// Raw socket for sending/receiving IP packets
 pub struct RawSocket {
-  fd: OwnedFd,
+  send_fd: OwnedFd,
+  recv_fd: OwnedFd,
+  policy: DestinationPolicy,
 }
 
 impl RawSocket {
   /// Create a new raw socket
   pub fn new() -> io::Result<Self> {
-    let fd =
-      unsafe { libc::socket(libc::AF_INET, libc::SOCK_RAW, libc::IPPROTO_RAW) };
-
-    if fd < 0 {
-      return Err(io::Error::last_os_error());
-    }
-
-    let owned_fd = unsafe { OwnedFd::from_raw_fd(fd) };
-
-    let socket = Self { fd: owned_fd };
+    let send_fd = open_raw(libc::IPPROTO_RAW).map_err(super::privilege::explain)?;
+    let recv_fd = open_raw(libc::IPPROTO_TCP).map_err(super::privilege::explain)?;
 
+    let socket = Self::from_fds(send_fd, recv_fd);
     socket.set_iphdrincl()?;
-    socket.set_broadcast()?;
 
     Ok(socket)
   }
 
-  fn set_iphdrincl(&self) -> io::Result<()> {
-    let value: libc::c_int = 1;
-    let ret = unsafe {
-      libc::setsockopt(
-        self.fd.as_raw_fd(),
-        libc::IPPROTO_IP,
-        libc::IP_HDRINCL,
-        &value as *const _ as *const libc::c_void,
-        std::mem::size_of_val(&value) as libc::socklen_t,
-      )
-    };
-
-    if ret < 0 {
-      Err(io::Error::last_os_error())
-    } else {
-      Ok(())
+  /// Wrap descriptors opened by `RawSocket::new`, in this process or one
+  /// that passed them over with `SCM_RIGHTS`. The destination policy
+  /// starts out as the default
+  pub fn from_fds(send_fd: OwnedFd, recv_fd: OwnedFd) -> Self {
+    Self {
+      send_fd,
+      recv_fd,
+      policy: DestinationPolicy::default(),
     }
   }
 
-  fn set_broadcast(&self) -> io::Result<()> {
-    let value: libc::c_int = 1;
-    let ret = unsafe {
-      libc::setsockopt(
-        self.fd.as_raw_fd(),
-        libc::SOL_SOCKET,
-        libc::SO_BROADCAST,
-        &value as *const _ as *const libc::c_void,
-        std::mem::size_of_val(&value) as libc::socklen_t,
-      )
-    };
+  /// The send and receive descriptors
+  pub fn as_fds(&self) -> [BorrowedFd<'_>; 2] {
+    [self.send_fd.as_fd(), self.recv_fd.as_fd()]
+  }
 
-    if ret < 0 {
-      Err(io::Error::last_os_error())
-    } else {
-      Ok(())
-    }
+  /// Another handle on the same sockets
+  pub fn try_clone(&self) -> io::Result<Self> {
+    Ok(Self {
+      send_fd: self.send_fd.try_clone()?,
+      recv_fd: self.recv_fd.try_clone()?,
+      policy: self.policy,
+    })
+  }
+
+  pub fn destination_policy(&self) -> DestinationPolicy {
+    self.policy
+  }
+
+  /// Choose which destinations besides unicast `send_to` accepts.
+  /// `SO_BROADCAST` is only set while broadcast is allowed
+  pub fn set_destination_policy(&mut self, policy: DestinationPolicy) -> io::Result<()> {
+    set_int_option(
+      &self.send_fd,
+      libc::SOL_SOCKET,
+      libc::SO_BROADCAST,
+      policy.broadcast as libc::c_int,
+    )?;
+    self.policy = policy;
+    Ok(())
   }
 
-  /// Send a packet to the given destination
+  fn set_iphdrincl(&self) -> io::Result<()> {
+    set_int_option(&self.send_fd, libc::IPPROTO_IP, libc::IP_HDRINCL, 1)
+  }
+
+  /// Send a packet to the given destination, failing with
+  /// `PermissionDenied` if the destination policy refuses it
   pub fn send_to(&self, packet: &[u8], dst: Ipv4Addr) -> io::Result<usize> {
-    let mut addr = libc::sockaddr_in {
-      sin_len: std::mem::size_of::<libc::sockaddr_in>() as u8,
-      sin_family: libc::AF_INET as libc::sa_family_t,
-      sin_port: 0,
-      sin_addr: libc::in_addr {
-        s_addr: u32::from_ne_bytes(dst.octets()),
-      },
-      sin_zero: [0; 8],
-    };
+    self
+      .policy
+      .check(super::destination::source_of(packet), dst)?;
+    let mut addr = platform::sockaddr_in(dst);
+    let packet = platform::outgoing(packet);
 
     let ret = unsafe {
       libc::sendto(
-        self.fd.as_raw_fd(),
+        self.send_fd.as_raw_fd(),
         packet.as_ptr() as *const libc::c_void,
         packet.len(),
         0,
@@ -102,18 +111,12 @@ impl RawSocket {
 
   /// Receive a packet
   pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Ipv4Addr)> {
-    let mut addr = libc::sockaddr_in {
-      sin_len: std::mem::size_of::<libc::sockaddr_in>() as u8,
-      sin_family: libc::AF_INET as libc::sa_family_t,
-      sin_port: 0,
-      sin_addr: libc::in_addr { s_addr: 0 },
-      sin_zero: [0; 8],
-    };
+    let mut addr = platform::sockaddr_in(Ipv4Addr::UNSPECIFIED);
     let mut addr_len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
 
     let ret = unsafe {
       libc::recvfrom(
-        self.fd.as_raw_fd(),
+        self.recv_fd.as_raw_fd(),
         buf.as_mut_ptr() as *mut libc::c_void,
         buf.len(),
         0,
@@ -125,26 +128,38 @@ impl RawSocket {
     if ret < 0 {
       Err(io::Error::last_os_error())
     } else {
+      let len = ret as usize;
+      platform::incoming(&mut buf[..len]);
       let src = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
       trace!("Received {} bytes from {}", ret, src);
-      Ok((ret as usize, src))
+      Ok((len, src))
     }
   }
 
   /// Set non-blocking mode
   pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
-    let flags = unsafe { libc::fcntl(self.fd.as_raw_fd(), libc::F_GETFL, 0) };
-    if flags < 0 {
-      return Err(io::Error::last_os_error());
-    }
+    set_fd_nonblocking(&self.send_fd, nonblocking)?;
+    set_fd_nonblocking(&self.recv_fd, nonblocking)
+  }
 
-    let new_flags = if nonblocking {
-      flags | libc::O_NONBLOCK
-    } else {
-      flags & !libc::O_NONBLOCK
+  /// Bound how long a blocking `recv_from` waits; on expiry it fails with
+  /// `WouldBlock`. `None` blocks indefinitely.
+  pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    let timeout = timeout.unwrap_or(Duration::ZERO);
+    let value = libc::timeval {
+      tv_sec: timeout.as_secs() as libc::time_t,
+      tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+    };
+    let ret = unsafe {
+      libc::setsockopt(
+        self.recv_fd.as_raw_fd(),
+        libc::SOL_SOCKET,
+        libc::SO_RCVTIMEO,
+        &value as *const _ as *const libc::c_void,
+        std::mem::size_of_val(&value) as libc::socklen_t,
+      )
     };
 
-    let ret = unsafe { libc::fcntl(self.fd.as_raw_fd(), libc::F_SETFL, new_flags) };
     if ret < 0 {
       Err(io::Error::last_os_error())
     } else {
@@ -153,8 +168,146 @@ impl RawSocket {
   }
 }
 
+/// The receive socket, which is the one worth polling for readiness
 impl AsRawFd for RawSocket {
   fn as_raw_fd(&self) -> RawFd {
-    self.fd.as_raw_fd()
+    self.recv_fd.as_raw_fd()
+  }
+}
+
+fn open_raw(protocol: libc::c_int) -> io::Result<OwnedFd> {
+  let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_RAW, protocol) };
+
+  if fd < 0 {
+    return Err(io::Error::last_os_error());
+  }
+
+  Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn set_int_option(
+  fd: &OwnedFd,
+  level: libc::c_int,
+  name: libc::c_int,
+  value: libc::c_int,
+) -> io::Result<()> {
+  let ret = unsafe {
+    libc::setsockopt(
+      fd.as_raw_fd(),
+      level,
+      name,
+      &value as *const _ as *const libc::c_void,
+      std::mem::size_of_val(&value) as libc::socklen_t,
+    )
+  };
+
+  if ret < 0 {
+    Err(io::Error::last_os_error())
+  } else {
+    Ok(())
+  }
+}
+
+fn set_fd_nonblocking(fd: &OwnedFd, nonblocking: bool) -> io::Result<()> {
+  let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL, 0) };
+  if flags < 0 {
+    return Err(io::Error::last_os_error());
+  }
+
+  let new_flags = if nonblocking {
+    flags | libc::O_NONBLOCK
+  } else {
+    flags & !libc::O_NONBLOCK
+  };
+
+  let ret = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, new_flags) };
+  if ret < 0 {
+    Err(io::Error::last_os_error())
+  } else {
+    Ok(())
+  }
+}
+
+/// BSD-derived stacks: `sin_len` and host-order `ip_len`/`ip_off`
+#[cfg(any(
+  target_os = "macos",
+  target_os = "ios",
+  target_os = "freebsd",
+  target_os = "openbsd",
+  target_os = "netbsd",
+  target_os = "dragonfly"
+))]
+mod platform {
+  use std::borrow::Cow;
+  use std::net::Ipv4Addr;
+
+  pub fn sockaddr_in(addr: Ipv4Addr) -> libc::sockaddr_in {
+    libc::sockaddr_in {
+      sin_len: std::mem::size_of::<libc::sockaddr_in>() as u8,
+      sin_family: libc::AF_INET as libc::sa_family_t,
+      sin_port: 0,
+      sin_addr: libc::in_addr {
+        s_addr: u32::from_ne_bytes(addr.octets()),
+      },
+      sin_zero: [0; 8],
+    }
+  }
+
+  /// Convert `ip_len` and `ip_off` to host byte order for `IP_HDRINCL`
+  pub fn outgoing(packet: &[u8]) -> Cow<'_, [u8]> {
+    if packet.len() < 20 {
+      return Cow::Borrowed(packet);
+    }
+    let mut out = packet.to_vec();
+    for field in [2, 6] {
+      let value = u16::from_be_bytes([out[field], out[field + 1]]);
+      out[field..field + 2].copy_from_slice(&value.to_ne_bytes());
+    }
+    Cow::Owned(out)
+  }
+
+  /// Restore network byte order; `ip_len` arrives in host order without the
+  /// IP header length
+  pub fn incoming(packet: &mut [u8]) {
+    if packet.len() < 20 {
+      return;
+    }
+    let header_len = ((packet[0] & 0x0F) as u16) * 4;
+    let payload_len = u16::from_ne_bytes([packet[2], packet[3]]);
+    let offset = u16::from_ne_bytes([packet[6], packet[7]]);
+    packet[2..4].copy_from_slice(&(payload_len + header_len).to_be_bytes());
+    packet[6..8].copy_from_slice(&offset.to_be_bytes());
   }
 }
+
+/// Linux and other stacks using the plain `sockaddr_in` layout and network
+/// byte order throughout
+#[cfg(not(any(
+  target_os = "macos",
+  target_os = "ios",
+  target_os = "freebsd",
+  target_os = "openbsd",
+  target_os = "netbsd",
+  target_os = "dragonfly"
+)))]
+mod platform {
+  use std::borrow::Cow;
+  use std::net::Ipv4Addr;
+
+  pub fn sockaddr_in(addr: Ipv4Addr) -> libc::sockaddr_in {
+    libc::sockaddr_in {
+      sin_family: libc::AF_INET as libc::sa_family_t,
+      sin_port: 0,
+      sin_addr: libc::in_addr {
+        s_addr: u32::from_ne_bytes(addr.octets()),
+      },
+      sin_zero: [0; 8],
+    }
+  }
+
+  pub fn outgoing(packet: &[u8]) -> Cow<'_, [u8]> {
+    Cow::Borrowed(packet)
+  }
+
+  pub fn incoming(_packet: &mut [u8]) {}
+}
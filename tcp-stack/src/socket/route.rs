@@ -0,0 +1,131 @@
+//! Route lookup before connecting
+//!
+//! A destination with no route would otherwise only show up as a
+//! handshake that times out after every SYN retry. `lookup` asks the
+//! routing table first. It fails at once with `NetworkUnreachable` or
+//! `HostUnreachable`, and otherwise names the source address and interface
+//! the connection will use.
+//!
+//! On Linux the lookup is an `RTM_GETROUTE` request over netlink, the same
+//! as `ip route get`. Elsewhere the source address comes from connecting a
+//! UDP socket, which routes without sending anything, and the interface is
+//! not known.
+
+use std::io;
+use std::net::Ipv4Addr;
+
+/// Where packets to a destination leave from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+  /// Source address the routing table picks
+  pub source: Ipv4Addr,
+  /// Outgoing interface, where the platform reports it
+  pub interface: Option<String>,
+  /// Next hop, unless the destination is on a local subnet
+  pub gateway: Option<Ipv4Addr>,
+}
+
+/// The route packets to `dst` would take
+pub fn lookup(dst: Ipv4Addr) -> io::Result<Route> {
+  #[cfg(target_os = "linux")]
+  {
+    let route = linux::lookup(dst)?;
+    if route.source.is_unspecified() {
+      // Some routes carry no preferred source; the kernel still picks one
+      return Ok(Route {
+        source: probe_source(dst)?,
+        ..route
+      });
+    }
+    Ok(route)
+  }
+  #[cfg(not(target_os = "linux"))]
+  {
+    Ok(Route {
+      source: probe_source(dst)?,
+      interface: None,
+      gateway: None,
+    })
+  }
+}
+
+/// Source address for `dst`, from a connected UDP socket
+fn probe_source(dst: Ipv4Addr) -> io::Result<Ipv4Addr> {
+  let probe = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+  probe.connect((dst, 9))?;
+  match probe.local_addr()? {
+    std::net::SocketAddr::V4(addr) => Ok(*addr.ip()),
+    std::net::SocketAddr::V6(_) => Err(io::Error::new(
+      io::ErrorKind::AddrNotAvailable,
+      "no IPv4 source address for destination",
+    )),
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+  use super::Route;
+  use crate::socket::netlink::{self, NLMSG_ERROR};
+  use std::io;
+  use std::net::Ipv4Addr;
+
+  /// Length of `struct rtmsg`
+  const RTMSG_LEN: usize = 12;
+
+  pub fn lookup(dst: Ipv4Addr) -> io::Result<Route> {
+    let fd = netlink::open(0)?;
+    netlink::send(&fd, &request(dst))?;
+    let mut buf = [0u8; 4096];
+    let reply = netlink::recv(&fd, &mut buf)?;
+    let (kind, body) = netlink::messages(reply)
+      .next()
+      .ok_or_else(netlink::malformed)?;
+    if kind == NLMSG_ERROR {
+      return Err(netlink::error(body).unwrap_or_else(netlink::malformed));
+    }
+    parse_route(kind, body)
+  }
+
+  /// `RTM_GETROUTE` for the host route to `dst`
+  fn request(dst: Ipv4Addr) -> Vec<u8> {
+    // rtmsg: family, dst_len, src_len, tos, table, protocol, scope, type,
+    // flags
+    let mut body = vec![libc::AF_INET as u8, 32, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    body.extend_from_slice(&8u16.to_ne_bytes());
+    body.extend_from_slice(&libc::RTA_DST.to_ne_bytes());
+    body.extend_from_slice(&dst.octets());
+    netlink::message(libc::RTM_GETROUTE, 0, &body)
+  }
+
+  fn parse_route(kind: u16, body: &[u8]) -> io::Result<Route> {
+    if kind != libc::RTM_NEWROUTE || body.len() < RTMSG_LEN {
+      return Err(netlink::malformed());
+    }
+    match body[7] {
+      libc::RTN_UNREACHABLE | libc::RTN_BLACKHOLE => {
+        return Err(io::Error::from_raw_os_error(libc::EHOSTUNREACH))
+      }
+      libc::RTN_PROHIBIT => return Err(io::Error::from_raw_os_error(libc::EACCES)),
+      _ => {}
+    }
+
+    let mut route = Route {
+      source: Ipv4Addr::UNSPECIFIED,
+      interface: None,
+      gateway: None,
+    };
+    for (kind, value) in netlink::attributes(&body[RTMSG_LEN..]) {
+      match kind {
+        libc::RTA_PREFSRC => route.source = netlink::ipv4(value).unwrap_or(route.source),
+        libc::RTA_GATEWAY => route.gateway = netlink::ipv4(value),
+        libc::RTA_OIF => {
+          if let &[a, b, c, d] = value {
+            route.interface = netlink::interface_name(u32::from_ne_bytes([a, b, c, d]));
+          }
+        }
+        _ => {}
+      }
+    }
+    Ok(route)
+  }
+}
@@ -0,0 +1,210 @@
+//! Interface and address watcher over rtnetlink
+//!
+//! `NetworkWatcher` subscribes to the kernel's link and IPv4 address
+//! notifications and turns the ones that strand connections into
+//! `NetworkEvent`s: an interface going down or away, or an address being
+//! removed. Handing them to `TcpConnection::on_network_event` or
+//! `TcpStack::on_network_event` closes the affected connections at once
+//! with `CloseReason::NetworkDown`. Without a watcher they would retransmit
+//! into the void until their retry limits ran out.
+//!
+//! The watcher reads the current addresses when it starts, so a link going
+//! down can name the addresses it took with it.
+
+use super::netlink::{self, NLMSG_DONE, NLMSG_ERROR, NLM_F_DUMP};
+use crate::connection::NetworkEvent;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::unix::prelude::*;
+use std::time::Duration;
+
+/// Length of `struct ifinfomsg`
+const IFINFOMSG_LEN: usize = 16;
+/// Length of `struct ifaddrmsg`
+const IFADDRMSG_LEN: usize = 8;
+
+/// What the watcher knows of one interface
+#[derive(Debug, Default)]
+struct Link {
+  name: String,
+  up: bool,
+  addrs: Vec<Ipv4Addr>,
+}
+
+/// Reports interfaces going down and addresses being removed
+pub struct NetworkWatcher {
+  fd: OwnedFd,
+  links: BTreeMap<u32, Link>,
+  buf: Vec<u8>,
+}
+
+impl NetworkWatcher {
+  /// Subscribe to link and IPv4 address changes and read the current state
+  pub fn new() -> io::Result<Self> {
+    let groups = (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR) as u32;
+    let mut watcher = Self {
+      fd: netlink::open(groups)?,
+      links: BTreeMap::new(),
+      buf: vec![0; 64 * 1024],
+    };
+    watcher.dump(libc::RTM_GETLINK, &[0; IFINFOMSG_LEN])?;
+    watcher.dump(
+      libc::RTM_GETADDR,
+      &[libc::AF_INET as u8, 0, 0, 0, 0, 0, 0, 0],
+    )?;
+    Ok(watcher)
+  }
+
+  /// IPv4 addresses the watcher knows on interface `index`
+  pub fn addresses(&self, index: u32) -> &[Ipv4Addr] {
+    self.links.get(&index).map_or(&[], |link| &link.addrs)
+  }
+
+  pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(self.fd.as_raw_fd(), libc::F_GETFL, 0) };
+    if flags < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    let flags = if nonblocking {
+      flags | libc::O_NONBLOCK
+    } else {
+      flags & !libc::O_NONBLOCK
+    };
+    if unsafe { libc::fcntl(self.fd.as_raw_fd(), libc::F_SETFL, flags) } < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(())
+  }
+
+  /// Bound how long a blocking `recv` waits; on expiry it fails with
+  /// `WouldBlock`. `None` blocks indefinitely
+  pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    let timeout = timeout.unwrap_or(Duration::ZERO);
+    let value = libc::timeval {
+      tv_sec: timeout.as_secs() as libc::time_t,
+      tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+    };
+    let ret = unsafe {
+      libc::setsockopt(
+        self.fd.as_raw_fd(),
+        libc::SOL_SOCKET,
+        libc::SO_RCVTIMEO,
+        &value as *const _ as *const libc::c_void,
+        std::mem::size_of_val(&value) as libc::socklen_t,
+      )
+    };
+    if ret < 0 {
+      Err(io::Error::last_os_error())
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Wait for the next notification and return the events it carries,
+  /// which may be none
+  pub fn recv(&mut self) -> io::Result<Vec<NetworkEvent>> {
+    let mut buf = std::mem::take(&mut self.buf);
+    let result = netlink::recv(&self.fd, &mut buf).map(|data| {
+      let mut events = Vec::new();
+      for (kind, body) in netlink::messages(data) {
+        self.apply(kind, body, &mut events);
+      }
+      events
+    });
+    self.buf = buf;
+    result
+  }
+
+  /// Request a dump and take in every reply until it is done
+  fn dump(&mut self, kind: u16, body: &[u8]) -> io::Result<()> {
+    netlink::send(&self.fd, &netlink::message(kind, NLM_F_DUMP, body))?;
+    let mut buf = std::mem::take(&mut self.buf);
+    let result = (|| loop {
+      let data = netlink::recv(&self.fd, &mut buf)?;
+      for (kind, body) in netlink::messages(data) {
+        match kind {
+          NLMSG_DONE => return Ok(()),
+          NLMSG_ERROR => {
+            if let Some(e) = netlink::error(body) {
+              return Err(e);
+            }
+          }
+          // Nothing is reported while reading the starting state
+          kind => self.apply(kind, body, &mut Vec::new()),
+        }
+      }
+    })();
+    self.buf = buf;
+    result
+  }
+
+  /// Update what is known from one message, noting what it took away
+  fn apply(&mut self, kind: u16, body: &[u8], events: &mut Vec<NetworkEvent>) {
+    match kind {
+      libc::RTM_NEWLINK | libc::RTM_DELLINK if body.len() >= IFINFOMSG_LEN => {
+        let index = u32::from_ne_bytes([body[4], body[5], body[6], body[7]]);
+        let flags = u32::from_ne_bytes([body[8], body[9], body[10], body[11]]);
+        let up = kind == libc::RTM_NEWLINK
+          && flags & (libc::IFF_UP | libc::IFF_RUNNING) as u32
+            == (libc::IFF_UP | libc::IFF_RUNNING) as u32;
+        let link = self.links.entry(index).or_default();
+        for (attr, value) in netlink::attributes(&body[IFINFOMSG_LEN..]) {
+          if attr == libc::IFLA_IFNAME {
+            let name = value.split(|&b| b == 0).next().unwrap_or_default();
+            link.name = String::from_utf8_lossy(name).into_owned();
+          }
+        }
+        if link.up && !up {
+          debug!("Interface {} went down", link.name);
+          events.push(NetworkEvent::LinkDown {
+            index,
+            name: link.name.clone(),
+            addrs: link.addrs.clone(),
+          });
+        }
+        link.up = up;
+        if kind == libc::RTM_DELLINK {
+          self.links.remove(&index);
+        }
+      }
+      libc::RTM_NEWADDR | libc::RTM_DELADDR if body.len() >= IFADDRMSG_LEN => {
+        if body[0] != libc::AF_INET as u8 {
+          return;
+        }
+        let index = u32::from_ne_bytes([body[4], body[5], body[6], body[7]]);
+        // IFA_LOCAL is the address itself; IFA_ADDRESS is the peer's on
+        // point-to-point links and the same otherwise
+        let mut local = None;
+        let mut address = None;
+        for (attr, value) in netlink::attributes(&body[IFADDRMSG_LEN..]) {
+          match attr {
+            libc::IFA_LOCAL => local = netlink::ipv4(value),
+            libc::IFA_ADDRESS => address = netlink::ipv4(value),
+            _ => {}
+          }
+        }
+        let Some(addr) = local.or(address) else {
+          return;
+        };
+        let link = self.links.entry(index).or_default();
+        if kind == libc::RTM_NEWADDR {
+          if !link.addrs.contains(&addr) {
+            link.addrs.push(addr);
+          }
+        } else {
+          link.addrs.retain(|known| *known != addr);
+          debug!("Address {} removed from {}", addr, link.name);
+          events.push(NetworkEvent::AddressRemoved { index, addr });
+        }
+      }
+      _ => {}
+    }
+  }
+}
+
+impl AsRawFd for NetworkWatcher {
+  fn as_raw_fd(&self) -> RawFd {
+    self.fd.as_raw_fd()
+  }
+}
@@ -0,0 +1,1022 @@
+//! Sans-IO protocol engine
+//!
+//! `TcpStack` runs TCP for one local address without doing any I/O, the way
+//! quinn-proto does for QUIC. The application owns the loop: it passes every
+//! IPv4 packet it receives to `handle_packet`, sends each packet
+//! `poll_transmit` returns until it returns `None`, and calls back no later
+//! than `poll_timeout`. The protocol can then run over a tun device, a
+//! DPDK queue or an existing event loop instead of the raw socket
+//! `TcpConnection` reads.
+//!
+//! Each connection is processed by `connection::Engine`; the stack carries
+//! out the `Action`s it returns. Time is always passed in, so the stack
+//! builds without `std`.
+//!
+//! Two `PacketLimiter`s, unlimited by default, keep a flood from taking
+//! over the loop: one for SYNs to listening ports, one for the resets and
+//! challenge ACKs sent in answer to unexpected segments.
+//!
+//! Connections' packets leave through a `FairScheduler`, so a bulk transfer
+//! cannot starve the others; weights set with `scheduler_mut` share the
+//! link unevenly, and a connection given a higher `Priority` goes ahead of
+//! lower ones. Resets for segments matching no connection go first.
+//!
+//! ICMP destination unreachables quoting a connection's segments are passed
+//! to its engine, so a handshake to a closed port or unreachable host fails
+//! with `CloseReason::IcmpError` instead of retrying until it times out.
+//!
+//! A SYN to a listening port opens only a `RequestSock`, which answers with
+//! SYN-ACKs from the stack's own queue. The full connection, with its
+//! buffers and congestion state, is built when the handshake's final ACK
+//! arrives, so a SYN flood costs a few dozen bytes per SYN.
+//!
+//! A `NetworkEvent` that removes the stack's address closes its
+//! connections at once with `CloseReason::NetworkDown`.
+//!
+//! What new connections get is a `StackConfig`, which a
+//! `StackConfigHandle` can change while the stack runs.
+
+use crate::config::StackConfig;
+#[cfg(feature = "std")]
+use crate::config::StackConfigHandle;
+use crate::congestion::CongestionAlgorithm;
+use crate::connection::engine::{ICMP_HARD_ERRORS, TIME_WAIT_DURATION};
+use crate::connection::{
+  stats, Action, CloseReason, ConnectionSnapshot, ControlBlock, DropCounters, DropReason,
+  Engine, NetworkEvent, RequestSock, TcpState, TimerKind,
+};
+use crate::flow_control::{FairScheduler, PacketLimiter, Priority};
+use crate::packet::{
+  CorruptStage, Corruption, IpOptionsPolicy, Ipv4Header, PacketFilter, TcpFlags, TcpHeader,
+};
+use crate::replay::PcapWriter;
+use crate::utils::{
+  calculate_checksum, fnv1a, Instant, SeqNumber, SimRng, Slab, SlabKey, FNV_OFFSET,
+};
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use bytes::Bytes;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+/// Half-open connections kept before new SYNs are dropped
+pub const DEFAULT_BACKLOG: usize = 128;
+
+/// First port of the ephemeral range (RFC 6335)
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// ICMP type of a destination unreachable
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+
+/// What a `TcpStack` is holding on to, for spotting leaks. Once every
+/// connection is closed and removed it is all zeros
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StackUsage {
+  /// Connections held, closed ones included until `remove`d
+  pub connections: usize,
+  /// Half-open connections waiting for the handshake's final ACK
+  pub half_open: usize,
+  /// Entries in the flow table that demultiplexes segments
+  pub flows: usize,
+  /// Established connections waiting for `accept`
+  pub accept_queue: usize,
+  /// Packets waiting for `poll_transmit`
+  pub queued_packets: usize,
+  /// Connections in the scheduler's round
+  pub scheduled: usize,
+  /// Connections with a timer armed
+  pub timers: usize,
+}
+
+/// Names a connection of a `TcpStack`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionHandle(pub SlabKey);
+
+/// Protocol engine for every connection of one local address
+pub struct TcpStack {
+  addr: Ipv4Addr,
+  seed: u64,
+  connections: Slab<Connection>,
+  /// Open connections by (local, remote) address
+  flows: BTreeMap<(SocketAddrV4, SocketAddrV4), ConnectionHandle>,
+  /// Half-open connections by (local, remote) address
+  requests: BTreeMap<(SocketAddrV4, SocketAddrV4), RequestSock>,
+  listeners: BTreeSet<u16>,
+  config: StackConfig,
+  /// Where `config` changes come from, once handed out
+  #[cfg(feature = "std")]
+  config_handle: Option<StackConfigHandle>,
+  /// Version of the handle's settings `config` holds
+  #[cfg(feature = "std")]
+  config_version: u64,
+  /// Packets received and sent while `config.capture` is on
+  capture: PcapWriter,
+  /// Only packets passing this are captured, if set
+  capture_filter: Option<PacketFilter>,
+  /// Picks the packets `config.corruption` damages
+  corruption_rng: SimRng,
+  /// Packets corrupted on purpose
+  corrupted: u64,
+  /// Policy on IP options for connections opened from now on
+  ip_options_policy: IpOptionsPolicy,
+  accept_queue: VecDeque<ConnectionHandle>,
+  /// Packets sent on behalf of no connection
+  transmit: VecDeque<Vec<u8>>,
+  /// Packets of each connection
+  scheduler: FairScheduler<ConnectionHandle>,
+  next_port: u16,
+  drops: DropCounters,
+  syn_limiter: PacketLimiter,
+  reply_limiter: PacketLimiter,
+}
+
+impl TcpStack {
+  /// An engine for `addr`. `seed` keys initial sequence numbers and should
+  /// be random
+  pub fn new(addr: Ipv4Addr, seed: u64) -> Self {
+    let range = u64::from(u16::MAX - EPHEMERAL_PORT_START) + 1;
+    Self {
+      addr,
+      seed,
+      connections: Slab::new(),
+      flows: BTreeMap::new(),
+      requests: BTreeMap::new(),
+      listeners: BTreeSet::new(),
+      config: StackConfig::new(),
+      #[cfg(feature = "std")]
+      config_handle: None,
+      #[cfg(feature = "std")]
+      config_version: 0,
+      capture: PcapWriter::new(),
+      capture_filter: None,
+      corruption_rng: SimRng::new(seed),
+      corrupted: 0,
+      ip_options_policy: IpOptionsPolicy::Accept,
+      accept_queue: VecDeque::new(),
+      transmit: VecDeque::new(),
+      scheduler: FairScheduler::new(),
+      next_port: EPHEMERAL_PORT_START + (seed % range) as u16,
+      drops: DropCounters::new(),
+      syn_limiter: PacketLimiter::new(),
+      reply_limiter: PacketLimiter::new(),
+    }
+  }
+
+  pub fn local_addr(&self) -> Ipv4Addr {
+    self.addr
+  }
+
+  /// Packets dropped before reaching a connection
+  pub fn drops(&self) -> &DropCounters {
+    &self.drops
+  }
+
+  pub fn usage(&self) -> StackUsage {
+    StackUsage {
+      connections: self.connections.len(),
+      half_open: self.requests.len(),
+      flows: self.flows.len(),
+      accept_queue: self.accept_queue.len(),
+      queued_packets: self.transmit.len() + self.scheduler.len(),
+      scheduled: self.scheduler.flow_count(),
+      timers: self
+        .connections
+        .iter()
+        .map(|(_, conn)| conn)
+        .filter(|conn| conn.deadline().is_some())
+        .count(),
+    }
+  }
+
+  pub fn set_backlog(&mut self, backlog: usize) {
+    self.update_config(|config| config.backlog = backlog);
+  }
+
+  /// Receive buffer for connections opened from now on
+  pub fn set_recv_buffer(&mut self, size: usize) {
+    self.update_config(|config| config.recv_buffer = size);
+  }
+
+  /// Settings for connections opened from now on
+  pub fn config(&self) -> &StackConfig {
+    &self.config
+  }
+
+  pub fn set_config(&mut self, config: StackConfig) {
+    self.update_config(|current| *current = config);
+  }
+
+  /// A handle for changing the settings while the stack runs, e.g. from
+  /// another thread. Every handle shares the same settings
+  #[cfg(feature = "std")]
+  pub fn config_handle(&mut self) -> StackConfigHandle {
+    self
+      .config_handle
+      .get_or_insert_with(|| StackConfigHandle::new(self.config.clone()))
+      .clone()
+  }
+
+  /// The packets captured since the last call, as a pcap file
+  pub fn take_capture(&mut self) -> Vec<u8> {
+    self.capture.take()
+  }
+
+  /// Capture only the packets `filter` passes, or all of them with `None`
+  pub fn set_capture_filter(&mut self, filter: Option<PacketFilter>) {
+    self.capture_filter = filter;
+  }
+
+  /// Corrupt `fraction` of the packets sent from now on, from 0 to 1, for
+  /// self-checks of the receive path; 0 stops. See `packet::corrupt`
+  pub fn set_corruption(&mut self, fraction: f64, stage: CorruptStage) {
+    let corruption = (fraction > 0.0).then(|| Corruption::new(fraction, stage));
+    self.update_config(|config| config.corruption = corruption);
+  }
+
+  /// Packets corrupted on purpose so far
+  pub fn corrupted(&self) -> u64 {
+    self.corrupted
+  }
+
+  /// Add `packet` to the capture if it is on and the filter passes it
+  fn capture_packet(&mut self, now: Instant, packet: &[u8]) {
+    if !self.config.capture {
+      return;
+    }
+    if self
+      .capture_filter
+      .as_ref()
+      .is_none_or(|filter| filter.matches(packet))
+    {
+      self.capture.write(now, packet);
+    }
+  }
+
+  /// What becomes of packets carrying IP options, for SYNs to listening
+  /// ports and connections opened from now on
+  pub fn set_ip_options_policy(&mut self, policy: IpOptionsPolicy) {
+    self.ip_options_policy = policy;
+  }
+
+  /// How connections share the link: weights and quantum
+  pub fn scheduler_mut(&mut self) -> &mut FairScheduler<ConnectionHandle> {
+    &mut self.scheduler
+  }
+
+  /// Serve the connection's packets in `priority`'s class and mark them
+  /// with its DSCP; false if there is no such connection
+  pub fn set_priority(&mut self, handle: ConnectionHandle, priority: Priority) -> bool {
+    let Some(conn) = self.connections.get_mut(handle.0) else {
+      return false;
+    };
+    conn.control.dscp = priority.dscp();
+    self.scheduler.set_priority(handle, priority);
+    true
+  }
+
+  /// Limits on SYNs to listening ports; those over it are dropped
+  pub fn syn_limiter_mut(&mut self) -> &mut PacketLimiter {
+    &mut self.syn_limiter
+  }
+
+  /// Limits on the resets and challenge ACKs the stack answers with; those
+  /// over it are not sent
+  pub fn reply_limiter_mut(&mut self) -> &mut PacketLimiter {
+    &mut self.reply_limiter
+  }
+
+  /// Accept connections on `port`; false if already listening there
+  pub fn listen(&mut self, port: u16) -> bool {
+    self.listeners.insert(port)
+  }
+
+  /// Stop accepting new connections on `port`
+  pub fn unlisten(&mut self, port: u16) -> bool {
+    self.listeners.remove(&port)
+  }
+
+  /// The next connection a listener completed the handshake for
+  pub fn accept(&mut self) -> Option<ConnectionHandle> {
+    self.accept_queue.pop_front()
+  }
+
+  /// Start an active open to `remote` from an ephemeral port, or `None` if
+  /// every port is in use towards it
+  pub fn connect(
+    &mut self,
+    remote: SocketAddrV4,
+    now: Instant,
+  ) -> Option<ConnectionHandle> {
+    self.reload_config();
+    let local = SocketAddrV4::new(self.addr, self.ephemeral_port(remote)?);
+    let mut conn = self.open(local, remote, now);
+    let actions = conn.engine().open(now);
+    Some(self.insert(conn, actions))
+  }
+
+  pub fn state(&self, handle: ConnectionHandle) -> Option<TcpState> {
+    Some(self.connections.get(handle.0)?.state())
+  }
+
+  /// Save a connection for `restore` on this or another stack, or `None`
+  /// unless both sides are synchronized
+  pub fn snapshot(&self, handle: ConnectionHandle) -> Option<ConnectionSnapshot> {
+    let conn = self.connections.get(handle.0)?;
+    ConnectionSnapshot::capture(conn.local, conn.remote, &conn.control)
+  }
+
+  /// Resume a connection saved by `snapshot`, or `None` if it is for
+  /// another address or its 4-tuple is taken
+  pub fn restore(
+    &mut self,
+    snapshot: &ConnectionSnapshot,
+    now: Instant,
+  ) -> Option<ConnectionHandle> {
+    let (local, remote) = (snapshot.local, snapshot.remote);
+    if *local.ip() != self.addr || self.flows.contains_key(&(local, remote)) {
+      return None;
+    }
+    let control = snapshot.restore(now);
+    let time_wait =
+      (control.state == TcpState::TimeWait).then(|| now + TIME_WAIT_DURATION);
+    let conn = Connection {
+      local,
+      remote,
+      control,
+      closing: false,
+      time_wait,
+    };
+    Some(self.insert(conn, Vec::new()))
+  }
+
+  /// Why the connection closed, once it has
+  pub fn close_reason(&self, handle: ConnectionHandle) -> Option<CloseReason> {
+    self.connections.get(handle.0)?.control.close_reason
+  }
+
+  pub fn remote_addr(&self, handle: ConnectionHandle) -> Option<SocketAddrV4> {
+    Some(self.connections.get(handle.0)?.remote)
+  }
+
+  /// The connection's control block, for stats and tuning
+  pub fn control(&self, handle: ConnectionHandle) -> Option<&ControlBlock> {
+    Some(&self.connections.get(handle.0)?.control)
+  }
+
+  pub fn control_mut(&mut self, handle: ConnectionHandle) -> Option<&mut ControlBlock> {
+    Some(&mut self.connections.get_mut(handle.0)?.control)
+  }
+
+  /// Queue `data` for sending, returning how much fit in the send buffer.
+  /// Nothing is accepted before the handshake completes or after `close`
+  pub fn send(&mut self, handle: ConnectionHandle, data: &[u8]) -> usize {
+    match self.connections.get_mut(handle.0) {
+      Some(conn) if conn.writable() => conn.control.write(data),
+      _ => 0,
+    }
+  }
+
+  /// `send` for data already in a `Bytes`: segments are sliced from it and
+  /// it is held for retransmission without being copied. Returns how much
+  /// was taken, the rest to be offered again as `data.slice(taken..)`
+  pub fn send_bytes(&mut self, handle: ConnectionHandle, data: Bytes) -> usize {
+    match self.connections.get_mut(handle.0) {
+      Some(conn) if conn.writable() => conn.control.write_bytes(data),
+      _ => 0,
+    }
+  }
+
+  /// Read received bytes into `buf`. Zero with the peer closed
+  /// (`CloseWait` or later) means end of stream
+  pub fn recv(
+    &mut self,
+    handle: ConnectionHandle,
+    buf: &mut [u8],
+    now: Instant,
+  ) -> usize {
+    let Some(conn) = self.connections.get_mut(handle.0) else {
+      return 0;
+    };
+    let len = conn.control.read(buf);
+    if len > 0 {
+      let actions = conn.engine().update_window(now);
+      conn.execute(actions, self.scheduler.queue(handle));
+    }
+    len
+  }
+
+  /// Take everything readable as the `Bytes` chunks it arrived in, without
+  /// copying. No chunks with the peer closed means end of stream, as for
+  /// `recv`
+  pub fn recv_bytes(&mut self, handle: ConnectionHandle, now: Instant) -> Vec<Bytes> {
+    let Some(conn) = self.connections.get_mut(handle.0) else {
+      return Vec::new();
+    };
+    let chunks = conn.control.read_bytes();
+    if !chunks.is_empty() {
+      let actions = conn.engine().update_window(now);
+      conn.execute(actions, self.scheduler.queue(handle));
+    }
+    chunks
+  }
+
+  /// Close our side: the FIN follows the data already queued. A connection
+  /// still in SYN-SENT just closes
+  pub fn close(&mut self, handle: ConnectionHandle) {
+    let Some(conn) = self.connections.get_mut(handle.0) else {
+      return;
+    };
+    match conn.state() {
+      TcpState::SynSent => {
+        conn.control.retransmit.clear();
+        conn.control.state = TcpState::Closed;
+        conn.control.close_reason = Some(CloseReason::UserAbort);
+      }
+      TcpState::SynReceived | TcpState::Established | TcpState::CloseWait => {
+        conn.closing = true;
+      }
+      _ => {}
+    }
+    self.reap_one(handle);
+  }
+
+  /// Reset the connection and forget it
+  pub fn abort(&mut self, handle: ConnectionHandle) {
+    let Some(mut conn) = self.connections.remove(handle.0) else {
+      return;
+    };
+    if !matches!(
+      conn.state(),
+      TcpState::Closed | TcpState::SynSent | TcpState::TimeWait
+    ) {
+      // The connection is gone, so its reset is the stack's to send
+      let reset = conn.engine().reset();
+      conn.execute(vec![reset], &mut self.transmit);
+    }
+    self.scheduler.remove(handle);
+    self.flows.remove(&(conn.local, conn.remote));
+    self.accept_queue.retain(|queued| *queued != handle);
+  }
+
+  /// Forget a connection that has closed; false if it is still open
+  pub fn remove(&mut self, handle: ConnectionHandle) -> bool {
+    if self.state(handle) != Some(TcpState::Closed) {
+      return false;
+    }
+    self.connections.remove(handle.0);
+    self.scheduler.remove(handle);
+    true
+  }
+
+  /// Process an IPv4 packet received for this stack
+  pub fn handle_packet(&mut self, packet: &[u8], now: Instant) {
+    self.reload_config();
+    self.capture_packet(now, packet);
+    let Some((ip, segment)) = Ipv4Header::parse(packet) else {
+      return;
+    };
+    if ip.dst_addr != self.addr {
+      return;
+    }
+    if ip.protocol == Ipv4Header::PROTOCOL_ICMP {
+      self.handle_icmp(segment, now);
+      return;
+    }
+    if ip.protocol != Ipv4Header::PROTOCOL_TCP {
+      return;
+    }
+    if !TcpHeader::verify_checksum(ip.src_addr.into(), ip.dst_addr.into(), segment) {
+      self.drops.record(DropReason::BadChecksum);
+      return;
+    }
+    let Some((tcp, payload)) = TcpHeader::parse(segment) else {
+      self.drops.record(DropReason::MalformedOptions);
+      return;
+    };
+    let local = SocketAddrV4::new(ip.dst_addr, tcp.dst_port);
+    let remote = SocketAddrV4::new(ip.src_addr, tcp.src_port);
+
+    if let Some(&handle) = self.flows.get(&(local, remote)) {
+      let conn = self
+        .connections
+        .get_mut(handle.0)
+        .expect("every flow names a connection");
+      if conn.engine().on_receive(&ip) {
+        let actions = Engine::new(conn.local, conn.remote, &mut conn.control)
+          .with_reply_limiter(&mut self.reply_limiter)
+          .on_segment(&tcp, payload, now);
+        conn.execute(actions, self.scheduler.queue(handle));
+      }
+      self.reap_one(handle);
+      return;
+    }
+    if self.requests.contains_key(&(local, remote)) {
+      self.handle_request(local, remote, &ip, &tcp, payload, now);
+      return;
+    }
+
+    let flags = tcp.flags;
+    if flags.is_syn()
+      && !flags.is_ack()
+      && !flags.is_rst()
+      && self.listeners.contains(&local.port())
+    {
+      if !self.syn_limiter.allow(ip.src_addr, now) {
+        self.drops.record(DropReason::RateLimited);
+        return;
+      }
+      self.open_passive(local, remote, &ip, &tcp, now);
+      return;
+    }
+    self.drops.record(DropReason::NoConnection);
+    if !flags.is_rst() && self.reply_limiter.allow(ip.src_addr, now) {
+      self.reset(local, remote, &tcp, payload.len());
+    }
+  }
+
+  /// Pass a destination unreachable quoting one of our segments to its
+  /// connection; other ICMP messages are ignored
+  fn handle_icmp(&mut self, message: &[u8], now: Instant) {
+    // Type, code, checksum and four unused bytes, then the IP header and at
+    // least the first eight bytes of the datagram we sent
+    if message.len() < 8
+      || message[0] != ICMP_DEST_UNREACHABLE
+      || calculate_checksum(message) != 0
+    {
+      return;
+    }
+    let Some((quoted, tcp)) = Ipv4Header::parse(&message[8..]) else {
+      return;
+    };
+    if quoted.protocol != Ipv4Header::PROTOCOL_TCP
+      || quoted.src_addr != self.addr
+      || tcp.len() < 8
+    {
+      return;
+    }
+    let local = SocketAddrV4::new(quoted.src_addr, u16::from_be_bytes([tcp[0], tcp[1]]));
+    let remote = SocketAddrV4::new(quoted.dst_addr, u16::from_be_bytes([tcp[2], tcp[3]]));
+    let seq = SeqNumber(u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]));
+    let Some(&handle) = self.flows.get(&(local, remote)) else {
+      // A half-open connection just goes, as it would after its last
+      // SYN-ACK timed out
+      if ICMP_HARD_ERRORS.contains(&message[1])
+        && self
+          .requests
+          .get(&(local, remote))
+          .is_some_and(|request| request.iss == seq)
+      {
+        self.requests.remove(&(local, remote));
+      }
+      return;
+    };
+    let conn = self
+      .connections
+      .get_mut(handle.0)
+      .expect("every flow names a connection");
+    let actions = conn.engine().on_icmp_unreachable(message[1], seq, now);
+    conn.execute(actions, self.scheduler.queue(handle));
+    self.reap_one(handle);
+  }
+
+  /// Close every connection without a reset if `event` took the stack's
+  /// address away, discarding the packets queued for it. Returns the
+  /// number of connections closed with `CloseReason::NetworkDown`
+  pub fn on_network_event(&mut self, event: &NetworkEvent, now: Instant) -> usize {
+    if !event.affects(self.addr) {
+      return 0;
+    }
+    let mut closed = self.requests.len();
+    self.requests.clear();
+    for (key, conn) in self.connections.iter_mut() {
+      let handle = ConnectionHandle(key);
+      if !matches!(conn.state(), TcpState::Closed | TcpState::TimeWait) {
+        closed += 1;
+      }
+      let actions = conn.engine().on_network_down(now);
+      let queue = self.scheduler.queue(handle);
+      conn.execute(actions, queue);
+      queue.clear();
+    }
+    self.transmit.clear();
+    self.reap();
+    closed
+  }
+
+  /// The next IPv4 packet to send, after running every timer due by `now`
+  pub fn poll_transmit(&mut self, now: Instant) -> Option<Vec<u8>> {
+    self.reload_config();
+    // Popping first keeps this O(1) per packet: checking the scheduler for
+    // emptiness walks every flow
+    let mut packet = match self.pop_queued() {
+      Some(packet) => packet,
+      None => {
+        for (key, conn) in self.connections.iter_mut() {
+          let handle = ConnectionHandle(key);
+          conn.poll(now, self.scheduler.queue(handle));
+        }
+        self.poll_requests(now);
+        self.reap();
+        self.pop_queued()?
+      }
+    };
+    if let Some(corruption) = &self.config.corruption {
+      if corruption.apply(&mut packet, &mut self.corruption_rng) {
+        self.corrupted += 1;
+      }
+    }
+    self.capture_packet(now, &packet);
+    Some(packet)
+  }
+
+  /// The stack's own packets first, then the connections' in turn
+  fn pop_queued(&mut self) -> Option<Vec<u8>> {
+    self
+      .transmit
+      .pop_front()
+      .or_else(|| self.scheduler.pop().map(|(_, packet)| packet))
+  }
+
+  /// Fire the timers of every connection with one due by `now`, queueing
+  /// the packets they send for `poll_transmit`. Connections with nothing
+  /// due are passed over. Returns the number of packets queued
+  pub fn on_tick(&mut self, now: Instant) -> usize {
+    let queued = self.scheduler.len() + self.transmit.len();
+    for (key, conn) in self.connections.iter_mut() {
+      let handle = ConnectionHandle(key);
+      if conn.deadline().is_some_and(|deadline| deadline <= now) {
+        conn.on_tick(now, self.scheduler.queue(handle));
+      }
+    }
+    self.poll_requests(now);
+    self.reap();
+    self.scheduler.len() + self.transmit.len() - queued
+  }
+
+  /// When `poll_transmit` or `on_tick` next has timer work to do
+  pub fn poll_timeout(&self) -> Option<Instant> {
+    self
+      .connections
+      .iter()
+      .filter_map(|(_, conn)| conn.deadline())
+      .chain(self.requests.values().map(|request| request.deadline))
+      .min()
+  }
+
+  /// Initial sequence number for a flow: the configured generator's, or a
+  /// keyed hash of its addresses plus a clock ticking every 4 microseconds
+  /// (RFC 6528)
+  fn initial_seq(
+    &self,
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    now: Instant,
+  ) -> SeqNumber {
+    if let Some(isn) = &self.config.isn {
+      return isn.initial_seq(local, remote, now);
+    }
+    let bytes = local
+      .ip()
+      .octets()
+      .into_iter()
+      .chain(local.port().to_be_bytes())
+      .chain(remote.ip().octets())
+      .chain(remote.port().to_be_bytes());
+    let hash = fnv1a(FNV_OFFSET ^ self.seed, bytes);
+    SeqNumber((hash as u32).wrapping_add((now.total_micros() / 4) as u32))
+  }
+
+  /// Change `config`, through the handle if there is one so it stays the
+  /// source of truth
+  fn update_config(&mut self, change: impl FnOnce(&mut StackConfig)) {
+    #[cfg(feature = "std")]
+    if let Some(handle) = &self.config_handle {
+      handle.update(change);
+      self.reload_config();
+      return;
+    }
+    change(&mut self.config);
+  }
+
+  /// Take up changes made through a `StackConfigHandle`
+  fn reload_config(&mut self) {
+    #[cfg(feature = "std")]
+    if let Some(handle) = &self.config_handle {
+      if let Some((config, version)) = handle.changed_since(self.config_version) {
+        self.config = config;
+        self.config_version = version;
+      }
+    }
+  }
+
+  /// A free ephemeral port towards `remote`
+  fn ephemeral_port(&mut self, remote: SocketAddrV4) -> Option<u16> {
+    for _ in EPHEMERAL_PORT_START..=u16::MAX {
+      let port = self.next_port;
+      self.next_port = port.checked_add(1).unwrap_or(EPHEMERAL_PORT_START);
+      let local = SocketAddrV4::new(self.addr, port);
+      if !self.listeners.contains(&port) && !self.flows.contains_key(&(local, remote)) {
+        return Some(port);
+      }
+    }
+    None
+  }
+
+  fn open(&self, local: SocketAddrV4, remote: SocketAddrV4, now: Instant) -> Connection {
+    let iss = self.initial_seq(local, remote, now);
+    let mut control = ControlBlock::with_initial_seq(iss, now);
+    match self.config.congestion {
+      CongestionAlgorithm::NewReno => {}
+    }
+    if let Some(policy) = self.config.keepalive {
+      control.keepalive.set_policy(policy);
+      control.keepalive.set_enabled(true);
+    }
+    control.set_recv_buffer(self.config.recv_buffer);
+    control.send_buffer_limit = self.config.send_buffer;
+    control.fin_wait2_timeout = self.config.fin_wait2_timeout;
+    control.retransmit.set_limits(self.config.retry_limits);
+    control.ip_options_policy = self.ip_options_policy;
+    control.timestamp_clock = self.config.timestamp_clock.clone();
+    if let Some(profile) = self.config.profile {
+      control.set_profile(profile);
+    }
+    Connection {
+      local,
+      remote,
+      control,
+      closing: false,
+      time_wait: None,
+    }
+  }
+
+  /// Add `conn`, carrying out the `actions` that opened it
+  fn insert(&mut self, conn: Connection, actions: Vec<Action>) -> ConnectionHandle {
+    let flow = (conn.local, conn.remote);
+    let handle = ConnectionHandle(self.connections.insert(conn));
+    let conn = self.connections.get_mut(handle.0).expect("just inserted");
+    conn.execute(actions, self.scheduler.queue(handle));
+    self.flows.insert(flow, handle);
+    handle
+  }
+
+  /// Answer a SYN to a listening port with a SYN-ACK, keeping only a
+  /// `RequestSock` until the handshake completes
+  fn open_passive(
+    &mut self,
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    ip: &Ipv4Header,
+    tcp: &TcpHeader,
+    now: Instant,
+  ) {
+    if self.requests.len() >= self.config.backlog {
+      self.drops.record(DropReason::BufferFull);
+      return;
+    }
+    if !self.ip_options_policy.admits(&ip.options) {
+      self.drops.record(DropReason::IpOptions);
+      return;
+    }
+    let iss = self.initial_seq(local, remote, now);
+    let mut request =
+      RequestSock::new(local, remote, iss, tcp, self.config.recv_buffer, now);
+    request.ip_options_stripped = self.ip_options_policy.strips(&ip.options);
+    request.ts_val = self.config.ts_val(local, remote, now);
+    if let Some(profile) = self.config.profile {
+      request.set_profile(profile);
+    }
+    self.transmit.push_back(syn_ack(&request));
+    self.requests.insert((local, remote), request);
+  }
+
+  /// SYN-RECEIVED processing for a half-open connection. The ACK of our
+  /// SYN-ACK builds the connection and queues it for `accept`
+  fn handle_request(
+    &mut self,
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    ip: &Ipv4Header,
+    tcp: &TcpHeader,
+    payload: &[u8],
+    now: Instant,
+  ) {
+    let key = (local, remote);
+    if !self.ip_options_policy.admits(&ip.options) {
+      self.drops.record(DropReason::IpOptions);
+      return;
+    }
+    if tcp.flags.is_rst() {
+      let request = self.requests.get(&key).expect("request exists");
+      if request.reset_in_window(tcp) {
+        self.requests.remove(&key);
+      } else {
+        self.drops.record(DropReason::OutOfWindow);
+      }
+      return;
+    }
+    let ts_val = self.config.ts_val(local, remote, now);
+    let request = self.requests.get_mut(&key).expect("request exists");
+    if tcp.flags.is_syn() && !tcp.flags.is_ack() {
+      // The peer retransmitted its SYN: our SYN-ACK was lost
+      request.ts_val = ts_val;
+      self.transmit.push_back(syn_ack(request));
+      return;
+    }
+    if !request.acknowledged_by(tcp) {
+      self.drops.record(DropReason::OutOfWindow);
+      return;
+    }
+    let request = self.requests.remove(&key).expect("request exists");
+    let mut conn = self.open(local, remote, now);
+    conn.engine().accept_request(&request);
+    let mut actions = Vec::new();
+    if conn.engine().on_receive(ip) {
+      actions = Engine::new(local, remote, &mut conn.control)
+        .with_reply_limiter(&mut self.reply_limiter)
+        .on_segment(tcp, payload, now);
+    }
+    let handle = self.insert(conn, actions);
+    self.accept_queue.push_back(handle);
+  }
+
+  /// Resend the SYN-ACKs due by `now`, dropping half-open connections that
+  /// have none left
+  fn poll_requests(&mut self, now: Instant) {
+    let transmit = &mut self.transmit;
+    let config = &self.config;
+    self.requests.retain(|_, request| {
+      if now < request.deadline {
+        return true;
+      }
+      // Not a drop: no segment is discarded, the peer just never finished
+      // the handshake. `usage().half_open` counts the request until here
+      if !request.on_timeout(now, &config.retry_limits) {
+        debug!(
+          "{} -> {}: Giving up after {} SYN-ACKs",
+          request.local,
+          request.remote,
+          request.retransmits + 1
+        );
+        return false;
+      }
+      request.ts_val = config.ts_val(request.local, request.remote, now);
+      transmit.push_back(syn_ack(request));
+      true
+    });
+  }
+
+  /// Reset a segment that matched no connection (RFC 793 3.4)
+  fn reset(
+    &mut self,
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    tcp: &TcpHeader,
+    len: usize,
+  ) {
+    let mut reset = TcpHeader::new(local.port(), remote.port());
+    reset.window_size = 0;
+    if tcp.flags.is_ack() {
+      reset.seq_num = tcp.ack_num;
+      reset.flags = TcpFlags::new().with_rst();
+    } else {
+      let len =
+        len as u32 + u32::from(tcp.flags.is_syn()) + u32::from(tcp.flags.is_fin());
+      reset.ack_num = (SeqNumber(tcp.seq_num) + len).0;
+      reset.flags = TcpFlags::new().with_rst().with_ack();
+    }
+    let ip = Ipv4Header::new(*local.ip(), *remote.ip(), 0);
+    self.transmit.push_back(encode(ip, &reset, &[]));
+  }
+
+  /// `reap` for a single connection, so handling one segment stays O(1)
+  fn reap_one(&mut self, handle: ConnectionHandle) {
+    let Some(conn) = self.connections.get(handle.0) else {
+      return;
+    };
+    let key = (conn.local, conn.remote);
+    if conn.state() == TcpState::Closed && self.flows.get(&key) == Some(&handle) {
+      self.flows.remove(&key);
+    }
+  }
+
+  /// Drop closed connections from the flow table
+  fn reap(&mut self) {
+    let connections = &self.connections;
+    self.flows.retain(|_, handle| {
+      connections
+        .get(handle.0)
+        .is_some_and(|conn| conn.state() != TcpState::Closed)
+    });
+  }
+}
+
+/// One connection of the stack
+struct Connection {
+  local: SocketAddrV4,
+  remote: SocketAddrV4,
+  control: ControlBlock,
+  /// The application closed its side; the FIN goes out once the send queue
+  /// drains
+  closing: bool,
+  /// When TIME-WAIT ends
+  time_wait: Option<Instant>,
+}
+
+impl Connection {
+  fn state(&self) -> TcpState {
+    self.control.state
+  }
+
+  fn engine(&mut self) -> Engine<'_> {
+    Engine::new(self.local, self.remote, &mut self.control)
+  }
+
+  fn writable(&self) -> bool {
+    !self.closing && matches!(self.state(), TcpState::Established | TcpState::CloseWait)
+  }
+
+  /// Run the timers and send what the windows allow
+  fn poll(&mut self, now: Instant, out: &mut VecDeque<Vec<u8>>) {
+    if self.state() == TcpState::TimeWait {
+      if self.time_wait.is_some_and(|end| now >= end) {
+        self.control.state = TcpState::Closed;
+      }
+      return;
+    }
+    let mut actions = self.engine().poll(now);
+    if self.closing {
+      actions.extend(self.engine().fin(now));
+    }
+    self.execute(actions, out);
+  }
+
+  /// Run only the timers due by `now`
+  fn on_tick(&mut self, now: Instant, out: &mut VecDeque<Vec<u8>>) {
+    if self.state() == TcpState::TimeWait {
+      if self.time_wait.is_some_and(|end| now >= end) {
+        self.control.state = TcpState::Closed;
+      }
+      return;
+    }
+    let actions = self.engine().on_tick(now);
+    self.execute(actions, out);
+  }
+
+  /// When `poll` next has timer work to do
+  fn deadline(&self) -> Option<Instant> {
+    match self.state() {
+      TcpState::TimeWait => self.time_wait,
+      _ => self.control.next_deadline(),
+    }
+  }
+
+  /// Queue the segments `actions` send as IPv4 packets, and keep the
+  /// TIME-WAIT timer the control block has no room for
+  fn execute(&mut self, actions: Vec<Action>, out: &mut VecDeque<Vec<u8>>) {
+    for action in actions {
+      match action {
+        Action::SendSegment { header, payload } => self.emit(&header, &payload, out),
+        Action::StartTimer {
+          timer: TimerKind::TimeWait,
+          deadline,
+        } => self.time_wait = Some(deadline),
+        // `poll_timeout` reads the other timers and the application reads
+        // data when it likes; closed connections are reaped by the stack
+        Action::StartTimer { .. } | Action::DeliverData { .. } | Action::Close { .. } => {
+        }
+      }
+    }
+  }
+
+  /// Queue a segment to the peer as an IPv4 packet
+  fn emit(&mut self, header: &TcpHeader, payload: &[u8], out: &mut VecDeque<Vec<u8>>) {
+    let ip = self
+      .control
+      .ip_header(*self.local.ip(), *self.remote.ip(), 0);
+    out.push_back(encode(ip, header, payload));
+    stats::count(&mut self.control.stats.segments_sent, 1);
+    stats::count(&mut self.control.stats.bytes_sent, payload.len() as u64);
+    self.control.on_transmit(payload.len());
+  }
+}
+
+/// The SYN-ACK of a half-open connection as an IPv4 packet
+fn syn_ack(request: &RequestSock) -> Vec<u8> {
+  let mut ip = Ipv4Header::new(*request.local.ip(), *request.remote.ip(), 0);
+  ip.ttl = request.ttl();
+  encode(ip, &request.syn_ack(), &[])
+}
+
+/// Checksum a segment and wrap it in the IPv4 header `ip`
+fn encode(mut ip: Ipv4Header, header: &TcpHeader, payload: &[u8]) -> Vec<u8> {
+  let checksum =
+    header.calculate_checksum(ip.src_addr.into(), ip.dst_addr.into(), payload);
+  let mut segment = header.serialize();
+  segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+  segment.extend_from_slice(payload);
+  ip.total_length = (ip.header_len() + segment.len()) as u16;
+  let mut packet = ip.serialize();
+  packet.extend_from_slice(&segment);
+  packet
+}
@@ -0,0 +1,264 @@
+//! Two-thread runtime: packet I/O apart from protocol and timer work
+//!
+//! `TcpStack::run_threaded` splits `run_blocking`'s loop across two
+//! threads. An I/O thread does nothing but move packets: it reads the
+//! socket into an inbound queue and writes out what the outbound queue
+//! holds. The calling thread becomes the control plane: it owns the stack,
+//! feeds it the inbound packets, fires its timers, gives the application
+//! its turn and queues what the stack sends. The queues are lock-free
+//! single-producer single-consumer rings (`utils::spsc`), so a
+//! retransmission storm keeping the control plane busy does not hold up
+//! reading the socket: packets wait in the inbound queue, and only once it
+//! fills are they dropped. The application is handed the depths of both
+//! queues every turn.
+
+use crate::device::NetworkDevice;
+use crate::socket::RawSocket;
+use crate::utils::spsc::{self, Consumer, Producer, QueueStats};
+use crate::utils::Instant;
+use crate::TcpStack;
+use std::io;
+use std::ops::ControlFlow;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+/// Packets each queue holds
+pub const QUEUE_CAPACITY: usize = 4096;
+
+/// Longest either thread waits, so the application gets a turn and the
+/// I/O thread notices a stop at least this often
+pub const MAX_WAIT: Duration = Duration::from_secs(1);
+
+/// The queues between the I/O thread and the control plane
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QueueMetrics {
+  /// Packets received and not yet handled; its `rejected` are packets
+  /// dropped because the control plane fell behind
+  pub inbound: QueueStats,
+  /// Packets the stack sent and the socket has not taken yet
+  pub outbound: QueueStats,
+}
+
+impl TcpStack {
+  /// Run the stack over `socket` until `app` breaks, as `run_blocking`
+  /// does, but with the socket served by a thread of its own. `app` gets
+  /// the stack, the current time and the queue depths. Returns the final
+  /// queue metrics, or the first socket error the I/O thread hit
+  pub fn run_threaded<F>(&mut self, socket: &mut RawSocket, mut app: F) -> io::Result<QueueMetrics>
+  where
+    F: FnMut(&mut TcpStack, Instant, &QueueMetrics) -> ControlFlow<()>,
+  {
+    socket.set_nonblocking(true)?;
+    let (inbound_tx, mut inbound) = spsc::channel(QUEUE_CAPACITY);
+    let (mut outbound, outbound_rx) = spsc::channel(QUEUE_CAPACITY);
+    let waker = Waker::new()?;
+    let stop = AtomicBool::new(false);
+    let control = thread::current();
+
+    thread::scope(|scope| {
+      let io = scope.spawn(|| {
+        let result = serve(socket, inbound_tx, outbound_rx, &waker, &stop, &control);
+        stop.store(true, Ordering::Release);
+        control.unpark();
+        result
+      });
+
+      let metrics = |inbound: &Consumer<Vec<u8>>, outbound: &Producer<Vec<u8>>| QueueMetrics {
+        inbound: inbound.stats(),
+        outbound: outbound.stats(),
+      };
+      while !stop.load(Ordering::Acquire) {
+        let now = Instant::now();
+        while let Some(packet) = inbound.pop() {
+          self.handle_packet(&packet, now);
+        }
+        if app(self, now, &metrics(&inbound, &outbound)).is_break() {
+          break;
+        }
+        // Left in the stack while the outbound queue is full; the I/O
+        // thread wakes us once it has made room
+        let mut queued = false;
+        while !outbound.is_full() {
+          let Some(packet) = self.poll_transmit(now) else {
+            break;
+          };
+          let _ = outbound.push(packet);
+          queued = true;
+        }
+        if queued {
+          waker.wake();
+        }
+
+        if inbound.is_empty() {
+          let timeout = match self.poll_timeout() {
+            Some(deadline) => deadline
+              .saturating_duration_since(Instant::now())
+              .min(MAX_WAIT),
+            None => MAX_WAIT,
+          };
+          thread::park_timeout(timeout);
+        }
+      }
+      stop.store(true, Ordering::Release);
+      waker.wake();
+      let result = io.join().expect("I/O thread panicked");
+      result.map(|()| metrics(&inbound, &outbound))
+    })
+  }
+}
+
+/// The I/O thread: send what is queued, wait for packets or a wake-up, and
+/// queue what arrived, until told to stop
+fn serve(
+  socket: &mut RawSocket,
+  mut inbound: Producer<Vec<u8>>,
+  mut outbound: Consumer<Vec<u8>>,
+  waker: &Waker,
+  stop: &AtomicBool,
+  control: &Thread,
+) -> io::Result<()> {
+  let mut buf = vec![0u8; 65535];
+  // A packet the socket would not take, held until it turns writable
+  let mut pending = None;
+  while !stop.load(Ordering::Acquire) {
+    let sent = flush(socket, &mut outbound, &mut pending)?;
+    let mut received = false;
+    if wait(socket, waker, pending.is_some(), MAX_WAIT)?.readable {
+      loop {
+        match socket.recv(&mut buf) {
+          // A full queue counts the packet as rejected
+          Ok(len) => received |= inbound.push(buf[..len].to_vec()).is_ok(),
+          Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+          Err(e) => return Err(e),
+        }
+      }
+    }
+    if sent || received {
+      control.unpark();
+    }
+  }
+  // What the control plane queued before it stopped still goes out, unless
+  // the socket stays unwritable for a whole `MAX_WAIT`. Its last wake-up is
+  // in by now, so only the socket can end the wait early
+  waker.drain();
+  flush(socket, &mut outbound, &mut pending)?;
+  while pending.is_some() && wait(socket, waker, true, MAX_WAIT)?.writable {
+    flush(socket, &mut outbound, &mut pending)?;
+  }
+  Ok(())
+}
+
+/// Send `pending`, then what `outbound` holds, until the socket would
+/// block, leaving the packet it refused in `pending`. Returns whether any
+/// packet went out
+fn flush(
+  socket: &mut RawSocket,
+  outbound: &mut Consumer<Vec<u8>>,
+  pending: &mut Option<Vec<u8>>,
+) -> io::Result<bool> {
+  let mut sent = false;
+  while let Some(packet) = pending.take().or_else(|| outbound.pop()) {
+    match socket.send(&packet) {
+      Ok(()) => sent = true,
+      Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+        *pending = Some(packet);
+        break;
+      }
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(sent)
+}
+
+/// What `wait` found ready on the socket
+struct Readiness {
+  readable: bool,
+  writable: bool,
+}
+
+/// Wait up to `timeout` for a packet on `socket`, room to send one if
+/// `writing`, or a wake-up. Wake-ups are consumed
+fn wait(
+  socket: &RawSocket,
+  waker: &Waker,
+  writing: bool,
+  timeout: Duration,
+) -> io::Result<Readiness> {
+  let [send, recv] = socket.as_fds();
+  let mut fds = [
+    libc::pollfd {
+      fd: recv.as_raw_fd(),
+      events: libc::POLLIN,
+      revents: 0,
+    },
+    libc::pollfd {
+      fd: waker.read.as_raw_fd(),
+      events: libc::POLLIN,
+      revents: 0,
+    },
+    libc::pollfd {
+      // A negative fd is skipped
+      fd: if writing { send.as_raw_fd() } else { -1 },
+      events: libc::POLLOUT,
+      revents: 0,
+    },
+  ];
+  let millis = timeout.as_millis().min(i32::MAX as u128) as libc::c_int;
+  let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, millis) };
+  if ret < 0 {
+    let e = io::Error::last_os_error();
+    if e.kind() == io::ErrorKind::Interrupted {
+      return Ok(Readiness {
+        readable: false,
+        writable: false,
+      });
+    }
+    return Err(e);
+  }
+  if fds[1].revents & libc::POLLIN != 0 {
+    waker.drain();
+  }
+  Ok(Readiness {
+    readable: fds[0].revents & libc::POLLIN != 0,
+    writable: fds[2].revents & libc::POLLOUT != 0,
+  })
+}
+
+/// A pipe the control plane writes to, to end the I/O thread's wait
+struct Waker {
+  read: OwnedFd,
+  write: OwnedFd,
+}
+
+impl Waker {
+  fn new() -> io::Result<Self> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    for fd in [&read, &write] {
+      let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+      if flags < 0
+        || unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+      {
+        return Err(io::Error::last_os_error());
+      }
+    }
+    Ok(Self { read, write })
+  }
+
+  /// A full pipe already has a wake-up pending, so a failed write is fine
+  fn wake(&self) {
+    let byte = 1u8;
+    unsafe { libc::write(self.write.as_raw_fd(), (&byte as *const u8).cast(), 1) };
+  }
+
+  fn drain(&self) {
+    let mut buf = [0u8; 64];
+    while unsafe { libc::read(self.read.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) } > 0 {}
+  }
+}
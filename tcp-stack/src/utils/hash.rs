@@ -0,0 +1,14 @@
+//! FNV-1a, the 64-bit non-cryptographic hash behind segment digests and
+//! keyed initial sequence numbers
+
+/// Offset basis every FNV-1a hash starts from
+pub const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Continue the FNV-1a hash `hash` over `bytes`
+pub fn fnv1a(hash: u64, bytes: impl IntoIterator<Item = u8>) -> u64 {
+  bytes
+    .into_iter()
+    .fold(hash, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME))
+}
@@ -1,9 +1,21 @@
 //! Utility functions for TCP stack
 
 pub mod checksum;
+pub mod hash;
+pub mod rng;
 pub mod seq;
+pub mod slab;
+#[cfg(feature = "std")]
+pub mod spsc;
+pub mod time;
 
 pub use checksum::{
-  CalculateChecksum, calculate_checksum, calculate_pseudo_header_checksum,
+  calculate_checksum, calculate_pseudo_header_checksum, CalculateChecksum,
 };
+pub use hash::{fnv1a, FNV_OFFSET};
+pub use rng::SimRng;
 pub use seq::SeqNumber;
+pub use slab::{Slab, SlabKey};
+#[cfg(feature = "std")]
+pub use time::SystemClock;
+pub use time::{Clock, Instant, ManualClock};
@@ -0,0 +1,40 @@
+//! Seeded random numbers for simulation and fault injection
+//!
+//! `SimRng` is xorshift64* behind a splitmix64 seed scrambler: cheap,
+//! `no_std`, and the same sequence on every platform for a given seed, so a
+//! run it drives replays exactly. It is predictable by design and must
+//! never pick anything an attacker could exploit, such as sequence numbers.
+
+/// Deterministic generator for simulations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimRng(u64);
+
+impl SimRng {
+  pub fn new(seed: u64) -> Self {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    // xorshift never leaves the all-zero state
+    Self((z ^ (z >> 31)).max(1))
+  }
+
+  pub fn next_u64(&mut self) -> u64 {
+    self.0 ^= self.0 >> 12;
+    self.0 ^= self.0 << 25;
+    self.0 ^= self.0 >> 27;
+    self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+  }
+
+  /// Uniform in `0..bound`; zero when `bound` is
+  pub fn below(&mut self, bound: u64) -> u64 {
+    if bound == 0 {
+      return 0;
+    }
+    ((u128::from(self.next_u64()) * u128::from(bound)) >> 64) as u64
+  }
+
+  /// True `numerator` times in `denominator`
+  pub fn ratio(&mut self, numerator: u32, denominator: u32) -> bool {
+    self.below(u64::from(denominator)) < u64::from(numerator)
+  }
+}
@@ -1,6 +1,6 @@
 //! TCP sequence number arithmetic
 
-use std::ops::{Add, Sub};
+use core::ops::{Add, Sub};
 
 /// TCP sequence number (32-bit, wraps around)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -11,6 +11,7 @@ impl SeqNumber {
     Self(val)
   }
 
+  #[cfg(feature = "std")]
   pub fn random() -> Self {
     use rand::Rng;
     Self(rand::thread_rng().gen_range(0..=u32::MAX))
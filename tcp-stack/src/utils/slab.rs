@@ -0,0 +1,137 @@
+//! Slab allocator with generation-checked keys
+//!
+//! Freed slots are reused, but every reuse bumps the slot's generation, so a
+//! key kept by a timer or event after its entry was removed no longer
+//! matches and cannot reach the entry that took its place.
+
+use alloc::vec::Vec;
+
+/// Key of a slab entry: slot index plus the generation it was issued for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SlabKey {
+  index: u32,
+  generation: u32,
+}
+
+impl SlabKey {
+  pub fn index(&self) -> u32 {
+    self.index
+  }
+
+  pub fn generation(&self) -> u32 {
+    self.generation
+  }
+}
+
+struct Slot<T> {
+  generation: u32,
+  value: Option<T>,
+}
+
+/// Slab of `T` addressed by `SlabKey`
+pub struct Slab<T> {
+  slots: Vec<Slot<T>>,
+  free: Vec<u32>,
+  len: usize,
+}
+
+impl<T> Slab<T> {
+  pub fn new() -> Self {
+    Self {
+      slots: Vec::new(),
+      free: Vec::new(),
+      len: 0,
+    }
+  }
+
+  pub fn insert(&mut self, value: T) -> SlabKey {
+    self.len += 1;
+    if let Some(index) = self.free.pop() {
+      let slot = &mut self.slots[index as usize];
+      slot.value = Some(value);
+      return SlabKey {
+        index,
+        generation: slot.generation,
+      };
+    }
+
+    let index = self.slots.len() as u32;
+    self.slots.push(Slot {
+      generation: 0,
+      value: Some(value),
+    });
+    SlabKey {
+      index,
+      generation: 0,
+    }
+  }
+
+  /// Remove the entry, invalidating `key` and every copy of it
+  pub fn remove(&mut self, key: SlabKey) -> Option<T> {
+    let slot = self.slots.get_mut(key.index as usize)?;
+    if slot.generation != key.generation {
+      return None;
+    }
+    let value = slot.value.take()?;
+    slot.generation = slot.generation.wrapping_add(1);
+    self.free.push(key.index);
+    self.len -= 1;
+    Some(value)
+  }
+
+  pub fn get(&self, key: SlabKey) -> Option<&T> {
+    let slot = self.slots.get(key.index as usize)?;
+    if slot.generation != key.generation {
+      return None;
+    }
+    slot.value.as_ref()
+  }
+
+  pub fn get_mut(&mut self, key: SlabKey) -> Option<&mut T> {
+    let slot = self.slots.get_mut(key.index as usize)?;
+    if slot.generation != key.generation {
+      return None;
+    }
+    slot.value.as_mut()
+  }
+
+  pub fn contains(&self, key: SlabKey) -> bool {
+    self.get(key).is_some()
+  }
+
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  /// Live entries with their keys
+  pub fn iter(&self) -> impl Iterator<Item = (SlabKey, &T)> {
+    self.slots.iter().enumerate().filter_map(|(index, slot)| {
+      let key = SlabKey {
+        index: index as u32,
+        generation: slot.generation,
+      };
+      slot.value.as_ref().map(|value| (key, value))
+    })
+  }
+
+  /// Live entries with their keys, mutably
+  pub fn iter_mut(&mut self) -> impl Iterator<Item = (SlabKey, &mut T)> {
+    self.slots.iter_mut().enumerate().filter_map(|(index, slot)| {
+      let key = SlabKey {
+        index: index as u32,
+        generation: slot.generation,
+      };
+      slot.value.as_mut().map(|value| (key, value))
+    })
+  }
+}
+
+impl<T> Default for Slab<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
@@ -0,0 +1,186 @@
+//! Bounded lock-free single-producer single-consumer queue
+//!
+//! A ring of slots with a head the consumer advances and a tail the
+//! producer advances, each written by one side only, so a push or pop is a
+//! couple of atomic loads and one store with no lock to contend on. The two
+//! halves are not `Clone`, which is what keeps it single-producer and
+//! single-consumer. A push to a full queue hands the value back rather than
+//! waiting. Both halves can read the queue's depth, high-water mark and
+//! count of rejected pushes.
+//!
+//! Ordering: the producer writes a slot, then stores `tail` with Release;
+//! the consumer loads `tail` with Acquire before reading the slot, so it
+//! sees the value whole. Likewise the consumer reads a slot out, then
+//! stores `head` with Release, and the producer loads `head` with Acquire
+//! before writing that slot again, so the read is done before the write.
+//! Each side loads its own index Relaxed, as only it ever stores it. The
+//! statistics order nothing. Under `--cfg loom` the atomics and cells are
+//! loom's, which tests/loom.rs model-checks.
+
+use std::mem::MaybeUninit;
+
+#[cfg(loom)]
+use loom::cell::UnsafeCell;
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+#[cfg(loom)]
+use loom::sync::Arc;
+#[cfg(not(loom))]
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+#[cfg(not(loom))]
+use std::sync::Arc;
+
+/// `std`'s `UnsafeCell` behind the closure API of loom's, which checks
+/// each access
+#[cfg(not(loom))]
+struct UnsafeCell<T>(std::cell::UnsafeCell<T>);
+
+#[cfg(not(loom))]
+impl<T> UnsafeCell<T> {
+  fn new(value: T) -> Self {
+    Self(std::cell::UnsafeCell::new(value))
+  }
+
+  fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+    f(self.0.get())
+  }
+}
+
+/// How full a queue is and has been
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QueueStats {
+  /// Values waiting now
+  pub depth: usize,
+  /// Most values ever waiting at once
+  pub high_water: usize,
+  /// Pushes refused because the queue was full
+  pub rejected: u64,
+  pub capacity: usize,
+}
+
+struct Ring<T> {
+  slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+  /// Next slot to pop, counting every pop ever made
+  head: AtomicUsize,
+  /// Next slot to push, counting every push ever made
+  tail: AtomicUsize,
+  high_water: AtomicUsize,
+  rejected: AtomicU64,
+}
+
+// SAFETY: each slot is touched by one side at a time: the producer until
+// it publishes the slot through `tail`, the consumer until it releases it
+// through `head`. Only `Producer` pushes and only `Consumer` pops, and
+// neither is `Clone`, so there is one of each. Values cross threads, hence
+// `T: Send`
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+  fn stats(&self) -> QueueStats {
+    let head = self.head.load(Ordering::Acquire);
+    let tail = self.tail.load(Ordering::Acquire);
+    QueueStats {
+      depth: tail.wrapping_sub(head),
+      high_water: self.high_water.load(Ordering::Relaxed),
+      rejected: self.rejected.load(Ordering::Relaxed),
+      capacity: self.slots.len(),
+    }
+  }
+}
+
+impl<T> Drop for Ring<T> {
+  fn drop(&mut self) {
+    // Both halves are gone, and dropping the last `Arc` synchronized with
+    // them, so Relaxed sees their final stores
+    let tail = self.tail.load(Ordering::Relaxed);
+    let mut head = self.head.load(Ordering::Relaxed);
+    while head != tail {
+      let slot = &self.slots[head % self.slots.len()];
+      // SAFETY: slots from `head` up to `tail` were written by pushes and
+      // not yet read by pops
+      slot.with_mut(|value| unsafe { (*value).assume_init_drop() });
+      head = head.wrapping_add(1);
+    }
+  }
+}
+
+/// The sending half
+pub struct Producer<T>(Arc<Ring<T>>);
+
+/// The receiving half
+pub struct Consumer<T>(Arc<Ring<T>>);
+
+/// A queue holding up to `capacity` values, at least one
+pub fn channel<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+  let slots = (0..capacity.max(1))
+    .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+    .collect();
+  let ring = Arc::new(Ring {
+    slots,
+    head: AtomicUsize::new(0),
+    tail: AtomicUsize::new(0),
+    high_water: AtomicUsize::new(0),
+    rejected: AtomicU64::new(0),
+  });
+  (Producer(ring.clone()), Consumer(ring))
+}
+
+impl<T> Producer<T> {
+  /// Queue `value`, or hand it back if the queue is full
+  pub fn push(&mut self, value: T) -> Result<(), T> {
+    let ring = &*self.0;
+    let tail = ring.tail.load(Ordering::Relaxed);
+    let depth = tail.wrapping_sub(ring.head.load(Ordering::Acquire));
+    if depth == ring.slots.len() {
+      ring.rejected.fetch_add(1, Ordering::Relaxed);
+      return Err(value);
+    }
+    let slot = &ring.slots[tail % ring.slots.len()];
+    // SAFETY: the queue is not full, so the slot at `tail` is free: the
+    // consumer read it out before its Release store of `head` made the
+    // Acquire load above see room, and it does not touch the slot again
+    // until our store of `tail` publishes it
+    slot.with_mut(|slot| unsafe { (*slot).write(value) });
+    ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+    ring.high_water.fetch_max(depth + 1, Ordering::Relaxed);
+    Ok(())
+  }
+
+  pub fn is_full(&self) -> bool {
+    let stats = self.0.stats();
+    stats.depth == stats.capacity
+  }
+
+  pub fn stats(&self) -> QueueStats {
+    self.0.stats()
+  }
+}
+
+impl<T> Consumer<T> {
+  /// The oldest value queued, if any
+  pub fn pop(&mut self) -> Option<T> {
+    let ring = &*self.0;
+    let head = ring.head.load(Ordering::Relaxed);
+    if head == ring.tail.load(Ordering::Acquire) {
+      return None;
+    }
+    let slot = &ring.slots[head % ring.slots.len()];
+    // SAFETY: the queue is not empty, so the slot at `head` holds a value:
+    // the producer wrote it before its Release store of `tail` that the
+    // Acquire load above saw, and it does not touch the slot again until
+    // our store of `head` frees it. Advancing `head` makes this the only
+    // read of the value
+    let value = slot.with_mut(|slot| unsafe { (*slot).assume_init_read() });
+    ring.head.store(head.wrapping_add(1), Ordering::Release);
+    Some(value)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.stats().depth == 0
+  }
+
+  pub fn stats(&self) -> QueueStats {
+    self.0.stats()
+  }
+}
@@ -0,0 +1,140 @@
+//! Time source abstraction
+//!
+//! The protocol core never reads a clock itself: every time-dependent
+//! operation takes the current `Instant` from the caller. On `std` builds
+//! `Instant::now()` and `SystemClock` read the monotonic system clock;
+//! embedded users implement `Clock` over their own tick source.
+
+use core::cell::Cell;
+use core::ops::{Add, AddAssign, Sub};
+use core::time::Duration;
+
+/// Monotonic timestamp with microsecond resolution, relative to an
+/// arbitrary origin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Instant {
+  micros: u64,
+}
+
+impl Instant {
+  pub const ZERO: Instant = Instant { micros: 0 };
+
+  pub const fn from_micros(micros: u64) -> Self {
+    Self { micros }
+  }
+
+  pub const fn from_millis(millis: u64) -> Self {
+    Self {
+      micros: millis * 1000,
+    }
+  }
+
+  pub const fn from_secs(secs: u64) -> Self {
+    Self {
+      micros: secs * 1_000_000,
+    }
+  }
+
+  /// Current time from the process-wide system clock
+  #[cfg(feature = "std")]
+  pub fn now() -> Self {
+    SystemClock.now()
+  }
+
+  pub const fn total_micros(&self) -> u64 {
+    self.micros
+  }
+
+  pub const fn total_millis(&self) -> u64 {
+    self.micros / 1000
+  }
+
+  /// Time elapsed since `earlier`, or zero if `earlier` is later
+  pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+    Duration::from_micros(self.micros.saturating_sub(earlier.micros))
+  }
+}
+
+impl Add<Duration> for Instant {
+  type Output = Instant;
+
+  fn add(self, rhs: Duration) -> Self::Output {
+    Instant {
+      micros: self.micros.saturating_add(rhs.as_micros() as u64),
+    }
+  }
+}
+
+impl AddAssign<Duration> for Instant {
+  fn add_assign(&mut self, rhs: Duration) {
+    *self = *self + rhs;
+  }
+}
+
+impl Sub<Duration> for Instant {
+  type Output = Instant;
+
+  fn sub(self, rhs: Duration) -> Self::Output {
+    Instant {
+      micros: self.micros.saturating_sub(rhs.as_micros() as u64),
+    }
+  }
+}
+
+impl Sub<Instant> for Instant {
+  type Output = Duration;
+
+  fn sub(self, rhs: Instant) -> Self::Output {
+    self.saturating_duration_since(rhs)
+  }
+}
+
+/// Source of the current time
+pub trait Clock {
+  fn now(&self) -> Instant;
+}
+
+impl<F: Fn() -> Instant> Clock for F {
+  fn now(&self) -> Instant {
+    self()
+  }
+}
+
+/// Clock that moves only when told to, for tests and simulations
+#[derive(Debug, Default)]
+pub struct ManualClock(Cell<Instant>);
+
+impl ManualClock {
+  pub fn new(start: Instant) -> Self {
+    Self(Cell::new(start))
+  }
+
+  pub fn set(&self, now: Instant) {
+    self.0.set(now);
+  }
+
+  pub fn advance(&self, by: Duration) {
+    self.0.set(self.0.get() + by);
+  }
+}
+
+impl Clock for ManualClock {
+  fn now(&self) -> Instant {
+    self.0.get()
+  }
+}
+
+/// Monotonic system clock; all instances share one origin
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    static ORIGIN: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+
+    let origin = *ORIGIN.get_or_init(std::time::Instant::now);
+    Instant::from_micros(origin.elapsed().as_micros() as u64)
+  }
+}
@@ -0,0 +1,96 @@
+//! Experimental ACK-less sending with receiver-side loss accounting
+
+#![cfg(feature = "blast")]
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+use tcp_stack::packet::{Ipv4Header, TcpHeader};
+use tcp_stack::utils::Instant;
+use tcp_stack::TcpStack;
+
+fn exchange(a: &mut TcpStack, b: &mut TcpStack, now: Instant) {
+  loop {
+    let mut idle = true;
+    while let Some(packet) = a.poll_transmit(now) {
+      b.handle_packet(&packet, now);
+      idle = false;
+    }
+    while let Some(packet) = b.poll_transmit(now) {
+      a.handle_packet(&packet, now);
+      idle = false;
+    }
+    if idle {
+      return;
+    }
+  }
+}
+
+fn payload_len(packet: &[u8]) -> usize {
+  let (_, segment) = Ipv4Header::parse(packet).unwrap();
+  let (_, payload) = TcpHeader::parse(segment).unwrap();
+  payload.len()
+}
+
+#[test]
+fn test_blast_ignores_acks_and_the_receiver_counts_the_holes() {
+  let server_addr = Ipv4Addr::new(10, 0, 0, 1);
+  let mut client = TcpStack::new(Ipv4Addr::new(10, 0, 0, 2), 1);
+  let mut server = TcpStack::new(server_addr, 2);
+  server.listen(80);
+  let mut now = Instant::ZERO;
+  let conn = client
+    .connect(SocketAddrV4::new(server_addr, 80), now)
+    .unwrap();
+  exchange(&mut client, &mut server, now);
+  let accepted = server.accept().unwrap();
+
+  client.control_mut(conn).unwrap().enable_blast(1_000_000, now);
+  server.control_mut(accepted).unwrap().enable_loss_accounting();
+  let data: Vec<u8> = (0..60_000u32).map(|i| i as u8).collect();
+  let mut written = client.send(conn, &data);
+
+  // No ACK ever reaches the sender, and its 3rd and 8th data segments are
+  // lost. The bucket's 10ms burst is all that leaves per 10ms
+  let mut segments = 0;
+  let mut lost = 0;
+  for _ in 0..200 {
+    written += client.send(conn, &data[written..]);
+    let mut sent = 0;
+    while let Some(packet) = client.poll_transmit(now) {
+      let len = payload_len(&packet);
+      sent += len;
+      segments += 1;
+      if segments == 3 || segments == 8 {
+        lost += len;
+        continue;
+      }
+      server.handle_packet(&packet, now);
+    }
+    assert!(sent <= 10_000, "{sent} bytes in one 10ms step");
+    while server.poll_transmit(now).is_some() {}
+    now += Duration::from_millis(10);
+  }
+  assert_eq!(written, data.len());
+
+  let sender = client.control(conn).unwrap();
+  assert_eq!(sender.stats.bytes_sent, data.len() as u64);
+  assert_eq!(sender.stats.retransmissions, 0);
+  assert_eq!(sender.retransmit.pending_count(), 0);
+
+  let loss = server.control(accepted).unwrap().loss.unwrap();
+  assert_eq!(loss.gaps, 2);
+  assert_eq!(loss.lost_bytes, lost as u64);
+  assert_eq!(loss.received_bytes, (data.len() - lost) as u64);
+  assert_eq!(loss.late_bytes, 0);
+  let mut received = vec![0u8; data.len()];
+  let mut len = 0;
+  loop {
+    let n = server.recv(accepted, &mut received[len..], now);
+    if n == 0 {
+      break;
+    }
+    len += n;
+  }
+  assert_eq!(len, data.len() - lost);
+  assert_eq!(&received[len - 100..len], &data[data.len() - 100..]);
+}
@@ -0,0 +1,357 @@
+//! RFC compliance checklist
+//!
+//! One test per requirement the stack implements, named
+//! `rfc<number>_<requirement>` and documented with the section it comes
+//! from, so `cargo test --test compliance` lists what is covered and a
+//! failure names the spec text it breaks. Requirements exercised in more
+//! depth elsewhere still get a direct check here.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+use tcp_stack::connection::control::{RttEstimator, PAWS_IDLE};
+use tcp_stack::connection::{Action, CloseReason, ControlBlock, Engine, TcpState};
+use tcp_stack::flow_control::{PacketLimiter, RateLimit};
+use tcp_stack::packet::{TcpFlags, TcpHeader, TcpOption};
+use tcp_stack::utils::{Instant, SeqNumber};
+
+const LOCAL: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 40000);
+const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80);
+const ISS: u32 = 1000;
+const IRS: u32 = 5000;
+const SMSS: u32 = 1460;
+
+fn engine(control: &mut ControlBlock) -> Engine<'_> {
+  Engine::new(LOCAL, REMOTE, control)
+}
+
+/// Segment from the peer with its sequence `seq` acknowledging `ack`
+fn segment(flags: TcpFlags, seq: u32, ack: u32) -> TcpHeader {
+  let mut header = TcpHeader::new(REMOTE.port(), LOCAL.port());
+  header.flags = flags;
+  header.seq_num = seq;
+  header.ack_num = ack;
+  header.window_size = 65535;
+  header
+}
+
+fn ack(ack: u32) -> TcpHeader {
+  segment(TcpFlags::new().with_ack(), IRS + 1, ack)
+}
+
+fn sent(actions: &[Action]) -> Vec<&TcpHeader> {
+  actions
+    .iter()
+    .filter_map(|action| match action {
+      Action::SendSegment { header, .. } => Some(header),
+      _ => None,
+    })
+    .collect()
+}
+
+/// A control block that completed an active open at time zero
+fn established() -> ControlBlock {
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+  engine(&mut control).open(Instant::ZERO);
+  let mut syn_ack = TcpHeader::syn(REMOTE.port(), LOCAL.port(), IRS, SMSS as u16);
+  syn_ack.flags = syn_ack.flags.with_ack();
+  syn_ack.ack_num = ISS + 1;
+  engine(&mut control).on_segment(&syn_ack, &[], Instant::ZERO);
+  assert_eq!(control.state, TcpState::Established);
+  control
+}
+
+/// `established` with `len` bytes sent and unacknowledged
+fn with_data_in_flight(len: usize) -> ControlBlock {
+  let mut control = established();
+  control.write(&vec![0; len]);
+  engine(&mut control).poll(Instant::ZERO);
+  assert!(control.in_flight() > 0);
+  control
+}
+
+/// Run the retransmission timer to its deadline, returning what it sent
+fn expire(control: &mut ControlBlock) -> (Instant, Vec<Action>) {
+  let deadline = control.next_deadline().expect("retransmission timer runs");
+  (deadline, engine(control).on_tick(deadline))
+}
+
+/// RFC 9293 3.7.1 (MUST-14): implementations must send and receive the
+/// MSS option
+#[test]
+fn rfc9293_syn_carries_mss_option() {
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+  let actions = engine(&mut control).open(Instant::ZERO);
+  let syn = sent(&actions)[0];
+  assert!(syn.flags.is_syn());
+  assert!(syn
+    .options
+    .iter()
+    .any(|option| matches!(option, TcpOption::MaximumSegmentSize(_))));
+
+  let control = established();
+  assert_eq!(u32::from(control.mss), SMSS);
+}
+
+/// RFC 9293 3.10.7.4: an ACK for something not yet sent is not taken
+#[test]
+fn rfc9293_ack_of_unsent_data_is_not_taken() {
+  let mut control = with_data_in_flight(100);
+  engine(&mut control).on_segment(&ack(ISS + 1 + 5000), &[], Instant::from_millis(10));
+  assert_eq!(control.snd_una(), SeqNumber(ISS + 1));
+}
+
+/// RFC 9293 3.8.6.1 (MUST-35, MUST-36): a zero window is probed rather
+/// than waited on forever
+#[test]
+fn rfc9293_zero_window_is_probed() {
+  let mut control = established();
+  let mut closed = ack(ISS + 1);
+  closed.window_size = 0;
+  engine(&mut control).on_segment(&closed, &[], Instant::ZERO);
+  control.write(&[1; 100]);
+  assert!(sent(&engine(&mut control).poll(Instant::ZERO)).is_empty());
+
+  let mut probes = 0;
+  let mut now = Instant::ZERO;
+  while probes < 2 {
+    now = control.next_deadline().expect("persist timer runs");
+    probes += sent(&engine(&mut control).on_tick(now)).len();
+  }
+  assert!(now > Instant::ZERO);
+  assert_eq!(control.state, TcpState::Established);
+}
+
+/// RFC 5961 3.2: a reset outside the receive window is dropped
+#[test]
+fn rfc5961_rst_outside_window_is_ignored() {
+  let mut control = established();
+  let rst = segment(TcpFlags::new().with_rst(), IRS + 1 + 10_000_000, 0);
+  engine(&mut control).on_segment(&rst, &[], Instant::ZERO);
+  assert_eq!(control.state, TcpState::Established);
+}
+
+/// RFC 5961 3.2: a reset in the window but not at RCV.NXT draws a
+/// challenge ACK, as far as the reply limiter allows, and is otherwise
+/// ignored
+#[test]
+fn rfc5961_rst_in_window_off_rcv_nxt_draws_challenge_ack() {
+  let mut control = established();
+  let rst = segment(TcpFlags::new().with_rst(), IRS + 2, 0);
+  let actions = engine(&mut control).on_segment(&rst, &[], Instant::ZERO);
+  let replies = sent(&actions);
+  assert_eq!(replies.len(), 1);
+  assert!(replies[0].flags.is_ack() && !replies[0].flags.is_rst());
+  assert_eq!(replies[0].ack_num, IRS + 1);
+  assert_eq!(control.state, TcpState::Established);
+
+  let mut limiter = PacketLimiter::new();
+  limiter.set_global(Some(RateLimit::new(1, 3)), Instant::ZERO);
+  for expected in [1, 1, 1, 0] {
+    let actions = engine(&mut control)
+      .with_reply_limiter(&mut limiter)
+      .on_segment(&rst, &[], Instant::ZERO);
+    assert_eq!(sent(&actions).len(), expected);
+  }
+  assert_eq!(control.state, TcpState::Established);
+}
+
+/// RFC 5961 3.2: a reset exactly at RCV.NXT closes the connection
+#[test]
+fn rfc9293_rst_in_window_closes() {
+  let mut control = established();
+  let rst = segment(TcpFlags::new().with_rst(), IRS + 1, 0);
+  let actions = engine(&mut control).on_segment(&rst, &[], Instant::ZERO);
+  assert!(actions.contains(&Action::Close {
+    reason: CloseReason::PeerRst
+  }));
+  assert_eq!(control.state, TcpState::Closed);
+}
+
+/// RFC 5961 4.2: a SYN on a synchronized connection draws an ACK and
+/// leaves it open
+#[test]
+fn rfc5961_syn_in_synchronized_state_draws_ack() {
+  let mut control = established();
+  let syn = TcpHeader::syn(REMOTE.port(), LOCAL.port(), IRS + 77, SMSS as u16);
+  let actions = engine(&mut control).on_segment(&syn, &[], Instant::ZERO);
+  let replies = sent(&actions);
+  assert_eq!(replies.len(), 1);
+  assert!(replies[0].flags.is_ack() && !replies[0].flags.is_rst());
+  assert_eq!(replies[0].ack_num, IRS + 1);
+  assert_eq!(control.state, TcpState::Established);
+}
+
+/// RFC 1337: a reset does not cut TIME-WAIT short
+#[test]
+fn rfc1337_time_wait_ignores_rst() {
+  let mut control = established();
+  engine(&mut control).fin(Instant::ZERO);
+  let fin = segment(TcpFlags::new().with_fin().with_ack(), IRS + 1, ISS + 2);
+  engine(&mut control).on_segment(&fin, &[], Instant::ZERO);
+  assert_eq!(control.state, TcpState::TimeWait);
+
+  let rst = segment(TcpFlags::new().with_rst(), IRS + 2, 0);
+  engine(&mut control).on_segment(&rst, &[], Instant::ZERO);
+  assert_eq!(control.state, TcpState::TimeWait);
+}
+
+/// `segment` carrying the timestamp option with `ts_val`
+fn stamped(flags: TcpFlags, seq: u32, ts_val: u32) -> TcpHeader {
+  let mut header = segment(flags, seq, ISS + 1);
+  header.options = vec![TcpOption::Timestamp { ts_val, ts_ecr: 0 }];
+  header
+}
+
+/// RFC 7323 5.3: a segment with a TSval older than TS.Recent is an old
+/// duplicate, acknowledged and dropped without touching TS.Recent, until
+/// TS.Recent has sat idle too long to trust
+#[test]
+fn rfc7323_paws_drops_old_duplicates() {
+  let mut control = established();
+  let data = TcpFlags::new().with_ack().with_psh();
+  engine(&mut control).on_segment(&stamped(data, IRS + 1, 100), b"new", Instant::ZERO);
+  assert_eq!(control.ts_recent, 100);
+
+  let old = stamped(data, IRS + 4, 50);
+  let actions = engine(&mut control).on_segment(&old, b"old", Instant::ZERO);
+  assert!(!actions.iter().any(|action| matches!(action, Action::DeliverData { .. })));
+  let replies = sent(&actions);
+  assert_eq!(replies.len(), 1);
+  assert_eq!(replies[0].ack_num, IRS + 4);
+  assert_eq!(control.stats.drops.paws_fail, 1);
+  assert_eq!(control.ts_recent, 100);
+
+  // Resets are exempt
+  let rst = stamped(TcpFlags::new().with_rst(), IRS + 4, 50);
+  engine(&mut control).on_segment(&rst, &[], Instant::ZERO);
+  assert_eq!(control.state, TcpState::Closed);
+
+  let mut control = established();
+  engine(&mut control).on_segment(&stamped(data, IRS + 1, 100), b"new", Instant::ZERO);
+  let later = Instant::ZERO + PAWS_IDLE + Duration::from_secs(1);
+  let actions = engine(&mut control).on_segment(&old, b"old", later);
+  assert!(actions.contains(&Action::DeliverData { len: 3 }));
+  assert_eq!(control.ts_recent, 50);
+}
+
+/// RFC 5681 4.2: an out-of-order segment is acknowledged at once
+#[test]
+fn rfc5681_out_of_order_segment_draws_immediate_ack() {
+  let mut control = established();
+  let ahead = segment(TcpFlags::new().with_ack(), IRS + 1 + 100, ISS + 1);
+  let actions = engine(&mut control).on_segment(&ahead, &[7; 100], Instant::ZERO);
+  let replies = sent(&actions);
+  assert_eq!(replies.len(), 1);
+  assert_eq!(replies[0].ack_num, IRS + 1);
+}
+
+/// RFC 5681 3.1 eq. 4: ssthresh after a loss is
+/// max(FlightSize / 2, 2 * SMSS)
+#[test]
+fn rfc5681_ssthresh_on_loss() {
+  let mut control = with_data_in_flight(100);
+  expire(&mut control);
+  assert_eq!(control.congestion.ssthresh(), 2 * SMSS);
+
+  let mut control = established();
+  control.congestion.restore(20 * SMSS, u32::MAX);
+  control.write(&vec![0; 10 * SMSS as usize]);
+  engine(&mut control).poll(Instant::ZERO);
+  let flight = control.in_flight();
+  assert!(flight / 2 > 2 * SMSS);
+  expire(&mut control);
+  assert_eq!(control.congestion.ssthresh(), flight / 2);
+}
+
+/// RFC 5681 3.1: after a retransmission timeout cwnd is no more than the
+/// loss window, one SMSS
+#[test]
+fn rfc5681_cwnd_is_loss_window_after_timeout() {
+  let mut control = established();
+  control.congestion.restore(20 * SMSS, u32::MAX);
+  control.write(&vec![0; 10 * SMSS as usize]);
+  engine(&mut control).poll(Instant::ZERO);
+  expire(&mut control);
+  assert_eq!(control.congestion.cwnd(), SMSS);
+}
+
+/// RFC 5681 3.2: the third duplicate ACK retransmits the segment it names
+/// without waiting for the timer
+#[test]
+fn rfc5681_third_duplicate_ack_triggers_fast_retransmit() {
+  let mut control = established();
+  control.congestion.restore(20 * SMSS, u32::MAX);
+  control.write(&vec![0; 5 * SMSS as usize]);
+  engine(&mut control).poll(Instant::ZERO);
+  let now = Instant::from_millis(10);
+  // The first ACK after the SYN-ACK scales the window, so it is an update
+  // rather than a duplicate
+  engine(&mut control).on_segment(&ack(ISS + 1), &[], now);
+
+  for _ in 0..2 {
+    let actions = engine(&mut control).on_segment(&ack(ISS + 1), &[], now);
+    assert!(sent(&actions).is_empty());
+  }
+  engine(&mut control).on_segment(&ack(ISS + 1), &[], now);
+  let actions = engine(&mut control).poll(now);
+  let resent = sent(&actions);
+  assert_eq!(resent.len(), 1);
+  assert_eq!(resent[0].seq_num, ISS + 1);
+}
+
+/// RFC 6298 2.2: the first measurement R sets SRTT to R and RTTVAR to
+/// R/2, and RTO to SRTT + 4 * RTTVAR
+#[test]
+fn rfc6298_first_sample_sets_srtt_and_rttvar() {
+  let mut estimator = RttEstimator::new();
+  estimator.update(0.5);
+  assert_eq!(estimator.srtt(), 0.5);
+  assert_eq!(estimator.rttvar(), 0.25);
+  assert_eq!(estimator.rto(), 1.5);
+}
+
+/// RFC 6298 2.4: an RTO computed below one second is rounded up to it
+#[test]
+fn rfc6298_rto_is_at_least_one_second() {
+  let mut estimator = RttEstimator::new();
+  assert_eq!(estimator.rto(), 1.0);
+  estimator.update(0.01);
+  assert_eq!(estimator.rto(), 1.0);
+}
+
+/// RFC 6298 5.5: each expiry of the retransmission timer doubles the RTO
+#[test]
+fn rfc6298_rto_backoff_doubles() {
+  let mut control = with_data_in_flight(100);
+  let (first, _) = expire(&mut control);
+  let (second, _) = expire(&mut control);
+  let (third, _) = expire(&mut control);
+  assert_eq!((third - second).as_secs_f64(), 2.0 * (second - first).as_secs_f64());
+  assert_eq!(control.stats.retransmissions, 3);
+}
+
+/// RFC 6298 3 (Karn): a retransmitted segment yields no RTT sample
+#[test]
+fn rfc6298_karn_no_sample_from_retransmission() {
+  let mut control = with_data_in_flight(100);
+  let srtt = control.rtt_estimator.srtt();
+  let (at, _) = expire(&mut control);
+  let late = at + Duration::from_millis(300);
+  engine(&mut control).on_segment(&ack(ISS + 101), &[], late);
+  assert_eq!(control.snd_una(), SeqNumber(ISS + 101));
+  assert_eq!(control.rtt_estimator.srtt(), srtt);
+}
+
+/// RFC 7323 2.2: the window of a SYN is never scaled
+#[test]
+fn rfc7323_syn_window_is_not_scaled() {
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+  control.set_recv_buffer(1 << 20);
+  let actions = engine(&mut control).open(Instant::ZERO);
+  let syn = sent(&actions)[0];
+  assert!(syn
+    .options
+    .iter()
+    .any(|option| matches!(option, TcpOption::WindowScale(scale) if *scale > 0)));
+  assert_eq!(syn.window_size, u16::MAX);
+}
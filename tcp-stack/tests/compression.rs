@@ -0,0 +1,86 @@
+//! Experimental payload transforms, with a run-length codec standing in for
+//! zstd or lz4
+
+#![cfg(feature = "compression")]
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use tcp_stack::connection::StreamTransform;
+use tcp_stack::utils::Instant;
+use tcp_stack::TcpStack;
+
+/// Runs of up to 255 equal bytes as (count, byte) pairs
+struct RunLength;
+
+impl StreamTransform for RunLength {
+  fn process(&mut self, input: &[u8], output: &mut Vec<u8>) {
+    for run in input.chunk_by(|a, b| a == b) {
+      for chunk in run.chunks(255) {
+        output.extend_from_slice(&[chunk.len() as u8, chunk[0]]);
+      }
+    }
+  }
+}
+
+/// Expands (count, byte) pairs, holding back half a pair split by a
+/// segment boundary
+#[derive(Default)]
+struct RunLengthDecoder(Option<u8>);
+
+impl StreamTransform for RunLengthDecoder {
+  fn process(&mut self, input: &[u8], output: &mut Vec<u8>) {
+    for &byte in input {
+      match self.0.take() {
+        Some(count) => output.resize(output.len() + usize::from(count), byte),
+        None => self.0 = Some(byte),
+      }
+    }
+  }
+}
+
+fn exchange(a: &mut TcpStack, b: &mut TcpStack, now: Instant) {
+  loop {
+    let mut idle = true;
+    while let Some(packet) = a.poll_transmit(now) {
+      b.handle_packet(&packet, now);
+      idle = false;
+    }
+    while let Some(packet) = b.poll_transmit(now) {
+      a.handle_packet(&packet, now);
+      idle = false;
+    }
+    if idle {
+      return;
+    }
+  }
+}
+
+#[test]
+fn test_transformed_stream_arrives_intact() {
+  let server_addr = Ipv4Addr::new(10, 0, 0, 1);
+  let mut client = TcpStack::new(Ipv4Addr::new(10, 0, 0, 2), 1);
+  let mut server = TcpStack::new(server_addr, 2);
+  server.listen(80);
+  let now = Instant::ZERO;
+  let conn = client
+    .connect(SocketAddrV4::new(server_addr, 80), now)
+    .unwrap();
+  exchange(&mut client, &mut server, now);
+  let accepted = server.accept().unwrap();
+
+  let sender = client.control_mut(conn).unwrap();
+  sender.transforms.set_send(Some(Box::new(RunLength)));
+  let receiver = server.control_mut(accepted).unwrap();
+  receiver
+    .transforms
+    .set_recv(Some(Box::new(RunLengthDecoder::default())));
+
+  let data: Vec<u8> = (0..40u8).flat_map(|i| vec![i; 100]).collect();
+  assert_eq!(client.send(conn, &data), data.len());
+  exchange(&mut client, &mut server, now);
+  let sent = client.control(conn).unwrap().stats.bytes_sent;
+  assert_eq!(sent, 80);
+
+  let mut received = vec![0u8; 8192];
+  let len = server.recv(accepted, &mut received, now);
+  assert_eq!(&received[..len], &data[..]);
+}
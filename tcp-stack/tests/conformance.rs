@@ -0,0 +1,388 @@
+//! Conformance tests driven by packetdrill-style scripts
+//!
+//! Each script in `tests/scripts` plays the peer of a connection whose
+//! control block runs against a mock clock and a loopback device. A line is
+//! `<time> <event>`, the time in seconds, absolute or `+` relative to the
+//! previous line:
+//!
+//! - `connect` sends our SYN; `write <n>` queues `n` bytes
+//! - `< <flags> <seq>:<end>(<len>) [ack <n>] [win <n>]` injects a segment
+//!   from the peer
+//! - `> ...` expects us to send that segment, within `TOLERANCE` of the time
+//!
+//! Flags are packetdrill's `S`, `F`, `R`, `P` and `.` for ACK. Sequence
+//! numbers count from each side's ISN, and every segment we send must be
+//! expected. Time only moves forward between lines, stopping at each timer
+//! deadline on the way.
+
+#![cfg(feature = "std")]
+
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tcp_stack::connection::{ControlBlock, TcpState};
+use tcp_stack::device::Loopback;
+use tcp_stack::packet::{Ipv4Header, TcpFlags, TcpHeader};
+use tcp_stack::utils::{Clock, Instant, ManualClock, SeqNumber};
+use tcp_stack::NetworkDevice;
+
+const LOCAL: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+const REMOTE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const LOCAL_PORT: u16 = 40000;
+const REMOTE_PORT: u16 = 8080;
+const ISS: u32 = 1_000_000;
+const IRS: u32 = 5_000_000;
+const TOLERANCE: Duration = Duration::from_millis(10);
+
+/// A segment as a script line writes it, sequence numbers relative
+#[derive(Debug, PartialEq)]
+struct Segment {
+  flags: u8,
+  seq: u32,
+  len: u32,
+  ack: Option<u32>,
+  window: Option<u16>,
+}
+
+impl Segment {
+  fn parse(spec: &str) -> Result<Self, String> {
+    let mut words = spec.split_whitespace();
+    let mut flags = TcpFlags::new();
+    for c in words.next().ok_or("missing flags")?.chars() {
+      flags = match c {
+        'S' => flags.with_syn(),
+        'F' => flags.with_fin(),
+        'R' => flags.with_rst(),
+        'P' => flags.with_psh(),
+        '.' => flags.with_ack(),
+        _ => return Err(format!("bad flag {c:?}")),
+      };
+    }
+    let range = words.next().ok_or("missing sequence range")?;
+    let (seq, rest) = range.split_once(':').ok_or("bad sequence range")?;
+    let (_, len) = rest.split_once('(').ok_or("bad sequence range")?;
+    let number = |s: &str| s.parse::<u32>().map_err(|e| format!("{s:?}: {e}"));
+    let mut segment = Segment {
+      flags: flags.0,
+      seq: number(seq)?,
+      len: number(len.trim_end_matches(')'))?,
+      ack: None,
+      window: None,
+    };
+    while let Some(word) = words.next() {
+      let value = words.next().ok_or(format!("{word} needs a value"))?;
+      match word {
+        "ack" => segment.ack = Some(number(value)?),
+        "win" => segment.window = Some(number(value)? as u16),
+        _ => return Err(format!("unknown field {word:?}")),
+      }
+    }
+    Ok(segment)
+  }
+
+  /// Whether a sent segment matches this expectation; unspecified fields
+  /// match anything
+  fn matches(&self, sent: &Segment) -> bool {
+    self.flags == sent.flags
+      && self.seq == sent.seq
+      && self.len == sent.len
+      && self.ack.is_none_or(|ack| sent.ack == Some(ack))
+      && self.window.is_none_or(|window| sent.window == Some(window))
+  }
+}
+
+/// One connection under test, with the clock and device it runs on
+struct Harness {
+  clock: ManualClock,
+  device: Loopback,
+  control: ControlBlock,
+  syn_sent_at: Instant,
+  /// Segments we sent and the script has yet to expect
+  sent: VecDeque<(Instant, Segment)>,
+}
+
+impl Harness {
+  fn new() -> Self {
+    let start = Instant::from_secs(1);
+    Self {
+      clock: ManualClock::new(start),
+      device: Loopback::new(),
+      control: ControlBlock::with_initial_seq(SeqNumber(ISS), start),
+      syn_sent_at: start,
+      sent: VecDeque::new(),
+    }
+  }
+
+  /// Seconds since the script started
+  fn elapsed(&self, time: Instant) -> f64 {
+    (time - Instant::from_secs(1)).as_secs_f64()
+  }
+
+  fn send(&mut self, header: TcpHeader, payload: &[u8]) {
+    let packet = segment_packet(LOCAL, REMOTE, &header, payload);
+    self.device.send(&packet).unwrap();
+  }
+
+  /// Our header for a segment at `seq`, acknowledging RCV.NXT
+  fn header(&self, flags: TcpFlags, seq: SeqNumber) -> TcpHeader {
+    let mut header = TcpHeader::new(LOCAL_PORT, REMOTE_PORT);
+    header.flags = flags;
+    header.seq_num = seq.0;
+    header.ack_num = self.control.rcv_nxt().0;
+    header.window_size = self.control.advertised_window();
+    header
+  }
+
+  fn connect(&mut self) {
+    let now = self.clock.now();
+    let mut syn = TcpHeader::syn(LOCAL_PORT, REMOTE_PORT, ISS, self.control.mss);
+    syn.window_size = self.control.rcv_wnd().min(u16::MAX as u32) as u16;
+    self.send(syn, &[]);
+    self.control.on_send(SeqNumber(ISS), 1);
+    self.control.state = TcpState::SynSent;
+    self.syn_sent_at = now;
+  }
+
+  /// Process one segment from the peer
+  fn receive(&mut self, tcp: &TcpHeader, payload: &[u8]) {
+    let now = self.clock.now();
+    let seq = SeqNumber(tcp.seq_num);
+    let ack = SeqNumber(tcp.ack_num);
+    if tcp.flags.is_rst() {
+      self.control.retransmit.clear();
+      self.control.state = TcpState::Closed;
+      return;
+    }
+    match self.control.state {
+      TcpState::SynSent if tcp.flags.is_syn() && tcp.flags.is_ack() => {
+        // The script's SYN-ACK carries no options: no scaling, SACK or
+        // timestamps
+        self.control.set_irs(seq);
+        self.control.window_scale = 0;
+        self
+          .control
+          .update_send_window(seq, ack, tcp.window_size as u32);
+        self.control.on_ack(ack);
+        self.control.state = TcpState::Established;
+        let rtt = now - self.syn_sent_at;
+        self.control.rtt_estimator.update(rtt.as_secs_f64());
+        let header = self.header(TcpFlags::new().with_ack(), self.control.snd_nxt());
+        self.send(header, &[]);
+      }
+      TcpState::Established if tcp.flags.is_ack() => {
+        let window = self.control.scaled_peer_window(tcp.window_size);
+        self
+          .control
+          .process_ack(seq, ack, window, payload.len(), now);
+        if !payload.is_empty() {
+          self.control.recv_stream.push(seq, payload.to_vec());
+          let header = self.header(TcpFlags::new().with_ack(), self.control.snd_nxt());
+          self.send(header, &[]);
+        }
+      }
+      _ => {}
+    }
+  }
+
+  /// Take in what the device received, then send what is due, as the
+  /// connection's event loop would at the current time
+  fn poll(&mut self) {
+    let mut buf = vec![0u8; 65535];
+    while let Ok(len) = self.device.recv(&mut buf) {
+      let (_, segment) = Ipv4Header::parse(&buf[..len]).unwrap();
+      let (tcp, payload) = TcpHeader::parse(segment).unwrap();
+      self.receive(&tcp, payload);
+    }
+
+    let now = self.clock.now();
+    let data = TcpFlags::new().with_ack().with_psh();
+    for (seq, payload) in self.control.next_segments(now) {
+      self.send(self.header(data, seq), &payload);
+    }
+    let fast = self.control.take_fast_retransmit(now);
+    for segment in fast.into_iter().chain(self.control.poll_retransmit(now)) {
+      self.send(self.header(data, segment.seq), &segment.data);
+    }
+    if self.control.poll_window_probe(now) {
+      let header = self.header(TcpFlags::new().with_ack(), self.control.snd_una() - 1);
+      self.send(header, &[]);
+    }
+
+    while let Some(packet) = self.device.take_sent() {
+      self.sent.push_back((now, relative(&packet)));
+    }
+  }
+
+  /// Move the clock to `to`, stopping at every timer deadline before it
+  fn advance(&mut self, to: Instant) {
+    loop {
+      let timers = [
+        self.control.retransmit.deadline(),
+        self.control.window_probe_deadline(),
+      ];
+      match timers.into_iter().flatten().min() {
+        Some(deadline) if deadline <= to => {
+          self.clock.set(deadline.max(self.clock.now()));
+          self.poll();
+        }
+        _ => break,
+      }
+    }
+    self.clock.set(to);
+    self.poll();
+  }
+
+  /// Fail if we sent anything the script has not expected yet
+  fn check_nothing_sent(&self, line: usize) -> Result<(), String> {
+    match self.sent.front() {
+      Some((time, segment)) => Err(format!(
+        "line {line}: unexpected segment at {:.3}s: {segment:?}",
+        self.elapsed(*time)
+      )),
+      None => Ok(()),
+    }
+  }
+
+  fn run(&mut self, script: &str) -> Result<(), String> {
+    let mut time = self.clock.now();
+    for (index, line) in script.lines().enumerate() {
+      let line_no = index + 1;
+      let line = line.split("//").next().unwrap().trim();
+      if line.is_empty() {
+        continue;
+      }
+      let (when, event) = line
+        .split_once(char::is_whitespace)
+        .ok_or(format!("line {line_no}: missing event"))?;
+      let (base, when) = match when.strip_prefix('+') {
+        Some(relative) => (time, relative),
+        None => (Instant::from_secs(1), when),
+      };
+      let secs: f64 = when
+        .parse()
+        .map_err(|e| format!("line {line_no}: bad time {when:?}: {e}"))?;
+      time = base + Duration::from_secs_f64(secs);
+      let event = event.trim();
+
+      if let Some(spec) = event.strip_prefix('>').map(str::trim) {
+        let expected =
+          Segment::parse(spec).map_err(|e| format!("line {line_no}: {e}"))?;
+        // Anything due by the end of the tolerance may satisfy it
+        if self.sent.is_empty() {
+          self.advance(time + TOLERANCE);
+        }
+        let (sent_at, sent) = self
+          .sent
+          .pop_front()
+          .ok_or(format!("line {line_no}: nothing sent, expected {spec}"))?;
+        if !expected.matches(&sent) {
+          return Err(format!("line {line_no}: expected {spec}, sent {sent:?}"));
+        }
+        let skew = (self.elapsed(sent_at) - self.elapsed(time)).abs();
+        if skew > TOLERANCE.as_secs_f64() {
+          return Err(format!(
+            "line {line_no}: {spec} sent at {:.3}s, expected at {:.3}s",
+            self.elapsed(sent_at),
+            self.elapsed(time)
+          ));
+        }
+        continue;
+      }
+
+      if time > self.clock.now() {
+        self.advance(time);
+      }
+      self.check_nothing_sent(line_no)?;
+      let mut words = event.split_whitespace();
+      match words.next() {
+        Some("connect") => self.connect(),
+        Some("write") => {
+          let len: usize = words
+            .next()
+            .and_then(|n| n.parse().ok())
+            .ok_or(format!("line {line_no}: write needs a length"))?;
+          self.control.write(&vec![0x5a; len]);
+        }
+        Some("<") => {
+          let segment =
+            Segment::parse(&event[1..]).map_err(|e| format!("line {line_no}: {e}"))?;
+          let packet = peer_packet(&segment);
+          self.device.inject(packet);
+        }
+        _ => return Err(format!("line {line_no}: unknown event {event:?}")),
+      }
+      self.poll();
+    }
+    self.check_nothing_sent(script.lines().count())
+  }
+}
+
+/// IPv4 packet carrying a TCP segment with a valid checksum
+fn segment_packet(
+  src: Ipv4Addr,
+  dst: Ipv4Addr,
+  header: &TcpHeader,
+  payload: &[u8],
+) -> Vec<u8> {
+  let checksum = header.calculate_checksum(u32::from(src), u32::from(dst), payload);
+  let mut segment = header.serialize();
+  segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+  segment.extend_from_slice(payload);
+  let mut packet = Ipv4Header::new(src, dst, segment.len()).serialize();
+  packet.extend_from_slice(&segment);
+  packet
+}
+
+/// The peer's packet for a script segment
+fn peer_packet(segment: &Segment) -> Vec<u8> {
+  let flags = TcpFlags(segment.flags);
+  let mut header = TcpHeader::new(REMOTE_PORT, LOCAL_PORT);
+  header.flags = flags;
+  header.seq_num = IRS.wrapping_add(segment.seq);
+  if flags.is_ack() {
+    header.ack_num = ISS.wrapping_add(segment.ack.unwrap_or(0));
+  }
+  header.window_size = segment.window.unwrap_or(u16::MAX);
+  segment_packet(REMOTE, LOCAL, &header, &vec![0xa5; segment.len as usize])
+}
+
+/// The script's view of a packet we sent
+fn relative(packet: &[u8]) -> Segment {
+  let (_, segment) = Ipv4Header::parse(packet).unwrap();
+  let (tcp, payload) = TcpHeader::parse(segment).unwrap();
+  Segment {
+    flags: tcp.flags.0,
+    seq: tcp.seq_num.wrapping_sub(ISS),
+    len: payload.len() as u32,
+    ack: tcp.flags.is_ack().then(|| tcp.ack_num.wrapping_sub(IRS)),
+    window: Some(tcp.window_size),
+  }
+}
+
+fn run_script(script: &str) {
+  if let Err(e) = Harness::new().run(script) {
+    panic!("{e}");
+  }
+}
+
+#[test]
+fn test_rto_backoff() {
+  run_script(include_str!("scripts/rto_backoff.pkt"));
+}
+
+#[test]
+fn test_fast_retransmit() {
+  run_script(include_str!("scripts/fast_retransmit.pkt"));
+}
+
+#[test]
+fn test_zero_window_probe() {
+  run_script(include_str!("scripts/zero_window_probe.pkt"));
+}
+
+#[test]
+fn test_unexpected_segment_fails_script() {
+  let script = "0.000 connect\n0.100 write 10\n";
+  let error = Harness::new().run(script).unwrap_err();
+  assert!(error.contains("unexpected segment"), "{error}");
+}
@@ -0,0 +1,545 @@
+//! Active open against the host kernel over loopback
+//!
+//! These tests need raw socket privileges and are skipped without them.
+
+#![cfg(all(target_os = "linux", feature = "raw-socket"))]
+
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+use std::time::{Duration, Instant};
+use tcp_stack::connection::{CancelHandle, ConnectOptions, TcpState};
+use tcp_stack::socket::privilege::check_raw_socket_privilege;
+use tcp_stack::socket::{DestinationClass, DestinationPolicy};
+use tcp_stack::{RawSocket, TcpConnection, TcpError};
+
+fn raw_sockets_available() -> bool {
+  match check_raw_socket_privilege() {
+    Ok(()) => true,
+    Err(e) => {
+      eprintln!("skipping: {e}");
+      false
+    }
+  }
+}
+
+/// Local address whose SYNs the kernel silently drops as martians, so the
+/// handshake never gets an answer
+fn blackholed_local(port: u16) -> SocketAddrV4 {
+  SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 5), port)
+}
+
+fn discard_port() -> SocketAddrV4 {
+  SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9)
+}
+
+/// A socket allowed to send from `blackholed_local` to `discard_port`,
+/// which the default destination policy refuses
+fn blackholed_socket() -> RawSocket {
+  let mut socket = RawSocket::new().unwrap();
+  let policy = DestinationPolicy {
+    loopback: true,
+    ..DestinationPolicy::new()
+  };
+  socket.set_destination_policy(policy).unwrap();
+  socket
+}
+
+#[test]
+fn test_connect_to_kernel_listener() {
+  if !raw_sockets_available() {
+    return;
+  }
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let port = listener.local_addr().unwrap().port();
+
+  let conn = TcpConnection::connect_timeout(
+    SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+    Duration::from_secs(3),
+  )
+  .unwrap();
+
+  assert_eq!(conn.state(), TcpState::Established);
+  assert_eq!(conn.stats().segments_sent, 2);
+  let route = conn.route().unwrap();
+  assert_eq!(conn.local_addr().ip(), &route.source);
+}
+
+#[test]
+fn test_route_to_loopback() {
+  let route = TcpConnection::route_to(Ipv4Addr::LOCALHOST).unwrap();
+  assert!(route.source.is_loopback());
+  assert_eq!(route.interface.as_deref(), Some("lo"));
+  assert_eq!(route.gateway, None);
+}
+
+#[test]
+fn test_connect_by_hostname() {
+  if !raw_sockets_available() {
+    return;
+  }
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let port = listener.local_addr().unwrap().port();
+
+  let conn = TcpConnection::connect_host(&format!("localhost:{port}")).unwrap();
+  assert_eq!(conn.state(), TcpState::Established);
+}
+
+#[test]
+fn test_connect_host_rejects_unresolvable() {
+  assert!(TcpConnection::connect_host("no-port-given").is_err());
+}
+
+#[test]
+fn test_connect_refused() {
+  if !raw_sockets_available() {
+    return;
+  }
+  let port = {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+  };
+
+  let result = TcpConnection::connect_timeout(
+    SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+    Duration::from_secs(3),
+  );
+  assert!(matches!(result, Err(TcpError::ConnectionRefused)));
+}
+
+#[test]
+fn test_destination_policy_refuses_by_default() {
+  let policy = DestinationPolicy::new();
+  let host = Ipv4Addr::new(192, 0, 2, 1);
+  for (dst, class) in [
+    (Ipv4Addr::BROADCAST, DestinationClass::Broadcast),
+    (Ipv4Addr::new(224, 0, 0, 1), DestinationClass::Multicast),
+    (Ipv4Addr::LOCALHOST, DestinationClass::Loopback),
+    (Ipv4Addr::new(0, 1, 2, 3), DestinationClass::Martian),
+    (Ipv4Addr::new(240, 0, 0, 1), DestinationClass::Martian),
+  ] {
+    let denied = policy.check(host, dst).unwrap_err();
+    assert_eq!((denied.addr, denied.class), (dst, class));
+    assert!(DestinationPolicy::allow_all().check(host, dst).is_ok());
+  }
+  assert!(policy.check(host, Ipv4Addr::new(198, 51, 100, 7)).is_ok());
+  assert!(policy
+    .check(Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST)
+    .is_ok());
+}
+
+#[test]
+fn test_connect_to_broadcast_fails_fast() {
+  let start = Instant::now();
+  let result = TcpConnection::connect(SocketAddrV4::new(Ipv4Addr::BROADCAST, 80));
+  match result {
+    Err(TcpError::Io(e)) => {
+      assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+      assert!(e.to_string().contains("broadcast address 255.255.255.255"));
+    }
+    other => panic!("expected a refused destination, got {:?}", other.err()),
+  }
+  assert!(start.elapsed() < Duration::from_millis(100));
+
+  if !raw_sockets_available() {
+    return;
+  }
+  let mut socket = RawSocket::new().unwrap();
+  let packet = [0u8; 20];
+  let err = socket.send_to(&packet, Ipv4Addr::BROADCAST).unwrap_err();
+  assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+  socket
+    .set_destination_policy(DestinationPolicy::allow_all())
+    .unwrap();
+  assert!(socket.destination_policy().broadcast);
+}
+
+#[test]
+fn test_connect_timeout_on_silent_peer() {
+  if !raw_sockets_available() {
+    return;
+  }
+  let start = Instant::now();
+  let result = TcpConnection::connect_socket(
+    blackholed_socket(),
+    blackholed_local(50010),
+    discard_port(),
+    ConnectOptions {
+      timeout: Some(Duration::from_millis(500)),
+      ..ConnectOptions::default()
+    },
+  );
+
+  assert!(matches!(result, Err(TcpError::Timeout)));
+  assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn test_connect_gives_up_after_syn_retries() {
+  if !raw_sockets_available() {
+    return;
+  }
+  let result = TcpConnection::connect_socket(
+    blackholed_socket(),
+    blackholed_local(50011),
+    discard_port(),
+    ConnectOptions {
+      syn_retries: 0,
+      ..ConnectOptions::default()
+    },
+  );
+  assert!(matches!(result, Err(TcpError::Timeout)));
+}
+
+#[test]
+fn test_connect_cancelled() {
+  if !raw_sockets_available() {
+    return;
+  }
+  let cancel = CancelHandle::new();
+  let canceller = cancel.clone();
+  std::thread::spawn(move || {
+    std::thread::sleep(Duration::from_millis(100));
+    canceller.cancel();
+  });
+
+  let start = Instant::now();
+  let result = TcpConnection::connect_socket(
+    blackholed_socket(),
+    blackholed_local(50012),
+    discard_port(),
+    ConnectOptions {
+      cancel: Some(cancel),
+      ..ConnectOptions::default()
+    },
+  );
+
+  assert!(matches!(result, Err(TcpError::Cancelled)));
+  assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_connect_async_races_addresses() {
+  if !raw_sockets_available() {
+    return;
+  }
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let open =
+    SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+
+  let slow = tokio::time::sleep(Duration::from_secs(5));
+  let conn = tokio::select! {
+    conn = TcpConnection::connect_async(open, ConnectOptions::default()) => conn.unwrap(),
+    _ = slow => panic!("connect did not win the race"),
+  };
+  assert!(conn.state().is_established());
+}
+
+#[test]
+fn test_connect_records_peer_dscp() {
+  use std::os::fd::AsRawFd;
+  use tcp_stack::packet::Ipv4Header;
+
+  if !raw_sockets_available() {
+    return;
+  }
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let tos = (Ipv4Header::DSCP_EF as libc::c_int) << 2;
+  let rc = unsafe {
+    libc::setsockopt(
+      listener.as_raw_fd(),
+      libc::IPPROTO_IP,
+      libc::IP_TOS,
+      &tos as *const _ as *const libc::c_void,
+      std::mem::size_of_val(&tos) as libc::socklen_t,
+    )
+  };
+  assert_eq!(rc, 0);
+  let port = listener.local_addr().unwrap().port();
+
+  let conn = TcpConnection::connect_timeout(
+    SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+    Duration::from_secs(3),
+  )
+  .unwrap();
+  assert_eq!(conn.stats().peer_dscp, Ipv4Header::DSCP_EF);
+}
+
+#[test]
+fn test_connect_gtsm_drops_low_ttl() {
+  use std::os::fd::AsRawFd;
+
+  if !raw_sockets_available() {
+    return;
+  }
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let remote =
+    SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+  let options = ConnectOptions {
+    timeout: Some(Duration::from_millis(500)),
+    gtsm_hops: Some(0),
+    ..ConnectOptions::default()
+  };
+
+  // The kernel answers with its default TTL of 64
+  let result = TcpConnection::connect_with(remote, options.clone());
+  assert!(matches!(result, Err(TcpError::Timeout)));
+
+  let ttl: libc::c_int = 255;
+  let rc = unsafe {
+    libc::setsockopt(
+      listener.as_raw_fd(),
+      libc::IPPROTO_IP,
+      libc::IP_TTL,
+      &ttl as *const _ as *const libc::c_void,
+      std::mem::size_of_val(&ttl) as libc::socklen_t,
+    )
+  };
+  assert_eq!(rc, 0);
+  let conn = TcpConnection::connect_with(remote, options).unwrap();
+  assert_eq!(conn.state(), TcpState::Established);
+}
+
+#[test]
+fn test_pool_discards_dead_and_expired_connections() {
+  use tcp_stack::connection::{ConnectionPool, PoolOptions};
+
+  if !raw_sockets_available() {
+    return;
+  }
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let remote =
+    SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+  let pool = ConnectionPool::with_options(PoolOptions {
+    max_idle_per_host: 1,
+    health_check_timeout: Duration::from_millis(500),
+    ..PoolOptions::default()
+  });
+
+  let first = pool.get(remote).unwrap();
+  let second = pool.get(remote).unwrap();
+  let reused_local = second.local_addr();
+  drop(first);
+  drop(second);
+  assert_eq!(pool.idle_count(remote), 1);
+
+  // The host kernel holds no socket for the userspace connection and
+  // resets the keep-alive, so the pool must connect afresh
+  let third = pool.get(remote).unwrap();
+  assert_ne!(third.local_addr(), reused_local);
+  assert_eq!(third.state(), TcpState::Established);
+  assert_eq!(pool.idle_count(remote), 0);
+  drop(third);
+
+  let expiring = ConnectionPool::with_options(PoolOptions {
+    idle_timeout: Duration::ZERO,
+    ..PoolOptions::default()
+  });
+  drop(expiring.get(remote).unwrap());
+  expiring.evict_expired();
+  assert_eq!(expiring.idle_count(remote), 0);
+}
+
+#[test]
+fn test_connect_any_races_past_failures() {
+  if !raw_sockets_available() {
+    return;
+  }
+  let closed = {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port())
+  };
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let open =
+    SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+
+  // The refused first attempt starts the second without waiting out the delay
+  let start = Instant::now();
+  let conn = TcpConnection::connect_any(
+    &[closed, open],
+    ConnectOptions {
+      attempt_delay: Some(Duration::from_secs(5)),
+      ..ConnectOptions::default()
+    },
+  )
+  .unwrap();
+  assert_eq!(conn.remote_addr(), open);
+  assert!(start.elapsed() < Duration::from_secs(2));
+
+  let result = TcpConnection::connect_any(
+    &[closed],
+    ConnectOptions {
+      attempt_delay: Some(Duration::from_millis(10)),
+      ..ConnectOptions::default()
+    },
+  );
+  assert!(matches!(result, Err(TcpError::ConnectionRefused)));
+}
+
+#[test]
+fn test_duplicate_four_tuple_is_addr_in_use() {
+  if !raw_sockets_available() {
+    return;
+  }
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let remote =
+    SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port());
+  let local = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 50020);
+  let connect = || {
+    TcpConnection::connect_socket(
+      RawSocket::new().unwrap(),
+      local,
+      remote,
+      ConnectOptions {
+        timeout: Some(Duration::from_secs(3)),
+        ..ConnectOptions::default()
+      },
+    )
+  };
+
+  let first = connect().unwrap();
+  assert!(matches!(connect(), Err(TcpError::AddrInUse(addr)) if addr == local));
+
+  // Dropping the connection releases its 4-tuple
+  drop(first);
+  assert!(connect().is_ok());
+}
+
+#[test]
+fn test_second_listener_on_port_is_addr_in_use() {
+  if !raw_sockets_available() {
+    return;
+  }
+  let local = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 50021);
+  let shards = tcp_stack::TcpListener::bind_sharded(local, 2).unwrap();
+  assert!(matches!(
+    tcp_stack::TcpListener::bind(local),
+    Err(TcpError::AddrInUse(_))
+  ));
+  drop(shards);
+  assert!(tcp_stack::TcpListener::bind(local).is_ok());
+}
+
+#[test]
+fn test_socket_options_round_trip() {
+  use tcp_stack::connection::{ConnOption, ConnOptionKind};
+
+  if !raw_sockets_available() {
+    return;
+  }
+  let socket = blackholed_socket();
+  let mut conn = TcpConnection::new(socket, blackholed_local(50030), discard_port());
+
+  let options = [
+    ConnOption::NoDelay(true),
+    ConnOption::KeepAlive(Some(Duration::from_secs(60))),
+    ConnOption::KeepInterval(Duration::from_secs(10)),
+    ConnOption::KeepCount(3),
+    ConnOption::Linger(Some(Duration::from_secs(5))),
+    ConnOption::MaxSeg(1200),
+    ConnOption::RcvBuf(1 << 20),
+    ConnOption::SndBuf(1 << 16),
+    ConnOption::SndLowat(4096),
+    ConnOption::UserTimeout(Some(Duration::from_secs(30))),
+    ConnOption::Ttl(32),
+    ConnOption::Tos(0xB8),
+  ];
+  for option in options {
+    conn.set_option(option).unwrap();
+    assert_eq!(conn.get_option(option.kind()), option);
+  }
+  assert_eq!(conn.control().dscp, 46);
+  assert_eq!(conn.control().rcv_wnd(), 1 << 20);
+
+  assert!(matches!(
+    conn.set_option(ConnOption::MaxSeg(100)),
+    Err(TcpError::InvalidOption(_))
+  ));
+  assert_eq!(
+    conn.get_option(ConnOptionKind::MaxSeg),
+    ConnOption::MaxSeg(1200)
+  );
+}
+
+#[test]
+fn test_listener_options_are_kept_for_accepted_connections() {
+  use tcp_stack::connection::{ConnOption, ConnOptionKind};
+
+  if !raw_sockets_available() {
+    return;
+  }
+  let local = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 50040);
+  let mut listener = tcp_stack::connection::TcpListener::bind(local).unwrap();
+  assert_eq!(listener.option(ConnOptionKind::NoDelay), None);
+  listener.set_option(ConnOption::NoDelay(true)).unwrap();
+  listener.set_option(ConnOption::RcvBuf(1 << 18)).unwrap();
+  listener.set_option(ConnOption::RcvBuf(1 << 20)).unwrap();
+  assert_eq!(
+    listener.option(ConnOptionKind::RcvBuf),
+    Some(ConnOption::RcvBuf(1 << 20))
+  );
+  assert!(matches!(
+    listener.set_option(ConnOption::KeepCount(0)),
+    Err(TcpError::InvalidOption(_))
+  ));
+  assert_eq!(listener.option(ConnOptionKind::KeepCount), None);
+  listener.set_on_accept(|conn| {
+    conn.set_option(ConnOption::NoDelay(false)).unwrap();
+  });
+}
+
+#[test]
+fn test_linger_close_times_out_with_reset() {
+  if !raw_sockets_available() {
+    return;
+  }
+  let socket = blackholed_socket();
+  let mut conn = TcpConnection::new(socket, blackholed_local(50031), discard_port());
+  conn.set_state(TcpState::Established);
+  conn.control_mut().write(b"never acknowledged");
+  conn.control_mut().linger = Some(Duration::from_millis(300));
+
+  assert!(matches!(conn.close(), Err(TcpError::Timeout)));
+  assert_eq!(conn.state(), TcpState::Closed);
+  assert_eq!(conn.control().send_buffered(), 0);
+}
+
+#[test]
+fn test_zero_linger_close_aborts() {
+  if !raw_sockets_available() {
+    return;
+  }
+  let socket = blackholed_socket();
+  let mut conn = TcpConnection::new(socket, blackholed_local(50032), discard_port());
+  conn.set_state(TcpState::Established);
+  conn.control_mut().write(b"discarded");
+  conn.control_mut().linger = Some(Duration::ZERO);
+
+  conn.close().unwrap();
+  assert_eq!(conn.state(), TcpState::Closed);
+  assert_eq!(conn.control().send_buffered(), 0);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_stream_writable_follows_window_and_buffer() {
+  use tcp_stack::connection::TcpStream;
+
+  if !raw_sockets_available() {
+    return;
+  }
+  let socket = blackholed_socket();
+  let mut conn = TcpConnection::new(socket, blackholed_local(50033), discard_port());
+  conn.set_state(TcpState::Established);
+  conn.control_mut().send_buffer_limit = 4000;
+  let mut stream = TcpStream::new(conn).unwrap();
+
+  stream.writable().await.unwrap();
+  assert_eq!(stream.try_write(&[0; 8000]).unwrap(), 4000);
+
+  // The initial window is in flight and the buffer is full: nothing frees
+  // either until the silent peer acknowledges
+  stream.set_send_low_watermark(1000);
+  let wait = tokio::time::timeout(Duration::from_millis(200), stream.writable()).await;
+  assert!(wait.is_err());
+  assert!(!stream.connection().control().write_ready());
+}
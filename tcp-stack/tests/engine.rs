@@ -0,0 +1,712 @@
+//! Segment processing with no I/O: the actions `Engine` returns
+
+use bytes::Bytes;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tcp_stack::connection::engine::TIME_WAIT_DURATION;
+use tcp_stack::connection::qlog::timeline_html;
+use tcp_stack::connection::{
+  AckFeedback, Action, BackoffDecision, CloseReason, ControlBlock, Engine, Expect, QlogEvent, RequestSock,
+  RetransmitTrigger, SegmentTrace, TcpState, TimerKind, WindowScaling,
+};
+use tcp_stack::packet::{TcpFlags, TcpHeader, TcpOption};
+use tcp_stack::reliability::RetryLimits;
+use tcp_stack::utils::{Instant, SeqNumber};
+
+const LOCAL: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 40000);
+const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80);
+const ISS: u32 = 1000;
+const IRS: u32 = 5000;
+
+fn engine(control: &mut ControlBlock) -> Engine<'_> {
+  Engine::new(LOCAL, REMOTE, control)
+}
+
+/// Segment from the peer with its sequence `seq` acknowledging `ack`
+fn segment(flags: TcpFlags, seq: u32, ack: u32) -> TcpHeader {
+  let mut header = TcpHeader::new(REMOTE.port(), LOCAL.port());
+  header.flags = flags;
+  header.seq_num = seq;
+  header.ack_num = ack;
+  header.window_size = 65535;
+  header
+}
+
+fn sent(actions: &[Action]) -> Vec<&TcpHeader> {
+  actions
+    .iter()
+    .filter_map(|action| match action {
+      Action::SendSegment { header, .. } => Some(header),
+      _ => None,
+    })
+    .collect()
+}
+
+/// A control block that completed an active open at time zero
+fn established() -> ControlBlock {
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+  engine(&mut control).open(Instant::ZERO);
+  let mut syn_ack = TcpHeader::syn(REMOTE.port(), LOCAL.port(), IRS, 1460);
+  syn_ack.flags = syn_ack.flags.with_ack();
+  syn_ack.ack_num = ISS + 1;
+  engine(&mut control).on_segment(&syn_ack, &[], Instant::ZERO);
+  assert_eq!(control.state, TcpState::Established);
+  control
+}
+
+#[test]
+fn test_open_sends_syn_and_arms_retransmit_timer() {
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+  let actions = engine(&mut control).open(Instant::ZERO);
+
+  let segments = sent(&actions);
+  assert_eq!(segments.len(), 1);
+  assert!(segments[0].flags.is_syn());
+  assert_eq!(segments[0].seq_num, ISS);
+  assert!(actions.iter().any(|action| matches!(
+    action,
+    Action::StartTimer {
+      timer: TimerKind::Retransmit,
+      ..
+    }
+  )));
+  assert_eq!(control.state, TcpState::SynSent);
+}
+
+#[test]
+fn test_data_is_delivered_and_acknowledged() {
+  let mut control = established();
+  let data = segment(TcpFlags::new().with_ack().with_psh(), IRS + 1, ISS + 1);
+  let actions =
+    engine(&mut control).on_segment(&data, b"hello", Instant::from_millis(10));
+
+  assert!(actions.contains(&Action::DeliverData { len: 5 }));
+  let segments = sent(&actions);
+  assert!(segments.iter().any(|header| header.ack_num == IRS + 6));
+  let mut buf = [0u8; 8];
+  let len = control.recv_stream.read(&mut buf);
+  assert_eq!(&buf[..len], b"hello");
+}
+
+#[test]
+fn test_open_send_and_retransmit_read_as_a_trace() {
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+  let mut trace = SegmentTrace::new(Instant::ZERO);
+  trace.record(Instant::ZERO, engine(&mut control).open(Instant::ZERO));
+  trace.expect(Expect::syn().seq(ISS).mss().window_scale());
+
+  let now = Instant::from_millis(50);
+  let mut syn_ack = TcpHeader::syn(REMOTE.port(), LOCAL.port(), IRS, 1460);
+  syn_ack.flags = syn_ack.flags.with_ack();
+  syn_ack.ack_num = ISS + 1;
+  trace.record(now, engine(&mut control).on_segment(&syn_ack, &[], now));
+  trace.expect(Expect::ack().acking(IRS + 1).within(Duration::from_millis(200)));
+
+  control.write(b"hello");
+  trace.record(now, engine(&mut control).poll(now));
+  trace.expect(Expect::ack().push().seq(ISS + 1).len(5));
+  let deadline = control.next_deadline().expect("data is held");
+  trace.record(deadline, engine(&mut control).on_tick(deadline));
+  trace.expect(Expect::ack().seq(ISS + 1).len(5).within(Duration::from_secs(1)));
+  trace.done();
+}
+
+#[test]
+#[should_panic(expected = "expected S with mss, got S seq 1000")]
+fn test_segment_trace_names_the_mismatch() {
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+  let mut trace = SegmentTrace::new(Instant::ZERO);
+  let mut actions = engine(&mut control).open(Instant::ZERO);
+  if let Some(Action::SendSegment { header, .. }) = actions.first_mut() {
+    header.options.clear();
+  }
+  trace.record(Instant::ZERO, actions);
+  trace.expect(Expect::syn().mss());
+}
+
+/// Payloads of the data segments among `actions`
+fn payloads(actions: &[Action]) -> Vec<&Bytes> {
+  actions
+    .iter()
+    .filter_map(|action| match action {
+      Action::SendSegment { payload, .. } if !payload.is_empty() => Some(payload),
+      _ => None,
+    })
+    .collect()
+}
+
+#[test]
+fn test_bytes_written_are_sent_and_resent_without_copying() {
+  let mut control = established();
+  let data = Bytes::from(vec![7u8; 4000]);
+  assert_eq!(control.write_bytes(data.clone()), 4000);
+
+  // Segments are slices of the application's buffer, and so is what the
+  // retransmission queue holds
+  let actions = engine(&mut control).poll(Instant::ZERO);
+  let first = payloads(&actions);
+  assert!(!first.is_empty());
+  assert_eq!(first[0].as_ptr(), data.as_ptr());
+  let held = control.retransmit.pending().next().unwrap();
+  assert_eq!(held.data.as_ptr(), data.as_ptr());
+
+  let deadline = control.next_deadline().expect("data is held");
+  let actions = engine(&mut control).on_tick(deadline);
+  let resent = payloads(&actions);
+  assert_eq!(resent[0].as_ptr(), data.as_ptr());
+  assert_eq!(resent[0], first[0]);
+}
+
+#[test]
+fn test_pure_acks_are_taken_without_reply_but_probes_are_answered() {
+  let mut control = established();
+  control.write(&[1; 100]);
+  engine(&mut control).poll(Instant::ZERO);
+  assert_eq!(control.snd_nxt(), SeqNumber(ISS + 101));
+
+  let ack = segment(TcpFlags::new().with_ack(), IRS + 1, ISS + 101);
+  let actions = engine(&mut control).on_segment(&ack, &[], Instant::from_millis(10));
+  assert!(sent(&actions).is_empty());
+  assert_eq!(control.snd_una(), SeqNumber(ISS + 101));
+  assert_eq!(control.stats.bytes_acked, 100);
+
+  // An empty segment below RCV.NXT is a keep-alive probe, not a pure ACK
+  let probe = segment(TcpFlags::new().with_ack(), IRS, ISS + 101);
+  let actions = engine(&mut control).on_segment(&probe, &[], Instant::from_millis(20));
+  assert_eq!(sent(&actions).len(), 1);
+  assert_eq!(sent(&actions)[0].ack_num, IRS + 1);
+}
+
+#[test]
+fn test_duplicates_and_spurious_retransmissions_are_counted() {
+  let mut control = established();
+  let now = Instant::from_millis(10);
+  let flags = TcpFlags::new().with_ack().with_psh();
+  let first = segment(flags, IRS + 1, ISS + 1);
+  let ahead = segment(flags, IRS + 201, ISS + 1);
+  engine(&mut control).on_segment(&first, &[1; 100], now);
+  engine(&mut control).on_segment(&ahead, &[2; 50], now);
+  assert_eq!(control.stats.duplicate_bytes_received, 0);
+  // Both again, the second overlapping what is held out of order
+  engine(&mut control).on_segment(&first, &[1; 100], now);
+  let overlap = segment(flags, IRS + 181, ISS + 1);
+  engine(&mut control).on_segment(&overlap, &[2; 40], now);
+  assert_eq!(control.stats.duplicate_bytes_received, 100 + 20);
+
+  // Our data times out and is resent, then the original's ACK arrives
+  // along with a D-SACK for the copy
+  control.write(&[0; 100]);
+  engine(&mut control).poll(now);
+  let deadline = control.next_deadline().expect("data is held");
+  engine(&mut control).poll(deadline);
+  assert_eq!(control.stats.retransmissions, 1);
+  let mut ack = segment(TcpFlags::new().with_ack(), IRS + 101, ISS + 101);
+  ack.options = vec![TcpOption::Sack {
+    left: ISS + 1,
+    right: ISS + 101,
+  }];
+  engine(&mut control).on_segment(&ack, &[], deadline);
+  assert_eq!(control.stats.spurious_retransmit_bytes, 100);
+
+  // A plain SACK block above the ACK is no D-SACK
+  ack.options = vec![TcpOption::Sack {
+    left: ISS + 201,
+    right: ISS + 301,
+  }];
+  engine(&mut control).on_segment(&ack, &[], deadline);
+  assert_eq!(control.stats.spurious_retransmit_bytes, 100);
+}
+
+#[test]
+fn test_reset_closes_connection() {
+  let mut control = established();
+  let reset = segment(TcpFlags::new().with_rst(), IRS + 1, 0);
+  let actions = engine(&mut control).on_segment(&reset, &[], Instant::from_millis(10));
+
+  assert_eq!(
+    actions,
+    vec![Action::Close {
+      reason: CloseReason::PeerRst
+    }]
+  );
+  assert_eq!(control.state, TcpState::Closed);
+  assert_eq!(control.close_reason, Some(CloseReason::PeerRst));
+}
+
+#[test]
+fn test_abort_resets_peer() {
+  let mut control = established();
+  control.write(b"unsent");
+  let actions = engine(&mut control).abort(Instant::from_millis(10));
+
+  let headers = sent(&actions);
+  assert_eq!(headers.len(), 1);
+  assert!(headers[0].flags.is_rst());
+  assert_eq!(
+    actions.last(),
+    Some(&Action::Close {
+      reason: CloseReason::UserAbort
+    })
+  );
+  assert_eq!(control.send_queue.queued(), 0);
+  assert_eq!(control.close_reason, Some(CloseReason::UserAbort));
+}
+
+#[test]
+fn test_active_close_waits_in_time_wait() {
+  let mut control = established();
+  let now = Instant::from_millis(10);
+  let actions = engine(&mut control).fin(now);
+  assert!(sent(&actions)[0].flags.is_fin());
+  assert_eq!(control.state, TcpState::FinWait1);
+
+  let fin_ack = segment(TcpFlags::new().with_fin().with_ack(), IRS + 1, ISS + 2);
+  let actions = engine(&mut control).on_segment(&fin_ack, &[], now);
+
+  assert_eq!(control.state, TcpState::TimeWait);
+  assert!(actions.contains(&Action::StartTimer {
+    timer: TimerKind::TimeWait,
+    deadline: now + TIME_WAIT_DURATION,
+  }));
+  assert_eq!(sent(&actions)[0].ack_num, IRS + 2);
+}
+
+#[test]
+fn test_time_wait_ignores_resets_and_stale_fins() {
+  let mut control = established();
+  let now = Instant::from_millis(10);
+  engine(&mut control).fin(now);
+  let fin_ack = segment(TcpFlags::new().with_fin().with_ack(), IRS + 1, ISS + 2);
+  engine(&mut control).on_segment(&fin_ack, &[], now);
+  assert_eq!(control.state, TcpState::TimeWait);
+
+  // RFC 1337: an in-window reset does not end TIME-WAIT early
+  let later = Instant::from_secs(1);
+  let rst = segment(TcpFlags::new().with_rst().with_ack(), IRS + 2, ISS + 2);
+  let actions = engine(&mut control).on_segment(&rst, &[], later);
+  assert!(actions.is_empty());
+  assert_eq!(control.state, TcpState::TimeWait);
+
+  // A FIN that is not the one we took leaves the timer alone
+  let stale = segment(TcpFlags::new().with_fin().with_ack(), IRS + 100, ISS + 2);
+  let actions = engine(&mut control).on_segment(&stale, &[], later);
+  assert!(actions.is_empty());
+
+  // The peer's FIN again means our ACK was lost: ACK it and restart
+  let actions = engine(&mut control).on_segment(&fin_ack, &[], later);
+  assert!(actions.contains(&Action::StartTimer {
+    timer: TimerKind::TimeWait,
+    deadline: later + TIME_WAIT_DURATION,
+  }));
+  assert_eq!(sent(&actions)[0].ack_num, IRS + 2);
+  assert_eq!(control.close_reason, Some(CloseReason::PeerFin));
+}
+
+/// Run the engine's timers at each deadline until it stops asking for one,
+/// returning every action along the way
+fn run_timers(control: &mut ControlBlock) -> Vec<(Instant, Action)> {
+  let mut log = Vec::new();
+  while let Some(deadline) = control.next_deadline() {
+    for action in engine(control).poll(deadline) {
+      log.push((deadline, action));
+    }
+  }
+  log
+}
+
+#[test]
+fn test_unanswered_syn_backs_off_and_gives_up() {
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+  control.retransmit.set_limits(RetryLimits {
+    syn: 3,
+    ..RetryLimits::default()
+  });
+  engine(&mut control).open(Instant::ZERO);
+  let log = run_timers(&mut control);
+
+  let resent: Vec<Instant> = log
+    .iter()
+    .filter(|(_, action)| matches!(action, Action::SendSegment { header, .. } if header.flags.is_syn()))
+    .map(|(at, _)| *at)
+    .collect();
+  assert_eq!(
+    resent,
+    vec![
+      Instant::from_secs(1),
+      Instant::from_secs(3),
+      Instant::from_secs(7)
+    ]
+  );
+  assert_eq!(
+    log.last().map(|(at, action)| (*at, action.clone())),
+    Some((
+      Instant::from_secs(15),
+      Action::Close {
+        reason: CloseReason::RetransmitLimit
+      }
+    ))
+  );
+  assert_eq!(control.stats.syn_retransmissions, 3);
+  // The handshake's losses leave the RTO for data unbacked
+  assert_eq!(control.rtt_estimator.rto(), 1.0);
+}
+
+#[test]
+fn test_ack_observer_hears_acked_bytes_rtt_and_sack_blocks() {
+  let mut control = established();
+  let feedback = Arc::new(Mutex::new(Vec::new()));
+  let seen = feedback.clone();
+  control.ack_observer = Some(Box::new(move |f: &AckFeedback<'_>| {
+    seen.lock().unwrap().push((f.ack.0, f.acked_bytes, f.rtt, f.sacked.to_vec()));
+  }));
+  control.write(&[1; 100]);
+  engine(&mut control).poll(Instant::ZERO);
+
+  let mut partial = segment(TcpFlags::new().with_ack(), IRS + 1, ISS + 41);
+  partial.options = vec![TcpOption::Sack {
+    left: ISS + 61,
+    right: ISS + 81,
+  }];
+  engine(&mut control).on_segment(&partial, &[], Instant::from_millis(30));
+  let full = segment(TcpFlags::new().with_ack(), IRS + 1, ISS + 101);
+  engine(&mut control).on_segment(&full, &[], Instant::from_millis(40));
+  // Data never sent is not acknowledged, so is not reported
+  let optimistic = segment(TcpFlags::new().with_ack(), IRS + 1, ISS + 500);
+  engine(&mut control).on_segment(&optimistic, &[], Instant::from_millis(50));
+
+  // The first ACK splits the segment, so only the second yields a sample
+  assert_eq!(
+    *feedback.lock().unwrap(),
+    vec![
+      (
+        ISS + 41,
+        40,
+        None,
+        vec![(SeqNumber(ISS + 61), SeqNumber(ISS + 81))]
+      ),
+      (ISS + 101, 60, Some(Duration::from_millis(40)), vec![]),
+    ]
+  );
+}
+
+#[test]
+fn test_backoff_observer_sees_each_timeout_and_may_abort() {
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+  let progress = Arc::new(Mutex::new(Vec::new()));
+  let seen = progress.clone();
+  control.backoff_observer = Some(Box::new(move |p| {
+    seen.lock().unwrap().push((p.attempt, p.next_retry, p.elapsed));
+    if p.attempt < 2 {
+      BackoffDecision::Continue
+    } else {
+      BackoffDecision::Abort
+    }
+  }));
+  engine(&mut control).open(Instant::ZERO);
+  let log = run_timers(&mut control);
+
+  assert_eq!(
+    *progress.lock().unwrap(),
+    vec![
+      (1, Duration::from_secs(2), Duration::from_secs(1)),
+      (2, Duration::from_secs(4), Duration::from_secs(3)),
+    ]
+  );
+  // The aborted retransmission is not sent, and a SYN-SENT peer gets no
+  // reset
+  let syns = log
+    .iter()
+    .filter(|(_, action)| matches!(action, Action::SendSegment { .. }))
+    .count();
+  assert_eq!(syns, 1);
+  assert_eq!(
+    log.last().map(|(at, action)| (*at, action.clone())),
+    Some((
+      Instant::from_secs(3),
+      Action::Close {
+        reason: CloseReason::UserAbort
+      }
+    ))
+  );
+}
+
+#[test]
+fn test_lost_fin_is_retransmitted_as_fin() {
+  let mut control = established();
+  engine(&mut control).fin(Instant::ZERO);
+  let deadline = control.next_deadline().expect("FIN is held");
+  let actions = engine(&mut control).poll(deadline);
+
+  let segments = sent(&actions);
+  assert_eq!(segments.len(), 1);
+  assert!(segments[0].flags.is_fin());
+  assert_eq!(segments[0].seq_num, ISS + 1);
+  assert_eq!(control.stats.fin_retransmissions, 1);
+  assert_eq!(control.stats.syn_retransmissions, 0);
+}
+
+#[test]
+fn test_fin_wait2_gives_up_on_peer_that_never_sends_fin() {
+  let mut control = established();
+  control.fin_wait2_timeout = Some(Duration::from_secs(30));
+  let now = Instant::from_millis(10);
+  engine(&mut control).fin(now);
+  let ack = segment(TcpFlags::new().with_ack(), IRS + 1, ISS + 2);
+  let actions = engine(&mut control).on_segment(&ack, &[], now);
+  assert_eq!(control.state, TcpState::FinWait2);
+  let deadline = now + Duration::from_secs(30);
+  assert!(actions.contains(&Action::StartTimer {
+    timer: TimerKind::FinWait2,
+    deadline,
+  }));
+
+  let log = run_timers(&mut control);
+  assert!(log.iter().all(|(at, _)| *at == deadline));
+  let actions: Vec<Action> = log.into_iter().map(|(_, action)| action).collect();
+  assert!(sent(&actions)[0].flags.is_rst());
+  assert!(actions.contains(&Action::Close {
+    reason: CloseReason::Timeout
+  }));
+  assert_eq!(control.state, TcpState::Closed);
+
+  // Without a timeout FIN-WAIT-2 waits for ever
+  let mut control = established();
+  control.fin_wait2_timeout = None;
+  engine(&mut control).fin(now);
+  engine(&mut control).on_segment(&ack, &[], now);
+  assert_eq!(control.state, TcpState::FinWait2);
+  assert_eq!(control.next_deadline(), None);
+}
+
+#[test]
+fn test_tick_fires_only_due_timers() {
+  let mut control = established();
+  engine(&mut control).fin(Instant::ZERO);
+  let deadline = control.next_deadline().expect("FIN is held");
+  let early = Instant::from_micros(deadline.total_micros() - 1);
+  assert!(engine(&mut control).on_tick(early).is_empty());
+  assert_eq!(control.next_deadline(), Some(deadline));
+
+  let actions = engine(&mut control).on_tick(deadline);
+  let segments = sent(&actions);
+  assert_eq!(segments.len(), 1);
+  assert!(segments[0].flags.is_fin());
+  assert!(actions.iter().any(|action| matches!(
+    action,
+    Action::StartTimer {
+      timer: TimerKind::Retransmit,
+      ..
+    }
+  )));
+}
+
+fn window_scale(header: &TcpHeader) -> Option<u8> {
+  header.options.iter().find_map(|option| match option {
+    TcpOption::WindowScale(shift) => Some(*shift),
+    _ => None,
+  })
+}
+
+#[test]
+fn test_syn_offers_scale_covering_recv_buffer() {
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+  control.set_recv_buffer(1 << 20);
+  let actions = engine(&mut control).open(Instant::ZERO);
+  assert_eq!(window_scale(sent(&actions)[0]), Some(5));
+}
+
+#[test]
+fn test_syn_ack_omits_scale_when_syn_had_none() {
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+  let mut syn = segment(TcpFlags::new().with_syn(), IRS, 0);
+  syn.options = vec![TcpOption::MaximumSegmentSize(1460)];
+  let actions = engine(&mut control).accept_syn(&syn, Instant::ZERO);
+
+  let syn_ack = sent(&actions)[0];
+  assert!(syn_ack.flags.is_syn() && syn_ack.flags.is_ack());
+  assert_eq!(window_scale(syn_ack), None);
+  assert_eq!(control.window_scale, 0);
+  assert_eq!(control.stats.window_scaling, None);
+}
+
+#[test]
+fn test_request_sock_hands_its_offer_to_the_control_block() {
+  let mut syn = TcpHeader::syn(REMOTE.port(), LOCAL.port(), IRS, 1460);
+  syn.options.push(TcpOption::WindowScale(7));
+  let mut request =
+    RequestSock::new(LOCAL, REMOTE, SeqNumber(ISS), &syn, 1 << 20, Instant::ZERO);
+  request.mss = 1200;
+  request.offered_scale = 9;
+  let syn_ack = request.syn_ack();
+  assert_eq!(window_scale(&syn_ack), Some(9));
+  assert!(syn_ack
+    .options
+    .contains(&TcpOption::MaximumSegmentSize(1200)));
+
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(0), Instant::ZERO);
+  engine(&mut control).accept_request(&request);
+  assert_eq!(control.state, TcpState::SynReceived);
+  assert_eq!(control.mss, 1200);
+  assert_eq!(control.window_scale, 9);
+  assert_eq!(control.recv_stream.capacity(), 1 << 20);
+}
+
+#[test]
+fn test_peer_window_scale_is_capped() {
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+  control.set_recv_buffer(1 << 20);
+  engine(&mut control).open(Instant::ZERO);
+  let mut syn_ack = TcpHeader::syn(REMOTE.port(), LOCAL.port(), IRS, 1460);
+  syn_ack.flags = syn_ack.flags.with_ack();
+  syn_ack.ack_num = ISS + 1;
+  syn_ack.options = vec![TcpOption::WindowScale(15)];
+  engine(&mut control).on_segment(&syn_ack, &[], Instant::ZERO);
+
+  assert_eq!(control.peer_window_scale, 14);
+  assert_eq!(
+    control.stats.window_scaling,
+    Some(WindowScaling {
+      local: 5,
+      peer: 14,
+      requested: 15,
+    })
+  );
+}
+
+#[test]
+fn test_peer_mss_is_validated() {
+  for (advertised, used) in [(0, 1460), (100, 536), (1200, 1200), (9000, 1460)] {
+    let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+    let mut syn = segment(TcpFlags::new().with_syn(), IRS, 0);
+    syn.options = vec![TcpOption::MaximumSegmentSize(advertised)];
+    engine(&mut control).accept_syn(&syn, Instant::ZERO);
+    assert_eq!(control.mss, used, "peer advertised {advertised}");
+  }
+}
+
+#[test]
+fn test_ip_options_come_out_of_segment_room() {
+  let mut control = established();
+  let room = control.payload_room(0) - 4;
+  assert!(!control.set_ip_options(&[1; 41]));
+  assert!(control.set_ip_options(&[0x94, 0x04, 0x00]));
+  assert_eq!(control.ip_options(), [0x94, 0x04, 0x00, 0x00]);
+  assert_eq!(control.payload_room(0), room);
+
+  control.write(&[7; 3000]);
+  let actions = engine(&mut control).poll(Instant::from_millis(1));
+  let sizes: Vec<usize> = actions
+    .iter()
+    .filter_map(|action| match action {
+      Action::SendSegment { payload, .. } => Some(payload.len()),
+      _ => None,
+    })
+    .collect();
+  assert_eq!(sizes.iter().max(), Some(&room));
+
+  let ip = control.ip_header(*LOCAL.ip(), *REMOTE.ip(), 20);
+  assert_eq!(ip.header_len(), 24);
+  assert_eq!(ip.total_length, 44);
+}
+
+/// TSval and TSecr of the timestamp option `header` carries
+fn timestamp(header: &TcpHeader) -> Option<(u32, u32)> {
+  header.options.iter().find_map(|option| match *option {
+    TcpOption::Timestamp { ts_val, ts_ecr } => Some((ts_val, ts_ecr)),
+    _ => None,
+  })
+}
+
+#[test]
+fn test_negotiated_timestamps_ride_every_segment_but_resets() {
+  let mut control = established();
+  assert!(control.timestamps);
+  let now = Instant::from_millis(10);
+  let mut data = segment(TcpFlags::new().with_ack().with_psh(), IRS + 1, ISS + 1);
+  data.options = vec![TcpOption::Timestamp {
+    ts_val: 77,
+    ts_ecr: 0,
+  }];
+  let actions = engine(&mut control).on_segment(&data, b"hello", now);
+  assert_eq!(timestamp(sent(&actions)[0]), Some((0, 77)));
+
+  // Past a hole: SACK rides alongside, and TS.Recent waits for the hole
+  let mut ahead = segment(TcpFlags::new().with_ack(), IRS + 106, ISS + 1);
+  ahead.options = vec![TcpOption::Timestamp {
+    ts_val: 99,
+    ts_ecr: 0,
+  }];
+  let actions = engine(&mut control).on_segment(&ahead, b"later", now);
+  let ack = sent(&actions)[0];
+  assert_eq!(timestamp(ack), Some((0, 77)));
+  assert!(ack
+    .options
+    .iter()
+    .any(|option| matches!(option, TcpOption::Sack { .. })));
+
+  control.write(b"reply");
+  let actions = engine(&mut control).poll(now);
+  assert_eq!(timestamp(sent(&actions)[0]), Some((0, 77)));
+
+  let Action::SendSegment { header, .. } = engine(&mut control).reset() else {
+    panic!("reset is a segment");
+  };
+  assert_eq!(timestamp(&header), None);
+}
+
+#[test]
+fn test_event_log_records_decisions() {
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(ISS), Instant::ZERO);
+  control.enable_qlog(64);
+  engine(&mut control).open(Instant::ZERO);
+  let mut syn_ack = TcpHeader::syn(REMOTE.port(), LOCAL.port(), IRS, 1460);
+  syn_ack.flags = syn_ack.flags.with_ack();
+  syn_ack.ack_num = ISS + 1;
+  engine(&mut control).on_segment(&syn_ack, &[], Instant::ZERO);
+  control.send_queue.write(b"lost");
+  engine(&mut control).poll(Instant::ZERO);
+  let deadline = control.next_deadline().expect("data is held");
+  engine(&mut control).on_tick(deadline);
+
+  let qlog = control.qlog.as_ref().unwrap();
+  let names: Vec<_> = qlog.records().map(|r| r.event.name()).collect();
+  assert_eq!(
+    names,
+    [
+      "connectivity:connection_state_updated",
+      "transport:packet_sent",
+      "recovery:timer_updated",
+      "transport:packet_received",
+      "connectivity:connection_state_updated",
+      "transport:packet_sent",
+      "transport:packet_sent",
+      "recovery:timer_updated",
+      "recovery:timer_updated",
+      "recovery:packet_retransmitted",
+      "transport:packet_sent",
+      "recovery:timer_updated",
+      "recovery:metrics_updated",
+    ]
+  );
+  assert!(matches!(
+    qlog.records().nth(9).unwrap().event,
+    QlogEvent::Retransmit {
+      seq,
+      len: 4,
+      trigger: RetransmitTrigger::Timeout,
+    } if seq == ISS + 1
+  ));
+
+  let json = qlog.to_json_lines();
+  assert_eq!(
+    json.lines().next(),
+    Some(
+      "{\"time_us\":0,\"name\":\"connectivity:connection_state_updated\",\
+       \"data\":{\"old\":\"Closed\",\"new\":\"SynSent\"}}"
+    )
+  );
+  let html = timeline_html(&json);
+  assert_eq!(html.matches("<tr class=").count(), names.len());
+  assert!(html.contains("<polyline"));
+}
@@ -0,0 +1,67 @@
+//! C bindings, called as a C program would
+//!
+//! Tests that open raw sockets are skipped without the privilege.
+
+#![cfg(all(target_os = "linux", feature = "ffi"))]
+
+use std::ffi::CString;
+use std::net::TcpListener;
+use std::ptr;
+use tcp_stack::ffi::*;
+use tcp_stack::socket::privilege::check_raw_socket_privilege;
+
+#[test]
+fn test_ffi_rejects_null_and_bad_arguments() {
+  unsafe {
+    let stack = tcp_stack_new();
+    assert!(!stack.is_null());
+    let mut conn = ptr::null_mut();
+
+    assert_eq!(
+      tcp_stack_connect(ptr::null_mut(), ptr::null(), &mut conn),
+      -libc::EINVAL
+    );
+    assert_eq!(
+      tcp_stack_connect(stack, ptr::null(), &mut conn),
+      -libc::EINVAL
+    );
+    let mut listener = ptr::null_mut();
+    let bad = CString::new("not an address").unwrap();
+    assert_eq!(
+      tcp_stack_listen(stack, bad.as_ptr(), &mut listener),
+      -libc::EINVAL
+    );
+    assert_eq!(
+      tcp_stack_send(ptr::null_mut(), ptr::null(), 0),
+      -libc::EINVAL as isize
+    );
+    assert_eq!(tcp_stack_close(ptr::null_mut()), -libc::EINVAL);
+
+    tcp_stack_free(stack);
+  }
+}
+
+#[test]
+fn test_ffi_connect_refused_is_econnrefused() {
+  if let Err(e) = check_raw_socket_privilege() {
+    eprintln!("skipping: {e}");
+    return;
+  }
+  let port = {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+  };
+  let remote = CString::new(format!("127.0.0.1:{port}")).unwrap();
+
+  unsafe {
+    let stack = tcp_stack_new();
+    assert_eq!(tcp_stack_set_connect_timeout(stack, 3000), 0);
+    let mut conn = ptr::null_mut();
+    assert_eq!(
+      tcp_stack_connect(stack, remote.as_ptr(), &mut conn),
+      -libc::ECONNREFUSED
+    );
+    assert!(conn.is_null());
+    tcp_stack_free(stack);
+  }
+}
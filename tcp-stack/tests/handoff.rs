@@ -0,0 +1,56 @@
+//! Handing connections to another process over a Unix socket
+//!
+//! Datagram socket pairs stand in for the raw sockets, so no privileges
+//! are needed to see the descriptors and snapshots arrive.
+
+#![cfg(all(unix, feature = "raw-socket"))]
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use tcp_stack::connection::{recv_handoff, send_handoff, TcpState};
+use tcp_stack::{RawSocket, TcpConnection};
+
+#[test]
+fn test_handoff_passes_socket_and_connections() {
+  let (send_fd, _send_peer) = UnixDatagram::pair().unwrap();
+  let (recv_fd, recv_peer) = UnixDatagram::pair().unwrap();
+  let socket = RawSocket::from_fds(OwnedFd::from(send_fd), OwnedFd::from(recv_fd));
+
+  let local = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 40000);
+  let remote = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80);
+  let mut conn = TcpConnection::new(socket.try_clone().unwrap(), local, remote);
+  conn.control_mut().state = TcpState::Established;
+  conn.control_mut().write(b"queued");
+  let opening = TcpConnection::new(socket.try_clone().unwrap(), local, remote);
+
+  let (mut old, mut new) = UnixStream::pair().unwrap();
+  send_handoff(&mut old, &socket, &[&conn, &opening]).unwrap();
+  drop((conn, opening, socket));
+
+  let handoff = recv_handoff(&mut new).unwrap();
+  assert_eq!(handoff.snapshots.len(), 1);
+  for fd in handoff.socket.as_fds() {
+    let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+    assert_ne!(flags & libc::FD_CLOEXEC, 0, "descriptors arrive close-on-exec");
+  }
+  let [_, recv] = handoff.socket.as_fds();
+  let recv = UnixDatagram::from(recv.try_clone_to_owned().unwrap());
+  recv_peer.send(b"still open").unwrap();
+  let mut buf = [0u8; 16];
+  assert_eq!(recv.recv(&mut buf).unwrap(), 10);
+
+  let resumed = handoff.resume().unwrap();
+  assert_eq!(resumed.len(), 1);
+  assert_eq!(resumed[0].local_addr(), local);
+  assert_eq!(resumed[0].remote_addr(), remote);
+  assert_eq!(resumed[0].state(), TcpState::Established);
+  assert_eq!(resumed[0].control().send_queue.queued(), 6);
+}
+
+#[test]
+fn test_handoff_rejects_stream_without_descriptors() {
+  let (mut old, mut new) = UnixStream::pair().unwrap();
+  std::io::Write::write_all(&mut old, b"TCPH\0\0\0\0").unwrap();
+  assert!(recv_handoff(&mut new).is_err());
+}
@@ -1,8 +1,11 @@
 //! Integration tests for TCP stack
 
-use std::net::Ipv4Addr;
-use tcp_stack::packet::{Ipv4Header, TcpFlags, TcpHeader, TcpOption};
-use tcp_stack::utils::{SeqNumber, calculate_checksum};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use tcp_stack::packet::{
+  FilterError, IpOption, Ipv4Header, OptionPadding, PacketFilter, TcpFlags, TcpHeader,
+  TcpOption, TimestampFlag,
+};
+use tcp_stack::utils::{calculate_checksum, SeqNumber};
 
 #[test]
 fn test_ipv4_header_serialization() {
@@ -17,6 +20,49 @@ fn test_ipv4_header_serialization() {
   assert_eq!(bytes[9], 6); // Protocol (TCP)
 }
 
+#[test]
+fn test_ipv4_options_adjust_header_length() {
+  let src = Ipv4Addr::new(192, 168, 1, 1);
+  let dst = Ipv4Addr::new(192, 168, 1, 2);
+  let mut header = Ipv4Header::new(src, dst, 100);
+
+  // 3 + 4 * 2 bytes of Record Route, padded to 12
+  assert!(header.set_options(&[IpOption::record_route(2)]));
+  assert_eq!(header.ihl, 8);
+  assert_eq!(header.total_length, 132);
+  let bytes = header.serialize();
+  assert_eq!(bytes.len(), 32);
+  assert_eq!(&bytes[20..24], &[7, 11, 4, 0]);
+  assert_eq!(bytes[31], IpOption::KIND_END);
+  assert_eq!(calculate_checksum(&bytes), 0);
+
+  // Routers fill the first slot; the parse sees it recorded
+  let mut bytes = bytes;
+  bytes[22] = 8;
+  bytes[23..27].copy_from_slice(&[10, 0, 0, 1]);
+  let (parsed, _) = Ipv4Header::parse(&bytes).unwrap();
+  let options = parsed.parsed_options().unwrap();
+  assert_eq!(options[0].recorded_route(), &[Ipv4Addr::new(10, 0, 0, 1)]);
+  assert_eq!(options[1], IpOption::EndOfList);
+
+  let timestamps = IpOption::timestamps(TimestampFlag::WithAddresses, 4);
+  assert!(header.set_options(std::slice::from_ref(&timestamps)));
+  assert_eq!((header.ihl, header.total_length), (14, 156));
+  let (parsed, _) = Ipv4Header::parse(&header.serialize()).unwrap();
+  assert_eq!(parsed.parsed_options().unwrap(), vec![timestamps]);
+
+  // Past 40 bytes the header is left as it was
+  let too_many = [
+    IpOption::record_route(9),
+    IpOption::NoOperation,
+    IpOption::NoOperation,
+  ];
+  assert!(!header.set_options(&too_many));
+  assert_eq!(header.ihl, 14);
+  assert!(header.set_options(&[]));
+  assert_eq!((header.ihl, header.total_length), (5, 120));
+}
+
 #[test]
 fn test_tcp_header_syn() {
   let header = TcpHeader::syn(12345, 80, 1000, 1460);
@@ -52,6 +98,79 @@ fn test_tcp_header_ack() {
   assert!(!header.flags.is_syn());
 }
 
+#[test]
+fn test_tcp_header_roundtrip() {
+  let mut header = TcpHeader::new(80, 12345);
+  header.ack_num = 1001;
+  header.flags = TcpFlags(TcpFlags::ACK | TcpFlags::ECE | TcpFlags::CWR);
+  header.options = vec![
+    TcpOption::NoOperation,
+    TcpOption::NoOperation,
+    TcpOption::Timestamp {
+      ts_val: 7,
+      ts_ecr: 3,
+    },
+  ];
+  let bytes = header.serialize();
+
+  assert_eq!(bytes[12] >> 4, header.data_offset());
+  assert_eq!(bytes[13], header.flags.0);
+
+  let (parsed, payload) = TcpHeader::parse(&bytes).unwrap();
+  assert!(payload.is_empty());
+  assert_eq!(parsed.data_offset(), 8);
+  assert_eq!(parsed.flags, header.flags);
+  assert_eq!(parsed.ack_num, 1001);
+  assert_eq!(parsed.options, header.options);
+}
+
+#[test]
+fn test_header_lengths_below_minimum_are_rejected() {
+  let mut tcp = TcpHeader::new(80, 12345).serialize();
+  tcp[12] = 4 << 4;
+  assert!(TcpHeader::parse(&tcp).is_none());
+
+  let mut ip = Ipv4Header::new(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), 0)
+    .serialize();
+  ip[0] = 0x44;
+  assert!(Ipv4Header::parse(&ip).is_none());
+
+  // A data offset past the end of the packet
+  tcp[12] = 6 << 4;
+  assert!(TcpHeader::parse(&tcp).is_none());
+}
+
+#[test]
+fn test_options_past_the_data_offset_limit_are_left_out() {
+  let sack = |n: u32| {
+    (0..n).map(|i| TcpOption::Sack {
+      left: i * 100,
+      right: i * 100 + 50,
+    })
+  };
+  let mut header = TcpHeader::new(80, 12345);
+  header.set_options(
+    std::iter::once(TcpOption::Timestamp {
+      ts_val: 1,
+      ts_ecr: 2,
+    })
+    .chain(sack(5))
+    .collect(),
+  );
+  let bytes = header.serialize();
+  assert_eq!(header.data_offset(), 15);
+  assert_eq!(bytes.len(), 60);
+
+  // The SACK option is cut to the three blocks that fit
+  let (parsed, _) = TcpHeader::parse(&bytes).unwrap();
+  assert_eq!(parsed.canonical_options()[..3], sack(3).collect::<Vec<_>>());
+
+  header.options = sack(5).collect();
+  header.padding = OptionPadding::EndOfList;
+  assert_eq!(header.data_offset(), 14);
+  assert_eq!(header.serialize()[21], 34);
+}
+
 #[test]
 fn test_sequence_number_arithmetic() {
   let seq1 = SeqNumber(100);
@@ -133,6 +252,104 @@ fn test_tcp_options_timestamp() {
   );
 }
 
+#[test]
+fn test_tcp_options_mptcp_round_trip() {
+  use tcp_stack::packet::{Dss, DssMapping, MpCapable, MptcpOption};
+
+  // A version 1 SYN carries no key; the third ACK carries both
+  let syn = [30, 4, 0x01, MpCapable::HMAC_SHA256];
+  let (option, len) = TcpOption::parse(&syn).unwrap();
+  assert_eq!(len, 4);
+  let TcpOption::Mptcp(MptcpOption::MpCapable(capable)) = &option else {
+    panic!("not MP_CAPABLE: {option:?}");
+  };
+  assert_eq!((capable.version, capable.sender_key), (1, None));
+  assert_eq!(option.serialize(), syn);
+
+  let ack = TcpOption::Mptcp(MptcpOption::MpCapable(MpCapable {
+    version: 1,
+    flags: MpCapable::CHECKSUM | MpCapable::HMAC_SHA256,
+    sender_key: Some(0x0102_0304_0506_0708),
+    receiver_key: Some(0x1112_1314_1516_1718),
+    data_len: None,
+    checksum: None,
+  }));
+  let bytes = ack.serialize();
+  assert_eq!(bytes[..4], [30, 20, 0x01, 0x81]);
+  assert_eq!(TcpOption::parse(&bytes), Some((ack, 20)));
+
+  // Data ACK of 8 octets, mapping with a 4-octet DSN and a checksum
+  let dss = TcpOption::Mptcp(MptcpOption::Dss(Dss {
+    flags: Dss::DATA_FIN | Dss::MAPPING | Dss::ACK_64 | Dss::DATA_ACK,
+    data_ack: Some(0xaabb_ccdd_0011_2233),
+    mapping: Some(DssMapping {
+      dsn: 0x4455_6677,
+      subflow_seq: 1,
+      data_len: 1400,
+      checksum: Some(0xbeef),
+    }),
+  }));
+  let bytes = dss.serialize();
+  assert_eq!(bytes[..4], [30, 24, 0x20, 0x17]);
+  assert_eq!(TcpOption::parse(&bytes), Some((dss, 24)));
+
+  // Other subtypes keep their bytes; malformed ones are skipped whole
+  let add_addr = [30, 8, 0x30, 7, 10, 0, 0, 3];
+  let (option, _) = TcpOption::parse(&add_addr).unwrap();
+  assert_eq!(option.serialize(), add_addr);
+  assert_eq!(
+    TcpOption::parse(&[30, 5, 0x00, 0, 0]),
+    Some((TcpOption::NoOperation, 5))
+  );
+}
+
+#[test]
+fn test_peer_mptcp_options_reach_the_control_block() {
+  use tcp_stack::connection::{ControlBlock, Engine};
+  use tcp_stack::packet::{Dss, DssMapping, MpCapable, MptcpOption};
+  use tcp_stack::utils::Instant;
+
+  let local = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 40000);
+  let remote = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80);
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(1000), Instant::ZERO);
+  Engine::new(local, remote, &mut control).open(Instant::ZERO);
+  let capable = MpCapable {
+    version: 1,
+    flags: MpCapable::HMAC_SHA256,
+    sender_key: Some(42),
+    receiver_key: None,
+    data_len: None,
+    checksum: None,
+  };
+  let mut syn_ack = TcpHeader::syn_ack(80, 40000, 5000, 1001, 1460);
+  syn_ack
+    .options
+    .push(TcpOption::Mptcp(MptcpOption::MpCapable(capable)));
+  let bytes = syn_ack.serialize();
+  let (syn_ack, _) = TcpHeader::parse(&bytes).unwrap();
+  Engine::new(local, remote, &mut control).on_segment(&syn_ack, &[], Instant::ZERO);
+  assert_eq!(control.peer_mp_capable, Some(capable));
+
+  let dss = Dss {
+    flags: 0,
+    data_ack: None,
+    mapping: Some(DssMapping {
+      dsn: 1,
+      subflow_seq: 1,
+      data_len: 5,
+      checksum: None,
+    }),
+  };
+  let mut data = TcpHeader::new(80, 40000);
+  data.flags = TcpFlags::new().with_ack();
+  data.seq_num = 5001;
+  data.ack_num = 1001;
+  data.window_size = 1000;
+  data.options = vec![TcpOption::Mptcp(MptcpOption::Dss(dss))];
+  Engine::new(local, remote, &mut control).on_segment(&data, b"hello", Instant::ZERO);
+  assert_eq!(control.peer_dss, Some(dss));
+}
+
 #[test]
 fn test_connection_state_transitions() {
   use tcp_stack::connection::TcpState;
@@ -150,6 +367,24 @@ fn test_connection_state_transitions() {
   assert!(state.is_established());
 }
 
+#[test]
+fn test_fair_scheduler_shares_bytes_not_packets() {
+  use tcp_stack::flow_control::FairScheduler;
+
+  let mut scheduler = FairScheduler::new();
+  for _ in 0..4 {
+    scheduler.push('a', vec![0u8; 1500]);
+  }
+  for _ in 0..8 {
+    scheduler.push('b', vec![0u8; 750]);
+  }
+  let order: String = std::iter::from_fn(|| scheduler.pop())
+    .map(|(flow, _)| flow)
+    .collect();
+  assert_eq!(order, "abbabbabbabb");
+  assert!(scheduler.is_empty());
+}
+
 #[test]
 fn test_sliding_window() {
   use tcp_stack::flow_control::SlidingWindow;
@@ -165,6 +400,38 @@ fn test_sliding_window() {
   assert!(window.can_send(SeqNumber(500), 500));
 }
 
+#[test]
+fn test_sliding_window_updates_across_wrap() {
+  use tcp_stack::flow_control::SlidingWindow;
+
+  let una = SeqNumber(u32::MAX - 499);
+  let peer_seq = SeqNumber(u32::MAX - 9);
+  let mut window = SlidingWindow::new(0);
+  assert!(window.update(peer_seq, una, 1000));
+  assert_eq!(window.right_edge(), SeqNumber(500));
+  assert_eq!(window.available(una), 1000);
+  assert_eq!(window.available(SeqNumber(400)), 100);
+  assert_eq!(window.available(SeqNumber(500)), 0);
+
+  // Newer by SEG.SEQ, past the wrap of the peer's sequence space
+  assert!(window.update(peer_seq + 20, una + 600, 2000));
+  assert_eq!(window.wl1(), Some(SeqNumber(10)));
+  assert_eq!(window.left_edge(), SeqNumber(100));
+  assert_eq!(window.right_edge(), SeqNumber(2100));
+
+  // A reordered segment with an older SEG.SEQ cannot reopen a stale window
+  assert!(!window.update(peer_seq + 5, una + 700, 8000));
+  assert_eq!(window.size(), 2000);
+  // Same SEG.SEQ needs SEG.ACK at least SND.WL2
+  assert!(!window.update(peer_seq + 20, una + 500, 8000));
+  assert!(window.update(peer_seq + 20, una + 600, 500));
+
+  // The peer shrank its window below data already sent
+  assert_eq!(window.right_edge(), SeqNumber(600));
+  assert_eq!(window.available(SeqNumber(700)), 0);
+  assert!(!window.can_send(SeqNumber(500), 200));
+}
+
 #[test]
 fn test_reorder_buffer() {
   use tcp_stack::reliability::ReorderBuffer;
@@ -181,6 +448,39 @@ fn test_reorder_buffer() {
   assert_eq!(ready[0].0, SeqNumber(0));
 }
 
+#[test]
+fn test_reorder_buffer_caps_segment_count() {
+  use tcp_stack::reliability::ReorderBuffer;
+
+  let mut buffer = ReorderBuffer::new();
+  buffer.set_next_expected(SeqNumber(0));
+  buffer.set_max_segments(4);
+
+  // One-byte segments, each behind its own hole
+  for seq in [10, 20, 30, 40] {
+    buffer.add(SeqNumber(seq), vec![0]);
+  }
+  assert_eq!(buffer.held_segments(), 4);
+
+  // Beyond everything held: refused
+  buffer.add(SeqNumber(50), vec![0]);
+  assert_eq!(buffer.held_segments(), 4);
+  assert_eq!(buffer.evicted(), 0);
+
+  // Nearer the oldest hole: the furthest segment makes room
+  buffer.add(SeqNumber(5), vec![0]);
+  assert_eq!(buffer.held_segments(), 4);
+  assert_eq!(buffer.held_bytes(), 4);
+  assert_eq!(buffer.evicted(), 1);
+  let lefts: Vec<u32> = buffer.sack_blocks().iter().map(|block| block.0 .0).collect();
+  assert_eq!(lefts, [5, 10, 20, 30]);
+
+  // Filling the hole still delivers
+  let ready = buffer.add(SeqNumber(0), vec![0; 5]);
+  assert_eq!(ready.len(), 2);
+  assert_eq!(buffer.held_segments(), 3);
+}
+
 #[test]
 fn test_newreno_congestion_control() {
   use tcp_stack::congestion::NewReno;
@@ -193,6 +493,1178 @@ fn test_newreno_congestion_control() {
   assert!(cc.cwnd() > initial_cwnd);
 
   // Simulate packet loss
-  cc.on_timeout();
+  cc.on_timeout(cc.cwnd());
   assert_eq!(cc.cwnd(), 1460); // Back to 1 MSS
 }
+
+#[test]
+fn test_loss_halves_flight_size_not_cwnd() {
+  use tcp_stack::connection::ControlBlock;
+  use tcp_stack::utils::Instant;
+
+  let now = Instant::from_secs(1);
+  let iss = SeqNumber(0);
+  let mut pcb = ControlBlock::with_initial_seq(iss, now);
+  for i in 0..20 {
+    pcb.congestion.on_ack(SeqNumber(i), 1460);
+  }
+  assert_eq!(pcb.congestion.cwnd(), 30_660);
+
+  // Application-limited: only three segments outstanding, one SACKed
+  pcb.send_queue.write(&[0u8; 3 * 1460]);
+  pcb.next_segments(now);
+  assert_eq!(pcb.flight_size(), 3 * 1460);
+  pcb.retransmit.on_sack(iss + 1460, iss + 2920);
+  assert_eq!(pcb.flight_size(), 2 * 1460);
+
+  pcb.on_retransmit_timeout();
+  // max(FlightSize / 2, 2 * SMSS) rather than cwnd / 2
+  assert_eq!(pcb.congestion.ssthresh(), 2 * 1460);
+  assert_eq!(pcb.congestion.cwnd(), 1460);
+}
+
+#[test]
+fn test_retransmit_timer_with_caller_clock() {
+  use std::time::Duration;
+  use tcp_stack::reliability::retransmit::{PendingSegment, SegmentKind};
+  use tcp_stack::reliability::RetransmissionManager;
+  use tcp_stack::utils::Instant;
+
+  let start = Instant::from_secs(100);
+  let mut manager = RetransmissionManager::new();
+  manager.add_segment(
+    PendingSegment {
+      seq: SeqNumber(1000),
+      len: 3,
+      data: vec![1, 2, 3].into(),
+      retransmit_count: 0,
+      first_sent: start,
+      kind: SegmentKind::Data,
+    },
+    1.0,
+    start,
+  );
+
+  assert!(!manager.should_retransmit(start + Duration::from_millis(999)));
+  assert!(manager.should_retransmit(start + Duration::from_secs(1)));
+
+  let segments = manager.get_retransmit_segments(1.0, start + Duration::from_secs(1));
+  assert_eq!(segments.len(), 1);
+  assert_eq!(segments[0].seq, SeqNumber(1000));
+  assert_eq!(segments[0].data, [1, 2, 3][..]);
+  let held = manager.segment_at(SeqNumber(1000)).unwrap();
+  assert_eq!(held.retransmit_count, 1);
+
+  let acked = manager.acknowledge(SeqNumber(1003), start + Duration::from_secs(2));
+  assert_eq!(acked.len(), 1);
+  assert_eq!(manager.pending_count(), 0);
+}
+
+#[test]
+fn test_pending_segments_stay_ordered_across_wrap() {
+  use tcp_stack::reliability::retransmit::{PendingSegment, SegmentKind};
+  use tcp_stack::reliability::RetransmissionManager;
+  use tcp_stack::utils::Instant;
+
+  let segment = |seq: SeqNumber| PendingSegment {
+    seq,
+    len: 100,
+    data: vec![0; 100].into(),
+    retransmit_count: 0,
+    first_sent: Instant::ZERO,
+    kind: SegmentKind::Data,
+  };
+  let start = SeqNumber(u32::MAX - 249);
+  let mut manager = RetransmissionManager::new();
+  for i in 0..6 {
+    manager.add_segment(segment(start + i * 100), 1.0, Instant::ZERO);
+  }
+  let seqs: Vec<_> = manager.pending().map(|seg| seg.seq).collect();
+  assert_eq!(seqs[3], SeqNumber(50));
+  assert!(manager.segment_at(SeqNumber(150)).is_some());
+  assert!(manager.segment_at(SeqNumber(151)).is_none());
+
+  // SACKed segments past the wrap, then a cumulative ACK up to them
+  assert_eq!(manager.on_sack(SeqNumber(50), SeqNumber(250)), 200);
+  let acked = manager.acknowledge(SeqNumber(50), Instant::ZERO);
+  assert_eq!(acked.len(), 3);
+  assert_eq!(manager.sacked_bytes(), 200);
+  assert_eq!(manager.in_flight(), 300);
+}
+
+#[cfg(all(target_os = "linux", feature = "raw-socket"))]
+#[test]
+fn test_privilege_check_matches_socket_creation() {
+  use tcp_stack::socket::privilege::check_raw_socket_privilege;
+  use tcp_stack::{RawSocket, TcpError};
+
+  match (check_raw_socket_privilege(), RawSocket::new()) {
+    (Ok(()), socket) => assert!(socket.is_ok()),
+    (Err(TcpError::MissingPrivilege(reason)), Err(e)) => {
+      assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+      assert_eq!(e.to_string(), reason);
+    }
+    (Err(e), _) => panic!("unexpected result: {e}"),
+  }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_shards_partition_flows() {
+  use std::net::SocketAddrV4;
+  use tcp_stack::demux::{ConnectionKey, Shard};
+
+  let local = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 443);
+  let shards: Vec<_> = (0..4).map(|i| Shard::new(i, 4)).collect();
+  let mut per_shard = [0; 4];
+
+  for port in 40000..41000 {
+    let key =
+      ConnectionKey::new(local, SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), port));
+    let owners: Vec<_> = shards.iter().filter(|s| s.owns(&key)).collect();
+    assert_eq!(owners.len(), 1);
+    assert_eq!(key.shard(4), owners[0].index);
+    per_shard[owners[0].index] += 1;
+  }
+
+  // Neighbouring ports still spread over every shard
+  assert!(per_shard.iter().all(|&n| n > 150), "{per_shard:?}");
+}
+
+#[test]
+fn test_token_bucket_paces_sends() {
+  use std::time::Duration;
+  use tcp_stack::flow_control::TokenBucket;
+  use tcp_stack::utils::Instant;
+
+  let start = Instant::from_secs(1);
+  let mut bucket = TokenBucket::new(100_000, start);
+  assert_eq!(bucket.available(start), 3000);
+
+  bucket.consume(3000);
+  assert_eq!(bucket.available(start), 0);
+  assert_eq!(bucket.delay_until(1000, start), Duration::from_millis(10));
+  assert_eq!(bucket.available(start + Duration::from_millis(10)), 1000);
+  // Never refills past the burst
+  assert_eq!(bucket.available(start + Duration::from_secs(10)), 3000);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_send_budget_honours_rate_limits() {
+  use std::time::Duration;
+  use tcp_stack::connection::ControlBlock;
+  use tcp_stack::flow_control::SharedShaper;
+  use tcp_stack::utils::Instant;
+
+  let start = Instant::from_secs(1);
+  let mut pcb = ControlBlock::with_initial_seq(SeqNumber(1000), start);
+  let cwnd = pcb.congestion.cwnd();
+  assert_eq!(pcb.send_budget(start), cwnd);
+
+  // The rate cap applies even when cwnd has room
+  pcb.set_max_send_rate(Some(1_000_000), start);
+  pcb.on_transmit(10_000);
+  assert_eq!(pcb.send_budget(start), 0);
+  assert_eq!(pcb.send_budget(start + Duration::from_millis(1)), 1000);
+
+  // A shared shaper caps the connections attached to it together
+  pcb.set_max_send_rate(None, start);
+  let shaper = SharedShaper::new(100_000, start);
+  let mut other = ControlBlock::with_initial_seq(SeqNumber(5000), start);
+  pcb.shaper = Some(shaper.clone());
+  other.shaper = Some(shaper);
+  pcb.on_transmit(2000);
+  assert_eq!(other.send_budget(start), 1000);
+}
+
+#[test]
+fn test_pacer_follows_cwnd_over_rtt() {
+  use std::time::Duration;
+  use tcp_stack::connection::ControlBlock;
+  use tcp_stack::flow_control::Pacer;
+  use tcp_stack::utils::Instant;
+
+  let start = Instant::from_secs(1);
+  let mut pcb = ControlBlock::with_initial_seq(SeqNumber(1000), start);
+  let mut pacer = Pacer::new(start);
+  pacer.set_shift(4);
+  pcb.pacer = Some(pacer);
+
+  // Nothing to pace over before an RTT sample
+  let cwnd = pcb.congestion.cwnd();
+  assert_eq!(pcb.send_budget(start), cwnd);
+
+  // 120% of a 14600-byte cwnd per 100ms, with bursts of 1/16s worth
+  pcb.rtt_estimator.update(0.1);
+  pcb.congestion.restore(14600, 10_000);
+  pcb.send_budget(start);
+  let pacer = pcb.pacer.as_ref().unwrap();
+  assert_eq!(pacer.rate(), 175_200);
+  assert_eq!(pacer.burst(), 175_200 >> 4);
+  pcb.on_transmit(14600);
+  assert_eq!(pcb.send_budget(start), 0);
+  assert_eq!(pcb.send_budget(start + Duration::from_millis(10)), 1752);
+
+  // 200% in slow start
+  pcb.congestion.restore(14600, 65535);
+  pcb.send_budget(start);
+  assert_eq!(pcb.pacer.as_ref().unwrap().rate(), 292_000);
+
+  // Smoothing moves halfway back towards the congestion avoidance rate
+  pcb.pacer.as_mut().unwrap().set_smoothing(1);
+  pcb.congestion.restore(14600, 10_000);
+  pcb.send_budget(start);
+  assert_eq!(pcb.pacer.as_ref().unwrap().rate(), 233_600);
+}
+
+#[test]
+fn test_effective_window_is_min_of_peer_window_and_cwnd() {
+  use tcp_stack::connection::ControlBlock;
+  use tcp_stack::utils::Instant;
+
+  let iss = SeqNumber(u32::MAX - 100);
+  let mut pcb = ControlBlock::with_initial_seq(iss, Instant::from_secs(1));
+  let cwnd = pcb.congestion.cwnd();
+  let peer = SeqNumber(5000);
+
+  // A large peer window leaves cwnd in charge
+  pcb.peer_window_scale = 2;
+  let window = pcb.scaled_peer_window(60_000);
+  assert!(pcb.update_send_window(peer, iss, window));
+  assert_eq!(pcb.snd_wnd(), 240_000);
+  assert_eq!(pcb.effective_window(), cwnd);
+
+  // In-flight bytes count from SND.UNA, across sequence wrap
+  pcb.on_send(iss, 1000);
+  assert_eq!(pcb.can_send_bytes(), cwnd - 1000);
+
+  // A small peer window takes over
+  assert!(pcb.update_send_window(peer + 1, iss, 1200));
+  assert_eq!(pcb.effective_window(), 1200);
+  assert_eq!(pcb.can_send_bytes(), 200);
+  pcb.on_send(pcb.snd_nxt(), 200);
+  assert_eq!(pcb.can_send_bytes(), 0);
+  // Outstanding data will draw a window update, so no probe yet
+  assert!(!pcb.needs_window_probe(100));
+
+  // A closed window with nothing in flight needs the persist timer
+  assert!(pcb.on_ack(pcb.snd_nxt()));
+  assert!(pcb.update_send_window(peer + 2, pcb.snd_una(), 0));
+  assert_eq!(pcb.can_send_bytes(), 0);
+  assert!(pcb.needs_window_probe(100));
+  assert!(!pcb.needs_window_probe(0));
+}
+
+#[test]
+fn test_reordered_acks_do_not_move_send_window() {
+  use tcp_stack::connection::ControlBlock;
+  use tcp_stack::utils::Instant;
+
+  let iss = SeqNumber(u32::MAX - 1000);
+  let mut pcb = ControlBlock::with_initial_seq(iss, Instant::from_secs(1));
+  pcb.on_send(iss, 3000);
+  let peer = SeqNumber(7000);
+
+  assert!(pcb.update_send_window(peer, iss + 1000, 4000));
+  assert_eq!(pcb.send_wl1(), Some(peer));
+  assert_eq!(pcb.send_wl2(), Some(iss + 1000));
+
+  // The peer's later ACK arrives first...
+  assert!(pcb.update_send_window(peer, iss + 2000, 8000));
+  // ...so the earlier one must not shrink the window back
+  assert!(!pcb.update_send_window(peer, iss + 1500, 1000));
+  assert_eq!(pcb.snd_wnd(), 8000);
+
+  // Nor may an ACK of data never sent inflate it
+  assert!(!pcb.update_send_window(peer + 1, iss + 4000, 65535));
+  assert_eq!(pcb.snd_wnd(), 8000);
+
+  // Newer data from the peer carries a fresh advertisement
+  assert!(pcb.update_send_window(peer + 100, iss + 2000, 2000));
+  assert_eq!(pcb.snd_wnd(), 2000);
+  assert_eq!(pcb.send_wl1(), Some(peer + 100));
+}
+
+#[test]
+fn test_ipv4_dscp_ecn_roundtrip() {
+  let mut header =
+    Ipv4Header::new(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), 20);
+  header.dscp = Ipv4Header::DSCP_EF;
+  header.ecn = Ipv4Header::ECN_ECT0;
+
+  let bytes = header.serialize();
+  assert_eq!(bytes[1], 0xBA);
+
+  let (parsed, _) = Ipv4Header::parse(&bytes).unwrap();
+  assert_eq!(parsed.dscp, Ipv4Header::DSCP_EF);
+  assert_eq!(parsed.ecn, Ipv4Header::ECN_ECT0);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_demux_rejects_duplicate_endpoints() {
+  use std::net::SocketAddrV4;
+  use tcp_stack::demux::{ConnectionKey, Demultiplexer, Shard};
+  use tcp_stack::TcpError;
+
+  let local = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 8080);
+  let remote = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 50000);
+  let mut demux = Demultiplexer::new();
+
+  let id = demux.register(ConnectionKey::new(local, remote)).unwrap();
+  let dup = demux.register(ConnectionKey::new(local, remote));
+  assert!(matches!(dup, Err(TcpError::AddrInUse(addr)) if addr == local));
+  assert_eq!(demux.find(&ConnectionKey::new(local, remote)), Some(id));
+
+  let any = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 8080);
+  demux.listen(any, Shard::new(0, 2)).unwrap();
+  demux.listen(any, Shard::new(1, 2)).unwrap();
+  assert!(demux.listen(any, Shard::new(1, 2)).is_err());
+  assert!(demux.listen(local, Shard::single()).is_err());
+
+  demux.unlisten(any, Shard::new(0, 2));
+  demux.unlisten(any, Shard::new(1, 2));
+  demux.listen(local, Shard::single()).unwrap();
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_stale_connection_ids_are_rejected() {
+  use std::net::SocketAddrV4;
+  use tcp_stack::demux::{ConnectionKey, Demultiplexer};
+
+  let local = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 8080);
+  let old_key =
+    ConnectionKey::new(local, SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 1));
+  let new_key =
+    ConnectionKey::new(local, SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 3), 2));
+  let mut demux = Demultiplexer::new();
+
+  let old = demux.register(old_key.clone()).unwrap();
+  assert_eq!(demux.unregister(old), Some(old_key));
+
+  // The slot is reused under a new generation
+  let new = demux.register(new_key.clone()).unwrap();
+  assert_eq!(new.index(), old.index());
+  assert_ne!(new, old);
+  assert_eq!(demux.key(old), None);
+  assert_eq!(demux.unregister(old), None);
+  assert_eq!(demux.key(new), Some(&new_key));
+  assert_eq!(demux.len(), 1);
+}
+
+#[test]
+fn test_multiple_sack_blocks_share_one_option() {
+  let mut header = TcpHeader::new(80, 50000);
+  header.flags = TcpFlags::new().with_ack();
+  header.set_options(vec![
+    TcpOption::NoOperation,
+    TcpOption::NoOperation,
+    TcpOption::Sack {
+      left: 1000,
+      right: 2000,
+    },
+    TcpOption::Sack {
+      left: 3000,
+      right: 4000,
+    },
+  ]);
+  assert_eq!(header.data_offset(), 10);
+
+  let bytes = header.serialize();
+  assert_eq!(&bytes[20..24], &[1, 1, TcpOption::KIND_SACK, 18]);
+
+  let (parsed, _) = TcpHeader::parse(&bytes).unwrap();
+  assert_eq!(parsed.options, header.options);
+}
+
+#[test]
+fn test_ack_policy_decisions() {
+  use std::time::Duration;
+  use tcp_stack::connection::ack::ReceivedSegment;
+  use tcp_stack::connection::{AckDecision, AckGenerator};
+  use tcp_stack::utils::Instant;
+
+  let now = Instant::from_secs(1);
+  let full = ReceivedSegment {
+    len: 1460,
+    full_sized: true,
+    in_order: true,
+    ..ReceivedSegment::default()
+  };
+  let mut acks = AckGenerator::new();
+
+  // Every other full-sized segment, otherwise the delayed-ACK timer
+  let deadline = now + Duration::from_millis(40);
+  assert_eq!(acks.on_segment(full, now), AckDecision::Delayed(deadline));
+  assert_eq!(acks.on_segment(full, now), AckDecision::Immediate);
+  acks.on_ack_sent(65535);
+  assert_eq!(acks.on_segment(full, now), AckDecision::Delayed(deadline));
+  assert!(!acks.poll(now));
+  assert!(acks.poll(deadline));
+
+  let out_of_order = ReceivedSegment {
+    in_order: false,
+    ..full
+  };
+  let marked = ReceivedSegment { ce: true, ..full };
+  let pure_ack = ReceivedSegment { len: 0, ..full };
+  assert_eq!(acks.on_segment(out_of_order, now), AckDecision::Immediate);
+  assert_eq!(acks.on_segment(marked, now), AckDecision::Immediate);
+  assert_eq!(acks.on_segment(pure_ack, now), AckDecision::None);
+
+  // Window updates once the window reopens to an MSS or grows by two
+  acks.on_ack_sent(0);
+  assert_eq!(acks.on_window_update(1000, 1460, now), AckDecision::None);
+  assert_eq!(
+    acks.on_window_update(1460, 1460, now),
+    AckDecision::Immediate
+  );
+  acks.on_ack_sent(1460);
+  assert_eq!(
+    acks.on_window_update(4380, 1460, now),
+    AckDecision::Immediate
+  );
+
+  // The update that reopened the window is re-sent, backing off, while
+  // the peer stays silent
+  let mut at = now;
+  for wait in [200, 400, 800, 1600] {
+    at += Duration::from_millis(wait);
+    assert!(!acks.poll_window_update(at - Duration::from_millis(1)));
+    assert!(acks.poll_window_update(at));
+  }
+  at += Duration::from_millis(3200);
+  assert!(!acks.poll_window_update(at));
+  assert_eq!(acks.window_update_deadline(), None);
+
+  acks.on_ack_sent(0);
+  acks.on_window_update(1460, 1460, now);
+  acks.on_peer_segment();
+  assert!(!acks.poll_window_update(now + Duration::from_secs(1)));
+}
+
+#[test]
+fn test_sack_options_from_reorder_buffer() {
+  use tcp_stack::connection::AckGenerator;
+  use tcp_stack::reliability::ReorderBuffer;
+
+  let mut buffer = ReorderBuffer::new();
+  buffer.set_next_expected(SeqNumber(1000));
+  buffer.add(SeqNumber(2000), vec![0; 100]);
+  buffer.add(SeqNumber(2100), vec![0; 100]);
+  buffer.add(SeqNumber(3000), vec![0; 100]);
+
+  let options = AckGenerator::new().sack_options(&buffer);
+  assert_eq!(
+    options,
+    vec![
+      TcpOption::Sack {
+        left: 2000,
+        right: 2200
+      },
+      TcpOption::Sack {
+        left: 3000,
+        right: 3100
+      },
+    ]
+  );
+}
+
+#[test]
+fn test_verify_checksum() {
+  let src = u32::from(Ipv4Addr::new(10, 0, 0, 1));
+  let dst = u32::from(Ipv4Addr::new(10, 0, 0, 2));
+  let payload = b"hello";
+  let header = TcpHeader::syn(40000, 80, 1000, 1460);
+  let checksum = header.calculate_checksum(src, dst, payload);
+
+  let mut segment = header.serialize();
+  segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+  segment.extend_from_slice(payload);
+  assert!(TcpHeader::verify_checksum(src, dst, &segment));
+
+  let last = segment.len() - 1;
+  segment[last] ^= 0x01;
+  assert!(!TcpHeader::verify_checksum(src, dst, &segment));
+}
+
+#[test]
+fn test_drop_counters() {
+  use tcp_stack::connection::{DropCounters, DropReason};
+
+  let mut drops = DropCounters::new();
+  drops.record(DropReason::BadChecksum);
+  drops.record(DropReason::BadChecksum);
+  drops.record(DropReason::TtlTooLow);
+
+  assert_eq!(drops.get(DropReason::BadChecksum), 2);
+  assert_eq!(drops.get(DropReason::TtlTooLow), 1);
+  assert_eq!(drops.get(DropReason::OutOfWindow), 0);
+  assert_eq!(drops.total(), 3);
+}
+
+#[test]
+fn test_receive_stream_assembles_bytes() {
+  use tcp_stack::reliability::ReceiveStream;
+
+  let mut stream = ReceiveStream::with_capacity(100);
+  stream.set_rcv_nxt(SeqNumber(u32::MAX - 4));
+
+  // Out of order: nothing readable yet, but the window is not consumed
+  assert_eq!(stream.push(SeqNumber(5), b"world".to_vec()), 0);
+  assert_eq!(stream.readable(), 0);
+  assert_eq!(stream.window(), 100);
+
+  // Filling the gap across the sequence wrap delivers both segments
+  assert_eq!(
+    stream.push(SeqNumber(u32::MAX - 4), b"hello.....".to_vec()),
+    15
+  );
+  assert_eq!(stream.rcv_nxt(), SeqNumber(10));
+  assert_eq!(stream.window(), 85);
+
+  // A retransmission overlapping RCV.NXT contributes only its new tail
+  assert_eq!(stream.push(SeqNumber(8), b"ld!".to_vec()), 1);
+
+  let mut buf = [0u8; 8];
+  assert_eq!(stream.read(&mut buf), 8);
+  assert_eq!(&buf, b"hello...");
+  let mut rest = [0u8; 16];
+  assert_eq!(stream.read(&mut rest), 8);
+  assert_eq!(&rest[..8], b"..world!");
+  assert_eq!(stream.window(), 100);
+}
+
+#[test]
+fn test_segmenter_respects_mss_window_and_nagle() {
+  use tcp_stack::flow_control::Segmenter;
+
+  let start = SeqNumber(u32::MAX - 999);
+  let mut segmenter = Segmenter::new();
+  segmenter.write(&[7u8; 2500]);
+
+  // Full-sized segments up to the budget; the 500-byte tail waits for the
+  // first ACK under Nagle
+  let segments = segmenter.segments(start, 1000, 10_000, 0);
+  let sizes: Vec<_> = segments.iter().map(|(seq, p)| (*seq, p.len())).collect();
+  assert_eq!(sizes, [(start, 1000), (SeqNumber(0), 1000)]);
+  assert_eq!(segmenter.queued(), 500);
+  assert!(segmenter
+    .segments(SeqNumber(1000), 1000, 10_000, 2000)
+    .is_empty());
+
+  // Once everything is acknowledged the tail goes out
+  let segments = segmenter.segments(SeqNumber(1000), 1000, 10_000, 0);
+  assert_eq!(segments.len(), 1);
+  assert_eq!(segments[0].1.len(), 500);
+
+  // Without Nagle a small write goes out despite data in flight, but a
+  // window too small for the queue still waits
+  segmenter.set_nagle(false);
+  segmenter.write(&[1u8; 100]);
+  assert_eq!(segmenter.segments(SeqNumber(1500), 1000, 50, 500).len(), 0);
+  let segments = segmenter.segments(SeqNumber(1500), 1000, 10_000, 500);
+  assert_eq!(segments[0].1.len(), 100);
+}
+
+#[test]
+fn test_timestamps_reduce_payload_room() {
+  use tcp_stack::connection::ControlBlock;
+  use tcp_stack::utils::Instant;
+
+  let now = Instant::from_secs(1);
+  let mut pcb = ControlBlock::with_initial_seq(SeqNumber(0), now);
+  pcb.mss = 1460;
+  assert_eq!(pcb.payload_room(0), 1460);
+
+  pcb.timestamps = true;
+  assert_eq!(pcb.payload_room(0), 1448);
+  let sack = [
+    TcpOption::NoOperation,
+    TcpOption::NoOperation,
+    TcpOption::Sack { left: 1, right: 2 },
+    TcpOption::Sack { left: 3, right: 4 },
+  ];
+  let sack_len = TcpHeader::padded_options_len(&sack, OptionPadding::Nop);
+  assert_eq!(sack_len, 20);
+  assert_eq!(pcb.payload_room(sack_len), 1428);
+
+  // Segments are cut to the payload room, not the raw MSS
+  pcb.send_queue.write(&[0u8; 3000]);
+  let segments = pcb.next_segments(now);
+  assert_eq!(segments[0].1.len(), 1448);
+  assert_eq!(
+    pcb.snd_nxt(),
+    SeqNumber(segments.iter().map(|(_, p)| p.len() as u32).sum())
+  );
+}
+
+#[test]
+fn test_control_block_sequence_variables_stay_coherent() {
+  use tcp_stack::connection::ControlBlock;
+  use tcp_stack::utils::Instant;
+
+  let iss = SeqNumber(u32::MAX - 10);
+  let mut pcb = ControlBlock::with_initial_seq(iss, Instant::from_secs(1));
+  assert_eq!(pcb.snd_una(), iss);
+  assert_eq!(pcb.send_window().left_edge(), iss);
+
+  pcb.on_send(iss, 100);
+  // Retransmitting earlier data leaves SND.NXT where it was
+  pcb.on_send(iss, 50);
+  assert_eq!(pcb.snd_nxt(), iss + 100);
+
+  // Acknowledgments move SND.UNA and the window's left edge together
+  assert!(pcb.on_ack(iss + 60));
+  assert!(!pcb.on_ack(iss + 40));
+  assert!(!pcb.on_ack(iss + 200));
+  assert_eq!(pcb.snd_una(), SeqNumber(49));
+  assert_eq!(pcb.in_flight(), 40);
+
+  // Delivered bytes advance RCV.NXT and shrink RCV.WND as one
+  pcb.set_irs(SeqNumber(999));
+  assert_eq!(pcb.rcv_nxt(), SeqNumber(1000));
+  let window = pcb.rcv_wnd();
+  pcb.recv_stream.push(SeqNumber(1000), vec![0u8; 300]);
+  assert_eq!(pcb.rcv_nxt(), SeqNumber(1300));
+  assert_eq!(pcb.rcv_wnd(), window - 300);
+}
+
+#[test]
+fn test_send_buffer_limit_counts_unsent_and_unacked_bytes() {
+  use tcp_stack::connection::ControlBlock;
+  use tcp_stack::utils::Instant;
+
+  let now = Instant::from_secs(1);
+  let iss = SeqNumber(1000);
+  let mut pcb = ControlBlock::with_initial_seq(iss, now);
+  pcb.send_buffer_limit = 4000;
+
+  assert_eq!(pcb.write(&[0u8; 3000]), 3000);
+  assert_eq!(pcb.write(&[0u8; 3000]), 1000);
+  assert_eq!(pcb.write(&[0u8; 10]), 0);
+
+  // Sending moves bytes to the retransmission queue: still held
+  let sent: usize = pcb.next_segments(now).iter().map(|(_, p)| p.len()).sum();
+  assert!(sent > 0);
+  assert_eq!(pcb.retransmit.buffered_bytes(), sent);
+  assert_eq!(pcb.retransmit.in_flight(), pcb.in_flight());
+  assert_eq!(pcb.send_buffer_free(), 0);
+
+  // Only acknowledgment frees space for the writer
+  let ack = iss + sent as u32;
+  assert!(pcb.on_ack(ack));
+  pcb.retransmit.acknowledge(ack, now);
+  assert_eq!(pcb.retransmit.buffered_bytes(), 0);
+  assert_eq!(pcb.send_buffer_free(), sent);
+  assert_eq!(pcb.write(&[0u8; 10_000]), sent);
+}
+
+#[test]
+fn test_write_readiness_tracks_window_and_low_watermark() {
+  use tcp_stack::connection::ControlBlock;
+  use tcp_stack::utils::Instant;
+
+  let now = Instant::from_secs(1);
+  let iss = SeqNumber(1000);
+  let mut pcb = ControlBlock::with_initial_seq(iss, now);
+  pcb.send_low_watermark = 500;
+
+  // The initial cwnd bounds what can go out at once
+  assert_eq!(pcb.writable_bytes(), 1460);
+  assert_eq!(pcb.write(&[0u8; 1000]), 1000);
+  assert_eq!(pcb.writable_bytes(), 460);
+  assert!(!pcb.write_ready());
+
+  // Sending does not free window; the peer's ACK does. Short of a full
+  // SMSS acknowledged, cwnd does not grow
+  pcb.next_segments(now);
+  assert_eq!(pcb.writable_bytes(), 460);
+  pcb.process_ack(SeqNumber(1), iss + 1000, 65535, 0, now);
+  assert_eq!(pcb.writable_bytes(), 1460);
+  assert!(pcb.write_ready());
+
+  // The send buffer caps it as well
+  pcb.send_buffer_limit = 300;
+  assert_eq!(pcb.writable_bytes(), 300);
+  assert!(!pcb.write_ready());
+}
+
+#[test]
+fn test_idle_restart_resets_cwnd() {
+  use std::time::Duration;
+  use tcp_stack::connection::ControlBlock;
+  use tcp_stack::utils::Instant;
+
+  let start = Instant::from_secs(1);
+  let mut pcb = ControlBlock::with_initial_seq(SeqNumber(0), start);
+  for i in 0..10 {
+    pcb.congestion.on_ack(SeqNumber(i), 1460);
+  }
+  let grown = pcb.congestion.cwnd();
+
+  // Idle for less than the RTO (1s): cwnd is kept
+  assert!(!pcb.restart_if_idle(start + Duration::from_millis(900)));
+  assert_eq!(pcb.congestion.cwnd(), grown);
+
+  // A long-idle connection restarts from the initial window before sending
+  pcb.send_queue.write(&[0u8; 20_000]);
+  let later = start + Duration::from_secs(30);
+  let sent: usize = pcb.next_segments(later).iter().map(|(_, p)| p.len()).sum();
+  assert_eq!(pcb.congestion.cwnd(), 1460);
+  assert_eq!(sent, 1460);
+  assert_eq!(pcb.last_activity, later);
+}
+
+#[test]
+fn test_stretch_ack_is_credited_by_bytes() {
+  use tcp_stack::congestion::NewReno;
+  use tcp_stack::connection::ControlBlock;
+  use tcp_stack::utils::Instant;
+
+  // Slow start: a stretch ACK for eight segments grows cwnd by at most
+  // two SMSS
+  let mut cc = NewReno::new();
+  cc.on_ack(SeqNumber(0), 8 * 1460);
+  assert_eq!(cc.cwnd(), 3 * 1460);
+
+  let now = Instant::from_secs(1);
+  let iss = SeqNumber(u32::MAX - 2000);
+  let mut pcb = ControlBlock::with_initial_seq(iss, now);
+  for i in 0..4 {
+    pcb.congestion.on_ack(SeqNumber(i), 1460);
+  }
+  pcb.send_queue.write(&[0u8; 4 * 1460]);
+  pcb.next_segments(now);
+  assert_eq!(pcb.in_flight(), 4 * 1460);
+  let peer = SeqNumber(500);
+  let window = pcb.snd_wnd();
+
+  // One ACK covering all four segments, across the sequence wrap
+  let acked = pcb.process_ack(peer, iss + 4 * 1460, window, 0, now);
+  assert_eq!(acked, 4 * 1460);
+  assert_eq!(pcb.stats.bytes_acked, 4 * 1460);
+  assert_eq!(pcb.retransmit.pending_count(), 0);
+  assert_eq!(pcb.in_flight(), 0);
+
+  // Repeating it with nothing outstanding is not a duplicate ACK
+  assert_eq!(pcb.process_ack(peer, iss + 4 * 1460, window, 0, now), 0);
+  assert_eq!(pcb.stats.duplicate_acks, 0);
+
+  // With data outstanding it is; a window update is not
+  pcb.send_queue.write(&[0u8; 100]);
+  pcb.next_segments(now);
+  assert_eq!(pcb.process_ack(peer, iss + 4 * 1460, window, 0, now), 0);
+  assert_eq!(pcb.stats.duplicate_acks, 1);
+  pcb.process_ack(peer, iss + 4 * 1460, window + 1000, 0, now);
+  assert_eq!(pcb.stats.duplicate_acks, 1);
+}
+
+#[test]
+fn test_segment_digest_covers_whole_segment() {
+  let header = TcpHeader::syn(40000, 80, 1000, 1460);
+  let mut segment = header.serialize();
+  let digest = TcpHeader::digest(&segment);
+  assert_eq!(digest, TcpHeader::digest(&header.serialize()));
+
+  // Any byte, checksum included, changes it
+  segment[17] ^= 0x01;
+  assert_ne!(TcpHeader::digest(&segment), digest);
+  // FNV-1a 64 offset basis for the empty input
+  assert_eq!(TcpHeader::digest(&[]), 0xcbf2_9ce4_8422_2325);
+}
+
+#[test]
+fn test_time_sequence_records_sends_acks_and_retransmits() {
+  use std::time::Duration;
+  use tcp_stack::connection::{ControlBlock, TimeSeqKind};
+  use tcp_stack::utils::Instant;
+
+  let start = Instant::from_secs(1);
+  let iss = SeqNumber(1000);
+  let mut pcb = ControlBlock::with_initial_seq(iss, start);
+  pcb.enable_timeseq(16);
+  pcb.send_queue.set_nagle(false);
+  pcb.send_queue.write(&[0u8; 1000]);
+  pcb.next_segments(start);
+  pcb.send_queue.write(&[0u8; 460]);
+  pcb.next_segments(start);
+
+  let later = start + Duration::from_secs(2);
+  assert_eq!(pcb.poll_retransmit(later).len(), 2);
+  let window = pcb.snd_wnd();
+  pcb.process_ack(SeqNumber(1), iss + 1460, window, 0, later);
+
+  let timeseq = pcb.timeseq.as_ref().unwrap();
+  let kinds: Vec<_> = timeseq.samples().map(|s| s.kind).collect();
+  assert_eq!(
+    kinds,
+    [
+      TimeSeqKind::Sent,
+      TimeSeqKind::Sent,
+      TimeSeqKind::Retransmit,
+      TimeSeqKind::Retransmit,
+      TimeSeqKind::Ack
+    ]
+  );
+  let csv = timeseq.to_csv();
+  let mut lines = csv.lines();
+  assert_eq!(lines.next(), Some("time_us,kind,seq,end,cwnd,window"));
+  assert_eq!(lines.next(), Some("1000000,sent,1000,2000,1460,65535"));
+  assert!(timeseq
+    .to_json()
+    .starts_with("[{\"time_us\":1000000,\"kind\":\"sent\""));
+}
+
+#[test]
+fn test_time_sequence_ring_drops_oldest() {
+  use tcp_stack::connection::{TimeSeqKind, TimeSequence};
+  use tcp_stack::utils::Instant;
+
+  let mut timeseq = TimeSequence::with_capacity(2);
+  for i in 0..3 {
+    timeseq.record(
+      Instant::from_secs(i),
+      TimeSeqKind::Sent,
+      SeqNumber(i as u32 * 10),
+      10,
+      0,
+      0,
+    );
+  }
+  let seqs: Vec<_> = timeseq.samples().map(|s| s.seq).collect();
+  assert_eq!(seqs, [10, 20]);
+}
+
+#[test]
+fn test_throughput_sampler_reports_interval_rates() {
+  use std::time::Duration;
+  use tcp_stack::connection::ControlBlock;
+  use tcp_stack::utils::Instant;
+
+  let start = Instant::from_secs(1);
+  let iss = SeqNumber(1000);
+  let mut pcb = ControlBlock::with_initial_seq(iss, start);
+  pcb.enable_sampling(Duration::from_secs(1), start);
+  pcb.send_queue.set_nagle(false);
+  pcb.send_queue.write(&[0u8; 1460]);
+  pcb.next_segments(start);
+  let window = pcb.snd_wnd();
+  let acked = start + Duration::from_millis(100);
+  pcb.process_ack(SeqNumber(1), iss + 1460, window, 0, acked);
+  pcb.send_queue.write(&[0u8; 500]);
+  pcb.next_segments(acked);
+  pcb.stats.segments_sent = 2;
+  assert_eq!(pcb.poll_sample(start + Duration::from_millis(500)), None);
+  let sample = pcb
+    .poll_sample(start + Duration::from_millis(1000))
+    .unwrap();
+  assert_eq!(sample.start_micros, 1_000_000);
+  assert_eq!(sample.bytes_acked, 1460);
+  assert_eq!(sample.goodput, 1460.0);
+  assert_eq!(sample.retransmit_ratio, 0.0);
+  assert_eq!(sample.avg_rtt, Some(Duration::from_millis(100)));
+
+  // A retransmitted segment counts against the ratio but gives no RTT
+  let later = start + Duration::from_secs(3);
+  assert_eq!(pcb.poll_retransmit(later).len(), 1);
+  pcb.stats.segments_sent += 1;
+  pcb.process_ack(SeqNumber(1), iss + 1960, window, 0, later);
+  let sample = pcb.poll_sample(later).unwrap();
+  assert_eq!(sample.duration, Duration::from_secs(2));
+  assert_eq!(sample.bytes_acked, 500);
+  assert_eq!(sample.goodput, 250.0);
+  assert_eq!(sample.retransmit_ratio, 1.0);
+  assert_eq!(sample.avg_rtt, None);
+}
+
+#[test]
+fn test_segment_equivalence_ignores_checksum_and_option_padding() {
+  use TcpOption::{
+    MaximumSegmentSize as Mss, NoOperation as Nop, SackPermitted, WindowScale as Ws,
+  };
+
+  let segment = |options: Vec<TcpOption>, checksum: u16, payload: &[u8]| {
+    let mut header = TcpHeader::new(1234, 80);
+    header.seq_num = 1000;
+    header.flags = TcpFlags::new().with_syn();
+    header.set_options(options);
+    let mut bytes = header.serialize();
+    bytes[16..18].copy_from_slice(&checksum.to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+  };
+
+  let linux = segment(
+    vec![Mss(1460), Nop, Ws(7), Nop, Nop, SackPermitted],
+    0x1234,
+    b"",
+  );
+  let ours = segment(vec![Ws(7), SackPermitted, Mss(1460)], 0xabcd, b"");
+  assert_ne!(linux, ours);
+  assert!(TcpHeader::segments_equivalent(&linux, &ours));
+
+  let (header, _) = TcpHeader::parse(&linux).unwrap();
+  let normalized = header.normalized();
+  assert_eq!(normalized.options, [Mss(1460), Ws(7), SackPermitted]);
+  assert_eq!((normalized.data_offset(), normalized.checksum), (8, 0));
+
+  let other_scale = segment(vec![Mss(1460), Nop, Ws(8), Nop, Nop, SackPermitted], 0, b"");
+  assert!(!TcpHeader::segments_equivalent(&linux, &other_scale));
+  let with_data = segment(vec![Mss(1460), Ws(7), SackPermitted], 0, b"x");
+  assert!(!TcpHeader::segments_equivalent(&linux, &with_data));
+  assert!(!TcpHeader::segments_equivalent(&linux, &[0; 4]));
+}
+
+#[test]
+fn test_option_padding_layouts() {
+  let mut header = TcpHeader::syn(40000, 80, 1, 1460);
+  header.options[2] = TcpOption::Timestamp {
+    ts_val: 0x0a0b0c0d,
+    ts_ecr: 0,
+  };
+  let bytes = header.serialize();
+  // Linux's SYN: MSS, SACK-permitted, timestamps, NOP, window scale
+  assert_eq!(header.data_offset(), 10);
+  assert_eq!(bytes[12] >> 4, 10);
+  assert_eq!(
+    &bytes[20..],
+    &[2, 4, 5, 180, 4, 2, 8, 10, 10, 11, 12, 13, 0, 0, 0, 0, 1, 3, 3, 7]
+  );
+
+  // NOPs in the list are placed anew; EOL keeps them and terminates
+  header.set_options(vec![
+    TcpOption::WindowScale(7),
+    TcpOption::NoOperation,
+    TcpOption::SackPermitted,
+  ]);
+  assert_eq!(&header.serialize()[20..], &[1, 3, 3, 7, 1, 1, 4, 2]);
+  header.padding = OptionPadding::EndOfList;
+  let bytes = header.serialize();
+  assert_eq!(&bytes[20..], &[3, 3, 7, 1, 4, 2, 0, 0]);
+
+  // Parsed headers re-encode as received
+  let (parsed, _) = TcpHeader::parse(&bytes).unwrap();
+  assert_eq!(parsed.padding, OptionPadding::EndOfList);
+  assert_eq!(parsed.serialize(), bytes);
+}
+
+#[test]
+fn test_keepalive_probes_then_gives_up() {
+  use std::time::Duration;
+  use tcp_stack::connection::{Keepalive, KeepaliveAction, KeepalivePolicy};
+  use tcp_stack::utils::Instant;
+
+  let start = Instant::from_secs(1);
+  let mut keepalive = Keepalive::new(start);
+  assert_eq!(keepalive.deadline(), None);
+  keepalive.set_enabled(true);
+  keepalive.set_policy(KeepalivePolicy {
+    idle: Duration::from_secs(10),
+    interval: Duration::from_secs(2),
+    probes: 2,
+  });
+
+  // Probes start once the peer is silent for the idle time
+  let idle = start + Duration::from_secs(10);
+  assert_eq!(
+    keepalive.poll(idle - Duration::from_millis(1)),
+    KeepaliveAction::None
+  );
+  assert_eq!(keepalive.poll(idle), KeepaliveAction::Probe);
+  keepalive.on_peer_segment(idle + Duration::from_secs(1));
+  assert_eq!(keepalive.deadline(), Some(start + Duration::from_secs(21)));
+
+  // Every interval after that until the count runs out
+  let idle = start + Duration::from_secs(21);
+  assert_eq!(keepalive.poll(idle), KeepaliveAction::Probe);
+  let second = idle + Duration::from_secs(2);
+  assert_eq!(keepalive.poll(second), KeepaliveAction::Probe);
+  let last = second + Duration::from_secs(2);
+  assert_eq!(keepalive.poll(last), KeepaliveAction::PeerUnreachable);
+  assert_eq!(keepalive.probes_sent(), 2);
+  assert_eq!(keepalive.silence(last), Duration::from_secs(14));
+
+  // A probe on demand is timed even with keep-alive disabled
+  let mut keepalive = Keepalive::new(start);
+  keepalive.on_probe_sent(start);
+  assert_eq!(
+    keepalive.deadline(),
+    Some(start + KeepalivePolicy::default().interval)
+  );
+}
+
+#[test]
+fn test_stats_reset_starts_a_new_epoch() {
+  use tcp_stack::connection::stats::count;
+  use tcp_stack::connection::{ConnectionStats, DropReason};
+
+  let mut stats = ConnectionStats::new();
+  count(&mut stats.bytes_sent, 1000);
+  stats.drops.record(DropReason::BadChecksum);
+  let snapshot = stats.clone();
+  count(&mut stats.bytes_sent, 500);
+  stats.drops.record(DropReason::BadChecksum);
+
+  let delta = stats.since(&snapshot);
+  assert_eq!(delta.bytes_sent, 500);
+  assert_eq!(delta.drops.bad_checksum, 1);
+
+  // After a reset the delta is everything counted in the new epoch, not a
+  // wrapped difference
+  stats.reset();
+  count(&mut stats.bytes_sent, 200);
+  assert_eq!(stats.epoch, 1);
+  assert_eq!(stats.since(&snapshot).bytes_sent, 200);
+  assert_eq!(stats.drops.total(), 0);
+
+  count(&mut stats.bytes_sent, u64::MAX);
+  assert_eq!(stats.bytes_sent, u64::MAX);
+}
+
+#[test]
+fn test_misbehaving_receiver_gains_no_cwnd() {
+  use tcp_stack::connection::ControlBlock;
+  use tcp_stack::utils::Instant;
+
+  let now = Instant::from_secs(1);
+  let iss = SeqNumber(1000);
+  let peer = SeqNumber(1);
+  let mut pcb = ControlBlock::with_initial_seq(iss, now);
+  pcb.send_queue.write(&[0u8; 1460]);
+  pcb.next_segments(now);
+  let window = pcb.snd_wnd();
+
+  // ACK division: one segment acknowledged in ten pieces grows cwnd by the
+  // one SMSS a single ACK would
+  for i in 1..=10 {
+    pcb.process_ack(peer, iss + 146 * i, window, 0, now);
+  }
+  assert_eq!(pcb.congestion.cwnd(), 2 * 1460);
+  assert_eq!(pcb.stats.split_acks, 9);
+
+  // Optimistic ACK for data never sent is ignored
+  pcb.send_queue.write(&[0u8; 2 * 1460]);
+  pcb.next_segments(now);
+  let una = pcb.snd_una();
+  assert_eq!(pcb.process_ack(peer, una + 10 * 1460, window, 0, now), 0);
+  assert_eq!(pcb.snd_una(), una);
+  assert_eq!(pcb.stats.optimistic_acks, 1);
+
+  // Two segments in flight can draw two duplicates, not more
+  for _ in 0..5 {
+    pcb.process_ack(peer, una, window, 0, now);
+  }
+  assert_eq!(pcb.stats.duplicate_acks, 5);
+  assert_eq!(pcb.stats.excess_duplicate_acks, 3);
+  assert_eq!(pcb.congestion.dup_acks(), 2);
+  assert_eq!(pcb.stats.suspicious_acks(), 13);
+}
+
+#[test]
+fn test_packet_filter_matches_host_port_and_flags() {
+  let client = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 40000);
+  let server = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 8080);
+  let syn = TcpFlags::new().with_syn();
+  let ack = TcpFlags::new().with_ack();
+
+  let filter = PacketFilter::parse("host 10.0.0.1 and port 8080").unwrap();
+  assert!(filter.matches_segment(client, server, ack));
+  assert!(filter.matches_segment(server, client, ack));
+  let other = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 22);
+  assert!(!filter.matches_segment(client, other, ack));
+
+  let filter = PacketFilter::parse("src port 8080 and (tcp-syn or tcp-rst)").unwrap();
+  assert!(filter.matches_segment(server, client, syn.with_ack()));
+  assert!(!filter.matches_segment(server, client, ack));
+  assert!(!filter.matches_segment(client, server, syn));
+
+  // `not` binds tighter than `and`, which binds tighter than `or`
+  let filter = PacketFilter::parse("! dst host 10.0.0.1 && tcp-ack || tcp-syn").unwrap();
+  assert!(filter.matches_segment(server, client, ack));
+  assert!(!filter.matches_segment(client, server, ack));
+  assert!(filter.matches_segment(client, server, syn));
+
+  assert!(PacketFilter::parse("").unwrap().matches_segment(client, server, ack));
+  assert_eq!(filter.to_string(), "! dst host 10.0.0.1 && tcp-ack || tcp-syn");
+}
+
+#[test]
+fn test_packet_filter_matches_whole_packets() {
+  let src = Ipv4Addr::new(10, 0, 0, 2);
+  let dst = Ipv4Addr::new(10, 0, 0, 1);
+  let mut packet = Ipv4Header::new(src, dst, 20).serialize();
+  packet.extend_from_slice(&TcpHeader::syn(40000, 8080, 1000, 1460).serialize());
+
+  let filter: PacketFilter = "dst port 8080 and tcp-syn".parse().unwrap();
+  assert!(filter.matches(&packet));
+  assert!(!"src host 10.0.0.1".parse::<PacketFilter>().unwrap().matches(&packet));
+  assert!(!filter.matches(&packet[..10]));
+}
+
+#[test]
+fn test_packet_filter_rejects_bad_expressions() {
+  assert_eq!(PacketFilter::parse("host").err(), Some(FilterError::UnexpectedEnd));
+  assert_eq!(
+    PacketFilter::parse("host 10.0.0").err(),
+    Some(FilterError::BadAddress("10.0.0".into()))
+  );
+  assert_eq!(
+    PacketFilter::parse("port 70000").err(),
+    Some(FilterError::BadPort("70000".into()))
+  );
+  assert_eq!(
+    PacketFilter::parse("src tcp-syn").err(),
+    Some(FilterError::Unexpected("tcp-syn".into()))
+  );
+  assert_eq!(
+    PacketFilter::parse("(port 80").err(),
+    Some(FilterError::UnexpectedEnd)
+  );
+  assert_eq!(
+    PacketFilter::parse("port 80 port 81").err(),
+    Some(FilterError::Unexpected("port".into()))
+  );
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_spsc_queue_hands_values_across_threads_in_order() {
+  use tcp_stack::utils::spsc;
+
+  let (mut tx, mut rx) = spsc::channel(2);
+  assert_eq!(tx.push(1), Ok(()));
+  assert_eq!(tx.push(2), Ok(()));
+  assert!(tx.is_full());
+  assert_eq!(tx.push(3), Err(3));
+  let stats = rx.stats();
+  assert_eq!((stats.depth, stats.high_water, stats.rejected), (2, 2, 1));
+  assert_eq!(rx.pop(), Some(1));
+  assert_eq!(rx.pop(), Some(2));
+  assert_eq!(rx.pop(), None);
+
+  let producer = std::thread::spawn(move || {
+    for value in 0..10_000u32 {
+      let mut value = value;
+      while let Err(back) = tx.push(value) {
+        value = back;
+        std::thread::yield_now();
+      }
+    }
+  });
+  let mut expected = 0;
+  while expected < 10_000 {
+    match rx.pop() {
+      Some(value) => {
+        assert_eq!(value, expected);
+        expected += 1;
+      }
+      None => std::thread::yield_now(),
+    }
+  }
+  producer.join().unwrap();
+  assert!(rx.is_empty());
+  assert_eq!(rx.stats().high_water, 2);
+}
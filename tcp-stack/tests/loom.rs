@@ -0,0 +1,130 @@
+//! Model checking of the state shared between threads
+//!
+//! loom runs each model under every interleaving its atomics and locks
+//! allow, so a missing Acquire/Release pair shows up as a torn or lost
+//! value, and an operation split across two lock sections as a lost update.
+//! Covered are the SPSC ring behind `run_threaded`, the `SharedShaper` lock
+//! and the demultiplexer table behind the mutex `Demultiplexer::global`
+//! wraps; a static cannot hold a loom mutex, so the models share their own.
+//! `ConnectionPool` is not modelled: its entries are live connections on
+//! raw sockets, which a model cannot open. Run with
+//! `RUSTFLAGS="--cfg loom" cargo test --release --no-default-features
+//! --features std --test loom`.
+
+#![cfg(loom)]
+
+use loom::sync::{Arc, Mutex};
+use loom::thread;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use tcp_stack::demux::{ConnectionKey, Demultiplexer};
+use tcp_stack::error::TcpError;
+use tcp_stack::flow_control::SharedShaper;
+use tcp_stack::utils::{spsc, Instant};
+
+fn key(remote_port: u16) -> ConnectionKey {
+  ConnectionKey::new(
+    SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 4000),
+    SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), remote_port),
+  )
+}
+
+#[test]
+fn test_spsc_values_cross_threads_whole_and_in_order() {
+  loom::model(|| {
+    let (mut tx, mut rx) = spsc::channel(2);
+    let producer = thread::spawn(move || {
+      for value in 0..3u32 {
+        let mut value = vec![value];
+        while let Err(back) = tx.push(value) {
+          value = back;
+          thread::yield_now();
+        }
+      }
+    });
+    let mut expected = 0;
+    while expected < 3 {
+      match rx.pop() {
+        Some(value) => {
+          assert_eq!(value, [expected]);
+          expected += 1;
+        }
+        None => thread::yield_now(),
+      }
+    }
+    producer.join().unwrap();
+    assert_eq!(rx.pop(), None);
+  });
+}
+
+#[test]
+fn test_spsc_drops_what_is_left_queued() {
+  loom::model(|| {
+    let (mut tx, mut rx) = spsc::channel(1);
+    let producer = thread::spawn(move || {
+      let _ = tx.push(vec![1u8]);
+      let _ = tx.push(vec![2u8]);
+    });
+    let popped = rx.pop();
+    producer.join().unwrap();
+    // Whatever stays in the ring is freed with it
+    drop(rx);
+    assert!(popped.is_none_or(|value| value == [1]));
+  });
+}
+
+#[test]
+fn test_shared_shaper_counts_every_charge() {
+  loom::model(|| {
+    let now = Instant::now();
+    let shaper = SharedShaper::new(100_000, now);
+    let burst = shaper.available(now);
+    let other = shaper.clone();
+    let sender = thread::spawn(move || other.consume(1000));
+    shaper.consume(500);
+    sender.join().unwrap();
+    assert_eq!(shaper.available(now), burst - 1500);
+  });
+}
+
+#[test]
+fn test_demux_gives_a_four_tuple_to_one_connection() {
+  loom::model(|| {
+    let table = Arc::new(Mutex::new(Demultiplexer::new()));
+    let other = table.clone();
+    let racer = thread::spawn(move || other.lock().unwrap().register(key(80)));
+    let mine = table.lock().unwrap().register(key(80));
+    let theirs = racer.join().unwrap();
+    let (winner, loser) = match (mine, theirs) {
+      (Ok(id), Err(e)) | (Err(e), Ok(id)) => (id, e),
+      other => panic!("both or neither registered: {other:?}"),
+    };
+    assert!(matches!(loser, TcpError::AddrInUse(_)));
+    let table = table.lock().unwrap();
+    assert_eq!(table.find(&key(80)), Some(winner));
+    assert_eq!(table.len(), 1);
+  });
+}
+
+#[test]
+fn test_demux_unregister_frees_the_tuple_for_a_racing_register() {
+  loom::model(|| {
+    let table = Arc::new(Mutex::new(Demultiplexer::new()));
+    let old = table.lock().unwrap().register(key(80)).unwrap();
+    let other = table.clone();
+    let closer = thread::spawn(move || other.lock().unwrap().unregister(old));
+    let reopened = table.lock().unwrap().register(key(80));
+    assert_eq!(closer.join().unwrap(), Some(key(80)));
+    let table = table.lock().unwrap();
+    match reopened {
+      // Registered after the close: a fresh id, never the stale one
+      Ok(id) => {
+        assert_ne!(id, old);
+        assert_eq!(table.find(&key(80)), Some(id));
+      }
+      // Raced ahead of the close and lost; the table is left empty
+      Err(TcpError::AddrInUse(_)) => assert!(table.is_empty()),
+      Err(e) => panic!("unexpected error: {e:?}"),
+    }
+    assert_eq!(table.key(old), None);
+  });
+}
@@ -0,0 +1,87 @@
+//! Stack-wide memory accounting
+//!
+//! The accountant is process-wide, so everything runs in one test.
+
+use tcp_stack::connection::ControlBlock;
+use tcp_stack::memory::{self, MemoryLimits, MemoryPool, MemoryPressure};
+use tcp_stack::reliability::retransmit::{PendingSegment, SegmentKind};
+use tcp_stack::reliability::{ReceiveStream, RetransmissionManager};
+use tcp_stack::utils::{Instant, SeqNumber};
+
+#[test]
+fn test_memory_accounting_and_limits() {
+  let now = Instant::from_secs(1);
+  let mut stream = ReceiveStream::new();
+  stream.set_rcv_nxt(SeqNumber(1000));
+
+  // Out-of-order data is held in the reorder pool until the gap fills
+  assert_eq!(stream.push(SeqNumber(1100), vec![0; 100]), 0);
+  assert_eq!(memory::stats().reorder, 100);
+  assert_eq!(stream.push(SeqNumber(1000), vec![0; 100]), 200);
+  let stats = memory::stats();
+  assert_eq!((stats.reorder, stats.receive), (0, 200));
+  let mut buf = [0u8; 50];
+  assert_eq!(stream.read(&mut buf), 50);
+  assert_eq!(memory::stats().receive, 150);
+
+  let mut retransmit = RetransmissionManager::new();
+  retransmit.add_segment(
+    PendingSegment {
+      seq: SeqNumber(1),
+      len: 850,
+      data: vec![0; 850].into(),
+      retransmit_count: 0,
+      first_sent: now,
+      kind: SegmentKind::Data,
+    },
+    1.0,
+    now,
+  );
+  assert_eq!(memory::stats().get(MemoryPool::Retransmit), 850);
+  assert_eq!(memory::stats().total(), 1000);
+
+  // Under pressure out-of-order data is refused and the window shrinks to
+  // the headroom, but in-order data still arrives
+  memory::set_limits(Some(MemoryLimits {
+    pressure: 900,
+    hard: 1500,
+  }));
+  assert_eq!(memory::pressure(), MemoryPressure::Pressure);
+  assert_eq!(stream.window(), 500);
+  assert_eq!(stream.push(SeqNumber(1300), vec![0; 10]), 0);
+  assert_eq!(memory::stats().reorder, 0);
+  assert_eq!(memory::stats().refused, 1);
+  assert_eq!(stream.push(SeqNumber(1200), vec![0; 400]), 400);
+
+  // At the hard limit in-order data is refused and writes take nothing
+  // beyond the headroom
+  assert_eq!(memory::headroom(), 100);
+  let mut control = ControlBlock::with_initial_seq(SeqNumber(0), now);
+  assert_eq!(control.write(&[0; 500]), 100);
+  assert_eq!(stream.push(SeqNumber(1600), vec![0; 200]), 0);
+  assert_eq!(stream.rcv_nxt(), SeqNumber(1600));
+  retransmit.add_segment(
+    PendingSegment {
+      seq: SeqNumber(851),
+      len: 100,
+      data: vec![0; 100].into(),
+      retransmit_count: 0,
+      first_sent: now,
+      kind: SegmentKind::Data,
+    },
+    1.0,
+    now,
+  );
+  assert_eq!(memory::pressure(), MemoryPressure::Exhausted);
+  assert_eq!(stream.window(), 0);
+
+  // Acknowledged and dropped buffers give their memory back
+  retransmit.acknowledge(SeqNumber(951), now);
+  assert_eq!(memory::stats().retransmit, 0);
+  drop(stream);
+  assert_eq!(memory::stats().total(), 0);
+  assert_eq!(memory::pressure(), MemoryPressure::Normal);
+
+  memory::set_limits(None);
+  assert_eq!(memory::limits(), None);
+}
@@ -0,0 +1,191 @@
+//! Offline replay of synthetic captures
+
+use std::net::Ipv4Addr;
+use tcp_stack::connection::TcpState;
+use tcp_stack::packet::{Ipv4Header, TcpFlags, TcpHeader, TcpOption};
+use tcp_stack::replay::{DivergenceKind, PcapError, PcapReader, Replay, Role};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+struct Packet {
+  micros: u32,
+  from_client: bool,
+  seq: u32,
+  ack: u32,
+  flags: u8,
+  options: Vec<TcpOption>,
+  payload: usize,
+}
+
+fn packet(
+  micros: u32,
+  from_client: bool,
+  seq: u32,
+  ack: u32,
+  flags: u8,
+  payload: usize,
+) -> Packet {
+  Packet {
+    micros,
+    from_client,
+    seq,
+    ack,
+    flags,
+    options: Vec::new(),
+    payload,
+  }
+}
+
+fn syn(micros: u32, from_client: bool, seq: u32, ack: u32, flags: u8) -> Packet {
+  Packet {
+    options: vec![
+      TcpOption::MaximumSegmentSize(1460),
+      TcpOption::NoOperation,
+      TcpOption::WindowScale(7),
+    ],
+    ..packet(micros, from_client, seq, ack, flags, 0)
+  }
+}
+
+/// A little-endian microsecond pcap of raw IPv4 packets
+fn capture(packets: &[Packet]) -> Vec<u8> {
+  let mut file = Vec::new();
+  file.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+  file.extend_from_slice(&2u16.to_le_bytes());
+  file.extend_from_slice(&4u16.to_le_bytes());
+  file.extend_from_slice(&[0; 8]);
+  file.extend_from_slice(&65535u32.to_le_bytes());
+  file.extend_from_slice(&101u32.to_le_bytes());
+
+  for p in packets {
+    let (src, dst, sport, dport) = if p.from_client {
+      (CLIENT, SERVER, 40000, 80)
+    } else {
+      (SERVER, CLIENT, 80, 40000)
+    };
+    let mut tcp = TcpHeader::new(sport, dport);
+    tcp.seq_num = p.seq;
+    tcp.ack_num = p.ack;
+    tcp.flags = TcpFlags(p.flags);
+    tcp.window_size = 512;
+    tcp.set_options(p.options.clone());
+    let mut segment = tcp.serialize();
+    segment.extend(std::iter::repeat_n(0x61, p.payload));
+    let mut frame = Ipv4Header::new(src, dst, segment.len()).serialize();
+    frame.extend_from_slice(&segment);
+
+    file.extend_from_slice(&(p.micros / 1_000_000).to_le_bytes());
+    file.extend_from_slice(&(p.micros % 1_000_000).to_le_bytes());
+    file.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    file.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    file.extend_from_slice(&frame);
+  }
+  file
+}
+
+const S: u8 = TcpFlags::SYN;
+const A: u8 = TcpFlags::ACK;
+const PA: u8 = TcpFlags::PSH | TcpFlags::ACK;
+
+fn handshake() -> Vec<Packet> {
+  vec![
+    syn(0, true, 1000, 0, S),
+    syn(10_000, false, 5000, 1001, S | A),
+    packet(10_100, true, 1001, 5001, A, 0),
+  ]
+}
+
+#[test]
+fn test_replay_conformant_flow() {
+  let mut packets = handshake();
+  packets.extend([
+    packet(11_000, true, 1001, 5001, PA, 100),
+    packet(20_000, false, 5001, 1101, PA, 50),
+    packet(20_100, true, 1101, 5051, A, 0),
+  ]);
+
+  let report = Replay::run(&capture(&packets), Role::Client).unwrap();
+  assert_eq!(report.segments, 6);
+  assert!(report.is_conformant(), "{report}");
+  assert_eq!(report.final_state, TcpState::Established);
+
+  // The same capture replayed from the server's side
+  let report = Replay::run(&capture(&packets), Role::Server).unwrap();
+  assert!(report.is_conformant(), "{report}");
+}
+
+#[test]
+fn test_replay_reports_divergences() {
+  let mut packets = handshake();
+  packets.extend([
+    packet(11_000, true, 1001, 5001, PA, 100),
+    // Retransmitted long before any RTO, with no duplicate ACKs
+    packet(12_000, true, 1001, 5001, PA, 100),
+    packet(20_000, false, 5001, 1101, PA, 50),
+    // Acknowledges only part of the peer's data
+    packet(20_100, true, 1101, 5041, A, 0),
+    // Skips ahead of SND.NXT
+    packet(21_000, true, 1201, 5051, PA, 10),
+  ]);
+
+  let report = Replay::run(&capture(&packets), Role::Client).unwrap();
+  let kinds: Vec<_> = report
+    .divergences
+    .iter()
+    .map(|d| (d.frame, d.kind))
+    .collect();
+  assert_eq!(
+    kinds,
+    vec![
+      (5, DivergenceKind::EarlyRetransmit { seq: 1001 }),
+      (
+        7,
+        DivergenceKind::AckNumber {
+          captured: 5041,
+          ours: 5051
+        }
+      ),
+      (
+        8,
+        DivergenceKind::SequenceGap {
+          captured: 1201,
+          snd_nxt: 1101
+        }
+      ),
+    ]
+  );
+  assert!(report.to_string().contains("frame 7 at 0.020100s"));
+}
+
+#[test]
+fn test_replay_skips_capture_duplicates() {
+  let mut packets = handshake();
+  packets.extend([
+    packet(11_000, true, 1001, 5001, PA, 100),
+    // The same segment seen again on a second interface, padded differently
+    Packet {
+      options: vec![TcpOption::NoOperation; 4],
+      ..packet(11_010, true, 1001, 5001, PA, 100)
+    },
+    packet(20_000, false, 5001, 1101, PA, 50),
+    packet(20_100, true, 1101, 5051, A, 0),
+  ]);
+
+  let report = Replay::run(&capture(&packets), Role::Client).unwrap();
+  assert!(report.is_conformant(), "{report}");
+  assert_eq!((report.segments, report.duplicates), (6, 1));
+}
+
+#[test]
+fn test_pcap_reader_rejects_bad_input() {
+  assert_eq!(PcapReader::new(&[0; 24]).err(), Some(PcapError::BadMagic));
+  assert_eq!(PcapReader::new(&[0; 10]).err(), Some(PcapError::Truncated));
+
+  let mut file = capture(&handshake());
+  file.truncate(file.len() - 5);
+  let packets: Vec<_> = PcapReader::new(&file).unwrap().collect();
+  assert_eq!(packets.len(), 3);
+  assert!(packets[..2].iter().all(Result::is_ok));
+  assert_eq!(packets[2].as_ref().err(), Some(&PcapError::Truncated));
+}
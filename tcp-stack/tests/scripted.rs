@@ -0,0 +1,183 @@
+//! Scenarios against a `ScriptedPeer` on the loopback device
+
+#![cfg(feature = "std")]
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use tcp_stack::connection::{CloseReason, DropReason, TcpState};
+use tcp_stack::device::{Behavior, Loopback, NetworkDevice, ScriptedPeer};
+use tcp_stack::utils::{Instant, SeqNumber};
+use tcp_stack::{ConnectionHandle, TcpStack};
+
+const LOCAL: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const PEER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 8080);
+
+/// Move packets between the stack and the peer until neither sends more
+fn exchange(stack: &mut TcpStack, device: &mut Loopback, peer: &mut ScriptedPeer, now: Instant) {
+  let mut buf = vec![0u8; 65535];
+  loop {
+    let mut idle = true;
+    while let Some(packet) = stack.poll_transmit(now) {
+      device.send(&packet).unwrap();
+    }
+    peer.poll(device, now);
+    while let Ok(len) = device.recv(&mut buf) {
+      stack.handle_packet(&buf[..len], now);
+      idle = false;
+    }
+    if idle {
+      return;
+    }
+  }
+}
+
+/// Run the stack's timers up to `end`, exchanging at each deadline
+fn run_until(
+  stack: &mut TcpStack,
+  device: &mut Loopback,
+  peer: &mut ScriptedPeer,
+  end: Instant,
+) {
+  while let Some(deadline) = stack.poll_timeout().filter(|&at| at <= end) {
+    exchange(stack, device, peer, deadline);
+  }
+  exchange(stack, device, peer, end);
+}
+
+fn connected(peer: &mut ScriptedPeer) -> (TcpStack, Loopback, ConnectionHandle) {
+  let mut stack = TcpStack::new(LOCAL, 1);
+  let mut device = Loopback::new();
+  let conn = stack.connect(PEER, Instant::ZERO).unwrap();
+  exchange(&mut stack, &mut device, peer, Instant::ZERO);
+  assert_eq!(stack.state(conn), Some(TcpState::Established));
+  (stack, device, conn)
+}
+
+fn payload(len: usize) -> Vec<u8> {
+  (0..len).map(|i| i as u8).collect()
+}
+
+#[test]
+fn test_peer_acking_every_other_segment_stalls_initial_window_until_rto() {
+  let mut peer = ScriptedPeer::new(PEER, 7000).with(Behavior::AckEvery(2));
+  let (mut stack, mut device, conn) = connected(&mut peer);
+  let mss = stack.control(conn).unwrap().mss as usize;
+  let data = payload(mss * 2);
+
+  assert_eq!(stack.send(conn, &data), data.len());
+  exchange(&mut stack, &mut device, &mut peer, Instant::from_millis(10));
+  // A one-segment initial window waits for an ACK the peer holds back
+  assert_eq!(peer.received(), &data[..mss]);
+  let control = stack.control(conn).unwrap();
+  assert_eq!(control.snd_nxt() - control.snd_una(), mss as u32);
+
+  // The retransmission arrives as a duplicate, which is acknowledged
+  run_until(&mut stack, &mut device, &mut peer, Instant::from_secs(10));
+  assert_eq!(peer.received(), &data[..]);
+  let control = stack.control(conn).unwrap();
+  assert_eq!(control.snd_una(), control.snd_nxt());
+  assert!(control.stats.retransmissions >= 1);
+}
+
+#[test]
+fn test_sender_stops_at_shrunken_window() {
+  let mut peer = ScriptedPeer::new(PEER, 7000).with(Behavior::ShrinkWindow {
+    at: 1000,
+    window: 0,
+  });
+  let (mut stack, mut device, conn) = connected(&mut peer);
+  let data = payload(64 * 1024);
+
+  stack.send(conn, &data);
+  run_until(&mut stack, &mut device, &mut peer, Instant::from_millis(100));
+  assert!(peer.received().len() >= 1000);
+  assert!(peer.received().len() < data.len());
+  assert_eq!(peer.received(), &data[..peer.received().len()]);
+  assert_eq!(stack.control(conn).unwrap().snd_wnd(), 0);
+  assert_eq!(stack.state(conn), Some(TcpState::Established));
+}
+
+#[test]
+fn test_peer_reset_at_time_closes_connection() {
+  let mut peer = ScriptedPeer::new(PEER, 7000).with(Behavior::ResetAt(Instant::from_secs(5)));
+  let (mut stack, mut device, conn) = connected(&mut peer);
+
+  exchange(&mut stack, &mut device, &mut peer, Instant::from_secs(4));
+  assert_eq!(stack.state(conn), Some(TcpState::Established));
+  exchange(&mut stack, &mut device, &mut peer, Instant::from_secs(5));
+  assert_eq!(stack.state(conn), Some(TcpState::Closed));
+  assert_eq!(stack.close_reason(conn), Some(CloseReason::PeerRst));
+}
+
+#[test]
+fn test_reordered_segments_arrive_whole() {
+  let mut peer = ScriptedPeer::new(PEER, 7000).with(Behavior::Reorder(2));
+  let (mut stack, mut device, conn) = connected(&mut peer);
+  let mss = stack.control(conn).unwrap().mss as usize;
+  let data = payload(mss * 6);
+
+  stack.send(conn, &data);
+  run_until(&mut stack, &mut device, &mut peer, Instant::from_secs(1));
+  assert_eq!(peer.received(), &data[..]);
+  let control = stack.control(conn).unwrap();
+  assert_eq!(control.snd_una(), control.snd_nxt());
+}
+
+#[test]
+fn test_peer_opens_sends_and_closes() {
+  let mut stack = TcpStack::new(LOCAL, 1);
+  let mut device = Loopback::new();
+  let mut peer = ScriptedPeer::new(PEER, 7000);
+  stack.listen(80);
+  peer.connect(SocketAddrV4::new(LOCAL, 80), &mut device);
+  peer.send(b"hello");
+  peer.close();
+  exchange(&mut stack, &mut device, &mut peer, Instant::ZERO);
+
+  let conn = stack.accept().expect("handshake completes");
+  let mut buf = [0u8; 16];
+  assert_eq!(stack.recv(conn, &mut buf, Instant::ZERO), 5);
+  assert_eq!(&buf[..5], b"hello");
+  assert_eq!(stack.state(conn), Some(TcpState::CloseWait));
+  assert!(peer.all_acked());
+
+  stack.send(conn, b"bye");
+  stack.close(conn);
+  exchange(&mut stack, &mut device, &mut peer, Instant::ZERO);
+  assert_eq!(peer.received(), b"bye");
+  assert!(peer.fin_received());
+}
+
+#[test]
+fn test_unread_data_is_acked_up_to_the_buffer_and_the_window_closes() {
+  let mut stack = TcpStack::new(LOCAL, 1);
+  let mut device = Loopback::new();
+  let mut peer = ScriptedPeer::new(PEER, 7000);
+  stack.set_recv_buffer(4000);
+  stack.listen(80);
+  peer.connect(SocketAddrV4::new(LOCAL, 80), &mut device);
+  exchange(&mut stack, &mut device, &mut peer, Instant::ZERO);
+  let conn = stack.accept().expect("handshake completes");
+
+  // The peer ignores our window and sends seven segments at once; the
+  // application reads none of them
+  peer.send(&payload(10_000));
+  peer.poll(&mut device, Instant::ZERO);
+  let mut buf = vec![0u8; 65535];
+  let mut windows = Vec::new();
+  while let Ok(len) = device.recv(&mut buf) {
+    stack.handle_packet(&buf[..len], Instant::ZERO);
+    while let Some(packet) = stack.poll_transmit(Instant::ZERO) {
+      windows.push(u16::from_be_bytes([packet[34], packet[35]]));
+    }
+  }
+
+  // Every segment is acknowledged, with the window shrinking as the
+  // buffer fills rather than the ACKs stopping
+  assert_eq!(windows, [2540, 1080, 0, 0, 0, 0, 0]);
+  let control = stack.control(conn).unwrap();
+  assert_eq!(control.rcv_nxt(), SeqNumber(7001 + 4000));
+  assert_eq!(control.stats.drops.get(DropReason::BufferFull), 5);
+
+  assert_eq!(stack.recv(conn, &mut buf, Instant::ZERO), 4000);
+  assert_eq!(&buf[..4000], &payload(10_000)[..4000]);
+}
@@ -0,0 +1,58 @@
+//! JSON round-trip tests for the optional serde support
+
+#![cfg(feature = "serde")]
+
+use std::net::Ipv4Addr;
+use tcp_stack::connection::{ConnectionStats, TcpState};
+use tcp_stack::packet::{Ipv4Header, TcpHeader, TcpOption};
+
+#[test]
+fn test_tcp_header_json_roundtrip() {
+  let header = TcpHeader::syn(12345, 80, 1000, 1460);
+  let json = serde_json::to_string(&header).unwrap();
+  let decoded: TcpHeader = serde_json::from_str(&json).unwrap();
+
+  assert_eq!(decoded.seq_num, 1000);
+  assert!(decoded.flags.is_syn());
+  assert_eq!(decoded.options, header.options);
+  assert_eq!(decoded.serialize(), header.serialize());
+}
+
+#[test]
+fn test_ipv4_header_json_roundtrip() {
+  let header = Ipv4Header::new(
+    Ipv4Addr::new(192, 168, 1, 1),
+    Ipv4Addr::new(192, 168, 1, 2),
+    40,
+  );
+  let json = serde_json::to_string(&header).unwrap();
+  let decoded: Ipv4Header = serde_json::from_str(&json).unwrap();
+
+  assert_eq!(decoded.src_addr, header.src_addr);
+  assert_eq!(decoded.serialize(), header.serialize());
+}
+
+#[test]
+fn test_tcp_option_json_shape() {
+  let option = TcpOption::Timestamp {
+    ts_val: 1000,
+    ts_ecr: 500,
+  };
+  let json = serde_json::to_string(&option).unwrap();
+  assert_eq!(json, r#"{"Timestamp":{"ts_val":1000,"ts_ecr":500}}"#);
+}
+
+#[test]
+fn test_state_and_stats_json() {
+  let json = serde_json::to_string(&TcpState::FinWait2).unwrap();
+  assert_eq!(json, r#""FinWait2""#);
+
+  let stats = ConnectionStats {
+    segments_sent: 3,
+    bytes_sent: 1460,
+    ..ConnectionStats::default()
+  };
+  let json = serde_json::to_string(&stats).unwrap();
+  let decoded: ConnectionStats = serde_json::from_str(&json).unwrap();
+  assert_eq!(decoded, stats);
+}
@@ -0,0 +1,51 @@
+//! smoltcp device adapter tests
+
+#![cfg(feature = "smoltcp")]
+
+use smoltcp::phy::{Device, Loopback, Medium, RxToken, TxToken};
+use smoltcp::time::Instant;
+use std::net::Ipv4Addr;
+use tcp_stack::device::smoltcp::{FromSmoltcp, SmoltcpDevice};
+use tcp_stack::packet::{Ipv4Header, TcpHeader};
+use tcp_stack::NetworkDevice;
+
+fn syn_packet() -> Vec<u8> {
+  let tcp = TcpHeader::new(40000, 80).serialize();
+  let ip = Ipv4Header::new(
+    Ipv4Addr::new(10, 0, 0, 2),
+    Ipv4Addr::new(10, 0, 0, 1),
+    tcp.len(),
+  );
+  [ip.serialize(), tcp].concat()
+}
+
+#[test]
+fn test_from_smoltcp_loopback_roundtrip() {
+  let mut device = FromSmoltcp::new(Loopback::new(Medium::Ip)).unwrap();
+  let packet = syn_packet();
+
+  device.send(&packet).unwrap();
+
+  let mut buf = [0u8; 1500];
+  let len = device.recv(&mut buf).unwrap();
+  assert_eq!(&buf[..len], &packet[..]);
+  assert_eq!(
+    device.recv(&mut buf).unwrap_err().kind(),
+    std::io::ErrorKind::WouldBlock
+  );
+}
+
+#[test]
+fn test_smoltcp_device_wraps_network_device() {
+  let inner = FromSmoltcp::new(Loopback::new(Medium::Ip)).unwrap();
+  let mut device = SmoltcpDevice::new(inner);
+  let packet = syn_packet();
+
+  assert_eq!(device.capabilities().medium, Medium::Ip);
+
+  let tx = device.transmit(Instant::from_millis(0)).unwrap();
+  tx.consume(packet.len(), |buf| buf.copy_from_slice(&packet));
+
+  let (rx, _tx) = device.receive(Instant::from_millis(0)).unwrap();
+  rx.consume(|data| assert_eq!(data, &packet[..]));
+}
@@ -0,0 +1,874 @@
+//! Sans-IO engine: two stacks wired back to back
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use tcp_stack::connection::{CloseReason, ConnectionSnapshot, TcpState};
+use tcp_stack::flow_control::{Priority, RateLimit};
+use tcp_stack::packet::{
+  CorruptStage, IpOption, IpOptionsPolicy, Ipv4Header, TcpFlags, TcpHeader, TcpOption,
+};
+use tcp_stack::utils::{Instant, SeqNumber};
+use tcp_stack::{ConnectionHandle, TcpStack};
+
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const PORT: u16 = 8080;
+
+/// Deliver packets both ways until neither stack has any left
+fn exchange(a: &mut TcpStack, b: &mut TcpStack, now: Instant) {
+  loop {
+    let mut idle = true;
+    while let Some(packet) = a.poll_transmit(now) {
+      b.handle_packet(&packet, now);
+      idle = false;
+    }
+    while let Some(packet) = b.poll_transmit(now) {
+      a.handle_packet(&packet, now);
+      idle = false;
+    }
+    if idle {
+      return;
+    }
+  }
+}
+
+fn connected() -> (TcpStack, TcpStack, ConnectionHandle, ConnectionHandle) {
+  let mut client = TcpStack::new(CLIENT, 1);
+  let mut server = TcpStack::new(SERVER, 2);
+  server.listen(PORT);
+  let now = Instant::ZERO;
+  let conn = client
+    .connect(SocketAddrV4::new(SERVER, PORT), now)
+    .unwrap();
+  exchange(&mut client, &mut server, now);
+  let accepted = server.accept().expect("handshake completes");
+  (client, server, conn, accepted)
+}
+
+#[test]
+fn test_stacks_transfer_data_and_close() {
+  let (mut client, mut server, conn, accepted) = connected();
+  assert_eq!(client.state(conn), Some(TcpState::Established));
+  assert_eq!(server.state(accepted), Some(TcpState::Established));
+  assert_eq!(
+    server.remote_addr(accepted).map(|addr| *addr.ip()),
+    Some(CLIENT)
+  );
+
+  let now = Instant::from_millis(10);
+  assert_eq!(client.send(conn, b"hello"), 5);
+  exchange(&mut client, &mut server, now);
+  let mut buf = [0u8; 16];
+  assert_eq!(server.recv(accepted, &mut buf, now), 5);
+  assert_eq!(&buf[..5], b"hello");
+
+  server.send(accepted, b"world");
+  client.close(conn);
+  exchange(&mut client, &mut server, now);
+  assert_eq!(client.recv(conn, &mut buf, now), 5);
+  assert_eq!(&buf[..5], b"world");
+  assert_eq!(client.state(conn), Some(TcpState::FinWait2));
+  assert_eq!(server.state(accepted), Some(TcpState::CloseWait));
+  assert_eq!(server.recv(accepted, &mut buf, now), 0);
+
+  server.close(accepted);
+  exchange(&mut client, &mut server, now);
+  assert_eq!(client.state(conn), Some(TcpState::TimeWait));
+  assert_eq!(server.state(accepted), Some(TcpState::Closed));
+  assert_eq!(server.close_reason(accepted), Some(CloseReason::PeerFin));
+  assert!(server.remove(accepted));
+
+  let end = client.poll_timeout().expect("TIME-WAIT timer");
+  assert!(client.poll_transmit(end).is_none());
+  assert_eq!(client.state(conn), Some(TcpState::Closed));
+  assert_eq!(client.close_reason(conn), Some(CloseReason::PeerFin));
+}
+
+#[test]
+fn test_recv_bytes_hands_over_segments_and_reopens_window() {
+  let (mut client, mut server, conn, accepted) = connected();
+  let now = Instant::from_millis(10);
+  let mss = client.control(conn).unwrap().mss as usize;
+  let data: Vec<u8> = (0..mss * 3).map(|i| i as u8).collect();
+  assert_eq!(client.send(conn, &data), data.len());
+  exchange(&mut client, &mut server, now);
+  let window = server.control(accepted).unwrap().rcv_wnd();
+
+  // One chunk per segment, in order, and the reader's window reopens
+  let chunks = server.recv_bytes(accepted, now);
+  assert!(chunks.len() >= 3);
+  assert!(chunks.iter().all(|chunk| chunk.len() <= mss));
+  assert_eq!(chunks.concat(), data);
+  assert!(server.control(accepted).unwrap().rcv_wnd() > window);
+  assert!(server.recv_bytes(accepted, now).is_empty());
+
+  client.close(conn);
+  exchange(&mut client, &mut server, now);
+  assert!(server.recv_bytes(accepted, now).is_empty());
+  assert_eq!(server.state(accepted), Some(TcpState::CloseWait));
+}
+
+/// An ICMP destination unreachable from `from`, quoting `packet`
+fn icmp_unreachable(from: Ipv4Addr, code: u8, packet: &[u8]) -> Vec<u8> {
+  let mut message = vec![3, code, 0, 0, 0, 0, 0, 0];
+  message.extend_from_slice(&packet[..28]);
+  let checksum = tcp_stack::utils::calculate_checksum(&message);
+  message[2..4].copy_from_slice(&checksum.to_be_bytes());
+  let mut ip = Ipv4Header::new(from, CLIENT, message.len());
+  ip.protocol = Ipv4Header::PROTOCOL_ICMP;
+  let mut packet = ip.serialize();
+  packet.extend_from_slice(&message);
+  packet
+}
+
+#[test]
+fn test_read_in_time_wait_sends_no_window_update() {
+  let (mut client, mut server, conn, accepted) = connected();
+  let now = Instant::from_millis(10);
+  server.close(accepted);
+  exchange(&mut client, &mut server, now);
+
+  // Data and FIN arrive unread while the server waits out TIME-WAIT
+  assert_eq!(client.send(conn, &[7; 32 * 1024]), 32 * 1024);
+  client.close(conn);
+  exchange(&mut client, &mut server, now);
+  assert_eq!(server.state(accepted), Some(TcpState::TimeWait));
+  assert_eq!(client.state(conn), Some(TcpState::Closed));
+
+  // The client has forgotten the connection, so an update would only
+  // draw a reset
+  let mut buf = [0u8; 64 * 1024];
+  assert_eq!(server.recv(accepted, &mut buf, now), 32 * 1024);
+  assert!(server.poll_transmit(now).is_none());
+}
+
+#[test]
+fn test_port_unreachable_fails_handshake() {
+  let mut client = TcpStack::new(CLIENT, 1);
+  let now = Instant::ZERO;
+  let conn = client
+    .connect(SocketAddrV4::new(SERVER, PORT), now)
+    .unwrap();
+  let syn = client.poll_transmit(now).expect("SYN");
+
+  // A quote of a sequence number we never sent is ignored
+  let mut forged = syn.clone();
+  forged[24] ^= 0x80;
+  client.handle_packet(&icmp_unreachable(SERVER, 3, &forged), now);
+  assert_eq!(client.state(conn), Some(TcpState::SynSent));
+
+  client.handle_packet(&icmp_unreachable(SERVER, 3, &syn), now);
+  assert_eq!(client.state(conn), Some(TcpState::Closed));
+  assert_eq!(client.close_reason(conn), Some(CloseReason::IcmpError));
+}
+
+#[test]
+fn test_removed_address_closes_connections() {
+  use tcp_stack::connection::NetworkEvent;
+
+  let (mut client, _server, conn, _) = connected();
+  let now = Instant::from_millis(10);
+  client.send(conn, b"stranded");
+
+  let elsewhere = NetworkEvent::AddressRemoved {
+    index: 3,
+    addr: Ipv4Addr::new(10, 0, 0, 9),
+  };
+  assert_eq!(client.on_network_event(&elsewhere, now), 0);
+  assert_eq!(client.state(conn), Some(TcpState::Established));
+
+  let down = NetworkEvent::LinkDown {
+    index: 2,
+    name: "eth0".into(),
+    addrs: vec![CLIENT],
+  };
+  assert_eq!(client.on_network_event(&down, now), 1);
+  assert_eq!(client.state(conn), Some(TcpState::Closed));
+  assert_eq!(client.close_reason(conn), Some(CloseReason::NetworkDown));
+  // Neither the data nor a reset goes out
+  assert!(client.poll_transmit(now).is_none());
+}
+
+#[test]
+fn test_stack_resets_syn_to_closed_port() {
+  let mut client = TcpStack::new(CLIENT, 1);
+  let mut server = TcpStack::new(SERVER, 2);
+  let now = Instant::ZERO;
+  let conn = client
+    .connect(SocketAddrV4::new(SERVER, PORT), now)
+    .unwrap();
+
+  let syn = client.poll_transmit(now).unwrap();
+  server.handle_packet(&syn, now);
+  assert_eq!(server.drops().no_connection, 1);
+  let reset = server.poll_transmit(now).unwrap();
+  let (_, segment) = Ipv4Header::parse(&reset).unwrap();
+  let (tcp, _) = TcpHeader::parse(segment).unwrap();
+  assert!(tcp.flags.is_rst() && tcp.flags.is_ack());
+
+  client.handle_packet(&reset, now);
+  assert_eq!(client.state(conn), Some(TcpState::Closed));
+}
+
+/// `packet` re-sent carrying `options`
+fn with_ip_options(packet: &[u8], options: &[IpOption]) -> Vec<u8> {
+  let (mut ip, segment) = Ipv4Header::parse(packet).unwrap();
+  assert!(ip.set_options(options));
+  let mut packet = ip.serialize();
+  packet.extend_from_slice(segment);
+  packet
+}
+
+#[test]
+fn test_stack_applies_ip_options_policy() {
+  let mut server = TcpStack::new(SERVER, 2);
+  server.listen(PORT);
+  server.set_ip_options_policy(IpOptionsPolicy::DropSourceRoute);
+  let now = Instant::ZERO;
+  let source_route = IpOption::Unknown {
+    kind: IpOption::KIND_LSRR,
+    data: vec![4, 10, 0, 0, 9],
+  };
+  let [routed, recorded] = syns(CLIENT, 2, now).try_into().unwrap();
+  server.handle_packet(&with_ip_options(&routed, &[source_route]), now);
+  assert_eq!(server.drops().ip_options, 1);
+  assert!(server.poll_transmit(now).is_none());
+
+  // Other options pass
+  server.handle_packet(
+    &with_ip_options(&recorded, &[IpOption::record_route(2)]),
+    now,
+  );
+  assert!(server.poll_transmit(now).is_some());
+
+  // Stripped, a SYN with options opens a connection as one without
+  server.set_ip_options_policy(IpOptionsPolicy::Strip);
+  let mut client = TcpStack::new(CLIENT, 1);
+  let conn = client
+    .connect(SocketAddrV4::new(SERVER, PORT), now)
+    .unwrap();
+  let syn = client.poll_transmit(now).unwrap();
+  server.handle_packet(&with_ip_options(&syn, &[IpOption::record_route(1)]), now);
+  exchange(&mut client, &mut server, now);
+  assert_eq!(client.state(conn), Some(TcpState::Established));
+  let accepted = server.accept().expect("handshake completes");
+  let stats = &server.control(accepted).unwrap().stats;
+  assert_eq!(stats.ip_options_stripped, 1);
+}
+
+/// Source port of each packet `stack` has to send at `now`
+fn sending_ports(stack: &mut TcpStack, now: Instant) -> Vec<u16> {
+  std::iter::from_fn(|| stack.poll_transmit(now))
+    .map(|packet| {
+      let (_, segment) = Ipv4Header::parse(&packet).unwrap();
+      TcpHeader::parse(segment).unwrap().0.src_port
+    })
+    .collect()
+}
+
+#[test]
+fn test_bulk_connection_does_not_starve_others() {
+  let (mut client, mut server, bulk, _) = connected();
+  let now = Instant::ZERO;
+  let small = client
+    .connect(SocketAddrV4::new(SERVER, PORT), now)
+    .unwrap();
+  exchange(&mut client, &mut server, now);
+  for conn in [bulk, small] {
+    let control = client.control_mut(conn).unwrap();
+    control.congestion.restore(64 * 1460, u32::MAX);
+  }
+  let mss = client.control(bulk).unwrap().payload_room(0);
+
+  let later = Instant::from_millis(10);
+  client.send(bulk, &vec![0u8; 8 * mss]);
+  client.send(small, b"ping");
+  let ports = sending_ports(&mut client, later);
+  // The small connection's segment goes second, not behind the bulk ones
+  assert_eq!(ports.len(), 9);
+  let small_port = ports[1];
+  assert_eq!(ports.iter().filter(|port| **port == small_port).count(), 1);
+
+  // Weighted 3, the bulk connection sends three segments to the other's one
+  client.scheduler_mut().set_weight(bulk, 3);
+  client.send(bulk, &vec![0u8; 6 * mss]);
+  client.send(small, &vec![0u8; 2 * mss]);
+  let ports = sending_ports(&mut client, later);
+  let small_at: Vec<_> = ports
+    .iter()
+    .enumerate()
+    .filter(|(_, port)| **port == small_port)
+    .map(|(i, _)| i)
+    .collect();
+  assert_eq!(ports.len(), 8);
+  assert!(small_at == [0, 4] || small_at == [3, 7], "{ports:?}");
+
+  // A control channel goes ahead of everything else, marked CS5
+  assert!(client.set_priority(small, Priority::Control));
+  client.send(bulk, &vec![0u8; 3 * mss]);
+  client.send(small, &vec![0u8; 2 * mss]);
+  let ports = sending_ports(&mut client, later);
+  assert_eq!(ports[..2], [small_port; 2]);
+  let dscp = client.control(small).unwrap().dscp;
+  assert_eq!(dscp, Ipv4Header::DSCP_CS5);
+}
+
+#[test]
+fn test_stack_retransmits_lost_segment_at_timeout() {
+  let (mut client, mut server, conn, accepted) = connected();
+  let now = Instant::from_millis(10);
+  client.send(conn, b"lost");
+  assert!(client.poll_transmit(now).is_some());
+  assert!(client.poll_transmit(now).is_none());
+
+  let deadline = client.poll_timeout().expect("retransmission timer");
+  assert!(deadline > now);
+  exchange(&mut client, &mut server, deadline);
+  let mut buf = [0u8; 16];
+  assert_eq!(server.recv(accepted, &mut buf, deadline), 4);
+  assert_eq!(&buf[..4], b"lost");
+  assert_eq!(client.control(conn).unwrap().stats.retransmissions, 1);
+}
+
+#[test]
+fn test_tick_runs_only_connections_with_timers_due() {
+  let (mut client, mut server, conn, _) = connected();
+  let now = Instant::from_millis(10);
+  let idle = client
+    .connect(SocketAddrV4::new(SERVER, PORT), now)
+    .unwrap();
+  exchange(&mut client, &mut server, now);
+  client.send(conn, b"lost");
+  while client.poll_transmit(now).is_some() {}
+
+  let deadline = client.poll_timeout().expect("retransmission timer");
+  assert_eq!(client.on_tick(now), 0);
+  assert_eq!(client.on_tick(deadline), 1);
+  assert_eq!(client.control(idle).unwrap().stats.retransmissions, 0);
+  assert_eq!(client.control(conn).unwrap().stats.retransmissions, 1);
+  exchange(&mut client, &mut server, deadline);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_config_handle_changes_only_new_connections() {
+  use std::time::Duration;
+  use tcp_stack::connection::KeepalivePolicy;
+  use tcp_stack::replay::PcapReader;
+
+  let (mut client, mut server, conn, _) = connected();
+  let handle = client.config_handle();
+  let now = Instant::from_millis(10);
+  let keepalive = KeepalivePolicy::new(Duration::from_secs(30));
+  std::thread::spawn({
+    let handle = handle.clone();
+    move || {
+      handle.update(|config| {
+        config.keepalive = Some(keepalive);
+        config.send_buffer = 4096;
+        config.capture = true;
+      })
+    }
+  })
+  .join()
+  .unwrap();
+
+  let fresh = client
+    .connect(SocketAddrV4::new(SERVER, PORT), now)
+    .unwrap();
+  exchange(&mut client, &mut server, now);
+  let old = client.control(conn).unwrap();
+  assert!(!old.keepalive.is_enabled());
+  assert_ne!(old.send_buffer_limit, 4096);
+  let new = client.control(fresh).unwrap();
+  assert!(new.keepalive.is_enabled());
+  assert_eq!(new.keepalive.policy(), keepalive);
+  assert_eq!(new.send_buffer_limit, 4096);
+  assert_eq!(client.config().send_buffer, 4096);
+
+  handle.update(|config| config.capture = false);
+  client.send(fresh, b"not captured");
+  exchange(&mut client, &mut server, now);
+  let capture = client.take_capture();
+  let packets: Vec<_> = PcapReader::new(&capture)
+    .unwrap()
+    .map(|packet| packet.unwrap().data.len())
+    .collect();
+  // SYN and ACK out, SYN-ACK in
+  assert_eq!(packets.len(), 3);
+  assert!(PcapReader::new(&client.take_capture())
+    .unwrap()
+    .next()
+    .is_none());
+}
+
+#[test]
+fn test_capture_filter_keeps_only_matching_packets() {
+  use tcp_stack::packet::PacketFilter;
+  use tcp_stack::replay::PcapReader;
+
+  let (mut client, mut server, conn, _) = connected();
+  let mut config = client.config().clone();
+  config.capture = true;
+  client.set_config(config);
+  client.set_capture_filter(Some(PacketFilter::parse("dst host 10.0.0.1").unwrap()));
+
+  let now = Instant::from_millis(10);
+  client.send(conn, b"hello");
+  exchange(&mut client, &mut server, now);
+  let capture = client.take_capture();
+  let packets: Vec<_> = PcapReader::new(&capture)
+    .unwrap()
+    .map(|packet| Ipv4Header::parse(packet.unwrap().data).unwrap().0.dst_addr)
+    .collect();
+  // The data segment goes out; its ACK coming back is left out
+  assert_eq!(packets, vec![SERVER]);
+}
+
+/// Hands every flow the same ISN
+#[derive(Debug)]
+struct FixedIsn(u32);
+
+impl tcp_stack::config::IsnGenerator for FixedIsn {
+  fn initial_seq(&self, _: SocketAddrV4, _: SocketAddrV4, _: Instant) -> SeqNumber {
+    SeqNumber(self.0)
+  }
+}
+
+/// Milliseconds plus a per-flow offset taken from the remote port
+#[derive(Debug)]
+struct PortOffsetClock;
+
+impl tcp_stack::config::TimestampClock for PortOffsetClock {
+  fn ts_val(&self, _: SocketAddrV4, remote: SocketAddrV4, now: Instant) -> u32 {
+    now.total_millis() as u32 + u32::from(remote.port())
+  }
+}
+
+/// The ISN and TSval of the SYN or SYN-ACK `packet`
+fn syn_stamp(packet: &[u8]) -> (u32, u32) {
+  let (_, segment) = Ipv4Header::parse(packet).unwrap();
+  let (tcp, _) = TcpHeader::parse(segment).unwrap();
+  assert!(tcp.flags.is_syn());
+  let ts_val = tcp
+    .options
+    .iter()
+    .find_map(|option| match option {
+      TcpOption::Timestamp { ts_val, .. } => Some(*ts_val),
+      _ => None,
+    })
+    .expect("timestamp option");
+  (tcp.seq_num, ts_val)
+}
+
+#[test]
+fn test_configured_isn_and_timestamp_clock_stamp_the_handshake() {
+  use std::sync::Arc;
+  use tcp_stack::config::MillisClock;
+
+  let mut client = TcpStack::new(CLIENT, 1);
+  let mut server = TcpStack::new(SERVER, 2);
+  let mut config = client.config().clone();
+  config.isn = Some(Arc::new(FixedIsn(1000)));
+  config.timestamp_clock = Some(Arc::new(MillisClock));
+  client.set_config(config);
+  let mut config = server.config().clone();
+  config.isn = Some(Arc::new(FixedIsn(9000)));
+  config.timestamp_clock = Some(Arc::new(PortOffsetClock));
+  server.set_config(config);
+  server.listen(PORT);
+
+  let now = Instant::from_millis(1234);
+  let conn = client
+    .connect(SocketAddrV4::new(SERVER, PORT), now)
+    .unwrap();
+  let syn = client.poll_transmit(now).unwrap();
+  assert_eq!(syn_stamp(&syn), (1000, 1234));
+  server.handle_packet(&syn, now);
+  let port = TcpHeader::parse(Ipv4Header::parse(&syn).unwrap().1).unwrap().0.src_port;
+  let syn_ack = server.poll_transmit(now).unwrap();
+  assert_eq!(syn_stamp(&syn_ack), (9000, 1234 + u32::from(port)));
+  client.handle_packet(&syn_ack, now);
+  exchange(&mut client, &mut server, now);
+  let accepted = server.accept().expect("handshake completes");
+  assert_eq!(client.control(conn).unwrap().snd_una(), SeqNumber(1001));
+  assert_eq!(server.control(accepted).unwrap().snd_una(), SeqNumber(9001));
+
+  // A stack without them keeps its keyed ISNs and sends a zero TSval
+  let mut plain = TcpStack::new(CLIENT, 1);
+  plain.connect(SocketAddrV4::new(SERVER, PORT), now).unwrap();
+  let (isn, ts_val) = syn_stamp(&plain.poll_transmit(now).unwrap());
+  assert_ne!(isn, 1000);
+  assert_eq!(ts_val, 0);
+}
+
+#[test]
+fn test_profiles_shape_the_handshake_and_what_follows() {
+  use tcp_stack::connection::OsProfile;
+
+  let mut client = TcpStack::new(CLIENT, 1);
+  let mut server = TcpStack::new(SERVER, 2);
+  let mut config = client.config().clone();
+  config.profile = Some(OsProfile::Windows);
+  client.set_config(config);
+  let mut config = server.config().clone();
+  config.profile = Some(OsProfile::MacOs);
+  server.set_config(config);
+  server.listen(PORT);
+
+  let now = Instant::ZERO;
+  let conn = client
+    .connect(SocketAddrV4::new(SERVER, PORT), now)
+    .unwrap();
+  let syn = client.poll_transmit(now).unwrap();
+  let (ip, segment) = Ipv4Header::parse(&syn).unwrap();
+  assert_eq!(ip.ttl, 128);
+  assert_eq!(u16::from_be_bytes([segment[14], segment[15]]), 64240);
+  // MSS, NOP, WS 8, NOP, NOP, SACK permitted
+  assert_eq!(&segment[20..32], &[2, 4, 5, 180, 1, 3, 3, 8, 1, 1, 4, 2]);
+
+  server.handle_packet(&syn, now);
+  let syn_ack = server.poll_transmit(now).unwrap();
+  let (ip, segment) = Ipv4Header::parse(&syn_ack).unwrap();
+  assert_eq!(ip.ttl, 64);
+  assert_eq!(u16::from_be_bytes([segment[14], segment[15]]), 65535);
+  // The SYN offered no timestamps, so the SYN-ACK leaves them out with
+  // the NOPs before them: MSS, NOP, WS 6, SACK permitted, EOL
+  assert_eq!(&segment[20..32], &[2, 4, 5, 180, 1, 3, 3, 6, 4, 2, 0, 0]);
+
+  client.handle_packet(&syn_ack, now);
+  exchange(&mut client, &mut server, now);
+  let accepted = server.accept().expect("handshake completes");
+  let stats = &client.control(conn).unwrap().stats;
+  assert_eq!(stats.window_scaling.map(|ws| (ws.local, ws.peer)), Some((8, 6)));
+  assert!(!server.control(accepted).unwrap().timestamps);
+
+  client.send(conn, b"hello");
+  let packet = client.poll_transmit(now).unwrap();
+  assert_eq!(Ipv4Header::parse(&packet).unwrap().0.ttl, 128);
+  server.handle_packet(&packet, now);
+  let mut buf = [0u8; 8];
+  assert_eq!(server.recv(accepted, &mut buf, now), 5);
+}
+
+#[test]
+fn test_linux_profile_syn_layout() {
+  use tcp_stack::connection::OsProfile;
+
+  let mut header = TcpHeader::syn(40000, 80, 1, 1460);
+  OsProfile::Linux.shape_syn(&mut header, true);
+  let bytes = header.serialize();
+  // MSS, SACK permitted, timestamps, NOP, WS 7
+  assert_eq!(&bytes[20..26], &[2, 4, 5, 180, 4, 2]);
+  assert_eq!(&bytes[26..28], &[8, 10]);
+  assert_eq!(&bytes[36..40], &[1, 3, 3, 7]);
+  assert_eq!(bytes.len(), 40);
+}
+
+/// SYNs of `count` connection attempts from `addr`
+fn syns(addr: Ipv4Addr, count: usize, now: Instant) -> Vec<Vec<u8>> {
+  let mut client = TcpStack::new(addr, 7);
+  (0..count)
+    .map(|_| {
+      client
+        .connect(SocketAddrV4::new(SERVER, PORT), now)
+        .unwrap();
+      client.poll_transmit(now).unwrap()
+    })
+    .collect()
+}
+
+#[test]
+fn test_half_open_connections_hold_no_control_block() {
+  let mut server = TcpStack::new(SERVER, 2);
+  server.listen(PORT);
+  let now = Instant::ZERO;
+  for syn in syns(Ipv4Addr::new(10, 0, 1, 2), 50, now) {
+    server.handle_packet(&syn, now);
+  }
+  while server.poll_transmit(now).is_some() {}
+  let usage = server.usage();
+  assert_eq!((usage.half_open, usage.connections, usage.flows), (50, 0, 0));
+
+  // The final ACK builds the connection, timed from the SYN
+  let mut client = TcpStack::new(CLIENT, 1);
+  let conn = client
+    .connect(SocketAddrV4::new(SERVER, PORT), now)
+    .unwrap();
+  server.handle_packet(&client.poll_transmit(now).unwrap(), now);
+  let later = Instant::from_millis(20);
+  client.handle_packet(&server.poll_transmit(now).unwrap(), later);
+  exchange(&mut client, &mut server, later);
+  let accepted = server.accept().expect("handshake completes");
+  assert_eq!(server.state(accepted), Some(TcpState::Established));
+  assert_eq!(server.usage().half_open, 50);
+  assert_eq!(server.usage().connections, 1);
+  let control = server.control(accepted).unwrap();
+  assert_eq!(control.snd_una(), client.control(conn).unwrap().rcv_nxt());
+  assert_eq!(control.stats.segments_received, 2);
+  assert!((control.rtt_estimator.srtt() - 0.02).abs() < 1e-9);
+}
+
+/// A RST on the flow `syn` opened, `offset` past the sequence number
+/// the SYN's sender continues from
+fn reset_after(syn: &[u8], offset: u32) -> Vec<u8> {
+  let (ip, segment) = Ipv4Header::parse(syn).unwrap();
+  let (mut tcp, _) = TcpHeader::parse(segment).unwrap();
+  tcp.flags = TcpFlags::new().with_rst();
+  tcp.seq_num = tcp.seq_num.wrapping_add(1).wrapping_add(offset);
+  tcp.set_options(Vec::new());
+  tcp.checksum = 0;
+  let mut segment = tcp.serialize();
+  let checksum = TcpHeader::segment_checksum(ip.src_addr.into(), ip.dst_addr.into(), &segment);
+  segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+  [Ipv4Header::new(ip.src_addr, ip.dst_addr, segment.len()).serialize(), segment].concat()
+}
+
+#[test]
+fn test_half_open_connection_ignores_blind_resets() {
+  let mut server = TcpStack::new(SERVER, 2);
+  server.listen(PORT);
+  let now = Instant::ZERO;
+  let syn = syns(Ipv4Addr::new(10, 0, 1, 2), 1, now).remove(0);
+  server.handle_packet(&syn, now);
+  while server.poll_transmit(now).is_some() {}
+
+  // Beyond the window the SYN-ACK offered: not from the peer
+  server.handle_packet(&reset_after(&syn, 1 << 30), now);
+  assert_eq!(server.usage().half_open, 1);
+  assert_eq!(server.drops().out_of_window, 1);
+
+  server.handle_packet(&reset_after(&syn, 100), now);
+  assert_eq!(server.usage().half_open, 0);
+}
+
+#[test]
+fn test_syn_ack_retransmitted_until_given_up() {
+  let mut server = TcpStack::new(SERVER, 2);
+  server.listen(PORT);
+  let mut client = TcpStack::new(CLIENT, 1);
+  let now = Instant::ZERO;
+  let conn = client
+    .connect(SocketAddrV4::new(SERVER, PORT), now)
+    .unwrap();
+  server.handle_packet(&client.poll_transmit(now).unwrap(), now);
+  let first = server.poll_transmit(now).expect("SYN-ACK");
+
+  // Lost; the retransmission a second later completes the handshake
+  assert_eq!(server.poll_timeout(), Some(Instant::from_secs(1)));
+  let later = Instant::from_secs(1);
+  let again = server.poll_transmit(later).expect("SYN-ACK resent");
+  assert_eq!(again, first);
+  client.handle_packet(&again, later);
+  exchange(&mut client, &mut server, later);
+  assert_eq!(client.state(conn), Some(TcpState::Established));
+  let accepted = server.accept().expect("handshake completes");
+  let stats = &server.control(accepted).unwrap().stats;
+  assert_eq!(stats.syn_retransmissions, 1);
+  assert_eq!(server.control(accepted).unwrap().rtt_estimator.srtt(), 0.0);
+
+  // A SYN never followed up is dropped after the last SYN-ACK times out
+  for syn in syns(Ipv4Addr::new(10, 0, 1, 2), 1, now) {
+    server.handle_packet(&syn, now);
+  }
+  let mut sent = 0;
+  while let Some(deadline) = server.poll_timeout() {
+    while server.poll_transmit(deadline).is_some() {
+      sent += 1;
+    }
+  }
+  assert_eq!(sent, 6);
+  assert_eq!(server.usage().half_open, 0);
+
+  // The configured limit applies, not the default
+  let mut config = server.config().clone();
+  config.retry_limits.syn_ack = 1;
+  server.set_config(config);
+  for syn in syns(Ipv4Addr::new(10, 0, 1, 3), 1, now) {
+    server.handle_packet(&syn, now);
+  }
+  let mut sent = 0;
+  while let Some(deadline) = server.poll_timeout() {
+    while server.poll_transmit(deadline).is_some() {
+      sent += 1;
+    }
+  }
+  assert_eq!(sent, 2);
+  assert_eq!(server.usage().half_open, 0);
+}
+
+#[test]
+fn test_stack_rate_limits_syns_per_prefix() {
+  let mut server = TcpStack::new(SERVER, 2);
+  server.listen(PORT);
+  let now = Instant::ZERO;
+  server
+    .syn_limiter_mut()
+    .set_per_prefix(24, Some(RateLimit::new(1, 3)));
+
+  for syn in syns(CLIENT, 5, now) {
+    server.handle_packet(&syn, now);
+  }
+  // Another /24 has its own bucket
+  for syn in syns(Ipv4Addr::new(10, 0, 1, 2), 1, now) {
+    server.handle_packet(&syn, now);
+  }
+  let mut syn_acks = 0;
+  while server.poll_transmit(now).is_some() {
+    syn_acks += 1;
+  }
+  assert_eq!(syn_acks, 4);
+  assert_eq!(server.drops().rate_limited, 2);
+
+  // A second later the bucket has a token again
+  let later = Instant::from_secs(1);
+  for syn in syns(CLIENT, 1, later) {
+    server.handle_packet(&syn, later);
+  }
+  assert!(server.poll_transmit(later).is_some());
+}
+
+#[test]
+fn test_stack_rate_limits_resets() {
+  let mut server = TcpStack::new(SERVER, 2);
+  let now = Instant::ZERO;
+  server
+    .reply_limiter_mut()
+    .set_global(Some(RateLimit::new(1, 3)), now);
+
+  for syn in syns(CLIENT, 5, now) {
+    server.handle_packet(&syn, now);
+  }
+  let mut resets = 0;
+  while server.poll_transmit(now).is_some() {
+    resets += 1;
+  }
+  assert_eq!(resets, 3);
+  assert_eq!(server.drops().no_connection, 5);
+  assert_eq!(server.reply_limiter_mut().limited(), 2);
+}
+
+#[test]
+fn test_timestamped_segments_fit_the_path_mtu() {
+  let (mut client, mut server, conn, accepted) = connected();
+  client.send(conn, &[7; 4000]);
+  let now = Instant::from_millis(10);
+  let mut sizes = Vec::new();
+  while let Some(packet) = client.poll_transmit(now) {
+    let (_, segment) = Ipv4Header::parse(&packet).unwrap();
+    let (tcp, _) = TcpHeader::parse(segment).unwrap();
+    assert!(tcp
+      .options
+      .iter()
+      .any(|option| matches!(option, TcpOption::Timestamp { .. })));
+    sizes.push(packet.len());
+    server.handle_packet(&packet, now);
+  }
+  assert_eq!(sizes.iter().max(), Some(&1500));
+  exchange(&mut client, &mut server, now);
+  let mut buf = [0u8; 4096];
+  assert_eq!(server.recv(accepted, &mut buf, now), 4000);
+}
+
+#[test]
+fn test_stale_handles_are_rejected() {
+  let (mut client, mut server, conn, _) = connected();
+  client.abort(conn);
+  exchange(&mut client, &mut server, Instant::ZERO);
+
+  // The next connection reuses the slot, under a new generation
+  let reopened = client
+    .connect(SocketAddrV4::new(SERVER, PORT), Instant::ZERO)
+    .unwrap();
+  assert_eq!(reopened.0.index(), conn.0.index());
+  assert_ne!(reopened, conn);
+  assert_eq!(client.state(conn), None);
+  assert_eq!(client.send(conn, b"stale"), 0);
+  assert_eq!(client.state(reopened), Some(TcpState::SynSent));
+}
+
+#[test]
+fn test_restored_connection_carries_on() {
+  let (mut client, mut server, conn, accepted) = connected();
+  let now = Instant::from_millis(10);
+  client.send(conn, b"lost in the handoff");
+  while client.poll_transmit(now).is_some() {}
+  server.send(accepted, b"unread");
+  exchange(&mut client, &mut server, now);
+
+  let snapshot = client.snapshot(conn).expect("established");
+  let decoded = ConnectionSnapshot::decode(&snapshot.encode()).expect("round trip");
+  assert_eq!(decoded, snapshot);
+  assert_eq!(decoded.unacked, b"lost in the handoff");
+  assert_eq!(decoded.unread, b"unread");
+
+  let mut restored = TcpStack::new(CLIENT, 3);
+  let conn = restored.restore(&decoded, now).expect("4-tuple is free");
+  assert!(restored.restore(&decoded, now).is_none());
+  assert_eq!(restored.state(conn), Some(TcpState::Established));
+  let mut buf = [0u8; 32];
+  assert_eq!(restored.recv(conn, &mut buf, now), 6);
+  assert_eq!(&buf[..6], b"unread");
+
+  let rto = restored.control(conn).unwrap().retransmit.deadline();
+  let rto = rto.expect("unacknowledged data is held");
+  exchange(&mut restored, &mut server, rto);
+  assert_eq!(server.recv(accepted, &mut buf, rto), 19);
+  assert_eq!(&buf[..19], b"lost in the handoff");
+  assert_eq!(
+    restored.control(conn).unwrap().retransmit.pending_count(),
+    0
+  );
+}
+
+#[test]
+fn test_corruption_after_the_checksum_is_dropped_by_the_peer() {
+  let mut client = TcpStack::new(CLIENT, 1);
+  let mut server = TcpStack::new(SERVER, 2);
+  server.listen(PORT);
+  client.set_corruption(1.0, CorruptStage::AfterChecksum);
+  let now = Instant::ZERO;
+  client
+    .connect(SocketAddrV4::new(SERVER, PORT), now)
+    .unwrap();
+  let syn = client.poll_transmit(now).unwrap();
+  assert_eq!(client.corrupted(), 1);
+  server.handle_packet(&syn, now);
+  assert_eq!(server.drops().bad_checksum, 1);
+  assert!(server.poll_transmit(now).is_none());
+
+  client.set_corruption(0.0, CorruptStage::AfterChecksum);
+  assert_eq!(client.config().corruption, None);
+}
+
+#[test]
+fn test_corruption_before_the_checksum_flips_one_bit_reproducibly() {
+  let syn = |stage: Option<CorruptStage>| {
+    let mut client = TcpStack::new(CLIENT, 7);
+    if let Some(stage) = stage {
+      client.set_corruption(1.0, stage);
+    }
+    client
+      .connect(SocketAddrV4::new(SERVER, PORT), Instant::ZERO)
+      .unwrap();
+    client.poll_transmit(Instant::ZERO).unwrap()
+  };
+  let clean = syn(None);
+  let corrupted = syn(Some(CorruptStage::BeforeChecksum));
+  assert_eq!(corrupted, syn(Some(CorruptStage::BeforeChecksum)));
+
+  let (ip, segment) = Ipv4Header::parse(&corrupted).unwrap();
+  assert!(TcpHeader::verify_checksum(
+    ip.src_addr.into(),
+    ip.dst_addr.into(),
+    segment
+  ));
+  // The TCP checksum sits at 36..38 behind a 20-byte IP header
+  let flipped: u32 = clean
+    .iter()
+    .zip(&corrupted)
+    .enumerate()
+    .filter(|(at, _)| !(36..38).contains(at))
+    .map(|(_, (a, b))| (a ^ b).count_ones())
+    .sum();
+  assert_eq!(flipped, 1);
+}